@@ -0,0 +1,77 @@
+//! Writing a download to disk: `io::copy`, which writes each chunk of the
+//! body as it's read, against `copy::buffered`, which gathers them into large
+//! writes, and (with the `io-uring` feature) those large writes going through
+//! io_uring.
+//!
+//! Run with `cargo bench --bench copy` (add `--features io-uring` on Linux).
+
+use std::fs::File;
+use std::hint::black_box;
+use std::io::{self, Read};
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use rustdl::copy;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use rustdl::uring;
+
+/// Size of the simulated download
+const INPUT_SIZE: usize = 256 * 1024 * 1024;
+
+/// A response body, handing out at most `chunk` bytes per read the way
+/// HTTP bodies come off the network
+struct Body<'a> {
+    data: &'a [u8],
+    chunk: usize,
+}
+
+impl Read for Body<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = buf.len().min(self.chunk).min(self.data.len());
+        buf[..read].copy_from_slice(&self.data[..read]);
+        self.data = &self.data[read..];
+        Ok(read)
+    }
+}
+
+fn copying(c: &mut Criterion) {
+    let data: Vec<u8> = (0..INPUT_SIZE).map(|i| (i % 251) as u8).collect();
+    let path = std::env::temp_dir().join(format!("rustdl-bench-copy-{}", std::process::id()));
+
+    let mut group = c.benchmark_group("copy");
+    group.throughput(Throughput::Bytes(INPUT_SIZE as u64));
+    group.sample_size(10);
+
+    for chunk in [16 * 1024, 64 * 1024] {
+        group.bench_with_input(BenchmarkId::new("io::copy", chunk), &data, |b, data| {
+            b.iter(|| {
+                let mut file = File::create(&path).unwrap();
+                black_box(io::copy(&mut Body { data, chunk }, &mut file).unwrap())
+            })
+        });
+
+        group.bench_with_input(BenchmarkId::new("buffered", chunk), &data, |b, data| {
+            b.iter(|| {
+                let mut file = File::create(&path).unwrap();
+                black_box(copy::buffered(&mut Body { data, chunk }, &mut file).unwrap())
+            })
+        });
+
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        group.bench_with_input(BenchmarkId::new("io_uring", chunk), &data, |b, data| {
+            use std::io::Write;
+
+            b.iter(|| {
+                let mut file = uring::writer(File::create(&path).unwrap());
+                let copied = copy::buffered(&mut Body { data, chunk }, &mut file).unwrap();
+                file.flush().unwrap();
+                black_box(copied)
+            })
+        });
+    }
+
+    group.finish();
+    let _ = std::fs::remove_file(&path);
+}
+
+criterion_group!(benches, copying);
+criterion_main!(benches);
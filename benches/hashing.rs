@@ -0,0 +1,57 @@
+//! Throughput of each hashing backend, both hashing inline and through the
+//! threaded `HashingWriter` used during downloads.
+//!
+//! Run with `cargo bench --bench hashing` (add `--features asm` to compare the
+//! assembly SHA-2 implementations).
+
+use std::hint::black_box;
+use std::io::{self, Write};
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+
+#[allow(dead_code, unused_imports)]
+#[path = "../src/checksum.rs"]
+mod checksum;
+
+use checksum::{HashAlgorithm, HashingWriter};
+
+/// Size of the simulated download
+const INPUT_SIZE: usize = 64 * 1024 * 1024;
+
+/// Size of each write, matching the buffer `std::io::copy` uses
+const WRITE_SIZE: usize = 8 * 1024;
+
+fn hashing(c: &mut Criterion) {
+    let data: Vec<u8> = (0..INPUT_SIZE).map(|i| (i % 251) as u8).collect();
+
+    let mut group = c.benchmark_group("hashing");
+    group.throughput(Throughput::Bytes(INPUT_SIZE as u64));
+    group.sample_size(10);
+
+    for algorithm in HashAlgorithm::all() {
+        group.bench_with_input(BenchmarkId::new("inline", algorithm), &data, |b, data| {
+            b.iter(|| {
+                let mut hasher = algorithm.hasher();
+                for chunk in data.chunks(WRITE_SIZE) {
+                    hasher.update(chunk);
+                }
+                black_box(hasher.finalize())
+            })
+        });
+
+        group.bench_with_input(BenchmarkId::new("writer", algorithm), &data, |b, data| {
+            b.iter(|| {
+                let mut writer = HashingWriter::new(io::sink(), algorithm);
+                for chunk in data.chunks(WRITE_SIZE) {
+                    writer.write_all(chunk).unwrap();
+                }
+                black_box(writer.finish().1)
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, hashing);
+criterion_main!(benches);
@@ -0,0 +1,306 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use log::{debug, info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use url::Url;
+
+use crate::daemon::{Control, DaemonError};
+
+/// Where the API listens if `--api` isn't given an address
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:7390";
+
+/// Largest request body we'll read; adding a job only takes a URL
+const MAX_BODY: usize = 64 * 1024;
+
+/// Most we'll read of the request line and headers together
+const MAX_HEAD: u64 = 16 * 1024;
+
+/// How long a client gets to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A job to add, as POSTed to `/jobs`
+#[derive(Debug, Deserialize)]
+struct NewJob {
+    url: String,
+    /// Relative to the daemon's directory, which is also the default
+    dir: Option<PathBuf>,
+    /// Seconds since the Unix epoch to wait for before starting it
+    start_at: Option<u64>,
+}
+
+/// A JSON response and its status
+#[derive(Debug, PartialEq)]
+struct Response {
+    status: u16,
+    body: Value,
+}
+
+impl Response {
+    fn ok(body: impl serde::Serialize) -> Self {
+        Response { status: 200, body: json!(body) }
+    }
+
+    fn error(status: u16, message: impl ToString) -> Self {
+        Response { status, body: json!({ "error": message.to_string() }) }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            201 => "Created",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            413 => "Payload Too Large",
+            415 => "Unsupported Media Type",
+            431 => "Request Header Fields Too Large",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+impl From<DaemonError> for Response {
+    fn from(e: DaemonError) -> Self {
+        match e {
+            DaemonError::NoSuchJob(_) => Response::error(404, e),
+            DaemonError::Unchanged { .. } => Response::error(409, e),
+            e => Response::error(500, e),
+        }
+    }
+}
+
+/// An HTTP interface to the daemon's queue, answering JSON:
+///
+/// - `GET /jobs` and `GET /jobs/{id}` list jobs and how far they've got
+/// - `POST /jobs` adds one, given `{"url": ..., "dir": ..., "start_at": ...}`
+/// - `POST /jobs/{id}/pause` and `POST /jobs/{id}/resume`
+/// - `DELETE /jobs/{id}` cancels one
+/// - `GET /summary` counts jobs by state
+#[derive(Debug, Clone)]
+pub struct Api {
+    control: Control,
+    /// Where jobs added without a directory are saved
+    dir: PathBuf,
+    /// Bearer token every request must carry, if set
+    token: Option<String>,
+}
+
+impl Api {
+    pub fn new(control: Control, dir: PathBuf) -> Self {
+        Api { control, dir, token: None }
+    }
+
+    /// Only answer requests with `Authorization: Bearer <token>`
+    pub fn token(mut self, token: String) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    /// Answer requests on `listener` until the program exits, one thread each
+    pub fn serve(self, listener: TcpListener) {
+        if let Ok(address) = listener.local_addr() {
+            info!("API listening on http://{}", address);
+        }
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let api = self.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = api.handle(stream) {
+                            debug!("API connection failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("API connection failed: {}", e),
+            }
+        }
+    }
+
+    fn handle(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let mut reader = BufReader::new(&stream);
+        let response = self.read(&mut reader)?;
+        let body = response.body.to_string();
+        let mut stream = &stream;
+        write!(
+            stream,
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            response.status,
+            response.reason(),
+            body.len(),
+            body
+        )?;
+        stream.flush()?;
+        // Whatever of the request went unread, like the rest of a refused one,
+        // is read and dropped once the client has its answer; closing with it
+        // unread would reset the connection before the client could read that
+        stream.shutdown(Shutdown::Write)?;
+        io::copy(&mut reader.take(MAX_BODY as u64), &mut io::sink())?;
+        Ok(())
+    }
+
+    /// Read a request and work out the response to it
+    fn read(&self, reader: &mut impl BufRead) -> io::Result<Response> {
+        let mut head = reader.by_ref().take(MAX_HEAD);
+        let mut line = String::new();
+        head.read_line(&mut line)?;
+        let mut parts = line.split_whitespace();
+        let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+            return Ok(Response::error(400, "malformed request"));
+        };
+        let (method, path) = (method.to_string(), target.split('?').next().unwrap_or_default().to_string());
+
+        let (mut length, mut authorization, mut content_type, mut origin) = (0, None, None, false);
+        loop {
+            line.clear();
+            head.read_line(&mut line)?;
+            if !line.ends_with('\n') && head.limit() == 0 {
+                return Ok(Response::error(431, format!("request lines and headers are limited to {} bytes", MAX_HEAD)));
+            }
+            if line.trim().is_empty() {
+                break;
+            }
+            let Some((name, value)) = line.split_once(':') else { continue };
+            match name.trim().to_ascii_lowercase().as_str() {
+                "content-length" => length = value.trim().parse().unwrap_or_default(),
+                "authorization" => authorization = Some(value.trim().to_string()),
+                "content-type" => content_type = Some(value.trim().to_ascii_lowercase()),
+                "origin" => origin = true,
+                _ => {}
+            }
+        }
+        // Browsers send an Origin with every request a web page makes, and
+        // nothing but a web page would; none of them have any business here
+        if origin {
+            return Ok(Response::error(403, "requests from web pages aren't accepted"));
+        }
+        if let Some(token) = &self.token
+            && !authorization.as_deref().and_then(|value| value.strip_prefix("Bearer ")).is_some_and(|given| same_token(given, token))
+        {
+            return Ok(Response::error(401, "missing or wrong token"));
+        }
+        if length > MAX_BODY {
+            return Ok(Response::error(413, format!("bodies are limited to {} bytes", MAX_BODY)));
+        }
+        // A form can be posted anywhere without asking, but JSON can't
+        if length > 0 && content_type.as_deref().is_none_or(|content_type| content_type.split(';').next() != Some("application/json")) {
+            return Ok(Response::error(415, "bodies have to be application/json"));
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+        debug!("API: {} {}", method, path);
+        Ok(self.route(&method, &path, &body))
+    }
+
+    fn route(&self, method: &str, path: &str, body: &[u8]) -> Response {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let result = match (method, segments.as_slice()) {
+            ("GET", ["jobs"]) => self.control.jobs().map(Response::ok),
+            ("POST", ["jobs"]) => return self.add(body),
+            ("GET", ["summary"]) => self.control.summary().map(Response::ok),
+            (_, ["jobs", id, action @ ..]) => {
+                let Ok(id) = id.parse() else {
+                    return Response::error(404, format!("there's no job {}", id));
+                };
+                match (method, action) {
+                    ("GET", []) => self.control.job(id),
+                    ("DELETE", []) => self.control.cancel(id),
+                    ("POST", ["pause"]) => self.control.pause(id),
+                    ("POST", ["resume"]) => self.control.resume(id),
+                    (_, [] | ["pause"] | ["resume"]) => return Response::error(405, format!("{} isn't allowed here", method)),
+                    _ => return Response::error(404, format!("nothing at {}", path)),
+                }
+                .map(Response::ok)
+            }
+            (_, ["jobs"] | ["summary"]) => return Response::error(405, format!("{} isn't allowed here", method)),
+            _ => return Response::error(404, format!("nothing at {}", path)),
+        };
+        result.unwrap_or_else(Response::from)
+    }
+
+    fn add(&self, body: &[u8]) -> Response {
+        let job: NewJob = match serde_json::from_slice(body) {
+            Ok(job) => job,
+            Err(e) => return Response::error(400, e),
+        };
+        if let Err(e) = Url::parse(&job.url) {
+            return Response::error(400, format!("{}: {}", job.url, e));
+        }
+        let dir = match job.dir {
+            Some(dir) if !inside(&dir) => return Response::error(400, format!("{} isn't a directory under the daemon's", dir.display())),
+            Some(dir) => self.dir.join(dir),
+            None => self.dir.clone(),
+        };
+        let start_at = match job.start_at.map(|start| SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(start))) {
+            Some(None) => return Response::error(400, "start_at is too far off"),
+            Some(start) => start,
+            None => None,
+        };
+        match self.control.add(&job.url, &dir, start_at) {
+            Ok(job) => Response { status: 201, body: json!(job) },
+            Err(e) => e.into(),
+        }
+    }
+}
+
+/// Whether `dir` stays under whatever it's joined to: relative, and with no `..`
+fn inside(dir: &Path) -> bool {
+    dir.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// Whether `given` is `token`, taking as long to find out whichever byte
+/// they differ at, so the token can't be guessed a byte at a time
+fn same_token(given: &str, token: &str) -> bool {
+    let difference = given.bytes().zip(token.bytes()).fold(0, |difference, (a, b)| difference | (a ^ b));
+    given.len() == token.len() && std::hint::black_box(difference) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::Queue;
+
+    #[test]
+    fn test_routes() {
+        let dir = std::env::temp_dir().join(format!("rustdl-api-{}", std::process::id()));
+        let api = Api::new(Control::new(Queue::new(dir.join("queue.jsonl"))), PathBuf::from("/downloads")).token("s3cret".to_string());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || api.serve(listener));
+
+        let request = |method: &str, path: &str, token: &str, body: &str| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            write!(stream, "{} {} HTTP/1.1\r\nAuthorization: Bearer {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", method, path, token, body.len(), body).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            let (head, body) = response.split_once("\r\n\r\n").unwrap();
+            (head.split_whitespace().nth(1).unwrap().parse::<u16>().unwrap(), serde_json::from_str::<Value>(body).unwrap())
+        };
+
+        assert_eq!(request("GET", "/jobs", "wrong", "").0, 401);
+        assert_eq!(request("GET", "/jobs", "s3cre", "").0, 401);
+        assert_eq!(request("GET", "/jobs", "s3cret2", "").0, 401);
+        let (status, job) = request("POST", "/jobs", "s3cret", r#"{"url": "https://example.com/a.iso", "dir": "isos"}"#);
+        assert_eq!((status, &job["id"], &job["state"], &job["dir"]), (201, &json!(1), &json!("queued"), &json!("/downloads/isos")));
+        assert_eq!(request("POST", "/jobs", "s3cret", r#"{"url": "nope"}"#).0, 400);
+        assert_eq!(request("POST", "/jobs", "s3cret", r#"{"url": "https://example.com/a.iso", "dir": "../../etc"}"#).0, 400);
+        assert_eq!(request("POST", "/jobs", "s3cret", r#"{"url": "https://example.com/a.iso", "dir": "/etc"}"#).0, 400);
+        assert_eq!(request("GET", "/jobs", &format!("s3cret\r\nX-Padding: {}", "x".repeat(MAX_HEAD as usize)), "").0, 431);
+        assert_eq!(request("POST", "/jobs/1/pause", "s3cret\r\nOrigin: https://evil.example", "").0, 403);
+        assert_eq!(request("POST", "/jobs/1/pause", "s3cret", "").1["state"], "paused");
+        assert_eq!(request("POST", "/jobs/1/pause", "s3cret", "").0, 409);
+        assert_eq!(request("DELETE", "/jobs/1", "s3cret", "").1["state"], "cancelled");
+        assert_eq!(request("GET", "/jobs/2", "s3cret", "").0, 404);
+        assert_eq!(request("PUT", "/jobs", "s3cret", "").0, 405);
+        assert_eq!(request("GET", "/summary", "s3cret", "").1["cancelled"], 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,116 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use log::debug;
+use reqwest::StatusCode;
+use reqwest::blocking::Response;
+use reqwest::header;
+
+use crate::{copy, partial};
+
+/// Errors from appending to a file
+#[derive(Debug, thiserror::Error)]
+pub enum AppendError {
+    #[error("could not append to {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("{url}: server sent the whole file instead of the rest of it (it doesn't support ranges); not appending")]
+    RangeIgnored { url: String },
+
+    #[error("{url}: server sent the wrong part of the file ({content_range}); not appending")]
+    WrongRange { url: String, content_range: String },
+}
+
+/// Carries on writing an existing file from its current size, with no
+/// control file and no checks beyond the server agreeing on the offset
+#[derive(Debug)]
+pub struct Appender {
+    path: PathBuf,
+    offset: u64,
+}
+
+impl Appender {
+    /// Get ready to append to `path`, which needn't exist yet
+    pub fn open(path: &Path) -> Result<Self, AppendError> {
+        let offset = match fs::metadata(path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(source) => return Err(AppendError::Io { path: path.to_path_buf(), source }),
+        };
+        debug!("Appending to {} from {} bytes", path.display(), offset);
+        Ok(Appender { path: path.to_path_buf(), offset })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// How much of the file we already have
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The `Range` header asking for the rest of the file, if we have any of it
+    pub fn range_header(&self) -> Option<String> {
+        (self.offset > 0).then(|| format!("bytes={}-", self.offset))
+    }
+
+    /// Whether the server says there's nothing past what we have
+    pub fn is_complete(&self, response: &Response) -> bool {
+        self.offset > 0
+            && response.status() == StatusCode::RANGE_NOT_SATISFIABLE
+            && content_range(response).and_then(|range| range.strip_prefix("bytes */")?.parse().ok()) == Some(self.offset)
+    }
+
+    /// Make sure a successful response carries on where the file leaves off
+    pub fn check(&self, response: &Response) -> Result<(), AppendError> {
+        if self.offset == 0 {
+            return Ok(());
+        }
+        let url = response.url().to_string();
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(AppendError::RangeIgnored { url });
+        }
+        let content_range = content_range(response).unwrap_or_default().to_string();
+        if partial::range_start(&content_range) != Some(self.offset) {
+            return Err(AppendError::WrongRange { url, content_range });
+        }
+        Ok(())
+    }
+
+    /// Add everything from `body` to the end of the file, returning how much was added
+    pub fn write(&self, body: &mut impl Read) -> Result<u64, AppendError> {
+        let io_err = |source| AppendError::Io { path: self.path.clone(), source };
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path).map_err(io_err)?;
+        copy::buffered(body, &mut file).map_err(io_err)
+    }
+}
+
+fn content_range(response: &Response) -> Option<&str> {
+    response.headers().get(header::CONTENT_RANGE).and_then(|value| value.to_str().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_append_continues_from_the_current_size() {
+        let dir = std::env::temp_dir().join(format!("rustdl-append-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.bin");
+
+        let appender = Appender::open(&path).unwrap();
+        assert_eq!(appender.offset(), 0);
+        assert_eq!(appender.range_header(), None);
+        assert_eq!(appender.write(&mut &b"hello"[..]).unwrap(), 5);
+
+        let appender = Appender::open(&path).unwrap();
+        assert_eq!(appender.offset(), 5);
+        assert_eq!(appender.range_header().as_deref(), Some("bytes=5-"));
+        appender.write(&mut &b"world"[..]).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"helloworld");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
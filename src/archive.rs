@@ -0,0 +1,342 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+
+use log::{debug, info};
+
+use crate::decompress::Compression;
+
+/// The most an archive may unpack to unless told otherwise, so one that's
+/// built to unpack into far more than it holds can't fill the disk
+pub const DEFAULT_MAX_SIZE: u64 = 16 << 30;
+
+/// Errors from extracting a downloaded archive
+#[derive(Debug, thiserror::Error)]
+pub enum ArchiveError {
+    #[error("could not extract {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("could not extract {path}: {message}")]
+    Corrupt { path: PathBuf, message: String },
+
+    #[error("{archive}: refusing to extract {entry}, which would land outside {}", dir.display())]
+    Unsafe { archive: PathBuf, entry: String, dir: PathBuf },
+
+    #[error("{archive}: refusing to extract {entry}, which is under a symlink")]
+    Symlink { archive: PathBuf, entry: String },
+
+    #[error("{archive}: stopped extracting at {limit} bytes, the most it's allowed to unpack to (--extract-max-size)")]
+    TooBig { archive: PathBuf, limit: u64 },
+
+    #[error("can't extract {0}: this build doesn't include the archives feature")]
+    Unsupported(PathBuf),
+}
+
+/// Archive formats `--extract` knows, recognised by their extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    /// A tarball, possibly compressed
+    Tar(Option<Compression>),
+    SevenZip,
+}
+
+impl ArchiveFormat {
+    /// How `path` is archived and what it's called without the extension
+    pub fn detect(path: &Path) -> Option<(ArchiveFormat, String)> {
+        let name = path.file_name()?.to_str()?;
+        let lower = name.to_ascii_lowercase();
+        let formats = [
+            (".zip", ArchiveFormat::Zip),
+            (".7z", ArchiveFormat::SevenZip),
+            (".tar", ArchiveFormat::Tar(None)),
+            (".tar.gz", ArchiveFormat::Tar(Some(Compression::Gzip))),
+            (".tgz", ArchiveFormat::Tar(Some(Compression::Gzip))),
+            (".tar.xz", ArchiveFormat::Tar(Some(Compression::Xz))),
+            (".txz", ArchiveFormat::Tar(Some(Compression::Xz))),
+            (".tar.zst", ArchiveFormat::Tar(Some(Compression::Zstd))),
+            (".tzst", ArchiveFormat::Tar(Some(Compression::Zstd))),
+        ];
+        let (extension, format) = formats.into_iter().find(|(extension, _)| lower.len() > extension.len() && lower.ends_with(extension))?;
+        Some((format, name[..name.len() - extension.len()].to_string()))
+    }
+}
+
+impl fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchiveFormat::Zip => write!(f, "zip"),
+            ArchiveFormat::Tar(None) => write!(f, "tar"),
+            ArchiveFormat::Tar(Some(compression)) => write!(f, "tar.{}", compression.extension()),
+            ArchiveFormat::SevenZip => write!(f, "7z"),
+        }
+    }
+}
+
+/// What to do with archives once they're downloaded (`--extract`)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Extraction {
+    /// Where to extract them, instead of a directory next to each named after it
+    pub to: Option<PathBuf>,
+    /// Delete each archive once it's been extracted
+    pub delete: bool,
+    /// The most each archive may unpack to, or `DEFAULT_MAX_SIZE`
+    pub max_size: Option<u64>,
+}
+
+impl Extraction {
+    /// Extract `path` if it's an archive, returning the directory it went into
+    pub fn run(&self, path: &Path) -> Result<Option<PathBuf>, ArchiveError> {
+        let Some((format, stem)) = ArchiveFormat::detect(path) else {
+            info!("Not extracting {}: it isn't a .zip, .tar.*, or .7z file", path.display());
+            return Ok(None);
+        };
+        let dir = match &self.to {
+            Some(to) => to.clone(),
+            None => path.with_file_name(stem),
+        };
+        extract(path, format, &dir, self.max_size.unwrap_or(DEFAULT_MAX_SIZE))?;
+        info!("Extracted {} to {}", path.display(), dir.display());
+        if self.delete {
+            fs::remove_file(path).map_err(|source| ArchiveError::Io { path: path.to_path_buf(), source })?;
+        }
+        Ok(Some(dir))
+    }
+}
+
+/// Extract `archive` into `dir`, creating it if need be. Entries that would
+/// end up outside `dir` (`../../etc/passwd`, `/etc/passwd`) or be written
+/// through a symlink stop it, as does unpacking more than `max_size` bytes.
+pub fn extract(archive: &Path, format: ArchiveFormat, dir: &Path, max_size: u64) -> Result<(), ArchiveError> {
+    debug!("Extracting {} ({}) to {}", archive.display(), format, dir.display());
+    fs::create_dir_all(dir).map_err(|source| ArchiveError::Io { path: dir.to_path_buf(), source })?;
+    let file = File::open(archive).map_err(|source| ArchiveError::Io { path: archive.to_path_buf(), source })?;
+    match format {
+        ArchiveFormat::Tar(compression) => {
+            let reader: Box<dyn Read> = match compression {
+                Some(compression) => compression.decoder(file).map_err(|source| ArchiveError::Io { path: archive.to_path_buf(), source })?,
+                None => Box::new(BufReader::new(file)),
+            };
+            extract_tar(archive, reader, dir, max_size)
+        }
+        ArchiveFormat::Zip => extract_zip(archive, file, dir, max_size),
+        ArchiveFormat::SevenZip => extract_7z(archive, file, dir, max_size),
+    }
+}
+
+/// Where `entry` goes in `dir`, if it stays inside it without going through
+/// a symlink, which anything extracted there before could have left
+#[cfg_attr(not(feature = "archives"), allow(dead_code))]
+fn inside(archive: &Path, dir: &Path, entry: &str) -> Result<PathBuf, ArchiveError> {
+    let mut path = dir.to_path_buf();
+    for component in Path::new(entry).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => continue,
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                return Err(ArchiveError::Unsafe { archive: archive.to_path_buf(), entry: entry.to_string(), dir: dir.to_path_buf() });
+            }
+        }
+        if fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+            return Err(ArchiveError::Symlink { archive: archive.to_path_buf(), entry: entry.to_string() });
+        }
+    }
+    Ok(path)
+}
+
+/// Write one file from an archive to `path`, taking what it comes to from `left`
+#[cfg(feature = "archives")]
+fn write_entry(archive: &Path, path: &Path, contents: &mut dyn Read, left: &mut u64, max_size: u64) -> Result<(), ArchiveError> {
+    let io_err = |source| ArchiveError::Io { path: archive.to_path_buf(), source };
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(io_err)?;
+    }
+    let mut out = create_new(path).map_err(|source| ArchiveError::Io { path: path.to_path_buf(), source })?;
+    // One byte more than is left is enough to know it's too much
+    let written = io::copy(&mut contents.take(*left + 1), &mut out).map_err(io_err)?;
+    if written > *left {
+        return Err(ArchiveError::TooBig { archive: archive.to_path_buf(), limit: max_size });
+    }
+    *left -= written;
+    Ok(())
+}
+
+/// Create (or empty) the file at `path`, without following a symlink that's
+/// turned up there since it was checked
+#[cfg(feature = "archives")]
+fn create_new(path: &Path) -> io::Result<File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(libc::O_NOFOLLOW);
+    }
+    options.open(path)
+}
+
+#[cfg(feature = "archives")]
+fn extract_tar(archive: &Path, reader: impl Read, dir: &Path, max_size: u64) -> Result<(), ArchiveError> {
+    let io_err = |source| ArchiveError::Io { path: archive.to_path_buf(), source };
+    let mut tar = tar::Archive::new(reader);
+    let mut left = max_size;
+    for entry in tar.entries().map_err(io_err)? {
+        let mut entry = entry.map_err(io_err)?;
+        let name = entry.path().map_err(io_err)?.to_string_lossy().into_owned();
+        inside(archive, dir, &name)?;
+        // A tar entry's data is as long as its header says, however well it compressed
+        left = left.checked_sub(entry.size()).ok_or_else(|| ArchiveError::TooBig { archive: archive.to_path_buf(), limit: max_size })?;
+        // Also refuses to write through symlinks that point out of `dir`
+        if !entry.unpack_in(dir).map_err(io_err)? {
+            return Err(ArchiveError::Unsafe { archive: archive.to_path_buf(), entry: name, dir: dir.to_path_buf() });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "archives")]
+fn extract_zip(archive: &Path, file: File, dir: &Path, max_size: u64) -> Result<(), ArchiveError> {
+    let corrupt = |e: zip::result::ZipError| ArchiveError::Corrupt { path: archive.to_path_buf(), message: e.to_string() };
+    let mut zip = zip::ZipArchive::new(BufReader::new(file)).map_err(corrupt)?;
+    let mut left = max_size;
+    for index in 0..zip.len() {
+        let mut entry = zip.by_index(index).map_err(corrupt)?;
+        let path = inside(archive, dir, entry.name())?;
+        if entry.is_dir() {
+            fs::create_dir_all(&path).map_err(|source| ArchiveError::Io { path: path.clone(), source })?;
+            continue;
+        }
+        // Symlinks are written as files holding their targets, so they can't lead anywhere
+        write_entry(archive, &path, &mut entry, &mut left, max_size)?;
+        #[cfg(unix)]
+        if let Some(mode) = entry.unix_mode() {
+            use std::os::unix::fs::PermissionsExt;
+            let _ = fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o777));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "archives")]
+fn extract_7z(archive: &Path, file: File, dir: &Path, max_size: u64) -> Result<(), ArchiveError> {
+    let corrupt = |e: sevenz_rust::Error| ArchiveError::Corrupt { path: archive.to_path_buf(), message: e.to_string() };
+    let length = file.metadata().map_err(|source| ArchiveError::Io { path: archive.to_path_buf(), source })?.len();
+    let mut reader = sevenz_rust::SevenZReader::new(file, length, sevenz_rust::Password::empty()).map_err(corrupt)?;
+    // The callback can only fail with the crate's errors, so ours wait here
+    let mut failure = None;
+    let mut left = max_size;
+    let result = reader.for_each_entries(|entry, contents| {
+        let written = inside(archive, dir, entry.name()).and_then(|path| match entry.is_directory() {
+            true => fs::create_dir_all(&path).map_err(|source| ArchiveError::Io { path, source }),
+            false => write_entry(archive, &path, contents, &mut left, max_size),
+        });
+        match written {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                failure = Some(e);
+                Ok(false)
+            }
+        }
+    });
+    match failure {
+        Some(e) => Err(e),
+        None => result.map_err(corrupt),
+    }
+}
+
+#[cfg(not(feature = "archives"))]
+fn extract_tar(archive: &Path, _reader: impl Read, _dir: &Path, _max_size: u64) -> Result<(), ArchiveError> {
+    Err(ArchiveError::Unsupported(archive.to_path_buf()))
+}
+
+#[cfg(not(feature = "archives"))]
+fn extract_zip(archive: &Path, _file: File, _dir: &Path, _max_size: u64) -> Result<(), ArchiveError> {
+    Err(ArchiveError::Unsupported(archive.to_path_buf()))
+}
+
+#[cfg(not(feature = "archives"))]
+fn extract_7z(archive: &Path, _file: File, _dir: &Path, _max_size: u64) -> Result<(), ArchiveError> {
+    Err(ArchiveError::Unsupported(archive.to_path_buf()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(ArchiveFormat::detect(Path::new("dir/src-1.0.tar.gz")), Some((ArchiveFormat::Tar(Some(Compression::Gzip)), "src-1.0".to_string())));
+        assert_eq!(ArchiveFormat::detect(Path::new("Release.ZIP")), Some((ArchiveFormat::Zip, "Release".to_string())));
+        assert_eq!(ArchiveFormat::detect(Path::new("tools.tzst")), Some((ArchiveFormat::Tar(Some(Compression::Zstd)), "tools".to_string())));
+        assert_eq!(ArchiveFormat::detect(Path::new("setup.7z")), Some((ArchiveFormat::SevenZip, "setup".to_string())));
+        assert_eq!(ArchiveFormat::detect(Path::new("dump.sql.gz")), None);
+        assert_eq!(ArchiveFormat::detect(Path::new(".zip")), None);
+        assert!(inside(Path::new("a.zip"), Path::new("out"), "../../etc/passwd").is_err());
+        assert!(inside(Path::new("a.zip"), Path::new("out"), "/etc/passwd").is_err());
+        assert_eq!(inside(Path::new("a.zip"), Path::new("out"), "./bin/tool").unwrap(), Path::new("out/bin/tool"));
+    }
+
+    #[cfg(feature = "archives")]
+    #[test]
+    fn test_extract() {
+        use std::io::Write;
+
+        let dir = std::env::temp_dir().join(format!("rustdl-archive-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let zipped = dir.join("release.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&zipped).unwrap());
+        zip.start_file("bin/tool", zip::write::SimpleFileOptions::default()).unwrap();
+        zip.write_all(b"#!/bin/sh\n").unwrap();
+        zip.finish().unwrap();
+        let extracted = Extraction { to: None, delete: true, max_size: None }.run(&zipped).unwrap().unwrap();
+        assert_eq!(fs::read(extracted.join("bin/tool")).unwrap(), b"#!/bin/sh\n");
+        assert!(!zipped.exists());
+
+        let tarball = dir.join("src.tar.gz");
+        let mut tar = tar::Builder::new(flate2::write::GzEncoder::new(File::create(&tarball).unwrap(), flate2::Compression::default()));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(6);
+        header.set_mode(0o644);
+        tar.append_data(&mut header, "src/main.rs", &b"fn(){}"[..]).unwrap();
+        tar.into_inner().unwrap().finish().unwrap();
+        let to = dir.join("unpacked");
+        assert_eq!(Extraction { to: Some(to.clone()), delete: false, max_size: None }.run(&tarball).unwrap(), Some(to.clone()));
+        assert_eq!(fs::read(to.join("src/main.rs")).unwrap(), b"fn(){}");
+        assert!(tarball.exists());
+
+        // Nothing's written outside the directory
+        let evil = dir.join("evil.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&evil).unwrap());
+        zip.start_file("../escaped", zip::write::SimpleFileOptions::default()).unwrap();
+        zip.write_all(b"gotcha").unwrap();
+        zip.finish().unwrap();
+        assert!(matches!(extract(&evil, ArchiveFormat::Zip, &dir.join("evil"), DEFAULT_MAX_SIZE), Err(ArchiveError::Unsafe { .. })));
+        assert!(!dir.join("escaped").exists());
+
+        // Nor through a symlink an earlier archive left behind
+        #[cfg(unix)]
+        {
+            let victim = dir.join("victim");
+            fs::create_dir_all(&victim).unwrap();
+            std::os::unix::fs::symlink(&victim, to.join("x")).unwrap();
+            let sneaky = dir.join("sneaky.zip");
+            let mut zip = zip::ZipWriter::new(File::create(&sneaky).unwrap());
+            zip.start_file("x/pwned.txt", zip::write::SimpleFileOptions::default()).unwrap();
+            zip.write_all(b"gotcha").unwrap();
+            zip.finish().unwrap();
+            assert!(matches!(extract(&sneaky, ArchiveFormat::Zip, &to, DEFAULT_MAX_SIZE), Err(ArchiveError::Symlink { .. })));
+            assert!(!victim.join("pwned.txt").exists());
+        }
+
+        // And no more than it's allowed to unpack to
+        assert!(matches!(extract(&tarball, ArchiveFormat::Tar(Some(Compression::Gzip)), &dir.join("small"), 5), Err(ArchiveError::TooBig { limit: 5, .. })));
+        let bomb = dir.join("bomb.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&bomb).unwrap());
+        zip.start_file("zeroes", zip::write::SimpleFileOptions::default()).unwrap();
+        zip.write_all(&[0; 1000]).unwrap();
+        zip.finish().unwrap();
+        assert!(matches!(extract(&bomb, ArchiveFormat::Zip, &dir.join("small"), 999), Err(ArchiveError::TooBig { limit: 999, .. })));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,302 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+
+use log::{debug, info};
+
+/// Default OIDC issuer for keyless signatures made from GitHub Actions
+pub const GITHUB_ACTIONS_ISSUER: &str = "https://token.actions.githubusercontent.com";
+
+/// Kinds of attestation we know how to verify
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttestationKind {
+    /// GitHub artifact attestations, checked with `gh attestation verify`
+    Github,
+    /// Sigstore bundles, checked with `cosign verify-blob`
+    Cosign,
+    /// SLSA provenance, checked with `slsa-verifier verify-artifact`
+    Slsa,
+}
+
+impl AttestationKind {
+    /// Get all supported attestation kinds
+    pub fn all() -> Vec<AttestationKind> {
+        vec![AttestationKind::Github, AttestationKind::Cosign, AttestationKind::Slsa]
+    }
+
+    /// Get the string representation of the attestation kind
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AttestationKind::Github => "github",
+            AttestationKind::Cosign => "cosign",
+            AttestationKind::Slsa => "slsa",
+        }
+    }
+
+    /// The external tool that does the actual verification
+    pub fn tool(&self) -> &'static str {
+        match self {
+            AttestationKind::Github => "gh",
+            AttestationKind::Cosign => "cosign",
+            AttestationKind::Slsa => "slsa-verifier",
+        }
+    }
+
+    /// Suffix appended to the download URL to find the bundle when none is given
+    fn default_bundle_suffix(&self) -> Option<&'static str> {
+        match self {
+            AttestationKind::Github => None,
+            AttestationKind::Cosign => Some(".sigstore.json"),
+            AttestationKind::Slsa => Some(".intoto.jsonl"),
+        }
+    }
+}
+
+impl fmt::Display for AttestationKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for AttestationKind {
+    type Err = AttestationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "github" | "gh" => Ok(AttestationKind::Github),
+            "cosign" | "sigstore" => Ok(AttestationKind::Cosign),
+            "slsa" => Ok(AttestationKind::Slsa),
+            _ => Err(AttestationError::UnsupportedKind { kind: s.to_string() }),
+        }
+    }
+}
+
+/// Errors raised while verifying attestations
+#[derive(Debug, thiserror::Error)]
+pub enum AttestationError {
+    #[error("Attestation type '{kind}' is not supported. Available types: {}",
+            AttestationKind::all().iter().map(|k| k.as_str()).collect::<Vec<_>>().join(", "))]
+    UnsupportedKind { kind: String },
+
+    #[error("{kind} attestation verification requires {option}")]
+    MissingOption { kind: AttestationKind, option: &'static str },
+
+    #[error("could not run {tool} (is it installed and on your PATH?): {source}")]
+    ToolUnavailable { tool: &'static str, source: io::Error },
+
+    #[error("could not fetch attestation bundle {location}: {message}")]
+    BundleUnavailable { location: String, message: String },
+
+    #[error("{tool} rejected the attestation: {message}")]
+    Rejected { tool: &'static str, message: String },
+}
+
+/// Verifies downloaded artifacts against their attestations using the vendor tooling
+#[derive(Debug, Clone)]
+pub struct AttestationVerifier {
+    kind: AttestationKind,
+    repo: Option<String>,
+    bundle: Option<String>,
+    identity: Option<String>,
+    issuer: String,
+}
+
+impl AttestationVerifier {
+    /// Create a verifier, checking that everything the attestation kind needs was given
+    pub fn new(
+        kind: AttestationKind,
+        repo: Option<String>,
+        bundle: Option<String>,
+        identity: Option<String>,
+        issuer: Option<String>,
+    ) -> Result<Self, AttestationError> {
+        match kind {
+            AttestationKind::Github | AttestationKind::Slsa if repo.is_none() => {
+                return Err(AttestationError::MissingOption { kind, option: "--attestation-repo" });
+            }
+            AttestationKind::Cosign if identity.is_none() => {
+                return Err(AttestationError::MissingOption { kind, option: "--certificate-identity" });
+            }
+            _ => {}
+        }
+        Ok(Self {
+            kind,
+            repo,
+            bundle,
+            identity,
+            issuer: issuer.unwrap_or_else(|| GITHUB_ACTIONS_ISSUER.to_string()),
+        })
+    }
+
+    /// Where to find the bundle or provenance for a download, if this kind uses one
+    pub fn bundle_location(&self, url: &str) -> Option<String> {
+        match (&self.bundle, self.kind.default_bundle_suffix()) {
+            (Some(bundle), _) => Some(bundle.replace("{url}", url)),
+            (None, Some(suffix)) => Some(format!("{}{}", url, suffix)),
+            (None, None) => None,
+        }
+    }
+
+    /// Build the verification command for an artifact
+    pub fn command(&self, artifact: &Path, bundle: Option<&Path>) -> Command {
+        let mut command = Command::new(self.kind.tool());
+        match self.kind {
+            AttestationKind::Github => {
+                command.args(["attestation", "verify"]).arg(artifact);
+                command.arg("--repo").arg(self.repo.as_deref().unwrap_or_default());
+                if let Some(bundle) = bundle {
+                    command.arg("--bundle").arg(bundle);
+                }
+            }
+            AttestationKind::Cosign => {
+                command.arg("verify-blob");
+                if let Some(bundle) = bundle {
+                    command.arg("--bundle").arg(bundle);
+                }
+                command.arg("--certificate-identity").arg(self.identity.as_deref().unwrap_or_default());
+                command.arg("--certificate-oidc-issuer").arg(&self.issuer);
+                command.arg(artifact);
+            }
+            AttestationKind::Slsa => {
+                command.arg("verify-artifact").arg(artifact);
+                if let Some(bundle) = bundle {
+                    command.arg("--provenance-path").arg(bundle);
+                }
+                let repo = self.repo.as_deref().unwrap_or_default();
+                let source_uri = if repo.contains('.') { repo.to_string() } else { format!("github.com/{}", repo) };
+                command.arg("--source-uri").arg(source_uri);
+            }
+        }
+        command
+    }
+
+    /// Verify a downloaded artifact, fetching its bundle with `client` if it's remote
+    pub fn verify(&self, artifact: &Path, url: &str, client: &reqwest::blocking::Client) -> Result<(), AttestationError> {
+        let location = self.bundle_location(url);
+        let (bundle, temporary) = match &location {
+            Some(location) if location.starts_with("http://") || location.starts_with("https://") => {
+                let path = temporary_bundle_path(artifact);
+                fetch_bundle(client, location, &path)?;
+                (Some(path), true)
+            }
+            Some(location) => (Some(PathBuf::from(location)), false),
+            None => (None, false),
+        };
+
+        let mut command = self.command(artifact, bundle.as_deref());
+        debug!("Verifying {} attestation: {:?}", self.kind, command);
+        let output = command.output();
+
+        if temporary && let Some(bundle) = &bundle {
+            let _ = fs::remove_file(bundle);
+        }
+
+        let output = output.map_err(|source| AttestationError::ToolUnavailable { tool: self.kind.tool(), source })?;
+        if output.status.success() {
+            info!("{} attestation verified for {}", self.kind, artifact.display());
+            Ok(())
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let message = stderr.lines().rev().find(|line| !line.trim().is_empty()).unwrap_or("verification failed");
+            Err(AttestationError::Rejected { tool: self.kind.tool(), message: message.trim().to_string() })
+        }
+    }
+}
+
+/// Path next to the artifact to store a downloaded bundle in
+fn temporary_bundle_path(artifact: &Path) -> PathBuf {
+    let name = artifact.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    artifact.with_file_name(format!(".{}.attestation", name))
+}
+
+fn fetch_bundle(client: &reqwest::blocking::Client, location: &str, path: &Path) -> Result<(), AttestationError> {
+    let unavailable = |message: String| AttestationError::BundleUnavailable { location: location.to_string(), message };
+    let mut response = client
+        .get(location)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| unavailable(e.to_string()))?;
+    let mut file = File::create(path).map_err(|e| unavailable(e.to_string()))?;
+    io::copy(&mut response, &mut file).map_err(|e| unavailable(e.to_string()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(command: &Command) -> Vec<String> {
+        command.get_args().map(|arg| arg.to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn test_attestation_kind_from_str() {
+        assert_eq!("GitHub".parse::<AttestationKind>().unwrap(), AttestationKind::Github);
+        assert_eq!("sigstore".parse::<AttestationKind>().unwrap(), AttestationKind::Cosign);
+        assert_eq!("slsa".parse::<AttestationKind>().unwrap(), AttestationKind::Slsa);
+        assert!(matches!("pgp".parse::<AttestationKind>(), Err(AttestationError::UnsupportedKind { .. })));
+    }
+
+    #[test]
+    fn test_verifier_requires_options() {
+        let result = AttestationVerifier::new(AttestationKind::Github, None, None, None, None);
+        assert!(matches!(result, Err(AttestationError::MissingOption { option: "--attestation-repo", .. })));
+
+        let result = AttestationVerifier::new(AttestationKind::Cosign, None, None, None, None);
+        assert!(matches!(result, Err(AttestationError::MissingOption { option: "--certificate-identity", .. })));
+    }
+
+    #[test]
+    fn test_github_command() {
+        let verifier = AttestationVerifier::new(AttestationKind::Github, Some("owner/repo".into()), None, None, None).unwrap();
+        assert_eq!(verifier.bundle_location("https://example.com/tool.tar.gz"), None);
+        let command = verifier.command(Path::new("tool.tar.gz"), None);
+        assert_eq!(command.get_program(), "gh");
+        assert_eq!(args(&command), vec!["attestation", "verify", "tool.tar.gz", "--repo", "owner/repo"]);
+    }
+
+    #[test]
+    fn test_cosign_command_uses_default_bundle() {
+        let verifier = AttestationVerifier::new(
+            AttestationKind::Cosign, None, None, Some("https://github.com/owner/repo/.github/workflows/release.yml@refs/tags/v1".into()), None,
+        ).unwrap();
+        assert_eq!(
+            verifier.bundle_location("https://example.com/tool.tar.gz").as_deref(),
+            Some("https://example.com/tool.tar.gz.sigstore.json")
+        );
+        let command = verifier.command(Path::new("tool.tar.gz"), Some(Path::new("bundle.json")));
+        let args = args(&command);
+        assert_eq!(args[0], "verify-blob");
+        assert!(args.windows(2).any(|pair| pair == ["--certificate-oidc-issuer", GITHUB_ACTIONS_ISSUER]));
+        assert_eq!(args.last().unwrap(), "tool.tar.gz");
+    }
+
+    #[test]
+    fn test_slsa_command_source_uri() {
+        let verifier = AttestationVerifier::new(AttestationKind::Slsa, Some("owner/repo".into()), None, None, None).unwrap();
+        let command = verifier.command(Path::new("tool"), Some(Path::new("tool.intoto.jsonl")));
+        assert!(args(&command).windows(2).any(|pair| pair == ["--source-uri", "github.com/owner/repo"]));
+    }
+
+    #[test]
+    fn test_bundle_location_template() {
+        let verifier = AttestationVerifier::new(
+            AttestationKind::Slsa, Some("owner/repo".into()), Some("{url}.provenance".into()), None, None,
+        ).unwrap();
+        assert_eq!(
+            verifier.bundle_location("https://example.com/tool").as_deref(),
+            Some("https://example.com/tool.provenance")
+        );
+    }
+
+    #[test]
+    fn test_temporary_bundle_path_is_hidden_sibling() {
+        assert_eq!(
+            temporary_bundle_path(Path::new("downloads/tool.tar.gz")),
+            PathBuf::from("downloads/.tool.tar.gz.attestation")
+        );
+    }
+}
@@ -0,0 +1,145 @@
+use std::process::{Command, ExitStatus, Stdio};
+use std::str::FromStr;
+
+use log::debug;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+
+/// Errors from running an authentication command
+#[derive(Debug, thiserror::Error)]
+pub enum AuthHookError {
+    #[error("could not run auth command: {0}")]
+    Spawn(#[from] std::io::Error),
+
+    #[error("auth command failed ({status}){}", if message.is_empty() { String::new() } else { format!(": {}", message) })]
+    Failed { status: ExitStatus, message: String },
+
+    #[error("auth command printed '{0}', which isn't a 'Name: value' header")]
+    BadHeader(String),
+}
+
+/// An external command that's run before each request and prints headers to
+/// add to it, like `sign-request {method} {url}`. It's run by the shell, with
+/// `{url}` and `{method}` replaced by the (quoted) request URL and method;
+/// they're also in `$DOWNLOAD_URL` and `$DOWNLOAD_METHOD`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthHook {
+    command: String,
+}
+
+impl AuthHook {
+    /// Run the command for a request, returning the headers it printed
+    pub fn headers(&self, method: &str, url: &str) -> Result<HeaderMap, AuthHookError> {
+        let command = substitute(&self.command, &[("{url}", url), ("{method}", method)]);
+        debug!("Running auth command: {}", command);
+        let output = shell(&command)
+            .env("DOWNLOAD_URL", url)
+            .env("DOWNLOAD_METHOD", method)
+            .stdin(Stdio::null())
+            .output()?;
+        if !output.status.success() {
+            return Err(AuthHookError::Failed {
+                status: output.status,
+                message: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            });
+        }
+        parse_headers(&String::from_utf8_lossy(&output.stdout))
+    }
+}
+
+impl FromStr for AuthHook {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "" => Err("the auth command can't be empty".to_string()),
+            command => Ok(AuthHook { command: command.to_string() }),
+        }
+    }
+}
+
+/// Headers printed one per line as `Name: value`; blank lines are ignored
+fn parse_headers(output: &str) -> Result<HeaderMap, AuthHookError> {
+    let mut headers = HeaderMap::new();
+    for line in output.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let bad = || AuthHookError::BadHeader(line.to_string());
+        let (name, value) = line.split_once(':').ok_or_else(bad)?;
+        let name = HeaderName::from_bytes(name.trim().as_bytes()).map_err(|_| bad())?;
+        let value = HeaderValue::from_str(value.trim()).map_err(|_| bad())?;
+        headers.append(name, value);
+    }
+    Ok(headers)
+}
+
+#[cfg(unix)]
+pub fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+pub fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+/// Quote a value so the shell passes it on as a single argument
+#[cfg(unix)]
+pub fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Quote a value so the shell passes it on as a single argument
+#[cfg(windows)]
+pub fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
+
+/// `command` with each of `placeholders` replaced by its (quoted) value, in
+/// one pass from left to right, so a value that happens to contain a
+/// placeholder, like a URL with `{checksum}` in it, is never substituted again
+pub fn substitute(command: &str, placeholders: &[(&str, &str)]) -> String {
+    let mut substituted = String::with_capacity(command.len());
+    let mut rest = command;
+    while !rest.is_empty() {
+        match placeholders.iter().find(|(placeholder, _)| rest.starts_with(placeholder)) {
+            Some((placeholder, value)) => {
+                substituted.push_str(&quote(value));
+                rest = &rest[placeholder.len()..];
+            }
+            None => {
+                let c = rest.chars().next().expect("rest isn't empty");
+                substituted.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    substituted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_headers() {
+        let headers = parse_headers("X-Signature: abc123\n\nAuthorization: Bearer token\r\n").unwrap();
+        assert_eq!(headers["x-signature"], "abc123");
+        assert_eq!(headers["authorization"], "Bearer token");
+        assert!(matches!(parse_headers("not a header"), Err(AuthHookError::BadHeader(_))));
+        assert!(matches!(parse_headers("Bad Name: value"), Err(AuthHookError::BadHeader(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_command_gets_the_url() {
+        let hook: AuthHook = "echo X-Signed: {method} {url}; echo \"X-Env: $DOWNLOAD_URL\"".parse().unwrap();
+        let headers = hook.headers("GET", "https://example.com/it's?a=1&b=2").unwrap();
+        assert_eq!(headers["x-signed"], "GET https://example.com/it's?a=1&b=2");
+        assert_eq!(headers["x-env"], "https://example.com/it's?a=1&b=2");
+
+        let failing: AuthHook = "echo nope >&2; exit 3".parse().unwrap();
+        assert!(matches!(failing.headers("GET", "https://example.com/"), Err(AuthHookError::Failed { message, .. }) if message == "nope"));
+    }
+}
@@ -0,0 +1,434 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressStyle};
+use log::{debug, info, warn};
+use reqwest::{Method, StatusCode};
+use reqwest::blocking::Client;
+use reqwest::header;
+use url::Url;
+
+use crate::auth_hook::AuthHook;
+use crate::checksum::{Checksum, ChecksumSpec};
+use crate::cancel::{Cancellation, Cancelled};
+use crate::checksum_db::{self, ChecksumDatabase};
+use crate::credentials::CredentialStore;
+use crate::exit_code::{ExitCode, Failure};
+use crate::logging;
+use crate::middleware::{DownloadContext, Middleware};
+use crate::naming::NamePolicy;
+use crate::negotiate::Negotiator;
+use crate::notify::Notifier;
+use crate::oauth::OAuthSettings;
+use crate::politeness::Politeness;
+use crate::progress::ProgressMode;
+use crate::report::Report;
+use crate::retry_after::RetryAfter;
+use crate::timeouts;
+use crate::throttle::{self, MinimumSpeed, RateLimiter};
+use crate::timestamping;
+use crate::warc::{Exchange, RequestHead, WarcWriter};
+
+/// Number of parallel requests in small-file mode unless `--jobs` says otherwise
+pub const DEFAULT_JOBS: usize = 8;
+
+/// How many finished downloads may wait for the writer thread before workers block
+const WRITE_QUEUE_DEPTH: usize = 256;
+
+/// A fetched file, ready for the writer thread
+struct Fetched {
+    url: String,
+    /// Where it would go, before the writer claims it
+    path: PathBuf,
+    body: Vec<u8>,
+    last_modified: Option<String>,
+    /// How long it took to fetch, and what the server said
+    elapsed: Duration,
+    status: StatusCode,
+    checksums: Vec<Checksum>,
+    /// Digests nothing was expected of, to print once the file's saved
+    unchecked: Vec<Checksum>,
+}
+
+/// What became of a URL once it was fetched
+enum Outcome {
+    Fetched(Fetched),
+    /// Our copy is up to date (`--timestamping`)
+    NotModified,
+    /// The file already exists and we mustn't overwrite it (`--no-clobber`)
+    Skipped(PathBuf),
+}
+
+/// How many downloads succeeded and failed, and how many were skipped
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BatchSummary {
+    pub succeeded: usize,
+    pub failed: usize,
+    pub skipped: usize,
+}
+
+/// Downloads lots of small files at once: a few workers share one client (so
+/// requests to the same host are multiplexed over HTTP/2 where the server
+/// supports it), bodies are buffered in memory, all file writes go through a
+/// single writer thread, and progress is reported for the batch as a whole.
+pub struct SmallFileBatch<'a> {
+    pub client: &'a Client,
+    pub middleware: &'a Middleware,
+    pub notifier: &'a Notifier,
+    pub checksum: &'a [ChecksumSpec],
+    pub checksum_db: Option<&'a ChecksumDatabase>,
+    pub credentials: Option<&'a CredentialStore>,
+    pub auth_hook: Option<&'a AuthHook>,
+    pub oauth: &'a OAuthSettings,
+    pub header_timeout: Option<Duration>,
+    pub retry_after: RetryAfter,
+    pub negotiator: Option<&'a Negotiator>,
+    pub timestamping: bool,
+    /// Give files the server's `Last-Modified` time
+    pub preserve_time: bool,
+    pub no_clobber: bool,
+    /// Chooses where each file goes, and keeps concurrent downloads of the same name apart
+    pub naming: &'a dyn NamePolicy,
+    pub limiter: Option<&'a Arc<RateLimiter>>,
+    /// Give up on bodies that come in slower than this
+    pub min_speed: Option<MinimumSpeed>,
+    /// Decides which URL each worker takes next, so no host gets more than its share
+    pub politeness: &'a Arc<Politeness>,
+    /// Archive every response here as well
+    pub warc: Option<&'a WarcWriter>,
+    /// Where each URL's outcome is recorded
+    pub report: &'a Report,
+    pub progress: ProgressMode,
+    pub jobs: usize,
+    /// Once cancelled, URLs not yet started fail straight away
+    pub cancellation: &'a Cancellation,
+    /// Name and write files in the order of their URLs, and date them all
+    /// with this time (`--deterministic`)
+    pub deterministic: Option<SystemTime>,
+}
+
+impl SmallFileBatch<'_> {
+    /// Download every URL, returning how many succeeded and failed
+    pub fn run(&self, urls: Vec<String>) -> BatchSummary {
+        let style = ProgressStyle::with_template("{prefix:.blue} {wide_bar:.blue/white} {pos}/{len} files • {msg:.green} • elapsed {elapsed:>4.cyan}  ")
+            .unwrap()
+            .progress_chars("━╸━");
+        let pb = self.progress.bar(urls.len() as u64).with_style(style.clone()).with_prefix("small files");
+        self.progress.started(&pb, None);
+        // The message says it's paused
+        self.cancellation.track(&pb, style);
+
+        let jobs = self.jobs.clamp(1, urls.len().max(1));
+        info!("Downloading {} files with {} workers", urls.len(), jobs);
+
+        let queue = Mutex::new(urls.into_iter().enumerate().collect::<VecDeque<_>>());
+        let failed = AtomicUsize::new(0);
+        let not_modified = AtomicUsize::new(0);
+        let skipped = AtomicUsize::new(0);
+        let bytes = AtomicU64::new(0);
+        // Every URL's index comes through, fetched or not, so the writer can keep them in order
+        let (sender, receiver) = mpsc::sync_channel::<(usize, Option<Fetched>)>(WRITE_QUEUE_DEPTH);
+
+        let succeeded = thread::scope(|scope| {
+            let writer = scope.spawn(|| {
+                let mut succeeded = 0;
+                let mut directories = HashSet::new();
+                let mut waiting = BTreeMap::new();
+                let mut next = 0;
+                for (index, fetched) in receiver {
+                    let ready: Vec<Fetched> = match self.deterministic {
+                        Some(_) => {
+                            waiting.insert(index, fetched);
+                            std::iter::from_fn(|| waiting.remove(&next).inspect(|_| next += 1)).flatten().collect()
+                        }
+                        None => fetched.into_iter().collect(),
+                    };
+                    for fetched in ready {
+                        succeeded += usize::from(self.save(fetched, &pb, &failed, &mut directories));
+                    }
+                }
+                succeeded
+            });
+
+            for _ in 0..jobs {
+                let sender = sender.clone();
+                scope.spawn(|| {
+                    let sender = sender;
+                    loop {
+                        let Some(((index, url), _permit)) = self.politeness.next(&queue, |(_, url)| url) else { break };
+                        let mut fetched = None;
+                        if let Some(reason) = self.cancellation.reason() {
+                            self.fail(&pb, &failed, &url, Cancelled(reason).to_string());
+                            pb.inc(1);
+                        } else {
+                            match self.fetch(&url, index, &pb) {
+                                Ok(Outcome::Fetched(done)) => {
+                                    let total = bytes.fetch_add(done.body.len() as u64, Ordering::Relaxed) + done.body.len() as u64;
+                                    pb.set_message(HumanBytes(total).to_string());
+                                    fetched = Some(done);
+                                }
+                                Ok(Outcome::NotModified) => {
+                                    self.report.skipped(&url, None, "not modified");
+                                    not_modified.fetch_add(1, Ordering::Relaxed);
+                                    pb.inc(1);
+                                }
+                                Ok(Outcome::Skipped(path)) => {
+                                    self.report.skipped(&url, Some(&path), "exists");
+                                    skipped.fetch_add(1, Ordering::Relaxed);
+                                    pb.inc(1);
+                                }
+                                Err(e) => {
+                                    self.fail(&pb, &failed, &url, e);
+                                    pb.inc(1);
+                                }
+                            }
+                        }
+                        if sender.send((index, fetched)).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(sender);
+
+            writer.join().unwrap_or_default()
+        });
+
+        self.progress.finish(&pb, None, pb.style(), pb.message());
+        BatchSummary {
+            succeeded: succeeded + not_modified.into_inner(),
+            failed: failed.into_inner(),
+            skipped: skipped.into_inner(),
+        }
+    }
+
+    /// Fetch a single URL (the `index`th) into memory, unless we can skip it
+    fn fetch(&self, url: &str, index: usize, pb: &ProgressBar) -> Result<Outcome, Failure> {
+        let started = Instant::now();
+        let parsed_url = Url::parse(url).map_err(|e| format!("{}: {}", url, e))?;
+        let place = |url: &Url, filename: &Path| self.naming.place(url, filename, index);
+        let url_path = self.naming.url_name(&parsed_url).and_then(|filename| place(&parsed_url, &filename));
+
+        if self.no_clobber && let Some(path) = url_path.as_ref().filter(|path| path.exists()) {
+            self.progress.status(pb, format!("{}: exists, skipped", path.display()));
+            return Ok(Outcome::Skipped(path.clone()));
+        }
+
+        let mut request = self.middleware.get(self.client, url);
+        if self.timestamping && let Some(since) = url_path.as_deref().and_then(timestamping::if_modified_since) {
+            request = request.header(header::IF_MODIFIED_SINCE, since);
+        }
+        if let Some(store) = self.credentials {
+            request = store.authorize(request, &parsed_url, self.client, self.oauth);
+        }
+        if let Some(hook) = self.auth_hook {
+            request = request.headers(hook.headers("GET", url).map_err(|e| format!("{}: {}", url, e))?);
+        }
+        let request = request.build().map_err(|e| e.to_string())?;
+        logging::request(&request);
+        let request_head = self.warc.map(|_| RequestHead::new(&request));
+        let countdown = |reason: &str, left| pb.set_message(format!("{}: {}, retrying in {}", url, reason, HumanDuration(left)));
+        let response = self.retry_after.send(request, self.cancellation, self.report, countdown, |request| match self.negotiator {
+            Some(negotiator) => negotiator.execute(self.client, request, self.header_timeout),
+            None => timeouts::execute(self.client, request, self.header_timeout),
+        });
+        let response = response.map_err(|e| Failure::new(ExitCode::Network, e.to_string()))?;
+        self.middleware.intercept(&response, &DownloadContext { method: &Method::GET, url }).map_err(|e| format!("{}: {}", url, e))?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            debug!("{} not modified", url);
+            return Ok(Outcome::NotModified);
+        }
+        let last_modified = match self.deterministic {
+            Some(time) => Some(httpdate::fmt_http_date(time)),
+            None => response
+                .headers()
+                .get(header::LAST_MODIFIED)
+                .and_then(|value| value.to_str().ok())
+                .filter(|_| self.preserve_time)
+                .map(str::to_string),
+        };
+        let status = response.status();
+        let response = response
+            .error_for_status()
+            .map_err(|e| Failure::new(ExitCode::for_http(status.as_u16()).unwrap_or(ExitCode::Error), e.to_string()))?;
+        let exchange = request_head.map(|head| Exchange::new(head, &response));
+        // The server's suggested name wins over the one in the URL we were
+        // redirected to, which wins over the one in the URL we asked for
+        let final_url = response.url().clone();
+        let path = match self.naming.server_name(response.headers()).or_else(|| self.naming.url_name(&final_url)) {
+            Some(filename) => place(&final_url, &filename).ok_or_else(|| format!("{}: the output template gives an empty name for {}", url, filename.display()))?,
+            None => url_path.ok_or_else(|| format!("{}: no filename could be detected from the URL or Content-Disposition headers", url))?,
+        };
+        if self.no_clobber && path.exists() {
+            self.progress.status(pb, format!("{}: exists, skipped", path.display()));
+            return Ok(Outcome::Skipped(path));
+        }
+        let mut body = Vec::with_capacity(response.content_length().unwrap_or(0) as usize);
+        // Nothing's written until the whole body is in, so stopping part way leaves nothing behind
+        throttle::throttled(response, self.cancellation, self.limiter.cloned(), self.min_speed)
+            .read_to_end(&mut body)
+            .map_err(|e| Failure::new(ExitCode::Network, format!("{}: {}", url, e)))?;
+        debug!("Fetched {} ({} bytes)", url, body.len());
+        if let (Some(warc), Some(exchange)) = (self.warc, &exchange) {
+            warc.write(exchange, &mut body.as_slice(), body.len() as u64).map_err(|e| e.to_string())?;
+        }
+
+        let plan = checksum_db::checksum_plan(self.checksum, self.checksum_db, url);
+        let checksums: Vec<Checksum> = plan
+            .algorithms
+            .iter()
+            .map(|&algorithm| {
+                let mut hasher = algorithm.hasher();
+                hasher.update(&body);
+                Checksum { algorithm, digest: hex::encode(hasher.finalize()) }
+            })
+            .collect();
+        plan.verify(&checksums).map_err(|e| Failure::new(ExitCode::Checksum, format!("{}: {}", path.display(), e)))?;
+        let unchecked = plan.unchecked(&checksums).cloned().collect();
+
+        Ok(Outcome::Fetched(Fetched { url: url.to_string(), path, body, last_modified, elapsed: started.elapsed(), status, checksums, unchecked }))
+    }
+
+    /// Claim a name for a fetched file and write it there, returning whether that worked
+    fn save(&self, fetched: Fetched, pb: &ProgressBar, failed: &AtomicUsize, directories: &mut HashSet<PathBuf>) -> bool {
+        let path = self.naming.claim(fetched.path);
+        pb.inc(1);
+        if let Err(e) = write_file(&path, &fetched.body, directories) {
+            self.fail(pb, failed, &fetched.url, format!("{}: {}", path.display(), e));
+            return false;
+        }
+        self.report.downloaded(&fetched.url, &path, fetched.body.len() as u64, fetched.elapsed, Some(fetched.status.as_u16()), &fetched.checksums);
+        if let Some(last_modified) = &fetched.last_modified
+            && let Err(e) = timestamping::set_modified(&path, last_modified)
+        {
+            warn!("Could not set modification time of {}: {}", path.display(), e);
+        }
+        for actual in &fetched.unchecked {
+            self.progress.println(pb, format!("{}  {}", actual, path.display()));
+        }
+        true
+    }
+
+    fn fail(&self, pb: &ProgressBar, failed: &AtomicUsize, url: &str, failure: impl Into<Failure>) {
+        let failure = failure.into();
+        failed.fetch_add(1, Ordering::Relaxed);
+        self.notifier.failure(url, &failure.message);
+        self.progress.error(pb, format!("[error] {}", failure));
+        self.report.failed(url, None, None, failure);
+    }
+}
+
+/// Write a downloaded file, creating each parent directory only the first time we see it
+fn write_file(path: &Path, body: &[u8], directories: &mut HashSet<PathBuf>) -> std::io::Result<()> {
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty())
+        && !directories.contains(parent)
+    {
+        fs::create_dir_all(parent)?;
+        directories.insert(parent.to_path_buf());
+    }
+    fs::write(path, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::TimeZone;
+    use crate::naming::StandardNames;
+
+    #[test]
+    fn test_write_file_creates_parent_once() {
+        let dir = std::env::temp_dir().join(format!("rustdl-batch-{}", std::process::id()));
+        let mut directories = HashSet::new();
+        let first = dir.join("nested/a.txt");
+        let second = dir.join("nested/b.txt");
+
+        write_file(&first, b"a", &mut directories).unwrap();
+        write_file(&second, b"b", &mut directories).unwrap();
+
+        assert_eq!(directories.len(), 1);
+        assert_eq!(fs::read(&second).unwrap(), b"b");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_run_reports_unusable_urls() {
+        let client = Client::new();
+        let middleware = Middleware::default();
+        let notifier = Notifier::with_sinks(Vec::new(), false, false);
+        let oauth = OAuthSettings::default();
+        let batch = SmallFileBatch {
+            client: &client,
+            middleware: &middleware,
+            notifier: &notifier,
+            checksum: &[],
+            checksum_db: None,
+            credentials: None,
+            auth_hook: None,
+            oauth: &oauth,
+            header_timeout: None,
+            retry_after: RetryAfter::default(),
+            negotiator: None,
+            timestamping: false,
+            preserve_time: false,
+            no_clobber: false,
+            naming: &StandardNames::default(),
+            limiter: None,
+            min_speed: None,
+            politeness: &Arc::default(),
+            warc: None,
+            report: &Report::new(None, None, None, TimeZone::Utc),
+            progress: ProgressMode::Bars,
+            jobs: 4,
+            cancellation: &Cancellation::default(),
+            deterministic: None,
+        };
+
+        let summary = batch.run(vec!["not a url".to_string(), "https://example.com/".to_string()]);
+        assert_eq!(summary, BatchSummary { succeeded: 0, failed: 2, skipped: 0 });
+    }
+
+    #[test]
+    fn test_no_clobber_skips_without_requesting() {
+        let client = Client::new();
+        let middleware = Middleware::default();
+        let notifier = Notifier::with_sinks(Vec::new(), false, false);
+        let oauth = OAuthSettings::default();
+        let batch = SmallFileBatch {
+            client: &client,
+            middleware: &middleware,
+            notifier: &notifier,
+            checksum: &[],
+            checksum_db: None,
+            credentials: None,
+            auth_hook: None,
+            oauth: &oauth,
+            header_timeout: None,
+            retry_after: RetryAfter::default(),
+            negotiator: None,
+            timestamping: false,
+            preserve_time: false,
+            no_clobber: true,
+            naming: &StandardNames::default(),
+            limiter: None,
+            min_speed: None,
+            politeness: &Arc::default(),
+            warc: None,
+            report: &Report::new(None, None, None, TimeZone::Utc),
+            progress: ProgressMode::Bars,
+            jobs: 1,
+            cancellation: &Cancellation::default(),
+            deterministic: None,
+        };
+
+        // Tests run from the crate root, so Cargo.toml exists; nothing listens on port 9
+        let summary = batch.run(vec!["http://127.0.0.1:9/Cargo.toml".to_string()]);
+        assert_eq!(summary, BatchSummary { succeeded: 0, failed: 0, skipped: 1 });
+    }
+}
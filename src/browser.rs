@@ -225,6 +225,7 @@ impl BrowserError {
 }
 
 /// Firefox browser strategy implementation
+#[derive(Default)]
 pub struct FirefoxStrategy;
 
 impl FirefoxStrategy {
@@ -293,6 +294,7 @@ impl BrowserStrategy for FirefoxStrategy {
 }
 
 /// Chrome browser strategy implementation
+#[derive(Default)]
 pub struct ChromeStrategy;
 
 impl ChromeStrategy {
@@ -368,6 +370,7 @@ impl BrowserStrategy for ChromeStrategy {
     }
 }
 
+#[derive(Default)]
 pub struct ChromiumStrategy;
 
 impl ChromiumStrategy {
@@ -444,6 +447,7 @@ impl BrowserStrategy for ChromiumStrategy {
 }
 
 /// Safari browser strategy implementation
+#[derive(Default)]
 pub struct SafariStrategy;
 
 impl SafariStrategy {
@@ -512,6 +516,7 @@ impl BrowserStrategy for SafariStrategy {
 }
 
 /// Edge browser strategy implementation
+#[derive(Default)]
 pub struct EdgeStrategy;
 
 impl EdgeStrategy {
@@ -719,8 +724,8 @@ impl CookieManager {
         self.strategy.browser_name()
     }
 
-    /// Create a CookieManager with a custom strategy (for testing)
-    #[cfg(test)]
+    /// Create a CookieManager with a custom strategy, for cookies from
+    /// somewhere other than the supported browsers
     pub fn with_strategy(strategy: Box<dyn BrowserStrategy>) -> Self {
         Self { strategy }
     }
@@ -1019,7 +1024,7 @@ mod tests {
             fn fetch_cookies(&self, domains: Vec<String>) -> Result<Vec<Cookie>, BrowserError> {
                 if self.should_succeed {
                     Ok(vec![Cookie {
-                        domain: domains.get(0).unwrap_or(&"example.com".to_string()).clone(),
+                        domain: domains.first().unwrap_or(&"example.com".to_string()).clone(),
                         path: "/".to_string(),
                         name: "test_cookie".to_string(),
                         value: "test_value".to_string(),
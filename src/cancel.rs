@@ -0,0 +1,617 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use indicatif::{ProgressBar, ProgressStyle, WeakProgressBar};
+use log::{debug, info, warn};
+
+use crate::partial;
+
+/// How often a paused transfer looks to see whether it's been resumed
+const PAUSE_POLL: Duration = Duration::from_millis(100);
+
+/// Why downloads were stopped before they finished
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CancelReason {
+    /// Ctrl-C
+    Interrupted,
+    /// SIGTERM, from a service manager or `kill`
+    Terminated,
+    /// Whatever's driving the download asked for it to stop
+    Requested,
+    /// `--deadline` passed
+    Deadline,
+    /// Another download failed, and we were asked to stop at the first failure (`--fail-fast`)
+    Failed,
+}
+
+impl CancelReason {
+    fn code(self) -> u8 {
+        match self {
+            CancelReason::Interrupted => 1,
+            CancelReason::Requested => 2,
+            CancelReason::Deadline => 3,
+            CancelReason::Failed => 4,
+            CancelReason::Terminated => 5,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(CancelReason::Interrupted),
+            2 => Some(CancelReason::Requested),
+            3 => Some(CancelReason::Deadline),
+            4 => Some(CancelReason::Failed),
+            5 => Some(CancelReason::Terminated),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CancelReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CancelReason::Interrupted => write!(f, "interrupted"),
+            CancelReason::Terminated => write!(f, "terminated"),
+            CancelReason::Requested => write!(f, "cancelled"),
+            CancelReason::Deadline => write!(f, "deadline passed"),
+            CancelReason::Failed => write!(f, "stopped after another download failed"),
+        }
+    }
+}
+
+/// The error a cancelled transfer stops with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("download {0}")]
+pub struct Cancelled(pub CancelReason);
+
+/// What to do with a half-finished download when it's cancelled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CancelPolicy {
+    /// Leave the `.part` file for `download resume`
+    #[default]
+    KeepPart,
+    /// Remove it
+    Delete,
+    /// Ask on the terminal, keeping it if there's nobody to ask
+    Ask,
+}
+
+impl CancelPolicy {
+    /// Get all supported policies
+    pub fn all() -> Vec<CancelPolicy> {
+        vec![CancelPolicy::KeepPart, CancelPolicy::Delete, CancelPolicy::Ask]
+    }
+
+    /// Get the string representation of the policy
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CancelPolicy::KeepPart => "keep-part",
+            CancelPolicy::Delete => "delete",
+            CancelPolicy::Ask => "ask",
+        }
+    }
+}
+
+impl fmt::Display for CancelPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for CancelPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        CancelPolicy::all()
+            .into_iter()
+            .find(|policy| policy.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                format!(
+                    "Unknown cancel policy '{}'. Available policies: {}",
+                    s,
+                    CancelPolicy::all().iter().map(|p| p.as_str()).collect::<Vec<_>>().join(", ")
+                )
+            })
+    }
+}
+
+/// Shared by every transfer in a run: once it's cancelled, they all stop at
+/// their next read or write, and their partial files are dealt with according
+/// to the policy. While it's paused, they wait at their next read or write
+/// instead, with their connections left open.
+#[derive(Debug, Clone, Default)]
+pub struct Cancellation {
+    reason: Arc<AtomicU8>,
+    policy: CancelPolicy,
+    paused: Arc<AtomicBool>,
+    bars: Arc<PausedBars>,
+    /// How many partial downloads were kept and deleted once cancelled
+    kept: Arc<AtomicUsize>,
+    deleted: Arc<AtomicUsize>,
+    /// Those kept until there's nothing else using the terminal to ask
+    /// whether to keep them with
+    undecided: Arc<Mutex<Vec<PathBuf>>>,
+    /// For one download's own, the run's, which stops and pauses it too
+    parent: Option<Box<Cancellation>>,
+}
+
+/// The cancellation Ctrl-C triggers, if there is one
+static ON_INTERRUPT: OnceLock<Cancellation> = OnceLock::new();
+
+/// The cancellation Ctrl-Z and SIGCONT pause and resume, if there is one
+static ON_SUSPEND: OnceLock<Cancellation> = OnceLock::new();
+
+/// When transfers were last resumed, in milliseconds since `STARTED` (0 if
+/// they never have been), so timers can leave out the time they were paused
+static RESUMED: AtomicU64 = AtomicU64::new(0);
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+impl Cancellation {
+    pub fn new(policy: CancelPolicy) -> Self {
+        Cancellation { policy, ..Default::default() }
+    }
+
+    /// Stop everything; the first reason given is the one that sticks
+    pub fn cancel(&self, reason: CancelReason) {
+        let _ = self.reason.compare_exchange(0, reason.code(), Ordering::SeqCst, Ordering::SeqCst);
+    }
+
+    /// Why we were cancelled, if we were
+    pub fn reason(&self) -> Option<CancelReason> {
+        CancelReason::from_code(self.reason.load(Ordering::SeqCst)).or_else(|| self.parent.as_ref().and_then(|parent| parent.reason()))
+    }
+
+    /// One for a single download, which can be paused and cancelled without
+    /// the others, and is whenever this one is
+    pub fn child(&self) -> Cancellation {
+        Cancellation {
+            reason: Arc::default(),
+            paused: Arc::default(),
+            bars: Arc::default(),
+            parent: Some(Box::new(self.clone())),
+            ..self.clone()
+        }
+    }
+
+    /// An error to stop a transfer with, if it should stop; while paused,
+    /// this waits until it's resumed (or cancelled)
+    pub fn check(&self) -> io::Result<()> {
+        while self.is_paused() && self.reason().is_none() {
+            thread::sleep(PAUSE_POLL);
+        }
+        match self.reason() {
+            Some(reason) => Err(io::Error::other(Cancelled(reason))),
+            None => Ok(()),
+        }
+    }
+
+    /// Cancel on the first Ctrl-C or SIGTERM; a second one stops the process right away
+    #[cfg(unix)]
+    pub fn on_interrupt(&self) {
+        if ON_INTERRUPT.set(self.clone()).is_err() {
+            return;
+        }
+        extern "C" fn interrupted(signal: libc::c_int) {
+            // Only atomics in here: this runs in the signal handler
+            if let Some(cancellation) = ON_INTERRUPT.get() {
+                let reason = if signal == libc::SIGTERM { CancelReason::Terminated } else { CancelReason::Interrupted };
+                cancellation.reason.store(reason.code(), Ordering::SeqCst);
+            }
+            unsafe { libc::signal(signal, libc::SIG_DFL) };
+        }
+        let handler: extern "C" fn(libc::c_int) = interrupted;
+        unsafe {
+            libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+            libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+        }
+    }
+
+    /// Ctrl-C and SIGTERM keep their default behaviour of stopping the process at once
+    #[cfg(not(unix))]
+    pub fn on_interrupt(&self) {}
+
+    /// Hold every transfer at its next read or write
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+        self.bars.show(true);
+    }
+
+    /// Let paused transfers carry on
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+        let started = *STARTED.get_or_init(Instant::now);
+        RESUMED.store((started.elapsed().as_millis() as u64).max(1), Ordering::SeqCst);
+        self.bars.show(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst) || self.parent.as_ref().is_some_and(|parent| parent.is_paused())
+    }
+
+    /// Show `pb` in the `paused` style whenever transfers are paused
+    pub fn track(&self, pb: &ProgressBar, paused: ProgressStyle) {
+        if let Some(parent) = &self.parent {
+            parent.track(pb, paused.clone());
+        }
+        self.bars.track(pb, paused);
+        if self.paused.load(Ordering::SeqCst) {
+            self.bars.show(true);
+        }
+    }
+
+    /// Pause on Ctrl-Z (SIGTSTP) instead of stopping the process, where the
+    /// servers would time out on us, and resume on SIGCONT; with `keys`,
+    /// pressing `p` on the terminal pauses and resumes too
+    #[cfg(unix)]
+    pub fn on_suspend(&self, keys: bool) {
+        if ON_SUSPEND.set(self.clone()).is_err() {
+            return;
+        }
+        extern "C" fn suspended(signal: libc::c_int) {
+            // Only atomics in here: the watcher below does the rest
+            if let Some(cancellation) = ON_SUSPEND.get() {
+                cancellation.paused.store(signal == libc::SIGTSTP, Ordering::SeqCst);
+            }
+        }
+        let handler: extern "C" fn(libc::c_int) = suspended;
+        unsafe {
+            libc::signal(libc::SIGTSTP, handler as libc::sighandler_t);
+            libc::signal(libc::SIGCONT, handler as libc::sighandler_t);
+        }
+        let cancellation = self.clone();
+        let keys = keys.then(keyboard::Keys::open).flatten();
+        thread::spawn(move || cancellation.watch(keys));
+    }
+
+    /// Ctrl-Z keeps its default behaviour of stopping the process
+    #[cfg(not(unix))]
+    pub fn on_suspend(&self, _keys: bool) {}
+
+    /// Act on signals and keypresses until we're cancelled, when the
+    /// terminal's given back for `--on-cancel ask`
+    #[cfg(unix)]
+    fn watch(&self, mut keys: Option<keyboard::Keys>) {
+        let mut shown = false;
+        while self.reason().is_none() {
+            match keys.as_mut().map(|keys| keys.next(PAUSE_POLL)) {
+                Some(Some(b'p' | b'P')) if self.is_paused() => self.paused.store(false, Ordering::SeqCst),
+                Some(Some(b'p' | b'P')) => self.paused.store(true, Ordering::SeqCst),
+                Some(_) => {}
+                None => thread::sleep(PAUSE_POLL),
+            }
+            let paused = self.is_paused();
+            if paused != shown {
+                match paused {
+                    true => self.pause(),
+                    false => self.resume(),
+                }
+                info!("Downloads {}", if paused { "paused" } else { "resumed" });
+                shown = paused;
+            }
+        }
+    }
+
+    /// Cancel once `limit` has passed
+    pub fn deadline(&self, limit: Duration) {
+        let cancellation = self.clone();
+        thread::spawn(move || {
+            thread::sleep(limit);
+            if cancellation.reason().is_none() {
+                warn!("Deadline of {} passed, stopping downloads", humantime::format_duration(limit));
+                cancellation.cancel(CancelReason::Deadline);
+            }
+        });
+    }
+
+    /// Whether a cancelled download's partial file at `path` should be kept.
+    /// Transfers stop on their own threads, while progress bars are still
+    /// drawn, so with `ask` they're kept for now and asked about afterwards.
+    pub fn keep(&self, path: &Path) -> bool {
+        let keep = match self.policy {
+            CancelPolicy::KeepPart => true,
+            CancelPolicy::Delete => false,
+            CancelPolicy::Ask => {
+                self.undecided.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(path.to_path_buf());
+                true
+            }
+        };
+        match keep {
+            true => self.kept.fetch_add(1, Ordering::SeqCst),
+            false => self.deleted.fetch_add(1, Ordering::SeqCst),
+        };
+        keep
+    }
+
+    /// With `--on-cancel ask`, once every transfer has stopped, ask on the
+    /// terminal about each partial download that was kept, deleting those
+    /// (and their control files) that aren't wanted
+    pub fn ask_about_kept(&self) {
+        let undecided = std::mem::take(&mut *self.undecided.lock().unwrap_or_else(|poisoned| poisoned.into_inner()));
+        for path in undecided {
+            if !ask(&path) {
+                let _ = fs::remove_file(&path);
+                let _ = fs::remove_file(partial::with_suffix(&path, ".json"));
+                info!("Deleted partial download {}", path.display());
+                self.kept.fetch_sub(1, Ordering::SeqCst);
+                self.deleted.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// How many partial downloads have been kept and deleted
+    pub fn leftovers(&self) -> (usize, usize) {
+        (self.kept.load(Ordering::SeqCst), self.deleted.load(Ordering::SeqCst))
+    }
+
+    /// Wrap a response body so it stops being read once we're cancelled
+    pub fn reader<R: Read>(&self, reader: R) -> CancellableReader<R> {
+        CancellableReader { inner: reader, cancellation: self.clone() }
+    }
+}
+
+/// Ask whether to keep a partial file; without a terminal to ask on, it's kept
+fn ask(path: &Path) -> bool {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        debug!("Nobody to ask about {}, keeping it", path.display());
+        return true;
+    }
+    eprint!("\nKeep partial download {} to resume later? [Y/n] ", path.display());
+    let _ = io::stderr().flush();
+    let mut answer = String::new();
+    if stdin.lock().read_line(&mut answer).is_err() {
+        return true;
+    }
+    !answer.trim().to_ascii_lowercase().starts_with('n')
+}
+
+/// The bars to restyle while paused, with what to put back afterwards
+#[derive(Default)]
+struct PausedBars(Mutex<Vec<PausedBar>>);
+
+struct PausedBar {
+    bar: WeakProgressBar,
+    paused: ProgressStyle,
+    /// The style and message the bar had before it was paused
+    saved: Option<(ProgressStyle, String)>,
+}
+
+impl PausedBars {
+    fn track(&self, pb: &ProgressBar, paused: ProgressStyle) {
+        let mut bars = self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        bars.retain(|bar| bar.bar.upgrade().is_some_and(|pb| !pb.is_finished()));
+        bars.push(PausedBar { bar: pb.downgrade(), paused, saved: None });
+    }
+
+    fn show(&self, paused: bool) {
+        let mut bars = self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for bar in bars.iter_mut() {
+            let Some(pb) = bar.bar.upgrade().filter(|pb| !pb.is_finished()) else { continue };
+            match (paused, bar.saved.take()) {
+                (true, None) => {
+                    bar.saved = Some((pb.style(), pb.message()));
+                    pb.set_style(bar.paused.clone());
+                    pb.set_message("paused");
+                }
+                (false, Some((style, message))) => {
+                    pb.set_style(style);
+                    pb.set_message(message);
+                }
+                (_, saved) => bar.saved = saved,
+            }
+        }
+    }
+}
+
+impl fmt::Debug for PausedBars {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bars = self.0.lock().map_or(0, |bars| bars.len());
+        f.debug_tuple("PausedBars").field(&bars).finish()
+    }
+}
+
+/// Whether transfers were paused and resumed since `since`
+pub fn resumed_since(since: Instant) -> bool {
+    match RESUMED.load(Ordering::SeqCst) {
+        0 => false,
+        resumed => *STARTED.get_or_init(Instant::now) + Duration::from_millis(resumed) > since,
+    }
+}
+
+/// Single keypresses from the terminal, which is put back as it was when
+/// we're done with it or the process exits
+#[cfg(unix)]
+mod keyboard {
+    use std::io::{self, IsTerminal};
+    use std::sync::OnceLock;
+    use std::time::Duration;
+
+    /// The terminal's settings before we changed them
+    static ORIGINAL: OnceLock<libc::termios> = OnceLock::new();
+
+    pub struct Keys;
+
+    impl Keys {
+        /// Read keys as they're pressed, without waiting for Enter or echoing
+        /// them; `None` if stdin isn't a terminal, or isn't ours because
+        /// we're running in the background
+        pub fn open() -> Option<Self> {
+            if !io::stdin().is_terminal() || !foreground() {
+                return None;
+            }
+            let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+            if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+                return None;
+            }
+            ORIGINAL.set(termios).ok()?;
+            extern "C" fn restore_at_exit() {
+                restore();
+            }
+            unsafe { libc::atexit(restore_at_exit) };
+            termios.c_lflag &= !(libc::ICANON | libc::ECHO);
+            set(&termios);
+            Some(Keys)
+        }
+
+        /// The next key pressed within `timeout`
+        pub fn next(&mut self, timeout: Duration) -> Option<u8> {
+            // Reading the terminal from the background would stop us until we're brought back
+            if !foreground() {
+                std::thread::sleep(timeout);
+                return None;
+            }
+            let mut poll = libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 };
+            if unsafe { libc::poll(&mut poll, 1, timeout.as_millis() as libc::c_int) } <= 0 {
+                return None;
+            }
+            let mut key = 0u8;
+            match unsafe { libc::read(libc::STDIN_FILENO, (&mut key as *mut u8).cast(), 1) } {
+                1 => Some(key),
+                // End of input: don't spin on it
+                _ => {
+                    std::thread::sleep(timeout);
+                    None
+                }
+            }
+        }
+    }
+
+    impl Drop for Keys {
+        fn drop(&mut self) {
+            restore();
+        }
+    }
+
+    fn restore() {
+        if let Some(original) = ORIGINAL.get() {
+            set(original);
+        }
+    }
+
+    /// Whether we're in the terminal's foreground process group, the one its keys go to
+    fn foreground() -> bool {
+        unsafe { libc::tcgetpgrp(libc::STDIN_FILENO) == libc::getpgrp() }
+    }
+
+    /// Change the terminal's settings. From the background that would stop
+    /// us with SIGTTOU, so it's held off while we put things back the way
+    /// they were, say after being moved to the background with `bg`.
+    fn set(termios: &libc::termios) {
+        unsafe {
+            let mut blocked = std::mem::zeroed::<libc::sigset_t>();
+            let mut previous = std::mem::zeroed::<libc::sigset_t>();
+            libc::sigemptyset(&mut blocked);
+            libc::sigaddset(&mut blocked, libc::SIGTTOU);
+            libc::pthread_sigmask(libc::SIG_BLOCK, &blocked, &mut previous);
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, termios);
+            libc::pthread_sigmask(libc::SIG_SETMASK, &previous, std::ptr::null_mut());
+        }
+    }
+}
+
+/// A reader that fails with `Cancelled` once its cancellation has been triggered
+pub struct CancellableReader<R> {
+    inner: R,
+    cancellation: Cancellation,
+}
+
+impl<R: Read> Read for CancellableReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.cancellation.check()?;
+        self.inner.read(buf)
+    }
+}
+
+/// Why an I/O error happened, if it was because we were cancelled
+pub fn reason(error: &io::Error) -> Option<CancelReason> {
+    error.get_ref().and_then(|inner| inner.downcast_ref::<Cancelled>()).map(|cancelled| cancelled.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel_stops_reads() {
+        let cancellation = Cancellation::new(CancelPolicy::Delete);
+        let mut reader = cancellation.reader(io::repeat(b'x'));
+        let mut buffer = [0; 4];
+        assert_eq!(reader.read(&mut buffer).unwrap(), 4);
+
+        cancellation.clone().cancel(CancelReason::Deadline);
+        cancellation.cancel(CancelReason::Interrupted);
+        let error = reader.read(&mut buffer).unwrap_err();
+        assert_eq!(reason(&error), Some(CancelReason::Deadline));
+        assert_eq!(error.to_string(), "download deadline passed");
+        assert!(!cancellation.keep(Path::new("file.part")));
+        assert_eq!(cancellation.leftovers(), (0, 1));
+        assert_eq!(reason(&io::Error::other("something else")), None);
+    }
+
+    #[test]
+    fn test_child_stops_on_its_own() {
+        let run = Cancellation::new(CancelPolicy::KeepPart);
+        let (first, second) = (run.child(), run.child());
+        first.pause();
+        assert!(first.is_paused() && !second.is_paused() && !run.is_paused());
+        first.cancel(CancelReason::Requested);
+        assert_eq!((first.reason(), second.reason(), run.reason()), (Some(CancelReason::Requested), None, None));
+        run.cancel(CancelReason::Interrupted);
+        assert_eq!(second.reason(), Some(CancelReason::Interrupted));
+    }
+
+    #[test]
+    fn test_pause_holds_reads() {
+        let cancellation = Cancellation::new(CancelPolicy::KeepPart);
+        let pb = ProgressBar::hidden();
+        cancellation.track(&pb, ProgressStyle::with_template("{prefix} {msg}").unwrap());
+        let before = Instant::now();
+        cancellation.pause();
+        assert_eq!(pb.message(), "paused");
+
+        let reader = thread::spawn({
+            let cancellation = cancellation.clone();
+            move || cancellation.reader(io::repeat(b'x')).read(&mut [0; 4]).map_err(|e| reason(&e))
+        });
+        thread::sleep(PAUSE_POLL * 3);
+        assert!(!reader.is_finished());
+        cancellation.resume();
+        assert_eq!(reader.join().unwrap(), Ok(4));
+        assert!(resumed_since(before));
+        assert_eq!(pb.message(), "");
+
+        // Cancelling lets a paused transfer go, to stop
+        cancellation.pause();
+        cancellation.cancel(CancelReason::Interrupted);
+        assert_eq!(cancellation.check().map_err(|e| reason(&e)), Err(Some(CancelReason::Interrupted)));
+    }
+
+    #[test]
+    fn test_ask_waits_until_downloads_stop() {
+        let cancellation = Cancellation::new(CancelPolicy::Ask);
+        cancellation.cancel(CancelReason::Interrupted);
+        // Nothing's asked from the download's own thread
+        let kept = thread::spawn({
+            let cancellation = cancellation.clone();
+            move || cancellation.keep(Path::new("file.part"))
+        });
+        assert!(kept.join().unwrap());
+        // There's no terminal here to ask on, so it's kept
+        cancellation.ask_about_kept();
+        assert!(cancellation.undecided.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_cancel_policy_from_str() {
+        assert_eq!("keep-part".parse::<CancelPolicy>().unwrap(), CancelPolicy::KeepPart);
+        assert_eq!("ASK".parse::<CancelPolicy>().unwrap(), CancelPolicy::Ask);
+        assert!("shred".parse::<CancelPolicy>().unwrap_err().contains("Available policies: keep-part, delete, ask"));
+    }
+}
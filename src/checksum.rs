@@ -0,0 +1,490 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc::{self, SyncSender};
+use std::thread::{self, JoinHandle};
+
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+
+/// How much data to hand to the hashing thread at a time; large enough for
+/// blake3 to use its widest SIMD paths
+const HASH_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// How many chunks may be queued for the hashing thread before writes block
+const HASH_QUEUE_DEPTH: usize = 4;
+
+/// Hash algorithms we can compute while downloading
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    /// Get all supported hash algorithms
+    pub fn all() -> Vec<HashAlgorithm> {
+        vec![
+            HashAlgorithm::Md5,
+            HashAlgorithm::Sha1,
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Sha512,
+            HashAlgorithm::Blake3,
+        ]
+    }
+
+    /// Get the string representation of the algorithm
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Md5 => "md5",
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha512 => "sha512",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    /// Length of a digest produced by this algorithm, in bytes
+    pub fn digest_len(&self) -> usize {
+        match self {
+            HashAlgorithm::Md5 => 16,
+            HashAlgorithm::Sha1 => 20,
+            HashAlgorithm::Sha256 => 32,
+            HashAlgorithm::Sha512 => 64,
+            HashAlgorithm::Blake3 => 32,
+        }
+    }
+
+    /// Guess the algorithm from the length of a hex digest; blake3 is never
+    /// guessed since its digests are the same length as sha256
+    pub fn from_hex_len(len: usize) -> Option<HashAlgorithm> {
+        HashAlgorithm::all().into_iter().find(|algorithm| algorithm.digest_len() * 2 == len)
+    }
+
+    /// Create a fresh hasher for this algorithm
+    pub fn hasher(&self) -> Box<dyn Hasher> {
+        match self {
+            HashAlgorithm::Md5 => Box::new(DigestHasher(md5::Md5::new())),
+            HashAlgorithm::Sha1 => Box::new(DigestHasher(sha1::Sha1::new())),
+            HashAlgorithm::Sha256 => Box::new(DigestHasher(sha2::Sha256::new())),
+            HashAlgorithm::Sha512 => Box::new(DigestHasher(sha2::Sha512::new())),
+            HashAlgorithm::Blake3 => Box::new(blake3::Hasher::new()),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = ChecksumError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().replace('-', "").as_str() {
+            "md5" => Ok(HashAlgorithm::Md5),
+            "sha1" => Ok(HashAlgorithm::Sha1),
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "sha512" => Ok(HashAlgorithm::Sha512),
+            "blake3" | "b3" => Ok(HashAlgorithm::Blake3),
+            _ => Err(ChecksumError::UnknownAlgorithm { algorithm: s.to_string() }),
+        }
+    }
+}
+
+/// An incremental hash computation; every algorithm's backend implements this
+pub trait Hasher: Send {
+    /// Feed more data into the hash
+    fn update(&mut self, data: &[u8]);
+
+    /// Consume the hasher, returning the raw digest
+    fn finalize(self: Box<Self>) -> Vec<u8>;
+}
+
+/// Backend for the RustCrypto hashes, which pick SHA-NI/ARMv8 instructions at
+/// runtime (and assembly implementations with the `asm` feature)
+struct DigestHasher<D>(D);
+
+impl<D: Digest + Send> Hasher for DigestHasher<D> {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(&mut self.0, data);
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        self.0.finalize().to_vec()
+    }
+}
+
+/// blake3 detects SSE4.1/AVX2/AVX-512/NEON at runtime
+impl Hasher for blake3::Hasher {
+    fn update(&mut self, data: &[u8]) {
+        blake3::Hasher::update(self, data);
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        blake3::Hasher::finalize(&self).as_bytes().to_vec()
+    }
+}
+
+/// A digest along with the algorithm that produced it, written as `sha256:<hex>`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checksum {
+    pub algorithm: HashAlgorithm,
+    /// Lowercase hex-encoded digest
+    pub digest: String,
+}
+
+impl Checksum {
+    /// Create a checksum from an algorithm and hex digest, validating the digest
+    pub fn new(algorithm: HashAlgorithm, digest: &str) -> Result<Self, ChecksumError> {
+        let digest = digest.trim().to_lowercase();
+        let valid = digest.len() == algorithm.digest_len() * 2
+            && digest.chars().all(|c| c.is_ascii_hexdigit());
+        if !valid {
+            return Err(ChecksumError::InvalidDigest { algorithm, digest });
+        }
+        Ok(Self { algorithm, digest })
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.digest)
+    }
+}
+
+impl FromStr for Checksum {
+    type Err = ChecksumError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((algorithm, digest)) => Checksum::new(algorithm.parse()?, digest),
+            None => Err(ChecksumError::MissingAlgorithm { value: s.to_string() }),
+        }
+    }
+}
+
+/// What the user asked for with `--checksum`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChecksumSpec {
+    /// Just compute and print the digest
+    Compute(HashAlgorithm),
+    /// Compute the digest and fail if it doesn't match
+    Verify(Checksum),
+}
+
+impl ChecksumSpec {
+    pub fn algorithm(&self) -> HashAlgorithm {
+        match self {
+            ChecksumSpec::Compute(algorithm) => *algorithm,
+            ChecksumSpec::Verify(checksum) => checksum.algorithm,
+        }
+    }
+}
+
+impl FromStr for ChecksumSpec {
+    type Err = ChecksumError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.contains(':') {
+            Ok(ChecksumSpec::Verify(s.parse()?))
+        } else {
+            Ok(ChecksumSpec::Compute(s.parse()?))
+        }
+    }
+}
+
+/// The checksums for one download: every digest to compute, in a single
+/// pass, and the ones they have to match
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChecksumPlan {
+    pub algorithms: Vec<HashAlgorithm>,
+    pub expected: Vec<Checksum>,
+}
+
+impl ChecksumPlan {
+    /// What `--checksum` asks for; `known` (from the checksum database) is
+    /// only checked if none of them gives a digest
+    pub fn new(specs: &[ChecksumSpec], known: Option<Checksum>) -> Self {
+        let mut expected: Vec<Checksum> = specs
+            .iter()
+            .filter_map(|spec| match spec {
+                ChecksumSpec::Verify(checksum) => Some(checksum.clone()),
+                ChecksumSpec::Compute(_) => None,
+            })
+            .collect();
+        if expected.is_empty() {
+            expected.extend(known);
+        }
+        let mut algorithms = Vec::new();
+        for algorithm in expected.iter().map(|c| c.algorithm).chain(specs.iter().map(ChecksumSpec::algorithm)) {
+            if !algorithms.contains(&algorithm) {
+                algorithms.push(algorithm);
+            }
+        }
+        ChecksumPlan { algorithms, expected }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.algorithms.is_empty()
+    }
+
+    /// Check the computed digests against every expected one
+    pub fn verify(&self, actual: &[Checksum]) -> Result<(), ChecksumError> {
+        for expected in &self.expected {
+            match actual.iter().find(|actual| actual.algorithm == expected.algorithm) {
+                Some(actual) => verify(expected, actual)?,
+                None => return Err(ChecksumError::NotComputed { algorithm: expected.algorithm }),
+            }
+        }
+        Ok(())
+    }
+
+    /// The computed digests nothing was expected of, which get printed instead
+    pub fn unchecked<'a>(&'a self, actual: &'a [Checksum]) -> impl Iterator<Item = &'a Checksum> {
+        actual.iter().filter(|actual| !self.expected.iter().any(|expected| expected.algorithm == actual.algorithm))
+    }
+}
+
+/// Errors related to checksums
+#[derive(Debug, thiserror::Error)]
+pub enum ChecksumError {
+    #[error("Unknown hash algorithm '{algorithm}'. Supported algorithms: {}",
+            HashAlgorithm::all().iter().map(|a| a.as_str()).collect::<Vec<_>>().join(", "))]
+    UnknownAlgorithm { algorithm: String },
+
+    #[error("'{digest}' is not a valid {algorithm} digest")]
+    InvalidDigest { algorithm: HashAlgorithm, digest: String },
+
+    #[error("'{value}' is missing a hash algorithm; expected something like sha256:<hex>")]
+    MissingAlgorithm { value: String },
+
+    #[error("checksum mismatch: expected {expected}, got {actual}")]
+    Mismatch { expected: Checksum, actual: Checksum },
+
+    #[error("no {algorithm} digest was computed to check")]
+    NotComputed { algorithm: HashAlgorithm },
+}
+
+/// Check a computed checksum against the expected one
+pub fn verify(expected: &Checksum, actual: &Checksum) -> Result<(), ChecksumError> {
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(ChecksumError::Mismatch { expected: expected.clone(), actual: actual.clone() })
+    }
+}
+
+/// Hash a file that's already on disk
+pub fn file_checksum(path: &Path, algorithm: HashAlgorithm) -> io::Result<Checksum> {
+    let mut checksums = file_checksums(path, &[algorithm])?;
+    Ok(checksums.remove(0))
+}
+
+/// Hash a file that's already on disk with several algorithms, reading it once
+pub fn file_checksums(path: &Path, algorithms: &[HashAlgorithm]) -> io::Result<Vec<Checksum>> {
+    let mut writer = HashingWriter::with_algorithms(io::sink(), algorithms);
+    io::copy(&mut File::open(path)?, &mut writer)?;
+    Ok(writer.finish_all().1)
+}
+
+/// Writer that hashes everything passing through it. Hashing happens on a
+/// separate thread so it overlaps with network and disk I/O instead of
+/// slowing down every write.
+pub struct HashingWriter<W> {
+    inner: W,
+    algorithms: Vec<HashAlgorithm>,
+    buffer: Vec<u8>,
+    sender: SyncSender<Vec<u8>>,
+    worker: JoinHandle<Vec<Vec<u8>>>,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W, algorithm: HashAlgorithm) -> Self {
+        Self::with_algorithms(inner, &[algorithm])
+    }
+
+    /// Hash with every one of `algorithms` at once, so the data only goes past once
+    pub fn with_algorithms(inner: W, algorithms: &[HashAlgorithm]) -> Self {
+        let algorithms = algorithms.to_vec();
+        let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(HASH_QUEUE_DEPTH);
+        let worker = thread::spawn({
+            let algorithms = algorithms.clone();
+            move || {
+                let mut hashers: Vec<_> = algorithms.iter().map(HashAlgorithm::hasher).collect();
+                for chunk in receiver {
+                    for hasher in &mut hashers {
+                        hasher.update(&chunk);
+                    }
+                }
+                hashers.into_iter().map(|hasher| hasher.finalize()).collect()
+            }
+        });
+        Self { inner, algorithms, buffer: Vec::with_capacity(HASH_CHUNK_SIZE), sender, worker }
+    }
+
+    /// Finish hashing, returning the inner writer and the checksum of everything written
+    pub fn finish(self) -> (W, Checksum) {
+        let (inner, mut checksums) = self.finish_all();
+        (inner, checksums.remove(0))
+    }
+
+    /// Finish hashing, returning the inner writer and a checksum for each algorithm
+    pub fn finish_all(self) -> (W, Vec<Checksum>) {
+        let Self { inner, algorithms, buffer, sender, worker } = self;
+        // If the worker has died the send fails, and join() below reports why
+        let _ = sender.send(buffer);
+        drop(sender);
+        let digests = worker.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        let checksums = algorithms
+            .into_iter()
+            .zip(digests)
+            .map(|(algorithm, digest)| Checksum { algorithm, digest: hex::encode(digest) })
+            .collect();
+        (inner, checksums)
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.buffer.extend_from_slice(&buf[..written]);
+        if self.buffer.len() >= HASH_CHUNK_SIZE {
+            let chunk = mem::replace(&mut self.buffer, Vec::with_capacity(HASH_CHUNK_SIZE));
+            self.sender
+                .send(chunk)
+                .map_err(|_| io::Error::other("hashing thread exited unexpectedly"))?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+    const BLAKE3_HELLO: &str = "ea8f163db38682925e4491c5e58d4bb3506ef8c14eb78a86e908c5624a67200f";
+
+    #[test]
+    fn test_hash_algorithm_from_str() {
+        assert_eq!("sha256".parse::<HashAlgorithm>().unwrap(), HashAlgorithm::Sha256);
+        assert_eq!("SHA-512".parse::<HashAlgorithm>().unwrap(), HashAlgorithm::Sha512);
+        assert_eq!("md5".parse::<HashAlgorithm>().unwrap(), HashAlgorithm::Md5);
+        assert!(matches!("crc32".parse::<HashAlgorithm>(), Err(ChecksumError::UnknownAlgorithm { .. })));
+    }
+
+    #[test]
+    fn test_hash_algorithm_from_hex_len() {
+        assert_eq!(HashAlgorithm::from_hex_len(32), Some(HashAlgorithm::Md5));
+        assert_eq!(HashAlgorithm::from_hex_len(64), Some(HashAlgorithm::Sha256));
+        assert_eq!(HashAlgorithm::from_hex_len(10), None);
+    }
+
+    #[test]
+    fn test_checksum_parse_and_display() {
+        let checksum: Checksum = format!("sha256:{}", HELLO_SHA256.to_uppercase()).parse().unwrap();
+        assert_eq!(checksum.algorithm, HashAlgorithm::Sha256);
+        assert_eq!(checksum.to_string(), format!("sha256:{}", HELLO_SHA256));
+    }
+
+    #[test]
+    fn test_checksum_rejects_bad_digest() {
+        assert!(matches!("sha256:abcd".parse::<Checksum>(), Err(ChecksumError::InvalidDigest { .. })));
+        assert!(matches!(HELLO_SHA256.parse::<Checksum>(), Err(ChecksumError::MissingAlgorithm { .. })));
+    }
+
+    #[test]
+    fn test_checksum_spec_parse() {
+        assert_eq!("sha1".parse::<ChecksumSpec>().unwrap(), ChecksumSpec::Compute(HashAlgorithm::Sha1));
+        let spec: ChecksumSpec = format!("sha256:{}", HELLO_SHA256).parse().unwrap();
+        assert!(matches!(spec, ChecksumSpec::Verify(_)));
+        assert_eq!(spec.algorithm(), HashAlgorithm::Sha256);
+    }
+
+    #[test]
+    fn test_hashing_writer() {
+        let mut writer = HashingWriter::new(Vec::new(), HashAlgorithm::Sha256);
+        writer.write_all(b"hel").unwrap();
+        writer.write_all(b"lo").unwrap();
+        let (output, checksum) = writer.finish();
+        assert_eq!(output, b"hello");
+        assert_eq!(checksum.digest, HELLO_SHA256);
+    }
+
+    #[test]
+    fn test_hashing_writer_large_input() {
+        // Spans several chunks so the hashing thread sees more than one message
+        let data: Vec<u8> = (0..HASH_CHUNK_SIZE * 3 + 17).map(|i| i as u8).collect();
+        for algorithm in HashAlgorithm::all() {
+            let mut writer = HashingWriter::new(io::sink(), algorithm);
+            for piece in data.chunks(64 * 1024 + 3) {
+                writer.write_all(piece).unwrap();
+            }
+            let (_, checksum) = writer.finish();
+
+            let mut hasher = algorithm.hasher();
+            hasher.update(&data);
+            assert_eq!(checksum.digest, hex::encode(hasher.finalize()), "{}", algorithm);
+        }
+    }
+
+    #[test]
+    fn test_hashing_writer_several_algorithms() {
+        let mut writer = HashingWriter::with_algorithms(io::sink(), &[HashAlgorithm::Md5, HashAlgorithm::Sha256]);
+        writer.write_all(b"hello").unwrap();
+        let (_, checksums) = writer.finish_all();
+        assert_eq!(checksums[0].to_string(), "md5:5d41402abc4b2a76b9719d911017c592");
+        assert_eq!(checksums[1].to_string(), format!("sha256:{}", HELLO_SHA256));
+    }
+
+    #[test]
+    fn test_checksum_plan() {
+        let md5 = Checksum::new(HashAlgorithm::Md5, "5d41402abc4b2a76b9719d911017c592").unwrap();
+        let sha256 = Checksum::new(HashAlgorithm::Sha256, HELLO_SHA256).unwrap();
+        let known = Checksum::new(HashAlgorithm::Sha1, "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d").unwrap();
+
+        // A digest on the command line wins over the database, and each algorithm is computed once
+        let plan = ChecksumPlan::new(&[ChecksumSpec::Compute(HashAlgorithm::Md5), ChecksumSpec::Verify(sha256.clone())], Some(known.clone()));
+        assert_eq!(plan.algorithms, vec![HashAlgorithm::Sha256, HashAlgorithm::Md5]);
+        let actual = vec![sha256.clone(), md5.clone()];
+        assert!(plan.verify(&actual).is_ok());
+        assert_eq!(plan.unchecked(&actual).collect::<Vec<_>>(), vec![&md5]);
+        assert!(matches!(plan.verify(std::slice::from_ref(&md5)), Err(ChecksumError::NotComputed { .. })));
+
+        let plan = ChecksumPlan::new(&[ChecksumSpec::Compute(HashAlgorithm::Md5)], Some(known.clone()));
+        assert_eq!(plan.algorithms, vec![HashAlgorithm::Sha1, HashAlgorithm::Md5]);
+        assert!(matches!(plan.verify(&[known.clone(), md5.clone()]), Ok(())));
+        assert!(ChecksumPlan::new(&[], None).is_empty());
+    }
+
+    #[test]
+    fn test_blake3() {
+        let mut writer = HashingWriter::new(Vec::new(), "b3".parse().unwrap());
+        writer.write_all(b"hello").unwrap();
+        let (_, checksum) = writer.finish();
+        assert_eq!(checksum.to_string(), format!("blake3:{}", BLAKE3_HELLO));
+        assert_eq!(HashAlgorithm::from_hex_len(BLAKE3_HELLO.len()), Some(HashAlgorithm::Sha256));
+    }
+
+    #[test]
+    fn test_verify_mismatch() {
+        let expected = Checksum::new(HashAlgorithm::Md5, "5d41402abc4b2a76b9719d911017c592").unwrap();
+        let actual = Checksum::new(HashAlgorithm::Md5, "00000000000000000000000000000000").unwrap();
+        assert!(verify(&expected, &expected.clone()).is_ok());
+        assert!(matches!(verify(&expected, &actual), Err(ChecksumError::Mismatch { .. })));
+    }
+}
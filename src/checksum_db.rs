@@ -0,0 +1,275 @@
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::checksum::{Checksum, ChecksumError, ChecksumPlan, ChecksumSpec, HashAlgorithm};
+
+/// Name of the database file inside the XDG data directory
+const DATABASE_FILE: &str = "checksums.json";
+
+/// An expected checksum for every URL matching `pattern`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KnownHash {
+    /// URL, optionally containing `*` wildcards
+    pub pattern: String,
+    pub checksum: Checksum,
+}
+
+impl KnownHash {
+    /// Check whether this entry applies to the given URL
+    pub fn matches(&self, url: &str) -> bool {
+        if !self.pattern.contains('*') {
+            return self.pattern == url;
+        }
+        let regex = format!(
+            "^{}$",
+            self.pattern.split('*').map(regex::escape).collect::<Vec<_>>().join(".*")
+        );
+        match Regex::new(&regex) {
+            Ok(regex) => regex.is_match(url),
+            Err(e) => {
+                warn!("Ignoring unusable checksum pattern {}: {}", self.pattern, e);
+                false
+            }
+        }
+    }
+}
+
+/// Errors from reading or updating the checksum database
+#[derive(Debug, thiserror::Error)]
+pub enum ChecksumDbError {
+    #[error("could not access checksum database {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("checksum database {path} is corrupt: {source}")]
+    Corrupt { path: PathBuf, source: serde_json::Error },
+
+    #[error("line {line}: {message}")]
+    InvalidLine { line: usize, message: String },
+
+    #[error(transparent)]
+    Checksum(#[from] ChecksumError),
+
+    #[error("could not determine where to store the checksum database")]
+    NoDataDir,
+}
+
+/// Local database of known-good checksums, stored as JSON under the XDG data dir
+#[derive(Debug)]
+pub struct ChecksumDatabase {
+    path: PathBuf,
+    entries: Vec<KnownHash>,
+}
+
+impl ChecksumDatabase {
+    /// Default location of the database
+    pub fn default_path() -> Result<PathBuf, ChecksumDbError> {
+        xdg::BaseDirectories::with_prefix("rustdl")
+            .get_data_home()
+            .map(|dir| dir.join(DATABASE_FILE))
+            .ok_or(ChecksumDbError::NoDataDir)
+    }
+
+    /// Open the database at the default location
+    pub fn open_default() -> Result<Self, ChecksumDbError> {
+        Self::open(Self::default_path()?)
+    }
+
+    /// Open the database at `path`; a missing file is treated as an empty database
+    pub fn open(path: PathBuf) -> Result<Self, ChecksumDbError> {
+        let entries = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map_err(|source| ChecksumDbError::Corrupt { path: path.clone(), source })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                debug!("No checksum database at {}, starting empty", path.display());
+                Vec::new()
+            }
+            Err(source) => return Err(ChecksumDbError::Io { path, source }),
+        };
+        Ok(Self { path, entries })
+    }
+
+    /// Write the database back to disk
+    pub fn save(&self) -> Result<(), ChecksumDbError> {
+        let io_err = |source| ChecksumDbError::Io { path: self.path.clone(), source };
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).map_err(io_err)?;
+        }
+        let contents = serde_json::to_vec_pretty(&self.entries)
+            .map_err(|source| ChecksumDbError::Corrupt { path: self.path.clone(), source })?;
+        fs::write(&self.path, contents).map_err(io_err)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn entries(&self) -> &[KnownHash] {
+        &self.entries
+    }
+
+    /// Add an entry, replacing any existing entry for the same pattern and algorithm
+    pub fn add(&mut self, pattern: &str, checksum: Checksum) {
+        self.entries.retain(|entry| !(entry.pattern == pattern && entry.checksum.algorithm == checksum.algorithm));
+        self.entries.push(KnownHash { pattern: pattern.to_string(), checksum });
+    }
+
+    /// Remove every entry for `pattern`, returning how many were removed
+    pub fn remove(&mut self, pattern: &str) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|entry| entry.pattern != pattern);
+        before - self.entries.len()
+    }
+
+    /// Find the expected checksum for a URL; exact matches win, then the longest matching pattern
+    pub fn lookup(&self, url: &str) -> Option<&Checksum> {
+        if let Some(entry) = self.entries.iter().find(|entry| entry.pattern == url) {
+            return Some(&entry.checksum);
+        }
+        self.entries
+            .iter()
+            .filter(|entry| entry.matches(url))
+            .max_by_key(|entry| entry.pattern.len())
+            .map(|entry| &entry.checksum)
+    }
+
+    /// Import a vendor checksum list (`sha256sum` or BSD `SHA256 (file) = hex` format),
+    /// mapping each filename onto `base_url`. Returns the number of entries imported.
+    pub fn import<R: BufRead>(
+        &mut self,
+        reader: R,
+        base_url: &str,
+        algorithm: Option<HashAlgorithm>,
+    ) -> Result<usize, ChecksumDbError> {
+        let base_url = if base_url.ends_with('/') { base_url.to_string() } else { format!("{}/", base_url) };
+        let mut imported = 0;
+
+        for (index, line) in reader.lines().enumerate() {
+            let line = line.map_err(|source| ChecksumDbError::Io { path: PathBuf::from("<import>"), source })?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (checksum, filename) = parse_sums_line(line, algorithm)
+                .map_err(|message| ChecksumDbError::InvalidLine { line: index + 1, message })?;
+            self.add(&format!("{}{}", base_url, filename), checksum);
+            imported += 1;
+        }
+
+        Ok(imported)
+    }
+}
+
+/// The checksums to compute for a download and what they have to match; an
+/// explicit `--checksum` digest wins over the database
+pub fn checksum_plan(specs: &[ChecksumSpec], database: Option<&ChecksumDatabase>, url: &str) -> ChecksumPlan {
+    ChecksumPlan::new(specs, database.and_then(|db| db.lookup(url)).cloned())
+}
+
+/// Parse a single line of a checksum list into a checksum and filename
+fn parse_sums_line(line: &str, algorithm: Option<HashAlgorithm>) -> Result<(Checksum, String), String> {
+    // BSD style: "SHA256 (file.tar.gz) = abcdef..."
+    if let Some((name, rest)) = line.split_once(" (")
+        && let Some((filename, digest)) = rest.rsplit_once(") = ")
+    {
+        let algorithm = match algorithm {
+            Some(algorithm) => algorithm,
+            None => name.parse().map_err(|e: ChecksumError| e.to_string())?,
+        };
+        let checksum = Checksum::new(algorithm, digest).map_err(|e| e.to_string())?;
+        return Ok((checksum, filename.to_string()));
+    }
+
+    // GNU style: "abcdef...  file.tar.gz" or "abcdef... *file.tar.gz" for binary mode
+    let (digest, filename) = line
+        .split_once(char::is_whitespace)
+        .ok_or_else(|| format!("expected '<digest>  <filename>', got '{}'", line))?;
+    let filename = filename.trim_start().trim_start_matches('*');
+    if filename.is_empty() {
+        return Err(format!("missing filename in '{}'", line));
+    }
+    let algorithm = algorithm
+        .or_else(|| HashAlgorithm::from_hex_len(digest.len()))
+        .ok_or_else(|| format!("cannot guess the hash algorithm for '{}'", digest))?;
+    let checksum = Checksum::new(algorithm, digest).map_err(|e| e.to_string())?;
+    Ok((checksum, filename.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA_A: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+    const SHA_B: &str = "486ea46224d1bb4fb680f34f7c9ad96a8f24ec88be73ea8e5a6c65260e9cb8a7";
+
+    fn empty_db() -> ChecksumDatabase {
+        ChecksumDatabase { path: PathBuf::from("/nonexistent/checksums.json"), entries: Vec::new() }
+    }
+
+    fn sha256(digest: &str) -> Checksum {
+        Checksum::new(HashAlgorithm::Sha256, digest).unwrap()
+    }
+
+    #[test]
+    fn test_lookup_prefers_exact_then_longest_pattern() {
+        let mut db = empty_db();
+        db.add("https://example.com/*", sha256(SHA_A));
+        db.add("https://example.com/releases/*.iso", sha256(SHA_B));
+
+        assert_eq!(db.lookup("https://example.com/releases/os.iso"), Some(&sha256(SHA_B)));
+        assert_eq!(db.lookup("https://example.com/other.txt"), Some(&sha256(SHA_A)));
+        assert_eq!(db.lookup("https://other.com/os.iso"), None);
+
+        db.add("https://example.com/releases/os.iso", sha256(SHA_A));
+        assert_eq!(db.lookup("https://example.com/releases/os.iso"), Some(&sha256(SHA_A)));
+    }
+
+    #[test]
+    fn test_pattern_escapes_regex_characters() {
+        let entry = KnownHash { pattern: "https://example.com/a+b?.tar".to_string(), checksum: sha256(SHA_A) };
+        assert!(entry.matches("https://example.com/a+b?.tar"));
+        assert!(!entry.matches("https://example.com/aab.tar"));
+    }
+
+    #[test]
+    fn test_add_replaces_and_remove() {
+        let mut db = empty_db();
+        db.add("https://example.com/a", sha256(SHA_A));
+        db.add("https://example.com/a", sha256(SHA_B));
+        assert_eq!(db.entries().len(), 1);
+        assert_eq!(db.remove("https://example.com/a"), 1);
+        assert!(db.entries().is_empty());
+    }
+
+    #[test]
+    fn test_import_gnu_and_bsd_formats() {
+        let sums = format!(
+            "# vendor list\n{}  os.iso\n{} *tools.tar.gz\nSHA256 (notes.txt) = {}\n",
+            SHA_A, SHA_B, SHA_A
+        );
+        let mut db = empty_db();
+        let imported = db.import(sums.as_bytes(), "https://example.com/releases", None).unwrap();
+
+        assert_eq!(imported, 3);
+        assert_eq!(db.lookup("https://example.com/releases/os.iso"), Some(&sha256(SHA_A)));
+        assert_eq!(db.lookup("https://example.com/releases/tools.tar.gz"), Some(&sha256(SHA_B)));
+        assert_eq!(db.lookup("https://example.com/releases/notes.txt"), Some(&sha256(SHA_A)));
+    }
+
+    #[test]
+    fn test_import_reports_bad_line() {
+        let mut db = empty_db();
+        let result = db.import("nonsense\n".as_bytes(), "https://example.com/", None);
+        assert!(matches!(result, Err(ChecksumDbError::InvalidLine { line: 1, .. })));
+    }
+
+    #[test]
+    fn test_open_missing_file_is_empty() {
+        let db = ChecksumDatabase::open(PathBuf::from("/nonexistent/rustdl/checksums.json")).unwrap();
+        assert!(db.entries().is_empty());
+    }
+}
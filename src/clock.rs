@@ -0,0 +1,152 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Days, FixedOffset, Local, NaiveDate, NaiveTime, SecondsFormat, TimeDelta, Utc};
+
+/// The timezone times are shown in, and clock times are read in: local
+/// unless `--utc` says otherwise
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimeZone {
+    #[default]
+    Local,
+    Utc,
+}
+
+impl TimeZone {
+    pub fn new(utc: bool) -> Self {
+        if utc { TimeZone::Utc } else { TimeZone::Local }
+    }
+
+    /// `time` as an RFC 3339 timestamp, to the second, with this timezone's offset
+    pub fn format(self, time: SystemTime) -> String {
+        self.format_with(time, SecondsFormat::Secs)
+    }
+
+    /// Like `format`, to the millisecond
+    pub fn format_millis(self, time: SystemTime) -> String {
+        self.format_with(time, SecondsFormat::Millis)
+    }
+
+    fn format_with(self, time: SystemTime, precision: SecondsFormat) -> String {
+        match self {
+            TimeZone::Local => DateTime::<Local>::from(time).to_rfc3339_opts(precision, true),
+            TimeZone::Utc => DateTime::<Utc>::from(time).to_rfc3339_opts(precision, true),
+        }
+    }
+
+    /// The first moment after `now` that a clock in this timezone shows `time`
+    fn next(self, time: NaiveTime, now: SystemTime) -> SystemTime {
+        let today = match self {
+            TimeZone::Local => DateTime::<Local>::from(now).date_naive(),
+            TimeZone::Utc => DateTime::<Utc>::from(now).date_naive(),
+        };
+        (0..3)
+            .filter_map(|days| today.checked_add_days(Days::new(days)))
+            .filter_map(|date| self.at(date, time))
+            .find(|moment| *moment > now)
+            .unwrap_or(now)
+    }
+
+    /// `time` on `date`; a time skipped by a daylight saving change is taken
+    /// to mean an hour later
+    fn at(self, date: NaiveDate, time: NaiveTime) -> Option<SystemTime> {
+        let naive = date.and_time(time);
+        match self {
+            TimeZone::Utc => Some(naive.and_utc().into()),
+            TimeZone::Local => naive
+                .and_local_timezone(Local)
+                .earliest()
+                .or_else(|| (naive + TimeDelta::hours(1)).and_local_timezone(Local).earliest())
+                .map(SystemTime::from),
+        }
+    }
+}
+
+/// When to start downloading: a time of day (`02:00`, the next time the
+/// clock shows it), a full RFC 3339 date and time, or (from `--start-in`)
+/// a while from now
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartAt {
+    Clock(NaiveTime),
+    Moment(DateTime<FixedOffset>),
+    In(Duration),
+}
+
+impl StartAt {
+    /// The moment to start, in `timezone` for a time of day, or None for a
+    /// wait too long for the clock to count to
+    pub fn resolve(self, timezone: TimeZone, now: SystemTime) -> Option<SystemTime> {
+        match self {
+            StartAt::Clock(time) => Some(timezone.next(time, now)),
+            StartAt::Moment(moment) => Some(moment.into()),
+            StartAt::In(wait) => now.checked_add(wait),
+        }
+    }
+}
+
+impl FromStr for StartAt {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(moment) = DateTime::parse_from_rfc3339(s) {
+            return Ok(StartAt::Moment(moment));
+        }
+        ["%H:%M", "%H:%M:%S"]
+            .iter()
+            .find_map(|format| NaiveTime::parse_from_str(s, format).ok())
+            .map(StartAt::Clock)
+            .ok_or_else(|| format!("invalid start time '{}', expected a time of day like 02:00 or a date and time like 2026-03-01T02:00:00+01:00", s))
+    }
+}
+
+impl fmt::Display for StartAt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartAt::Clock(time) => write!(f, "{}", time.format("%H:%M:%S")),
+            StartAt::Moment(moment) => write!(f, "{}", moment.to_rfc3339()),
+            StartAt::In(wait) => write!(f, "in {}", humantime::format_duration(*wait)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_start_at() {
+        assert_eq!("02:00".parse(), Ok(StartAt::Clock(NaiveTime::from_hms_opt(2, 0, 0).unwrap())));
+        assert_eq!("23:15:30".parse(), Ok(StartAt::Clock(NaiveTime::from_hms_opt(23, 15, 30).unwrap())));
+        let StartAt::Moment(moment) = "2026-03-01T02:00:00+01:00".parse().unwrap() else {
+            panic!("expected a date and time");
+        };
+        assert_eq!(moment.timestamp(), 1772326800);
+        assert!("25:00".parse::<StartAt>().is_err());
+        assert!("tomorrow".parse::<StartAt>().is_err());
+    }
+
+    #[test]
+    fn test_resolve_start_at() {
+        // 2026-03-01T01:00:00Z
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1772326800);
+        let two_am = StartAt::Clock(NaiveTime::from_hms_opt(2, 0, 0).unwrap());
+        assert_eq!(two_am.resolve(TimeZone::Utc, now), Some(now + Duration::from_secs(3600)));
+        // A time that's already gone by today means tomorrow
+        let midnight = StartAt::Clock(NaiveTime::from_hms_opt(0, 0, 0).unwrap());
+        assert_eq!(midnight.resolve(TimeZone::Utc, now), Some(now + Duration::from_secs(23 * 3600)));
+        let moment: StartAt = "2026-03-01T02:00:00+01:00".parse().unwrap();
+        assert_eq!(moment.resolve(TimeZone::Local, now), Some(now));
+        assert_eq!(StartAt::In(Duration::from_secs(3 * 3600)).resolve(TimeZone::Local, now), Some(now + Duration::from_secs(3 * 3600)));
+        assert_eq!(StartAt::In(Duration::MAX).resolve(TimeZone::Local, now), None);
+    }
+
+    #[test]
+    fn test_format() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_millis(1772326800250);
+        assert_eq!(TimeZone::Utc.format(time), "2026-03-01T01:00:00Z");
+        assert_eq!(TimeZone::Utc.format_millis(time), "2026-03-01T01:00:00.250Z");
+        assert_eq!(TimeZone::Local.format(time).parse::<DateTime<FixedOffset>>().unwrap().timestamp(), 1772326800);
+    }
+}
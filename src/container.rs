@@ -0,0 +1,50 @@
+use std::env;
+use std::path::Path;
+
+use log::debug;
+
+/// Files container runtimes leave in the root filesystem
+const MARKER_FILES: &[&str] = &["/.dockerenv", "/run/.containerenv"];
+
+/// Environment variables set in containers
+const MARKER_VARIABLES: &[&str] = &["container", "KUBERNETES_SERVICE_HOST"];
+
+/// Set to take configuration from the environment alone
+const NO_CONFIG_VARIABLE: &str = "DOWNLOAD_NO_CONFIG";
+
+/// Whether we're running in a container (or were built for one with the
+/// `container` feature), where nobody is watching the terminal
+pub fn detect() -> bool {
+    if cfg!(feature = "container") {
+        debug!("Built with the container profile");
+        return true;
+    }
+    if let Some(marker) = MARKER_FILES.iter().find(|path| Path::new(path).exists()) {
+        debug!("Running in a container ({} exists)", marker);
+        return true;
+    }
+    if let Some(variable) = MARKER_VARIABLES.iter().find(|name| is_set(name)) {
+        debug!("Running in a container (${} is set)", variable);
+        return true;
+    }
+    false
+}
+
+/// Whether configuration comes only from the environment: when built with
+/// the `container` feature, or asked for with `$DOWNLOAD_NO_CONFIG`. Merely
+/// running in a container isn't enough, as toolbox and dev containers often
+/// have the user's home directory, config file and all, mounted
+pub fn environment_only() -> bool {
+    cfg!(feature = "container") || is_set(NO_CONFIG_VARIABLE)
+}
+
+/// Whether we're running in a CI job, where nobody is watching the terminal
+/// either, but the user's configuration and browser are as much theirs as anywhere
+pub fn ci() -> bool {
+    is_set("CI")
+}
+
+/// Whether an environment variable is set to something other than empty or "false"
+fn is_set(name: &str) -> bool {
+    env::var(name).is_ok_and(|value| !value.is_empty() && !value.eq_ignore_ascii_case("false"))
+}
@@ -0,0 +1,538 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use indicatif::{ProgressBar, WeakProgressBar};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::cancel::{CancelReason, Cancellation};
+use crate::daemon::{Control, DaemonError, Job, Progress, Queue, State};
+use crate::report::{Report, Status};
+
+/// Name of the socket, next to the queue in the XDG data directory
+const SOCKET_FILE: &str = "daemon.sock";
+
+/// Name of the socket a download in the foreground listens on, if no other is
+const BATCH_FILE: &str = "batch.sock";
+
+/// Errors from asking the daemon to do something
+#[derive(Debug, thiserror::Error)]
+pub enum SocketError {
+    #[error("{0}")]
+    Daemon(#[from] DaemonError),
+
+    #[error("talking to the daemon: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("the daemon's reply made no sense: {0}")]
+    Reply(#[from] serde_json::Error),
+
+    /// What went wrong, as the daemon put it
+    #[error("{0}")]
+    Refused(String),
+}
+
+/// Something to ask the daemon, sent as a line of JSON
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "lowercase")]
+pub enum Request {
+    /// One job, or all of them
+    Status { id: Option<u64> },
+    Pause { id: u64 },
+    Resume { id: u64 },
+    Cancel { id: u64 },
+}
+
+/// The daemon's answer, also a line of JSON
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Reply {
+    Jobs(Vec<Job>),
+    Error(String),
+}
+
+/// Default location of the daemon's socket
+#[cfg(not(windows))]
+pub fn default_path() -> Result<PathBuf, DaemonError> {
+    Ok(Queue::default_path()?.with_file_name(SOCKET_FILE))
+}
+
+/// The daemon's named pipe; pipes all share one namespace, so it's named
+/// after the user as well
+#[cfg(windows)]
+pub fn default_path() -> Result<PathBuf, DaemonError> {
+    Ok(pipe::path(SOCKET_FILE))
+}
+
+/// Where a download in the foreground can be reached, when no other is
+/// already listening there
+#[cfg(not(windows))]
+pub fn batch_path() -> Result<PathBuf, DaemonError> {
+    Ok(Queue::default_path()?.with_file_name(BATCH_FILE))
+}
+
+#[cfg(windows)]
+pub fn batch_path() -> Result<PathBuf, DaemonError> {
+    Ok(pipe::path(BATCH_FILE))
+}
+
+/// Where to look for something to ask, in turn: the daemon, then a download
+/// in the foreground
+pub fn paths() -> Result<Vec<PathBuf>, DaemonError> {
+    Ok(vec![default_path()?, batch_path()?])
+}
+
+/// What `download status`, `pause`, `resume` and `cancel` act on
+pub trait Controlled: Send + Sync {
+    /// Every job, with how far the running ones have got
+    fn jobs(&self) -> Result<Vec<Job>, DaemonError>;
+
+    fn job(&self, id: u64) -> Result<Job, DaemonError> {
+        self.jobs()?.into_iter().find(|job| job.id == id).ok_or(DaemonError::NoSuchJob(id))
+    }
+
+    fn pause(&self, id: u64) -> Result<Job, DaemonError>;
+
+    fn resume(&self, id: u64) -> Result<Job, DaemonError>;
+
+    fn cancel(&self, id: u64) -> Result<Job, DaemonError>;
+}
+
+impl Controlled for Control {
+    fn jobs(&self) -> Result<Vec<Job>, DaemonError> {
+        Control::jobs(self)
+    }
+
+    fn job(&self, id: u64) -> Result<Job, DaemonError> {
+        Control::job(self, id)
+    }
+
+    fn pause(&self, id: u64) -> Result<Job, DaemonError> {
+        Control::pause(self, id)
+    }
+
+    fn resume(&self, id: u64) -> Result<Job, DaemonError> {
+        Control::resume(self, id)
+    }
+
+    fn cancel(&self, id: u64) -> Result<Job, DaemonError> {
+        Control::cancel(self, id)
+    }
+}
+
+/// The URLs a download in the foreground is working through, as jobs
+/// numbered from 1 in the order they're downloaded. Each can be paused and
+/// cancelled without the others; what's left of a cancelled one is dealt
+/// with as `--on-cancel` says.
+#[derive(Clone, Default)]
+pub struct BatchControl(Arc<Mutex<Batch>>);
+
+#[derive(Default)]
+struct Batch {
+    /// Where the URLs are being saved
+    dir: PathBuf,
+    /// What's happened to the ones that are done
+    report: Option<Arc<Report>>,
+    cancellation: Cancellation,
+    jobs: Vec<BatchJob>,
+}
+
+struct BatchJob {
+    url: String,
+    /// Its own, so it can be stopped before it's even started
+    cancellation: Cancellation,
+    started: bool,
+    cancelled: bool,
+    bar: Option<WeakProgressBar>,
+}
+
+impl BatchControl {
+    pub fn new(dir: PathBuf) -> Self {
+        BatchControl(Arc::new(Mutex::new(Batch { dir, ..Default::default() })))
+    }
+
+    /// Number `urls`, which are about to be downloaded and reported to `report`
+    pub fn start(&self, urls: &[String], report: &Arc<Report>, cancellation: &Cancellation) {
+        let mut batch = self.0.lock().unwrap();
+        batch.report = Some(Arc::clone(report));
+        batch.cancellation = cancellation.clone();
+        for url in urls {
+            let job = BatchJob { url: url.clone(), cancellation: cancellation.child(), started: false, cancelled: false, bar: None };
+            batch.jobs.push(job);
+        }
+    }
+
+    /// `url` is being started: what stops it, and only it. URLs that weren't
+    /// in the batch to start with, like those found recursing, are added to it.
+    pub fn begin(&self, url: &str) -> Cancellation {
+        let mut batch = self.0.lock().unwrap();
+        if let Some(job) = batch.jobs.iter_mut().find(|job| job.url == url && !job.started) {
+            job.started = true;
+            return job.cancellation.clone();
+        }
+        let cancellation = batch.cancellation.child();
+        batch.jobs.push(BatchJob { url: url.to_string(), cancellation: cancellation.clone(), started: true, cancelled: false, bar: None });
+        cancellation
+    }
+
+    /// Show how far `url` has got from `pb`
+    pub fn track(&self, url: &str, pb: &ProgressBar) {
+        let mut batch = self.0.lock().unwrap();
+        if let Some(job) = batch.jobs.iter_mut().rev().find(|job| job.url == url && job.started) {
+            job.bar = Some(pb.downgrade());
+        }
+    }
+
+    /// Do `change` to job `id`, if it's in one of the states it can be changed from
+    fn change(&self, id: u64, from: &[State], change: impl FnOnce(&mut BatchJob)) -> Result<Job, DaemonError> {
+        let job = self.job(id)?;
+        if !from.contains(&job.state) {
+            return Err(DaemonError::Unchanged { id, state: job.state.as_str() });
+        }
+        change(&mut self.0.lock().unwrap().jobs[id as usize - 1]);
+        self.job(id)
+    }
+}
+
+impl Controlled for BatchControl {
+    fn jobs(&self) -> Result<Vec<Job>, DaemonError> {
+        let batch = self.0.lock().unwrap();
+        let entries = batch.report.as_ref().map(|report| report.entries()).unwrap_or_default();
+        let jobs = batch.jobs.iter().zip(1..).map(|(job, id)| {
+            let entry = entries.iter().rfind(|entry| entry.url == job.url).filter(|_| job.started);
+            let state = match entry.map(|entry| entry.status) {
+                Some(Status::Failed) if job.cancelled => State::Cancelled,
+                Some(Status::Failed) => State::Failed,
+                Some(_) => State::Done,
+                None if job.cancelled => State::Cancelled,
+                None if job.cancellation.is_paused() => State::Paused,
+                None if job.started => State::Running,
+                None => State::Queued,
+            };
+            let bar = job.bar.as_ref().and_then(WeakProgressBar::upgrade).filter(|_| entry.is_none());
+            Job {
+                id,
+                url: job.url.clone(),
+                dir: batch.dir.clone(),
+                state,
+                start_at: None,
+                file: entry.and_then(|entry| entry.file.clone()),
+                bytes: entry.filter(|entry| entry.status != Status::Failed).map(|entry| entry.bytes),
+                error: entry.and_then(|entry| entry.error.clone()).filter(|_| state == State::Failed),
+                progress: bar.map(|pb| Progress { bytes: pb.position(), total: pb.length(), speed: pb.per_sec() }),
+            }
+        });
+        Ok(jobs.collect())
+    }
+
+    /// Hold a download at its next read or write, or before it starts
+    fn pause(&self, id: u64) -> Result<Job, DaemonError> {
+        let job = self.change(id, &[State::Queued, State::Running], |job| job.cancellation.pause())?;
+        info!("Paused job {}", id);
+        Ok(job)
+    }
+
+    fn resume(&self, id: u64) -> Result<Job, DaemonError> {
+        let job = self.change(id, &[State::Paused], |job| job.cancellation.resume())?;
+        info!("Resumed job {}", id);
+        Ok(job)
+    }
+
+    fn cancel(&self, id: u64) -> Result<Job, DaemonError> {
+        let job = self.change(id, &[State::Queued, State::Running, State::Paused], |job| {
+            job.cancelled = true;
+            job.cancellation.cancel(CancelReason::Requested);
+        })?;
+        info!("Cancelled job {}", id);
+        Ok(job)
+    }
+}
+
+impl fmt::Debug for BatchControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let jobs = self.0.lock().map_or(0, |batch| batch.jobs.len());
+        f.debug_tuple("BatchControl").field(&jobs).finish()
+    }
+}
+
+/// What's listened on for requests: a Unix socket, or a named pipe on Windows
+#[cfg(unix)]
+pub type Listener = UnixListener;
+#[cfg(windows)]
+pub type Listener = pipe::PipeListener;
+
+/// Lets commands like `download status` and `download pause` reach a running
+/// daemon or foreground download, which are the only ones that can stop the
+/// downloads they've started
+#[derive(Clone)]
+pub struct ControlSocket {
+    control: Arc<dyn Controlled>,
+}
+
+impl ControlSocket {
+    pub fn new(control: impl Controlled + 'static) -> Self {
+        ControlSocket { control: Arc::new(control) }
+    }
+
+    /// Listen at `path`, replacing a socket left behind by a daemon that's
+    /// gone, but not one that's still answering
+    #[cfg(unix)]
+    pub fn bind(path: &Path) -> io::Result<Listener> {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("a daemon is already listening on {}", path.display())));
+        }
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        UnixListener::bind(path)
+    }
+
+    /// Listen on the named pipe `path`; a pipe goes when its last handle
+    /// does, so there's never one left behind
+    #[cfg(windows)]
+    pub fn bind(path: &Path) -> io::Result<Listener> {
+        pipe::PipeListener::bind(path)
+    }
+
+    /// Answer requests on `listener` until the program exits, one thread each
+    #[cfg(any(unix, windows))]
+    pub fn serve(self, listener: Listener) {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            let control = Arc::clone(&self.control);
+            std::thread::spawn(move || {
+                if let Err(e) = reply(control.as_ref(), &stream, &stream) {
+                    debug!("Control connection failed: {}", e);
+                }
+                // Closing a pipe throws away what the client hasn't read yet
+                #[cfg(windows)]
+                let _ = stream.sync_all();
+            });
+        }
+    }
+}
+
+impl fmt::Debug for ControlSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ControlSocket").finish_non_exhaustive()
+    }
+}
+
+/// Read a request from `reader` and write the reply to `writer`
+fn reply(control: &dyn Controlled, reader: impl io::Read, mut writer: impl Write) -> io::Result<()> {
+    let mut line = String::new();
+    BufReader::new(reader).read_line(&mut line)?;
+    let reply = match serde_json::from_str(&line) {
+        Ok(request) => match answer(control, request) {
+            Ok(jobs) => Reply::Jobs(jobs),
+            Err(e) => Reply::Error(e.to_string()),
+        },
+        Err(e) => Reply::Error(format!("bad request: {}", e)),
+    };
+    writeln!(writer, "{}", serde_json::to_string(&reply)?)
+}
+
+fn answer(control: &dyn Controlled, request: Request) -> Result<Vec<Job>, DaemonError> {
+    match request {
+        Request::Status { id: None } => control.jobs(),
+        Request::Status { id: Some(id) } => control.job(id).map(|job| vec![job]),
+        Request::Pause { id } => control.pause(id).map(|job| vec![job]),
+        Request::Resume { id } => control.resume(id).map(|job| vec![job]),
+        Request::Cancel { id } => control.cancel(id).map(|job| vec![job]),
+    }
+}
+
+/// Ask whatever's listening at the first of `sockets` that something is; if
+/// nothing is, nothing's being downloaded, so the queue is read and changed
+/// directly instead
+pub fn send(sockets: &[PathBuf], queue: Queue, request: Request) -> Result<Vec<Job>, SocketError> {
+    for socket in sockets {
+        match connect(socket) {
+            Ok(mut stream) => {
+                writeln!(stream, "{}", serde_json::to_string(&request)?)?;
+                let mut line = String::new();
+                BufReader::new(stream).read_line(&mut line)?;
+                return match serde_json::from_str(&line)? {
+                    Reply::Jobs(jobs) => Ok(jobs),
+                    Reply::Error(e) => Err(SocketError::Refused(e)),
+                };
+            }
+            Err(e) if matches!(e.kind(), io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused | io::ErrorKind::Unsupported) => {
+                debug!("Nothing listening at {} ({})", socket.display(), e);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    debug!("No daemon running, using the queue directly");
+    Ok(answer(&Control::new(queue), request)?)
+}
+
+#[cfg(unix)]
+fn connect(socket: &Path) -> io::Result<UnixStream> {
+    UnixStream::connect(socket)
+}
+
+#[cfg(windows)]
+fn connect(socket: &Path) -> io::Result<std::fs::File> {
+    pipe::connect(socket)
+}
+
+/// There are no sockets or pipes here, so there's never a daemon to talk to
+#[cfg(not(any(unix, windows)))]
+fn connect(_socket: &Path) -> io::Result<std::fs::File> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Named pipes, which are what Windows has instead of Unix sockets
+#[cfg(windows)]
+mod pipe {
+    use std::fs::{File, OpenOptions};
+    use std::io;
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::io::{AsRawHandle, FromRawHandle};
+    use std::path::{Path, PathBuf};
+    use std::{ptr, thread};
+    use std::time::Duration;
+
+    use windows_sys::Win32::Foundation::{ERROR_ACCESS_DENIED, ERROR_PIPE_BUSY, ERROR_PIPE_CONNECTED, INVALID_HANDLE_VALUE};
+    use windows_sys::Win32::Storage::FileSystem::{FILE_FLAG_FIRST_PIPE_INSTANCE, PIPE_ACCESS_DUPLEX};
+    use windows_sys::Win32::System::Pipes::{ConnectNamedPipe, CreateNamedPipeW, PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT};
+
+    /// How long to wait for a pipe that's busy answering someone else, and how often
+    const BUSY_WAIT: Duration = Duration::from_millis(50);
+    const BUSY_TRIES: u32 = 20;
+
+    /// Size of each instance's buffers; requests and replies are a line each
+    const BUFFER_SIZE: u32 = 64 * 1024;
+
+    /// `name`'s pipe for the current user
+    pub fn path(name: &str) -> PathBuf {
+        let user = std::env::var("USERNAME").unwrap_or_default();
+        PathBuf::from(format!(r"\\.\pipe\rustdl-{}-{}", user, name))
+    }
+
+    /// A named pipe being listened on, with the instance the next client will connect to
+    pub struct PipeListener {
+        name: Vec<u16>,
+        next: File,
+    }
+
+    impl PipeListener {
+        /// Create the pipe at `path`, unless something else already has
+        pub fn bind(path: &Path) -> io::Result<PipeListener> {
+            let name: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+            let next = match create(&name, true) {
+                Err(e) if e.raw_os_error() == Some(ERROR_ACCESS_DENIED as i32) => {
+                    return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("something is already listening on {}", path.display())));
+                }
+                result => result?,
+            };
+            Ok(PipeListener { name, next })
+        }
+
+        /// Wait for the next client, leaving a fresh instance for the one after
+        pub fn accept(&mut self) -> io::Result<File> {
+            // SAFETY: the handle is a pipe instance we created and still own,
+            // and without an OVERLAPPED this waits for the client
+            if unsafe { ConnectNamedPipe(self.next.as_raw_handle(), ptr::null_mut()) } == 0 {
+                let e = io::Error::last_os_error();
+                // A client that connected before we waited is connected all the same
+                if e.raw_os_error() != Some(ERROR_PIPE_CONNECTED as i32) {
+                    return Err(e);
+                }
+            }
+            let next = create(&self.name, false)?;
+            Ok(std::mem::replace(&mut self.next, next))
+        }
+
+        pub fn incoming(mut self) -> impl Iterator<Item = io::Result<File>> {
+            std::iter::from_fn(move || Some(self.accept()))
+        }
+    }
+
+    /// A new instance of the pipe `name`, a NUL-terminated wide string; the
+    /// first one fails if the pipe's already there
+    fn create(name: &[u16], first: bool) -> io::Result<File> {
+        let open_mode = PIPE_ACCESS_DUPLEX | if first { FILE_FLAG_FIRST_PIPE_INSTANCE } else { 0 };
+        let pipe_mode = PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS;
+        // SAFETY: `name` is NUL-terminated, and no security attributes means the default ones,
+        // which only let the same user and administrators in
+        let handle = unsafe { CreateNamedPipeW(name.as_ptr(), open_mode, pipe_mode, PIPE_UNLIMITED_INSTANCES, BUFFER_SIZE, BUFFER_SIZE, 0, ptr::null()) };
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the handle's valid, and nothing else owns it
+        Ok(unsafe { File::from_raw_handle(handle) })
+    }
+
+    /// Connect to the pipe at `path`, waiting a little while every instance is busy
+    pub fn connect(path: &Path) -> io::Result<File> {
+        for _ in 1..BUSY_TRIES {
+            match OpenOptions::new().read(true).write(true).open(path) {
+                Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY as i32) => thread::sleep(BUSY_WAIT),
+                result => return result,
+            }
+        }
+        OpenOptions::new().read(true).write(true).open(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::TimeZone;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_send() {
+        let dir = std::env::temp_dir().join(format!("rustdl-socket-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (queue, socket) = (Queue::new(dir.join("queue.jsonl")), dir.join(SOCKET_FILE));
+        let sockets = [socket.clone(), dir.join(BATCH_FILE)];
+        queue.add("https://example.com/a.iso", Path::new("/downloads"), None).unwrap();
+
+        // No daemon yet, so the queue's changed directly
+        assert_eq!(send(&sockets, queue.clone(), Request::Pause { id: 1 }).unwrap()[0].state, State::Paused);
+        let listener = ControlSocket::bind(&socket).unwrap();
+        assert!(ControlSocket::bind(&socket).is_err());
+        std::thread::spawn(move || ControlSocket::new(Control::new(Queue::new(dir.join("queue.jsonl")))).serve(listener));
+        assert_eq!(send(&sockets, queue.clone(), Request::Resume { id: 1 }).unwrap()[0].state, State::Queued);
+        assert_eq!(send(&sockets, queue.clone(), Request::Status { id: None }).unwrap().len(), 1);
+        assert!(matches!(send(&sockets, queue.clone(), Request::Cancel { id: 2 }), Err(SocketError::Refused(e)) if e == "there's no job 2"));
+        std::fs::remove_dir_all(socket.parent().unwrap()).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_send_to_foreground_batch() {
+        let dir = std::env::temp_dir().join(format!("rustdl-batch-socket-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (queue, socket) = (Queue::new(dir.join("queue.jsonl")), dir.join(BATCH_FILE));
+        let sockets = [dir.join(SOCKET_FILE), socket.clone()];
+        let (batch, cancellation) = (BatchControl::new(dir.clone()), Cancellation::default());
+        let urls = ["https://example.com/a.iso".to_string(), "https://example.com/b.iso".to_string()];
+        batch.start(&urls, &Arc::new(Report::new(None, None, None, TimeZone::Utc)), &cancellation);
+        let running = batch.begin(&urls[0]);
+
+        // Nothing's listening for the daemon, so the batch is asked instead
+        let listener = ControlSocket::bind(&socket).unwrap();
+        std::thread::spawn({
+            let batch = batch.clone();
+            move || ControlSocket::new(batch).serve(listener)
+        });
+        let states = |jobs: Vec<Job>| jobs.into_iter().map(|job| job.state).collect::<Vec<_>>();
+        assert_eq!(states(send(&sockets, queue.clone(), Request::Status { id: None }).unwrap()), [State::Running, State::Queued]);
+        assert_eq!(states(send(&sockets, queue.clone(), Request::Pause { id: 1 }).unwrap()), [State::Paused]);
+        assert!(running.is_paused());
+        assert_eq!(states(send(&sockets, queue.clone(), Request::Cancel { id: 2 }).unwrap()), [State::Cancelled]);
+        assert_eq!(batch.begin(&urls[1]).reason(), Some(CancelReason::Requested));
+        assert!(running.reason().is_none() && cancellation.reason().is_none());
+        assert!(matches!(send(&sockets, queue.clone(), Request::Resume { id: 2 }), Err(SocketError::Refused(_))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
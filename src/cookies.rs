@@ -1,10 +1,12 @@
+use std::sync::Arc;
+
 use tldextract::{TldExtractor, TldOption};
 
 use reqwest::header::{self, HeaderValue};
 
 use rookie::{common::enums::CookieToString, common::enums::Cookie};
-use crate::browser::CookieManager;
-use log::{debug, warn};
+use crate::browser::{BrowserError, BrowserType, CookieManager};
+use tracing::{debug, error, info, trace_span, warn};
 
 pub struct CookieJarWrapper {
     cookie_manager: CookieManager,
@@ -16,6 +18,76 @@ impl CookieJarWrapper {
     }
 }
 
+/// Validate and parse browser argument
+pub fn validate_browser_argument(browser_arg: Option<String>) -> Result<Option<BrowserType>, BrowserError> {
+    match browser_arg {
+        Some(browser_str) => {
+            match browser_str.parse::<BrowserType>() {
+                Ok(browser_type) => Ok(Some(browser_type)),
+                Err(e) => Err(e),
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+/// Cookies from `browser_type`, or (if `detect_browser` is set) from whichever
+/// browser we can find when none was chosen
+pub fn from_browser(browser_type: Option<BrowserType>, detect_browser: bool) -> Option<Arc<CookieJarWrapper>> {
+    let _span = trace_span!("browser_cookies", browser = ?browser_type).entered();
+    // Create CookieManager based on browser selection
+    let cookie_manager = match browser_type {
+        Some(browser) => {
+            info!("User specified browser: {}", browser);
+            // User specified a browser, try to use it
+            match CookieManager::new(browser.clone()) {
+                Ok(manager) => {
+                    info!("Successfully created CookieManager with {} browser", manager.browser_name());
+                    debug!("Using {} browser for cookies", manager.browser_name());
+                    Some(manager)
+                }
+                Err(e) => {
+                    // Shown without -v, as the cookies asked for won't be sent
+                    error!("Can't use cookies from {}: {}\nFalling back to auto-detection...", browser, e.user_friendly_message());
+                    match CookieManager::with_auto_detection() {
+                        Ok(manager) => {
+                            info!("Fallback auto-detection successful: {}", manager.browser_name());
+                            debug!("Using {} browser for cookies", manager.browser_name());
+                            Some(manager)
+                        }
+                        Err(fallback_err) => {
+                            error!("No browser cookies to use instead: {}", fallback_err.user_friendly_message());
+                            None
+                        }
+                    }
+                }
+            }
+        }
+        None if !detect_browser => {
+            debug!("No browser specified, not looking for cookies");
+            None
+        }
+        None => {
+            debug!("No browser specified, using fallback with Firefox preference");
+            // No browser specified, use auto-detection for backward compatibility
+            // Default to Firefox first for backward compatibility, then auto-detect
+            match CookieManager::with_fallback(Some(BrowserType::Firefox)) {
+                Ok(manager) => {
+                    info!("Fallback CookieManager created with: {}", manager.browser_name());
+                    debug!("Using {} browser for cookies", manager.browser_name());
+                    Some(manager)
+                }
+                Err(e) => {
+                    warn!("Fallback CookieManager creation failed: {}", e.brief_message());
+                    None
+                }
+            }
+        }
+    };
+
+    cookie_manager.map(|cookie_manager| Arc::new(CookieJarWrapper::new(cookie_manager)))
+}
+
 pub fn cookie_matches_url(cookie: &Cookie, url: &url::Url) -> bool {
     // Here's how we match cookies to URLs:
     // 1. The cookie should have a path, and the URL should start with that path
@@ -44,13 +116,10 @@ pub fn cookie_matches_url(cookie: &Cookie, url: &url::Url) -> bool {
     };
 
     let url_domain = url.domain().unwrap();
-    let domain_offset = match url_domain.find(cookie_domain_noprefix) {
-        Some(offset) => offset,
-        None => 0
-    };
+    let domain_offset = url_domain.find(cookie_domain_noprefix).unwrap_or_default();
     
-    // If domain_offset is 0 (or less?), then no
-    let last_char_before_cookie_domain_is_periodt = if domain_offset <= 0 {
+    // If domain_offset is 0, then no
+    let last_char_before_cookie_domain_is_periodt = if domain_offset == 0 {
         false
     } else {
         // If domain_offset > 0, then
@@ -86,6 +155,7 @@ impl reqwest::cookie::CookieStore for CookieJarWrapper {
     }
     
     fn cookies(&self, url: &url::Url) -> Option<HeaderValue> {
+        let _span = trace_span!("cookie_lookup", url = %url).entered();
         debug!("Fetching cookies for URL: {}", url.as_str());
         
         let extractor: TldExtractor = TldOption::default().build();
@@ -131,7 +201,7 @@ impl reqwest::cookie::CookieStore for CookieJarWrapper {
         let matching_cookies: Vec<_> = cookies.into_iter().filter_map(
             |cookie|
             {
-                if cookie_matches_url(&cookie, &url) {
+                if cookie_matches_url(&cookie, url) {
                     debug!("Cookie {} matches URL {}", cookie.name, url.as_str());
                     Some(cookie)
                 } else {
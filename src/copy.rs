@@ -0,0 +1,92 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
+
+/// How much is gathered up before each write. A response body is read a
+/// chunk at a time as it comes off the network, often 16 KiB or less, and
+/// `io::copy` writes each chunk out as it's read; gathering them means a
+/// multi-gigabyte download is a few thousand writes rather than a few hundred
+/// thousand.
+pub const WRITE_SIZE: usize = 1024 * 1024;
+
+/// The longest anything read waits to be written, so a slow download still
+/// reaches the disk as it comes in
+const MAX_DELAY: Duration = Duration::from_secs(1);
+
+/// Copy everything from `reader` to `writer` like `io::copy`, but in writes
+/// of up to `WRITE_SIZE`. If reading fails, what was read before it is
+/// still written, so a resumed download can pick up from there.
+pub fn buffered<R: Read + ?Sized, W: Write + ?Sized>(reader: &mut R, writer: &mut W) -> io::Result<u64> {
+    let mut buffer = vec![0; WRITE_SIZE];
+    let mut copied = 0;
+    loop {
+        let (mut filled, started) = (0, Instant::now());
+        let read = loop {
+            match reader.read(&mut buffer[filled..]) {
+                Ok(0) => break Ok(true),
+                Ok(read) => {
+                    filled += read;
+                    if filled == buffer.len() || started.elapsed() >= MAX_DELAY {
+                        break Ok(false);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        };
+        let written = writer.write_all(&buffer[..filled]);
+        copied += filled as u64;
+        match (read, written) {
+            (Err(e), _) | (_, Err(e)) => return Err(e),
+            (Ok(true), Ok(())) => return Ok(copied),
+            (Ok(false), Ok(())) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands out its data a few bytes at a time, like a network body, then fails
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(io::Error::new(ErrorKind::ConnectionReset, "connection reset"));
+            }
+            let read = buf.len().min(self.0.len()).min(7);
+            buf[..read].copy_from_slice(&self.0[..read]);
+            self.0 = &self.0[read..];
+            Ok(read)
+        }
+    }
+
+    /// Counts the writes it's given
+    #[derive(Default)]
+    struct Counted(Vec<u8>, usize);
+
+    impl Write for Counted {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.1 += 1;
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_buffered() {
+        let data: Vec<u8> = (0..3 * WRITE_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        let mut out = Counted::default();
+        assert_eq!(buffered(&mut &data[..], &mut out).unwrap(), data.len() as u64);
+        assert_eq!((out.0 == data, out.1), (true, 4));
+
+        // What came in before the connection dropped is kept
+        let mut out = Counted::default();
+        assert_eq!(buffered(&mut Trickle(&data[..1000]), &mut out).unwrap_err().kind(), ErrorKind::ConnectionReset);
+        assert_eq!((out.0.as_slice(), out.1), (&data[..1000], 1));
+    }
+}
@@ -0,0 +1,543 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use log::{debug, warn};
+use reqwest::blocking::{Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::oauth::{self, OAuthProvider, OAuthSettings, TokenSet};
+
+/// Service name our secrets are filed under in the system keyring
+#[cfg(feature = "keyring")]
+const KEYRING_SERVICE: &str = "rustdl";
+
+/// Name of the host index inside the XDG data directory; secrets never go in here
+const INDEX_FILE: &str = "credentials.json";
+
+/// How a stored credential is sent to the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthScheme {
+    Basic,
+    Bearer,
+    /// Tokens from `download auth login`, shared by all of a provider's hosts
+    OAuth,
+}
+
+impl AuthScheme {
+    /// Get all supported authentication schemes
+    pub fn all() -> Vec<AuthScheme> {
+        vec![AuthScheme::Basic, AuthScheme::Bearer, AuthScheme::OAuth]
+    }
+
+    /// Get the string representation of the scheme
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuthScheme::Basic => "basic",
+            AuthScheme::Bearer => "bearer",
+            AuthScheme::OAuth => "oauth",
+        }
+    }
+}
+
+impl fmt::Display for AuthScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for AuthScheme {
+    type Err = CredentialError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "basic" => Ok(AuthScheme::Basic),
+            "bearer" | "token" => Ok(AuthScheme::Bearer),
+            "oauth" => Ok(AuthScheme::OAuth),
+            _ => Err(CredentialError::UnknownScheme { scheme: s.to_string() }),
+        }
+    }
+}
+
+/// Credentials for a single host
+#[derive(Clone, PartialEq, Eq)]
+pub enum Credential {
+    Basic { username: String, password: String },
+    Bearer { token: String },
+    OAuth { provider: String, tokens: TokenSet },
+}
+
+impl Credential {
+    pub fn scheme(&self) -> AuthScheme {
+        match self {
+            Credential::Basic { .. } => AuthScheme::Basic,
+            Credential::Bearer { .. } => AuthScheme::Bearer,
+            Credential::OAuth { .. } => AuthScheme::OAuth,
+        }
+    }
+
+    /// Attach these credentials to a request; OAuth tokens should be
+    /// refreshed with `oauth::access_token` first
+    pub fn apply(&self, request: RequestBuilder) -> RequestBuilder {
+        match self {
+            Credential::Basic { username, password } => request.basic_auth(username, Some(password)),
+            Credential::Bearer { token } => request.bearer_auth(token),
+            Credential::OAuth { tokens, .. } => request.bearer_auth(&tokens.access_token),
+        }
+    }
+
+    fn secret(&self) -> String {
+        match self {
+            Credential::Basic { password, .. } => password.clone(),
+            Credential::Bearer { token } => token.clone(),
+            Credential::OAuth { tokens, .. } => serde_json::to_string(tokens).unwrap_or_default(),
+        }
+    }
+}
+
+// Keep secrets out of debug logs
+impl fmt::Debug for Credential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credential::Basic { username, .. } => write!(f, "Basic({}:****)", username),
+            Credential::Bearer { .. } => write!(f, "Bearer(****)"),
+            Credential::OAuth { provider, .. } => write!(f, "OAuth({})", provider),
+        }
+    }
+}
+
+/// An entry in the host index; the secret itself lives in the keyring
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostEntry {
+    /// Host name, optionally with a port (`example.com:8443`), and starting
+    /// `http://` if it was added to be sent over plain HTTP
+    pub host: String,
+    pub scheme: AuthScheme,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// OAuth provider whose tokens this host uses
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+}
+
+impl HostEntry {
+    /// Name of this entry's secret in the keyring
+    fn secret_key(&self) -> String {
+        match &self.provider {
+            Some(provider) => OAuthProvider::secret_key(provider),
+            None => self.host.clone(),
+        }
+    }
+
+    /// Check whether this entry applies to the given URL. Entries are for
+    /// HTTPS unless they were added as `http://...`, and then only for HTTP,
+    /// so credentials are never sent where anyone on the way can read them
+    /// unless asked to.
+    pub fn matches(&self, url: &Url) -> bool {
+        let (scheme, entry) = match self.host.strip_prefix("http://") {
+            Some(entry) => ("http", entry),
+            None => ("https", self.host.as_str()),
+        };
+        let Some(host) = url.host_str().filter(|_| url.scheme() == scheme) else {
+            return false;
+        };
+        let host = host.to_lowercase();
+        match url.port_or_known_default() {
+            Some(port) if entry == format!("{}:{}", host, port) => true,
+            _ => entry == host,
+        }
+    }
+}
+
+/// Errors from storing or retrieving credentials
+#[derive(Debug, thiserror::Error)]
+pub enum CredentialError {
+    #[error("could not access credential index {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("credential index {path} is corrupt: {source}")]
+    Corrupt { path: PathBuf, source: serde_json::Error },
+
+    #[cfg(feature = "keyring")]
+    #[error("system keyring error: {0}")]
+    Keyring(#[from] keyring::Error),
+
+    #[error("no secret for {host} in the system keyring; add it again with `download auth add`")]
+    MissingSecret { host: String },
+
+    #[error("Unknown authentication scheme '{scheme}'. Supported schemes: {}",
+            AuthScheme::all().iter().map(|s| s.as_str()).collect::<Vec<_>>().join(", "))]
+    UnknownScheme { scheme: String },
+
+    #[error("stored secret for {host} is unreadable; add it again with `download auth add`")]
+    InvalidSecret { host: String },
+
+    #[error("'{host}' is not a valid host name")]
+    InvalidHost { host: String },
+
+    #[error("could not determine where to store the credential index")]
+    NoDataDir,
+}
+
+/// Somewhere to keep secrets, keyed by host
+pub trait SecretStore: Send + Sync {
+    fn get(&self, host: &str) -> Result<Option<String>, CredentialError>;
+    fn set(&self, host: &str, secret: &str) -> Result<(), CredentialError>;
+    fn delete(&self, host: &str) -> Result<(), CredentialError>;
+}
+
+/// Secrets kept in the platform keychain (Keychain, Credential Manager, Secret Service)
+#[cfg(feature = "keyring")]
+struct KeyringSecrets;
+
+#[cfg(feature = "keyring")]
+impl SecretStore for KeyringSecrets {
+    fn get(&self, host: &str) -> Result<Option<String>, CredentialError> {
+        match keyring::Entry::new(KEYRING_SERVICE, host)?.get_password() {
+            Ok(secret) => Ok(Some(secret)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn set(&self, host: &str, secret: &str) -> Result<(), CredentialError> {
+        Ok(keyring::Entry::new(KEYRING_SERVICE, host)?.set_password(secret)?)
+    }
+
+    fn delete(&self, host: &str) -> Result<(), CredentialError> {
+        match keyring::Entry::new(KEYRING_SERVICE, host)?.delete_credential() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Per-host credentials: a JSON index of hosts under the XDG data dir, with
+/// the secrets themselves in the system keyring
+pub struct CredentialStore {
+    path: PathBuf,
+    entries: Vec<HostEntry>,
+    secrets: Box<dyn SecretStore>,
+}
+
+impl CredentialStore {
+    /// Default location of the host index
+    pub fn default_path() -> Result<PathBuf, CredentialError> {
+        xdg::BaseDirectories::with_prefix("rustdl")
+            .get_data_home()
+            .map(|dir| dir.join(INDEX_FILE))
+            .ok_or(CredentialError::NoDataDir)
+    }
+
+    /// Open the store at the default location, backed by the system keyring
+    #[cfg(feature = "keyring")]
+    pub fn open_default() -> Result<Self, CredentialError> {
+        Self::open(Self::default_path()?, Box::new(KeyringSecrets))
+    }
+
+    /// The store `download auth` saves to, for sending what's in it with
+    /// downloads: none if it can't be opened (which is only warned about), or
+    /// if this build doesn't have the `keyring` feature
+    pub fn open_saved() -> Option<Self> {
+        #[cfg(feature = "keyring")]
+        return Self::open_default().inspect_err(|e| warn!("Not using stored credentials: {}", e)).ok();
+        #[cfg(not(feature = "keyring"))]
+        None
+    }
+
+    /// Open the index at `path`; a missing file is treated as an empty index
+    pub fn open(path: PathBuf, secrets: Box<dyn SecretStore>) -> Result<Self, CredentialError> {
+        let entries = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map_err(|source| CredentialError::Corrupt { path: path.clone(), source })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                debug!("No credential index at {}, starting empty", path.display());
+                Vec::new()
+            }
+            Err(source) => return Err(CredentialError::Io { path, source }),
+        };
+        Ok(Self { path, entries, secrets })
+    }
+
+    /// Write the host index back to disk
+    pub fn save(&self) -> Result<(), CredentialError> {
+        let io_err = |source| CredentialError::Io { path: self.path.clone(), source };
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).map_err(io_err)?;
+        }
+        let contents = serde_json::to_vec_pretty(&self.entries)
+            .map_err(|source| CredentialError::Corrupt { path: self.path.clone(), source })?;
+        fs::write(&self.path, contents).map_err(io_err)
+    }
+
+    pub fn entries(&self) -> &[HostEntry] {
+        &self.entries
+    }
+
+    /// Store credentials for a host, replacing any it already had
+    pub fn add(&mut self, host: &str, credential: &Credential) -> Result<(), CredentialError> {
+        let host = normalize_host(host)?;
+        let (username, provider) = match credential {
+            Credential::Basic { username, .. } => (Some(username.clone()), None),
+            Credential::Bearer { .. } => (None, None),
+            Credential::OAuth { provider, .. } => (None, Some(provider.clone())),
+        };
+        let entry = HostEntry { host, scheme: credential.scheme(), username, provider };
+        self.secrets.set(&entry.secret_key(), &credential.secret())?;
+        self.entries.retain(|existing| existing.host != entry.host);
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Store a provider's OAuth tokens and use them for each of its hosts
+    pub fn add_oauth(&mut self, provider: &str, hosts: &[String], tokens: &TokenSet) -> Result<(), CredentialError> {
+        let credential = Credential::OAuth { provider: provider.to_string(), tokens: tokens.clone() };
+        for host in hosts {
+            self.add(host, &credential)?;
+        }
+        Ok(())
+    }
+
+    /// Replace a provider's OAuth tokens after refreshing them
+    pub fn update_oauth(&self, provider: &str, tokens: &TokenSet) -> Result<(), CredentialError> {
+        let credential = Credential::OAuth { provider: provider.to_string(), tokens: tokens.clone() };
+        self.secrets.set(&OAuthProvider::secret_key(provider), &credential.secret())
+    }
+
+    /// Forget the credentials for a host, returning whether there were any
+    pub fn remove(&mut self, host: &str) -> Result<bool, CredentialError> {
+        let host = normalize_host(host)?;
+        let Some(index) = self.entries.iter().position(|entry| entry.host == host) else {
+            return Ok(false);
+        };
+        let entry = self.entries.remove(index);
+        // OAuth tokens are shared with the provider's other hosts; `auth logout` removes those
+        if entry.provider.is_none() {
+            self.secrets.delete(&entry.secret_key())?;
+        }
+        Ok(true)
+    }
+
+    /// Forget a provider's OAuth tokens and every host using them, returning how many hosts were removed
+    pub fn logout(&mut self, provider: &str) -> Result<usize, CredentialError> {
+        self.secrets.delete(&OAuthProvider::secret_key(provider))?;
+        let before = self.entries.len();
+        self.entries.retain(|entry| entry.provider.as_deref() != Some(provider));
+        Ok(before - self.entries.len())
+    }
+
+    /// Find the credentials to use for a URL; an entry with a port wins over one without
+    pub fn lookup(&self, url: &Url) -> Result<Option<Credential>, CredentialError> {
+        let Some(entry) = self
+            .entries
+            .iter()
+            .filter(|entry| entry.matches(url))
+            .max_by_key(|entry| entry.host.len())
+        else {
+            return Ok(None);
+        };
+
+        let secret = self
+            .secrets
+            .get(&entry.secret_key())?
+            .ok_or_else(|| CredentialError::MissingSecret { host: entry.host.clone() })?;
+        let credential = match entry.scheme {
+            AuthScheme::Basic => Credential::Basic {
+                username: entry.username.clone().unwrap_or_default(),
+                password: secret,
+            },
+            AuthScheme::Bearer => Credential::Bearer { token: secret },
+            AuthScheme::OAuth => Credential::OAuth {
+                provider: entry.provider.clone().unwrap_or_default(),
+                tokens: serde_json::from_str(&secret)
+                    .map_err(|_| CredentialError::InvalidSecret { host: entry.host.clone() })?,
+            },
+        };
+        debug!("Using stored {} credentials for {}", entry.scheme, entry.host);
+        Ok(Some(credential))
+    }
+
+    /// Attach the stored credentials for `url` to a request, refreshing OAuth
+    /// tokens if needed. Credentials in the URL itself take priority.
+    pub fn authorize(&self, request: RequestBuilder, url: &Url, client: &Client, oauth: &OAuthSettings) -> RequestBuilder {
+        if !url.username().is_empty() {
+            return request;
+        }
+        match self.lookup(url) {
+            Ok(Some(Credential::OAuth { provider, tokens })) => {
+                match oauth::access_token(client, oauth, self, &provider, tokens) {
+                    Ok(token) => request.bearer_auth(token),
+                    Err(e) => {
+                        warn!("Not using {} login for {}: {}", provider, url, e);
+                        request
+                    }
+                }
+            }
+            Ok(Some(credential)) => credential.apply(request),
+            Ok(None) => request,
+            Err(e) => {
+                warn!("Not using stored credentials for {}: {}", url, e);
+                request
+            }
+        }
+    }
+}
+
+/// Reduce a host name or URL to the lowercase `host[:port]` we index by,
+/// with `http://` in front for a plain HTTP URL
+pub fn normalize_host(host: &str) -> Result<String, CredentialError> {
+    let invalid = || CredentialError::InvalidHost { host: host.to_string() };
+    let url = if host.contains("://") { Url::parse(host) } else { Url::parse(&format!("https://{}", host)) }
+        .map_err(|_| invalid())?;
+    let name = url.host_str().ok_or_else(invalid)?.to_lowercase();
+    if url.path() != "/" {
+        warn!("Credentials apply to the whole host; ignoring the path in {}", host);
+    }
+    let name = match url.port() {
+        Some(port) => format!("{}:{}", name, port),
+        None => name,
+    };
+    match url.scheme() {
+        "https" => Ok(name),
+        "http" => Ok(format!("http://{}", name)),
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Secrets kept in memory instead of the system keyring
+    #[derive(Default)]
+    struct MemorySecrets(Mutex<HashMap<String, String>>);
+
+    impl SecretStore for MemorySecrets {
+        fn get(&self, host: &str) -> Result<Option<String>, CredentialError> {
+            Ok(self.0.lock().unwrap().get(host).cloned())
+        }
+
+        fn set(&self, host: &str, secret: &str) -> Result<(), CredentialError> {
+            self.0.lock().unwrap().insert(host.to_string(), secret.to_string());
+            Ok(())
+        }
+
+        fn delete(&self, host: &str) -> Result<(), CredentialError> {
+            self.0.lock().unwrap().remove(host);
+            Ok(())
+        }
+    }
+
+    fn empty_store() -> CredentialStore {
+        CredentialStore {
+            path: PathBuf::from("/nonexistent/credentials.json"),
+            entries: Vec::new(),
+            secrets: Box::new(MemorySecrets::default()),
+        }
+    }
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn test_normalize_host() {
+        assert_eq!(normalize_host("Example.COM").unwrap(), "example.com");
+        assert_eq!(normalize_host("https://example.com/some/path").unwrap(), "example.com");
+        assert_eq!(normalize_host("example.com:8443").unwrap(), "example.com:8443");
+        assert_eq!(normalize_host("HTTP://Example.com:8080/").unwrap(), "http://example.com:8080");
+        assert!(normalize_host("").is_err());
+        assert!(normalize_host("ftp://example.com").is_err());
+    }
+
+    #[test]
+    fn test_add_and_lookup() {
+        let mut store = empty_store();
+        let basic = Credential::Basic { username: "me".into(), password: "hunter2".into() };
+        store.add("example.com", &basic).unwrap();
+
+        assert_eq!(store.lookup(&url("https://example.com/file.iso")).unwrap(), Some(basic.clone()));
+        assert_eq!(store.lookup(&url("https://other.com/file.iso")).unwrap(), None);
+        assert_eq!(store.entries()[0].username.as_deref(), Some("me"));
+
+        // Never over plain HTTP, unless added for it
+        assert_eq!(store.lookup(&url("http://example.com/file.iso")).unwrap(), None);
+        store.add("http://example.com", &basic).unwrap();
+        assert_eq!(store.lookup(&url("http://example.com/file.iso")).unwrap(), Some(basic));
+    }
+
+    #[test]
+    fn test_lookup_prefers_host_with_port() {
+        let mut store = empty_store();
+        let everywhere = Credential::Bearer { token: "a".into() };
+        let on_port = Credential::Bearer { token: "b".into() };
+        store.add("example.com", &everywhere).unwrap();
+        store.add("example.com:8443", &on_port).unwrap();
+
+        assert_eq!(store.lookup(&url("https://example.com:8443/x")).unwrap(), Some(on_port));
+        assert_eq!(store.lookup(&url("https://example.com/x")).unwrap(), Some(everywhere));
+    }
+
+    #[test]
+    fn test_add_replaces_and_remove() {
+        let mut store = empty_store();
+        store.add("example.com", &Credential::Bearer { token: "old".into() }).unwrap();
+        store.add("example.com", &Credential::Bearer { token: "new".into() }).unwrap();
+        assert_eq!(store.entries().len(), 1);
+        assert_eq!(
+            store.lookup(&url("https://example.com/")).unwrap(),
+            Some(Credential::Bearer { token: "new".into() })
+        );
+
+        assert!(store.remove("example.com").unwrap());
+        assert!(!store.remove("example.com").unwrap());
+        assert_eq!(store.lookup(&url("https://example.com/")).unwrap(), None);
+    }
+
+    #[test]
+    fn test_missing_secret() {
+        let mut store = empty_store();
+        store.entries.push(HostEntry {
+            host: "example.com".into(),
+            scheme: AuthScheme::Bearer,
+            username: None,
+            provider: None,
+        });
+        assert!(matches!(
+            store.lookup(&url("https://example.com/")),
+            Err(CredentialError::MissingSecret { .. })
+        ));
+    }
+
+    #[test]
+    fn test_oauth_tokens_shared_between_hosts() {
+        let mut store = empty_store();
+        let tokens = TokenSet { access_token: "old".into(), refresh_token: Some("r".into()), expires_at: None };
+        store.add_oauth("github", &["github.com".into(), "api.github.com".into()], &tokens).unwrap();
+
+        let refreshed = TokenSet { access_token: "new".into(), ..tokens };
+        store.update_oauth("github", &refreshed).unwrap();
+        let expected = Some(Credential::OAuth { provider: "github".into(), tokens: refreshed });
+        assert_eq!(store.lookup(&url("https://api.github.com/repos")).unwrap(), expected);
+
+        // Removing one host leaves the tokens for the others
+        assert!(store.remove("api.github.com").unwrap());
+        assert_eq!(store.lookup(&url("https://github.com/")).unwrap(), expected);
+
+        assert_eq!(store.logout("github").unwrap(), 1);
+        assert!(store.entries().is_empty());
+    }
+
+    #[test]
+    fn test_debug_hides_secrets() {
+        let basic = Credential::Basic { username: "me".into(), password: "hunter2".into() };
+        assert!(!format!("{:?}", basic).contains("hunter2"));
+    }
+}
@@ -0,0 +1,656 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::cancel::Cancellation;
+use crate::partial::PartFile;
+
+/// Name of the file inside the XDG data directory holding the queue
+const QUEUE_FILE: &str = "queue.jsonl";
+
+/// Name of the file next to the queue that the running daemon keeps locked
+const LOCK_FILE: &str = "daemon.lock";
+
+/// Directory next to the queue where each job's run writes its report
+const REPORTS_DIR: &str = "reports";
+
+/// Downloads the daemon runs at once, unless told otherwise
+pub const DEFAULT_JOBS: usize = 4;
+
+/// How often the daemon looks for new jobs
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Errors from the download queue
+#[derive(Debug, thiserror::Error)]
+pub enum DaemonError {
+    #[error("no data directory to keep the queue in")]
+    NoDataDir,
+
+    #[error("{path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("could not run {program}: {source}")]
+    Spawn { program: String, source: io::Error },
+
+    #[error("could not listen on {address}: {source}")]
+    Listen { address: String, source: io::Error },
+
+    #[error("another daemon is already working through {0}")]
+    AlreadyRunning(PathBuf),
+
+    #[error("there's no job {0}")]
+    NoSuchJob(u64),
+
+    #[error("job {id} is {state}")]
+    Unchanged { id: u64, state: &'static str },
+}
+
+/// One line of the queue file. The queue is only ever appended to, so jobs
+/// can be added while the daemon's running, and it's replayed to find out
+/// where each job is.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum Record {
+    Added {
+        id: u64,
+        url: String,
+        dir: PathBuf,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        start_at: Option<u64>,
+    },
+    Started { id: u64 },
+    Finished {
+        id: u64,
+        file: Option<PathBuf>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        bytes: Option<u64>,
+    },
+    Failed { id: u64, error: String },
+    Paused { id: u64 },
+    /// Back in the queue after being paused
+    Resumed { id: u64 },
+    Cancelled { id: u64 },
+}
+
+/// Where a job has got to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Queued,
+    /// Started, and not finished yet; if the daemon isn't running, it was
+    /// stopped partway through and will carry on when it's started again
+    Running,
+    Done,
+    Failed,
+    /// Waiting to be resumed, keeping whatever it had downloaded
+    Paused,
+    Cancelled,
+}
+
+impl State {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            State::Queued => "queued",
+            State::Running => "running",
+            State::Done => "done",
+            State::Failed => "failed",
+            State::Paused => "paused",
+            State::Cancelled => "cancelled",
+        }
+    }
+
+    /// Whether nothing more will happen to a job in this state
+    pub fn is_over(&self) -> bool {
+        matches!(self, State::Done | State::Failed | State::Cancelled)
+    }
+}
+
+/// How far a running job has got, with its speed in bytes per second
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Progress {
+    pub bytes: u64,
+    pub total: Option<u64>,
+    pub speed: f64,
+}
+
+/// A URL waiting to be downloaded, or that has been
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    pub url: String,
+    /// Directory to save it in
+    pub dir: PathBuf,
+    pub state: State,
+    /// When it's to start, in seconds since the Unix epoch, if it's waiting for off-peak hours
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+    /// How much was downloaded, once it's done
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// How far it's got, while the daemon's downloading it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress: Option<Progress>,
+}
+
+/// How many jobs are in each state, and how much the finished ones downloaded
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Summary {
+    pub queued: usize,
+    pub running: usize,
+    pub paused: usize,
+    pub done: usize,
+    pub failed: usize,
+    pub cancelled: usize,
+    pub bytes: u64,
+}
+
+/// The jobs added with `download add`, kept on disk so they outlive the daemon
+#[derive(Debug, Clone)]
+pub struct Queue {
+    path: PathBuf,
+}
+
+impl Queue {
+    /// Default location of the queue
+    pub fn default_path() -> Result<PathBuf, DaemonError> {
+        xdg::BaseDirectories::with_prefix("rustdl")
+            .get_data_home()
+            .map(|dir| dir.join(QUEUE_FILE))
+            .ok_or(DaemonError::NoDataDir)
+    }
+
+    pub fn new(path: PathBuf) -> Self {
+        Queue { path }
+    }
+
+    /// Every job, in the order they were added
+    pub fn jobs(&self) -> Result<Vec<Job>, DaemonError> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(source) => return Err(self.io_error(source)),
+        };
+        replay(BufReader::new(file)).map_err(|source| self.io_error(source))
+    }
+
+    /// Queue `url` to be saved in `dir`, not starting before `start_at`, returning its job's ID
+    pub fn add(&self, url: &str, dir: &Path, start_at: Option<SystemTime>) -> Result<u64, DaemonError> {
+        let mut file = self.open()?;
+        // Held until we've written, so two at once can't take the same ID
+        file.lock().map_err(|source| self.io_error(source))?;
+        let id = replay(BufReader::new(File::open(&self.path).map_err(|source| self.io_error(source))?))
+            .map_err(|source| self.io_error(source))?
+            .last()
+            .map_or(1, |job| job.id + 1);
+        let start_at = start_at.map(|time| time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs());
+        write_record(&mut file, &Record::Added { id, url: url.to_string(), dir: dir.to_path_buf(), start_at }).map_err(|source| self.io_error(source))?;
+        Ok(id)
+    }
+
+    fn record(&self, record: &Record) -> Result<(), DaemonError> {
+        let mut file = self.open()?;
+        file.lock().map_err(|source| self.io_error(source))?;
+        write_record(&mut file, record).map_err(|source| self.io_error(source))
+    }
+
+    /// Take the lock that only one daemon at a time can hold, for as long as
+    /// the file returned is open
+    fn lock_daemon(&self) -> Result<File, DaemonError> {
+        let path = self.path.with_file_name(LOCK_FILE);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(|source| DaemonError::Io { path: path.clone(), source })?;
+        match file.try_lock() {
+            Ok(()) => Ok(file),
+            Err(TryLockError::WouldBlock) => Err(DaemonError::AlreadyRunning(self.path.clone())),
+            Err(TryLockError::Error(source)) => Err(DaemonError::Io { path, source }),
+        }
+    }
+
+    /// Where job `id`'s run writes its report: beside the queue, where only
+    /// we can write, rather than in a temporary directory anyone can
+    fn report_path(&self, id: u64) -> Result<PathBuf, DaemonError> {
+        let dir = self.path.with_file_name(REPORTS_DIR);
+        fs::create_dir_all(&dir).map_err(|source| DaemonError::Io { path: dir.clone(), source })?;
+        let path = dir.join(format!("job-{}.json", id));
+        match fs::remove_file(&path) {
+            Err(source) if source.kind() != io::ErrorKind::NotFound => Err(DaemonError::Io { path, source }),
+            _ => Ok(path),
+        }
+    }
+
+    fn open(&self) -> Result<File, DaemonError> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).map_err(|source| self.io_error(source))?;
+        }
+        OpenOptions::new().create(true).append(true).open(&self.path).map_err(|source| self.io_error(source))
+    }
+
+    fn io_error(&self, source: io::Error) -> DaemonError {
+        DaemonError::Io { path: self.path.clone(), source }
+    }
+}
+
+fn write_record(file: &mut File, record: &Record) -> io::Result<()> {
+    let mut line = serde_json::to_string(record).map_err(io::Error::other)?;
+    line.push('\n');
+    file.write_all(line.as_bytes())
+}
+
+/// The jobs a queue file's records add up to
+fn replay(queue: impl BufRead) -> io::Result<Vec<Job>> {
+    let mut jobs = BTreeMap::new();
+    for line in queue.lines() {
+        let line = line?;
+        let record = match serde_json::from_str::<Record>(&line) {
+            Ok(record) => record,
+            Err(e) => {
+                debug!("Skipping unreadable queue line: {}", e);
+                continue;
+            }
+        };
+        let id = match record {
+            Record::Added { id, url, dir, start_at } => {
+                jobs.insert(id, Job { id, url, dir, state: State::Queued, start_at, file: None, bytes: None, error: None, progress: None });
+                continue;
+            }
+            Record::Started { id } | Record::Finished { id, .. } | Record::Failed { id, .. } | Record::Paused { id } | Record::Resumed { id } | Record::Cancelled { id } => id,
+        };
+        let Some(job) = jobs.get_mut(&id) else { continue };
+        match record {
+            Record::Added { .. } => {}
+            Record::Started { .. } => job.state = State::Running,
+            Record::Finished { file, bytes, .. } => {
+                job.state = State::Done;
+                job.file = file;
+                job.bytes = bytes;
+            }
+            Record::Failed { error, .. } => {
+                job.state = State::Failed;
+                job.error = Some(error);
+            }
+            Record::Paused { .. } => job.state = State::Paused,
+            Record::Resumed { .. } => job.state = State::Queued,
+            Record::Cancelled { .. } => job.state = State::Cancelled,
+        }
+    }
+    Ok(jobs.into_values().collect())
+}
+
+/// A job the daemon's downloading
+#[derive(Debug)]
+struct Task {
+    child: Arc<Mutex<Child>>,
+    progress: Progress,
+}
+
+/// What can be done to the queue while the daemon's working through it,
+/// shared between the daemon and whatever's driving it
+#[derive(Debug, Clone)]
+pub struct Control {
+    queue: Queue,
+    tasks: Arc<Mutex<HashMap<u64, Task>>>,
+}
+
+impl Control {
+    pub fn new(queue: Queue) -> Self {
+        Control { queue, tasks: Arc::default() }
+    }
+
+    /// Every job, with how far the running ones have got
+    pub fn jobs(&self) -> Result<Vec<Job>, DaemonError> {
+        let mut jobs = self.queue.jobs()?;
+        let tasks = self.tasks.lock().unwrap();
+        for job in &mut jobs {
+            job.progress = tasks.get(&job.id).map(|task| task.progress);
+        }
+        Ok(jobs)
+    }
+
+    pub fn job(&self, id: u64) -> Result<Job, DaemonError> {
+        self.jobs()?.into_iter().find(|job| job.id == id).ok_or(DaemonError::NoSuchJob(id))
+    }
+
+    /// Queue `url` to be saved in `dir`, not starting before `start_at`
+    pub fn add(&self, url: &str, dir: &Path, start_at: Option<SystemTime>) -> Result<Job, DaemonError> {
+        let id = self.queue.add(url, dir, start_at)?;
+        info!("Queued job {}: {}", id, url);
+        self.job(id)
+    }
+
+    /// Stop a job until it's resumed, keeping what it's downloaded so far
+    pub fn pause(&self, id: u64) -> Result<Job, DaemonError> {
+        let job = self.job(id)?;
+        if !matches!(job.state, State::Queued | State::Running) {
+            return Err(DaemonError::Unchanged { id, state: job.state.as_str() });
+        }
+        self.queue.record(&Record::Paused { id })?;
+        self.stop(id);
+        info!("Paused job {}", id);
+        self.job(id)
+    }
+
+    /// Put a paused job back in the queue
+    pub fn resume(&self, id: u64) -> Result<Job, DaemonError> {
+        let job = self.job(id)?;
+        if job.state != State::Paused {
+            return Err(DaemonError::Unchanged { id, state: job.state.as_str() });
+        }
+        self.queue.record(&Record::Resumed { id })?;
+        info!("Resumed job {}", id);
+        self.job(id)
+    }
+
+    /// Give up on a job, throwing away whatever it's downloaded so far
+    pub fn cancel(&self, id: u64) -> Result<Job, DaemonError> {
+        let job = self.job(id)?;
+        if job.state.is_over() {
+            return Err(DaemonError::Unchanged { id, state: job.state.as_str() });
+        }
+        self.queue.record(&Record::Cancelled { id })?;
+        // A running download's partial file goes once it has stopped
+        if !self.stop(id)
+            && let Some(part) = resumable(&job)
+        {
+            part.discard();
+        }
+        info!("Cancelled job {}", id);
+        self.job(id)
+    }
+
+    pub fn summary(&self) -> Result<Summary, DaemonError> {
+        let mut summary = Summary::default();
+        for job in self.queue.jobs()? {
+            let count = match job.state {
+                State::Queued => &mut summary.queued,
+                State::Running => &mut summary.running,
+                State::Done => &mut summary.done,
+                State::Failed => &mut summary.failed,
+                State::Paused => &mut summary.paused,
+                State::Cancelled => &mut summary.cancelled,
+            };
+            *count += 1;
+            summary.bytes += job.bytes.unwrap_or_default();
+        }
+        Ok(summary)
+    }
+
+    /// Stop a job's download if it's running, returning whether it was
+    fn stop(&self, id: u64) -> bool {
+        let Some(child) = self.tasks.lock().unwrap().get(&id).map(|task| Arc::clone(&task.child)) else {
+            return false;
+        };
+        interrupt(&mut child.lock().unwrap());
+        true
+    }
+}
+
+/// Stop a download as Ctrl-C would, so it leaves its `.part` file tidy
+#[cfg(unix)]
+fn interrupt(child: &mut Child) {
+    // SAFETY: kill() only sends a signal; at worst the process has gone and it fails
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) };
+}
+
+/// Stop a download; there's no Ctrl-C to send it here, so it's only as
+/// resumable as its last checkpoint
+#[cfg(not(unix))]
+fn interrupt(child: &mut Child) {
+    let _ = child.kill();
+}
+
+/// The events we follow in a job's `--progress json`
+#[derive(Debug, Deserialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum Reported {
+    Started { total: Option<u64> },
+    Progress { bytes: u64, total: Option<u64>, speed: f64 },
+    Finished { bytes: u64 },
+    #[serde(other)]
+    Other,
+}
+
+/// The little of `--report-json` we need from a job's run
+#[derive(Debug, Deserialize)]
+struct RunReport {
+    downloads: Vec<RunEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RunEntry {
+    file: Option<PathBuf>,
+    error: Option<String>,
+}
+
+/// How a job's run went
+#[derive(Debug)]
+struct Outcome {
+    status: ExitStatus,
+    file: Option<PathBuf>,
+    bytes: u64,
+    error: Option<String>,
+}
+
+/// Works through the queue a few jobs at a time, each downloaded by running
+/// `program` (this program, normally) as if it had been asked on the command line
+pub struct Daemon<'a> {
+    pub control: &'a Control,
+    /// Most downloads to run at once
+    pub jobs: usize,
+    pub program: PathBuf,
+    pub cancellation: &'a Cancellation,
+}
+
+impl Daemon<'_> {
+    /// Run jobs as they're added, until we're cancelled; downloads under way
+    /// then are stopped as Ctrl-C would, to be picked up by the next daemon
+    pub fn run(&self) -> Result<(), DaemonError> {
+        let queue = &self.control.queue;
+        let _lock = queue.open().and_then(|_| queue.lock_daemon())?;
+        // Whatever was running when the last daemon stopped is started again
+        let mut interrupted: HashSet<u64> = queue.jobs()?.iter().filter(|job| job.state == State::Running).map(|job| job.id).collect();
+        if !interrupted.is_empty() {
+            info!("Picking up {} interrupted downloads", interrupted.len());
+        }
+        let (sender, finished) = mpsc::channel();
+        let mut stopping = false;
+        loop {
+            let cancelled = self.cancellation.reason().is_some();
+            if cancelled && !stopping {
+                stopping = true;
+                let running: Vec<u64> = self.control.tasks.lock().unwrap().keys().copied().collect();
+                if !running.is_empty() {
+                    info!("Stopping {} downloads", running.len());
+                }
+                for id in running {
+                    self.control.stop(id);
+                }
+            }
+            let running: HashSet<u64> = self.control.tasks.lock().unwrap().keys().copied().collect();
+            if cancelled && running.is_empty() {
+                return Ok(());
+            }
+            if !cancelled {
+                let jobs = queue.jobs()?;
+                let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+                let ready = jobs
+                    .iter()
+                    .filter(|job| !running.contains(&job.id))
+                    .filter(|job| (job.state == State::Queued && job.start_at.is_none_or(|start| start <= now)) || interrupted.contains(&job.id));
+                for job in ready.take(self.jobs.saturating_sub(running.len())).cloned().collect::<Vec<_>>() {
+                    interrupted.remove(&job.id);
+                    queue.record(&Record::Started { id: job.id })?;
+                    info!("Starting job {}: {}", job.id, job.url);
+                    if let Err(e) = self.start(&job, sender.clone()) {
+                        error!("Job {} failed: {}", job.id, e);
+                        queue.record(&Record::Failed { id: job.id, error: e.to_string() })?;
+                    }
+                }
+            }
+            let Ok((job, outcome)) = finished.recv_timeout(POLL_INTERVAL) else { continue };
+            self.control.tasks.lock().unwrap().remove(&job.id);
+            self.finish(&job, outcome)?;
+        }
+    }
+
+    /// Start downloading `job`, carrying on from its `.part` file if it got
+    /// partway before; how it went is sent to `finished` when it's done
+    fn start(&self, job: &Job, finished: mpsc::Sender<(Job, io::Result<Outcome>)>) -> Result<(), DaemonError> {
+        let report = self.control.queue.report_path(job.id)?;
+        let resumed = resumable(job);
+        let mut command = Command::new(&self.program);
+        // Out of the terminal's process group, so Ctrl-C reaches only us, and
+        // each download's stopped once, by us, rather than twice
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        match &resumed {
+            Some(part) => {
+                info!("Resuming job {} from {}", job.id, part.data_path().display());
+                command.args(["resume", "--progress", "json"]).arg(part.data_path());
+            }
+            None => {
+                command.args(["--progress", "json", "--report-json"]).arg(&report).arg("--output-dir").arg(&job.dir).arg("--").arg(&job.url);
+            }
+        }
+        let mut child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|source| DaemonError::Spawn { program: self.program.display().to_string(), source })?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let child = Arc::new(Mutex::new(child));
+        self.control.tasks.lock().unwrap().insert(job.id, Task { child: Arc::clone(&child), progress: Progress::default() });
+
+        let tasks = Arc::clone(&self.control.tasks);
+        let job = job.clone();
+        let resumed = resumed.map(|part| part.output_path());
+        thread::spawn(move || {
+            let mut bytes = 0;
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                let progress = match serde_json::from_str(&line) {
+                    Ok(Reported::Started { total }) => Progress { bytes: 0, total, speed: 0.0 },
+                    Ok(Reported::Progress { bytes, total, speed }) => Progress { bytes, total, speed },
+                    Ok(Reported::Finished { bytes: done }) => {
+                        bytes += done;
+                        continue;
+                    }
+                    _ => continue,
+                };
+                if let Some(task) = tasks.lock().unwrap().get_mut(&job.id) {
+                    task.progress = progress;
+                }
+            }
+            let outcome = child.lock().unwrap().wait().map(|status| {
+                let entry = fs::read(&report).ok().and_then(|json| serde_json::from_slice::<RunReport>(&json).ok()).and_then(|report| report.downloads.into_iter().next());
+                let _ = fs::remove_file(&report);
+                let (file, error) = entry.map_or((resumed, None), |entry| (entry.file, entry.error));
+                Outcome { status, file, bytes, error }
+            });
+            let _ = finished.send((job, outcome));
+        });
+        Ok(())
+    }
+
+    /// Record how `job` went, unless it was stopped on purpose
+    fn finish(&self, job: &Job, outcome: io::Result<Outcome>) -> Result<(), DaemonError> {
+        let queue = &self.control.queue;
+        let state = self.control.job(job.id)?.state;
+        match outcome {
+            Ok(outcome) if outcome.status.success() => {
+                info!("Finished job {}: {}", job.id, job.url);
+                queue.record(&Record::Finished { id: job.id, file: outcome.file, bytes: Some(outcome.bytes) })
+            }
+            // Its partial file is kept for when it's resumed
+            _ if state == State::Paused => {
+                debug!("Job {} paused", job.id);
+                Ok(())
+            }
+            _ if state == State::Cancelled => {
+                if let Some(part) = resumable(job) {
+                    part.discard();
+                }
+                Ok(())
+            }
+            // Stopped along with us, so it's left running for next time
+            Ok(_) if self.cancellation.reason().is_some() => {
+                debug!("Job {} stopped", job.id);
+                Ok(())
+            }
+            Ok(outcome) => {
+                let error = outcome.error.unwrap_or_else(|| format!("download exited with {}", outcome.status));
+                error!("Job {} failed: {}", job.id, error);
+                queue.record(&Record::Failed { id: job.id, error })
+            }
+            Err(e) => {
+                error!("Job {} failed: {}", job.id, e);
+                queue.record(&Record::Failed { id: job.id, error: e.to_string() })
+            }
+        }
+    }
+}
+
+/// The partial download a job left behind, if there's one to carry on from
+fn resumable(job: &Job) -> Option<PartFile> {
+    let parts = PartFile::find(&job.dir).ok()?;
+    parts.iter().filter_map(|path| PartFile::open(path).ok()).find(|part| part.metadata.url == job.url && part.can_resume())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queue() {
+        let dir = std::env::temp_dir().join(format!("rustdl-daemon-{}", std::process::id()));
+        let queue = Queue::new(dir.join(QUEUE_FILE));
+        assert!(queue.jobs().unwrap().is_empty());
+        assert_eq!(queue.add("https://example.com/a.iso", Path::new("/downloads"), None).unwrap(), 1);
+        assert_eq!(queue.add("https://example.com/b.iso", Path::new("/downloads"), Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1772326800))).unwrap(), 2);
+        queue.record(&Record::Started { id: 1 }).unwrap();
+        queue.record(&Record::Finished { id: 1, file: Some(PathBuf::from("/downloads/a.iso")), bytes: Some(100) }).unwrap();
+        queue.record(&Record::Started { id: 2 }).unwrap();
+
+        let jobs = queue.jobs().unwrap();
+        assert_eq!((jobs[0].state, jobs[0].file.as_deref(), jobs[0].bytes), (State::Done, Some(Path::new("/downloads/a.iso")), Some(100)));
+        assert_eq!((jobs[1].state, jobs[1].url.as_str(), jobs[1].start_at), (State::Running, "https://example.com/b.iso", Some(1772326800)));
+        // Nothing was left behind, so it starts again from the beginning
+        assert!(resumable(&jobs[1]).is_none());
+
+        let lock = queue.lock_daemon().unwrap();
+        assert!(matches!(queue.lock_daemon(), Err(DaemonError::AlreadyRunning(_))));
+        drop(lock);
+        queue.lock_daemon().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_control() {
+        let dir = std::env::temp_dir().join(format!("rustdl-control-{}", std::process::id()));
+        let control = Control::new(Queue::new(dir.join(QUEUE_FILE)));
+        let job = control.add("https://example.com/a.iso", Path::new("/downloads"), None).unwrap();
+        assert_eq!(control.pause(job.id).unwrap().state, State::Paused);
+        assert!(matches!(control.pause(job.id), Err(DaemonError::Unchanged { state: "paused", .. })));
+        assert_eq!(control.resume(job.id).unwrap().state, State::Queued);
+        assert_eq!(control.cancel(job.id).unwrap().state, State::Cancelled);
+        assert!(matches!(control.resume(job.id), Err(DaemonError::Unchanged { state: "cancelled", .. })));
+        assert!(matches!(control.job(7), Err(DaemonError::NoSuchJob(7))));
+        assert_eq!(control.summary().unwrap(), Summary { cancelled: 1, ..Summary::default() });
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
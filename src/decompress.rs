@@ -0,0 +1,205 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use log::debug;
+
+use crate::checksum::{self, Checksum, ChecksumError, HashingWriter};
+
+/// Errors from decompressing a download
+#[derive(Debug, thiserror::Error)]
+pub enum DecompressError {
+    #[error("could not decompress {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("decompressed contents: {0}")]
+    Checksum(ChecksumError),
+}
+
+/// Single-file compression formats, recognised by their extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    pub fn all() -> Vec<Compression> {
+        vec![Compression::Gzip, Compression::Xz, Compression::Zstd]
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Xz => "xz",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gz",
+            Compression::Xz => "xz",
+            Compression::Zstd => "zst",
+        }
+    }
+
+    /// How `path` is compressed and what it's called without the extension,
+    /// if it's a single compressed file; compressed tarballs are archives,
+    /// so they're left alone
+    pub fn detect(path: &Path) -> Option<(Compression, PathBuf)> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        let compression = Compression::all().into_iter().find(|c| c.extension() == extension)?;
+        let stem = path.file_stem()?;
+        if Path::new(stem).extension().is_some_and(|inner| inner.eq_ignore_ascii_case("tar")) {
+            return None;
+        }
+        Some((compression, path.with_file_name(stem)))
+    }
+
+    /// A reader giving the decompressed contents of `reader`
+    #[cfg(feature = "compression")]
+    pub fn decoder<'a>(&self, reader: impl Read + 'a) -> io::Result<Box<dyn Read + 'a>> {
+        let reader = io::BufReader::new(reader);
+        Ok(match self {
+            Compression::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(reader)),
+            Compression::Xz => Box::new(xz2::bufread::XzDecoder::new_multi_decoder(reader)),
+            Compression::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(reader)?),
+        })
+    }
+
+    #[cfg(not(feature = "compression"))]
+    pub fn decoder<'a>(&self, _reader: impl Read + 'a) -> io::Result<Box<dyn Read + 'a>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "this build doesn't include the compression feature"))
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// What to do about compressed responses (`Content-Encoding`), as opposed to
+/// compressed files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ContentDecoding {
+    /// Whatever the HTTP client does by itself: with the compression
+    /// feature, ask for gzip, brotli, zstd or deflate and decompress them
+    #[default]
+    Auto,
+    /// Ask for a compressed response and decompress it to disk (`--compressed`)
+    Compressed,
+    /// Ask for nothing and save exactly the bytes the server sent, encoded or
+    /// not (`--no-decompress`), so checksums of pre-compressed files hold
+    Raw,
+}
+
+/// Decompress `path` to `output` (see [`Compression::detect`]), and remove the
+/// compressed file once that's worked. With `expected`, the decompressed
+/// contents have to match it. Returns where the decompressed file went.
+pub fn decompress_file(path: &Path, compression: Compression, output: &Path, expected: Option<&Checksum>) -> Result<PathBuf, DecompressError> {
+    debug!("Decompressing {} ({}) to {}", path.display(), compression, output.display());
+    let mut partial = output.as_os_str().to_os_string();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+
+    let result = decompress_to(path, compression, &partial, expected);
+    if let Err(e) = result {
+        let _ = fs::remove_file(&partial);
+        return Err(e);
+    }
+    // The decompressed file is as old as the compressed one
+    if let Ok(modified) = fs::metadata(path).and_then(|metadata| metadata.modified()) {
+        let _ = File::options().write(true).open(&partial).and_then(|file| file.set_modified(modified));
+    }
+    fs::rename(&partial, output).map_err(|source| DecompressError::Io { path: output.to_path_buf(), source })?;
+    fs::remove_file(path).map_err(|source| DecompressError::Io { path: path.to_path_buf(), source })?;
+    Ok(output.to_path_buf())
+}
+
+fn decompress_to(path: &Path, compression: Compression, partial: &Path, expected: Option<&Checksum>) -> Result<(), DecompressError> {
+    let io_err = |source| DecompressError::Io { path: path.to_path_buf(), source };
+    let mut decoder = compression.decoder(File::open(path).map_err(io_err)?).map_err(io_err)?;
+    let out = File::create(partial).map_err(|source| DecompressError::Io { path: partial.to_path_buf(), source })?;
+    match expected {
+        Some(expected) => {
+            let mut writer = HashingWriter::new(out, expected.algorithm);
+            io::copy(&mut decoder, &mut writer).and_then(|_| writer.flush()).map_err(io_err)?;
+            let (_, actual) = writer.finish();
+            checksum::verify(expected, &actual).map_err(DecompressError::Checksum)
+        }
+        None => {
+            let mut out = out;
+            io::copy(&mut decoder, &mut out).map(drop).map_err(io_err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(Compression::detect(Path::new("dir/dump.sql.gz")), Some((Compression::Gzip, PathBuf::from("dir/dump.sql"))));
+        assert_eq!(Compression::detect(Path::new("access.log.XZ")), Some((Compression::Xz, PathBuf::from("access.log"))));
+        assert_eq!(Compression::detect(Path::new("data.zst")), Some((Compression::Zstd, PathBuf::from("data"))));
+        assert_eq!(Compression::detect(Path::new("src.tar.gz")), None);
+        assert_eq!(Compression::detect(Path::new("dump.sql")), None);
+        assert_eq!(Compression::detect(Path::new("archive.zip")), None);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_decoders() {
+        let data = b"2026-01-01 GET /index.html 200\n".repeat(100);
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(&data).unwrap();
+        let mut xz = xz2::write::XzEncoder::new(Vec::new(), 6);
+        xz.write_all(&data).unwrap();
+        let compressed = [
+            (Compression::Gzip, gzip.finish().unwrap()),
+            (Compression::Xz, xz.finish().unwrap()),
+            (Compression::Zstd, zstd::encode_all(&data[..], 0).unwrap()),
+        ];
+        for (compression, compressed) in compressed {
+            let mut decompressed = Vec::new();
+            compression.decoder(&compressed[..]).unwrap().read_to_end(&mut decompressed).unwrap();
+            assert_eq!(decompressed, data, "{}", compression);
+        }
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_decompress_file_checks_the_contents() {
+        use crate::checksum::HashAlgorithm;
+
+        let dir = std::env::temp_dir().join(format!("rustdl-decompress-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let compressed = dir.join("dump.sql.gz");
+        let mut encoder = flate2::write::GzEncoder::new(File::create(&compressed).unwrap(), flate2::Compression::default());
+        encoder.write_all(b"SELECT 1;\n").unwrap();
+        encoder.finish().unwrap();
+
+        let mut hasher = HashingWriter::new(io::sink(), HashAlgorithm::Sha256);
+        hasher.write_all(b"SELECT 1;\n").unwrap();
+        let expected = hasher.finish().1;
+        let (compression, output) = Compression::detect(&compressed).unwrap();
+        let output = decompress_file(&compressed, compression, &output, Some(&expected)).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), b"SELECT 1;\n");
+        assert!(!compressed.exists());
+
+        // A mismatch leaves the compressed file where it was
+        let mut encoder = flate2::write::GzEncoder::new(File::create(&compressed).unwrap(), flate2::Compression::default());
+        encoder.write_all(b"SELECT 2;\n").unwrap();
+        encoder.finish().unwrap();
+        fs::remove_file(&output).unwrap();
+        assert!(matches!(decompress_file(&compressed, compression, &output, Some(&expected)), Err(DecompressError::Checksum(_))));
+        assert!(compressed.exists());
+        assert!(!output.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,57 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use url::Url;
+
+/// `urls` with only the first of each URL kept, and the repeats that were
+/// dropped. URLs that differ only in ways that don't matter to the server,
+/// like the case of the host or a default port, count as the same.
+pub fn unique(urls: Vec<String>) -> (Vec<String>, Vec<String>) {
+    let mut seen = HashSet::new();
+    urls.into_iter().partition(|url| seen.insert(normalized(url)))
+}
+
+fn normalized(url: &str) -> String {
+    Url::parse(url).map(String::from).unwrap_or_else(|_| url.to_string())
+}
+
+/// The files a batch is saving to, so that two URLs that come out with the
+/// same name don't both write to it at once
+#[derive(Debug, Default)]
+pub struct Outputs {
+    taken: Mutex<HashMap<PathBuf, String>>,
+}
+
+impl Outputs {
+    /// Save `url` as `path`, or the earlier URL of the batch that's already being saved there
+    pub fn take(&self, path: &Path, url: &str) -> Result<(), String> {
+        let key = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+        match self.taken.lock().unwrap().entry(key) {
+            Entry::Occupied(earlier) => Err(earlier.get().clone()),
+            Entry::Vacant(free) => {
+                free.insert(url.to_string());
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dedupe() {
+        let urls = ["https://example.com/a.iso", "https://EXAMPLE.com:443/a.iso", "https://example.com/b.iso", "https://example.com/a.iso"];
+        let (unique, repeats) = unique(urls.map(String::from).to_vec());
+        assert_eq!(unique, ["https://example.com/a.iso", "https://example.com/b.iso"]);
+        assert_eq!(repeats, ["https://EXAMPLE.com:443/a.iso", "https://example.com/a.iso"]);
+
+        let outputs = Outputs::default();
+        assert!(outputs.take(Path::new("a.iso"), "https://example.com/a.iso").is_ok());
+        assert!(outputs.take(Path::new("b.iso"), "https://example.com/b.iso").is_ok());
+        assert_eq!(outputs.take(Path::new("./a.iso"), "https://mirror.example.com/a.iso"), Err("https://example.com/a.iso".to_string()));
+    }
+}
@@ -0,0 +1,124 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use indicatif::HumanBytes;
+use log::{debug, info, warn};
+
+use crate::checksum::{Checksum, HashAlgorithm, HashingWriter};
+
+/// Errors from writing an image to a device
+#[derive(Debug, thiserror::Error)]
+pub enum DeviceError {
+    #[error("could not write to {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("{path} holds {}, too small for a {} image", HumanBytes(*size), HumanBytes(*needed))]
+    TooSmall { path: PathBuf, size: u64, needed: u64 },
+
+    #[error("{path} doesn't read back what was written to it (wrote {written}, read back {read})")]
+    Mismatch { path: PathBuf, written: Checksum, read: Checksum },
+}
+
+/// Whether `path` is a block device, like a disk or USB stick
+#[cfg(unix)]
+pub fn is_block_device(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_block_device())
+}
+
+/// Whether `path` is a block device, like a disk or USB stick
+#[cfg(not(unix))]
+pub fn is_block_device(_path: &Path) -> bool {
+    false
+}
+
+/// Write an image straight onto a device (or any existing file, without
+/// truncating it), then read it back to make sure it got there. `length` is
+/// the image's size if we know it, so a device that's too small is refused
+/// before anything is written. Returns the image's checksum for each of
+/// `algorithms`, all of which are checked on the way back.
+pub fn write_image(path: &Path, image: &mut impl Read, length: Option<u64>, algorithms: &[HashAlgorithm]) -> Result<Vec<Checksum>, DeviceError> {
+    let io_err = |source| DeviceError::Io { path: path.to_path_buf(), source };
+    let mut device = OpenOptions::new().write(true).open(path).map_err(io_err)?;
+    let size = device.seek(SeekFrom::End(0)).map_err(io_err)?;
+    if let Some(needed) = length.filter(|needed| *needed > size) {
+        return Err(DeviceError::TooSmall { path: path.to_path_buf(), size, needed });
+    }
+    device.seek(SeekFrom::Start(0)).map_err(io_err)?;
+    debug!("Writing {} image to {} ({})", length.map(HumanBytes).map(|l| l.to_string()).unwrap_or_default(), path.display(), HumanBytes(size));
+
+    let mut writer = HashingWriter::with_algorithms(device, algorithms);
+    let written = io::copy(image, &mut writer).map_err(io_err)?;
+    let (device, written_checksums) = writer.finish_all();
+    // Make sure it's really on the device before we read it back
+    device.sync_all().map_err(io_err)?;
+    drop(device);
+
+    info!("Verifying {} bytes written to {}", written, path.display());
+    let mut readback = HashingWriter::with_algorithms(io::sink(), algorithms);
+    let device = File::open(path).map_err(io_err)?;
+    if let Err(e) = uncached(&device) {
+        warn!("Reading back {} may only read the kernel's cache of it: {}", path.display(), e);
+    }
+    io::copy(&mut device.take(written), &mut readback).map_err(io_err)?;
+    let (_, read_checksums) = readback.finish_all();
+    if let Some((written, read)) = written_checksums.iter().zip(&read_checksums).find(|(written, read)| written != read) {
+        return Err(DeviceError::Mismatch { path: path.to_path_buf(), written: written.clone(), read: read.clone() });
+    }
+    Ok(written_checksums)
+}
+
+/// Have `file` read from the device rather than from what the kernel kept of
+/// the writes, which would read back fine whatever the device did with them
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn uncached(file: &File) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    // The writes have been synced, so the cached pages are clean and can go
+    match unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) } {
+        0 => Ok(()),
+        error => Err(io::Error::from_raw_os_error(error)),
+    }
+}
+
+/// Have `file` read from the device rather than from what the kernel kept of
+/// the writes, which would read back fine whatever the device did with them
+#[cfg(target_os = "macos")]
+fn uncached(file: &File) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    match unsafe { libc::fcntl(file.as_raw_fd(), libc::F_NOCACHE, 1) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "macos")))]
+fn uncached(_file: &File) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "not supported on this platform"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_image_checks_size_and_reads_back() {
+        let path = std::env::temp_dir().join(format!("rustdl-device-{}", std::process::id()));
+        std::fs::write(&path, vec![0u8; 16]).unwrap();
+        assert!(!is_block_device(&path));
+
+        let checksums = write_image(&path, &mut &b"image"[..], Some(5), &[HashAlgorithm::Sha256]).unwrap();
+        let mut expected = HashingWriter::new(io::sink(), HashAlgorithm::Sha256);
+        io::copy(&mut &b"image"[..], &mut expected).unwrap();
+        assert_eq!(checksums, vec![expected.finish().1]);
+        let contents = std::fs::read(&path).unwrap();
+        assert_eq!(&contents[..5], b"image");
+        // The rest of the device is left alone
+        assert_eq!(contents.len(), 16);
+
+        let too_big = write_image(&path, &mut &[1u8; 32][..], Some(32), &[HashAlgorithm::Sha256]);
+        assert!(matches!(too_big, Err(DeviceError::TooSmall { size: 16, needed: 32, .. })));
+        assert_eq!(&std::fs::read(&path).unwrap()[..5], b"image");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
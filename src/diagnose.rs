@@ -0,0 +1,189 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+use log::debug;
+use url::Url;
+
+use crate::resolver::Resolver;
+
+/// A site that's always up, to tell "this server is down" from "the network is"
+const KNOWN_HOST: (&str, u16) = ("example.com", 443);
+
+/// How long each check gets; they're only worth running if they're quick
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What's probably wrong when nothing could be downloaded at all
+#[derive(Debug)]
+pub enum Hint {
+    ProxyUnreachable { proxy: String, error: io::Error },
+    /// No names can be looked up, not even well-known ones
+    DnsFailing,
+    /// Other names can be looked up, just not this one
+    UnknownHost { host: String },
+    /// Names can be looked up, but nothing can be connected to
+    Offline,
+    /// Other servers can be connected to, just not this one
+    HostUnreachable { host: String },
+    /// A well-known site's certificate isn't trusted, so something in between is swapping certificates
+    TlsIntercepted { error: String },
+}
+
+impl fmt::Display for Hint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Hint::ProxyUnreachable { proxy, error } => write!(f, "proxy unreachable: could not connect to {} ({}); check --proxy or DOWNLOAD_PROXY", proxy, error),
+            Hint::DnsFailing => write!(f, "DNS failing: no host names can be looked up, not even {}; check your network connection or DNS settings", KNOWN_HOST.0),
+            Hint::UnknownHost { host } => write!(f, "{} could not be looked up, though other names can; check the URL, or whether it's only reachable over a VPN", host),
+            Hint::Offline => write!(f, "no connection: host names can be looked up but nothing can be connected to, not even {}; are you offline or behind a firewall?", KNOWN_HOST.0),
+            Hint::HostUnreachable { host } => write!(f, "{} is not accepting connections, though other servers are; it may be down, or only reachable over a VPN", host),
+            Hint::TlsIntercepted { error } => write!(
+                f,
+                "TLS interception detected: even {}'s certificate is rejected ({}), so something on the network is replacing certificates; a proxy or antivirus may need its CA certificate installed",
+                KNOWN_HOST.0,
+                error
+            ),
+        }
+    }
+}
+
+/// Checks the way out to the network, one step at a time, to say which step
+/// is broken: the proxy, DNS, connecting, or TLS. Names are looked up by
+/// `resolver`, so the addresses checked are the ones downloads use.
+#[derive(Debug)]
+pub struct Diagnosis {
+    proxy: Option<String>,
+    resolver: Resolver,
+    known_host: (String, u16),
+}
+
+impl Diagnosis {
+    pub fn new(proxy: Option<String>, resolver: Resolver) -> Self {
+        Diagnosis { proxy, resolver, known_host: (KNOWN_HOST.0.to_string(), KNOWN_HOST.1) }
+    }
+
+    /// Work out why none of `urls` could be connected to, if the checks can tell
+    pub fn run(&self, urls: &[String]) -> Option<Hint> {
+        let url = urls.iter().find_map(|url| Url::parse(url).ok())?;
+        let host = url.host_str()?;
+        let port = url.port_or_known_default()?;
+
+        if let Some(proxy) = &self.proxy {
+            // The proxy does the looking up and connecting, so all we can check is the proxy itself
+            if let Err(error) = self.connect_proxy(proxy) {
+                return Some(Hint::ProxyUnreachable { proxy: proxy.clone(), error });
+            }
+        } else {
+            let Ok(addrs) = self.resolve(host, port) else {
+                return Some(match self.resolve(&self.known_host.0, self.known_host.1) {
+                    Ok(_) => Hint::UnknownHost { host: host.to_string() },
+                    Err(_) => Hint::DnsFailing,
+                });
+            };
+            if connect(&addrs).is_err() {
+                let reachable = self.resolve(&self.known_host.0, self.known_host.1).and_then(|known| connect(&known)).is_ok();
+                return Some(match reachable {
+                    true => Hint::HostUnreachable { host: host.to_string() },
+                    false => Hint::Offline,
+                });
+            }
+        }
+
+        match url.scheme() {
+            "https" => self.check_tls(),
+            _ => None,
+        }
+    }
+
+    /// See whether a certificate everyone trusts is trusted from here
+    fn check_tls(&self) -> Option<Hint> {
+        let mut builder = reqwest::blocking::Client::builder().timeout(CHECK_TIMEOUT);
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy).ok()?);
+        }
+        let (host, port) = &self.known_host;
+        let error = builder.build().ok()?.head(format!("https://{}:{}/", host, port)).send().err()?;
+        let error = chain(&error);
+        debug!("TLS check against {} failed: {}", host, error);
+        error.contains("certificate").then_some(Hint::TlsIntercepted { error })
+    }
+
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let addrs = self.resolver.addresses(host, port)?;
+        match addrs.is_empty() {
+            true => Err(io::Error::new(io::ErrorKind::NotFound, format!("{} has no addresses", host))),
+            false => Ok(addrs),
+        }
+    }
+
+    fn connect_proxy(&self, proxy: &str) -> io::Result<()> {
+        let url = Url::parse(proxy).map_err(io::Error::other)?;
+        let host = url.host_str().ok_or_else(|| io::Error::other("no host"))?;
+        let port = url.port_or_known_default().unwrap_or(1080);
+        connect(&self.resolve(host, port)?)
+    }
+}
+
+/// Connect to whichever address answers first
+fn connect(addrs: &[SocketAddr]) -> io::Result<()> {
+    let mut last = io::Error::new(io::ErrorKind::NotFound, "no addresses");
+    for addr in addrs {
+        match TcpStream::connect_timeout(addr, CHECK_TIMEOUT) {
+            Ok(_) => return Ok(()),
+            Err(e) => last = e,
+        }
+    }
+    Err(last)
+}
+
+/// An error and everything that caused it, as one line; the part that says
+/// what was wrong with a certificate is usually a few causes down
+fn chain(error: &dyn Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    use crate::resolver::AddressFamily;
+
+    #[test]
+    fn test_diagnosis() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap().port();
+        // Free as soon as the listener's dropped, so nothing's listening on it
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let diagnosis = |proxy: Option<String>, known_port| Diagnosis { proxy, resolver: Resolver::default(), known_host: ("127.0.0.1".to_string(), known_port) };
+        let urls = |port| vec![format!("http://127.0.0.1:{}/file", port)];
+
+        let proxy = format!("http://127.0.0.1:{}", closed);
+        assert!(matches!(diagnosis(Some(proxy), open).run(&urls(open)), Some(Hint::ProxyUnreachable { .. })));
+        assert!(matches!(diagnosis(None, open).run(&urls(closed)), Some(Hint::HostUnreachable { host }) if host == "127.0.0.1"));
+        assert!(matches!(diagnosis(None, closed).run(&urls(closed)), Some(Hint::Offline)));
+        assert!(diagnosis(None, open).run(&urls(open)).is_none());
+    }
+
+    #[test]
+    fn test_diagnosis_looks_up_like_downloads() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap().port();
+        let pinned = format!("files.invalid:{}:127.0.0.1", open).parse().unwrap();
+        let url = vec![format!("http://files.invalid:{}/file", open)];
+        let diagnosis = |resolver| Diagnosis { proxy: None, resolver, known_host: ("127.0.0.1".to_string(), open) };
+        assert!(diagnosis(Resolver::new(&[pinned], &[])).run(&url).is_none());
+        // The same address is no use to a download that's only to use IPv6
+        let only_ipv6 = Resolver::default().only(Some(AddressFamily::V6));
+        assert!(matches!(diagnosis(only_ipv6).run(&[format!("http://127.0.0.1:{}/file", open)]), Some(Hint::UnknownHost { .. } | Hint::DnsFailing)));
+    }
+}
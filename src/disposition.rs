@@ -0,0 +1,242 @@
+use std::borrow::Cow;
+use std::path::PathBuf;
+
+use log::debug;
+use percent_encoding::percent_decode_str;
+use reqwest::header::{self, HeaderMap};
+
+use crate::naming;
+
+/// What a `Content-Disposition` header asks us to do with the response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DispositionType {
+    Inline,
+    Attachment,
+    /// Unknown types are to be treated like `attachment` (RFC 6266 section 4.2)
+    Other(String),
+}
+
+/// A parsed `Content-Disposition` header (RFC 6266)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentDisposition {
+    pub disposition: DispositionType,
+    /// The suggested filename, from `filename*` if it could be decoded and
+    /// `filename` otherwise, exactly as the server sent it
+    pub filename: Option<String>,
+}
+
+impl ContentDisposition {
+    /// Parse a header value, or `None` if it's too broken to make sense of.
+    /// Individual malformed parameters are ignored rather than spoiling the
+    /// whole header, and a header with no type (just `filename=...`) is
+    /// accepted as an attachment, since browsers accept it too.
+    pub fn parse(header: &str) -> Option<Self> {
+        let (first, rest) = header.split_once(';').unwrap_or((header, ""));
+        let first = first.trim();
+        let (disposition, parameters) = if first.contains('=') {
+            (DispositionType::Attachment, header)
+        } else {
+            let disposition = match first.to_ascii_lowercase().as_str() {
+                "" => return None,
+                "inline" => DispositionType::Inline,
+                "attachment" => DispositionType::Attachment,
+                other if other.chars().all(is_token_char) => DispositionType::Other(other.to_string()),
+                _ => return None,
+            };
+            (disposition, rest)
+        };
+        // Only the first of a repeated parameter counts
+        let mut filename = None;
+        let mut extended_filename = None;
+        for (name, value) in (Parameters { rest: parameters }) {
+            if name.eq_ignore_ascii_case("filename*") {
+                if extended_filename.is_none() {
+                    extended_filename = Some(decode_ext_value(&value));
+                }
+            } else if name.eq_ignore_ascii_case("filename") && filename.is_none() {
+                filename = Some(value.into_owned());
+            }
+        }
+        let filename = extended_filename.flatten().or(filename).filter(|name| !name.is_empty());
+        Some(ContentDisposition { disposition, filename })
+    }
+}
+
+/// The filename suggested by a response's `Content-Disposition` header, if
+/// any, made safe to save (see `naming::sanitize`)
+pub fn filename(headers: &HeaderMap, trust_server_names: bool) -> Option<PathBuf> {
+    naming::sanitize(&suggested_filename(headers)?, trust_server_names)
+}
+
+/// The filename a response's `Content-Disposition` header suggests, exactly
+/// as the server sent it
+pub fn suggested_filename(headers: &HeaderMap) -> Option<String> {
+    // Plenty of servers send raw UTF-8 rather than bothering with filename*
+    let value = headers.get(header::CONTENT_DISPOSITION)?;
+    let parsed = ContentDisposition::parse(&String::from_utf8_lossy(value.as_bytes()))?;
+    debug!("{:?} disposition suggests filename {:?}", parsed.disposition, parsed.filename);
+    parsed.filename
+}
+
+/// The parameters after the disposition type, as `name=value` pairs
+struct Parameters<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Iterator for Parameters<'a> {
+    type Item = (&'a str, Cow<'a, str>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.rest = self.rest.trim_start_matches(|c: char| c == ';' || c.is_ascii_whitespace());
+            if self.rest.is_empty() {
+                return None;
+            }
+            let end = self.rest.find([';', '=']).unwrap_or(self.rest.len());
+            let name = self.rest[..end].trim();
+            self.rest = &self.rest[end..];
+            // A parameter without a value is skipped
+            let Some(after) = self.rest.strip_prefix('=') else { continue };
+            let after = after.trim_start();
+            let value = if let Some(quoted) = after.strip_prefix('"') {
+                match parse_quoted_string(quoted) {
+                    Some((value, rest)) => {
+                        self.rest = rest;
+                        value
+                    }
+                    // An unterminated quoted string swallows the rest of the header
+                    None => {
+                        self.rest = "";
+                        continue;
+                    }
+                }
+            } else {
+                let end = after.find(';').unwrap_or(after.len());
+                self.rest = &after[end..];
+                Cow::Borrowed(after[..end].trim_end())
+            };
+            if name.is_empty() || !name.chars().all(is_token_char) {
+                continue;
+            }
+            return Some((name, value));
+        }
+    }
+}
+
+/// Parse the inside of a quoted string (after the opening quote), returning
+/// its value and whatever follows the closing quote
+fn parse_quoted_string(input: &str) -> Option<(Cow<'_, str>, &str)> {
+    let mut value = String::new();
+    let mut chars = input.char_indices();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '"' => {
+                let value = if value.len() == index { Cow::Borrowed(&input[..index]) } else { Cow::Owned(value) };
+                return Some((value, &input[index + 1..]));
+            }
+            '\\' => value.push(chars.next()?.1),
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+/// Decode an RFC 5987 `charset'language'percent-encoded` value, if it's
+/// well formed and in a charset we understand
+fn decode_ext_value(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let (charset, _language, encoded) = (parts.next()?, parts.next()?, parts.next()?);
+    let bytes: Vec<u8> = percent_decode_str(encoded).collect();
+    match charset.to_ascii_lowercase().as_str() {
+        "utf-8" => String::from_utf8(bytes).ok(),
+        "iso-8859-1" => Some(bytes.into_iter().map(char::from).collect()),
+        _ => None,
+    }
+}
+
+/// Characters allowed in an RFC 7230 token
+fn is_token_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn suggested(header: &str) -> Option<String> {
+        ContentDisposition::parse(header).and_then(|disposition| disposition.filename)
+    }
+
+    #[test]
+    fn test_disposition_types() {
+        let parsed = ContentDisposition::parse("attachment").unwrap();
+        assert_eq!(parsed, ContentDisposition { disposition: DispositionType::Attachment, filename: None });
+        assert_eq!(ContentDisposition::parse("INLINE; filename=a.txt").unwrap().disposition, DispositionType::Inline);
+        assert_eq!(
+            ContentDisposition::parse("x-custom; filename=a.txt").unwrap().disposition,
+            DispositionType::Other("x-custom".to_string())
+        );
+        // Some servers leave the type out entirely
+        assert_eq!(
+            ContentDisposition::parse("filename=\"a.txt\""),
+            Some(ContentDisposition { disposition: DispositionType::Attachment, filename: Some("a.txt".to_string()) })
+        );
+    }
+
+    #[test]
+    fn test_plain_and_quoted_filenames() {
+        assert_eq!(suggested("attachment; filename=report.pdf").as_deref(), Some("report.pdf"));
+        assert_eq!(suggested("inline; filename=\"monthly report.pdf\"").as_deref(), Some("monthly report.pdf"));
+        assert_eq!(suggested("attachment;FileName = \"a;b.txt\" ; size=10").as_deref(), Some("a;b.txt"));
+        assert_eq!(suggested(r#"attachment; filename="say \"hi\".txt""#).as_deref(), Some("say \"hi\".txt"));
+        assert_eq!(suggested("attachment; filename=first.txt; filename=second.txt").as_deref(), Some("first.txt"));
+    }
+
+    #[test]
+    fn test_extended_filenames() {
+        assert_eq!(suggested("attachment; filename*=UTF-8''na%C3%AFve%20file.txt").as_deref(), Some("naïve file.txt"));
+        assert_eq!(suggested("attachment; filename*=iso-8859-1'en'%A3%20rates.txt").as_deref(), Some("£ rates.txt"));
+        // filename* wins whichever order they come in
+        assert_eq!(suggested("attachment; filename*=UTF-8''%E2%82%AC.txt; filename=EUR.txt").as_deref(), Some("€.txt"));
+        assert_eq!(suggested("attachment; filename=EUR.txt; filename*=UTF-8''%E2%82%AC.txt").as_deref(), Some("€.txt"));
+        // ...unless it can't be decoded, when we fall back to filename
+        assert_eq!(suggested("attachment; filename*=x-unknown''abc.txt; filename=fallback.txt").as_deref(), Some("fallback.txt"));
+        assert_eq!(suggested("attachment; filename*=UTF-8''%FF.txt; filename=fallback.txt").as_deref(), Some("fallback.txt"));
+        assert_eq!(suggested("attachment; filename*=no-quotes.txt; filename=fallback.txt").as_deref(), Some("fallback.txt"));
+    }
+
+    #[test]
+    fn test_malformed_headers() {
+        assert_eq!(ContentDisposition::parse(""), None);
+        assert_eq!(ContentDisposition::parse("   "), None);
+        assert_eq!(ContentDisposition::parse("attach ment; filename=a.txt"), None);
+        assert_eq!(ContentDisposition::parse("; filename=a.txt"), None);
+        // Broken parameters are dropped, not the whole header
+        assert_eq!(suggested("attachment; filename"), None);
+        assert_eq!(suggested("attachment; =a.txt; filename=b.txt").as_deref(), Some("b.txt"));
+        assert_eq!(suggested("attachment; filename=\"unterminated.txt"), None);
+        assert_eq!(suggested("attachment; filename=\"\""), None);
+    }
+
+    #[test]
+    fn test_filename_from_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(filename(&headers, false), None);
+        headers.insert(header::CONTENT_DISPOSITION, header::HeaderValue::from_bytes("attachment; filename=\"résumé.pdf\"".as_bytes()).unwrap());
+        assert_eq!(filename(&headers, false), Some(PathBuf::from("résumé.pdf")));
+    }
+
+    #[test]
+    fn test_filenames_are_kept_in_the_output_directory() {
+        let saved = |value: &str| {
+            let headers = HeaderMap::from_iter([(header::CONTENT_DISPOSITION, header::HeaderValue::from_str(value).unwrap())]);
+            filename(&headers, false)
+        };
+        assert_eq!(suggested("attachment; filename=\"../../etc/passwd\"").as_deref(), Some("../../etc/passwd"));
+        assert_eq!(saved("attachment; filename=\"../../etc/passwd\""), Some(PathBuf::from("passwd")));
+        assert_eq!(saved("attachment; filename=\"C:\\\\Windows\\\\evil.dll\""), Some(PathBuf::from("evil.dll")));
+        assert_eq!(saved("attachment; filename*=UTF-8''..%2F..%2Fdata.bin"), Some(PathBuf::from("data.bin")));
+        assert_eq!(saved("attachment; filename=\"dir/\""), Some(PathBuf::from("dir")));
+        assert_eq!(saved("attachment; filename=.."), None);
+    }
+}
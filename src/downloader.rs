@@ -0,0 +1,2309 @@
+use std::fs::{self, File};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{mpsc, Arc, Mutex};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use log::{debug, info, warn, error};
+use tracing::trace_span;
+
+use reqwest::{Method, StatusCode};
+use reqwest::blocking::{Request, Response};
+use reqwest::header::{self};
+
+use indicatif::{HumanDuration, MultiProgress, ProgressBar, ProgressStyle};
+
+use url;
+use url::Url;
+
+#[cfg(feature = "browser-cookies")]
+use crate::cookies;
+use crate::{cancel, checksum, checksum_db, copy, decompress, dedupe, device, encrypt, glob, hls, logging, netload, order, partial, pinning, recursive, swarm, throttle, timeouts, timestamping, timing};
+use crate::append::Appender;
+use crate::archive::Extraction;
+use crate::attestation::AttestationVerifier;
+use crate::auth_hook::AuthHook;
+use crate::batch::SmallFileBatch;
+#[cfg(feature = "browser-cookies")]
+use crate::browser::BrowserType;
+use crate::cancel::Cancellation;
+use crate::clock::TimeZone;
+use crate::control_socket::BatchControl;
+use crate::checksum::{Checksum, ChecksumPlan, ChecksumSpec, HashAlgorithm, HashingWriter};
+use crate::checksum_db::ChecksumDatabase;
+use crate::credentials::CredentialStore;
+use crate::decompress::{Compression, ContentDecoding, DecompressError};
+use crate::dedupe::Outputs;
+use crate::diagnose::Diagnosis;
+use crate::dry_run::DryRun;
+use crate::encrypt::Recipient;
+use crate::middleware::{DownloadContext, Middleware, RequestMiddleware, ResponseInterceptor};
+#[cfg(feature = "browser-cookies")]
+use crate::middleware::BrowserCookies;
+use crate::naming::{NamePolicy, StandardNames};
+use crate::negotiate::Negotiator;
+use crate::netload::NetworkShare;
+use crate::exec_hook::{Download, ExecHook};
+use crate::exit_code::{ExitCode, Failure};
+use crate::extractor::{Fetcher, Registry};
+use crate::feed::{FeedHistory, FeedReader};
+use crate::hls::{HlsDownloader, HlsError};
+use crate::notify::Notifier;
+use crate::ntlm::NtlmAuthenticator;
+use crate::oauth::OAuthSettings;
+use crate::order::DownloadOrder;
+use crate::page::{PageFormat, PageSaver};
+use crate::partial::{PartFile, PartWriter};
+use crate::pinning::PinSet;
+use crate::politeness::{HostLimits, HostPermit, Politeness};
+use crate::progress::{self, BarStyle, Event, ProgressEvent, ProgressMode, Subscription};
+use crate::redirects::Redirects;
+use crate::report::Report;
+use crate::resolver::{self, Resolver};
+use crate::retry_after::RetryAfter;
+use crate::range::ByteRange;
+use crate::recursive::{Crawler, Recursion};
+use crate::spider::{HeaderFormat, Spider};
+#[cfg(feature = "state-db")]
+use crate::state_db::StateDb;
+use crate::tee::TeeReader;
+use crate::throttle::{ByteRate, MinimumSpeed, RateLimiter, throttled};
+use crate::timing::{Phases, Stopwatch};
+use crate::transport::{Transport, TransportError, Transports};
+use crate::warc::{Exchange, RequestHead, WarcWriter};
+
+/// Settings for the HTTP client shared by every request in a run
+#[derive(Default)]
+pub struct ClientOptions<'a> {
+    #[cfg(feature = "browser-cookies")]
+    pub cookie_store: Option<&'a Arc<cookies::CookieJarWrapper>>,
+    pub tls_config: Option<&'a rustls::ClientConfig>,
+    /// Keep enough idle connections around for this many parallel requests
+    pub jobs: Option<usize>,
+    /// Zero for no limit, None for the default
+    pub connect_timeout: Option<Duration>,
+    pub read_timeout: Option<Duration>,
+    pub header_timeout: Option<Duration>,
+    /// Stick to HTTP/1.1, which connection-based authentication like NTLM needs
+    pub http1_only: bool,
+    /// Speak HTTP/2 without negotiating it first
+    pub http2_prior_knowledge: bool,
+    /// Whether to ask for and decompress compressed responses
+    pub content_decoding: ContentDecoding,
+    pub proxy: Option<&'a str>,
+    /// Make every connection to this Unix socket
+    pub unix_socket: Option<&'a Path>,
+    /// Looks hosts up, minding `--resolve`, `--connect-to` and `-4`/`-6`
+    pub resolver: Resolver,
+    /// Where each new connection's lookup, handshake and the rest are timed
+    pub phases: Phases,
+    /// Where the redirects each request follows are recorded
+    pub redirects: Redirects,
+}
+
+/// Build the HTTP client used for downloads
+pub fn build_client(options: &ClientOptions) -> reqwest::Result<reqwest::blocking::Client> {
+    let mut builder = reqwest::blocking::Client::builder()
+        .connection_verbose(true)
+        .dns_resolver(Arc::new(options.resolver.clone()))
+        .redirect(options.redirects.policy())
+        .connector_layer(timing::TimedConnect(options.phases.clone()))
+        .connector_layer(resolver::ConnectPort);
+    if let Some(jobs) = options.jobs {
+        builder = builder.pool_max_idle_per_host(jobs);
+    }
+    if options.http1_only {
+        builder = builder.http1_only();
+    }
+    if options.http2_prior_knowledge {
+        builder = builder.http2_prior_knowledge();
+    }
+    match options.content_decoding {
+        ContentDecoding::Auto => {}
+        #[cfg(feature = "compression")]
+        ContentDecoding::Compressed => builder = builder.gzip(true).brotli(true).zstd(true).deflate(true),
+        // Refused before it gets this far
+        #[cfg(not(feature = "compression"))]
+        ContentDecoding::Compressed => {}
+        ContentDecoding::Raw => builder = builder.no_gzip().no_brotli().no_zstd().no_deflate(),
+    }
+    #[cfg(unix)]
+    if let Some(socket) = options.unix_socket {
+        // Nothing goes anywhere but the socket, whatever proxy is set or in the environment
+        builder = builder.unix_socket(socket).no_proxy();
+    }
+    if let Some(proxy) = options.proxy.filter(|_| options.unix_socket.is_none()) {
+        builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+    }
+    #[cfg(feature = "browser-cookies")]
+    if let Some(store) = options.cookie_store {
+        builder = builder.cookie_provider(Arc::clone(store));
+    }
+    // Set up as reqwest would, but with handshakes timed
+    let tls_config = match options.tls_config {
+        Some(tls_config) => Some(tls_config.clone()),
+        None => pinning::platform_tls_config().inspect_err(|e| debug!("Not timing TLS handshakes: {}", e)).ok(),
+    };
+    if let Some(mut tls_config) = tls_config {
+        if options.http1_only {
+            tls_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        }
+        builder = builder.tls_backend_preconfigured(timing::timed_tls(tls_config));
+    }
+    let connect_timeout = options.connect_timeout.unwrap_or(timeouts::DEFAULT_CONNECT_TIMEOUT);
+    builder = builder.connect_timeout(Some(connect_timeout).filter(|timeout| !timeout.is_zero()));
+    // The blocking client's timeout is how long each read of the body can
+    // take, and also how long it waits for the headers, which --header-timeout
+    // can only make shorter (see `timeouts::execute`)
+    let read_timeout = Some(options.read_timeout.unwrap_or(timeouts::DEFAULT_IO_TIMEOUT)).filter(|timeout| !timeout.is_zero());
+    builder = builder.timeout(read_timeout);
+    builder.build()
+}
+
+/// What to say when a download stops partway through
+fn copy_error(e: std::io::Error) -> Failure {
+    if cancel::reason(&e).is_some() {
+        return Failure::from(e.to_string());
+    }
+    // Errors reading the body are the connection's; anything else is writing it
+    let read = e.get_ref().and_then(|inner| inner.downcast_ref::<reqwest::Error>());
+    let code = match read {
+        Some(_) => ExitCode::Network,
+        None => ExitCode::Error,
+    };
+    if let Some(too_slow) = throttle::too_slow(&e) {
+        return Failure::new(ExitCode::Network, format!("Transfer was {}; run `download resume` to finish it", too_slow));
+    }
+    if read.is_some_and(reqwest::Error::is_timeout) {
+        return Failure::new(code, "Server stopped sending for longer than --read-timeout; run `download resume` to finish it".to_string());
+    }
+    Failure::new(code, format!("Failed to copy content: {}; run `download resume` to finish it", e))
+}
+
+/// Copy a response body to stdout as it's read, for --tee
+fn teed(reader: Box<dyn Read + Send>, tee: bool) -> Box<dyn Read + Send> {
+    match tee {
+        true => Box::new(TeeReader::new(reader, std::io::stdout())),
+        false => reader,
+    }
+}
+
+/// Everything besides the URLs that affects how we download them
+#[derive(Clone)]
+pub struct DownloadOptions {
+    #[cfg(feature = "browser-cookies")]
+    pub cookie_store: Option<Arc<cookies::CookieJarWrapper>>,
+    pub checksum: Vec<ChecksumSpec>,
+    pub checksum_db: Option<Arc<ChecksumDatabase>>,
+    pub pinned_keys: Option<PinSet>,
+    pub attestation: Option<AttestationVerifier>,
+    pub credentials: Option<Arc<CredentialStore>>,
+    pub oauth: OAuthSettings,
+    pub auth_hook: Option<AuthHook>,
+    /// A command to run before each file is downloaded
+    pub exec_before: Option<ExecHook>,
+    /// Number of workers for small-file mode, if it's enabled
+    pub small_files: Option<usize>,
+    /// Number of workers for checking URLs without downloading them, if that's all we're doing
+    pub spider: Option<usize>,
+    /// Print the headers of the URLs we're checking
+    pub headers: Option<HeaderFormat>,
+    /// Say what downloading the URLs would do instead of doing it
+    pub dry_run: bool,
+    /// Follow links from the URLs to find more to download
+    pub recursion: Option<Recursion>,
+    /// Point links in downloaded pages at the local copies
+    pub convert_links: bool,
+    /// Finds the files behind sites' download pages
+    pub extractors: Registry,
+    /// Which transport fetches URLs of each scheme
+    pub transports: Transports,
+    /// What every request goes through before it's sent, and every response
+    /// once its headers arrive; cookies from `cookie_store` are added after it
+    pub middleware: Middleware,
+    /// Feeds whose new items are to be downloaded too
+    pub feeds: Vec<String>,
+    /// How recently feed items must have been published
+    pub newer_than: Option<Duration>,
+    pub order: DownloadOrder,
+    pub timestamping: bool,
+    pub preserve_time: bool,
+    /// The time every file is dated with, so two runs give the same output (`--deterministic`)
+    pub deterministic: Option<SystemTime>,
+    pub no_clobber: bool,
+    /// Don't download URLs the state database says are already downloaded
+    #[cfg(feature = "state-db")]
+    pub skip_downloaded: bool,
+    /// Where each download is remembered between runs
+    #[cfg(feature = "state-db")]
+    pub state: Option<Arc<StateDb>>,
+    /// How output files are named
+    pub naming: Arc<dyn NamePolicy>,
+    pub save_page: Option<PageFormat>,
+    pub warc: Option<Arc<WarcWriter>>,
+    pub range: Option<ByteRange>,
+    pub append: bool,
+    pub output: Option<PathBuf>,
+    /// `output` is a block device, and we've been allowed to write to it
+    pub device: bool,
+    pub encrypt_to: Option<Recipient>,
+    /// Write the download to stdout instead of a file
+    pub stdout: bool,
+    /// Write the download to stdout as well as the file
+    pub tee: bool,
+    /// Other servers to fetch parts of the (single) URL from
+    pub mirrors: Vec<String>,
+    /// Decompress downloads that are single compressed files
+    pub decompress: bool,
+    /// What the decompressed (single) file has to match
+    pub decompressed_checksum: Option<Checksum>,
+    /// Unpack downloads that are archives
+    pub extract: Option<Extraction>,
+    /// The container to copy HLS streams into
+    pub remux: Option<String>,
+    pub output_dir: Option<PathBuf>,
+    pub proxy: Option<String>,
+    /// Make every connection to this Unix socket; URLs can then be just paths (`/v1.43/info`)
+    pub unix_socket: Option<PathBuf>,
+    /// `--resolve` and `--connect-to`
+    pub resolver: Resolver,
+    pub limit_rate: Option<ByteRate>,
+    pub auto_throttle: Option<NetworkShare>,
+    /// `--speed-limit` and `--speed-time`
+    pub min_speed: Option<MinimumSpeed>,
+    /// How many downloads each host gets at once, and how far apart
+    pub host_limits: HostLimits,
+    /// Most downloads to have going at once, whatever their hosts
+    pub max_concurrent: Option<usize>,
+    pub connect_timeout: Option<Duration>,
+    pub read_timeout: Option<Duration>,
+    pub header_timeout: Option<Duration>,
+    /// Wait and retry when rate limited or timed out
+    pub retry_after: RetryAfter,
+    /// Speak HTTP/2 without negotiating it first
+    pub http2_prior_knowledge: bool,
+    /// `--compressed` and `--no-decompress`
+    pub content_decoding: ContentDecoding,
+    pub negotiator: Option<Arc<Negotiator>>,
+    pub ntlm: Option<Arc<NtlmAuthenticator>>,
+    pub progress: ProgressMode,
+    pub bar_style: BarStyle,
+    /// Stops every download when triggered, and says what to do with what they leave behind
+    pub cancellation: Cancellation,
+    /// Lets each URL be paused and cancelled on its own, through the control socket
+    pub control: Option<BatchControl>,
+    /// What happened to each URL, for `--report-json`
+    pub report: Arc<Report>,
+    /// Exit with the failures' own status even if other URLs were downloaded
+    pub strict: bool,
+}
+
+/// What `download` does with no options: save each URL in the current
+/// directory, named after it
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        DownloadOptions {
+            #[cfg(feature = "browser-cookies")]
+            cookie_store: None,
+            checksum: Vec::new(),
+            checksum_db: None,
+            pinned_keys: None,
+            attestation: None,
+            credentials: None,
+            oauth: OAuthSettings::default(),
+            auth_hook: None,
+            exec_before: None,
+            small_files: None,
+            spider: None,
+            headers: None,
+            dry_run: false,
+            recursion: None,
+            convert_links: false,
+            extractors: Registry::builtin(),
+            transports: Transports::builtin(),
+            middleware: Middleware::standard(),
+            feeds: Vec::new(),
+            newer_than: None,
+            order: DownloadOrder::default(),
+            timestamping: false,
+            preserve_time: true,
+            deterministic: None,
+            no_clobber: false,
+            #[cfg(feature = "state-db")]
+            skip_downloaded: false,
+            #[cfg(feature = "state-db")]
+            state: None,
+            naming: Arc::new(StandardNames::default()),
+            save_page: None,
+            warc: None,
+            range: None,
+            append: false,
+            output: None,
+            device: false,
+            encrypt_to: None,
+            stdout: false,
+            tee: false,
+            mirrors: Vec::new(),
+            decompress: false,
+            decompressed_checksum: None,
+            extract: None,
+            remux: None,
+            output_dir: None,
+            proxy: None,
+            unix_socket: None,
+            resolver: Resolver::default(),
+            limit_rate: None,
+            auto_throttle: None,
+            min_speed: None,
+            host_limits: HostLimits::default(),
+            max_concurrent: None,
+            connect_timeout: None,
+            read_timeout: None,
+            header_timeout: None,
+            retry_after: RetryAfter::default(),
+            http2_prior_knowledge: false,
+            content_decoding: ContentDecoding::Auto,
+            negotiator: None,
+            ntlm: None,
+            // A program using the library draws nothing unless it asks to
+            progress: ProgressMode::Quiet,
+            bar_style: BarStyle::default(),
+            cancellation: Cancellation::default(),
+            control: None,
+            report: Arc::new(Report::new(None, None, None, TimeZone::default())),
+            strict: false,
+        }
+    }
+}
+
+/// Downloads URLs the same way each time, with the options it was built with
+///
+/// ```no_run
+/// use rustdl::Downloader;
+///
+/// let downloader = Downloader::builder().max_concurrent(4).limit_rate("2M".parse()?).output_dir("isos").build();
+/// downloader.fetch("https://example.com/a.iso")?;
+/// downloader.fetch_all(["https://example.com/b.iso", "https://example.com/c.iso"])?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Clone)]
+pub struct Downloader {
+    options: DownloadOptions,
+    notifier: Arc<Notifier>,
+    /// Keeps the progress listener registered for as long as there's a clone of this
+    _subscription: Option<Arc<Subscription>>,
+}
+
+impl Downloader {
+    pub fn builder() -> DownloaderBuilder {
+        DownloaderBuilder::default()
+    }
+
+    /// Download one URL, returning the status `download` would exit with
+    pub fn fetch(&self, url: impl Into<String>) -> Result<ExitCode, Box<dyn std::error::Error>> {
+        self.fetch_all([url])
+    }
+
+    /// Download `urls` as one batch, returning the status `download` would exit with
+    pub fn fetch_all<I>(&self, urls: I) -> Result<ExitCode, Box<dyn std::error::Error>>
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        run(urls.into_iter().map(Into::into).collect(), Arc::clone(&self.notifier), self.options.clone())
+    }
+
+    /// What's happened to every URL fetched so far
+    pub fn report(&self) -> &Report {
+        &self.options.report
+    }
+
+    /// Stops whatever's downloading when triggered, from any thread
+    pub fn cancellation(&self) -> &Cancellation {
+        &self.options.cancellation
+    }
+}
+
+/// Sets up a [`Downloader`], starting from what `download` does with no options
+#[derive(Default)]
+pub struct DownloaderBuilder {
+    options: DownloadOptions,
+    #[cfg(feature = "browser-cookies")]
+    browser: Option<BrowserType>,
+    notifier: Option<Notifier>,
+    subscription: Option<Arc<Subscription>>,
+    /// Files are named by a policy of the caller's, rather than after their URLs in `output_dir`
+    named: bool,
+}
+
+impl DownloaderBuilder {
+    /// Send the cookies `browser` has for each site along with its requests
+    #[cfg(feature = "browser-cookies")]
+    pub fn browser(mut self, browser: BrowserType) -> Self {
+        self.browser = Some(browser);
+        self
+    }
+
+    /// Have no more than `jobs` downloads going at once
+    pub fn max_concurrent(mut self, jobs: usize) -> Self {
+        self.options.max_concurrent = Some(jobs);
+        self
+    }
+
+    /// Have no more than `jobs` downloads from any one host going at once
+    pub fn max_per_host(mut self, jobs: usize) -> Self {
+        self.options.host_limits.connections = Some(jobs);
+        self
+    }
+
+    /// Share `rate` between every download
+    pub fn limit_rate(mut self, rate: ByteRate) -> Self {
+        self.options.limit_rate = Some(rate);
+        self
+    }
+
+    /// Save files in `dir` instead of the current directory
+    pub fn output_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.options.output_dir = Some(dir.into());
+        self
+    }
+
+    /// Name files with `naming`, which then decides the directory too
+    pub fn naming(mut self, naming: impl NamePolicy + 'static) -> Self {
+        self.options.naming = Arc::new(naming);
+        self.named = true;
+        self
+    }
+
+    /// Check every download against `checksum`
+    pub fn checksum(mut self, checksum: ChecksumSpec) -> Self {
+        self.options.checksum.push(checksum);
+        self
+    }
+
+    /// Skip files that already exist
+    pub fn no_clobber(mut self) -> Self {
+        self.options.no_clobber = true;
+        self
+    }
+
+    pub fn proxy(mut self, proxy: impl Into<String>) -> Self {
+        self.options.proxy = Some(proxy.into());
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.options.connect_timeout = Some(timeout);
+        self
+    }
+
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.options.read_timeout = Some(timeout);
+        self
+    }
+
+    /// How to show progress; nothing but errors by default
+    pub fn progress(mut self, progress: ProgressMode) -> Self {
+        self.options.progress = progress;
+        self
+    }
+
+    /// Call `callback` with each download's progress instead of drawing it
+    pub fn on_progress(mut self, callback: impl Fn(&ProgressEvent) + Send + Sync + 'static) -> Self {
+        let subscription = progress::listen(callback);
+        self.options.progress = ProgressMode::Events(subscription.id());
+        self.subscription = Some(Arc::new(subscription));
+        self
+    }
+
+    /// Send each download's progress to `sender` instead of drawing it
+    pub fn progress_channel(self, sender: mpsc::Sender<ProgressEvent>) -> Self {
+        self.on_progress(move |event| {
+            // Nobody's listening any more, which is theirs to decide
+            let _ = sender.send(event.clone());
+        })
+    }
+
+    /// Fetch `scheme` URLs with `transport`, as in `artifactory://repo/libs/a.jar`
+    pub fn transport(mut self, scheme: &str, transport: impl Transport + 'static) -> Self {
+        self.options.transports = self.options.transports.with(scheme, transport);
+        self
+    }
+
+    /// Send requests through `middleware` and show it their responses,
+    /// instead of the standard chain
+    pub fn middleware(mut self, middleware: Middleware) -> Self {
+        self.options.middleware = middleware;
+        self
+    }
+
+    /// Shape every request with `middleware` too, after what's already there
+    pub fn with_request(mut self, middleware: impl RequestMiddleware + 'static) -> Self {
+        self.options.middleware = self.options.middleware.with_request(middleware);
+        self
+    }
+
+    /// Show every response to `interceptor` too, which can turn it down
+    pub fn with_response(mut self, interceptor: impl ResponseInterceptor + 'static) -> Self {
+        self.options.middleware = self.options.middleware.with_response(interceptor);
+        self
+    }
+
+    /// Unpack downloads that are archives, as `extraction` says
+    pub fn extract(mut self, extraction: Extraction) -> Self {
+        self.options.extract = Some(extraction);
+        self
+    }
+
+    /// Record what happens to each URL in `report`
+    pub fn report(mut self, report: Report) -> Self {
+        self.options.report = Arc::new(report);
+        self
+    }
+
+    /// Stop downloading when `cancellation` is triggered
+    pub fn cancellation(mut self, cancellation: Cancellation) -> Self {
+        self.options.cancellation = cancellation;
+        self
+    }
+
+    /// Let `control` pause and cancel each URL on its own
+    pub fn control(mut self, control: BatchControl) -> Self {
+        self.options.control = Some(control);
+        self
+    }
+
+    /// Tell `notifier` how each batch went
+    pub fn notifier(mut self, notifier: Notifier) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    pub fn build(self) -> Downloader {
+        let mut options = self.options;
+        #[cfg(feature = "browser-cookies")]
+        if let Some(browser) = self.browser {
+            options.cookie_store = cookies::from_browser(Some(browser), false);
+        }
+        if !self.named
+            && let Some(dir) = &options.output_dir
+        {
+            options.naming = Arc::new(StandardNames::new(false, dir.clone(), None, false));
+        }
+        let notifier = self.notifier.unwrap_or_else(|| Notifier::with_sinks(Vec::new(), false, false));
+        Downloader { options, notifier: Arc::new(notifier), _subscription: self.subscription }
+    }
+}
+
+/// Every option as it is, for callers like `download` that set them all
+impl From<DownloadOptions> for DownloaderBuilder {
+    fn from(options: DownloadOptions) -> Self {
+        DownloaderBuilder { options, named: true, ..Default::default() }
+    }
+}
+
+/// The blocking front-end, which downloads on the calling thread: for
+/// programs like `download` that have no async runtime, and don't want one
+pub mod blocking {
+    pub use super::{Downloader as Client, DownloaderBuilder as ClientBuilder};
+}
+
+/// Downloads from async code. Each fetch runs on a thread of its own and
+/// completes a future, so no executor's threads are held up and any executor
+/// will do; build one from a [`Downloader`]
+///
+/// Dropping a fetch's future doesn't stop it; [`Client::cancellation`] does.
+#[cfg(feature = "async")]
+#[derive(Clone)]
+pub struct Client {
+    downloader: Downloader,
+}
+
+#[cfg(feature = "async")]
+impl Client {
+    pub fn new(downloader: Downloader) -> Self {
+        Client { downloader }
+    }
+
+    /// Download one URL, returning the status `download` would exit with
+    pub async fn fetch(&self, url: impl Into<String>) -> Result<ExitCode, Box<dyn std::error::Error + Send + Sync>> {
+        self.fetch_all([url]).await
+    }
+
+    /// Download `urls` as one batch, returning the status `download` would exit with
+    pub async fn fetch_all<I>(&self, urls: I) -> Result<ExitCode, Box<dyn std::error::Error + Send + Sync>>
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let urls: Vec<String> = urls.into_iter().map(Into::into).collect();
+        let (sender, receiver) = futures::channel::oneshot::channel();
+        let downloader = self.downloader.clone();
+        thread::spawn(move || {
+            let _ = sender.send(downloader.fetch_all(urls).map_err(|e| e.to_string()));
+        });
+        Ok(receiver.await??)
+    }
+
+    /// What's happened to every URL fetched so far
+    pub fn report(&self) -> &Report {
+        self.downloader.report()
+    }
+
+    /// Stops whatever's downloading when triggered
+    pub fn cancellation(&self) -> &Cancellation {
+        self.downloader.cancellation()
+    }
+}
+
+#[cfg(feature = "async")]
+impl From<Downloader> for Client {
+    fn from(downloader: Downloader) -> Self {
+        Client::new(downloader)
+    }
+}
+
+/// Download `urls` (and whatever else `options` adds to them, like feed items
+/// and recursion), returning the status the command would exit with
+fn run(urls: Vec<String>, notifier: Arc<Notifier>, options: DownloadOptions) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    debug!("Starting a download of {} URLs", urls.len());
+    // Mirrors are other places to get the one file from, so they can't be shared out between several
+    if !options.mirrors.is_empty() && urls.len() > 1 {
+        return Err(format!("mirrors can only be used with a single URL, not {}", urls.len()).into());
+    }
+    let urls = match &options.unix_socket {
+        // URLs can be just paths; any host will do, as it's never looked up
+        Some(_) => urls.into_iter().map(|url| if url.starts_with('/') { format!("http://localhost{}", url) } else { url }).collect(),
+        None => urls,
+    };
+    // Its own report, so fetches going at once on clones don't count each other's URLs
+    let report = Arc::new(Report::batch(&options.report));
+    let progress = options.progress;
+    let outcomes = Outcomes {
+        report: Arc::clone(&report),
+        notifier: Arc::clone(&notifier),
+        progress,
+        finished: options.bar_style.finished(),
+        failed: ProgressStyle::with_template("{prefix:.red} [error] {msg:} ").unwrap(),
+        skipped: ProgressStyle::with_template("{prefix:.blue} [{msg:.cyan}] ").unwrap(),
+    };
+    let multiprog = progress.multi_progress();
+
+    // Pinning public keys means doing certificate verification ourselves
+    let tls_config = match &options.pinned_keys {
+        Some(pins) => {
+            debug!("Pinning server public keys to {}", pins);
+            Some(pinning::pinned_tls_config(pins)?)
+        }
+        None => None,
+    };
+
+    // One client for the whole batch, so connections are reused between URLs
+    let phases = Phases::default();
+    let client = build_client(&ClientOptions {
+        #[cfg(feature = "browser-cookies")]
+        cookie_store: options.cookie_store.as_ref(),
+        tls_config: tls_config.as_ref(),
+        jobs: options.small_files.or(options.spider),
+        connect_timeout: options.connect_timeout,
+        read_timeout: options.read_timeout,
+        header_timeout: options.header_timeout,
+        http1_only: options.ntlm.is_some(),
+        http2_prior_knowledge: options.http2_prior_knowledge,
+        content_decoding: options.content_decoding,
+        proxy: options.proxy.as_deref(),
+        unix_socket: options.unix_socket.as_deref(),
+        resolver: options.resolver.clone(),
+        phases: phases.clone(),
+        redirects: report.redirects().clone(),
+    })?;
+    let middleware = options.middleware.clone();
+    #[cfg(feature = "browser-cookies")]
+    let middleware = match &options.cookie_store {
+        Some(store) => middleware.with_request(BrowserCookies(Arc::clone(store))),
+        None => middleware,
+    };
+    // Over a Unix socket there's no network to check
+    let diagnosis = options.unix_socket.is_none().then(|| Diagnosis::new(options.proxy.clone(), options.resolver.clone()));
+    let limiter = match (options.limit_rate, options.auto_throttle) {
+        (Some(rate), _) => {
+            debug!("Limiting downloads to {}", rate);
+            Some(Arc::new(RateLimiter::new(rate)))
+        }
+        (None, Some(_)) => Some(Arc::new(RateLimiter::unlimited())),
+        (None, None) => None,
+    };
+    if let (Some(limiter), Some(share)) = (&limiter, options.auto_throttle) {
+        netload::spawn(limiter, share, options.limit_rate);
+    }
+    let politeness = Politeness::new(options.host_limits);
+    let politeness = match options.max_concurrent {
+        Some(total) => politeness.at_most(total),
+        None => politeness,
+    };
+    let politeness = Arc::new(if options.deterministic.is_some() { politeness.in_order() } else { politeness });
+
+    let feed_history = FeedHistory::default_path().map(FeedHistory::new);
+    let mut feed_items = Vec::new();
+    // A dry run sends nothing, so there's no reading the feeds
+    if !options.feeds.is_empty() && !options.dry_run {
+        let downloaded = match &feed_history {
+            Ok(history) => history.downloaded()?,
+            Err(e) => {
+                debug!("Not remembering feed downloads: {}", e);
+                HashSet::new()
+            }
+        };
+        let reader = FeedReader {
+            client: &client,
+            middleware: &middleware,
+            credentials: options.credentials.as_deref(),
+            oauth: &options.oauth,
+            header_timeout: options.header_timeout,
+            newer_than: options.newer_than,
+            downloaded: &downloaded,
+        };
+        for feed in &options.feeds {
+            match reader.new_items(feed) {
+                Ok(items) => feed_items.extend(items.into_iter().map(|item| (feed.clone(), item))),
+                Err(e) => {
+                    error!("{}", e);
+                    report.failed(feed, None, None, e.to_string());
+                }
+            }
+        }
+    }
+    // What we were asked for, rather than found in feeds, pages or by following links
+    let given: HashSet<String> = urls.iter().cloned().collect();
+    let urls: Vec<String> = urls.into_iter().chain(feed_items.iter().map(|(_, item)| item.url.clone())).collect();
+
+    // Sites that put a page in front of their files have extractors to find
+    // them; a dry run leaves the pages be, as it sends nothing
+    let urls: Vec<String> = match options.dry_run {
+        true => urls,
+        false => {
+            let fetcher = Fetcher {
+                client: &client,
+                middleware: &middleware,
+                credentials: options.credentials.as_deref(),
+                oauth: &options.oauth,
+                header_timeout: options.header_timeout,
+            };
+            urls.into_iter()
+                .filter_map(|url| match options.extractors.resolve(&url, &fetcher) {
+                    Ok(url) => Some(url),
+                    Err(e) => {
+                        error!("{}", e);
+                        report.failed(&url, None, None, e.to_string());
+                        None
+                    }
+                })
+                .collect()
+        }
+    };
+
+    let urls = match &options.recursion {
+        Some(recursion) => {
+            let crawler = Crawler {
+                client: &client,
+                middleware: &middleware,
+                credentials: options.credentials.as_deref(),
+                oauth: &options.oauth,
+                header_timeout: options.header_timeout,
+                recursion,
+                cancellation: &options.cancellation,
+            };
+            crawler.crawl(urls)
+        }
+        None => urls,
+    };
+
+    // The same URL twice would be two downloads racing to write one file
+    let (urls, repeats) = dedupe::unique(urls);
+    for url in &repeats {
+        info!("{} is in the batch more than once, downloading it once", url);
+        let pb = multiprog.add(ProgressBar::new(0).with_prefix(url.clone()));
+        outcomes.skip(&pb, url, None, "duplicate", "duplicate, skipped");
+    }
+
+    if options.dry_run {
+        let dry_run = DryRun {
+            client: &client,
+            middleware: &middleware,
+            credentials: options.credentials.as_deref(),
+            auth_hook: options.auth_hook.is_some(),
+            naming: options.naming.as_ref(),
+            output: options.output.as_deref(),
+            range: options.range.as_ref(),
+            encrypt_to: options.encrypt_to.as_ref(),
+            stdout: options.stdout,
+        };
+        return Ok(match dry_run.run(&urls) {
+            0 => ExitCode::Success,
+            _ => ExitCode::Usage,
+        });
+    }
+
+    if let Some(jobs) = options.spider {
+        let spider = Spider {
+            client: &client,
+            middleware: &middleware,
+            credentials: options.credentials.as_deref(),
+            auth_hook: options.auth_hook.as_ref(),
+            oauth: &options.oauth,
+            negotiator: options.negotiator.as_deref(),
+            header_timeout: options.header_timeout,
+            retry_after: options.retry_after,
+            naming: options.naming.as_ref(),
+            politeness: &politeness,
+            report: &report,
+            progress,
+            jobs,
+            cancellation: &options.cancellation,
+            headers: options.headers,
+        };
+        let failed = spider.run(urls);
+        report.save();
+        return Ok(outcome(&report, options.strict, failed > 0, diagnosis.as_ref()));
+    }
+
+    if let Some(output_dir) = options.output_dir.as_ref().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(output_dir).map_err(|e| format!("Failed to create {}: {}", output_dir.display(), e))?;
+    }
+
+    let sizes = if options.order.needs_sizes() { order::probe_sizes(&client, &middleware, &politeness, &urls) } else { HashMap::new() };
+    // A random order is still random with --deterministic, just the same random every time
+    let urls = order::arrange(urls, options.order, &sizes, options.deterministic.map_or_else(order::random_seed, |_| 0));
+    debug!("Downloading in {} order", options.order);
+    for url in &urls {
+        progress.queued(url);
+    }
+
+    if let Some(format) = options.save_page {
+        let saver = PageSaver {
+            client: &client,
+            middleware: &middleware,
+            header_timeout: options.header_timeout,
+            format,
+            warc: options.warc.as_deref(),
+            deterministic: options.deterministic,
+        };
+        let mut failed = 0;
+        for (index, url) in urls.iter().enumerate() {
+            let pb = multiprog.add(ProgressBar::new(0).with_prefix(url.clone()));
+            match saver.save(url, index, options.naming.as_ref()) {
+                Ok(saved) => {
+                    let bytes = fs::metadata(&saved.path).map(|metadata| metadata.len()).unwrap_or_default();
+                    report.downloaded(url, &saved.path, bytes, pb.elapsed(), None, &[]);
+                    pb.set_prefix(saved.path.display().to_string());
+                    progress.finish(&pb, Some(url), outcomes.skipped.clone(), saved.to_string());
+                }
+                Err(e) => {
+                    outcomes.fail(Some(&pb), url, None, None, e.to_string());
+                    failed += 1;
+                }
+            }
+        }
+        notifier.batch_complete(urls.len() - failed, failed);
+        report.save();
+        return Ok(outcome(&report, options.strict, failed > 0, diagnosis.as_ref()));
+    }
+
+    // Once everything's been downloaded
+    let convert_links = options.convert_links;
+    let finish = || {
+        if convert_links {
+            recursive::convert_links(&report.entries());
+        }
+        if let Ok(history) = &feed_history
+            && !feed_items.is_empty()
+            && let Err(e) = history.record(&feed_items, &report.entries())
+        {
+            warn!("Could not record feed downloads: {}", e);
+        }
+    };
+
+    if let Some(jobs) = options.small_files {
+        let batch = SmallFileBatch {
+            client: &client,
+            middleware: &middleware,
+            notifier: &notifier,
+            checksum: &options.checksum,
+            checksum_db: options.checksum_db.as_deref(),
+            credentials: options.credentials.as_deref(),
+            oauth: &options.oauth,
+            auth_hook: options.auth_hook.as_ref(),
+            header_timeout: options.header_timeout,
+            retry_after: options.retry_after,
+            negotiator: options.negotiator.as_deref(),
+            timestamping: options.timestamping,
+            preserve_time: options.preserve_time,
+            no_clobber: options.no_clobber,
+            naming: options.naming.as_ref(),
+            limiter: limiter.as_ref(),
+            min_speed: options.min_speed,
+            politeness: &politeness,
+            warc: options.warc.as_deref(),
+            report: &report,
+            progress,
+            jobs,
+            cancellation: &options.cancellation,
+            deterministic: options.deterministic,
+        };
+        let summary = batch.run(urls);
+        notifier.batch_complete(summary.succeeded, summary.failed);
+        finish();
+        report.save();
+        if summary.skipped > 0 {
+            info!("Skipped {} files that already exist", summary.skipped);
+        }
+        return Ok(outcome(&report, options.strict, summary.failed > 0, diagnosis.as_ref()));
+    }
+
+    let mut run = Run {
+        options,
+        client,
+        middleware,
+        phases,
+        limiter,
+        outcomes,
+        multiprog,
+        given,
+        outputs: Outputs::default(),
+        failed: 0,
+        skipped: repeats.len(),
+        not_modified: 0,
+    };
+    let mut succeeded = 0;
+    // With mirrors, the one URL is fetched a piece at a time from all of them
+    if let Some(url) = urls.first().filter(|_| !run.options.mirrors.is_empty()) {
+        if run.mirrored(url)? {
+            succeeded += 1;
+        }
+    } else {
+        // Each URL's permit goes with its download, so its host is counted as busy until the body's in
+        if let Some(control) = &run.options.control {
+            control.start(&urls, &report, &run.options.cancellation);
+        }
+        let queue = Mutex::new(urls.into_iter().enumerate().collect::<VecDeque<_>>());
+        let mut handles = Vec::new();
+        while let Some(((index, url), permit)) = politeness.next(&queue, |(_, url)| url) {
+            if let Some(handle) = run.start(index, url, permit)? {
+                handles.push(handle);
+            }
+        }
+        for handle in handles {
+            match handle.join() {
+                Ok(Ok(())) => succeeded += 1,
+                _ => run.failed += 1,
+            }
+        }
+    }
+    succeeded += run.not_modified;
+
+    notifier.batch_complete(succeeded, run.failed);
+    finish();
+    report.save();
+    if run.skipped > 0 {
+        info!("Skipped {} files", run.skipped);
+    }
+
+    // Skipped files aren't failures: if everything else worked, we exit successfully
+    Ok(outcome(&report, run.options.strict, run.failed > 0, diagnosis.as_ref()))
+}
+
+/// The thread writing one URL's download, which says whether it worked
+type Writing = JoinHandle<Result<(), Failure>>;
+
+/// Where what happens to each URL goes: the report, the notifier and its bar
+#[derive(Clone)]
+struct Outcomes {
+    report: Arc<Report>,
+    notifier: Arc<Notifier>,
+    progress: ProgressMode,
+    finished: ProgressStyle,
+    failed: ProgressStyle,
+    skipped: ProgressStyle,
+}
+
+impl Outcomes {
+    /// `url` failed: shown on its bar if it has one and logged if not, then
+    /// notified and reported
+    fn fail(&self, pb: Option<&ProgressBar>, url: &str, path: Option<&Path>, http_status: Option<u16>, failure: impl Into<Failure>) {
+        let failure = failure.into();
+        match pb {
+            Some(pb) => self.progress.fail(pb, Some(url), self.failed.clone(), failure.message.clone()),
+            None => {
+                error!("{}", failure.message);
+                self.progress.event(Event::Failed { url: Some(url), file: "", error: &failure.message });
+            }
+        }
+        self.notifier.failure(url, &failure.message);
+        self.report.failed(url, path, http_status, failure);
+    }
+
+    /// `url` was skipped for `reason`, which its bar finishes with as `message`
+    fn skip(&self, pb: &ProgressBar, url: &str, path: Option<&Path>, reason: &str, message: &'static str) {
+        self.report.skipped(url, path, reason);
+        self.progress.finish(pb, Some(url), self.skipped.clone(), message);
+    }
+}
+
+/// What the download loop shares between the URLs of a run, and how many of
+/// them have failed or been skipped
+struct Run {
+    options: DownloadOptions,
+    client: reqwest::blocking::Client,
+    middleware: Middleware,
+    phases: Phases,
+    limiter: Option<Arc<RateLimiter>>,
+    outcomes: Outcomes,
+    multiprog: MultiProgress,
+    /// What we were asked for, rather than found in feeds, pages or by following links
+    given: HashSet<String>,
+    /// Different URLs can still come out with the same name, which only the first gets
+    outputs: Outputs,
+    failed: usize,
+    skipped: usize,
+    /// Already up to date, which counts as downloaded
+    not_modified: usize,
+}
+
+/// One URL of a run, from when its turn comes until it's written
+struct Job {
+    index: usize,
+    url: String,
+    parsed: Url,
+    /// Its host is counted as busy until this is dropped
+    _permit: HostPermit,
+    cancellation: Cancellation,
+}
+
+impl Job {
+    /// The last segment of the URL's path, which may be empty
+    fn filename(&self) -> &str {
+        self.parsed.path_segments().and_then(|mut segments| segments.next_back()).unwrap_or_default()
+    }
+}
+
+/// A response worth reading, with what's needed to archive it or ask for it again
+struct Sent {
+    response: Response,
+    request_head: Option<RequestHead>,
+    stopwatch: Stopwatch,
+    /// Kept to ask for the rest of the body with if it stalls
+    again: Option<Request>,
+}
+
+impl Run {
+    /// `url` failed before there was anything to write
+    fn fail(&mut self, pb: Option<&ProgressBar>, url: &str, path: Option<&Path>, http_status: Option<u16>, failure: impl Into<Failure>) {
+        self.outcomes.fail(pb, url, path, http_status, failure);
+        self.failed += 1;
+    }
+
+    /// `url` was skipped for `reason`, which its bar finishes with as `message`
+    fn skip(&mut self, pb: &ProgressBar, url: &str, path: Option<&Path>, reason: &str, message: &'static str) {
+        self.outcomes.skip(pb, url, path, reason, message);
+        self.skipped += 1;
+    }
+
+    /// A bar that's only there to say what happened to a URL
+    fn bar(&self, prefix: &str) -> ProgressBar {
+        self.multiprog.add(ProgressBar::new(0).with_prefix(prefix.to_string()))
+    }
+
+    /// A bar for a download
+    fn download_bar(&self) -> ProgressBar {
+        self.multiprog.add(ProgressBar::new(0).with_style(self.options.bar_style.downloading()))
+    }
+
+    /// Where the `index`th URL is saved when it's called `filename`, marked
+    /// as a range or encrypted if it is
+    fn place(&self, url: &Url, filename: &Path, index: usize) -> Option<PathBuf> {
+        if let Some(output) = &self.options.output {
+            return Some(output.clone());
+        }
+        let path = self.options.naming.place(url, filename, index)?;
+        let path = match &self.options.range {
+            Some(range) => range.output_path(&path),
+            None => path,
+        };
+        Some(match &self.options.encrypt_to {
+            Some(recipient) => recipient.output_path(&path),
+            None => path,
+        })
+    }
+
+    /// The checksums to work out for `url`, and the ones it should have.
+    /// Known checksums are for whole files, not ranges of them
+    fn checksums(&self, url: &str) -> ChecksumPlan {
+        let known_checksums = self.options.checksum_db.as_deref().filter(|_| self.options.range.is_none());
+        checksum_db::checksum_plan(&self.options.checksum, known_checksums, url)
+    }
+
+    /// Run the `--exec-before` hook for `url`, about to be saved as `file`
+    fn exec_before(&self, url: &str, file: &Path) -> Result<(), String> {
+        match &self.options.exec_before {
+            Some(hook) => hook.run(&Download { url, file: Some(file), ..Download::default() }).map_err(|e| format!("{}: {}", url, e)),
+            None => Ok(()),
+        }
+    }
+
+    /// `job`'s data is on the way, shown on `pb`
+    fn started(&self, job: &Job, pb: &ProgressBar) {
+        self.outcomes.progress.started(pb, Some(&job.url));
+        job.cancellation.track(pb, self.options.bar_style.paused());
+        if let Some(control) = &self.options.control {
+            control.track(&job.url, pb);
+        }
+    }
+
+    /// `job`, ready to be written to `file` on a thread of its own
+    fn transfer(&self, job: Job, pb: ProgressBar, stopwatch: Stopwatch, http_status: Option<u16>, file: PathBuf, checksums: ChecksumPlan) -> Transfer {
+        Transfer {
+            job,
+            pb,
+            stopwatch,
+            http_status,
+            name: file.display().to_string(),
+            file,
+            checksums,
+            outcomes: self.outcomes.clone(),
+            limiter: self.limiter.clone(),
+            min_speed: self.options.min_speed,
+            tee: self.options.tee,
+            stdout: self.options.stdout,
+        }
+    }
+
+    /// `response`'s body, asked for again from where it stopped if it stalls partway through
+    fn body(&self, response: Response, again: Option<&Request>, cancellation: &Cancellation) -> Box<dyn Read + Send> {
+        match again.and_then(Request::try_clone) {
+            Some(request) => self.options.retry_after.resuming(response, request, &self.client, self.options.header_timeout, cancellation, Arc::clone(&self.outcomes.report)),
+            None => Box::new(response),
+        }
+    }
+
+    /// The last-modified time to give `job`'s file, from `--deterministic`
+    /// or, with `--preserve-time`, the server's
+    fn last_modified(&self, modified: Option<String>) -> Option<String> {
+        match self.options.deterministic {
+            Some(time) => Some(httpdate::fmt_http_date(time)),
+            None => modified.filter(|_| self.options.preserve_time),
+        }
+    }
+
+    /// Fetch `url` a piece at a time from it and all its mirrors, returning
+    /// whether it was downloaded
+    fn mirrored(&mut self, url: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let parsed_url = Url::parse(url)?;
+        let url_filename = parsed_url.path_segments().and_then(|mut segments| segments.next_back()).unwrap_or_default();
+        let output_path = match &self.options.output {
+            Some(output) => output.clone(),
+            None => match self.options.naming.url_name(&parsed_url).and_then(|name| self.options.naming.place(&parsed_url, &name, 0)) {
+                Some(path) => self.options.naming.claim(path),
+                None => return Err(format!("{}: no filename in the URL to save it as; use --output", url).into()),
+            },
+        };
+        if self.options.no_clobber && output_path.exists() {
+            let pb = self.bar(url_filename);
+            self.skip(&pb, url, Some(&output_path), "exists", "exists, skipped");
+            return Ok(false);
+        }
+        if let Err(e) = self.exec_before(url, &output_path) {
+            self.fail(None, url, Some(&output_path), None, e);
+            return Ok(false);
+        }
+        create_parent(&output_path)?;
+        let pb = self.download_bar();
+        pb.set_prefix(output_path.file_name().unwrap_or_default().to_string_lossy().into_owned());
+        self.outcomes.progress.started(&pb, Some(url));
+        self.options.cancellation.track(&pb, self.options.bar_style.paused());
+        let checksums = self.checksums(url);
+        let filename = output_path.display().to_string();
+        let sources: Vec<String> = std::iter::once(url.to_string()).chain(self.options.mirrors.iter().cloned()).collect();
+
+        let result = swarm::download(&self.client, &self.middleware, &sources, &output_path, self.options.header_timeout, &pb, self.limiter.clone(), self.options.min_speed, &self.options.cancellation, &checksums);
+        match result {
+            Ok((_, actual)) => {
+                for actual in checksums.unchecked(&actual) {
+                    let line = format!("{}  {}", actual, filename);
+                    // With --tee, stdout is taken by the download itself
+                    match self.options.tee {
+                        true => self.outcomes.progress.eprintln(&pb, line),
+                        false => self.outcomes.progress.println(&pb, line),
+                    }
+                }
+                self.outcomes.report.downloaded(url, &output_path, pb.position(), pb.elapsed(), None, &actual);
+                self.outcomes.progress.finish(&pb, Some(url), self.outcomes.finished.clone(), "");
+                Ok(true)
+            }
+            Err(e) => {
+                let failure = match e {
+                    swarm::SwarmError::Checksum { .. } => Failure::new(ExitCode::Checksum, e.to_string()),
+                    e => Failure::from(e.to_string()),
+                };
+                self.fail(Some(&pb), url, Some(&output_path), None, failure);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Start on the `index`th URL, returning the thread writing it, or
+    /// nothing if it was skipped or failed before there was anything to write
+    fn start(&mut self, index: usize, url: String, permit: HostPermit) -> Result<Option<Writing>, Box<dyn std::error::Error>> {
+        // Each URL's stopped by its own, when they can be stopped one at a time
+        let cancellation = match &self.options.control {
+            Some(control) => control.begin(&url),
+            None => self.options.cancellation.clone(),
+        };
+        // Once we're cancelled, nothing new is started
+        if let Some(reason) = cancellation.reason() {
+            self.outcomes.report.failed(&url, None, None, cancel::Cancelled(reason).to_string());
+            self.failed += 1;
+            return Ok(None);
+        }
+        let span = trace_span!("download", url = %url);
+        let _span = span.enter();
+        // Parse our URL out so we can get a destination filename
+        let parsed = Url::parse(&url)?;
+        if parsed.cannot_be_a_base() {
+            return Err("cannot be base".into());
+        }
+        let job = Job { index, url, parsed, _permit: permit, cancellation };
+        let url_path = Some(job.filename()).filter(|filename| !filename.is_empty()).and_then(|filename| self.place(&job.parsed, Path::new(filename), index));
+
+        // With --no-clobber, don't even ask for files we already have
+        if self.options.no_clobber && url_path.as_ref().is_some_and(|path| path.exists()) {
+            let pb = self.bar(job.filename());
+            self.skip(&pb, &job.url, url_path.as_deref(), "exists", "exists, skipped");
+            return Ok(None);
+        }
+
+        // With --skip-downloaded, nor for ones an earlier run finished
+        #[cfg(feature = "state-db")]
+        if self.options.skip_downloaded
+            && let Some(path) = self.options.state.as_ref().and_then(|state| state.downloaded(&job.url).inspect_err(|e| warn!("{}", e)).ok().flatten())
+        {
+            let pb = self.bar(&path.file_name().unwrap_or_default().to_string_lossy());
+            self.skip(&pb, &job.url, Some(&path), "already downloaded", "already downloaded, skipped");
+            return Ok(None);
+        }
+
+        // Other schemes are read through their transports, which know nothing of HTTP's options
+        if let Some(transport) = self.options.transports.for_url(&job.parsed).cloned() {
+            return self.start_transport(job, transport, url_path);
+        }
+
+        // Anything else left is for our own HTTP client, which can't fetch other schemes
+        if !matches!(job.parsed.scheme(), "http" | "https") {
+            let errstr = match job.parsed.scheme() {
+                "file" => format!("{}: file:// URLs are only read with --allow-file-urls", job.url),
+                scheme => format!("{}: {}:// URLs can't be downloaded", job.url, scheme),
+            };
+            self.fail(None, &job.url, url_path.as_deref(), None, errstr);
+            return Ok(None);
+        }
+        self.start_http(job, url_path)
+    }
+
+    /// Start reading `job` through `transport`, saved like any download,
+    /// checksums, range and all
+    fn start_transport(&mut self, job: Job, transport: Arc<dyn Transport>, url_path: Option<PathBuf>) -> Result<Option<Writing>, Box<dyn std::error::Error>> {
+        let pb = self.download_bar();
+        // Local files could be anything on this machine, so only URLs we were given can name them
+        if job.parsed.scheme() == "file" && !self.given.contains(&job.url) {
+            let errstr = format!("{}: file:// URLs are only read when they're given to download, not when they're found", job.url);
+            self.fail(Some(&pb), &job.url, None, None, errstr);
+            return Ok(None);
+        }
+        let output_path = match self.options.stdout {
+            true => Some(PathBuf::from("-")),
+            false => url_path.map(|path| self.options.naming.claim(path)),
+        };
+        let Some(output_path) = output_path else {
+            let errstr = format!("{}: no filename in the URL to save it as", job.url);
+            self.fail(Some(&pb), &job.url, None, None, errstr);
+            return Ok(None);
+        };
+        if let Err(earlier) = self.outputs.take(&output_path, &job.url) {
+            warn!("{} would be saved as {}, which {} already is; skipping it", job.url, output_path.display(), earlier);
+            self.skip(&pb, &job.url, Some(&output_path), "same file as an earlier URL", "same file as an earlier URL, skipped");
+            return Ok(None);
+        }
+        create_parent(&output_path)?;
+        pb.set_prefix(output_path.file_name().unwrap_or_default().to_string_lossy().into_owned());
+        if !self.options.stdout
+            && let Err(e) = self.exec_before(&job.url, &output_path)
+        {
+            self.fail(Some(&pb), &job.url, Some(&output_path), None, e);
+            return Ok(None);
+        }
+        self.started(&job, &pb);
+        debug!("Fetching {} with the {} transport", job.url, transport.name());
+        let checksums = self.checksums(&job.url);
+        let range = self.options.range.clone();
+        let encrypt_to = self.options.encrypt_to.clone();
+        let extract = self.options.extract.clone();
+        let deterministic = self.options.deterministic;
+        let preserve_time = self.options.preserve_time;
+        let transfer = self.transfer(job, pb, Stopwatch::start(), None, output_path, checksums);
+        Ok(Some(transfer.spawn(move |transfer| {
+            let metadata = transport.metadata(&transfer.job.parsed).map_err(transport_failure)?;
+            let (start, end) = range.as_ref().map_or((0, None), |range| (range.start, range.end));
+            let length = end.or(metadata.size).map(|end| end.saturating_sub(start));
+            transfer.pb.set_length(length.unwrap_or_default());
+            let body = transport.open(&transfer.job.parsed, start, end).map_err(transport_failure)?;
+            // With --stdout there's nothing to name, just a stream to pass on
+            if transfer.stdout {
+                return transfer.to_stdout(body);
+            }
+            // Encrypted, it goes straight through the encryption as an HTTP download would
+            if let Some(recipient) = &encrypt_to {
+                return transfer.encrypted(body, recipient);
+            }
+            let dest = PartFile::create(&transfer.file, &transfer.job.url, &header::HeaderMap::new(), length, range.as_ref())
+                .map_err(|e| format!("Failed to create file: {}", e))?
+                .cancellable(&transfer.job.cancellation);
+            let last_modified = deterministic.or(metadata.modified.filter(|_| preserve_time)).map(httpdate::fmt_http_date);
+            let saved = transfer.to_part(body, dest, last_modified, None)?;
+            transfer.extracted(saved, extract.as_ref())
+        })))
+    }
+
+    /// Ask for `job` over HTTP, with whatever the options add to the request,
+    /// failing it if there's no response worth reading
+    fn send(&mut self, job: &Job, url_path: Option<&Path>, appender: Option<&Appender>) -> Option<Sent> {
+        let url = job.url.as_str();
+        // Make our HTTP request and get our response (headers)
+        let mut request = self.middleware.get(&self.client, url);
+        if self.options.timestamping && let Some(since) = url_path.and_then(timestamping::if_modified_since) {
+            request = request.header(header::IF_MODIFIED_SINCE, since);
+        }
+        if let Some(range) = &self.options.range {
+            // Byte offsets only mean something for the unencoded file
+            request = request.header(header::RANGE, range.header_value(0)).header(header::ACCEPT_ENCODING, "identity");
+        }
+        if let Some(range) = appender.and_then(Appender::range_header) {
+            request = request.header(header::RANGE, range).header(header::ACCEPT_ENCODING, "identity");
+        }
+        if let Some(store) = &self.options.credentials {
+            request = store.authorize(request, &job.parsed, &self.client, &self.options.oauth);
+        }
+        if let Some(hook) = &self.options.auth_hook {
+            match hook.headers("GET", url) {
+                Ok(headers) => request = request.headers(headers),
+                Err(e) => {
+                    self.fail(None, url, url_path, None, format!("{}: {}", url, e));
+                    return None;
+                }
+            }
+        }
+        // A header that isn't a valid value, like a stored token with a control character in it
+        let request = match request.build() {
+            Ok(request) => request,
+            Err(e) => {
+                self.fail(None, url, url_path, None, format!("{}: {}", url, e));
+                return None;
+            }
+        };
+        logging::request(&request);
+        let request_head = self.options.warc.as_ref().map(|_| RequestHead::new(&request));
+        let mut stopwatch = Stopwatch::start();
+        let again = request.try_clone();
+        // Shown only while we wait to retry
+        let mut countdown: Option<ProgressBar> = None;
+        let progress = self.outcomes.progress;
+        let response = self.options.retry_after.send(
+            request,
+            &job.cancellation,
+            &self.outcomes.report,
+            |reason, left| {
+                let message = format!("{}: {}, retrying in {}", job.filename(), reason, HumanDuration(left));
+                let pb = countdown.get_or_insert_with(|| {
+                    let pb = self.multiprog.add(ProgressBar::new_spinner());
+                    // Plain progress has no bar to count down in, so it just says so once
+                    if progress == ProgressMode::Plain {
+                        progress.status(&pb, &message);
+                    }
+                    pb
+                });
+                pb.set_message(message);
+                pb.tick();
+            },
+            |request| {
+                stopwatch = Stopwatch::start();
+                match (&self.options.negotiator, &self.options.ntlm) {
+                    (Some(negotiator), _) => negotiator.execute(&self.client, request, self.options.header_timeout),
+                    (None, Some(ntlm)) => ntlm.execute(&self.client, request, self.options.header_timeout),
+                    (None, None) => timeouts::execute(&self.client, request, self.options.header_timeout),
+                }
+            },
+        );
+        if let Some(pb) = countdown {
+            pb.finish_and_clear();
+        }
+        let response = match response {
+            Ok(response) => {
+                self.outcomes.report.timed(url, stopwatch.headers(&self.phases));
+                response
+            }
+            Err(e) => {
+                self.fail(None, url, url_path, None, Failure::new(ExitCode::Network, e.to_string()));
+                return None;
+            }
+        };
+        if let Err(reason) = self.middleware.intercept(&response, &DownloadContext { method: &Method::GET, url }) {
+            let http_status = response.status().as_u16();
+            self.fail(None, url, url_path, Some(http_status), format!("{}: {}", url, reason));
+            return None;
+        }
+        Some(Sent { response, request_head, stopwatch, again })
+    }
+
+    /// Why `response` isn't what was asked for, if it isn't
+    fn refused(&self, job: &Job, response: &Response, appender: Option<&Appender>) -> Option<String> {
+        if response.status().is_server_error() || response.status().is_client_error() {
+            return Some(format!("{}: server returned {}", job.parsed.as_str(), response.status()));
+        }
+        if let Some(range) = &self.options.range {
+            let content_range = response.headers().get(header::CONTENT_RANGE).and_then(|value| value.to_str().ok()).unwrap_or_default();
+            if response.status() != StatusCode::PARTIAL_CONTENT {
+                return Some(format!("{}: server sent the whole file instead of {} (it doesn't support ranges)", job.parsed.as_str(), range));
+            }
+            if partial::range_start(content_range) != Some(range.start) {
+                return Some(format!("{}: server sent the wrong range ({})", job.parsed.as_str(), content_range));
+            }
+        }
+        appender.and_then(|appender| appender.check(response).err()).map(|e| e.to_string())
+    }
+
+    /// Ask for `job` over HTTP, and start writing what comes back the way the options say to
+    fn start_http(&mut self, job: Job, url_path: Option<PathBuf>) -> Result<Option<Writing>, Box<dyn std::error::Error>> {
+        let append = self.options.append;
+        // With --append, files are named after the URL, so we know what to append to before asking
+        if append
+            && let Some(path) = &url_path
+            && let Err(earlier) = self.outputs.take(path, &job.url)
+        {
+            let pb = self.bar(job.filename());
+            warn!("{} would be appended to {}, which {} already is; skipping it", job.url, path.display(), earlier);
+            self.skip(&pb, &job.url, Some(path), "same file as an earlier URL", "same file as an earlier URL, skipped");
+            return Ok(None);
+        }
+        let appender = match url_path.as_deref().filter(|_| append).map(Appender::open) {
+            Some(Ok(appender)) => Some(appender),
+            Some(Err(e)) => {
+                self.fail(None, &job.url, url_path.as_deref(), None, e.to_string());
+                return Ok(None);
+            }
+            None if append => {
+                self.fail(None, &job.url, None, None, format!("{}: no filename in the URL to append to", job.url));
+                return Ok(None);
+            }
+            None => None,
+        };
+        let Some(Sent { response, request_head, stopwatch, again }) = self.send(&job, url_path.as_deref(), appender.as_ref()) else {
+            return Ok(None);
+        };
+        let http_status = response.status().as_u16();
+
+        // Instantiate our progress bar
+        let pb = self.download_bar();
+
+        // With --timestamping, our copy may already be up to date
+        if response.status() == StatusCode::NOT_MODIFIED {
+            pb.set_prefix(job.filename().to_string());
+            self.outcomes.skip(&pb, &job.url, url_path.as_deref(), "not modified", "not modified");
+            self.not_modified += 1;
+            return Ok(None);
+        }
+        if let Some(appender) = appender.as_ref().filter(|appender| appender.is_complete(&response)) {
+            pb.set_prefix(appender.path().display().to_string());
+            self.outcomes.skip(&pb, &job.url, Some(appender.path()), "already complete", "already complete");
+            self.not_modified += 1;
+            return Ok(None);
+        }
+
+        // Bail out if some bad stuff happened
+        if let Some(errstr) = self.refused(&job, &response, appender.as_ref()) {
+            self.fail(Some(&pb), &job.url, None, Some(http_status), errstr);
+            return Ok(None);
+        }
+
+        // Check the Content-Length header if we got one. A response that's
+        // decompressed on the way (gzip, br, zstd) has none: only the
+        // compressed length was sent, so there's no total to show
+        let content_length = match response.content_length() {
+            Some(length) => length,
+            None => {
+                pb.unset_length();
+                pb.set_style(self.options.bar_style.streaming());
+                0
+            }
+        };
+        if content_length > 0 {
+            pb.set_length(content_length);
+        }
+
+        let checksums = self.checksums(&job.url);
+        for expected in &checksums.expected {
+            debug!("Expecting {} for {}", expected, job.url);
+        }
+
+        // With --stdout there's nothing to name, just a stream to pass on
+        if self.options.stdout {
+            let name = Some(job.filename()).filter(|name| !name.is_empty()).unwrap_or("stdout").to_string();
+            pb.set_prefix(name.clone());
+            let body = self.body(response, again.as_ref(), &job.cancellation);
+            let mut transfer = self.transfer(job, pb, stopwatch, Some(http_status), PathBuf::from("-"), checksums);
+            transfer.name = name;
+            return Ok(Some(transfer.spawn(move |transfer| transfer.to_stdout(body))));
+        }
+
+        // The server's suggested name wins over the one in the URL we were
+        // redirected to, which wins over the one in the URL we asked for
+        let final_url = response.url().clone();
+        let output_filename = self.options.naming.server_name(response.headers())
+            .or_else(|| self.options.naming.url_name(&final_url))
+            .filter(|_| !append)
+            .unwrap_or_else(|| PathBuf::from(job.filename()));
+        // An HLS playlist is saved as the stream it lists, not the playlist itself
+        let is_hls = !append && self.options.range.is_none() && hls::is_playlist(&final_url, response.headers());
+        let output_filename = if is_hls { hls::output_name(&output_filename) } else { output_filename };
+
+        if output_filename.to_string_lossy().trim().is_empty() {
+            let errstr = format!("{}: no filename could be detected from the URL or Content-Disposition headers", job.parsed.as_str());
+            self.fail(Some(&pb), &job.url, None, Some(http_status), errstr);
+            return Ok(None);
+        }
+
+        // A template's `{1}` and so on are still what the URL asked for matched
+        if final_url != job.parsed {
+            glob::redirected(job.parsed.as_str(), final_url.as_str());
+        }
+        let Some(output_path) = self.place(&final_url, &output_filename, job.index) else {
+            let errstr = format!("{}: the output template gives an empty name for {}", job.parsed.as_str(), output_filename.display());
+            self.fail(Some(&pb), &job.url, None, Some(http_status), errstr);
+            return Ok(None);
+        };
+        if self.options.no_clobber && output_path.exists() {
+            pb.set_prefix(output_filename.display().to_string());
+            self.skip(&pb, &job.url, Some(&output_path), "exists", "exists, skipped");
+            return Ok(None);
+        }
+        let output_path = self.options.naming.claim(output_path);
+        // An appended file was taken before asking for it
+        if appender.is_none()
+            && let Err(earlier) = self.outputs.take(&output_path, &job.url)
+        {
+            pb.set_prefix(output_filename.display().to_string());
+            warn!("{} would be saved as {}, which {} already is; skipping it", job.url, output_path.display(), earlier);
+            self.skip(&pb, &job.url, Some(&output_path), "same file as an earlier URL", "same file as an earlier URL, skipped");
+            return Ok(None);
+        }
+
+        // Set the prefix to our filename so we can display it
+        pb.set_prefix(output_path.file_name().unwrap_or_default().to_string_lossy().into_owned());
+        self.started(&job, &pb);
+
+        // Now we create our output file, which stays a .part file until it's complete...
+        create_parent(&output_path)?;
+        if let Err(e) = self.exec_before(&job.url, &output_path) {
+            self.fail(Some(&pb), &job.url, Some(&output_path), Some(http_status), e);
+            return Ok(None);
+        }
+        let transfer = self.transfer(job, pb, stopwatch, Some(http_status), output_path, checksums);
+
+        // Its segments are fetched a few at a time and joined into one file
+        if is_hls {
+            return Ok(Some(self.start_hls(transfer, response)));
+        }
+
+        let exchange = request_head.map(|head| Exchange::new(head, &response));
+        let server_modified = response.headers().get(header::LAST_MODIFIED).and_then(|value| value.to_str().ok()).map(str::to_string);
+        let last_modified = self.last_modified(server_modified);
+
+        // --append writes straight to the file, and checks the whole of it once it's done
+        if let Some(appender) = appender {
+            transfer.pb.set_length(appender.offset() + content_length);
+            transfer.pb.set_position(appender.offset());
+            let body = self.body(response, again.as_ref(), &transfer.job.cancellation);
+            let warc = self.options.warc.clone();
+            return Ok(Some(transfer.spawn(move |transfer| transfer.appended(body, &appender, warc.as_deref().zip(exchange.as_ref()), last_modified))));
+        }
+
+        // Encrypted downloads go straight through the encryption, so there's no plaintext to resume from
+        if let Some(recipient) = self.options.encrypt_to.clone() {
+            let body = self.body(response, again.as_ref(), &transfer.job.cancellation);
+            return Ok(Some(transfer.spawn(move |transfer| transfer.encrypted(body, &recipient))));
+        }
+
+        // Images written to a device are checked by reading them back
+        if self.options.device {
+            let image_length = response.content_length();
+            let body = self.body(response, again.as_ref(), &transfer.job.cancellation);
+            return Ok(Some(transfer.spawn(move |transfer| transfer.to_device(body, image_length))));
+        }
+
+        let dest = PartFile::create(&transfer.file, &transfer.job.url, response.headers(), response.content_length(), self.options.range.as_ref())
+            .map_err(|e| format!("Failed to create file: {}", e))?
+            .cancellable(&transfer.job.cancellation);
+        #[cfg(feature = "state-db")]
+        let dest = dest.recorded(self.options.state.as_ref());
+        #[cfg(feature = "state-db")]
+        if let Some(state) = &self.options.state
+            && let Err(e) = state.started(&transfer.job.url, &transfer.file, response.headers().get(header::ETAG).and_then(|etag| etag.to_str().ok()))
+        {
+            warn!("Could not record {}: {}", transfer.job.url, e);
+        }
+        let decompression = self.options.decompress.then(|| Compression::detect(&transfer.file)).flatten();
+        if self.options.decompress && decompression.is_none() {
+            info!("Not decompressing {}: it isn't a single .gz, .xz or .zst file", transfer.name);
+        }
+        let body = self.body(response, again.as_ref(), &transfer.job.cancellation);
+        let warc = self.options.warc.clone();
+        let attestation = self.options.attestation.clone();
+        let client = self.client.clone();
+        let decompressed_checksum = self.options.decompressed_checksum.clone();
+        let extract = self.options.extract.clone();
+        Ok(Some(transfer.spawn(move |transfer| {
+            let saved = transfer.to_part(body, dest, last_modified, warc.as_deref().zip(exchange.as_ref()))?;
+            let saved = transfer.attested(saved, attestation.as_ref(), &client)?;
+            let saved = transfer.decompressed(saved, decompression, decompressed_checksum.as_ref())?;
+            transfer.extracted(saved, extract.as_ref())
+        })))
+    }
+
+    /// Start fetching the stream `playlist` lists into `transfer`'s file
+    fn start_hls(&self, transfer: Transfer, playlist: Response) -> Writing {
+        let client = self.client.clone();
+        let middleware = self.middleware.clone();
+        let credentials = self.options.credentials.clone();
+        let oauth = self.options.oauth.clone();
+        let auth_hook = self.options.auth_hook.clone();
+        let header_timeout = self.options.header_timeout;
+        let encrypt_to = self.options.encrypt_to.clone();
+        let remux = self.options.remux.clone();
+        transfer.spawn(move |transfer| {
+            let downloader = HlsDownloader {
+                client: &client,
+                middleware: &middleware,
+                credentials: credentials.as_deref(),
+                oauth: &oauth,
+                auth_hook: auth_hook.as_ref(),
+                header_timeout,
+                limiter: transfer.limiter.clone(),
+                min_speed: transfer.min_speed,
+                jobs: hls::DEFAULT_JOBS,
+                cancellation: &transfer.job.cancellation,
+            };
+            transfer.hls(playlist, &downloader, encrypt_to.as_ref(), remux.as_deref())
+        })
+    }
+}
+
+/// A download that's been written, and where it ended up
+struct Saved {
+    path: PathBuf,
+    bytes: u64,
+    checksums: Vec<Checksum>,
+}
+
+/// One URL on its way to its file, with everything the thread writing it needs
+struct Transfer {
+    job: Job,
+    pb: ProgressBar,
+    stopwatch: Stopwatch,
+    http_status: Option<u16>,
+    file: PathBuf,
+    /// What it's called in errors, and next to the checksums printed for it
+    name: String,
+    checksums: ChecksumPlan,
+    outcomes: Outcomes,
+    limiter: Option<Arc<RateLimiter>>,
+    min_speed: Option<MinimumSpeed>,
+    tee: bool,
+    stdout: bool,
+}
+
+impl Transfer {
+    /// Write it on a thread of its own with `write`, then report how that went
+    fn spawn(self, write: impl FnOnce(&Transfer) -> Result<Saved, Failure> + Send + 'static) -> Writing {
+        let span = trace_span!("write");
+        thread::spawn(move || {
+            let _span = span.entered();
+            let result = write(&self);
+            match &result {
+                Ok(saved) => {
+                    self.outcomes.report.downloaded(&self.job.url, &saved.path, saved.bytes, self.stopwatch.elapsed(), self.http_status, &saved.checksums);
+                    self.outcomes.progress.finish(&self.pb, Some(&self.job.url), self.outcomes.finished.clone(), "");
+                }
+                Err(e) => self.outcomes.fail(Some(&self.pb), &self.job.url, Some(&self.file), self.http_status, e),
+            }
+            result.map(drop)
+        })
+    }
+
+    /// `body` as it's written: throttled, counted on the bar and, with
+    /// `--tee`, copied to stdout
+    fn read(&self, body: Box<dyn Read + Send>) -> impl Read + use<> {
+        self.pb.wrap_read(teed(throttled(body, &self.job.cancellation, self.limiter.clone(), self.min_speed), self.tee))
+    }
+
+    /// Check `actual` against the checksums we expected, and print the ones
+    /// that were only asked for
+    fn verify(&self, actual: Vec<Checksum>) -> Result<Vec<Checksum>, Failure> {
+        self.checksums.verify(&actual).map_err(|e| Failure::new(ExitCode::Checksum, format!("{}: {}", self.name, e)))?;
+        // A device is read back with a checksum of its own, which nobody asked to see
+        for actual in self.checksums.unchecked(&actual).filter(|_| !self.checksums.is_empty()) {
+            let line = format!("{}  {}", actual, self.name);
+            // With --tee or --stdout, stdout is taken by the download itself
+            match self.tee || self.stdout {
+                true => self.outcomes.progress.eprintln(&self.pb, line),
+                false => self.outcomes.progress.println(&self.pb, line),
+            }
+        }
+        Ok(actual)
+    }
+
+    /// Saved as `path`, with everything read
+    fn saved(&self, path: PathBuf, checksums: Vec<Checksum>) -> Saved {
+        Saved { path, bytes: self.pb.position(), checksums }
+    }
+
+    /// Pass `body` on to stdout
+    fn to_stdout(&self, body: Box<dyn Read + Send>) -> Result<Saved, Failure> {
+        let mut body = self.read(body);
+        let out = std::io::stdout().lock();
+        let actual = match self.checksums.is_empty() {
+            false => {
+                let mut writer = HashingWriter::with_algorithms(out, &self.checksums.algorithms);
+                copy::buffered(&mut body, &mut writer).and_then(|_| writer.flush()).map(|()| writer.finish_all().1)
+            }
+            true => {
+                let mut out = out;
+                copy::buffered(&mut body, &mut out).and_then(|_| out.flush()).map(|()| Vec::new())
+            }
+        };
+        let actual = actual.map_err(|e| format!("Failed to write to stdout: {}", e))?;
+        Ok(self.saved(PathBuf::from("-"), self.verify(actual)?))
+    }
+
+    /// Fetch the stream `playlist` lists with `downloader` and join it into
+    /// one file, encrypted or remuxed if it's to be
+    fn hls(&self, playlist: Response, downloader: &HlsDownloader, encrypt_to: Option<&Recipient>, remux: Option<&str>) -> Result<Saved, Failure> {
+        let (bytes, actual) = match encrypt_to {
+            // The segments go straight into the encryption, so the stream never reaches the disk unencrypted
+            Some(recipient) => {
+                let mut encrypted = encrypt::EncryptingWriter::create(&self.file, recipient).map_err(|e| e.to_string())?;
+                let mut writer = HashingWriter::with_algorithms(&mut encrypted, &self.checksums.algorithms);
+                let downloaded = downloader.download_to(playlist, &mut writer, &self.pb);
+                let actual = writer.finish_all().1;
+                match downloaded {
+                    Ok(bytes) => {
+                        encrypted.finish().map_err(|e| e.to_string())?;
+                        (bytes, actual)
+                    }
+                    Err(HlsError::Write(source)) => return Err(encrypted.abandon(source).to_string().into()),
+                    Err(e) => return Err(e.to_string().into()),
+                }
+            }
+            None => {
+                let bytes = downloader.download(playlist, &self.file, &self.pb).map_err(|e| e.to_string())?;
+                let actual = match self.checksums.is_empty() {
+                    true => Vec::new(),
+                    false => checksum::file_checksums(&self.file, &self.checksums.algorithms).map_err(|e| format!("{}: {}", self.name, e))?,
+                };
+                (bytes, actual)
+            }
+        };
+        // The checksum is of the stream as it was downloaded, so it's checked before any remuxing
+        let actual = self.verify(actual).inspect_err(|_| {
+            let _ = fs::remove_file(&self.file);
+        })?;
+        let path = match remux {
+            Some(format) => hls::remux(&self.file, format).map_err(|e| e.to_string())?,
+            None => self.file.clone(),
+        };
+        Ok(Saved { path, bytes, checksums: actual })
+    }
+
+    /// Append `body` to what `appender` already has, and check the whole file
+    /// once it's done
+    fn appended(&self, body: Box<dyn Read + Send>, appender: &Appender, warc: Option<(&WarcWriter, &Exchange)>, last_modified: Option<String>) -> Result<Saved, Failure> {
+        let added = appender.write(&mut self.read(body)).map_err(|e| e.to_string())?;
+        if let Some((warc, exchange)) = warc {
+            let mut file = File::open(&self.file).map_err(|e| format!("{}: {}", self.name, e))?;
+            file.seek(SeekFrom::Start(appender.offset())).map_err(|e| format!("{}: {}", self.name, e))?;
+            warc.write(exchange, &mut file, added).map_err(|e| e.to_string())?;
+        }
+        if let Some(last_modified) = &last_modified
+            && let Err(e) = timestamping::set_modified(&self.file, last_modified)
+        {
+            warn!("Could not set modification time of {}: {}", self.file.display(), e);
+        }
+        if self.checksums.is_empty() {
+            return Ok(self.saved(self.file.clone(), Vec::new()));
+        }
+        let actual = checksum::file_checksums(&self.file, &self.checksums.algorithms).map_err(|e| format!("{}: {}", self.name, e))?;
+        Ok(self.saved(self.file.clone(), self.verify(actual)?))
+    }
+
+    /// Write `body` through the encryption to `recipient`
+    fn encrypted(&self, body: Box<dyn Read + Send>, recipient: &Recipient) -> Result<Saved, Failure> {
+        let actual = encrypt::write_encrypted(&self.file, recipient, &mut self.read(body), &self.checksums.algorithms).map_err(|e| e.to_string())?;
+        let actual = self.verify(actual).inspect_err(|_| {
+            let _ = fs::remove_file(&self.file);
+        })?;
+        Ok(self.saved(self.file.clone(), actual))
+    }
+
+    /// Write the image `body` to a device, and read it back to check it
+    fn to_device(&self, body: Box<dyn Read + Send>, image_length: Option<u64>) -> Result<Saved, Failure> {
+        // Reading back needs something to compare, even if nobody asked for a checksum
+        let algorithms = match self.checksums.is_empty() {
+            true => vec![HashAlgorithm::Blake3],
+            false => self.checksums.algorithms.clone(),
+        };
+        let actual = device::write_image(&self.file, &mut self.read(body), image_length, &algorithms).map_err(|e| e.to_string())?;
+        Ok(self.saved(self.file.clone(), self.verify(actual)?))
+    }
+
+    /// Write `body` to `dest`, which stays a .part file until it's complete
+    /// and its checksums check out, then archive it if there's a WARC
+    fn to_part(&self, body: Box<dyn Read + Send>, dest: PartWriter, last_modified: Option<String>, warc: Option<(&WarcWriter, &Exchange)>) -> Result<Saved, Failure> {
+        let mut body = self.read(body);
+        // ...and write the data to it as we get it
+        let (part, actual) = match self.checksums.is_empty() {
+            false => {
+                let mut writer = HashingWriter::with_algorithms(dest, &self.checksums.algorithms);
+                copy::buffered(&mut body, &mut writer).map_err(copy_error)?;
+                let (dest, actual) = writer.finish_all();
+                (dest.finish().map_err(|e| e.to_string())?, actual)
+            }
+            true => {
+                let mut dest = dest;
+                copy::buffered(&mut body, &mut dest).map_err(copy_error)?;
+                (dest.finish().map_err(|e| e.to_string())?, Vec::new())
+            }
+        };
+        let actual = match self.verify(actual) {
+            Ok(actual) => actual,
+            Err(e) => {
+                // Don't leave a file we know is bad lying around
+                part.discard();
+                return Err(e);
+            }
+        };
+        let output = part.complete().map_err(|e| e.to_string())?;
+        if let Some(last_modified) = &last_modified
+            && let Err(e) = timestamping::set_modified(&output, last_modified)
+        {
+            warn!("Could not set modification time of {}: {}", output.display(), e);
+        }
+        if let Some((warc, exchange)) = warc {
+            // The body is archived from the finished file, so it's never held in memory
+            let mut file = File::open(&output).map_err(|e| format!("{}: {}", output.display(), e))?;
+            let length = file.metadata().map_err(|e| format!("{}: {}", output.display(), e))?.len();
+            warc.write(exchange, &mut file, length).map_err(|e| e.to_string())?;
+        }
+        Ok(self.saved(output, actual))
+    }
+
+    /// Check `saved` is attested to by someone `verifier` trusts, deleting it if it isn't
+    fn attested(&self, saved: Saved, verifier: Option<&AttestationVerifier>, client: &reqwest::blocking::Client) -> Result<Saved, Failure> {
+        let Some(verifier) = verifier else {
+            return Ok(saved);
+        };
+        match verifier.verify(&saved.path, &self.job.url, client) {
+            Ok(()) => Ok(saved),
+            Err(e) => {
+                let _ = fs::remove_file(&saved.path);
+                Err(format!("{}: {}", self.name, e).into())
+            }
+        }
+    }
+
+    /// Decompress `saved` with `decompression` if there is one, after which
+    /// it's reported as where it was decompressed to
+    fn decompressed(&self, saved: Saved, decompression: Option<(Compression, PathBuf)>, expected: Option<&Checksum>) -> Result<Saved, Failure> {
+        match decompression {
+            Some((compression, decompressed)) => {
+                let path = decompress::decompress_file(&saved.path, compression, &decompressed, expected).map_err(|e| match e {
+                    DecompressError::Checksum(_) => Failure::new(ExitCode::Checksum, e.to_string()),
+                    e => Failure::from(e.to_string()),
+                })?;
+                info!("Decompressed {} to {}", self.name, path.display());
+                Ok(Saved { path, ..saved })
+            }
+            None if expected.is_some() => Err(format!("{}: isn't compressed, so there's nothing to check --decompressed-checksum against", self.name).into()),
+            None => Ok(saved),
+        }
+    }
+
+    /// Extract `saved` with `extraction` if there is one; an archive that's
+    /// been extracted and deleted is reported as where it went
+    fn extracted(&self, saved: Saved, extraction: Option<&Extraction>) -> Result<Saved, Failure> {
+        let Some(extraction) = extraction else {
+            return Ok(saved);
+        };
+        match extraction.run(&saved.path).map_err(|e| e.to_string())? {
+            Some(dir) if extraction.delete => Ok(Saved { path: dir, ..saved }),
+            _ => Ok(saved),
+        }
+    }
+}
+
+/// Make the directory `path` goes in, if it has one
+fn create_parent(path: &Path) -> Result<(), String> {
+    match path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        Some(parent) => fs::create_dir_all(parent).map_err(|e| format!("Failed to create {}: {}", parent.display(), e)),
+        None => Ok(()),
+    }
+}
+
+/// A transport that couldn't reach the file failed on the network; one that
+/// couldn't read it, like any other error
+fn transport_failure(e: TransportError) -> Failure {
+    match e {
+        TransportError::Fetch { .. } => Failure::new(ExitCode::Network, e.to_string()),
+        TransportError::Io { .. } => Failure::from(e.to_string()),
+    }
+}
+
+/// The status the report calls for
+fn outcome(report: &Report, strict: bool, failed: bool, diagnosis: Option<&Diagnosis>) -> ExitCode {
+    // One hint about the network beats the same connection error for every URL
+    if let Some(diagnosis) = diagnosis
+        && let Some(urls) = report.unreachable()
+    {
+        info!("No download could connect, checking the network");
+        if let Some(hint) = diagnosis.run(&urls) {
+            error!("Nothing could be downloaded: {}", hint);
+        }
+    }
+    match report.exit_code(strict) {
+        // Something failed without making it into the report
+        ExitCode::Success if failed => ExitCode::Error,
+        code => code,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::Status;
+
+    /// A server with `/a.txt` on it, and nothing else
+    fn serve() -> std::net::SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = [0u8; 1024];
+                let read = stream.read(&mut request).unwrap();
+                let reply = match String::from_utf8_lossy(&request[..read]).split(' ').nth(1) {
+                    Some("/a.txt") => "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello",
+                    // Cloudflare's, which has no standard reason
+                    Some("/origin-down.txt") => "HTTP/1.1 520 \r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                };
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn test_downloader() {
+        let address = serve();
+        let dir = std::env::temp_dir().join(format!("rustdl-downloader-{}", std::process::id()));
+        let (sender, receiver) = mpsc::channel();
+        let downloader = Downloader::builder().output_dir(&dir).max_concurrent(2).progress_channel(sender).build();
+        let url = format!("http://{}/a.txt", address);
+        assert_eq!(downloader.fetch(&url).unwrap(), ExitCode::Success);
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "hello");
+        let finished = receiver.try_iter().last().unwrap();
+        assert_eq!((finished.url, finished.bytes, finished.state), (url, 5, progress::DownloadState::Finished));
+        let code = downloader.fetch_all([format!("http://{}/b.txt", address), format!("http://{}/c.txt", address)]).unwrap();
+        assert_eq!(code, ExitCode::ClientError);
+        let statuses: Vec<Status> = downloader.report().entries().iter().map(|entry| entry.status).collect();
+        assert_eq!(statuses, [Status::Downloaded, Status::Failed, Status::Failed]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_embedder_middleware() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, traced) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = [0u8; 1024];
+                let read = stream.read(&mut request).unwrap();
+                let request = String::from_utf8_lossy(&request[..read]).to_ascii_lowercase();
+                let _ = sender.send(request.lines().find_map(|line| line.strip_prefix("x-trace: ")).map(str::to_string));
+                stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello").unwrap();
+            }
+        });
+        let dir = std::env::temp_dir().join(format!("rustdl-middleware-{}", std::process::id()));
+        let url = format!("http://{}/a.txt", address);
+
+        let traced_downloader = Downloader::builder()
+            .output_dir(&dir)
+            .with_request(|request: reqwest::blocking::RequestBuilder, _: &DownloadContext| request.header("x-trace", "abc123"))
+            .build();
+        assert_eq!(traced_downloader.fetch(&url).unwrap(), ExitCode::Success);
+        assert_eq!(traced.recv().unwrap().as_deref(), Some("abc123"));
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "hello");
+        fs::remove_dir_all(&dir).unwrap();
+
+        let rejecting = Downloader::builder()
+            .output_dir(&dir)
+            .with_response(|_: &reqwest::blocking::Response, _: &DownloadContext| Err("not signed".to_string()))
+            .build();
+        assert_ne!(rejecting.fetch(&url).unwrap(), ExitCode::Success);
+        let entry = &rejecting.report().entries()[0];
+        assert_eq!(entry.status, Status::Failed);
+        assert!(entry.error.as_deref().unwrap_or_default().contains("not signed"));
+        assert!(!dir.join("a.txt").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_status_without_a_reason() {
+        let address = serve();
+        let dir = std::env::temp_dir().join(format!("rustdl-status-{}", std::process::id()));
+        let downloader = Downloader::builder().output_dir(&dir).build();
+        assert_ne!(downloader.fetch(format!("http://{}/origin-down.txt", address)).unwrap(), ExitCode::Success);
+        let entry = &downloader.report().entries()[0];
+        assert_eq!((entry.status, entry.http_status), (Status::Failed, Some(520)));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_unsendable_request_fails_its_url() {
+        let address = serve();
+        let dir = std::env::temp_dir().join(format!("rustdl-bad-header-{}", std::process::id()));
+        let downloader = Downloader::builder()
+            .output_dir(&dir)
+            .with_request(|request: reqwest::blocking::RequestBuilder, context: &DownloadContext| match context.url.ends_with("/b.txt") {
+                true => request.bearer_auth("token\nwith a newline"),
+                false => request,
+            })
+            .build();
+        let code = downloader.fetch_all([format!("http://{}/b.txt", address), format!("http://{}/a.txt", address)]).unwrap();
+        assert_ne!(code, ExitCode::Success);
+        let statuses: Vec<Status> = downloader.report().entries().iter().map(|entry| entry.status).collect();
+        assert_eq!(statuses, [Status::Failed, Status::Downloaded]);
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "hello");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket() {
+        let dir = std::env::temp_dir().join(format!("rustdl-unix-socket-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("app.sock");
+        let listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+        let (sender, requested) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = [0u8; 1024];
+                let read = stream.read(&mut request).unwrap();
+                let _ = sender.send(String::from_utf8_lossy(&request[..read]).lines().next().unwrap_or_default().to_string());
+                stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello").unwrap();
+            }
+        });
+        let options = DownloadOptions {
+            unix_socket: Some(socket.clone()),
+            // Never used, as nothing goes anywhere but the socket
+            proxy: Some("http://127.0.0.1:9".to_string()),
+            naming: Arc::new(StandardNames::new(false, dir.join("out"), None, false)),
+            ..Default::default()
+        };
+        let downloader = DownloaderBuilder::from(options).build();
+        assert_eq!(downloader.fetch("/a.txt").unwrap(), ExitCode::Success);
+        assert_eq!(requested.recv().unwrap(), "GET /a.txt HTTP/1.1");
+        assert_eq!(fs::read_to_string(dir.join("out").join("a.txt")).unwrap(), "hello");
+        assert_eq!(downloader.report().entries()[0].url, "http://localhost/a.txt");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_same_file_twice() {
+        let address = serve();
+        let dir = std::env::temp_dir().join(format!("rustdl-same-file-{}", std::process::id()));
+        let options = DownloadOptions {
+            append: true,
+            naming: Arc::new(StandardNames::new(false, dir.clone(), None, false)),
+            progress: ProgressMode::Quiet,
+            ..Default::default()
+        };
+        let downloader = DownloaderBuilder::from(options).build();
+        let url = format!("http://{}/a.txt", address);
+        let code = downloader.fetch_all([url.clone(), format!("http://{}/again/a.txt", address), url]).unwrap();
+        assert_eq!(code, ExitCode::Success);
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "hello");
+        let skipped: Vec<String> = downloader.report().entries().iter().filter(|entry| entry.status == Status::Skipped).map(|entry| entry.reason.clone().unwrap_or_default()).collect();
+        assert_eq!(skipped, ["duplicate", "same file as an earlier URL"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_mirrors_with_several_urls() {
+        let options = DownloadOptions { mirrors: vec!["http://127.0.0.1:9/a.txt".to_string()], ..Default::default() };
+        let downloader = DownloaderBuilder::from(options).build();
+        let error = downloader.fetch_all(["http://127.0.0.1:9/a.txt", "http://127.0.0.1:9/b.txt"]).unwrap_err();
+        assert_eq!(error.to_string(), "mirrors can only be used with a single URL, not 2");
+        assert!(downloader.report().entries().is_empty());
+    }
+
+    #[test]
+    fn test_mirrored_file_that_exists() {
+        let dir = std::env::temp_dir().join(format!("rustdl-mirrored-exists-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.txt"), "hello").unwrap();
+        let options = DownloadOptions {
+            mirrors: vec!["http://127.0.0.1:9/a.txt".to_string()],
+            no_clobber: true,
+            naming: Arc::new(StandardNames::new(false, dir.clone(), None, false)),
+            progress: ProgressMode::Quiet,
+            ..Default::default()
+        };
+        let downloader = DownloaderBuilder::from(options).build();
+        assert_eq!(downloader.fetch("http://127.0.0.1:9/a.txt").unwrap(), ExitCode::Success);
+        let entries = downloader.report().entries();
+        assert_eq!((entries[0].status, entries[0].reason.as_deref()), (Status::Skipped, Some("exists")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_saved_pages_are_named_by_the_policy() {
+        struct Prefixed(PathBuf);
+
+        impl NamePolicy for Prefixed {
+            fn sanitize(&self, name: &str) -> Option<PathBuf> {
+                Some(PathBuf::from(name))
+            }
+
+            fn place(&self, _url: &Url, filename: &Path, _index: usize) -> Option<PathBuf> {
+                Some(self.0.join(format!("named-{}", filename.display())))
+            }
+
+            fn claim(&self, path: PathBuf) -> PathBuf {
+                path
+            }
+        }
+
+        let address = serve();
+        let dir = std::env::temp_dir().join(format!("rustdl-page-names-{}", std::process::id()));
+        let options = DownloadOptions { save_page: Some(PageFormat::Directory), progress: ProgressMode::Quiet, ..Default::default() };
+        let downloader = DownloaderBuilder::from(options).naming(Prefixed(dir.clone())).build();
+        assert_eq!(downloader.fetch(format!("http://{}/a.txt", address)).unwrap(), ExitCode::Success);
+        assert_eq!(fs::read_to_string(dir.join("named-a.txt")).unwrap(), "hello");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_content_decoding() {
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(b"hello").unwrap();
+        let body = gzip.finish().unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let _ = stream.read(&mut [0u8; 1024]);
+                let head = format!("HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+                stream.write_all(head.as_bytes()).and_then(|()| stream.write_all(&body)).unwrap();
+            }
+        });
+        let fetch = |content_decoding| {
+            let client = build_client(&ClientOptions { content_decoding, ..Default::default() }).unwrap();
+            client.get(format!("http://{}/a.tar.gz", address)).send().unwrap().bytes().unwrap().to_vec()
+        };
+        assert_eq!(fetch(ContentDecoding::Compressed), b"hello");
+        assert_eq!(&fetch(ContentDecoding::Raw)[..2], [0x1f, 0x8b]);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_zstd_and_brotli() {
+        let body = zstd::encode_all(&b"hello"[..], 0).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, accepted) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = [0u8; 1024];
+                let read = stream.read(&mut request).unwrap();
+                let request = String::from_utf8_lossy(&request[..read]).to_ascii_lowercase();
+                let _ = sender.send(request.lines().find_map(|line| line.strip_prefix("accept-encoding: ")).unwrap_or_default().to_string());
+                let head = format!("HTTP/1.1 200 OK\r\nContent-Encoding: zstd\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+                stream.write_all(head.as_bytes()).and_then(|()| stream.write_all(&body)).unwrap();
+            }
+        });
+        let dir = std::env::temp_dir().join(format!("rustdl-zstd-{}", std::process::id()));
+        let (sender, events) = mpsc::channel();
+        let downloader = Downloader::builder().output_dir(&dir).progress_channel(sender).build();
+        assert_eq!(downloader.fetch(format!("http://{}/a.json", address)).unwrap(), ExitCode::Success);
+        let accepted = accepted.recv().unwrap();
+        assert!(accepted.contains("br") && accepted.contains("zstd"), "{}", accepted);
+        assert_eq!(fs::read_to_string(dir.join("a.json")).unwrap(), "hello");
+        // Only the compressed length was sent, which isn't the size of the file
+        let events: Vec<ProgressEvent> = events.try_iter().collect();
+        let started = events.iter().find(|event| event.state == progress::DownloadState::Started).unwrap();
+        assert_eq!(started.total, None);
+        assert_eq!(events.last().map(|event| event.bytes), Some(5));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_transport() {
+        struct Hello;
+
+        impl Transport for Hello {
+            fn name(&self) -> &str {
+                "hello"
+            }
+
+            fn metadata(&self, _url: &Url) -> Result<crate::transport::Metadata, TransportError> {
+                Ok(crate::transport::Metadata { size: Some(5), modified: None })
+            }
+
+            fn open(&self, _url: &Url, _start: u64, _end: Option<u64>) -> Result<Box<dyn Read + Send>, TransportError> {
+                Ok(Box::new(&b"hello"[..]))
+            }
+        }
+
+        let dir = std::env::temp_dir().join(format!("rustdl-transport-{}", std::process::id()));
+        let downloader = Downloader::builder().output_dir(&dir).transport("hello", Hello).progress(ProgressMode::Quiet).build();
+        assert_eq!(downloader.fetch("hello://server/greetings/a.txt").unwrap(), ExitCode::Success);
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "hello");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_client() {
+        let address = serve();
+        let dir = std::env::temp_dir().join(format!("rustdl-async-{}", std::process::id()));
+        let client = Client::new(Downloader::builder().output_dir(&dir).progress(ProgressMode::Quiet).build());
+        let code = futures::executor::block_on(client.fetch(format!("http://{}/a.txt", address))).unwrap();
+        assert_eq!(code, ExitCode::Success);
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "hello");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,159 @@
+use std::path::{Path, PathBuf};
+
+use reqwest::blocking::Client;
+use reqwest::header::{self, HeaderName, HeaderValue};
+use url::Url;
+
+use crate::credentials::{Credential, CredentialStore};
+use crate::encrypt::Recipient;
+use crate::middleware::Middleware;
+use crate::naming::NamePolicy;
+use crate::range::ByteRange;
+
+/// Works out what downloading each URL would do, without sending anything
+/// (`--dry-run`): where it'd be saved and the headers it'd be asked for with
+pub struct DryRun<'a> {
+    pub client: &'a Client,
+    pub middleware: &'a Middleware,
+    pub credentials: Option<&'a CredentialStore>,
+    /// `--auth-cmd` isn't run, since it could do anything, but we say it would be
+    pub auth_hook: bool,
+    pub naming: &'a dyn NamePolicy,
+    pub output: Option<&'a Path>,
+    pub range: Option<&'a ByteRange>,
+    pub encrypt_to: Option<&'a Recipient>,
+    pub stdout: bool,
+}
+
+impl DryRun<'_> {
+    /// Describe every URL, and return how many couldn't be downloaded as they are
+    pub fn run(&self, urls: &[String]) -> usize {
+        let mut failed = 0;
+        for (index, url) in urls.iter().enumerate() {
+            match self.describe(url, index) {
+                Ok(description) => print!("{}", description),
+                Err(e) => {
+                    eprintln!("[error] {}", e);
+                    failed += 1;
+                }
+            }
+        }
+        failed
+    }
+
+    /// What downloading `url` (the `index`th URL) would do, a line for where
+    /// it goes and one for each header
+    pub fn describe(&self, url: &str, index: usize) -> Result<String, String> {
+        let parsed_url = Url::parse(url).map_err(|e| format!("{}: {}", url, e))?;
+        let mut description = format!("{}: {}\n", url, self.destination(&parsed_url, index)?);
+        let mut request = self.middleware.get(self.client, url);
+        let mut oauth = None;
+        // As CredentialStore::authorize would, without refreshing OAuth tokens
+        let stored = match self.credentials {
+            Some(store) if parsed_url.username().is_empty() => store.lookup(&parsed_url),
+            _ => Ok(None),
+        };
+        match stored {
+            Ok(Some(Credential::OAuth { provider, .. })) => oauth = Some(provider),
+            Ok(Some(credential)) => request = credential.apply(request),
+            Ok(None) => {}
+            Err(e) => description.push_str(&format!("  (stored credentials can't be read: {})\n", e)),
+        }
+        let request = request.build().map_err(|e| format!("{}: {}", url, e))?;
+        for (name, value) in request.headers() {
+            description.push_str(&format!("  {}: {}\n", name, redact(name, value)));
+        }
+        if let Some(provider) = oauth {
+            description.push_str(&format!("  authorization: Bearer <{} login>\n", provider));
+        }
+        if self.auth_hook {
+            description.push_str("  (and whatever headers --auth-cmd prints)\n");
+        }
+        Ok(description)
+    }
+
+    /// Where the download would end up, named the way it would be if the
+    /// server doesn't suggest a name of its own
+    fn destination(&self, url: &Url, index: usize) -> Result<String, String> {
+        if self.stdout {
+            return Ok("would be written to stdout".to_string());
+        }
+        let path = match self.output {
+            Some(output) => output.to_path_buf(),
+            None => {
+                let Some(filename) = self.naming.url_name(url) else {
+                    return Ok("would be named by the server, as there's no filename in the URL".to_string());
+                };
+                let path = self.naming.place(url, &filename, index).ok_or_else(|| format!("{}: nowhere to save {}", url, filename.display()))?;
+                self.naming.claim(self.renamed(path))
+            }
+        };
+        Ok(format!("would be saved as {}", path.display()))
+    }
+
+    /// `path` as `--range` and `--encrypt-to` change it
+    fn renamed(&self, path: PathBuf) -> PathBuf {
+        let path = match self.range {
+            Some(range) => range.output_path(&path),
+            None => path,
+        };
+        match self.encrypt_to {
+            Some(recipient) => recipient.output_path(&path),
+            None => path,
+        }
+    }
+}
+
+/// A header's value as it's safe to show: credentials hidden, and only the
+/// names of cookies, so cookie matching can still be checked
+fn redact(name: &HeaderName, value: &HeaderValue) -> String {
+    let value = String::from_utf8_lossy(value.as_bytes());
+    match *name {
+        header::AUTHORIZATION | header::PROXY_AUTHORIZATION => match value.split_once(' ') {
+            Some((scheme, _)) => format!("{} <redacted>", scheme),
+            None => "<redacted>".to_string(),
+        },
+        header::COOKIE => value
+            .split(';')
+            .map(|cookie| format!("{}=<redacted>", cookie.split_once('=').map_or(cookie, |(name, _)| name).trim()))
+            .collect::<Vec<_>>()
+            .join("; "),
+        _ => value.into_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::naming::StandardNames;
+
+    #[test]
+    fn test_describe() {
+        let client = Client::new();
+        let middleware = Middleware::default()
+            .with_request(|request: reqwest::blocking::RequestBuilder, _: &crate::middleware::DownloadContext| {
+                request.header(header::COOKIE, "session=abc123; theme=dark").basic_auth("alice", Some("secret"))
+            });
+        let naming = StandardNames::new(false, PathBuf::from("downloads"), None, false);
+        let dry_run = DryRun {
+            client: &client,
+            middleware: &middleware,
+            credentials: None,
+            auth_hook: false,
+            naming: &naming,
+            output: None,
+            range: None,
+            encrypt_to: None,
+            stdout: false,
+        };
+        assert_eq!(
+            dry_run.describe("https://example.com/os.iso", 0).unwrap(),
+            format!(
+                "https://example.com/os.iso: would be saved as {}\n  cookie: session=<redacted>; theme=<redacted>\n  authorization: Basic <redacted>\n",
+                Path::new("downloads").join("os.iso").display()
+            )
+        );
+        assert!(dry_run.describe("https://example.com/", 1).unwrap().starts_with("https://example.com/: would be named by the server"));
+        assert!(dry_run.describe("not a url", 2).is_err());
+    }
+}
@@ -0,0 +1,222 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, ExitStatus, Stdio};
+use std::str::FromStr;
+use std::thread::{self, JoinHandle};
+
+use log::debug;
+
+use crate::checksum::{Checksum, HashAlgorithm, HashingWriter};
+
+/// Errors from encrypting a download
+#[derive(Debug, thiserror::Error)]
+pub enum EncryptError {
+    #[error("could not run {program}: {source} (is it installed?)")]
+    Spawn { program: &'static str, source: io::Error },
+
+    #[error("could not encrypt to {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("{program} failed ({status}): {message}")]
+    Failed { program: &'static str, status: ExitStatus, message: String },
+}
+
+/// Who a download is encrypted for: an age recipient (`age1...`, or an SSH
+/// public key), or anything else GPG can find a key for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Recipient {
+    Age(String),
+    Gpg(String),
+}
+
+impl Recipient {
+    /// The program that does the encrypting
+    pub fn program(&self) -> &'static str {
+        match self {
+            Recipient::Age(_) => "age",
+            Recipient::Gpg(_) => "gpg",
+        }
+    }
+
+    /// Where to save an encrypted copy of `path`: `file.iso` becomes `file.iso.age`
+    pub fn output_path(&self, path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_os_string();
+        name.push(match self {
+            Recipient::Age(_) => ".age",
+            Recipient::Gpg(_) => ".gpg",
+        });
+        PathBuf::from(name)
+    }
+
+    fn command(&self, output: &Path) -> Command {
+        let mut command = Command::new(self.program());
+        match self {
+            Recipient::Age(recipient) => command.args(["--encrypt", "--recipient", recipient]),
+            Recipient::Gpg(recipient) => command.args(["--batch", "--yes", "--encrypt", "--recipient", recipient]),
+        };
+        command.arg("--output").arg(output);
+        command
+    }
+}
+
+impl FromStr for Recipient {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err("the recipient can't be empty".to_string());
+        }
+        if s.starts_with("age1") || s.starts_with("ssh-ed25519 ") || s.starts_with("ssh-rsa ") {
+            Ok(Recipient::Age(s.to_string()))
+        } else {
+            Ok(Recipient::Gpg(s.to_string()))
+        }
+    }
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Recipient::Age(recipient) | Recipient::Gpg(recipient) => write!(f, "{}", recipient),
+        }
+    }
+}
+
+/// Encrypt everything from `body` into `path` for `recipient`, so the
+/// plaintext never reaches the disk. The ciphertext is written next to
+/// `path` and only moved into place once the encryption has succeeded.
+/// Returns the plaintext's checksum for each of `algorithms`.
+pub fn write_encrypted(
+    path: &Path,
+    recipient: &Recipient,
+    body: &mut impl Read,
+    algorithms: &[HashAlgorithm],
+) -> Result<Vec<Checksum>, EncryptError> {
+    let mut encrypted = EncryptingWriter::create(path, recipient)?;
+    let copied = match algorithms.is_empty() {
+        false => {
+            let mut writer = HashingWriter::with_algorithms(&mut encrypted, algorithms);
+            io::copy(body, &mut writer).map(|_| writer.finish_all().1)
+        }
+        true => io::copy(body, &mut encrypted).map(|_| Vec::new()),
+    };
+    match copied {
+        Ok(checksums) => encrypted.finish().map(|()| checksums),
+        Err(source) => Err(encrypted.abandon(source)),
+    }
+}
+
+/// Encrypts what's written to it into a file, for downloads that are
+/// written out rather than read in. Like [`write_encrypted`], the ciphertext
+/// only replaces `path` once [`finish`](EncryptingWriter::finish) has
+/// succeeded; dropped before then, it's thrown away.
+pub struct EncryptingWriter {
+    path: PathBuf,
+    partial: PathBuf,
+    program: &'static str,
+    child: Child,
+    stdin: Option<ChildStdin>,
+    complaints: Option<JoinHandle<String>>,
+}
+
+impl EncryptingWriter {
+    /// Start encrypting for `recipient` into `path`
+    pub fn create(path: &Path, recipient: &Recipient) -> Result<Self, EncryptError> {
+        let mut partial = path.as_os_str().to_os_string();
+        partial.push(".part");
+        let partial = PathBuf::from(partial);
+        debug!("Encrypting {} for {} with {}", path.display(), recipient, recipient.program());
+
+        let program = recipient.program();
+        let mut child = recipient
+            .command(&partial)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|source| EncryptError::Spawn { program, source })?;
+
+        // Read its complaints as they come, so it can't block on a full pipe
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+        let complaints = thread::spawn(move || {
+            let mut message = String::new();
+            let _ = stderr.read_to_string(&mut message);
+            message
+        });
+        let stdin = child.stdin.take();
+        Ok(EncryptingWriter { path: path.to_path_buf(), partial, program, child, stdin, complaints: Some(complaints) })
+    }
+
+    /// Finish encrypting, and move the ciphertext into place
+    pub fn finish(mut self) -> Result<(), EncryptError> {
+        self.wait()?;
+        fs::rename(&self.partial, &self.path).map_err(|source| EncryptError::Io { path: self.path.clone(), source })
+    }
+
+    /// Give up after writing failed with `source`; if the program failed
+    /// first, which is often why writing to it did, that's what's returned
+    pub fn abandon(mut self, source: io::Error) -> EncryptError {
+        match self.wait() {
+            Ok(()) => EncryptError::Io { path: self.partial.clone(), source },
+            Err(e) => e,
+        }
+    }
+
+    /// Close the stream, so the program finishes up, and wait for it
+    fn wait(&mut self) -> Result<(), EncryptError> {
+        drop(self.stdin.take());
+        let status = self.child.wait().map_err(|source| EncryptError::Io { path: self.partial.clone(), source })?;
+        let message = self.complaints.take().and_then(|complaints| complaints.join().ok()).unwrap_or_default();
+        if !status.success() {
+            return Err(EncryptError::Failed { program: self.program, status, message: message.trim().to_string() });
+        }
+        Ok(())
+    }
+
+    fn stdin(&mut self) -> io::Result<&mut ChildStdin> {
+        self.stdin.as_mut().ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+impl Write for EncryptingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdin()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdin()?.flush()
+    }
+}
+
+impl Drop for EncryptingWriter {
+    fn drop(&mut self) {
+        if self.complaints.is_some() {
+            drop(self.stdin.take());
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+        // Only there if it was never moved into place
+        let _ = fs::remove_file(&self.partial);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recipients() {
+        let age: Recipient = "age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p".parse().unwrap();
+        assert_eq!(age.program(), "age");
+        assert_eq!(age.output_path(Path::new("dir/secret.tar")), Path::new("dir/secret.tar.age"));
+        assert_eq!("ssh-ed25519 AAAAC3Nza... me@host".parse::<Recipient>().unwrap().program(), "age");
+
+        let gpg: Recipient = "ops@example.com".parse().unwrap();
+        assert_eq!(gpg, Recipient::Gpg("ops@example.com".to_string()));
+        assert_eq!(gpg.output_path(Path::new("secret.tar")), Path::new("secret.tar.gpg"));
+        assert!("  ".parse::<Recipient>().is_err());
+    }
+}
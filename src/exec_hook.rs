@@ -0,0 +1,118 @@
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
+use std::str::FromStr;
+
+use log::{debug, info};
+
+use crate::auth_hook::{shell, substitute};
+use crate::report::Entry;
+
+/// Errors from running a command for a file
+#[derive(Debug, thiserror::Error)]
+pub enum ExecHookError {
+    #[error("could not run '{command}': {source}")]
+    Spawn { command: String, source: std::io::Error },
+
+    #[error("'{command}' failed ({status}){}", if message.is_empty() { String::new() } else { format!(": {}", message) })]
+    Failed { command: String, status: ExitStatus, message: String },
+}
+
+/// What a command is told about the file it's run for
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Download<'a> {
+    pub url: &'a str,
+    pub file: Option<&'a Path>,
+    /// downloaded, skipped, failed or checked; empty before the download
+    pub status: &'a str,
+    pub checksum: Option<&'a str>,
+}
+
+impl<'a> From<&'a Entry> for Download<'a> {
+    fn from(entry: &'a Entry) -> Self {
+        // SHA-256 if we have it, as the report's CSV does, or else whatever we have
+        let checksum = entry.checksums.get("sha256").or_else(|| entry.checksums.values().next());
+        Download { url: &entry.url, file: entry.file.as_deref(), status: entry.status.as_str(), checksum: checksum.map(String::as_str) }
+    }
+}
+
+/// A command run by the shell for each file, like `tar xf {}`, before it's
+/// downloaded or once it's done. `{}` and `{path}` are replaced by the
+/// (quoted) file, and `{url}`, `{status}` and `{checksum}` by the rest of
+/// what we know; they're also in `$DOWNLOAD_FILE`, `$DOWNLOAD_URL`,
+/// `$DOWNLOAD_STATUS` and `$DOWNLOAD_CHECKSUM`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecHook {
+    command: String,
+}
+
+impl ExecHook {
+    /// Run the command for `download`, waiting for it to finish
+    pub fn run(&self, download: &Download) -> Result<(), ExecHookError> {
+        let file = download.file.map(|file| file.display().to_string()).unwrap_or_default();
+        let checksum = download.checksum.unwrap_or_default();
+        let command = substitute(
+            &self.command,
+            &[("{}", &file), ("{path}", &file), ("{url}", download.url), ("{status}", download.status), ("{checksum}", checksum)],
+        );
+        debug!("Running: {}", command);
+        let output = shell(&command)
+            .env("DOWNLOAD_FILE", &file)
+            .env("DOWNLOAD_URL", download.url)
+            .env("DOWNLOAD_STATUS", download.status)
+            .env("DOWNLOAD_CHECKSUM", checksum)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|source| ExecHookError::Spawn { command: self.command.clone(), source })?;
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            info!("{}: {}", download.url, line);
+        }
+        if !output.status.success() {
+            return Err(ExecHookError::Failed {
+                command: self.command.clone(),
+                status: output.status,
+                message: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ExecHook {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "" => Err("the command can't be empty".to_string()),
+            command => Ok(ExecHook { command: command.to_string() }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth_hook::quote;
+    use crate::report::Status;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run() {
+        let dir = std::env::temp_dir().join(format!("rustdl-exec-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let out = dir.join("out");
+        let hook: ExecHook = format!("echo {{status}} {{}} {{url}} $DOWNLOAD_CHECKSUM > {}", out.display()).parse().unwrap();
+        let mut entry = Entry::new("https://example.com/it's.tar", Status::Downloaded, Some(Path::new("it's.tar")));
+        entry.checksums.insert("sha256".to_string(), "abc123".to_string());
+        hook.run(&Download::from(&entry)).unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "downloaded it's.tar https://example.com/it's.tar abc123\n");
+
+        // A placeholder inside a value is left as it is, quotes and all
+        let url = "https://example.com/{checksum}'{url}";
+        assert_eq!(substitute("echo {url} {checksum}", &[("{url}", url), ("{checksum}", "x;y")]), format!("echo {} {}", quote(url), quote("x;y")));
+
+        let failing: ExecHook = "echo nope >&2; exit 3".parse().unwrap();
+        assert!(matches!(failing.run(&Download::default()), Err(ExecHookError::Failed { message, .. }) if message == "nope"));
+        assert!(" ".parse::<ExecHook>().is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
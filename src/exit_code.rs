@@ -0,0 +1,147 @@
+use std::fmt;
+use std::process;
+
+/// What `download` exits with, so scripts can tell what kind of thing went wrong
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    Success,
+    /// Anything without a status of its own, or a batch that failed in more than one way
+    Error,
+    /// Options that can't be used, or can't be used together
+    Usage,
+    /// Couldn't connect, look the host up, or get a response in time
+    Network,
+    /// The server said no (4xx)
+    ClientError,
+    /// The server broke (5xx)
+    ServerError,
+    /// The download didn't match its checksum
+    Checksum,
+    /// Browser cookies couldn't be used
+    Cookies,
+    /// Some URLs were downloaded and some failed (unless `--strict`)
+    Partial,
+}
+
+impl ExitCode {
+    /// The number the process exits with
+    pub fn code(self) -> i32 {
+        match self {
+            ExitCode::Success => 0,
+            ExitCode::Error => 1,
+            ExitCode::Usage => 2,
+            ExitCode::Network => 3,
+            ExitCode::ClientError => 4,
+            ExitCode::ServerError => 5,
+            ExitCode::Checksum => 6,
+            ExitCode::Cookies => 7,
+            ExitCode::Partial => 8,
+        }
+    }
+
+    /// The class of failure an HTTP status is, if it's an error
+    pub fn for_http(status: u16) -> Option<ExitCode> {
+        match status {
+            400..=499 => Some(ExitCode::ClientError),
+            500..=599 => Some(ExitCode::ServerError),
+            _ => None,
+        }
+    }
+
+    /// What a batch exits with, given how each failed URL failed and how many
+    /// others were fine. A batch that failed in one way exits with that
+    /// failure's code; one that partly worked exits with `Partial`, unless
+    /// `strict` says any failure counts.
+    pub fn for_batch(failures: &[ExitCode], succeeded: usize, strict: bool) -> ExitCode {
+        let Some(&first) = failures.first() else {
+            return ExitCode::Success;
+        };
+        if succeeded > 0 && !strict {
+            ExitCode::Partial
+        } else if failures.iter().all(|&code| code == first) {
+            first
+        } else {
+            ExitCode::Error
+        }
+    }
+
+    /// Stop the process with this code
+    pub fn exit(self) -> ! {
+        process::exit(self.code())
+    }
+}
+
+impl fmt::Display for ExitCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
+
+/// Why a URL couldn't be downloaded: the message for the user, and the kind of
+/// failure it was for the exit code
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    pub code: ExitCode,
+    pub message: String,
+}
+
+impl Failure {
+    pub fn new(code: ExitCode, message: impl Into<String>) -> Self {
+        Failure { code, message: message.into() }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl From<String> for Failure {
+    fn from(message: String) -> Self {
+        Failure::new(ExitCode::Error, message)
+    }
+}
+
+impl From<&String> for Failure {
+    fn from(message: &String) -> Self {
+        Failure::new(ExitCode::Error, message.as_str())
+    }
+}
+
+impl From<&str> for Failure {
+    fn from(message: &str) -> Self {
+        Failure::new(ExitCode::Error, message)
+    }
+}
+
+impl From<&Failure> for Failure {
+    fn from(failure: &Failure) -> Self {
+        failure.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes_are_distinct() {
+        use ExitCode::*;
+        let codes = [Success, Error, Usage, Network, ClientError, ServerError, Checksum, Cookies, Partial].map(ExitCode::code);
+        assert_eq!(codes, [0, 1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(ExitCode::for_http(404), Some(ExitCode::ClientError));
+        assert_eq!(ExitCode::for_http(503), Some(ExitCode::ServerError));
+        assert_eq!(ExitCode::for_http(304), None);
+    }
+
+    #[test]
+    fn test_batch_exit_code() {
+        use ExitCode::*;
+        assert_eq!(ExitCode::for_batch(&[], 3, false), Success);
+        assert_eq!(ExitCode::for_batch(&[ClientError, ClientError], 0, false), ClientError);
+        assert_eq!(ExitCode::for_batch(&[ClientError, Network], 0, false), Error);
+        assert_eq!(ExitCode::for_batch(&[Checksum], 2, false), Partial);
+        assert_eq!(ExitCode::for_batch(&[Checksum], 2, true), Checksum);
+    }
+}
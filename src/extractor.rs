@@ -0,0 +1,223 @@
+// With no extractors compiled in, nothing uses the parts they build on
+#![cfg_attr(not(feature = "extractor-sourceforge"), allow(dead_code))]
+
+use std::io::Read;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, info};
+use reqwest::blocking::Client;
+use url::Url;
+
+use crate::credentials::CredentialStore;
+use crate::middleware::{DownloadContext, Middleware};
+use crate::oauth::OAuthSettings;
+use crate::timeouts;
+
+/// The most of a page we'll read looking for the file it leads to
+const MAX_PAGE_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Errors from finding the file behind a page
+#[derive(Debug, thiserror::Error)]
+pub enum ExtractError {
+    #[error("{url}: {message}")]
+    Fetch { url: String, message: String },
+
+    #[error("{url}: the {extractor} extractor couldn't find the file on the page")]
+    NotFound { extractor: String, url: String },
+}
+
+/// Knows how to get from a site's page about a file to the file itself, for
+/// sites that put an HTML page in the way of their downloads
+pub trait Extractor: Send + Sync {
+    /// What it's called, for logs and errors
+    fn name(&self) -> &str;
+
+    /// Whether `url` is one of the pages it knows about
+    fn matches(&self, url: &Url) -> bool;
+
+    /// The URL of the file `url` is a page about, using `fetcher` to fetch
+    /// whatever pages it needs to
+    fn extract(&self, url: &Url, fetcher: &Fetcher) -> Result<Url, ExtractError>;
+}
+
+/// Fetches pages for extractors the way a download would, cookies,
+/// credentials and all
+pub struct Fetcher<'a> {
+    pub client: &'a Client,
+    pub middleware: &'a Middleware,
+    pub credentials: Option<&'a CredentialStore>,
+    pub oauth: &'a OAuthSettings,
+    pub header_timeout: Option<Duration>,
+}
+
+impl Fetcher<'_> {
+    /// Fetch `url` as text, returning where it ended up after any redirects
+    pub fn text(&self, url: &Url) -> Result<(Url, String), ExtractError> {
+        let fetch_error = |message: String| ExtractError::Fetch { url: url.to_string(), message };
+        let mut request = self.middleware.get(self.client, url.as_str());
+        if let Some(store) = self.credentials {
+            request = store.authorize(request, url, self.client, self.oauth);
+        }
+        let request = request.build().map_err(|e| fetch_error(e.to_string()))?;
+        let response = timeouts::execute(self.client, request, self.header_timeout).map_err(|e| fetch_error(e.to_string()))?;
+        self.middleware.intercept(&response, &DownloadContext { method: &reqwest::Method::GET, url: url.as_str() }).map_err(fetch_error)?;
+        if !response.status().is_success() {
+            return Err(fetch_error(format!("server returned {}", response.status())));
+        }
+        let final_url = response.url().clone();
+        let mut body = Vec::new();
+        response.take(MAX_PAGE_SIZE).read_to_end(&mut body).map_err(|e| fetch_error(e.to_string()))?;
+        Ok((final_url, String::from_utf8_lossy(&body).into_owned()))
+    }
+}
+
+/// The extractors to try on each URL before it's downloaded, in the order
+/// they were added; the first that matches a URL is the one used
+#[derive(Clone, Default)]
+pub struct Registry {
+    extractors: Vec<Arc<dyn Extractor>>,
+}
+
+impl Registry {
+    /// The extractors compiled in with cargo features
+    pub fn builtin() -> Self {
+        #[allow(unused_mut)]
+        let mut registry = Registry::default();
+        #[cfg(feature = "extractor-sourceforge")]
+        {
+            registry = registry.with(SourceForge);
+        }
+        registry
+    }
+
+    /// Try `extractor` on URLs too, after the ones already added
+    pub fn with(mut self, extractor: impl Extractor + 'static) -> Self {
+        self.extractors.push(Arc::new(extractor));
+        self
+    }
+
+    /// The names of the extractors, in order
+    pub fn names(&self) -> Vec<&str> {
+        self.extractors.iter().map(|extractor| extractor.name()).collect()
+    }
+
+    /// The URL to download for `url`: the file behind it if an extractor
+    /// knows the site, or `url` itself if none do
+    pub fn resolve(&self, url: &str, fetcher: &Fetcher) -> Result<String, ExtractError> {
+        let Ok(parsed_url) = Url::parse(url) else { return Ok(url.to_string()) };
+        let Some(extractor) = self.extractors.iter().find(|extractor| extractor.matches(&parsed_url)) else {
+            return Ok(url.to_string());
+        };
+        debug!("Extracting {} with the {} extractor", url, extractor.name());
+        let file = extractor.extract(&parsed_url, fetcher)?;
+        info!("{}: the file is {}", url, file);
+        Ok(file.into())
+    }
+}
+
+impl std::fmt::Debug for Registry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.names()).finish()
+    }
+}
+
+/// SourceForge's `.../files/.../download` links lead to a page that starts
+/// the download with a meta refresh to one of its mirrors
+#[cfg(feature = "extractor-sourceforge")]
+pub struct SourceForge;
+
+#[cfg(feature = "extractor-sourceforge")]
+impl SourceForge {
+    /// The mirror URL a download page refreshes to
+    fn mirror_url(html: &str, page_url: &Url) -> Option<Url> {
+        static REFRESH: std::sync::LazyLock<regex::Regex> = std::sync::LazyLock::new(|| {
+            regex::Regex::new(r#"(?is)<meta\b[^>]*http-equiv\s*=\s*["']?refresh["']?[^>]*content\s*=\s*["'][^"']*?url\s*=\s*([^"']+)["']"#).unwrap()
+        });
+        let link = REFRESH.captures(html)?.get(1)?.as_str().trim().replace("&amp;", "&");
+        page_url.join(&link).ok()
+    }
+}
+
+#[cfg(feature = "extractor-sourceforge")]
+impl Extractor for SourceForge {
+    fn name(&self) -> &str {
+        "sourceforge"
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        url.host_str().is_some_and(|host| host == "sourceforge.net" || host.ends_with(".sourceforge.net"))
+            && url.path().contains("/files/")
+            && url.path().trim_end_matches('/').ends_with("/download")
+    }
+
+    fn extract(&self, url: &Url, fetcher: &Fetcher) -> Result<Url, ExtractError> {
+        let (page_url, html) = fetcher.text(url)?;
+        Self::mirror_url(&html, &page_url).ok_or_else(|| ExtractError::NotFound { extractor: self.name().to_string(), url: url.to_string() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Finds the link on pages under /get/ whose text is "Download"
+    struct DownloadLink;
+
+    impl Extractor for DownloadLink {
+        fn name(&self) -> &str {
+            "download-link"
+        }
+
+        fn matches(&self, url: &Url) -> bool {
+            url.path().starts_with("/get/")
+        }
+
+        fn extract(&self, url: &Url, fetcher: &Fetcher) -> Result<Url, ExtractError> {
+            let (page_url, html) = fetcher.text(url)?;
+            let href = html.split("href=\"").nth(1).and_then(|rest| rest.split('"').next());
+            href.and_then(|href| page_url.join(href).ok())
+                .ok_or_else(|| ExtractError::NotFound { extractor: self.name().to_string(), url: url.to_string() })
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let _ = std::io::Read::read(&mut stream, &mut [0u8; 1024]);
+                let body = r#"<p>Your download will start shortly. <a href="/files/report.pdf?token=abc">Download</a></p>"#;
+                let reply = format!("HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+        });
+
+        let client = Client::builder().pool_max_idle_per_host(0).build().unwrap();
+        let fetcher = Fetcher { client: &client, middleware: &Middleware::default(), credentials: None, oauth: &OAuthSettings::default(), header_timeout: None };
+        let registry = Registry::default().with(DownloadLink);
+        assert_eq!(registry.names(), ["download-link"]);
+        let page = format!("http://{}/get/report", address);
+        assert_eq!(registry.resolve(&page, &fetcher).unwrap(), format!("http://{}/files/report.pdf?token=abc", address));
+        let direct = format!("http://{}/files/other.pdf", address);
+        assert_eq!(registry.resolve(&direct, &fetcher).unwrap(), direct);
+    }
+
+    #[cfg(feature = "extractor-sourceforge")]
+    #[test]
+    fn test_sourceforge() {
+        let page = Url::parse("https://sourceforge.net/projects/tool/files/tool-1.0.tar.gz/download").unwrap();
+        assert!(SourceForge.matches(&page));
+        assert!(!SourceForge.matches(&Url::parse("https://sourceforge.net/projects/tool/").unwrap()));
+        let html = r#"<meta http-equiv="refresh" content="5; url=https://downloads.sourceforge.net/project/tool/tool-1.0.tar.gz?ts=1&amp;use_mirror=kent">"#;
+        assert_eq!(
+            SourceForge::mirror_url(html, &page).unwrap().as_str(),
+            "https://downloads.sourceforge.net/project/tool/tool-1.0.tar.gz?ts=1&use_mirror=kent"
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::sync::LazyLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::DateTime;
+use log::{debug, info};
+use regex::Regex;
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::credentials::CredentialStore;
+use crate::middleware::{DownloadContext, Middleware};
+use crate::oauth::OAuthSettings;
+use crate::report::{Entry, Status};
+use crate::timeouts;
+
+/// Name of the file inside the XDG data directory remembering what feeds gave us
+const HISTORY_FILE: &str = "feeds.jsonl";
+
+/// The most of a feed we'll read
+const MAX_FEED_SIZE: u64 = 16 * 1024 * 1024;
+
+static ITEM: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?is)<(item|entry)\b[^>]*>(.*?)</(?:item|entry)\s*>").unwrap());
+static ENCLOSURE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?is)<enclosure\b([^>]*)>").unwrap());
+/// Atom's `<link href="..."/>`, or RSS's `<link>...</link>`
+static LINK: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?is)<link\b([^>]*?)/?>(?:((?:<!\[CDATA\[.*?\]\]>|[^<])*)</link\s*>)?").unwrap());
+static ATTRIBUTE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"(?is)\b([a-z]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap());
+static DATE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?is)<(pubDate|published|updated|dc:date)\b[^>]*>\s*(?:<!\[CDATA\[)?(.*?)(?:\]\]>)?\s*</").unwrap());
+
+/// Errors from reading a feed
+#[derive(Debug, thiserror::Error)]
+pub enum FeedError {
+    #[error("{url}: {message}")]
+    Fetch { url: String, message: String },
+
+    #[error("{0}: not an RSS or Atom feed")]
+    NotAFeed(String),
+
+    #[error("could not read feed history {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("could not determine where to store the feed history")]
+    NoDataDir,
+}
+
+/// Something a feed links to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Item {
+    pub url: String,
+    /// When the feed says it was published, if it says
+    pub published: Option<SystemTime>,
+}
+
+/// The items of an RSS or Atom feed: each one's enclosures, or its link
+/// if it has none; relative links are relative to `feed_url`
+pub fn items(xml: &str, feed_url: &Url) -> Result<Vec<Item>, FeedError> {
+    if !xml.contains("<rss") && !xml.contains("<feed") && !xml.contains("<rdf:RDF") {
+        return Err(FeedError::NotAFeed(feed_url.to_string()));
+    }
+    let mut items = Vec::new();
+    for item in ITEM.captures_iter(xml) {
+        let body = item.get(2).unwrap().as_str();
+        let published = DATE
+            .captures(body)
+            .and_then(|date| DateTime::parse_from_rfc2822(date[2].trim()).or_else(|_| DateTime::parse_from_rfc3339(date[2].trim())).ok())
+            .map(SystemTime::from);
+
+        let mut enclosures: Vec<String> = ENCLOSURE.captures_iter(body).filter_map(|enclosure| attribute(&enclosure[1], "url")).collect();
+        let mut link = None;
+        for captures in LINK.captures_iter(body) {
+            match attribute(&captures[1], "href") {
+                // Atom
+                Some(href) => match attribute(&captures[1], "rel").as_deref() {
+                    Some("enclosure") => enclosures.push(href),
+                    None | Some("alternate") => link = link.or(Some(href)),
+                    _ => {}
+                },
+                // RSS
+                None => link = link.or(captures.get(2).map(|text| unescape(text.as_str().trim()))),
+            }
+        }
+        let urls = if enclosures.is_empty() { link.into_iter().collect() } else { enclosures };
+        items.extend(
+            urls.iter()
+                .filter_map(|url| feed_url.join(url.trim()).ok())
+                .map(|url| Item { url: url.to_string(), published }),
+        );
+    }
+    Ok(items)
+}
+
+fn attribute(attributes: &str, wanted: &str) -> Option<String> {
+    ATTRIBUTE
+        .captures_iter(attributes)
+        .find(|captures| captures[1].eq_ignore_ascii_case(wanted))
+        .and_then(|captures| captures.get(2).or(captures.get(3)))
+        .map(|value| unescape(value.as_str()))
+}
+
+/// Undo XML's escaping of text and attributes
+fn unescape(text: &str) -> String {
+    let text = text.strip_prefix("<![CDATA[").and_then(|text| text.strip_suffix("]]>")).unwrap_or(text);
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&")
+}
+
+/// What's been downloaded from feeds before, one JSON object a line, so
+/// running `--feed` on a schedule only fetches what's new
+#[derive(Debug, Clone)]
+pub struct FeedHistory {
+    path: PathBuf,
+}
+
+/// One downloaded item, as remembered between runs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Record {
+    /// When it was downloaded, in seconds since the Unix epoch
+    at: u64,
+    feed: String,
+    url: String,
+}
+
+impl FeedHistory {
+    /// Default location of the history
+    pub fn default_path() -> Result<PathBuf, FeedError> {
+        xdg::BaseDirectories::with_prefix("rustdl")
+            .get_data_home()
+            .map(|dir| dir.join(HISTORY_FILE))
+            .ok_or(FeedError::NoDataDir)
+    }
+
+    pub fn new(path: PathBuf) -> Self {
+        FeedHistory { path }
+    }
+
+    /// Every URL downloaded from a feed so far
+    pub fn downloaded(&self) -> Result<HashSet<String>, FeedError> {
+        let file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+            Err(source) => return Err(FeedError::Io { path: self.path.clone(), source }),
+        };
+        let mut urls = HashSet::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|source| FeedError::Io { path: self.path.clone(), source })?;
+            match serde_json::from_str::<Record>(&line) {
+                Ok(record) => {
+                    urls.insert(record.url);
+                }
+                Err(e) => debug!("Skipping unreadable feed history line: {}", e),
+            }
+        }
+        Ok(urls)
+    }
+
+    /// Remember the items from `found` that were downloaded (or were already there)
+    pub fn record(&self, found: &[(String, Item)], entries: &[Entry]) -> io::Result<()> {
+        let done: HashSet<&str> = entries
+            .iter()
+            .filter(|entry| matches!(entry.status, Status::Downloaded | Status::Skipped))
+            .map(|entry| entry.url.as_str())
+            .collect();
+        let at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut lines = String::new();
+        for (feed, item) in found.iter().filter(|(_, item)| done.contains(item.url.as_str())) {
+            let record = Record { at, feed: feed.clone(), url: item.url.clone() };
+            lines.push_str(&serde_json::to_string(&record).map_err(io::Error::other)?);
+            lines.push('\n');
+        }
+        if lines.is_empty() {
+            return Ok(());
+        }
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        OpenOptions::new().create(true).append(true).open(&self.path)?.write_all(lines.as_bytes())
+    }
+}
+
+/// Fetches feeds and works out which of their items to download (`--feed`)
+pub struct FeedReader<'a> {
+    pub client: &'a Client,
+    pub middleware: &'a Middleware,
+    pub credentials: Option<&'a CredentialStore>,
+    pub oauth: &'a OAuthSettings,
+    pub header_timeout: Option<Duration>,
+    /// Only items published more recently than this (`--newer-than`)
+    pub newer_than: Option<Duration>,
+    /// Items downloaded before, to leave out
+    pub downloaded: &'a HashSet<String>,
+}
+
+impl FeedReader<'_> {
+    /// The items of `feed` that haven't been downloaded yet and are new
+    /// enough, in the feed's order
+    pub fn new_items(&self, feed: &str) -> Result<Vec<Item>, FeedError> {
+        let fetch_error = |message: String| FeedError::Fetch { url: feed.to_string(), message };
+        let feed_url = Url::parse(feed).map_err(|e| fetch_error(e.to_string()))?;
+        let mut request = self.middleware.get(self.client, feed);
+        if let Some(store) = self.credentials {
+            request = store.authorize(request, &feed_url, self.client, self.oauth);
+        }
+        let request = request.build().map_err(|e| fetch_error(e.to_string()))?;
+        let response = timeouts::execute(self.client, request, self.header_timeout).map_err(|e| fetch_error(e.to_string()))?;
+        self.middleware.intercept(&response, &DownloadContext { method: &reqwest::Method::GET, url: feed }).map_err(fetch_error)?;
+        if !response.status().is_success() {
+            return Err(fetch_error(format!("server returned {}", response.status())));
+        }
+        let final_url = response.url().clone();
+        let mut body = Vec::new();
+        response.take(MAX_FEED_SIZE).read_to_end(&mut body).map_err(|e| fetch_error(e.to_string()))?;
+
+        let items = items(&String::from_utf8_lossy(&body), &final_url)?;
+        // An age from before the clock began lets everything through
+        let cutoff = self.newer_than.and_then(|age| SystemTime::now().checked_sub(age));
+        let total = items.len();
+        let new: Vec<Item> = items
+            .into_iter()
+            .filter(|item| !self.downloaded.contains(&item.url))
+            // Items without a date can't be told apart, so they're kept
+            .filter(|item| cutoff.zip(item.published).is_none_or(|(cutoff, published)| published > cutoff))
+            .collect();
+        info!("{}: {} new of {} items", feed, new.len(), total);
+        Ok(new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_items() {
+        let feed = Url::parse("https://example.com/podcast/feed.xml").unwrap();
+        let rss = r#"<?xml version="1.0"?><rss version="2.0"><channel><title>Show</title>
+            <item><title>Two</title><link>https://example.com/2</link><pubDate>Tue, 02 Jun 2026 10:00:00 +0000</pubDate>
+              <enclosure url="episodes/2.mp3?a=1&amp;b=2" length="1000" type="audio/mpeg"/></item>
+            <item><title>Notes</title><link><![CDATA[https://example.com/notes]]></link></item>
+        </channel></rss>"#;
+        let items = items(rss, &feed).unwrap();
+        assert_eq!(items[0].url, "https://example.com/podcast/episodes/2.mp3?a=1&b=2");
+        assert_eq!(items[0].published, Some(UNIX_EPOCH + Duration::from_secs(1_780_394_400)));
+        assert_eq!(items[1], Item { url: "https://example.com/notes".to_string(), published: None });
+
+        let atom = r#"<feed xmlns="http://www.w3.org/2005/Atom"><link href="https://example.com/"/>
+            <entry><link rel="alternate" href="/releases/1.0"/><link rel="enclosure" href="/files/app-1.0.tar.gz"/><updated>2026-06-02T10:00:00Z</updated></entry>
+            <entry><link href="/releases/0.9"/></entry></feed>"#;
+        let urls: Vec<String> = super::items(atom, &feed).unwrap().into_iter().map(|item| item.url).collect();
+        assert_eq!(urls, ["https://example.com/files/app-1.0.tar.gz", "https://example.com/releases/0.9"]);
+
+        assert!(super::items("<html><body>Not a feed</body></html>", &feed).is_err());
+    }
+
+    #[test]
+    fn test_history() {
+        let path = std::env::temp_dir().join(format!("rustdl-feeds-{}.jsonl", std::process::id()));
+        let history = FeedHistory::new(path.clone());
+        assert!(history.downloaded().unwrap().is_empty());
+        let item = |url: &str| ("https://example.com/feed".to_string(), Item { url: url.to_string(), published: None });
+        let found = [item("https://example.com/1.mp3"), item("https://example.com/2.mp3")];
+        let entries = [Entry::new("https://example.com/1.mp3", Status::Downloaded, None), Entry::new("https://example.com/2.mp3", Status::Failed, None)];
+        history.record(&found, &entries).unwrap();
+        assert_eq!(history.downloaded().unwrap(), HashSet::from(["https://example.com/1.mp3".to_string()]));
+        fs::remove_file(&path).unwrap();
+    }
+}
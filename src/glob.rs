@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+
+use regex::Regex;
+
+/// The most URLs one pattern may expand to, so a typo can't queue millions
+const MAX_URLS: usize = 100_000;
+
+static RANGE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^(?:(\d+)-(\d+)|([a-z])-([a-z])|([A-Z])-([A-Z]))(?::(\d+))?$").unwrap());
+
+/// What each expanded URL's `[...]` and `{...}` parts matched, in order, for
+/// the output template's `{1}`, `{2}` and so on. URLs are expanded long
+/// before they're named, so this is how the two meet.
+static MATCHES: LazyLock<Mutex<HashMap<String, Vec<String>>>> = LazyLock::new(Mutex::default);
+
+/// Errors from expanding a URL pattern
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum GlobError {
+    #[error("{pattern}: unclosed '{brace}' (use --globoff, or escape it with a backslash, to leave it as it is)")]
+    Unclosed { pattern: String, brace: char },
+
+    #[error("{pattern}: bad range [{range}]")]
+    BadRange { pattern: String, range: String },
+
+    #[error("{pattern}: expands to more than {MAX_URLS} URLs")]
+    TooMany { pattern: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    /// One of these, each in turn
+    Choices(Vec<String>),
+}
+
+/// A URL a pattern expanded to, with what each of its parts matched
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expanded {
+    pub url: String,
+    pub matched: Vec<String>,
+}
+
+/// Every URL `pattern` stands for, curl-style: `{a,b,c}` is each of a, b
+/// and c, `[1-100]` each number (`[001-100]` zero-padded, `[0-100:10]` in
+/// tens) and `[a-z]` each letter. Brackets that aren't a range, like an IPv6
+/// address, are left alone, as is anything escaped with a backslash.
+pub fn expand(pattern: &str) -> Result<Vec<Expanded>, GlobError> {
+    let parts = parse(pattern)?;
+    let mut expanded = vec![Expanded { url: String::new(), matched: Vec::new() }];
+    for part in parts {
+        match part {
+            Part::Literal(text) => expanded.iter_mut().for_each(|so_far| so_far.url.push_str(&text)),
+            Part::Choices(choices) => {
+                if expanded.len() * choices.len() > MAX_URLS {
+                    return Err(GlobError::TooMany { pattern: pattern.to_string() });
+                }
+                expanded = expanded
+                    .into_iter()
+                    .flat_map(|so_far| {
+                        choices.iter().map(move |choice| {
+                            let mut matched = so_far.matched.clone();
+                            matched.push(choice.clone());
+                            Expanded { url: format!("{}{}", so_far.url, choice), matched }
+                        })
+                    })
+                    .collect();
+            }
+        }
+    }
+    Ok(expanded)
+}
+
+/// Expand every pattern, in order, remembering what each URL matched for
+/// the output template
+pub fn expand_all(patterns: &[String]) -> Result<Vec<String>, GlobError> {
+    let mut urls = Vec::new();
+    let mut matches = MATCHES.lock().unwrap();
+    for pattern in patterns {
+        for Expanded { url, matched } in expand(pattern)? {
+            if !matched.is_empty() {
+                let key = url::Url::parse(&url).map_or_else(|_| url.clone(), String::from);
+                matches.insert(key, matched);
+            }
+            urls.push(url);
+        }
+    }
+    Ok(urls)
+}
+
+/// The `n`th thing (from 1) `url`, or the URL that redirected to it, matched
+/// when it was expanded
+pub fn matched(url: &str, n: usize) -> Option<String> {
+    MATCHES.lock().unwrap().get(url)?.get(n.checked_sub(1)?).cloned()
+}
+
+/// `from` was redirected to `to`, which is to be named with what `from` matched
+pub fn redirected(from: &str, to: &str) {
+    let mut matches = MATCHES.lock().unwrap();
+    if let Some(matched) = matches.get(from).cloned() {
+        matches.insert(to.to_string(), matched);
+    }
+}
+
+fn parse(pattern: &str) -> Result<Vec<Part>, GlobError> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut chars = pattern.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '\\' if chars.peek().is_some_and(|(_, next)| "[]{}".contains(*next)) => literal.push(chars.next().unwrap().1),
+            '{' | '[' => {
+                let close = if c == '{' { '}' } else { ']' };
+                let Some(length) = pattern[index + 1..].find(close) else {
+                    return Err(GlobError::Unclosed { pattern: pattern.to_string(), brace: c });
+                };
+                let inside = &pattern[index + 1..index + 1 + length];
+                let choices = if c == '{' { Some(inside.split(',').map(str::to_string).collect()) } else { range(pattern, inside)? };
+                match choices {
+                    Some(choices) => {
+                        if !literal.is_empty() {
+                            parts.push(Part::Literal(std::mem::take(&mut literal)));
+                        }
+                        parts.push(Part::Choices(choices));
+                    }
+                    None => literal.push_str(&pattern[index..index + length + 2]),
+                }
+                while chars.peek().is_some_and(|(next, _)| *next <= index + length + 1) {
+                    chars.next();
+                }
+            }
+            c => literal.push(c),
+        }
+    }
+    if !literal.is_empty() {
+        parts.push(Part::Literal(literal));
+    }
+    Ok(parts)
+}
+
+/// What a `[...]` range stands for, or `None` if it isn't one
+fn range(pattern: &str, inside: &str) -> Result<Option<Vec<String>>, GlobError> {
+    let Some(captures) = RANGE.captures(inside) else { return Ok(None) };
+    let bad_range = || GlobError::BadRange { pattern: pattern.to_string(), range: inside.to_string() };
+    let step = match captures.get(7) {
+        Some(step) => step.as_str().parse::<usize>().ok().filter(|step| *step > 0).ok_or_else(bad_range)?,
+        None => 1,
+    };
+    if let (Some(start), Some(end)) = (captures.get(1), captures.get(2)) {
+        let (first, last) = (start.as_str().parse::<u64>().map_err(|_| bad_range())?, end.as_str().parse::<u64>().map_err(|_| bad_range())?);
+        if first > last || ((last - first) / step as u64) as usize >= MAX_URLS {
+            return Err(bad_range());
+        }
+        // A leading zero pads every number to the first one's width
+        let width = if start.as_str().starts_with('0') { start.len() } else { 0 };
+        return Ok(Some((first..=last).step_by(step).map(|n| format!("{:0width$}", n, width = width)).collect()));
+    }
+    let letters = [(3, 4), (5, 6)].iter().find_map(|&(start, end)| Some((captures.get(start)?, captures.get(end)?))).unwrap();
+    let (first, last) = (letters.0.as_str().chars().next().unwrap(), letters.1.as_str().chars().next().unwrap());
+    if first > last {
+        return Err(bad_range());
+    }
+    Ok(Some((first..=last).step_by(step).map(String::from).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn urls(pattern: &str) -> Vec<String> {
+        expand(pattern).unwrap().into_iter().map(|expanded| expanded.url).collect()
+    }
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(urls("https://example.com/img[08-10].jpg"), ["https://example.com/img08.jpg", "https://example.com/img09.jpg", "https://example.com/img10.jpg"]);
+        assert_eq!(urls("https://{www,cdn}.example.com/[a-b]"), [
+            "https://www.example.com/a",
+            "https://www.example.com/b",
+            "https://cdn.example.com/a",
+            "https://cdn.example.com/b",
+        ]);
+        assert_eq!(urls("https://example.com/[0-20:10]"), ["https://example.com/0", "https://example.com/10", "https://example.com/20"]);
+        assert_eq!(urls("http://[::1]:8080/a\\{b\\}"), ["http://[::1]:8080/a{b}"]);
+        assert_eq!(expand("https://example.com/{a,b}/[1-2]").unwrap()[3].matched, ["b", "2"]);
+
+        assert!(matches!(expand("https://example.com/{a,b"), Err(GlobError::Unclosed { brace: '{', .. })));
+        assert!(matches!(expand("https://example.com/[9-1]"), Err(GlobError::BadRange { .. })));
+        assert!(matches!(expand("https://example.com/[1-1000][1-1000]"), Err(GlobError::TooMany { .. })));
+    }
+
+    #[test]
+    fn test_matched() {
+        let urls = expand_all(&["https://glob.example/{photos,videos}/[1-2].jpg".to_string()]).unwrap();
+        assert_eq!(urls.len(), 4);
+        assert_eq!(matched("https://glob.example/videos/1.jpg", 1).as_deref(), Some("videos"));
+        assert_eq!(matched("https://glob.example/videos/1.jpg", 2).as_deref(), Some("1"));
+        assert_eq!(matched("https://glob.example/videos/1.jpg", 3), None);
+        assert_eq!(matched("https://glob.example/other.jpg", 1), None);
+
+        redirected("https://glob.example/videos/1.jpg", "https://cdn.example/v1.jpg");
+        assert_eq!(matched("https://cdn.example/v1.jpg", 1).as_deref(), Some("videos"));
+    }
+}
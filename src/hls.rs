@@ -0,0 +1,482 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, LazyLock, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use indicatif::ProgressBar;
+use log::{debug, info, warn};
+use regex::Regex;
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{self, HeaderMap};
+use url::Url;
+
+use crate::auth_hook::AuthHook;
+use crate::cancel::{Cancellation, Cancelled};
+use crate::credentials::CredentialStore;
+use crate::middleware::{DownloadContext, Middleware};
+use crate::oauth::OAuthSettings;
+use crate::throttle::{self, MinimumSpeed, RateLimiter};
+use crate::timeouts;
+
+/// Segments fetched at once
+pub const DEFAULT_JOBS: usize = 4;
+
+/// How many segments each job may get ahead of the one being written, so a
+/// slow segment can't leave the rest piling up in memory
+const WINDOW_PER_JOB: usize = 4;
+
+/// How many times a segment is asked for before the download fails
+const SEGMENT_ATTEMPTS: usize = 3;
+
+/// The most of a playlist we'll read
+const MAX_PLAYLIST_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Content types servers give HLS playlists
+const PLAYLIST_TYPES: &[&str] = &["application/vnd.apple.mpegurl", "application/x-mpegurl"];
+
+static ATTRIBUTE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"([A-Z0-9-]+)=("[^"]*"|[^,]*)"#).unwrap());
+
+/// Errors from downloading an HLS stream
+#[derive(Debug, thiserror::Error)]
+pub enum HlsError {
+    #[error("{url}: {message}")]
+    Request { url: String, message: String },
+
+    #[error("{0}: not an HLS playlist")]
+    NotAPlaylist(String),
+
+    #[error("{url}: segments are encrypted with {method}, which isn't supported")]
+    Encrypted { url: String, method: String },
+
+    #[error("{0}: the playlist has no segments")]
+    Empty(String),
+
+    #[error("could not write {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("could not write the stream: {0}")]
+    Write(#[source] io::Error),
+
+    #[error("could not remux {path}: {message}")]
+    Remux { path: PathBuf, message: String },
+
+    #[error(transparent)]
+    Cancelled(#[from] Cancelled),
+}
+
+/// Part of the stream, or all of a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub url: Url,
+    /// Where in the file it is, as an offset and a length
+    pub range: Option<(u64, u64)>,
+}
+
+/// One of the streams a master playlist offers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variant {
+    pub url: Url,
+    /// Bits per second, at most
+    pub bandwidth: u64,
+}
+
+/// What a playlist turned out to be
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Playlist {
+    /// A list of the same stream at different qualities
+    Master(Vec<Variant>),
+    /// The segments of one stream, in order
+    Media {
+        /// What has to come before the segments (fragmented MP4's `#EXT-X-MAP`)
+        init: Option<Segment>,
+        segments: Vec<Segment>,
+        /// Whether it's a live stream's playlist, with more segments to come
+        live: bool,
+    },
+}
+
+/// Whether the response to `url` is an HLS playlist, going by its content
+/// type or, failing that, its extension
+pub fn is_playlist(url: &Url, headers: &HeaderMap) -> bool {
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or_default().to_ascii_lowercase();
+    PLAYLIST_TYPES.iter().any(|playlist_type| content_type.starts_with(playlist_type)) || url.path().to_ascii_lowercase().ends_with(".m3u8")
+}
+
+/// What to save a stream as, given the name its playlist would have had
+pub fn output_name(name: &Path) -> PathBuf {
+    name.with_extension("ts")
+}
+
+/// Parse a playlist fetched from `base`, which its URIs are relative to
+pub fn parse(text: &str, base: &Url) -> Result<Playlist, HlsError> {
+    if !text.trim_start_matches('\u{feff}').starts_with("#EXTM3U") {
+        return Err(HlsError::NotAPlaylist(base.to_string()));
+    }
+    let resolve = |uri: &str| {
+        base.join(uri.trim()).map_err(|e| HlsError::Request { url: base.to_string(), message: format!("bad URI {}: {}", uri, e) })
+    };
+
+    let mut variants = Vec::new();
+    let mut segments = Vec::new();
+    let mut init = None;
+    let mut live = true;
+    // What the tags say about the next URI
+    let mut bandwidth = None;
+    let mut byte_range = None;
+    // Where the last byte range ended, for ranges that carry on from it
+    let mut range_end = 0;
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        if let Some(tag) = line.strip_prefix('#') {
+            let (name, value) = tag.split_once(':').unwrap_or((tag, ""));
+            match name {
+                "EXT-X-STREAM-INF" => bandwidth = Some(attribute(value, "BANDWIDTH").and_then(|value| value.parse().ok()).unwrap_or(0)),
+                "EXT-X-BYTERANGE" => byte_range = Some(value.to_string()),
+                "EXT-X-ENDLIST" => live = false,
+                "EXT-X-KEY" => match attribute(value, "METHOD").as_deref() {
+                    Some("NONE") | None => {}
+                    Some(method) => return Err(HlsError::Encrypted { url: base.to_string(), method: method.to_string() }),
+                },
+                "EXT-X-MAP" => {
+                    if let Some(uri) = attribute(value, "URI") {
+                        let range = attribute(value, "BYTERANGE").map(|range| parse_range(&range, 0));
+                        init = Some(Segment { url: resolve(&uri)?, range });
+                    }
+                }
+                _ => {}
+            }
+            continue;
+        }
+        let url = resolve(line)?;
+        match bandwidth.take() {
+            Some(bandwidth) => variants.push(Variant { url, bandwidth }),
+            None => {
+                let range = byte_range.take().map(|range| parse_range(&range, range_end));
+                if let Some((offset, length)) = range {
+                    range_end = offset + length;
+                }
+                segments.push(Segment { url, range });
+            }
+        }
+    }
+    Ok(match variants.is_empty() {
+        true => Playlist::Media { init, segments, live },
+        false => Playlist::Master(variants),
+    })
+}
+
+fn attribute(attributes: &str, wanted: &str) -> Option<String> {
+    ATTRIBUTE
+        .captures_iter(attributes)
+        .find(|captures| &captures[1] == wanted)
+        .map(|captures| captures[2].trim_matches('"').to_string())
+}
+
+/// `length[@offset]`, the offset being where the last range ended if it's left out
+fn parse_range(range: &str, carry_on_from: u64) -> (u64, u64) {
+    let (length, offset) = range.split_once('@').map_or((range, None), |(length, offset)| (length, Some(offset)));
+    (offset.and_then(|offset| offset.parse().ok()).unwrap_or(carry_on_from), length.parse().unwrap_or(0))
+}
+
+/// What the jobs fetching segments and the writer share
+#[derive(Default)]
+struct Shared {
+    /// Segments fetched but not written yet
+    fetched: BTreeMap<usize, Vec<u8>>,
+    /// How many segments have been written
+    written: usize,
+    /// The first thing to go wrong
+    error: Option<HlsError>,
+    /// Whether the jobs should give up
+    stopped: bool,
+}
+
+/// Downloads an HLS stream: the best variant of a master playlist, its
+/// segments a few at a time, written one after another into a single file
+pub struct HlsDownloader<'a> {
+    pub client: &'a Client,
+    pub middleware: &'a Middleware,
+    pub credentials: Option<&'a CredentialStore>,
+    pub oauth: &'a OAuthSettings,
+    pub auth_hook: Option<&'a AuthHook>,
+    pub header_timeout: Option<Duration>,
+    pub limiter: Option<Arc<RateLimiter>>,
+    pub min_speed: Option<MinimumSpeed>,
+    pub jobs: usize,
+    pub cancellation: &'a Cancellation,
+}
+
+impl HlsDownloader<'_> {
+    /// Download the stream `playlist` is the response for into `path`,
+    /// returning how many bytes it came to; `pb` counts them, and estimates
+    /// the total from the segments so far
+    pub fn download(&self, playlist: Response, path: &Path, pb: &ProgressBar) -> Result<u64, HlsError> {
+        let mut partial = path.as_os_str().to_os_string();
+        partial.push(".part");
+        let partial = PathBuf::from(partial);
+        let io_error = |source| HlsError::Io { path: partial.clone(), source };
+        let mut file = BufWriter::new(File::create(&partial).map_err(io_error)?);
+        let result = self.download_to(playlist, &mut file, pb).map_err(|e| match e {
+            HlsError::Write(source) => io_error(source),
+            e => e,
+        });
+        let result = result.and_then(|bytes| {
+            file.flush().map_err(io_error)?;
+            drop(file);
+            fs::rename(&partial, path).map_err(|source| HlsError::Io { path: path.to_path_buf(), source })?;
+            Ok(bytes)
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(&partial);
+        }
+        result
+    }
+
+    /// Download the stream `playlist` is the response for into `out`, for
+    /// when it isn't going straight to a file, like when it's encrypted
+    pub fn download_to(&self, playlist: Response, out: &mut dyn Write, pb: &ProgressBar) -> Result<u64, HlsError> {
+        let mut url = playlist.url().clone();
+        let mut playlist = parse(&read_text(playlist)?, &url)?;
+        if let Playlist::Master(variants) = &playlist {
+            let best = variants.iter().max_by_key(|variant| variant.bandwidth).expect("master playlists have a variant");
+            info!("{}: downloading the {} bit/s stream of {}", url, best.bandwidth, variants.len());
+            url = best.url.clone();
+            let response = self.fetch(&Segment { url: url.clone(), range: None })?;
+            url = response.url().clone();
+            playlist = parse(&read_text(response)?, &url)?;
+        }
+        let Playlist::Media { init, segments, live } = playlist else {
+            return Err(HlsError::NotAPlaylist(url.to_string()));
+        };
+        if segments.is_empty() {
+            return Err(HlsError::Empty(url.to_string()));
+        }
+        if live {
+            warn!("{} is a live stream; only the {} segments listed so far will be downloaded", url, segments.len());
+        }
+        let segments: Vec<Segment> = init.into_iter().chain(segments).collect();
+        debug!("Downloading {} segments of {}", segments.len(), url);
+        self.fetch_all(&segments, pb, |data| out.write_all(data).map_err(HlsError::Write))
+    }
+
+    /// Fetch every segment, `jobs` at a time, handing them to `write` in order
+    fn fetch_all(&self, segments: &[Segment], pb: &ProgressBar, mut write: impl FnMut(&[u8]) -> Result<(), HlsError>) -> Result<u64, HlsError> {
+        let jobs = self.jobs.clamp(1, segments.len());
+        let window = jobs * WINDOW_PER_JOB;
+        let next = AtomicUsize::new(0);
+        let fetched_bytes = AtomicU64::new(0);
+        let fetched_count = AtomicUsize::new(0);
+        let shared = Mutex::new(Shared::default());
+        let changed = Condvar::new();
+
+        thread::scope(|scope| {
+            for _ in 0..jobs {
+                scope.spawn(|| {
+                    loop {
+                        let index = next.fetch_add(1, Ordering::SeqCst);
+                        let Some(segment) = segments.get(index) else { break };
+                        let waiting = |shared: &mut Shared| !shared.stopped && index >= shared.written + window;
+                        if changed.wait_while(shared.lock().unwrap(), waiting).unwrap().stopped {
+                            break;
+                        }
+                        let result = match self.cancellation.reason() {
+                            Some(reason) => Err(HlsError::from(Cancelled(reason))),
+                            None => self.fetch_segment(segment),
+                        };
+                        let mut shared = shared.lock().unwrap();
+                        match result {
+                            Ok(data) => {
+                                let bytes = fetched_bytes.fetch_add(data.len() as u64, Ordering::SeqCst) + data.len() as u64;
+                                let count = fetched_count.fetch_add(1, Ordering::SeqCst) + 1;
+                                pb.set_length(bytes / count as u64 * segments.len() as u64);
+                                pb.inc(data.len() as u64);
+                                shared.fetched.insert(index, data);
+                            }
+                            Err(e) => {
+                                shared.error.get_or_insert(e);
+                                shared.stopped = true;
+                            }
+                        }
+                        changed.notify_all();
+                    }
+                });
+            }
+
+            // Write them in order as they come in; if anything goes wrong, the jobs stop too
+            let stop = |error: HlsError| {
+                shared.lock().unwrap().stopped = true;
+                changed.notify_all();
+                Err(error)
+            };
+            let mut total = 0;
+            for index in 0..segments.len() {
+                let waiting = |shared: &mut Shared| shared.error.is_none() && !shared.fetched.contains_key(&index);
+                let mut guard = changed.wait_while(shared.lock().unwrap(), waiting).unwrap();
+                if let Some(error) = guard.error.take() {
+                    drop(guard);
+                    return stop(error);
+                }
+                let data = guard.fetched.remove(&index).unwrap();
+                drop(guard);
+                if let Err(e) = write(&data) {
+                    return stop(e);
+                }
+                total += data.len() as u64;
+                shared.lock().unwrap().written = index + 1;
+                changed.notify_all();
+            }
+            pb.set_length(total);
+            Ok(total)
+        })
+    }
+
+    fn fetch_segment(&self, segment: &Segment) -> Result<Vec<u8>, HlsError> {
+        let mut attempt = 1;
+        loop {
+            let result = self.fetch(segment).and_then(|response| {
+                let mut data = Vec::new();
+                throttle::throttled(response, self.cancellation, self.limiter.clone(), self.min_speed)
+                    .read_to_end(&mut data)
+                    .map(|_| data)
+                    .map_err(|e| HlsError::Request { url: segment.url.to_string(), message: e.to_string() })
+            });
+            match result {
+                Err(HlsError::Request { url, message }) if attempt < SEGMENT_ATTEMPTS && self.cancellation.reason().is_none() => {
+                    debug!("{}: {}, trying again", url, message);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn fetch(&self, segment: &Segment) -> Result<Response, HlsError> {
+        let request_error = |message: String| HlsError::Request { url: segment.url.to_string(), message };
+        let mut request = self.middleware.get(self.client, segment.url.as_str());
+        if let Some((offset, length)) = segment.range {
+            request = request.header(header::RANGE, format!("bytes={}-{}", offset, offset + length.max(1) - 1));
+        }
+        if let Some(store) = self.credentials {
+            request = store.authorize(request, &segment.url, self.client, self.oauth);
+        }
+        if let Some(hook) = self.auth_hook {
+            request = request.headers(hook.headers("GET", segment.url.as_str()).map_err(|e| request_error(e.to_string()))?);
+        }
+        let request = request.build().map_err(|e| request_error(e.to_string()))?;
+        let response = timeouts::execute(self.client, request, self.header_timeout).map_err(|e| request_error(e.to_string()))?;
+        self.middleware
+            .intercept(&response, &DownloadContext { method: &reqwest::Method::GET, url: segment.url.as_str() })
+            .map_err(request_error)?;
+        if !response.status().is_success() {
+            return Err(request_error(format!("server returned {}", response.status())));
+        }
+        Ok(response)
+    }
+}
+
+fn read_text(response: Response) -> Result<String, HlsError> {
+    let url = response.url().to_string();
+    let mut body = Vec::new();
+    response.take(MAX_PLAYLIST_SIZE).read_to_end(&mut body).map_err(|e| HlsError::Request { url, message: e.to_string() })?;
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Copy the stream in `path` into a `format` container (`--remux`) with
+/// ffmpeg, without re-encoding it, and remove the original; returns where it went
+pub fn remux(path: &Path, format: &str) -> Result<PathBuf, HlsError> {
+    let remuxed = path.with_extension(format);
+    let remux_error = |message: String| HlsError::Remux { path: path.to_path_buf(), message };
+    // Removing the original would remove what ffmpeg just wrote over it
+    if path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case(format)) {
+        return Err(remux_error(format!("it's already named .{}, so it would be remuxed into itself", format)));
+    }
+    let output = Command::new("ffmpeg")
+        .args(["-nostdin", "-loglevel", "error", "-y", "-i"])
+        .arg(path)
+        .args(["-c", "copy"])
+        .arg(&remuxed)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| remux_error(format!("could not run ffmpeg: {}", e)))?;
+    if !output.status.success() {
+        let _ = fs::remove_file(&remuxed);
+        return Err(remux_error(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+    fs::remove_file(path).map_err(|source| HlsError::Io { path: path.to_path_buf(), source })?;
+    Ok(remuxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_parse() {
+        let base = Url::parse("https://cdn.example/video/master.m3u8").unwrap();
+        let master = "#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=800000,RESOLUTION=640x360,CODECS=\"avc1.4d401e,mp4a.40.2\"\nlow/index.m3u8\n#EXT-X-STREAM-INF:BANDWIDTH=2400000\nhttps://other.example/high.m3u8\n";
+        assert_eq!(
+            parse(master, &base).unwrap(),
+            Playlist::Master(vec![
+                Variant { url: Url::parse("https://cdn.example/video/low/index.m3u8").unwrap(), bandwidth: 800_000 },
+                Variant { url: Url::parse("https://other.example/high.m3u8").unwrap(), bandwidth: 2_400_000 },
+            ])
+        );
+
+        let media = "#EXTM3U\n#EXT-X-TARGETDURATION:6\n#EXT-X-MAP:URI=\"init.mp4\"\n#EXTINF:6.0,\nseg0.m4s\n#EXTINF:6.0,\n#EXT-X-BYTERANGE:100@50\nall.m4s\n#EXTINF:6.0,\n#EXT-X-BYTERANGE:20\nall.m4s\n#EXT-X-ENDLIST\n";
+        let Playlist::Media { init, segments, live } = parse(media, &base).unwrap() else { panic!("not a media playlist") };
+        assert_eq!(init.unwrap().url.as_str(), "https://cdn.example/video/init.mp4");
+        assert_eq!(segments.iter().map(|segment| segment.range).collect::<Vec<_>>(), [None, Some((50, 100)), Some((150, 20))]);
+        assert!(!live);
+
+        assert!(matches!(parse("#EXTM3U\n#EXT-X-KEY:METHOD=AES-128,URI=\"key\"\nseg0.ts\n", &base), Err(HlsError::Encrypted { .. })));
+        assert!(matches!(parse("<html></html>", &base), Err(HlsError::NotAPlaylist(_))));
+        assert_eq!(output_name(Path::new("master.m3u8")), PathBuf::from("master.ts"));
+    }
+
+    #[test]
+    fn test_download() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = [0u8; 1024];
+                let read = stream.read(&mut request).unwrap();
+                let path = String::from_utf8_lossy(&request[..read]).split(' ').nth(1).unwrap_or_default().to_string();
+                let body = match path.as_str() {
+                    "/master.m3u8" => "#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=1\nlow.m3u8\n#EXT-X-STREAM-INF:BANDWIDTH=2\nhigh.m3u8\n".to_string(),
+                    "/high.m3u8" => "#EXTM3U\n".to_string() + &(0..10).map(|n| format!("#EXTINF:1,\nseg{}.ts\n", n)).collect::<String>() + "#EXT-X-ENDLIST\n",
+                    path => path.trim_start_matches("/seg").replace(".ts", "-"),
+                };
+                let reply = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+        });
+
+        let client = Client::builder().pool_max_idle_per_host(0).build().unwrap();
+        let downloader = HlsDownloader {
+            client: &client,
+            middleware: &Middleware::default(),
+            credentials: None,
+            oauth: &OAuthSettings::default(),
+            auth_hook: None,
+            header_timeout: None,
+            limiter: None,
+            min_speed: None,
+            jobs: 3,
+            cancellation: &Cancellation::default(),
+        };
+        let path = std::env::temp_dir().join(format!("rustdl-hls-{}.ts", std::process::id()));
+        let master = client.get(format!("http://{}/master.m3u8", address)).send().unwrap();
+        let bytes = downloader.download(master, &path, &ProgressBar::hidden()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "0-1-2-3-4-5-6-7-8-9-");
+        assert_eq!(bytes, 20);
+        assert!(matches!(remux(&path, "TS"), Err(HlsError::Remux { .. })));
+        fs::remove_file(&path).unwrap();
+    }
+}
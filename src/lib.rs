@@ -0,0 +1,112 @@
+//! The engine behind the `download` command, for embedding in other programs.
+//!
+//! A [`Downloader`] fetches URLs with everything the command line can ask
+//! for: how files are named and where they go ([`naming`]), cookies from the
+//! user's browsers ([`cookies`], with the `browser-cookies` feature),
+//! checksums, rate limits, progress and the rest. [`Downloader::builder`]
+//! starts from what `download` does with no options; each fetch returns the
+//! [`ExitCode`] the command would exit with, and what happened to each URL is
+//! in its [`report::Report`].
+//!
+//! ```no_run
+//! use rustdl::{Downloader, ExitCode};
+//!
+//! let downloader = Downloader::builder().max_concurrent(4).output_dir("downloads").no_clobber().build();
+//! assert_eq!(downloader.fetch("https://example.com/file.iso")?, ExitCode::Success);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! A `Downloader` blocks the thread it's used on, and is also
+//! [`downloader::blocking::Client`]; the `async` feature adds
+//! `downloader::Client`, which does the same from async code under any
+//! executor. Progress can go to a callback or a channel, as
+//! [`progress::ProgressEvent`]s, instead of being drawn as bars.
+//!
+//! Every option, as [`DownloadOptions`], can be given with
+//! [`DownloaderBuilder::from`]. The public modules are the pieces it's built
+//! from that can be used on their own, like [`resume`] for finishing the
+//! `.part` files a download left behind. Beyond the progress they're asked
+//! to draw, what's shown to the user is up to the program using them.
+
+pub mod api;
+mod append;
+pub mod archive;
+pub mod attestation;
+pub mod auth_hook;
+pub mod batch;
+pub mod cancel;
+#[cfg(feature = "browser-cookies")]
+pub mod browser;
+pub mod checksum;
+pub mod checksum_db;
+pub mod clock;
+pub mod container;
+#[doc(hidden)]
+pub mod copy;
+pub mod control_socket;
+#[cfg(feature = "browser-cookies")]
+pub mod cookies;
+pub mod decompress;
+mod dedupe;
+mod diagnose;
+pub mod downloader;
+mod dry_run;
+mod disposition;
+pub mod credentials;
+pub mod daemon;
+pub mod device;
+pub mod encrypt;
+pub mod exec_hook;
+pub mod exit_code;
+pub mod extractor;
+mod feed;
+pub mod glob;
+mod hls;
+pub mod logging;
+pub mod middleware;
+pub mod naming;
+pub mod negotiate;
+pub mod netload;
+pub mod notify;
+pub mod ntlm;
+pub mod oauth;
+pub mod order;
+pub mod page;
+pub mod partial;
+pub mod pinning;
+pub mod politeness;
+mod preallocate;
+pub mod progress;
+pub mod range;
+pub mod recursive;
+pub mod redirects;
+pub mod report;
+pub mod resolver;
+pub mod resume;
+pub mod retry_after;
+pub mod retry_history;
+pub mod session;
+pub mod settings;
+mod speed;
+pub mod spider;
+pub mod ssh_tunnel;
+#[cfg(feature = "state-db")]
+pub mod state_db;
+mod swarm;
+mod tee;
+pub mod template;
+pub mod timeouts;
+pub mod throttle;
+pub mod timestamping;
+pub mod timing;
+pub mod transport;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+#[doc(hidden)]
+pub mod uring;
+pub mod verify_remote;
+pub mod verify_tree;
+pub mod warc;
+pub mod webhook;
+
+pub use downloader::{build_client, ClientOptions, DownloadOptions, Downloader, DownloaderBuilder};
+pub use exit_code::{ExitCode, Failure};
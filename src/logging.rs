@@ -0,0 +1,244 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use log::{LevelFilter, Log, Metadata, Record, debug, log_enabled, trace};
+use reqwest::blocking::{Request, Response};
+use reqwest::header::{self, HeaderMap};
+use serde::Serialize;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::{Layer, filter};
+
+use crate::clock::TimeZone;
+
+/// Headers whose values are secrets, so they're never logged
+const SENSITIVE_HEADERS: &[header::HeaderName] =
+    &[header::AUTHORIZATION, header::PROXY_AUTHORIZATION, header::COOKIE, header::SET_COOKIE];
+
+/// The modules everything in this crate logs under: the library's, and the
+/// `download` binary's
+const CRATE_MODULES: [&str; 2] = [env!("CARGO_CRATE_NAME"), "download"];
+
+/// How much we log for each `-v`: just errors by default, then warnings and
+/// progress, then requests and responses, then their headers (and what the
+/// HTTP client's up to)
+pub fn level(verbosity: u8) -> LevelFilter {
+    match verbosity {
+        0 => LevelFilter::Error,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Start logging to stderr at the level `-v` asks for; `RUST_LOG` still
+/// wins if it's set. With a `file`, everything we log goes there as well,
+/// whatever the terminal's showing. From `-vvv`, how long each span (resolving,
+/// requests, writing) took is shown as it closes.
+pub fn init(verbosity: u8, file: Option<RotatingFile>, timezone: TimeZone) {
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(LevelFilter::Error);
+    for module in CRATE_MODULES {
+        builder.filter_module(module, level(verbosity));
+    }
+    if verbosity >= 3 {
+        builder.filter_module("reqwest", LevelFilter::Debug);
+    }
+    let terminal = builder.parse_env(env_logger::Env::default()).build();
+    let max_level = match file {
+        Some(_) => LevelFilter::Trace,
+        None => terminal.filter(),
+    };
+    let logger = Logger { terminal, file: file.map(Mutex::new), timezone };
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(max_level);
+    }
+    if verbosity >= 3 {
+        init_spans();
+    }
+}
+
+/// Report spans' timings on stderr; events inside them are already logged
+/// like any other log message
+fn init_spans() {
+    let spans = tracing_subscriber::fmt::layer()
+        .with_writer(io::stderr)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_filter(filter::filter_fn(|metadata| metadata.is_span() && ours(metadata.target())));
+    let _ = tracing::subscriber::set_global_default(tracing_subscriber::registry().with(spans));
+}
+
+/// Whether something logged under `target` is from this crate
+fn ours(target: &str) -> bool {
+    CRATE_MODULES.iter().any(|module| target.starts_with(module))
+}
+
+/// One line of the log file
+#[derive(Serialize)]
+struct FileLine<'a> {
+    time: String,
+    level: &'a str,
+    target: &'a str,
+    message: String,
+}
+
+/// Sends each record to the terminal and the log file, if they want it
+struct Logger {
+    terminal: env_logger::Logger,
+    file: Option<Mutex<RotatingFile>>,
+    /// What the log file's times are shown in
+    timezone: TimeZone,
+}
+
+impl Logger {
+    /// The log file gets everything from this crate, and warnings from anything else
+    fn file_wants(&self, metadata: &Metadata) -> bool {
+        self.file.is_some() && (ours(metadata.target()) || metadata.level() <= log::Level::Warn)
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.terminal.enabled(metadata) || self.file_wants(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.terminal.log(record);
+        if let Some(file) = &self.file
+            && self.file_wants(record.metadata())
+        {
+            let line = FileLine {
+                time: self.timezone.format_millis(SystemTime::now()),
+                level: record.level().as_str(),
+                target: record.target(),
+                message: record.args().to_string(),
+            };
+            // There's nowhere left to report a log file that can't be written
+            if let Ok(line) = serde_json::to_string(&line) {
+                let _ = file.lock().unwrap().write_line(&line);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.terminal.flush();
+    }
+}
+
+/// A log file that's moved aside to `<path>.1` once it reaches `max_size`,
+/// with older ones shuffled along to `<path>.2` and so on, up to `keep` of them
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    /// Open `path` to add to it
+    pub fn open(path: &Path, max_size: u64, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile { path: path.to_path_buf(), max_size, keep, file, size })
+    }
+
+    /// Add a line, rotating first if it would take the file past its limit
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let length = line.len() as u64 + 1;
+        if self.size > 0 && self.size + length > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += length;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n: usize| {
+            let mut path = self.path.as_os_str().to_os_string();
+            path.push(format!(".{}", n));
+            PathBuf::from(path)
+        };
+        if self.keep > 0 {
+            for n in (1..self.keep).rev() {
+                if rotated(n).exists() {
+                    fs::rename(rotated(n), rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+        self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Log a request that's about to be sent
+pub fn request(request: &Request) {
+    debug!("> {} {}", request.method(), request.url());
+    headers(">", request.headers());
+}
+
+/// Log the response to a request, before its body's read
+pub fn response(response: &Response) {
+    debug!(
+        "< {} {} ({:?}{})",
+        response.status(),
+        response.url(),
+        response.version(),
+        response.remote_addr().map(|addr| format!(" from {}", addr)).unwrap_or_default()
+    );
+    headers("<", response.headers());
+}
+
+fn headers(direction: &str, headers: &HeaderMap) {
+    if !log_enabled!(log::Level::Trace) {
+        return;
+    }
+    for (name, value) in headers {
+        match SENSITIVE_HEADERS.contains(name) {
+            true => trace!("{} {}: <redacted>", direction, name),
+            false => trace!("{} {}: {}", direction, name, String::from_utf8_lossy(value.as_bytes())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verbosity_levels() {
+        assert_eq!(level(0), LevelFilter::Error);
+        assert_eq!(level(1), LevelFilter::Info);
+        assert_eq!(level(2), LevelFilter::Debug);
+        assert_eq!(level(3), LevelFilter::Trace);
+        assert_eq!(level(10), LevelFilter::Trace);
+    }
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = std::env::temp_dir().join(format!("rustdl-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("download.log");
+        let mut file = RotatingFile::open(&path, 20, 2).unwrap();
+        for line in ["first line", "second line", "third line", "fourth line"] {
+            file.write_line(line).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth line\n");
+        assert_eq!(fs::read_to_string(dir.join("download.log.1")).unwrap(), "third line\n");
+        assert_eq!(fs::read_to_string(dir.join("download.log.2")).unwrap(), "second line\n");
+        assert!(!dir.join("download.log.3").exists());
+
+        // Reopening carries on where the file left off
+        let mut file = RotatingFile::open(&path, 20, 2).unwrap();
+        file.write_line("fifth").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth line\nfifth\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,315 +1,2538 @@
-use std::{fs::File, process::exit};
+use std::convert::Infallible;
+use std::fmt;
+use std::fs::{self, File};
+use std::ops::Deref;
+use std::str::FromStr;
+use std::process::exit;
 use std::sync::Arc;
-use std::io::copy;
-use std::thread::{self, JoinHandle};
+use std::io::BufReader;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
 
-use clap::Parser;
-use clap::crate_version;
+use clap::{Parser, Subcommand};
 use log::{debug, info, warn, error};
 
-use reqwest::header::{self};
+use indicatif::{HumanBytes, HumanDuration, ProgressStyle};
 
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-
-use url;
 use url::Url;
 
-use content_disposition::{parse_content_disposition, DispositionType};
+use rustdl::{api, batch, container, control_socket, daemon, device, glob, logging, recursive, retry_history, timeouts, timestamping, verify_remote, verify_tree};
+use rustdl::archive::Extraction;
+use rustdl::attestation::{AttestationKind, AttestationVerifier};
+use rustdl::auth_hook::AuthHook;
+use rustdl::cancel::{CancelPolicy, CancelReason, Cancellation};
+#[cfg(feature = "browser-cookies")]
+use rustdl::cookies::{self, validate_browser_argument, CookieJarWrapper};
+use rustdl::checksum::{Checksum, ChecksumSpec, HashAlgorithm};
+use rustdl::checksum_db::{ChecksumDatabase, ChecksumDbError};
+use rustdl::clock::{StartAt, TimeZone};
+#[cfg(feature = "keyring")]
+use rustdl::credentials::{Credential, CredentialError};
+use rustdl::credentials::CredentialStore;
+use rustdl::downloader::{build_client, ClientOptions, DownloadOptions, DownloaderBuilder};
+use rustdl::encrypt::Recipient;
+use rustdl::middleware::Middleware;
+use rustdl::naming::StandardNames;
+use rustdl::negotiate::Negotiator;
+use rustdl::netload::NetworkShare;
+use rustdl::api::Api;
+use rustdl::control_socket::{BatchControl, ControlSocket, Request, SocketError};
+use rustdl::daemon::{Control, Daemon, DaemonError, Progress, Queue, State};
+use rustdl::decompress::ContentDecoding;
+use rustdl::exec_hook::ExecHook;
+use rustdl::exit_code::{ExitCode, Failure};
+use rustdl::extractor::Registry;
+use rustdl::transport::{LocalFile, Transports};
+use rustdl::notify::{Notifier, SinkConfig};
+use rustdl::ntlm::{NtlmAuthenticator, NtlmCredentials};
+#[cfg(feature = "keyring")]
+use rustdl::oauth::{self, OAuthError, OAuthProvider, OAuthSettings};
+use rustdl::order::DownloadOrder;
+use rustdl::page::PageFormat;
+use rustdl::partial::PartError;
+use rustdl::pinning::PinSet;
+use rustdl::politeness::HostLimits;
+use rustdl::progress::{BarStyle, ProgressMode};
+use rustdl::report::{Report, Status};
+use rustdl::resolver::{AddressFamily, ConnectTo, ResolveOverride, Resolver};
+use rustdl::retry_after::RetryAfter;
+use rustdl::retry_history::{HistoryError, RetryHistory};
+use rustdl::range::ByteRange;
+use rustdl::recursive::Recursion;
+use rustdl::resume::{self, Resumer};
+use rustdl::session::Session;
+use rustdl::settings::Settings;
+use rustdl::spider::HeaderFormat;
+use rustdl::ssh_tunnel::SshTunnel;
+#[cfg(feature = "state-db")]
+use rustdl::state_db::{DownloadStatus, StateDb, StateError};
+use rustdl::template::OutputTemplate;
+use rustdl::throttle::{ByteRate, MinimumSpeed};
+use rustdl::timing::WriteOut;
+use rustdl::verify_remote::RemoteVerifier;
+use rustdl::verify_tree::{Manifest, VerifyTreeError};
+use rustdl::warc::WarcWriter;
+use rustdl::webhook::Webhook;
+
+/// A password or token given on the command line. The arguments are logged
+/// when we start, to the log file at every level, so it's left out of them.
+#[derive(Clone)]
+struct Secret(String);
+
+impl FromStr for Secret {
+    type Err = Infallible;
+
+    fn from_str(secret: &str) -> Result<Self, Self::Err> {
+        Ok(Secret(secret.to_string()))
+    }
+}
 
-mod browser;
-mod cookies;
+impl Deref for Secret {
+    type Target = str;
 
-use browser::{BrowserType, BrowserError, CookieManager};
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
 
-/// Validate and parse browser argument
-fn validate_browser_argument(browser_arg: Option<String>) -> Result<Option<BrowserType>, BrowserError> {
-    match browser_arg {
-        Some(browser_str) => {
-            match browser_str.parse::<BrowserType>() {
-                Ok(browser_type) => Ok(Some(browser_type)),
-                Err(e) => Err(e),
-            }
-        }
-        None => Ok(None),
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
     }
 }
 
 #[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
-    /// The URL to download from
-    #[arg(required = true)]
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// The URL to download from; {a,b,c} and [1-100] in it are expanded as curl does, and what they matched is {1}, {2}... in --output-template
+    #[arg(required_unless_present_any = ["feed", "continue_session"])]
     urls: Vec<String>,
+
+    /// Pick up the batch an interrupted run left in this session file, along with any URLs given; running the same command again does this by itself
+    #[arg(long, value_name = "FILE")]
+    continue_session: Option<PathBuf>,
+
+    /// Take URLs as they are, without expanding {} and []
+    #[arg(long, short = 'g')]
+    globoff: bool,
     
     /// Browser to use for cookies (chrome, firefox, safari, edge)
     #[arg(long, short, value_name = "BROWSER")]
     browser: Option<String>,
+
+    /// Verify the download against a checksum (e.g. sha256:<hex>), or give just an algorithm to print the digest; repeat or separate with commas for several, computed in one pass
+    #[arg(long, value_name = "ALGO[:HEX]", value_delimiter = ',')]
+    checksum: Vec<ChecksumSpec>,
+
+    /// Don't look up expected checksums in the known-good checksum database
+    #[arg(long)]
+    no_checksum_db: bool,
+
+    /// Only connect to servers whose public key matches this pin (sha256//<base64>, separate several with ';')
+    #[arg(long, value_name = "PINS")]
+    pinnedpubkey: Option<PinSet>,
+
+    /// Verify the download's provenance (github, cosign, slsa) using the matching external tool
+    #[arg(long, value_name = "KIND")]
+    verify_attestation: Option<AttestationKind>,
+
+    /// Repository the artifact must have been built from (github and slsa attestations)
+    #[arg(long, value_name = "OWNER/REPO")]
+    attestation_repo: Option<String>,
+
+    /// Attestation bundle or provenance to verify against; `{url}` is replaced with the download URL
+    #[arg(long, value_name = "PATH|URL")]
+    attestation_bundle: Option<String>,
+
+    /// Signer identity expected in the certificate (cosign attestations)
+    #[arg(long, value_name = "IDENTITY")]
+    certificate_identity: Option<String>,
+
+    /// OIDC issuer expected in the certificate (cosign attestations, defaults to GitHub Actions)
+    #[arg(long, value_name = "URL")]
+    certificate_oidc_issuer: Option<String>,
+
+    /// Give up connecting to a server after this long (e.g. 5s) [default: 10s]; requests that time out are retried, and 0 waits forever
+    #[arg(long, value_name = "DURATION", value_parser = timeouts::parse_duration)]
+    connect_timeout: Option<Duration>,
+
+    /// Give up on a server that sends nothing for this long, whether we're waiting for its answer or partway through the body [default: 30s]; 0 waits forever
+    #[arg(long, value_name = "DURATION", value_parser = timeouts::parse_duration)]
+    read_timeout: Option<Duration>,
+
+    /// Give up if the server hasn't sent response headers within this long (e.g. 10s), up to --read-timeout; doesn't limit the body
+    #[arg(long, value_name = "DURATION", value_parser = timeouts::parse_duration)]
+    header_timeout: Option<Duration>,
+
+    /// Longest to wait when a rate-limited server says when to come back (Retry-After on 429 and 503) before retrying [default: 5m]; 0 never waits, nor retries requests that time out
+    #[arg(long, value_name = "DURATION", value_parser = timeouts::parse_duration)]
+    retry_after_max: Option<Duration>,
+
+    /// Speak HTTP/2 from the first byte instead of negotiating it, for servers that only speak cleartext HTTP/2 (h2c)
+    #[arg(long, conflicts_with = "ntlm")]
+    http2_prior_knowledge: bool,
+
+    /// Ask for a compressed response (gzip, brotli, zstd) and save it decompressed
+    #[arg(long)]
+    compressed: bool,
+
+    /// Save exactly the bytes the server sends, without asking for or undoing any compression; for checksums of pre-compressed files
+    #[arg(long, conflicts_with = "compressed")]
+    no_decompress: bool,
+
+    /// Answer Negotiate (Kerberos/SPNEGO) challenges using tickets from the system credential cache
+    #[arg(long)]
+    negotiate: bool,
+
+    /// Answer NTLM challenges from legacy intranet servers, using the account given with --user
+    #[arg(long, requires = "user", conflicts_with_all = ["negotiate", "small_files"])]
+    ntlm: bool,
+
+    /// Account for --ntlm as DOMAIN\user:password (prompts for the password if it's left out)
+    #[arg(long, short, value_name = "DOMAIN\\USER[:PASSWORD]", requires = "ntlm")]
+    user: Option<Secret>,
+
+    /// Run this command before each request and add the headers it prints ("Name: value" lines), e.g. 'sign-request {method} {url}'
+    #[arg(long, value_name = "COMMAND")]
+    auth_cmd: Option<AuthHook>,
+
+    /// Optimise for many small files: parallel requests over shared connections, one progress bar for the batch
+    #[arg(long, group = "parallel", conflicts_with = "verify_attestation")]
+    small_files: bool,
+
+    /// Check the URLs without downloading anything: status, size, type and the file each would be saved as
+    #[arg(long, group = "parallel",
+          conflicts_with_all = ["output", "stdout", "tee", "save_page", "append", "warc", "verify_attestation", "ntlm"])]
+    spider: bool,
+
+    /// Print each URL's response status and headers instead of downloading it, as --headers=text (the default) or --headers=json
+    #[arg(long, short = 'I', value_name = "FORMAT", num_args = 0..=1, require_equals = true, default_missing_value = "text", group = "parallel",
+          conflicts_with_all = ["output", "stdout", "tee", "save_page", "append", "warc", "verify_attestation", "ntlm"])]
+    headers: Option<HeaderFormat>,
+
+    /// Follow links in HTML pages and download what they point to as well, staying on the same hosts
+    #[arg(long, short = 'r', group = "mirrored", conflicts_with_all = ["output", "stdout", "tee", "save_page", "mirror", "append", "range", "decompressed_checksum"])]
+    recursive: bool,
+
+    /// How many links deep --recursive goes (0 for no limit) [default: 5]
+    #[arg(long, short = 'l', value_name = "N", requires = "recursive")]
+    level: Option<usize>,
+
+    /// Only download files with these extensions when recursing, e.g. iso,zip (pages are still followed)
+    #[arg(long, short = 'A', value_name = "EXTENSIONS", value_delimiter = ',', requires = "recursive")]
+    accept: Vec<String>,
+
+    /// Don't download files with these extensions (or names) when recursing
+    #[arg(long, short = 'R', value_name = "EXTENSIONS", value_delimiter = ',', requires = "recursive")]
+    reject: Vec<String>,
+
+    /// Download page URLs as they are, instead of letting the built-in site extractors find the files behind them
+    #[arg(long)]
+    no_extractors: bool,
+
+    /// Copy file:// URLs given on the command line from the local filesystem, as though they were downloads
+    #[arg(long)]
+    allow_file_urls: bool,
+
+    /// Download what an RSS or Atom feed links to: each item's enclosures, or its link if it has none (repeat for several feeds)
+    #[arg(long, value_name = "URL", conflicts_with_all = ["output", "stdout", "tee", "save_page", "append", "range"])]
+    feed: Vec<String>,
+
+    /// Only download feed items published within DURATION (e.g. 7d)
+    #[arg(long, value_name = "DURATION", value_parser = timeouts::parse_duration, requires = "feed")]
+    newer_than: Option<Duration>,
+
+    /// Download everything the pages need to be shown too: images, stylesheets, scripts and fonts, from any host
+    #[arg(long, short = 'p', group = "mirrored", conflicts_with_all = ["output", "stdout", "tee", "save_page", "mirror", "append", "range", "decompressed_checksum"])]
+    page_requisites: bool,
+
+    /// Once downloaded, point links in the pages at the local copies of what they link to, so they work offline
+    #[arg(long, short = 'k', requires = "mirrored")]
+    convert_links: bool,
+
+    /// Show where each URL would be saved and the headers it would be requested with, without sending anything
+    #[arg(long, conflicts_with_all = ["spider", "headers", "save_page", "mirror", "warc", "ssh_tunnel", "recursive", "page_requisites", "continue_session", "feed"])]
+    dry_run: bool,
+
+    /// Number of parallel requests in small-file or spider mode [default: 8]
+    #[arg(long, short, value_name = "N", requires = "parallel")]
+    jobs: Option<usize>,
+
+    /// Save the download as FILE instead of a name from the URL or server (a single URL only; - for stdout)
+    #[arg(long, short = 'O', value_name = "FILE", conflicts_with_all = ["small_files", "save_page", "output_template", "auto_rename"])]
+    output: Option<PathBuf>,
+
+    /// Allow --output to be a block device (e.g. /dev/sdb), writing the image straight onto it and reading it back to verify
+    #[arg(long, requires = "output", conflicts_with_all = ["append", "range", "timestamping", "warc", "encrypt_to"])]
+    allow_block_device: bool,
+
+    /// Encrypt downloads with age (for age1... or SSH keys) or GPG (anything else) as they arrive, saving <file>.age or <file>.gpg
+    #[arg(long, value_name = "RECIPIENT",
+          conflicts_with_all = ["append", "small_files", "save_page", "timestamping", "warc", "verify_attestation"])]
+    encrypt_to: Option<Recipient>,
+
+    /// Write the download to stdout, e.g. to pipe it into tar (a single URL only); progress goes to stderr
+    #[arg(long, conflicts_with_all = ["output", "small_files", "save_page", "output_template", "append", "timestamping",
+          "no_clobber", "auto_rename", "warc", "encrypt_to", "verify_attestation"])]
+    stdout: bool,
+
+    /// Save the download and write it to stdout at the same time, to process it while keeping a copy (a single URL only)
+    #[arg(long, conflicts_with_all = ["stdout", "small_files", "save_page", "encrypt_to"])]
+    tee: bool,
+
+    /// Another server with the same file, to download parts of it from at the same time (a single URL only; repeat for more)
+    #[arg(long, value_name = "URL", conflicts_with_all = ["small_files", "save_page", "append", "range", "stdout", "tee",
+          "encrypt_to", "timestamping", "allow_block_device", "output_template", "warc"])]
+    mirror: Vec<String>,
+
+    /// Decompress .gz, .xz and .zst downloads once they're saved, dropping the extension (compressed tarballs are left alone)
+    #[arg(long, conflicts_with_all = ["stdout", "append", "range", "allow_block_device", "encrypt_to", "small_files", "save_page",
+          "mirror", "timestamping"])]
+    decompress: bool,
+
+    /// Extract .zip, .tar(.gz/.xz/.zst) and .7z downloads into a directory next to each, named after it
+    #[arg(long, conflicts_with_all = ["stdout", "append", "range", "allow_block_device", "encrypt_to", "small_files", "save_page", "mirror"])]
+    extract: bool,
+
+    /// Extract archives into this directory instead
+    #[arg(long, value_name = "DIR", requires = "extract")]
+    extract_to: Option<PathBuf>,
+
+    /// Delete each archive once it's been extracted
+    #[arg(long, requires = "extract")]
+    delete_archive: bool,
+
+    /// Stop extracting an archive once it's unpacked this much (e.g. 100G; default 16G)
+    #[arg(long, value_name = "SIZE", requires = "extract", value_parser = |s: &str| s.parse::<ByteRate>().map(|size| size.0))]
+    extract_max_size: Option<u64>,
+
+    /// Copy HLS streams into an mp4 or mkv file with ffmpeg once they're downloaded, instead of leaving them as .ts
+    #[arg(long, value_name = "FORMAT", value_parser = ["mp4", "mkv"], conflicts_with = "encrypt_to")]
+    remux: Option<String>,
+
+    /// Verify the decompressed contents against this checksum (e.g. sha256:<hex>), for checksums published for the uncompressed file
+    #[arg(long, value_name = "ALGO:HEX", requires = "decompress")]
+    decompressed_checksum: Option<Checksum>,
+
+    /// Directory to save downloads in (created if it doesn't exist)
+    #[arg(long, short = 'P', value_name = "DIR")]
+    output_dir: Option<PathBuf>,
+
+    /// Send every request through this proxy (http://, https:// or socks5://)
+    #[arg(long, value_name = "URL")]
+    proxy: Option<String>,
+
+    /// Connect to this Unix socket instead of the URL's host, for services like Docker that speak HTTP over one; URLs can then be just paths (/v1.43/info)
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["proxy", "mirror"])]
+    unix_socket: Option<PathBuf>,
+
+    /// Look HOST up as ADDR instead of asking DNS, like curl, e.g. to try a staging server under its real name; repeatable
+    #[arg(long, value_name = "HOST:PORT:ADDR[,ADDR...]", conflicts_with = "unix_socket")]
+    resolve: Vec<ResolveOverride>,
+
+    /// Connect to TARGET whenever HOST is asked for, still sending HOST's name in the request and TLS handshake; repeatable
+    #[arg(long, value_name = "HOST:PORT:TARGET:TARGET_PORT", conflicts_with = "unix_socket")]
+    connect_to: Vec<ConnectTo>,
+
+    /// Only connect over IPv4, for networks where IPv6 is configured but broken
+    #[arg(long = "ipv4", short = '4', global = true, conflicts_with = "ipv6")]
+    ipv4: bool,
+
+    /// Only connect over IPv6
+    #[arg(long = "ipv6", short = '6', global = true)]
+    ipv6: bool,
+
+    /// Reach the URLs through an SSH tunnel to this jump host (user@bastion), for servers only reachable from inside its network
+    #[arg(long, value_name = "DESTINATION", conflicts_with_all = ["proxy", "unix_socket"])]
+    ssh_tunnel: Option<String>,
+
+    /// Cap the combined download rate, in bytes per second (e.g. 500k, 2M)
+    #[arg(long, value_name = "RATE")]
+    limit_rate: Option<ByteRate>,
+
+    /// Keep downloads to this share of the network's capacity, less whatever else is using it (e.g. 70%), so they don't swamp video calls
+    #[arg(long, value_name = "PERCENT")]
+    auto_throttle: Option<NetworkShare>,
+
+    /// Give up on a download that stays slower than this many bytes per second (e.g. 1k) for --speed-time
+    #[arg(long, value_name = "RATE")]
+    speed_limit: Option<ByteRate>,
+
+    /// How long a download can stay under --speed-limit before it's given up on [default: 30s]
+    #[arg(long, value_name = "DURATION", value_parser = timeouts::parse_duration)]
+    speed_time: Option<Duration>,
+
+    /// Most downloads to have going from any one host at once; URLs for other hosts go ahead while it's busy
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    per_host_limit: Option<u64>,
+
+    /// Wait at least this long between requests to the same host (e.g. 500ms, 2s)
+    #[arg(long, value_name = "DURATION", value_parser = timeouts::parse_duration)]
+    per_host_delay: Option<Duration>,
+
+    /// Skip URLs whose output file already exists instead of overwriting it
+    #[arg(long, conflicts_with = "timestamping")]
+    no_clobber: bool,
+
+    /// Skip URLs that an earlier run already downloaded, as long as the file is still there, unchanged
+    #[arg(long)]
+    skip_downloaded: bool,
+
+    /// Save as file.1.ext, file.2.ext, ... instead of overwriting a file that already exists
+    #[arg(long, conflicts_with_all = ["no_clobber", "timestamping"])]
+    auto_rename: bool,
+
+    /// Use the server's suggested filename as it is, including any subdirectories, instead of sanitizing it
+    #[arg(long)]
+    trust_server_names: bool,
+
+    /// Only download files that are newer on the server than the local copy
+    #[arg(long, short = 'N')]
+    timestamping: bool,
+
+    /// Don't give downloaded files the server's Last-Modified time
+    #[arg(long, conflicts_with = "timestamping")]
+    no_preserve_time: bool,
+
+    /// Make the output the same on every run: files named in URL order and dated $SOURCE_DATE_EPOCH (or 1970), saved pages without today's date, the report sorted and without times
+    #[arg(long, conflicts_with_all = ["timestamping", "warc", "encrypt_to"])]
+    deterministic: bool,
+
+    /// Save HTML pages along with their stylesheets, images and scripts, as --save-page=directory (the default) or --save-page=mhtml
+    #[arg(long, value_name = "FORMAT", num_args = 0..=1, require_equals = true, default_missing_value = "directory",
+          conflicts_with_all = ["small_files", "verify_attestation", "timestamping", "ntlm", "negotiate"])]
+    save_page: Option<PageFormat>,
+
+    /// Carry on writing existing files from their current size, using range requests and no control files
+    #[arg(long, conflicts_with_all = ["range", "timestamping", "no_clobber", "auto_rename", "small_files", "save_page", "verify_attestation"])]
+    append: bool,
+
+    /// Where to save each file in the output directory, e.g. "{host}/{filename}"; variables are {host}, {path}, {filename}, {ext}, {date}, {index}, and {1}, {2}... for what the URL's {} and [] patterns matched
+    #[arg(long, value_name = "TEMPLATE", conflicts_with = "save_page")]
+    output_template: Option<OutputTemplate>,
+
+    /// Download only this part of each file, e.g. 100M-200M (end exclusive) or 1G- for the rest, saved as <file>.part-<RANGE>
+    #[arg(long, value_name = "RANGE", conflicts_with_all = ["small_files", "save_page", "timestamping", "verify_attestation"])]
+    range: Option<ByteRange>,
+
+    /// Append WARC request/response records for everything fetched to this file, for web-archive tools
+    #[arg(long, value_name = "FILE")]
+    warc: Option<PathBuf>,
+
+    /// Write a JSON summary of every URL's outcome here once the run is done (- for stdout)
+    #[arg(long, value_name = "FILE")]
+    report_json: Option<PathBuf>,
+
+    /// Add a row for each URL to this CSV file (url, file, status, bytes, seconds, sha256), keeping what's already there
+    #[arg(long, value_name = "FILE")]
+    report_csv: Option<PathBuf>,
+
+    /// Print this for each URL once it's done, with %{url}, %{file}, %{status}, %{http_code}, %{size}, %{speed}, %{dns}, %{connect}, %{ttfb} and %{total} filled in, e.g. "%{url} %{ttfb} %{total}\n"
+    #[arg(long, short = 'w', value_name = "TEMPLATE")]
+    write_out: Option<WriteOut>,
+
+    /// Run this shell command before each file is downloaded, with {} (or {path}) and {url} filled in, e.g. 'check-quota {url}'; if it fails, the file isn't downloaded
+    #[arg(long, value_name = "COMMAND", conflicts_with_all = ["small_files", "spider", "stdout"])]
+    exec_before: Option<ExecHook>,
+
+    /// Run this shell command for each URL once it's done, with {} (or {path}), {url}, {status} and {checksum} filled in, e.g. 'tar -xf {}'
+    #[arg(long, value_name = "COMMAND")]
+    exec_after: Option<ExecHook>,
+
+    /// POST a JSON payload to this URL as each URL finishes, and a summary of the run at the end
+    #[arg(long, value_name = "URL")]
+    webhook: Option<Url>,
+
+    /// Sign webhook payloads with HMAC-SHA256 using this secret, in an X-Download-Signature-256 header
+    #[arg(long, value_name = "SECRET", requires = "webhook")]
+    webhook_secret: Option<Secret>,
+
+    /// Send this for each URL instead of its report entry, with --write-out's %{...} variables, e.g. '{"text": "%{status}: %{url}"}'
+    #[arg(long, value_name = "TEMPLATE", requires = "webhook")]
+    webhook_template: Option<WriteOut>,
+
+    /// Exit with the failure's own status when some URLs fail, even if others were downloaded (instead of 8)
+    #[arg(long)]
+    strict: bool,
+
+    /// Stop at the first URL that fails, cancelling downloads in progress and skipping the rest
+    #[arg(long, overrides_with = "keep_going")]
+    fail_fast: bool,
+
+    /// Carry on with the other URLs when one fails (the default)
+    #[arg(long, overrides_with = "fail_fast")]
+    keep_going: bool,
+
+    /// Pop up a desktop notification when the downloads finish or one fails, as well as any configured in [notify]
+    #[arg(long)]
+    notify: bool,
+
+    /// Wait until this time of day (02:00) or RFC 3339 date and time (2026-03-01T02:00:00+01:00) to start
+    #[arg(long, value_name = "TIME")]
+    start_at: Option<StartAt>,
+
+    /// Wait this long (3h, 90m) to start
+    #[arg(long, value_name = "DURATION", value_parser = timeouts::parse_duration, conflicts_with = "start_at")]
+    start_in: Option<Duration>,
+
+    /// Order to download URLs in: fifo, smallest-first, largest-first or random (sizes come from HEAD requests)
+    #[arg(long, value_name = "ORDER", default_value_t = DownloadOrder::Fifo)]
+    order: DownloadOrder,
+
+    /// Don't show progress or per-file messages, only errors
+    #[arg(long, short, global = true)]
+    quiet: bool,
+
+    /// Log more about what's going on: -v for progress, -vv for requests and responses, -vvv for their headers
+    #[arg(long, short, action = clap::ArgAction::Count, global = true, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Print one line per file when it's done instead of progress bars, for cron jobs and CI logs
+    #[arg(long, global = true, conflicts_with = "quiet")]
+    no_progress: bool,
+
+    /// How to show progress: bars, plain, quiet, or json for one JSON event per line on stdout
+    #[arg(long, value_name = "MODE", global = true, conflicts_with_all = ["quiet", "no_progress"])]
+    progress: Option<ProgressMode>,
+
+    /// What to do with a partial download when it's cancelled (Ctrl-C or --deadline): keep-part, delete or ask
+    #[arg(long, value_name = "POLICY", global = true, default_value_t = CancelPolicy::KeepPart)]
+    on_cancel: CancelPolicy,
+
+    /// Delete partial downloads when cancelled instead of keeping them to resume; the same as --on-cancel delete
+    #[arg(long, global = true, conflicts_with = "on_cancel")]
+    no_keep_partial: bool,
+
+    /// Don't remember downloads in the state database, for --skip-downloaded, `download history` and `download resume --all`
+    #[arg(long, global = true)]
+    no_state: bool,
+
+    /// Ignore the config file, taking configuration from DOWNLOAD_* environment variables alone
+    #[arg(long, global = true)]
+    no_config: bool,
+
+    /// Stop whatever's still downloading once the run has taken this long (e.g. 30m, 2h)
+    #[arg(long, value_name = "DURATION", global = true, value_parser = timeouts::parse_duration)]
+    deadline: Option<Duration>,
+
+    /// Template for download progress bars, e.g. "{prefix} {bar:40} {bytes}/{total_bytes} {eta}"
+    #[arg(long, value_name = "TEMPLATE", global = true)]
+    progress_template: Option<String>,
+
+    /// Characters progress bars are drawn with: filled, (optionally) the tip, and empty, e.g. "#>-"
+    #[arg(long, value_name = "CHARS", global = true)]
+    progress_chars: Option<String>,
+
+    /// Show and read times in UTC instead of the local timezone
+    #[arg(long, global = true)]
+    utc: bool,
+
+    /// Also log everything (requests, responses, cookies) to this file as JSON lines, whatever -v says
+    #[arg(long, value_name = "FILE", global = true)]
+    log_file: Option<PathBuf>,
+
+    /// Rotate the log file once it's this many MiB
+    #[arg(long, value_name = "MIB", global = true, default_value_t = 10, requires = "log_file", value_parser = clap::value_parser!(u64).range(1..))]
+    log_max_size: u64,
+
+    /// How many rotated log files to keep
+    #[arg(long, value_name = "N", global = true, default_value_t = 5, requires = "log_file")]
+    log_keep: usize,
 }
 
-fn download_file<'a>(urls: Vec<String>, browser_type: Option<BrowserType>) -> Result<(), Box<dyn std::error::Error>> {
-    debug!("Starting download_file with {} URLs and browser type: {:?}", urls.len(), browser_type);
-    let mut failed_download = false;
-
-    // Create CookieManager based on browser selection
-    let _cookie_manager = match browser_type {
-        Some(browser) => {
-            info!("User specified browser: {}", browser);
-            // User specified a browser, try to use it
-            match CookieManager::new(browser.clone()) {
-                Ok(manager) => {
-                    info!("Successfully created CookieManager with {} browser", manager.browser_name());
-                    debug!("Using {} browser for cookies", manager.browser_name());
-                    Some(manager)
-                }
-                Err(e) => {
-                    warn!("Failed to create CookieManager with {}: {}", browser, e.brief_message());
-                    eprintln!("Warning: {}", e.user_friendly_message());
-                    eprintln!("Falling back to auto-detection...");
-                    match CookieManager::with_auto_detection() {
-                        Ok(manager) => {
-                            info!("Fallback auto-detection successful: {}", manager.browser_name());
-                            debug!("Using {} browser for cookies", manager.browser_name());
-                            Some(manager)
-                        }
-                        Err(fallback_err) => {
-                            warn!("Fallback auto-detection failed: {}", fallback_err.brief_message());
-                            eprintln!("Warning: {}", fallback_err.user_friendly_message());
-                            None
-                        }
-                    }
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage the database of known-good checksums
+    #[command(subcommand)]
+    Checksums(ChecksumsCommand),
+    /// Manage credentials stored in the system keyring
+    #[cfg(feature = "keyring")]
+    #[command(subcommand)]
+    Auth(AuthCommand),
+    /// Finish interrupted downloads from their .part files, wherever they've been moved to
+    Resume {
+        /// .part files, their control files, or directories containing them (defaults to the current directory)
+        paths: Vec<PathBuf>,
+        /// Put a job the daemon paused back in its queue instead
+        #[arg(long = "job", value_name = "ID", conflicts_with = "paths")]
+        jobs: Vec<u64>,
+        /// Every download `download history --unfinished` lists whose .part file is still there
+        #[arg(long, conflicts_with_all = ["paths", "jobs"])]
+        all: bool,
+    },
+    /// Check files that are already downloaded against a manifest of their checksums
+    VerifyTree {
+        /// Directory the manifest's paths are relative to
+        dir: PathBuf,
+        /// JSON manifest mapping each file's path to its checksum(s)
+        #[arg(long, value_name = "FILE")]
+        manifest: PathBuf,
+        /// Files to hash at once (defaults to the number of CPUs)
+        #[arg(long, short, value_name = "N")]
+        jobs: Option<usize>,
+    },
+    /// Check downloaded files against the servers they came from, without downloading them again
+    Verify {
+        /// URLs the files were downloaded from, or files listing them one a line
+        #[arg(required = true, value_name = "URL_OR_LIST")]
+        urls: Vec<String>,
+        /// Directory the files were saved in
+        #[arg(long, short = 'P', value_name = "DIR")]
+        output_dir: Option<PathBuf>,
+        /// Files to check at once [default: 8]
+        #[arg(long, short, value_name = "N")]
+        jobs: Option<usize>,
+    },
+    /// Summarize the retries every run has needed, to see which hosts are flaky
+    Stats {
+        /// List hosts by how many retries they needed, the most first
+        #[arg(long)]
+        flaky: bool,
+        /// Only count retries this recent (e.g. 7d, 12h)
+        #[arg(long, value_name = "DURATION", value_parser = timeouts::parse_duration)]
+        since: Option<Duration>,
+    },
+    /// Download the URLs queued with `download add`, a few at a time, until stopped
+    Daemon {
+        /// Downloads to run at once [default: 4]
+        #[arg(long, short, value_name = "N")]
+        jobs: Option<usize>,
+        /// Serve an HTTP API for adding, pausing and cancelling jobs [default address: 127.0.0.1:7390]
+        #[arg(long, value_name = "ADDR", num_args = 0..=1, require_equals = true, default_missing_value = api::DEFAULT_ADDRESS)]
+        api: Option<String>,
+        /// Bearer token the API requires with every request
+        #[arg(long, value_name = "TOKEN", requires = "api")]
+        api_token: Option<Secret>,
+    },
+    /// Queue URLs for the daemon to download
+    Add {
+        #[arg(required = true, value_name = "URL")]
+        urls: Vec<String>,
+        /// Directory to save them in (defaults to the current directory)
+        #[arg(long, short = 'P', value_name = "DIR")]
+        output_dir: Option<PathBuf>,
+        /// Don't start them before this time of day (02:00) or RFC 3339 date and time
+        #[arg(long, value_name = "TIME")]
+        start_at: Option<StartAt>,
+        /// Don't start them for this long (3h, 90m)
+        #[arg(long, value_name = "DURATION", value_parser = timeouts::parse_duration, conflicts_with = "start_at")]
+        start_in: Option<Duration>,
+    },
+    /// Show the daemon's jobs and how far they've got
+    Status {
+        /// Only this job
+        id: Option<u64>,
+    },
+    /// Stop jobs until they're resumed with `download resume --job`, keeping what they've downloaded
+    Pause {
+        #[arg(required = true, value_name = "ID")]
+        ids: Vec<u64>,
+    },
+    /// List downloads from every run, the latest first
+    #[cfg(feature = "state-db")]
+    History {
+        /// How many to list
+        #[arg(long, short = 'n', value_name = "N", default_value_t = 20)]
+        limit: usize,
+        /// Only the latest download of each file that didn't finish
+        #[arg(long)]
+        unfinished: bool,
+    },
+    /// Stop jobs for good, deleting what they've downloaded
+    Cancel {
+        #[arg(required = true, value_name = "ID")]
+        ids: Vec<u64>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ChecksumsCommand {
+    /// List the known checksums
+    List,
+    /// Record the expected checksum for a URL (`*` matches anything)
+    Add {
+        /// URL or URL pattern, e.g. https://example.com/releases/*.iso
+        pattern: String,
+        /// Expected checksum, e.g. sha256:<hex>
+        checksum: Checksum,
+    },
+    /// Forget the checksums recorded for a URL pattern
+    Remove {
+        pattern: String,
+    },
+    /// Import a vendor-provided checksum list such as SHA256SUMS
+    Import {
+        /// Checksum list in sha256sum or BSD format
+        file: PathBuf,
+        /// URL the filenames in the list are relative to
+        #[arg(long, value_name = "URL")]
+        base_url: String,
+        /// Hash algorithm, if it can't be guessed from the list
+        #[arg(long, value_name = "ALGO")]
+        algorithm: Option<HashAlgorithm>,
+    },
+}
+
+#[cfg(feature = "keyring")]
+#[derive(Subcommand, Debug)]
+enum AuthCommand {
+    /// List the hosts we have credentials for
+    List,
+    /// Save credentials for a host; you'll be prompted for the password or token
+    Add {
+        /// Host name, optionally with a port (e.g. example.com:8443); they're only sent over HTTPS unless it's given as http://example.com
+        host: String,
+        /// Username for basic authentication
+        #[arg(long, short, required_unless_present = "bearer", conflicts_with = "bearer")]
+        user: Option<String>,
+        /// Store a bearer token instead of a username and password
+        #[arg(long)]
+        bearer: bool,
+        /// Read the password or token from standard input instead of prompting
+        #[arg(long)]
+        secret_stdin: bool,
+    },
+    /// Forget the credentials for a host
+    Remove {
+        host: String,
+    },
+    /// Log in to an OAuth provider (github, google, or one from config.toml) with a device code
+    Login {
+        provider: String,
+        /// OAuth client ID to use instead of the one in config.toml
+        #[arg(long, value_name = "ID")]
+        client_id: Option<String>,
+    },
+    /// Forget an OAuth provider's tokens
+    Logout {
+        provider: String,
+    },
+}
+
+#[cfg(feature = "keyring")]
+fn run_auth_command(command: AuthCommand, mut oauth_settings: OAuthSettings, family: Option<AddressFamily>) -> Result<(), OAuthError> {
+    let mut store = CredentialStore::open_default()?;
+    match command {
+        AuthCommand::List => {
+            for entry in store.entries() {
+                match (&entry.username, &entry.provider) {
+                    (Some(detail), _) | (None, Some(detail)) => println!("{}  {} ({})", entry.host, entry.scheme, detail),
+                    (None, None) => println!("{}  {}", entry.host, entry.scheme),
                 }
             }
         }
-        None => {
-            debug!("No browser specified, using fallback with Firefox preference");
-            // No browser specified, use auto-detection for backward compatibility
-            // Default to Firefox first for backward compatibility, then auto-detect
-            match CookieManager::with_fallback(Some(BrowserType::Firefox)) {
-                Ok(manager) => {
-                    info!("Fallback CookieManager created with: {}", manager.browser_name());
-                    debug!("Using {} browser for cookies", manager.browser_name());
-                    Some(manager)
-                }
-                Err(e) => {
-                    warn!("Fallback CookieManager creation failed: {}", e.brief_message());
-                    None
-                }
+        AuthCommand::Add { host, user, bearer: _, secret_stdin } => {
+            let prompt = if user.is_some() { "Password: " } else { "Token: " };
+            let secret = if secret_stdin {
+                let mut line = String::new();
+                std::io::stdin().read_line(&mut line).map(|_| line.trim_end_matches(['\r', '\n']).to_string())
+            } else {
+                rpassword::prompt_password(prompt)
+            }
+            .map_err(|source| CredentialError::Io { path: PathBuf::from("<stdin>"), source })?;
+            let credential = match user {
+                Some(username) => Credential::Basic { username, password: secret },
+                None => Credential::Bearer { token: secret },
+            };
+            store.add(&host, &credential)?;
+            store.save()?;
+        }
+        AuthCommand::Remove { host } => {
+            if !store.remove(&host)? {
+                eprintln!("No credentials stored for {}", host);
             }
+            store.save()?;
         }
+        AuthCommand::Login { provider, client_id } => {
+            if let Some(client_id) = client_id {
+                oauth_settings.providers.entry(provider.to_lowercase()).or_default().client_id = Some(client_id);
+            }
+            let provider = OAuthProvider::resolve(&provider, &oauth_settings)?;
+            let client = build_client(&ClientOptions { resolver: Resolver::default().only(family), ..Default::default() })
+                .map_err(|source| OAuthError::Http { provider: provider.name.clone(), source })?;
+            oauth::login(&client, &provider, &mut store, |authorization| {
+                eprintln!(
+                    "To log in to {}, open {} and enter the code {}",
+                    provider.name, authorization.verification_uri, authorization.user_code
+                );
+            })?;
+            store.save()?;
+            eprintln!("Logged in to {}; its token will be used for {}", provider.name, provider.hosts.join(", "));
+        }
+        AuthCommand::Logout { provider } => {
+            if store.logout(&provider.to_lowercase())? == 0 {
+                eprintln!("Not logged in to {}", provider);
+            }
+            store.save()?;
+        }
+    }
+    Ok(())
+}
+
+/// How requests get to servers, from the flags and settings a download's
+/// would, so subcommands' requests go out the same way downloads do
+struct Connection {
+    #[cfg(feature = "browser-cookies")]
+    cookie_store: Option<Arc<CookieJarWrapper>>,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    header_timeout: Option<Duration>,
+    http2_prior_knowledge: bool,
+    proxy: Option<String>,
+    unix_socket: Option<PathBuf>,
+    resolver: Resolver,
+}
+
+impl Connection {
+    fn from_cli(args: &Cli, settings: &Settings, family: Option<AddressFamily>) -> Self {
+        Self {
+            #[cfg(feature = "browser-cookies")]
+            cookie_store: None,
+            connect_timeout: args.connect_timeout,
+            read_timeout: args.read_timeout,
+            header_timeout: args.header_timeout,
+            http2_prior_knowledge: args.http2_prior_knowledge,
+            // The socket is the only way out, so a configured proxy doesn't apply
+            proxy: args.proxy.clone().or(settings.proxy.clone()).filter(|_| args.unix_socket.is_none()),
+            unix_socket: args.unix_socket.clone(),
+            resolver: Resolver::new(&args.resolve, &args.connect_to).only(family),
+        }
+    }
+
+    /// Options for a client connecting this way
+    fn client_options(&self) -> ClientOptions<'_> {
+        ClientOptions {
+            #[cfg(feature = "browser-cookies")]
+            cookie_store: self.cookie_store.as_ref(),
+            connect_timeout: self.connect_timeout,
+            read_timeout: self.read_timeout,
+            header_timeout: self.header_timeout,
+            http2_prior_knowledge: self.http2_prior_knowledge,
+            proxy: self.proxy.as_deref(),
+            unix_socket: self.unix_socket.as_deref(),
+            resolver: self.resolver.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// `client` and `limit_rate` come from the same flags a download's would, so
+/// a part is finished through the same proxy, resolver and cookies it was started with
+#[allow(clippy::too_many_arguments)]
+fn run_resume_command(paths: Vec<PathBuf>, all: bool, client: &ClientOptions, limit_rate: Option<ByteRate>, settings: &Settings, progress: ProgressMode, bar_style: &BarStyle, cancellation: &Cancellation) -> Result<(), PartError> {
+    #[cfg(feature = "state-db")]
+    let state = open_state(settings);
+    #[cfg(feature = "state-db")]
+    let parts = resume::find(paths, all, state.as_deref())?;
+    #[cfg(not(feature = "state-db"))]
+    let parts = if all {
+        warn!("This build doesn't keep download state, so there are no unfinished downloads to resume");
+        Vec::new()
+    } else {
+        resume::find(paths)?
+    };
+    if parts.is_empty() {
+        eprintln!("No partial downloads found");
+        return Ok(());
+    }
+    let resumer = Resumer {
+        client,
+        middleware: &Middleware::standard(),
+        limit_rate,
+        oauth: &settings.oauth,
+        #[cfg(feature = "state-db")]
+        state: state.as_ref(),
+        progress,
+        bar_style,
+        cancellation,
     };
+    let result = resumer.resume(parts);
+    cancellation.ask_about_kept();
+    result
+}
 
-    // Set our progress bar components
-    let style = ProgressStyle::with_template("{prefix:.blue} {wide_bar:.blue/white} {percent}% • {bytes:.green}/{total_bytes:.green} • {binary_bytes_per_sec:>11.red} • eta {eta:>5.cyan}  ")
-    .unwrap()
-    .progress_chars("━╸━");
+/// The database every download is remembered in, unless that's been turned off
+#[cfg(feature = "state-db")]
+fn open_state(settings: &Settings) -> Option<Arc<StateDb>> {
+    if settings.state == Some(false) {
+        debug!("Not keeping download state");
+        return None;
+    }
+    StateDb::open_default()
+        .inspect_err(|e| warn!("Not keeping download state: {}", e))
+        .ok()
+        .map(Arc::new)
+}
 
-    let finish_style = ProgressStyle::with_template("{prefix:.blue} {wide_bar:.blue/white} {percent}% • {total_bytes:.green} • {binary_bytes_per_sec:>11.red} • elapsed {elapsed:>4.cyan}  ")
-    .unwrap()
-    .progress_chars("━╸━");
+fn run_daemon_command(jobs: Option<usize>, api: Option<String>, api_token: Option<Secret>, settings: &Settings, cancellation: &Cancellation) -> Result<(), DaemonError> {
+    let control = Control::new(Queue::new(Queue::default_path()?));
+    let program = std::env::current_exe().map_err(|source| DaemonError::Spawn { program: "download".to_string(), source })?;
+    let jobs = jobs.or(settings.jobs).unwrap_or(daemon::DEFAULT_JOBS);
+    if let Some(address) = api {
+        let dir = std::env::current_dir().map_err(|source| DaemonError::Io { path: PathBuf::from("."), source })?;
+        let listener = TcpListener::bind(&address).map_err(|source| DaemonError::Listen { address, source })?;
+        let api = Api::new(control.clone(), dir);
+        let api = match api_token {
+            Some(token) => api.token(token.0),
+            None => api,
+        };
+        thread::spawn(move || api.serve(listener));
+    }
+    #[cfg(any(unix, windows))]
+    let socket = {
+        let path = control_socket::default_path()?;
+        let listener = ControlSocket::bind(&path).map_err(|source| DaemonError::Listen { address: path.display().to_string(), source })?;
+        let socket = ControlSocket::new(control.clone());
+        thread::spawn(move || socket.serve(listener));
+        path
+    };
+    info!("Downloading queued URLs, {} at a time", jobs);
+    let result = Daemon { control: &control, jobs, program, cancellation }.run();
+    #[cfg(unix)]
+    let _ = fs::remove_file(socket);
+    #[cfg(windows)]
+    drop(socket);
+    result
+}
 
+fn run_add_command(urls: Vec<String>, output_dir: Option<PathBuf>, start_at: Option<SystemTime>, timezone: TimeZone) -> Result<(), DaemonError> {
+    let queue = Queue::new(Queue::default_path()?);
+    // The daemon doesn't run where we do, so it needs the whole path
+    let dir = std::path::absolute(output_dir.unwrap_or_else(|| PathBuf::from("."))).map_err(|source| DaemonError::Io { path: PathBuf::from("."), source })?;
+    if let Some(start) = start_at {
+        eprintln!("Starting at {}", timezone.format(start));
+    }
+    for url in urls {
+        let id = queue.add(&url, &dir, start_at)?;
+        println!("{}\t{}", id, url);
+    }
+    Ok(())
+}
 
-    let mut headers = header::HeaderMap::new();
-    let user_agent = format!("rust-downloader/{} (https://github.com/danudey/rust-downloader)", crate_version!()).into_bytes();
-    headers.insert(header::ACCEPT, header::HeaderValue::from_static("*/*"));
-    headers.insert(header::USER_AGENT, header::HeaderValue::from_bytes(&user_agent).unwrap());
-    
-    let errstyle = ProgressStyle::with_template("{prefix:.red} [error] {msg:} ").unwrap();
-    let multiprog = Arc::new(MultiProgress::new());
-    let mut handles: Vec<JoinHandle<_>> = vec![];
+fn run_status_command(id: Option<u64>) -> Result<(), SocketError> {
+    let jobs = control_socket::send(&control_socket::paths()?, Queue::new(Queue::default_path()?), Request::Status { id })?;
+    println!("{:>5}  {:<9} {:>12}  URL", "ID", "STATE", "PROGRESS");
+    for job in jobs {
+        let waiting = job.start_at.and_then(|start| SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(start))).and_then(|start| start.duration_since(SystemTime::now()).ok());
+        let progress = match (job.progress, job.bytes) {
+            (Some(Progress { bytes, total: Some(total), .. }), _) if total > 0 => format!("{:.0}%", bytes as f64 * 100.0 / total as f64),
+            (Some(Progress { bytes, .. }), _) | (None, Some(bytes)) => HumanBytes(bytes).to_string(),
+            (None, None) => match waiting {
+                Some(wait) if job.state == State::Queued => format!("in {}", HumanDuration(wait)),
+                _ => String::new(),
+            },
+        };
+        println!("{:>5}  {:<9} {:>12}  {}", job.id, job.state.as_str(), progress, job.url);
+        if let Some(error) = job.error {
+            println!("{:>5}  {}", "", error);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "state-db")]
+fn run_history_command(limit: usize, unfinished: bool, timezone: TimeZone) -> Result<(), StateError> {
+    let state = StateDb::open_default()?;
+    let downloads = if unfinished { state.unfinished()? } else { state.history(limit)? };
+    for download in downloads {
+        let size = match download.status {
+            DownloadStatus::Downloading if download.offset == 0 => String::new(),
+            _ => HumanBytes(download.offset).to_string(),
+        };
+        let path = download.path.map(|path| format!(" -> {}", path.display())).unwrap_or_default();
+        println!("{}  {:<11} {:>10}  {}{}", timezone.format(download.updated), download.status.as_str(), size, download.url, path);
+        if let Some(error) = download.error {
+            println!("    {}", error);
+        }
+    }
+    Ok(())
+}
+
+/// Pause, resume or cancel jobs
+fn run_job_command(requests: Vec<Request>) -> Result<(), SocketError> {
+    let (sockets, queue) = (control_socket::paths()?, Queue::new(Queue::default_path()?));
+    for request in requests {
+        for job in control_socket::send(&sockets, queue.clone(), request)? {
+            println!("{}\t{}", job.id, job.state.as_str());
+        }
+    }
+    Ok(())
+}
 
-    // Use the CookieManager that was created earlier in the function
-    let cookie_store = match _cookie_manager {
-        Some(cookie_manager) => {
-            let cookiejar_wrapper = cookies::CookieJarWrapper::new(cookie_manager);
-            Some(std::sync::Arc::new(cookiejar_wrapper))
+fn run_verify_tree_command(dir: PathBuf, manifest: PathBuf, jobs: Option<usize>, progress: ProgressMode, bar_style: &BarStyle) -> Result<(), VerifyTreeError> {
+    let manifest = Manifest::load(&manifest)?;
+    let jobs = jobs.unwrap_or_else(|| thread::available_parallelism().map_or(1, |cpus| cpus.get()));
+    let pb = progress.bar(0).with_style(bar_style.downloading()).with_prefix(dir.display().to_string());
+    match verify_tree::verify(&dir, &manifest, jobs, progress, &pb) {
+        Ok(_) => {
+            progress.finish(&pb, None, bar_style.finished(), format!("{} files verified", manifest.files.len()));
+            Ok(())
         }
-        None => {
-            // No cookie manager available, continue without cookies
-            None
+        Err(e) => {
+            progress.fail(&pb, None, bar_style.finished(), e.to_string());
+            Err(e)
         }
+    }
+}
+
+fn run_verify_command(urls: Vec<String>, output_dir: Option<PathBuf>, jobs: Option<usize>, client: &ClientOptions, settings: &Settings, progress: ProgressMode, bar_style: &BarStyle) -> Result<(), Failure> {
+    let urls = verify_remote::read_urls(&urls).map_err(|e| Failure::new(ExitCode::Usage, e.to_string()))?;
+    let header_timeout = client.header_timeout.or(Some(timeouts::DEFAULT_IO_TIMEOUT));
+    let client = build_client(client).map_err(|e| Failure::from(e.to_string()))?;
+    let credentials = CredentialStore::open_saved();
+    let checksum_db = ChecksumDatabase::open_default()
+        .inspect_err(|e| warn!("Not verifying against known checksums: {}", e))
+        .ok();
+    let output_dir = output_dir.or_else(|| settings.output_dir.clone()).unwrap_or_default();
+    let verifier = RemoteVerifier {
+        client: &client,
+        middleware: &Middleware::standard(),
+        credentials: credentials.as_ref(),
+        oauth: &settings.oauth,
+        checksum_db: checksum_db.as_ref(),
+        header_timeout,
+        naming: &StandardNames::new(false, output_dir.clone(), None, false),
     };
+    let pb = progress.bar(0).with_style(bar_style.downloading()).with_prefix(output_dir.display().to_string());
+    match verifier.verify(&urls, jobs.or(settings.jobs).unwrap_or(batch::DEFAULT_JOBS), progress, &pb) {
+        Ok(()) => {
+            progress.finish(&pb, None, bar_style.finished(), format!("{} files verified", urls.len()));
+            Ok(())
+        }
+        Err(e) => {
+            progress.fail(&pb, None, bar_style.finished(), e.to_string());
+            Err(Failure::new(ExitCode::Checksum, e.to_string()))
+        }
+    }
+}
 
-    for url in urls {
-        // Parse our URL out so we can get a destination filename
-        let parsed_url  = Url::parse(&url)?;
-        let path_segments = parsed_url.path_segments().ok_or_else(|| "cannot be base")?;
-        let url_filename = path_segments.last().ok_or_else(|| "I don't even know what's going on")?;
-
-        let client = match &cookie_store {
-            Some(store) => {
-                reqwest::blocking::Client::builder()
-                    .cookie_provider(std::sync::Arc::clone(store))
-                    .connection_verbose(true)
-                    .build()
-                    .unwrap()
+fn run_checksums_command(command: ChecksumsCommand) -> Result<(), ChecksumDbError> {
+    let mut database = ChecksumDatabase::open_default()?;
+    match command {
+        ChecksumsCommand::List => {
+            for entry in database.entries() {
+                println!("{}  {}", entry.checksum, entry.pattern);
             }
-            None => {
-                reqwest::blocking::Client::builder()
-                .connection_verbose(true)
-                    .build()
-                    .unwrap()
+        }
+        ChecksumsCommand::Add { pattern, checksum } => {
+            database.add(&pattern, checksum);
+            database.save()?;
+        }
+        ChecksumsCommand::Remove { pattern } => {
+            if database.remove(&pattern) == 0 {
+                eprintln!("No checksums recorded for {}", pattern);
             }
-        };
+            database.save()?;
+        }
+        ChecksumsCommand::Import { file, base_url, algorithm } => {
+            let reader = File::open(&file)
+                .map(BufReader::new)
+                .map_err(|source| ChecksumDbError::Io { path: file.clone(), source })?;
+            let imported = database.import(reader, &base_url, algorithm)?;
+            database.save()?;
+            println!("Imported {} checksums into {}", imported, database.path().display());
+        }
+    }
+    Ok(())
+}
 
-        let headers = headers.clone();
+fn run_stats_command(flaky: bool, since: Option<Duration>, timezone: TimeZone) -> Result<(), HistoryError> {
+    let records = RetryHistory::new(RetryHistory::default_path()?).load()?;
+    // A while longer than the clock has been running is all of it
+    let since = since.and_then(|since| SystemTime::now().checked_sub(since));
+    let hosts = retry_history::flaky(&records, since);
+    if flaky {
+        println!("{:<40} {:>7} {:>5} {:>10}  LAST", "HOST", "RETRIES", "URLS", "WAITED");
+        for host in hosts {
+            let waited = humantime::format_duration(Duration::from_secs(host.waited.round() as u64));
+            println!("{:<40} {:>7} {:>5} {:>10}  {}", host.host, host.retries, host.urls, waited.to_string(), timezone.format(host.last));
+        }
+        return Ok(());
+    }
+    let retries: usize = hosts.iter().map(|host| host.retries).sum();
+    let urls: usize = hosts.iter().map(|host| host.urls).sum();
+    let waited = Duration::from_secs(hosts.iter().map(|host| host.waited).sum::<f64>().round() as u64);
+    println!("{} retries of {} URLs on {} hosts, {} spent waiting", retries, urls, hosts.len(), humantime::format_duration(waited));
+    for (reason, count) in retry_history::reasons(&records, since) {
+        println!("  {:>5}  {}", count, reason);
+    }
+    Ok(())
+}
 
-        // Make our HTTP request and get our response (headers)
-        let request = client
-            .get(url.clone())
-            .headers(headers.clone())
-            .build()
-            .unwrap();
-        let response = match client.execute(request) {
-            Ok(response) => response,
-            Err(e) => {
-                error!("Failed to query URL: {}", e.with_url(parsed_url));
-                continue;
-            },
-        };
+/// Resume an interrupted session's .part files the way `download resume`
+/// does, returning the URLs still to be downloaded from the start: the ones
+/// it never got to, any that can't be resumed after all, and `extra`
+#[allow(clippy::too_many_arguments)]
+fn continue_session(session: Session, path: &Path, extra: Vec<String>, client: &ClientOptions, limit_rate: Option<ByteRate>, settings: &Settings, progress: ProgressMode, bar_style: &BarStyle, cancellation: &Cancellation) -> Vec<String> {
+    eprintln!("Continuing the session in {}: {} URL(s) left", path.display(), session.unfinished.len());
+    // It's written again if this run's interrupted too
+    if let Err(e) = fs::remove_file(path) {
+        warn!("Could not remove {}: {}", path.display(), e);
+    }
+    #[cfg(feature = "state-db")]
+    let state = open_state(settings);
+    let resumer = Resumer {
+        client,
+        middleware: &Middleware::standard(),
+        limit_rate,
+        oauth: &settings.oauth,
+        #[cfg(feature = "state-db")]
+        state: state.as_ref(),
+        progress,
+        bar_style,
+        cancellation,
+    };
+    match resumer.continue_session(session, extra) {
+        Ok(urls) => urls,
+        Err(left) => {
+            cancellation.ask_about_kept();
+            match left.save(path) {
+                Ok(()) => eprintln!("Continue with `download --continue-session {}`", path.display()),
+                Err(e) => error!("Could not save the session: {}", e),
+            }
+            ExitCode::Error.exit();
+        }
+    }
+}
 
-        // Instantiate our progress bar
-        let pb: ProgressBar = multiprog.add(ProgressBar::new(0).with_style(style.clone()));
-
-        // Bail out if some bad stuff happened
-
-        if response.status().is_server_error() {
-            let errstr = format!("{}: server returned {} {}", parsed_url.as_str(), response.status().as_str(), response.status().canonical_reason().unwrap());
-            pb.set_style(errstyle.clone());
-            pb.finish_with_message(errstr);
-            failed_download = true;
-            continue;
-        } else if  response.status().is_client_error() {
-            let errstr = format!("{}: server returned {} {}", parsed_url.as_str(), response.status().as_str(), response.status().canonical_reason().unwrap());
-            pb.set_style(errstyle.clone());
-            pb.finish_with_message(errstr);
-            failed_download = true;
-            continue;
-        }
-
-        // Check the Content-Length header if we got one; otherwise, set it to zero
-        let content_length = match response.content_length() {
-            Some(length) => length,
-            None => 0
-        };
+/// Hold off until `start`, counting down to it; once we're cancelled, there's
+/// nothing to wait for
+fn wait_to_start(start: SystemTime, timezone: TimeZone, progress: ProgressMode, cancellation: &Cancellation) {
+    let Ok(wait) = start.duration_since(SystemTime::now()) else { return };
+    info!("Waiting {} to start", humantime::format_duration(Duration::from_secs(wait.as_secs())));
+    let pb = progress.bar(0).with_style(ProgressStyle::default_spinner());
+    // Plain progress has no bar to count down in, so it just says so once
+    progress.status(&pb, format!("Starting at {}", timezone.format(start)));
+    while let Ok(left) = start.duration_since(SystemTime::now())
+        && cancellation.reason().is_none()
+    {
+        pb.set_message(format!("Starting in {}", HumanDuration(left)));
+        pb.tick();
+        thread::sleep(left.min(Duration::from_secs(1)));
+    }
+    pb.finish_and_clear();
+}
 
-        pb.set_length(content_length );
+/// What every subcommand and download shares, from the flags and configuration
+struct Setup {
+    /// Containers have no browser to take cookies from unless one's asked for
+    #[cfg(feature = "browser-cookies")]
+    container: bool,
+    progress: ProgressMode,
+    settings: Settings,
+    cancellation: Cancellation,
+    bar_style: BarStyle,
+    family: Option<AddressFamily>,
+    connection: Connection,
+}
 
-        let disposition = match response.headers().get("Content-Disposition") {
-            Some(value) => value.to_str().unwrap(),
-            None => ""
-        };
+impl Setup {
+    /// Send a subcommand's requests with cookies from the browser a download's
+    /// would take them from; one that can't be used just means no cookies
+    fn use_browser_cookies(&mut self, args: &Cli) {
+        #[cfg(feature = "browser-cookies")]
+        {
+            self.connection.cookie_store = cookies::from_browser(validate_browser_argument(args.browser.clone().or(self.settings.browser.clone())).ok().flatten(), !self.container);
+        }
+        #[cfg(not(feature = "browser-cookies"))]
+        let _ = args;
+    }
+}
 
-        let disparsed = parse_content_disposition(disposition);
-        let output_filename = if disparsed.disposition == DispositionType::Attachment {
-            disparsed.filename_full().unwrap_or(url_filename.to_string())
-        } else {
-            url_filename.to_string()
-        };
+fn main() {
+    let mut args = Cli::parse();
+    init_logging(&args);
+    debug!("Application started with args: {:?}", args);
+    let setup = setup(&args);
+    if let Some(command) = args.command.take() {
+        if let Err(e) = run_command(command, &args, setup) {
+            error!("{}", e);
+            e.code.exit();
+        }
+        return;
+    }
+    run_download(args, setup);
+}
 
-        if output_filename.trim().is_empty() {
-            let errstr = format!("{}: no filename could be detected from the URL or Content-Disposition headers", parsed_url.as_str());
-            pb.set_style(errstyle.clone());
-            pb.finish_with_message(errstr);
-            failed_download = true;
-            continue;
+/// Log to stderr, and to `--log-file` if one was given
+fn init_logging(args: &Cli) {
+    let log_file = match &args.log_file {
+        Some(path) => {
+            let opened = match args.log_max_size.checked_mul(1024 * 1024) {
+                Some(max_size) => logging::RotatingFile::open(path, max_size, args.log_keep).map_err(|e| e.to_string()),
+                None => Err(format!("--log-max-size {} MiB is too large", args.log_max_size)),
+            };
+            match opened {
+                Ok(file) => Some(file),
+                Err(e) => {
+                    logging::init(args.verbose, None, TimeZone::new(args.utc));
+                    error!("Could not open log file {}: {}", path.display(), e);
+                    exit(1);
+                }
+            }
         }
+        None => None,
+    };
+    logging::init(args.verbose, log_file, TimeZone::new(args.utc));
+}
 
-        // Set the prefix to our filename so we can display it
-        pb.set_prefix(String::from(url_filename));
+/// Progress, configuration and the rest of what every subcommand needs,
+/// exiting if any of it can't be had
+fn setup(args: &Cli) -> Setup {
+    // Containers and CI jobs get plain progress
+    let container = container::detect();
+    let progress = match (args.progress, args.quiet, args.no_progress) {
+        (Some(progress), _, _) => progress,
+        (None, true, _) => ProgressMode::Quiet,
+        (None, _, true) => ProgressMode::Plain,
+        _ => ProgressMode::for_environment(container || container::ci()),
+    };
+    let mut settings = match if args.no_config || container::environment_only() { Settings::from_environment() } else { Settings::load() } {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!("Failed to load configuration: {}", e);
+            exit(1);
+        }
+    };
+    if args.no_state {
+        settings.state = Some(false);
+    }
+    let cancellation = Cancellation::new(if args.no_keep_partial { CancelPolicy::Delete } else { args.on_cancel });
+    cancellation.on_interrupt();
+    if let Some(deadline) = args.deadline {
+        cancellation.deadline(deadline);
+    }
+    let bar_style = match BarStyle::new(args.progress_template.clone().or(settings.progress_template.clone()), args.progress_chars.clone().or(settings.progress_chars.clone())) {
+        Ok(bar_style) => bar_style,
+        Err(e) => {
+            error!("{}", e);
+            ExitCode::Usage.exit();
+        }
+    };
 
-        // Now we create our output file...
-        let mut dest = File::create(url_filename).map_err(|e| format!("Failed to create file: {}", e))?;
+    let family = match (args.ipv4, args.ipv6) {
+        (true, _) => Some(AddressFamily::V4),
+        (_, true) => Some(AddressFamily::V6),
+        _ => None,
+    };
 
-        let finish = finish_style.clone();
-        let handle = thread::spawn(move || {
-            // ...and write the data to it as we get it
-            let _ = copy(&mut pb.wrap_read(response), &mut dest).map_err(|e| format!("Failed to copy content: {}", e));
-            pb.set_style(finish);
-            pb.finish();
-        });
-        handles.push(handle);
+    if let Some(header_timeout) = args.header_timeout
+        && let Err(e) = timeouts::check_header_timeout(header_timeout, args.read_timeout)
+    {
+        error!("{}", e);
+        ExitCode::Usage.exit();
     }
 
-    for handle in handles {
-        let _ = handle.join();
+    Setup {
+        #[cfg(feature = "browser-cookies")]
+        container,
+        progress,
+        connection: Connection::from_cli(args, &settings, family),
+        settings,
+        cancellation,
+        bar_style,
+        family,
     }
+}
 
-    if failed_download {
-        exit(1);
+/// Run a subcommand instead of downloading
+fn run_command(command: Command, args: &Cli, mut setup: Setup) -> Result<(), Failure> {
+    match command {
+        Command::Checksums(command) => run_checksums_command(command).map_err(|e| Failure::from(e.to_string())),
+        #[cfg(feature = "keyring")]
+        Command::Auth(command) => run_auth_command(command, setup.settings.oauth, setup.family).map_err(|e| Failure::from(e.to_string())),
+        Command::Resume { jobs, .. } if !jobs.is_empty() => run_job_command(jobs.into_iter().map(|id| Request::Resume { id }).collect()).map_err(|e| Failure::from(e.to_string())),
+        Command::Resume { paths, all, .. } => {
+            setup.use_browser_cookies(args);
+            run_resume_command(paths, all, &setup.connection.client_options(), args.limit_rate.or(setup.settings.limit_rate), &setup.settings, setup.progress, &setup.bar_style, &setup.cancellation).map_err(|e| Failure::from(e.to_string()))
+        }
+        Command::VerifyTree { dir, manifest, jobs } => run_verify_tree_command(dir, manifest, jobs, setup.progress, &setup.bar_style).map_err(|e| match e {
+            VerifyTreeError::Failed { .. } => Failure::new(ExitCode::Checksum, e.to_string()),
+            e => Failure::from(e.to_string()),
+        }),
+        Command::Verify { urls, output_dir, jobs } => {
+            // Checked the way they were downloaded, with the same cookies, overrides and timeouts
+            setup.use_browser_cookies(args);
+            run_verify_command(urls, output_dir, jobs, &setup.connection.client_options(), &setup.settings, setup.progress, &setup.bar_style)
+        }
+        #[cfg(feature = "state-db")]
+        Command::History { limit, unfinished } => run_history_command(limit, unfinished, TimeZone::new(args.utc)).map_err(|e| Failure::from(e.to_string())),
+        Command::Stats { flaky, since } => run_stats_command(flaky, since, TimeZone::new(args.utc)).map_err(|e| Failure::from(e.to_string())),
+        Command::Daemon { jobs, api, api_token } => run_daemon_command(jobs, api, api_token, &setup.settings, &setup.cancellation).map_err(|e| Failure::from(e.to_string())),
+        Command::Add { urls, output_dir, start_at, start_in } => {
+            let timezone = TimeZone::new(args.utc);
+            // Resolved now, so the daemon starts them when they were asked for, whenever it reads the queue
+            match start_at.or(start_in.map(StartAt::In)).map(|start_at| start_at.resolve(timezone, SystemTime::now())) {
+                Some(None) => Err(Failure::new(ExitCode::Usage, "The start time is too far off".to_string())),
+                start_at => run_add_command(urls, output_dir, start_at.flatten(), timezone).map_err(|e| Failure::from(e.to_string())),
+            }
+        }
+        Command::Status { id } => run_status_command(id).map_err(|e| Failure::from(e.to_string())),
+        Command::Pause { ids } => run_job_command(ids.into_iter().map(|id| Request::Pause { id }).collect()).map_err(|e| Failure::from(e.to_string())),
+        Command::Cancel { ids } => run_job_command(ids.into_iter().map(|id| Request::Cancel { id }).collect()).map_err(|e| Failure::from(e.to_string())),
     }
-
-    Ok(())
 }
 
-fn main() {
-    // Initialize logging
-    env_logger::init();
-        
-    let args = Cli::parse();
-    debug!("Application started with args: {:?}", args);
+/// Download the URLs given on the command line
+fn run_download(mut args: Cli, setup: Setup) {
+    let Setup { #[cfg(feature = "browser-cookies")] container, progress, settings, cancellation, bar_style, family, mut connection } = setup;
+    if !args.globoff {
+        match glob::expand_all(&args.urls) {
+            Ok(urls) => args.urls = urls,
+            Err(e) => {
+                error!("{}", e);
+                ExitCode::Usage.exit();
+            }
+        }
+    }
+    check_download_args(&args, progress);
+    let stdout = writes_to_stdout(&args);
+    let output = args.output.take().filter(|_| !stdout);
+    let device = output.as_deref().is_some_and(device::is_block_device);
+    if let Some(output) = output.as_deref().filter(|_| device && !args.allow_block_device) {
+        error!("{} is a block device; pass --allow-block-device if you really want to overwrite it", output.display());
+        ExitCode::Usage.exit();
+    }
 
     // Validate browser argument if provided
-    let browser_type = match validate_browser_argument(args.browser.clone()) {
+    #[cfg(feature = "browser-cookies")]
+    let browser_type = match validate_browser_argument(args.browser.clone().or(settings.browser.clone())) {
         Ok(browser) => {
             debug!("Browser argument validation successful: {:?}", browser);
             browser
         }
         Err(e) => {
             error!("{}", e.user_friendly_message());
-            exit(1);
+            ExitCode::Cookies.exit();
         }
     };
+    #[cfg(not(feature = "browser-cookies"))]
+    if let Some(browser) = args.browser.as_ref().or(settings.browser.as_ref()) {
+        error!("Can't use cookies from {}: this build doesn't include the browser-cookies feature", browser);
+        ExitCode::Cookies.exit();
+    }
 
-    debug!("Starting download process for {} URLs", args.urls.len());
-    let result = download_file(args.urls, browser_type);
-    match result {
-        Ok(()) => {
-            debug!("Download process completed successfully");
+    let mut report = download_report(&mut args, &cancellation);
+    let warc = match args.warc.as_deref().map(WarcWriter::open).transpose() {
+        Ok(warc) => warc.map(Arc::new),
+        Err(e) => {
+            error!("{}", e);
+            exit(1);
         }
+    };
+
+    let mut notify = settings.notify.clone();
+    if args.notify {
+        notify.sinks.push(SinkConfig::Desktop);
+    }
+    let notifier = match Notifier::from_settings(&notify) {
+        Ok(notifier) => notifier,
         Err(e) => {
-            error!("Download process failed: {}", e);
-            println!("Application error: {}", e);
+            error!("{}", e);
+            exit(1);
+        }
+    };
+
+    let attestation = match args.verify_attestation {
+        Some(kind) => match AttestationVerifier::new(
+            kind,
+            args.attestation_repo,
+            args.attestation_bundle,
+            args.certificate_identity,
+            args.certificate_oidc_issuer,
+        ) {
+            Ok(verifier) => Some(verifier),
+            Err(e) => {
+                error!("{}", e);
+                exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let negotiator = if args.negotiate {
+        match Negotiator::new() {
+            Ok(negotiator) => Some(negotiator),
+            Err(e) => {
+                error!("{}", e);
+                exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    let ntlm = match args.user.as_deref() {
+        Some(user) => match NtlmCredentials::parse(user, |username| rpassword::prompt_password(format!("Password for {}: ", username))) {
+            Ok(credentials) => Some(NtlmAuthenticator::new(credentials)),
+            Err(e) => {
+                error!("{}", e);
+                exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let checksum_db = if args.no_checksum_db {
+        None
+    } else {
+        match ChecksumDatabase::open_default() {
+            Ok(database) => Some(database),
+            Err(e) => {
+                warn!("Not using the checksum database: {}", e);
+                None
+            }
         }
+    };
+
+    let credentials = CredentialStore::open_saved();
+
+    if let Some(start_at) = args.start_at.or(args.start_in.map(StartAt::In)) {
+        let timezone = TimeZone::new(args.utc);
+        let Some(start_at) = start_at.resolve(timezone, SystemTime::now()) else {
+            error!("The start time is too far off");
+            ExitCode::Usage.exit();
+        };
+        wait_to_start(start_at, timezone, progress, &cancellation);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use clap::Parser;
+    // Opened once we're about to start, and kept open until we're done
+    let tunnel = match args.ssh_tunnel.as_deref().map(|destination| SshTunnel::open(destination, family)).transpose() {
+        Ok(tunnel) => tunnel,
+        Err(e) => {
+            error!("{}", e);
+            ExitCode::Network.exit();
+        }
+    };
 
-    #[test]
-    fn test_cli_parsing_no_browser() {
-        let args = Cli::try_parse_from(&["download", "http://example.com"]).unwrap();
-        assert_eq!(args.urls, vec!["http://example.com"]);
-        assert_eq!(args.browser, None);
+    let deterministic = args.deterministic.then(timestamping::source_date_epoch);
+    #[cfg(feature = "state-db")]
+    let state = open_state(&settings);
+    #[cfg(feature = "state-db")]
+    if args.skip_downloaded && state.is_none() {
+        warn!("Without the state database, --skip-downloaded has no earlier downloads to skip");
+    }
+    #[cfg(not(feature = "state-db"))]
+    if args.skip_downloaded {
+        warn!("This build doesn't keep download state, so --skip-downloaded has no earlier downloads to skip");
+    }
+    #[cfg(feature = "state-db")]
+    if let Some(state) = &state {
+        report = report.state(Arc::clone(state));
     }
 
-    #[test]
-    fn test_cli_parsing_with_browser_long() {
-        let args = Cli::try_parse_from(&["download", "--browser", "chrome", "http://example.com"]).unwrap();
-        assert_eq!(args.urls, vec!["http://example.com"]);
-        assert_eq!(args.browser, Some("chrome".to_string()));
+    #[cfg(feature = "browser-cookies")]
+    {
+        connection.cookie_store = cookies::from_browser(browser_type, !container);
     }
+    if let Some(tunnel) = &tunnel
+        && connection.unix_socket.is_none()
+    {
+        connection.proxy = Some(tunnel.proxy());
+    }
+    let limit_rate = args.limit_rate.or(settings.limit_rate);
 
-    #[test]
-    fn test_cli_parsing_with_browser_short() {
-        let args = Cli::try_parse_from(&["download", "-b", "firefox", "http://example.com"]).unwrap();
-        assert_eq!(args.urls, vec!["http://example.com"]);
-        assert_eq!(args.browser, Some("firefox".to_string()));
+    if let Some(url) = args.webhook {
+        report = report.webhook(webhook(url, args.webhook_secret, args.webhook_template, &connection));
     }
 
-    #[test]
-    fn test_cli_parsing_multiple_urls() {
-        let args = Cli::try_parse_from(&[
-            "download", 
-            "--browser", "safari", 
-            "http://example.com", 
-            "http://test.com"
-        ]).unwrap();
+    let output_dir = args.output_dir.or(settings.output_dir.clone());
+    let session_path = args.continue_session.clone().unwrap_or_else(|| Session::default_path(output_dir.as_deref()));
+    let session = match &args.continue_session {
+        Some(path) => match Session::load(path) {
+            Ok(session) => Some(session),
+            Err(e) => {
+                error!("{}", e);
+                ExitCode::Usage.exit();
+            }
+        },
+        // A dry run only shows what would be done, so it leaves the session be
+        None if args.dry_run => None,
+        None => Session::detect(&session_path, &args.urls),
+    };
+    let session_urls = match session {
+        Some(session) => {
+            let urls = session.urls.clone();
+            let extra = if args.continue_session.is_some() { std::mem::take(&mut args.urls) } else { Vec::new() };
+            args.urls = continue_session(session, &session_path, extra, &connection.client_options(), limit_rate, &settings, progress, &bar_style, &cancellation);
+            urls
+        }
+        None => args.urls.clone(),
+    };
+    report = report.session(session_path, session_urls);
+
+    debug!("Starting download process for {} URLs", args.urls.len());
+    // A recursive download mirrors the site's layout, unless told otherwise
+    let mirrored = args.recursive || args.page_requisites;
+    let output_template = args.output_template.or_else(|| mirrored.then(|| recursive::MIRROR_TEMPLATE.parse().expect("the mirror template is valid")));
+    let naming = StandardNames::new(
+        args.trust_server_names,
+        output_dir.clone().unwrap_or_default(),
+        output_template.map(|template: OutputTemplate| match deterministic {
+            Some(time) => template.dated(time),
+            None => template,
+        }),
+        args.auto_rename,
+    );
+    let naming = if mirrored { naming.index_pages() } else { naming };
+    let control = BatchControl::new(std::path::absolute(output_dir.clone().unwrap_or_else(|| PathBuf::from("."))).unwrap_or_default());
+    let socket = listen_for_control(&control);
+    let report = Arc::new(report);
+    let options = DownloadOptions {
+        #[cfg(feature = "browser-cookies")]
+        cookie_store: connection.cookie_store,
+        checksum: args.checksum,
+        checksum_db: checksum_db.map(Arc::new),
+        pinned_keys: args.pinnedpubkey,
+        attestation,
+        credentials: credentials.map(Arc::new),
+        oauth: settings.oauth,
+        auth_hook: args.auth_cmd,
+        exec_before: args.exec_before,
+        small_files: args.small_files.then(|| args.jobs.or(settings.jobs).unwrap_or(batch::DEFAULT_JOBS)),
+        spider: (args.spider || args.headers.is_some()).then(|| args.jobs.or(settings.jobs).unwrap_or(batch::DEFAULT_JOBS)),
+        headers: args.headers,
+        dry_run: args.dry_run,
+        recursion: mirrored.then(|| Recursion {
+            level: match args.level.unwrap_or(recursive::DEFAULT_LEVEL) {
+                // --page-requisites alone is just the pages given and what they need
+                _ if !args.recursive => Some(0),
+                0 => None,
+                level => Some(level),
+            },
+            accept: args.accept,
+            reject: args.reject,
+            requisites: args.page_requisites,
+        }),
+        convert_links: args.convert_links,
+        extractors: if args.no_extractors { Registry::default() } else { Registry::builtin() },
+        transports: match args.allow_file_urls {
+            true => Transports::builtin().with("file", LocalFile),
+            false => Transports::builtin(),
+        },
+        middleware: Middleware::standard(),
+        feeds: args.feed,
+        newer_than: args.newer_than,
+        order: args.order,
+        timestamping: args.timestamping,
+        preserve_time: !args.no_preserve_time,
+        deterministic,
+        no_clobber: args.no_clobber,
+        #[cfg(feature = "state-db")]
+        skip_downloaded: args.skip_downloaded,
+        #[cfg(feature = "state-db")]
+        state,
+        naming: Arc::new(naming),
+        save_page: args.save_page,
+        warc,
+        range: args.range,
+        append: args.append,
+        output,
+        device,
+        encrypt_to: args.encrypt_to,
+        stdout,
+        tee: args.tee,
+        mirrors: args.mirror,
+        decompress: args.decompress,
+        remux: args.remux,
+        decompressed_checksum: args.decompressed_checksum,
+        extract: args.extract.then_some(Extraction { to: args.extract_to, delete: args.delete_archive, max_size: args.extract_max_size }),
+        output_dir,
+        proxy: connection.proxy,
+        unix_socket: connection.unix_socket,
+        resolver: connection.resolver,
+        limit_rate,
+        auto_throttle: args.auto_throttle.or(settings.auto_throttle),
+        min_speed: MinimumSpeed::new(args.speed_limit, args.speed_time),
+        host_limits: HostLimits { connections: args.per_host_limit.map(|limit| limit as usize), delay: args.per_host_delay },
+        max_concurrent: None,
+        connect_timeout: connection.connect_timeout,
+        read_timeout: connection.read_timeout,
+        header_timeout: connection.header_timeout,
+        retry_after: args.retry_after_max.map_or_else(RetryAfter::default, RetryAfter::new),
+        http2_prior_knowledge: connection.http2_prior_knowledge,
+        content_decoding: match (args.compressed, args.no_decompress) {
+            (true, _) => ContentDecoding::Compressed,
+            (_, true) => ContentDecoding::Raw,
+            _ => ContentDecoding::Auto,
+        },
+        negotiator: negotiator.map(Arc::new),
+        ntlm: ntlm.map(Arc::new),
+        progress,
+        bar_style,
+        cancellation: cancellation.clone(),
+        control: Some(control.clone()),
+        report: Arc::clone(&report),
+        strict: args.strict,
+    };
+    let downloader = DownloaderBuilder::from(options).notifier(notifier).build();
+    downloader.cancellation().on_suspend(progress != ProgressMode::Json);
+    let result = downloader.fetch_all(args.urls);
+    #[cfg(unix)]
+    if let Some(socket) = socket {
+        let _ = fs::remove_file(socket);
+    }
+    #[cfg(not(unix))]
+    drop(socket);
+    finish_download(result, &report, &cancellation);
+}
+
+/// The record of how each download went, written out where the flags ask
+fn download_report(args: &mut Cli, cancellation: &Cancellation) -> Report {
+    let mut report = Report::new(args.report_json.take(), args.report_csv.take(), args.write_out.take(), TimeZone::new(args.utc));
+    if args.fail_fast {
+        report = report.fail_fast(cancellation);
+    }
+    if args.deterministic {
+        report = report.deterministic();
+    }
+    if let Some(hook) = args.exec_after.take() {
+        report = report.exec_after(hook);
+    }
+    match RetryHistory::default_path() {
+        Ok(path) => report = report.history(RetryHistory::new(path)),
+        Err(e) => debug!("Not remembering retries: {}", e),
+    }
+    report
+}
+
+/// -O - is the same as --stdout
+fn writes_to_stdout(args: &Cli) -> bool {
+    args.stdout || args.output.as_deref() == Some(Path::new("-"))
+}
+
+/// Exit if the flags given can't be used together, or with this many URLs,
+/// or in this build
+fn check_download_args(args: &Cli, progress: ProgressMode) {
+    if args.checksum.iter().any(|spec| matches!(spec, ChecksumSpec::Verify(_))) && args.urls.len() > 1 {
+        error!("--checksum with an expected digest can only be used with a single URL");
+        ExitCode::Usage.exit();
+    }
+    if args.output.is_some() && args.urls.len() > 1 {
+        error!("--output can only be used with a single URL");
+        ExitCode::Usage.exit();
+    }
+    if args.stdout && args.urls.len() > 1 {
+        error!("--stdout can only be used with a single URL");
+        ExitCode::Usage.exit();
+    }
+    if args.tee && args.urls.len() > 1 {
+        error!("--tee can only be used with a single URL");
+        ExitCode::Usage.exit();
+    }
+    if args.decompressed_checksum.is_some() && args.urls.len() > 1 {
+        error!("--decompressed-checksum can only be used with a single URL");
+        ExitCode::Usage.exit();
+    }
+    if !args.mirror.is_empty() && args.urls.len() > 1 {
+        error!("--mirror can only be used with a single URL");
+        ExitCode::Usage.exit();
+    }
+    let stdout = writes_to_stdout(args);
+    if stdout && (args.append || args.tee || args.encrypt_to.is_some() || args.warc.is_some() || args.timestamping || args.no_clobber || args.verify_attestation.is_some()) {
+        error!("-O - writes to stdout, so it can't be used with --append, --tee, --encrypt-to, --warc, --timestamping, --no-clobber or --verify-attestation");
+        ExitCode::Usage.exit();
+    }
+    if args.report_json.as_deref() == Some(Path::new("-")) && (stdout || args.tee || progress == ProgressMode::Json) {
+        error!("--report-json - writes the report to stdout, so it can't be used with --stdout, -O -, --tee or --progress json");
+        ExitCode::Usage.exit();
+    }
+    if args.write_out.is_some() && (stdout || args.tee || progress == ProgressMode::Json || args.report_json.as_deref() == Some(Path::new("-"))) {
+        error!("--write-out prints to stdout, so it can't be used with --stdout, -O -, --tee, --progress json or --report-json -");
+        ExitCode::Usage.exit();
+    }
+    if progress == ProgressMode::Json && (stdout || args.tee) {
+        error!("--progress json writes its events to stdout, so it can't be used with --stdout, -O - or --tee");
+        ExitCode::Usage.exit();
+    }
+    #[cfg(not(unix))]
+    if args.unix_socket.is_some() {
+        error!("--unix-socket is only supported on Unix");
+        ExitCode::Usage.exit();
+    }
+    #[cfg(not(feature = "compression"))]
+    if args.compressed {
+        error!("Can't use --compressed: this build doesn't include the compression feature");
+        ExitCode::Usage.exit();
+    }
+    #[cfg(not(feature = "compression"))]
+    if args.decompress {
+        error!("Can't use --decompress: this build doesn't include the compression feature");
+        ExitCode::Usage.exit();
+    }
+    #[cfg(not(feature = "archives"))]
+    if args.extract {
+        error!("Can't use --extract: this build doesn't include the archives feature");
+        ExitCode::Usage.exit();
+    }
+}
+
+/// The webhook told about the batch once it's done
+fn webhook(url: Url, secret: Option<Secret>, template: Option<WriteOut>, connection: &Connection) -> Webhook {
+    let mut webhook = Webhook::new(url);
+    // Sent the same way as the downloads, through the proxy or tunnel, but
+    // without their cookies or assumptions about what the server speaks
+    match build_client(&ClientOptions {
+        #[cfg(feature = "browser-cookies")]
+        cookie_store: None,
+        http2_prior_knowledge: false,
+        ..connection.client_options()
+    }) {
+        Ok(client) => webhook = webhook.client(client),
+        Err(e) => warn!("Could not set up the webhook's client, sending it directly: {}", e),
+    }
+    if let Some(secret) = secret {
+        webhook = webhook.secret(secret.0);
+    }
+    if let Some(template) = template {
+        webhook = webhook.template(template);
+    }
+    webhook
+}
+
+/// Say what a cancelled run left behind, and exit with how the downloads went
+fn finish_download(result: Result<ExitCode, Box<dyn std::error::Error>>, report: &Report, cancellation: &Cancellation) {
+    // Including those cancelled one at a time through the control socket
+    cancellation.ask_about_kept();
+    if let Some(reason) = cancellation.reason() {
+        eprintln!("{}", cancel_summary(reason, report, cancellation.leftovers()));
+        match report.save_session() {
+            Ok(Some(path)) => eprintln!("Continue with `download --continue-session {}`, or run the same command again", path.display()),
+            Ok(None) => {}
+            Err(e) => error!("Could not save the session: {}", e),
+        }
+    }
+    match result {
+        Ok(ExitCode::Success) => {
+            debug!("Download process completed successfully");
+        }
+        Ok(code) => code.exit(),
+        Err(e) => {
+            error!("Download process failed: {}", e);
+            eprintln!("Application error: {}", e);
+            ExitCode::Error.exit();
+        }
+    }
+}
+
+/// Let `download status`, `pause` and `cancel` reach the URLs being downloaded,
+/// unless another download in the foreground already can be reached
+fn listen_for_control(control: &BatchControl) -> Option<PathBuf> {
+    #[cfg(any(unix, windows))]
+    {
+        let path = control_socket::batch_path().inspect_err(|e| debug!("Not listening for control: {}", e)).ok()?;
+        let listener = ControlSocket::bind(&path).inspect_err(|e| debug!("Not listening for control on {}: {}", path.display(), e)).ok()?;
+        let socket = ControlSocket::new(control.clone());
+        thread::spawn(move || socket.serve(listener));
+        Some(path)
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = control;
+        None
+    }
+}
+
+/// What a cancelled run got done, and what it left behind
+fn cancel_summary(reason: CancelReason, report: &Report, (kept, deleted): (usize, usize)) -> String {
+    let downloaded = report.entries().iter().filter(|entry| entry.status == Status::Downloaded).count();
+    let mut summary = format!("Stopped ({}) after downloading {} file(s)", reason, downloaded);
+    if kept > 0 {
+        summary += &format!("; kept {} partial download(s) to finish with `download resume`", kept);
+    }
+    if deleted > 0 {
+        summary += &format!("; deleted {} partial download(s)", deleted);
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    #[cfg(feature = "browser-cookies")]
+    use rustdl::browser::{BrowserError, BrowserType, CookieManager};
+
+    #[test]
+    fn test_cancel_summary() {
+        let report = Report::new(None, None, None, TimeZone::new(true));
+        report.downloaded("http://example.com/a", Path::new("a"), 10, Duration::ZERO, Some(200), &[]);
+        report.failed("http://example.com/b", None, None, "download terminated");
+        assert_eq!(cancel_summary(CancelReason::Terminated, &report, (0, 0)), "Stopped (terminated) after downloading 1 file(s)");
+        assert_eq!(
+            cancel_summary(CancelReason::Interrupted, &report, (2, 1)),
+            "Stopped (interrupted) after downloading 1 file(s); kept 2 partial download(s) to finish with `download resume`; deleted 1 partial download(s)"
+        );
+    }
+
+    #[test]
+    fn test_cli_parsing_no_browser() {
+        let args = Cli::try_parse_from(["download", "http://example.com"]).unwrap();
+        assert_eq!(args.urls, vec!["http://example.com"]);
+        assert_eq!(args.browser, None);
+    }
+
+    #[test]
+    fn test_cli_parsing_with_browser_long() {
+        let args = Cli::try_parse_from(["download", "--browser", "chrome", "http://example.com"]).unwrap();
+        assert_eq!(args.urls, vec!["http://example.com"]);
+        assert_eq!(args.browser, Some("chrome".to_string()));
+    }
+
+    #[test]
+    fn test_cli_parsing_with_browser_short() {
+        let args = Cli::try_parse_from(["download", "-b", "firefox", "http://example.com"]).unwrap();
+        assert_eq!(args.urls, vec!["http://example.com"]);
+        assert_eq!(args.browser, Some("firefox".to_string()));
+    }
+
+    #[test]
+    fn test_cli_parsing_multiple_urls() {
+        let args = Cli::try_parse_from([
+            "download", 
+            "--browser", "safari", 
+            "http://example.com", 
+            "http://test.com"
+        ]).unwrap();
         assert_eq!(args.urls, vec!["http://example.com", "http://test.com"]);
         assert_eq!(args.browser, Some("safari".to_string()));
     }
 
+    #[test]
+    fn test_cli_parsing_checksum() {
+        let args = Cli::try_parse_from(["download", "--checksum", "sha256", "http://example.com"]).unwrap();
+        assert_eq!(args.checksum, vec![ChecksumSpec::Compute(HashAlgorithm::Sha256)]);
+
+        let args = Cli::try_parse_from(["download", "--checksum", "md5:5d41402abc4b2a76b9719d911017c592", "http://example.com"]).unwrap();
+        assert!(matches!(args.checksum[..], [ChecksumSpec::Verify(_)]));
+
+        let args = Cli::try_parse_from(["download", "--checksum", "md5,sha256", "--checksum", "blake3", "http://example.com"]).unwrap();
+        assert_eq!(args.checksum.iter().map(ChecksumSpec::algorithm).collect::<Vec<_>>(), vec![HashAlgorithm::Md5, HashAlgorithm::Sha256, HashAlgorithm::Blake3]);
+
+        assert!(Cli::try_parse_from(["download", "--checksum", "crc32", "http://example.com"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_attestation() {
+        let args = Cli::try_parse_from([
+            "download", "--verify-attestation", "github", "--attestation-repo", "owner/repo", "http://example.com"
+        ]).unwrap();
+        assert_eq!(args.verify_attestation, Some(AttestationKind::Github));
+        assert_eq!(args.attestation_repo.as_deref(), Some("owner/repo"));
+
+        assert!(Cli::try_parse_from(["download", "--verify-attestation", "pgp", "http://example.com"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_header_timeout() {
+        let args = Cli::try_parse_from(["download", "--header-timeout", "90s", "http://example.com"]).unwrap();
+        assert_eq!(args.header_timeout, Some(Duration::from_secs(90)));
+
+        assert!(Cli::try_parse_from(["download", "--header-timeout", "soon", "http://example.com"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_retry_after_max() {
+        let args = Cli::try_parse_from(["download", "--retry-after-max", "10m", "http://example.com/a"]).unwrap();
+        assert_eq!(args.retry_after_max, Some(Duration::from_secs(600)));
+        assert_eq!(Cli::try_parse_from(["download", "http://example.com/a"]).unwrap().retry_after_max, None);
+    }
+
+    #[test]
+    fn test_cli_parsing_per_host_limits() {
+        let args = Cli::try_parse_from(["download", "--per-host-limit", "2", "--per-host-delay", "500ms", "http://example.com/a"]).unwrap();
+        assert_eq!(args.per_host_limit, Some(2));
+        assert_eq!(args.per_host_delay, Some(Duration::from_millis(500)));
+        assert!(Cli::try_parse_from(["download", "--per-host-limit", "0", "http://example.com/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_deterministic() {
+        assert!(Cli::try_parse_from(["download", "--deterministic", "http://example.com/a"]).unwrap().deterministic);
+        assert!(!Cli::try_parse_from(["download", "http://example.com/a"]).unwrap().deterministic);
+        assert!(Cli::try_parse_from(["download", "--deterministic", "--warc", "out.warc", "http://example.com/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_resolve_and_connect_to() {
+        let args = Cli::try_parse_from([
+            "download",
+            "--resolve", "example.com:443:10.0.0.5",
+            "--resolve", "cdn.example.com:443:10.0.0.6",
+            "--connect-to", "www.example.com:443:staging.example.com:8443",
+            "https://example.com/a",
+        ])
+        .unwrap();
+        assert_eq!(args.resolve.iter().map(|pinned| pinned.host.as_str()).collect::<Vec<_>>(), ["example.com", "cdn.example.com"]);
+        assert_eq!(args.connect_to[0].target_port, Some(8443));
+        assert!(Cli::try_parse_from(["download", "--resolve", "example.com:10.0.0.5", "https://example.com/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_address_family() {
+        let args = Cli::try_parse_from(["download", "-4", "https://example.com/file"]).unwrap();
+        assert!(args.ipv4 && !args.ipv6);
+        let args = Cli::try_parse_from(["download", "resume", "--ipv6", "file.part"]).unwrap();
+        assert!(args.ipv6);
+        assert!(Cli::try_parse_from(["download", "-4", "-6", "https://example.com/file"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_connect_and_read_timeouts() {
+        let args = Cli::try_parse_from(["download", "--connect-timeout", "5s", "--read-timeout", "0", "http://example.com"]).unwrap();
+        assert_eq!(args.connect_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(args.read_timeout, Some(Duration::ZERO));
+        assert!(Cli::try_parse_from(["download", "--connect-timeout", "soon", "http://example.com"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_speed_limit() {
+        let args = Cli::try_parse_from(["download", "--speed-limit", "1k", "--speed-time", "15", "http://example.com"]).unwrap();
+        assert_eq!(MinimumSpeed::new(args.speed_limit, args.speed_time), Some(MinimumSpeed { rate: ByteRate(1024), time: Duration::from_secs(15) }));
+        assert!(Cli::try_parse_from(["download", "--speed-limit", "slow", "http://example.com"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_spider() {
+        let args = Cli::try_parse_from(["download", "--spider", "-j", "16", "http://example.com/a", "http://example.com/b"]).unwrap();
+        assert!(args.spider);
+        assert_eq!(args.jobs, Some(16));
+        assert!(Cli::try_parse_from(["download", "--spider", "--small-files", "http://example.com/a"]).is_err());
+        assert!(Cli::try_parse_from(["download", "--spider", "-O", "a", "http://example.com/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_headers() {
+        let args = Cli::try_parse_from(["download", "-I", "http://example.com/a"]).unwrap();
+        assert_eq!(args.headers, Some(HeaderFormat::Text));
+        let args = Cli::try_parse_from(["download", "--headers=json", "http://example.com/a"]).unwrap();
+        assert_eq!(args.headers, Some(HeaderFormat::Json));
+        assert!(Cli::try_parse_from(["download", "--headers=yaml", "http://example.com/a"]).is_err());
+        assert!(Cli::try_parse_from(["download", "-I", "--spider", "http://example.com/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_dry_run() {
+        let args = Cli::try_parse_from(["download", "--dry-run", "-P", "out", "http://example.com/a"]).unwrap();
+        assert!(args.dry_run);
+        assert!(!Cli::try_parse_from(["download", "http://example.com/a"]).unwrap().dry_run);
+        assert!(Cli::try_parse_from(["download", "--dry-run", "--spider", "http://example.com/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_recursive() {
+        let args = Cli::try_parse_from(["download", "-r", "-l", "2", "-A", "iso,zip", "-R", "old.iso", "https://example.com/releases/"]).unwrap();
+        assert!(args.recursive);
+        assert_eq!(args.level, Some(2));
+        assert_eq!(args.accept, ["iso", "zip"]);
+        assert_eq!(args.reject, ["old.iso"]);
+        assert!(Cli::try_parse_from(["download", "-A", "iso", "https://example.com/releases/"]).is_err());
+        assert!(Cli::try_parse_from(["download", "-r", "-O", "index.html", "https://example.com/"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_page_requisites() {
+        let args = Cli::try_parse_from(["download", "-p", "-k", "https://example.com/article"]).unwrap();
+        assert!(args.page_requisites);
+        assert!(args.convert_links);
+        assert!(!args.recursive);
+        assert!(Cli::try_parse_from(["download", "-r", "-k", "https://example.com/"]).unwrap().convert_links);
+        assert!(Cli::try_parse_from(["download", "-k", "https://example.com/"]).is_err());
+        assert!(Cli::try_parse_from(["download", "-p", "--save-page", "https://example.com/"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_feed() {
+        let args = Cli::try_parse_from(["download", "--feed", "https://example.com/podcast.xml", "--newer-than", "7d"]).unwrap();
+        assert_eq!(args.feed, ["https://example.com/podcast.xml"]);
+        assert!(args.urls.is_empty());
+        assert_eq!(args.newer_than, Some(Duration::from_secs(7 * 24 * 60 * 60)));
+        assert!(Cli::try_parse_from(["download"]).is_err());
+        assert!(Cli::try_parse_from(["download", "--newer-than", "7d", "https://example.com/a"]).is_err());
+        assert!(Cli::try_parse_from(["download", "--feed", "https://example.com/podcast.xml", "-O", "a.mp3"]).is_err());
+        // Reading the feed would send requests
+        assert!(Cli::try_parse_from(["download", "--feed", "https://example.com/podcast.xml", "--dry-run"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_globoff() {
+        let args = Cli::try_parse_from(["download", "-g", "https://example.com/a[1].jpg"]).unwrap();
+        assert!(args.globoff);
+        assert!(!Cli::try_parse_from(["download", "https://example.com/a[1-2].jpg"]).unwrap().globoff);
+    }
+
+    #[test]
+    fn test_cli_parsing_remux() {
+        let args = Cli::try_parse_from(["download", "--remux", "mp4", "https://example.com/video/master.m3u8"]).unwrap();
+        assert_eq!(args.remux.as_deref(), Some("mp4"));
+        assert!(Cli::try_parse_from(["download", "--remux", "avi", "https://example.com/video/master.m3u8"]).is_err());
+        assert!(Cli::try_parse_from(["download", "--remux", "mp4", "--encrypt-to", "ops@example.com", "https://example.com/video/master.m3u8"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_no_extractors() {
+        assert!(Cli::try_parse_from(["download", "--no-extractors", "https://sourceforge.net/projects/tool/files/tool.tar.gz/download"]).unwrap().no_extractors);
+        assert!(!Cli::try_parse_from(["download", "http://example.com/a"]).unwrap().no_extractors);
+    }
+
+    #[test]
+    fn test_cli_parsing_exec_hooks() {
+        let args = Cli::try_parse_from(["download", "--exec-before", "check-quota {url}", "--exec-after", "tar -xf {}", "http://example.com/a.tar"]).unwrap();
+        assert_eq!(args.exec_before, Some("check-quota {url}".parse().unwrap()));
+        assert_eq!(args.exec_after, Some("tar -xf {}".parse().unwrap()));
+        assert!(Cli::try_parse_from(["download", "--exec-after", "", "http://example.com/a"]).is_err());
+        assert!(Cli::try_parse_from(["download", "--exec-before", "true", "--small-files", "http://example.com/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_webhook() {
+        let args = Cli::try_parse_from(["download", "--webhook", "https://hooks.example.com/dl", "--webhook-secret", "s3cret", "--webhook-template", "{\"text\": \"%{url}\"}", "http://example.com/a"]).unwrap();
+        assert!(!format!("{:?}", args).contains("s3cret"));
+        assert_eq!(args.webhook.unwrap().as_str(), "https://hooks.example.com/dl");
+        assert_eq!(args.webhook_secret.as_deref(), Some("s3cret"));
+        assert!(Cli::try_parse_from(["download", "--webhook-secret", "s3cret", "http://example.com/a"]).is_err());
+        assert!(Cli::try_parse_from(["download", "--webhook", "https://hooks.example.com/dl", "--webhook-template", "%{nope}", "http://example.com/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_negotiate() {
+        let args = Cli::try_parse_from(["download", "--negotiate", "http://example.com"]).unwrap();
+        assert!(args.negotiate);
+        let args = Cli::try_parse_from(["download", "http://example.com"]).unwrap();
+        assert!(!args.negotiate);
+    }
+
+    #[test]
+    fn test_cli_parsing_compressed() {
+        assert!(Cli::try_parse_from(["download", "--compressed", "http://example.com/a.json"]).unwrap().compressed);
+        assert!(Cli::try_parse_from(["download", "--no-decompress", "http://example.com/a.tar.gz"]).unwrap().no_decompress);
+        assert!(Cli::try_parse_from(["download", "--compressed", "--no-decompress", "http://example.com/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_http2_prior_knowledge() {
+        let args = Cli::try_parse_from(["download", "--http2-prior-knowledge", "http://internal:8080/a"]).unwrap();
+        assert!(args.http2_prior_knowledge);
+        // NTLM needs HTTP/1.1
+        assert!(Cli::try_parse_from(["download", "--http2-prior-knowledge", "--ntlm", "-u", "CORP\\alice:secret", "http://intranet/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_ntlm() {
+        let args = Cli::try_parse_from(["download", "--ntlm", "--user", "CORP\\alice:secret", "http://intranet/a"]).unwrap();
+        assert!(args.ntlm);
+        assert_eq!(args.user.as_deref(), Some("CORP\\alice:secret"));
+        assert!(!format!("{:?}", args).contains("alice"));
+
+        // --ntlm needs an account, and --user is only for --ntlm
+        assert!(Cli::try_parse_from(["download", "--ntlm", "http://intranet/a"]).is_err());
+        assert!(Cli::try_parse_from(["download", "-u", "alice", "http://intranet/a"]).is_err());
+        assert!(Cli::try_parse_from(["download", "--ntlm", "-u", "alice", "--negotiate", "http://intranet/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_auth_cmd() {
+        let args = Cli::try_parse_from(["download", "--auth-cmd", "sign-request {url}", "http://example.com/a"]).unwrap();
+        assert_eq!(args.auth_cmd, Some("sign-request {url}".parse().unwrap()));
+        assert!(Cli::try_parse_from(["download", "--auth-cmd", " ", "http://example.com/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_small_files() {
+        let args = Cli::try_parse_from(["download", "--small-files", "-j", "32", "http://example.com/a", "http://example.com/b"]).unwrap();
+        assert!(args.small_files);
+        assert_eq!(args.jobs, Some(32));
+
+        let args = Cli::try_parse_from(["download", "--small-files", "http://example.com/a"]).unwrap();
+        // Left for the config file or DOWNLOAD_JOBS, then batch::DEFAULT_JOBS
+        assert_eq!(args.jobs, None);
+
+        // --jobs only means something in small-file mode
+        assert!(Cli::try_parse_from(["download", "-j", "4", "http://example.com/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_timestamping() {
+        let args = Cli::try_parse_from(["download", "-N", "http://example.com/a"]).unwrap();
+        assert!(args.timestamping);
+        let args = Cli::try_parse_from(["download", "--timestamping", "--small-files", "http://example.com/a"]).unwrap();
+        assert!(args.timestamping);
+        let args = Cli::try_parse_from(["download", "http://example.com/a"]).unwrap();
+        assert!(!args.timestamping);
+    }
+
+    #[test]
+    fn test_cli_parsing_no_clobber() {
+        let args = Cli::try_parse_from(["download", "--no-clobber", "http://example.com/a"]).unwrap();
+        assert!(args.no_clobber);
+        let args = Cli::try_parse_from(["download", "http://example.com/a"]).unwrap();
+        assert!(!args.no_clobber);
+        // Either skip existing files or refresh them, not both
+        assert!(Cli::try_parse_from(["download", "--no-clobber", "-N", "http://example.com/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_auto_rename() {
+        let args = Cli::try_parse_from(["download", "--auto-rename", "http://example.com/a"]).unwrap();
+        assert!(args.auto_rename);
+        assert!(Cli::try_parse_from(["download", "--auto-rename", "--no-clobber", "http://example.com/a"]).is_err());
+        assert!(Cli::try_parse_from(["download", "--auto-rename", "-N", "http://example.com/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_output_dir_proxy_and_rate() {
+        let args = Cli::try_parse_from([
+            "download", "-P", "/tmp/out", "--proxy", "socks5://localhost:1080", "--limit-rate", "2M", "http://example.com/a",
+        ]).unwrap();
+        assert_eq!(args.output_dir, Some(PathBuf::from("/tmp/out")));
+        assert_eq!(args.proxy.as_deref(), Some("socks5://localhost:1080"));
+        assert_eq!(args.limit_rate, Some(ByteRate(2 * 1024 * 1024)));
+        assert!(Cli::try_parse_from(["download", "--limit-rate", "fast", "http://example.com/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_auto_throttle() {
+        let args = Cli::try_parse_from(["download", "--auto-throttle", "70%", "http://example.com/a"]).unwrap();
+        assert_eq!(args.auto_throttle, Some("70".parse().unwrap()));
+        assert!(Cli::try_parse_from(["download", "--auto-throttle", "150%", "http://example.com/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_trust_server_names() {
+        let args = Cli::try_parse_from(["download", "http://example.com/a"]).unwrap();
+        assert!(!args.trust_server_names);
+        let args = Cli::try_parse_from(["download", "--trust-server-names", "http://example.com/a"]).unwrap();
+        assert!(args.trust_server_names);
+    }
+
+    #[test]
+    fn test_cli_parsing_output() {
+        let args = Cli::try_parse_from(["download", "-O", "/dev/sdb", "--allow-block-device", "http://example.com/os.iso"]).unwrap();
+        assert_eq!(args.output, Some(PathBuf::from("/dev/sdb")));
+        assert!(args.allow_block_device);
+        assert!(Cli::try_parse_from(["download", "--allow-block-device", "http://example.com/os.iso"]).is_err());
+        assert!(Cli::try_parse_from(["download", "-O", "a.iso", "--small-files", "http://example.com/os.iso"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_stdout() {
+        let args = Cli::try_parse_from(["download", "--stdout", "http://example.com/src.tar.gz"]).unwrap();
+        assert!(args.stdout);
+        let args = Cli::try_parse_from(["download", "-O", "-", "http://example.com/src.tar.gz"]).unwrap();
+        assert_eq!(args.output, Some(PathBuf::from("-")));
+        assert!(Cli::try_parse_from(["download", "--stdout", "-O", "out.tar.gz", "http://example.com/src.tar.gz"]).is_err());
+        assert!(Cli::try_parse_from(["download", "--stdout", "--append", "http://example.com/src.tar.gz"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_verbose() {
+        assert_eq!(Cli::try_parse_from(["download", "http://example.com/a"]).unwrap().verbose, 0);
+        assert_eq!(Cli::try_parse_from(["download", "-vv", "http://example.com/a"]).unwrap().verbose, 2);
+        assert_eq!(Cli::try_parse_from(["download", "resume", "-v", "-v", "-v"]).unwrap().verbose, 3);
+        assert!(Cli::try_parse_from(["download", "-v", "-q", "http://example.com/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_log_file() {
+        let args = Cli::try_parse_from(["download", "--log-file", "download.log", "--log-max-size", "50", "http://example.com/a"]).unwrap();
+        assert_eq!(args.log_file, Some(PathBuf::from("download.log")));
+        assert_eq!((args.log_max_size, args.log_keep), (50, 5));
+        assert!(Cli::try_parse_from(["download", "--log-keep", "2", "http://example.com/a"]).is_err());
+        assert!(Cli::try_parse_from(["download", "--log-file", "download.log", "--log-max-size", "0", "http://example.com/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_quiet_and_no_progress() {
+        let args = Cli::try_parse_from(["download", "-q", "http://example.com/a"]).unwrap();
+        assert!(args.quiet);
+        let args = Cli::try_parse_from(["download", "resume", "--no-progress"]).unwrap();
+        assert!(args.no_progress);
+        assert!(Cli::try_parse_from(["download", "--quiet", "--no-progress", "http://example.com/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_no_config() {
+        let args = Cli::try_parse_from(["download", "--no-config", "http://example.com/a"]).unwrap();
+        assert!(args.no_config);
+        let args = Cli::try_parse_from(["download", "daemon", "--no-config"]).unwrap();
+        assert!(args.no_config);
+        assert!(!Cli::try_parse_from(["download", "http://example.com/a"]).unwrap().no_config);
+    }
+
+    #[test]
+    fn test_connection_from_cli() {
+        let settings = Settings { proxy: Some("http://proxy.example.com:3128".to_string()), ..Default::default() };
+        let args = Cli::try_parse_from(["download", "resume"]).unwrap();
+        let connection = Connection::from_cli(&args, &settings, None);
+        assert_eq!(connection.client_options().proxy, Some("http://proxy.example.com:3128"));
+
+        let args = Cli::try_parse_from(["download", "--proxy", "socks5://localhost:1080", "verify", "http://example.com/a"]).unwrap();
+        assert_eq!(Connection::from_cli(&args, &settings, None).proxy.as_deref(), Some("socks5://localhost:1080"));
+
+        // The socket is the only way out
+        let args = Cli::try_parse_from(["download", "--unix-socket", "/run/docker.sock", "resume"]).unwrap();
+        let connection = Connection::from_cli(&args, &settings, None);
+        assert_eq!(connection.client_options().proxy, None);
+        assert_eq!(connection.client_options().unix_socket, Some(Path::new("/run/docker.sock")));
+    }
+
+    #[test]
+    fn test_cli_parsing_report_json() {
+        let args = Cli::try_parse_from(["download", "--report-json", "report.json", "http://example.com/a", "http://example.com/b"]).unwrap();
+        assert_eq!(args.report_json, Some(PathBuf::from("report.json")));
+        assert!(Cli::try_parse_from(["download", "http://example.com/a", "--report-json"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_write_out() {
+        let args = Cli::try_parse_from(["download", "-w", r"%{url} %{ttfb}\n", "http://example.com/a"]).unwrap();
+        assert!(args.write_out.is_some());
+        assert!(Cli::try_parse_from(["download", "--write-out", "%{tls}", "http://example.com/a"]).is_ok());
+        assert!(Cli::try_parse_from(["download", "--write-out", "%{appconnect}", "http://example.com/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_unix_socket() {
+        let args = Cli::try_parse_from(["download", "--unix-socket", "/var/run/docker.sock", "/v1.43/info"]).unwrap();
+        assert_eq!(args.unix_socket, Some(PathBuf::from("/var/run/docker.sock")));
+        assert!(Cli::try_parse_from(["download", "--unix-socket", "/run/app.sock", "--proxy", "http://proxy:3128", "/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_strict() {
+        assert!(Cli::try_parse_from(["download", "--strict", "http://example.com/a", "http://example.com/b"]).unwrap().strict);
+        // Usage errors exit with 2
+        let error = Cli::try_parse_from(["download", "--no-such-option", "http://example.com/a"]).unwrap_err();
+        assert_eq!(error.exit_code(), ExitCode::Usage.code());
+    }
+
+    #[test]
+    fn test_cli_parsing_fail_fast() {
+        assert!(!Cli::try_parse_from(["download", "http://example.com/a"]).unwrap().fail_fast);
+        assert!(Cli::try_parse_from(["download", "--fail-fast", "http://example.com/a"]).unwrap().fail_fast);
+        // The last one given wins, so an alias can be overridden
+        assert!(!Cli::try_parse_from(["download", "--fail-fast", "--keep-going", "http://example.com/a"]).unwrap().fail_fast);
+        assert!(Cli::try_parse_from(["download", "--keep-going", "--fail-fast", "http://example.com/a"]).unwrap().fail_fast);
+    }
+
+    #[test]
+    fn test_cli_parsing_ssh_tunnel() {
+        let args = Cli::try_parse_from(["download", "--ssh-tunnel", "alice@bastion", "http://artifacts.internal/build.tar.gz"]).unwrap();
+        assert_eq!(args.ssh_tunnel.as_deref(), Some("alice@bastion"));
+        assert!(Cli::try_parse_from(["download", "--ssh-tunnel", "alice@bastion", "--proxy", "http://proxy:3128", "http://a/"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_notify() {
+        assert!(Cli::try_parse_from(["download", "--notify", "http://example.com/big.iso"]).unwrap().notify);
+        assert!(!Cli::try_parse_from(["download", "http://example.com/big.iso"]).unwrap().notify);
+    }
+
+    #[test]
+    fn test_cli_parsing_start_at_and_utc() {
+        let args = Cli::try_parse_from(["download", "--start-at", "02:00", "--utc", "http://example.com/a"]).unwrap();
+        assert!(matches!(args.start_at, Some(StartAt::Clock(_))));
+        assert!(args.utc);
+        let args = Cli::try_parse_from(["download", "--start-at", "2026-03-01T02:00:00+01:00", "http://example.com/a"]).unwrap();
+        assert!(matches!(args.start_at, Some(StartAt::Moment(_))));
+        assert!(!args.utc);
+        assert!(Cli::try_parse_from(["download", "--start-at", "soon", "http://example.com/a"]).is_err());
+        let args = Cli::try_parse_from(["download", "--start-in", "3h", "http://example.com/a"]).unwrap();
+        assert_eq!(args.start_in, Some(Duration::from_secs(3 * 3600)));
+        assert!(Cli::try_parse_from(["download", "--start-in", "3h", "--start-at", "02:00", "http://example.com/a"]).is_err());
+        match Cli::try_parse_from(["download", "add", "--start-at", "02:00", "http://example.com/a"]).unwrap().command {
+            Some(Command::Add { start_at, start_in, .. }) => assert!(matches!((start_at, start_in), (Some(StartAt::Clock(_)), None))),
+            other => panic!("unexpected command: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_cli_parsing_report_csv() {
+        let args = Cli::try_parse_from(["download", "--report-csv", "history.csv", "--report-json", "-", "http://example.com/a"]).unwrap();
+        assert_eq!(args.report_csv, Some(PathBuf::from("history.csv")));
+        assert_eq!(args.report_json, Some(PathBuf::from("-")));
+    }
+
+    #[test]
+    fn test_cli_parsing_progress() {
+        let args = Cli::try_parse_from(["download", "--progress", "json", "http://example.com/a"]).unwrap();
+        assert_eq!(args.progress, Some(ProgressMode::Json));
+        assert!(Cli::try_parse_from(["download", "--progress", "fancy", "http://example.com/a"]).is_err());
+        assert!(Cli::try_parse_from(["download", "--progress", "json", "-q", "http://example.com/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_mirror() {
+        let args = Cli::try_parse_from([
+            "download", "https://a.example.com/os.iso", "--mirror", "https://b.example.com/os.iso", "--mirror", "https://c.example.com/os.iso",
+        ]).unwrap();
+        assert_eq!(args.mirror, vec!["https://b.example.com/os.iso", "https://c.example.com/os.iso"]);
+        assert!(Cli::try_parse_from(["download", "--mirror", "https://b.example.com/os.iso", "--range", "0-1M", "https://a.example.com/os.iso"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_tee() {
+        let args = Cli::try_parse_from(["download", "--tee", "http://example.com/data.csv"]).unwrap();
+        assert!(args.tee);
+        assert!(Cli::try_parse_from(["download", "--tee", "--stdout", "http://example.com/data.csv"]).is_err());
+        assert!(Cli::try_parse_from(["download", "--tee", "--encrypt-to", "ops@example.com", "http://example.com/data.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_encrypt_to() {
+        let args = Cli::try_parse_from(["download", "--encrypt-to", "ops@example.com", "http://example.com/db.dump"]).unwrap();
+        assert_eq!(args.encrypt_to, Some(Recipient::Gpg("ops@example.com".to_string())));
+        assert!(Cli::try_parse_from(["download", "--encrypt-to", "ops@example.com", "--append", "http://example.com/db.dump"]).is_err());
+        assert!(Cli::try_parse_from(["download", "--encrypt-to", "ops@example.com", "--warc", "a.warc", "http://example.com/db.dump"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_extract() {
+        let args = Cli::try_parse_from(["download", "--extract", "--extract-to", "tools", "--delete-archive", "http://example.com/tools.zip"]).unwrap();
+        assert!(args.extract && args.delete_archive);
+        assert_eq!(args.extract_to, Some(PathBuf::from("tools")));
+        let args = Cli::try_parse_from(["download", "--extract", "--extract-max-size", "2G", "http://example.com/tools.zip"]).unwrap();
+        assert_eq!(args.extract_max_size, Some(2 << 30));
+        assert!(Cli::try_parse_from(["download", "--delete-archive", "http://example.com/tools.zip"]).is_err());
+        assert!(Cli::try_parse_from(["download", "--extract", "--stdout", "http://example.com/tools.zip"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_decompress() {
+        let args = Cli::try_parse_from(["download", "--decompress", "--decompressed-checksum", "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824", "http://example.com/dump.sql.gz"]).unwrap();
+        assert!(args.decompress);
+        assert_eq!(args.decompressed_checksum.unwrap().algorithm, HashAlgorithm::Sha256);
+        assert!(Cli::try_parse_from(["download", "--decompressed-checksum", "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824", "http://example.com/dump.sql.gz"]).is_err());
+        assert!(Cli::try_parse_from(["download", "--decompress", "--stdout", "http://example.com/dump.sql.gz"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_no_preserve_time() {
+        let args = Cli::try_parse_from(["download", "http://example.com/a"]).unwrap();
+        assert!(!args.no_preserve_time);
+        let args = Cli::try_parse_from(["download", "--no-preserve-time", "http://example.com/a"]).unwrap();
+        assert!(args.no_preserve_time);
+        assert!(Cli::try_parse_from(["download", "--no-preserve-time", "-N", "http://example.com/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_append() {
+        let args = Cli::try_parse_from(["download", "--append", "http://example.com/big.iso"]).unwrap();
+        assert!(args.append);
+        assert!(Cli::try_parse_from(["download", "--append", "--range", "0-1k", "http://example.com/big.iso"]).is_err());
+        assert!(Cli::try_parse_from(["download", "--append", "-N", "http://example.com/big.iso"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_output_template() {
+        let args = Cli::try_parse_from(["download", "--output-template", "{host}/{filename}", "http://example.com/a"]).unwrap();
+        assert_eq!(args.output_template, Some("{host}/{filename}".parse().unwrap()));
+        assert!(Cli::try_parse_from(["download", "--output-template", "{hostname}", "http://example.com/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_on_cancel() {
+        let args = Cli::try_parse_from(["download", "http://example.com/a"]).unwrap();
+        assert_eq!(args.on_cancel, CancelPolicy::KeepPart);
+        assert_eq!(args.deadline, None);
+        let args = Cli::try_parse_from(["download", "--on-cancel", "delete", "--deadline", "2h", "http://example.com/a"]).unwrap();
+        assert_eq!(args.on_cancel, CancelPolicy::Delete);
+        assert_eq!(args.deadline, Some(Duration::from_secs(7200)));
+        assert!(Cli::try_parse_from(["download", "--on-cancel", "shred", "http://example.com/a"]).is_err());
+        assert!(Cli::try_parse_from(["download", "--no-keep-partial", "http://example.com/a"]).unwrap().no_keep_partial);
+        assert!(Cli::try_parse_from(["download", "--no-keep-partial", "--on-cancel", "ask", "http://example.com/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_continue_session() {
+        let args = Cli::try_parse_from(["download", "--continue-session", "out/.download-session.json"]).unwrap();
+        assert_eq!(args.continue_session, Some(PathBuf::from("out/.download-session.json")));
+        assert!(args.urls.is_empty());
+        assert!(Cli::try_parse_from(["download"]).is_err());
+        assert!(Cli::try_parse_from(["download", "--dry-run", "--continue-session", "out/.download-session.json"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_progress_template() {
+        let args = Cli::try_parse_from(["download", "--progress-template", "{prefix} {bar:40} {eta}", "--progress-chars", "#>-", "http://example.com/a"]).unwrap();
+        assert_eq!(args.progress_template.as_deref(), Some("{prefix} {bar:40} {eta}"));
+        assert_eq!(args.progress_chars.as_deref(), Some("#>-"));
+        let args = Cli::try_parse_from(["download", "resume", "--progress-template", "{msg}", "a.part"]).unwrap();
+        assert_eq!(args.progress_template.as_deref(), Some("{msg}"));
+    }
+
+    #[test]
+    fn test_cli_parsing_range() {
+        let args = Cli::try_parse_from(["download", "--range", "100M-200M", "http://example.com/disk.img"]).unwrap();
+        let range = args.range.unwrap();
+        assert_eq!((range.start, range.end), (100 << 20, Some(200 << 20)));
+        assert!(Cli::try_parse_from(["download", "--range", "200M-100M", "http://example.com/disk.img"]).is_err());
+        assert!(Cli::try_parse_from(["download", "--range", "0-1k", "--small-files", "http://example.com/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_warc() {
+        let args = Cli::try_parse_from(["download", "--warc", "crawl.warc", "http://example.com/a"]).unwrap();
+        assert_eq!(args.warc, Some(PathBuf::from("crawl.warc")));
+        assert!(Cli::try_parse_from(["download", "http://example.com/a", "--warc"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_save_page() {
+        let args = Cli::try_parse_from(["download", "--save-page", "http://example.com/a"]).unwrap();
+        assert_eq!(args.save_page, Some(PageFormat::Directory));
+        assert_eq!(args.urls, vec!["http://example.com/a"]);
+        let args = Cli::try_parse_from(["download", "--save-page=mhtml", "http://example.com/a"]).unwrap();
+        assert_eq!(args.save_page, Some(PageFormat::Mhtml));
+        assert!(Cli::try_parse_from(["download", "--save-page=pdf", "http://example.com/a"]).is_err());
+        assert!(Cli::try_parse_from(["download", "--save-page", "--small-files", "http://example.com/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_order() {
+        let args = Cli::try_parse_from(["download", "--order", "smallest-first", "http://example.com/a"]).unwrap();
+        assert_eq!(args.order, DownloadOrder::SmallestFirst);
+        let args = Cli::try_parse_from(["download", "http://example.com/a"]).unwrap();
+        assert_eq!(args.order, DownloadOrder::Fifo);
+        assert!(Cli::try_parse_from(["download", "--order", "alphabetical", "http://example.com/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_verify_subcommand() {
+        let args = Cli::try_parse_from(["download", "verify", "-P", "mirror", "-j", "4", "urls.txt", "http://example.com/a.iso"]).unwrap();
+        match args.command {
+            Some(Command::Verify { urls, output_dir, jobs }) => {
+                assert_eq!(urls, ["urls.txt", "http://example.com/a.iso"]);
+                assert_eq!(output_dir, Some(PathBuf::from("mirror")));
+                assert_eq!(jobs, Some(4));
+            }
+            other => panic!("unexpected command: {:?}", other),
+        }
+        assert!(Cli::try_parse_from(["download", "verify"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_resume_subcommand() {
+        let args = Cli::try_parse_from(["download", "resume"]).unwrap();
+        assert!(matches!(args.command, Some(Command::Resume { paths, .. }) if paths.is_empty()));
+
+        let args = Cli::try_parse_from(["download", "resume", "/mnt/usb/big.iso.part", "downloads"]).unwrap();
+        match args.command {
+            Some(Command::Resume { paths, .. }) => assert_eq!(paths, vec![PathBuf::from("/mnt/usb/big.iso.part"), PathBuf::from("downloads")]),
+            other => panic!("unexpected command: {:?}", other),
+        }
+        let args = Cli::try_parse_from(["download", "resume", "--all"]).unwrap();
+        assert!(matches!(args.command, Some(Command::Resume { all: true, .. })));
+        assert!(Cli::try_parse_from(["download", "resume", "--all", "downloads"]).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "state-db")]
+    fn test_cli_parsing_history_subcommand() {
+        let args = Cli::try_parse_from(["download", "history"]).unwrap();
+        assert!(matches!(args.command, Some(Command::History { limit: 20, unfinished: false })));
+        let args = Cli::try_parse_from(["download", "history", "-n", "5", "--unfinished"]).unwrap();
+        assert!(matches!(args.command, Some(Command::History { limit: 5, unfinished: true })));
+        let args = Cli::try_parse_from(["download", "--skip-downloaded", "http://example.com/a"]).unwrap();
+        assert!(args.skip_downloaded);
+        let args = Cli::try_parse_from(["download", "resume", "--all", "--no-state"]).unwrap();
+        assert!(args.no_state);
+    }
+
+    #[test]
+    fn test_cli_parsing_verify_tree_subcommand() {
+        let args = Cli::try_parse_from(["download", "verify-tree", "/data", "--manifest", "m.json", "--jobs", "8"]).unwrap();
+        match args.command {
+            Some(Command::VerifyTree { dir, manifest, jobs }) => {
+                assert_eq!((dir, manifest, jobs), (PathBuf::from("/data"), PathBuf::from("m.json"), Some(8)));
+            }
+            other => panic!("unexpected command: {:?}", other),
+        }
+        assert!(Cli::try_parse_from(["download", "verify-tree", "/data"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_stats_subcommand() {
+        let args = Cli::try_parse_from(["download", "stats", "--flaky", "--since", "30d"]).unwrap();
+        match args.command {
+            Some(Command::Stats { flaky, since }) => assert_eq!((flaky, since), (true, Some(Duration::from_secs(30 * 86400)))),
+            other => panic!("unexpected command: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_cli_parsing_daemon_subcommands() {
+        let args = Cli::try_parse_from(["download", "add", "-P", "/srv/isos", "https://example.com/a.iso", "https://example.com/b.iso"]).unwrap();
+        match args.command {
+            Some(Command::Add { urls, output_dir, .. }) => {
+                assert_eq!(urls, ["https://example.com/a.iso", "https://example.com/b.iso"]);
+                assert_eq!(output_dir, Some(PathBuf::from("/srv/isos")));
+            }
+            other => panic!("unexpected command: {:?}", other),
+        }
+        assert!(Cli::try_parse_from(["download", "add"]).is_err());
+        assert!(matches!(Cli::try_parse_from(["download", "daemon", "-j", "2"]).unwrap().command, Some(Command::Daemon { jobs: Some(2), api: None, .. })));
+        let args = Cli::try_parse_from(["download", "daemon", "--api", "--api-token", "s3cret"]).unwrap();
+        assert!(!format!("{:?}", args).contains("s3cret"));
+        match args.command {
+            Some(Command::Daemon { api, api_token, .. }) => assert_eq!((api.as_deref(), api_token.as_deref()), (Some(api::DEFAULT_ADDRESS), Some("s3cret"))),
+            other => panic!("unexpected command: {:?}", other),
+        }
+        assert!(Cli::try_parse_from(["download", "daemon", "--api-token", "s3cret"]).is_err());
+        assert!(matches!(Cli::try_parse_from(["download", "status"]).unwrap().command, Some(Command::Status { id: None })));
+        assert!(matches!(Cli::try_parse_from(["download", "pause", "3", "4"]).unwrap().command, Some(Command::Pause { ids }) if ids == [3, 4]));
+        assert!(matches!(Cli::try_parse_from(["download", "resume", "--job", "3"]).unwrap().command, Some(Command::Resume { jobs, .. }) if jobs == [3]));
+        assert!(Cli::try_parse_from(["download", "resume", "--job", "3", "file.part"]).is_err());
+        assert!(Cli::try_parse_from(["download", "cancel"]).is_err());
+    }
+
+    #[cfg(feature = "keyring")]
+    #[test]
+    fn test_cli_parsing_auth_subcommand() {
+        let args = Cli::try_parse_from(["download", "auth", "add", "example.com", "--user", "me"]).unwrap();
+        match args.command {
+            Some(Command::Auth(AuthCommand::Add { host, user, bearer, .. })) => {
+                assert_eq!(host, "example.com");
+                assert_eq!(user.as_deref(), Some("me"));
+                assert!(!bearer);
+            }
+            other => panic!("Expected auth add command, got {:?}", other),
+        }
+
+        // Basic credentials need a username, and bearer tokens don't take one
+        assert!(Cli::try_parse_from(["download", "auth", "add", "example.com"]).is_err());
+        assert!(Cli::try_parse_from(["download", "auth", "add", "example.com", "--bearer", "--user", "me"]).is_err());
+        assert!(Cli::try_parse_from(["download", "auth", "add", "example.com", "--bearer"]).is_ok());
+
+        let args = Cli::try_parse_from(["download", "auth", "login", "github", "--client-id", "Iv1.abc"]).unwrap();
+        assert!(matches!(
+            args.command,
+            Some(Command::Auth(AuthCommand::Login { provider, client_id: Some(_) })) if provider == "github"
+        ));
+    }
+
+    #[test]
+    fn test_cli_parsing_checksums_subcommand() {
+        let args = Cli::try_parse_from([
+            "download", "checksums", "import", "SHA256SUMS", "--base-url", "https://example.com/releases/"
+        ]).unwrap();
+        assert!(args.urls.is_empty());
+        match args.command {
+            Some(Command::Checksums(ChecksumsCommand::Import { file, base_url, algorithm })) => {
+                assert_eq!(file, PathBuf::from("SHA256SUMS"));
+                assert_eq!(base_url, "https://example.com/releases/");
+                assert_eq!(algorithm, None);
+            }
+            other => panic!("Expected checksums import command, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "browser-cookies")]
     #[test]
     fn test_validate_browser_argument_valid() {
         let result = validate_browser_argument(Some("chrome".to_string()));
@@ -329,6 +2552,7 @@ mod tests {
         assert_eq!(result.unwrap(), Some(BrowserType::Edge));
     }
 
+    #[cfg(feature = "browser-cookies")]
     #[test]
     fn test_validate_browser_argument_case_insensitive() {
         let result = validate_browser_argument(Some("CHROME".to_string()));
@@ -340,6 +2564,7 @@ mod tests {
         assert_eq!(result.unwrap(), Some(BrowserType::Firefox));
     }
 
+    #[cfg(feature = "browser-cookies")]
     #[test]
     fn test_validate_browser_argument_none() {
         let result = validate_browser_argument(None);
@@ -347,6 +2572,7 @@ mod tests {
         assert_eq!(result.unwrap(), None);
     }
 
+    #[cfg(feature = "browser-cookies")]
     #[test]
     fn test_validate_browser_argument_invalid() {
         let result = validate_browser_argument(Some("invalid".to_string()));
@@ -359,6 +2585,7 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "browser-cookies")]
     #[test]
     fn test_validate_browser_argument_empty() {
         let result = validate_browser_argument(Some("".to_string()));
@@ -373,7 +2600,7 @@ mod tests {
 
     #[test]
     fn test_cli_help_contains_browser_options() {
-        let help_output = Cli::try_parse_from(&["download", "--help"]);
+        let help_output = Cli::try_parse_from(["download", "--help"]);
         assert!(help_output.is_err());
         
         // The help should be in the error message
@@ -387,12 +2614,13 @@ mod tests {
 
     #[test]
     fn test_cli_parsing_browser_with_equals() {
-        let args = Cli::try_parse_from(&["download", "--browser=chrome", "http://example.com"]).unwrap();
+        let args = Cli::try_parse_from(["download", "--browser=chrome", "http://example.com"]).unwrap();
         assert_eq!(args.urls, vec!["http://example.com"]);
         assert_eq!(args.browser, Some("chrome".to_string()));
     }
 
     // Integration tests for complete CLI-to-cookie-fetching flow
+    #[cfg(feature = "browser-cookies")]
     #[test]
     fn test_integration_browser_selection_valid() {
         // Test that valid browser selection works end-to-end
@@ -410,6 +2638,7 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "browser-cookies")]
     #[test]
     fn test_integration_browser_selection_invalid() {
         // Test that invalid browser selection fails appropriately
@@ -430,6 +2659,7 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "browser-cookies")]
     #[test]
     fn test_integration_backward_compatibility() {
         // Test that no browser argument works (backward compatibility)
@@ -438,6 +2668,7 @@ mod tests {
         assert_eq!(result.unwrap(), None);
     }
 
+    #[cfg(feature = "browser-cookies")]
     #[test]
     fn test_integration_cli_to_browser_type_flow() {
         // Test complete flow from CLI parsing to browser type validation
@@ -450,7 +2681,7 @@ mod tests {
 
         for (browser_str, expected_type) in test_cases {
             // Parse CLI arguments
-            let args = Cli::try_parse_from(&[
+            let args = Cli::try_parse_from([
                 "download", 
                 "--browser", browser_str, 
                 "http://example.com"
@@ -464,6 +2695,7 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "browser-cookies")]
     #[test]
     fn test_integration_error_message_format() {
         // Test that error messages are user-friendly
@@ -478,6 +2710,7 @@ mod tests {
         assert!(user_message.contains("chrome") || user_message.contains("firefox"));
     }
 
+    #[cfg(feature = "browser-cookies")]
     #[test]
     fn test_integration_case_insensitive_flow() {
         // Test that case-insensitive browser names work end-to-end
@@ -489,7 +2722,7 @@ mod tests {
         ];
 
         for (browser_str, expected_type) in test_cases {
-            let args = Cli::try_parse_from(&[
+            let args = Cli::try_parse_from([
                 "download", 
                 "--browser", browser_str, 
                 "http://example.com"
@@ -501,6 +2734,7 @@ mod tests {
     }
 
     // Test the main function error handling (without actually running download_file)
+    #[cfg(feature = "browser-cookies")]
     #[test]
     fn test_main_function_browser_validation() {
         // This test verifies that the main function properly validates browser arguments
@@ -521,9 +2755,10 @@ mod tests {
     }
 
     // Integration tests for HTTP requests with cookies from different browsers
+    #[cfg(feature = "browser-cookies")]
     #[test]
     fn test_integration_cookie_jar_wrapper_with_reqwest() {
-        use crate::cookies::CookieJarWrapper;
+        use rustdl::cookies::CookieJarWrapper;
         use reqwest::cookie::CookieStore;
         use url::Url;
         
@@ -540,11 +2775,12 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "browser-cookies")]
     #[test]
     fn test_integration_client_creation_with_cookies() {
         // Test that we can create a reqwest client with cookie support
         if let Ok(cookie_manager) = CookieManager::with_auto_detection() {
-            let cookiejar_wrapper = crate::cookies::CookieJarWrapper::new(cookie_manager);
+            let cookiejar_wrapper = rustdl::cookies::CookieJarWrapper::new(cookie_manager);
             let cookie_store = std::sync::Arc::new(cookiejar_wrapper);
             
             // Test that we can create a client with the cookie store
@@ -565,18 +2801,19 @@ mod tests {
         assert!(client_result.is_ok(), "Should be able to create client without cookies");
     }
 
+    #[cfg(feature = "browser-cookies")]
     #[test]
     fn test_integration_cookie_manager_error_handling() {
         // Test that cookie manager errors are handled gracefully
-        use crate::cookies::CookieJarWrapper;
+        use rustdl::cookies::CookieJarWrapper;
         use reqwest::cookie::CookieStore;
         use url::Url;
         
         // Create a mock strategy that always errors
         struct ErrorStrategy;
-        impl crate::browser::BrowserStrategy for ErrorStrategy {
-            fn fetch_cookies(&self, _domains: Vec<String>) -> Result<Vec<rookie::common::enums::Cookie>, crate::browser::BrowserError> {
-                Err(crate::browser::BrowserError::cookie_fetch_error("test", "Mock error"))
+        impl rustdl::browser::BrowserStrategy for ErrorStrategy {
+            fn fetch_cookies(&self, _domains: Vec<String>) -> Result<Vec<rookie::common::enums::Cookie>, rustdl::browser::BrowserError> {
+                Err(rustdl::browser::BrowserError::cookie_fetch_error("test", "Mock error"))
             }
             fn is_available(&self) -> bool { true }
             fn browser_name(&self) -> &'static str { "test" }
@@ -591,18 +2828,19 @@ mod tests {
         assert!(result.is_none(), "Should return None when cookie fetching fails");
     }
 
+    #[cfg(feature = "browser-cookies")]
     #[test]
     fn test_integration_cookie_filtering_with_different_browsers() {
         // Test that cookie filtering works consistently across different browser strategies
-        use crate::cookies::CookieJarWrapper;
+        use rustdl::cookies::CookieJarWrapper;
         use reqwest::cookie::CookieStore;
         use url::Url;
         use rookie::common::enums::Cookie;
         
         // Create a mock strategy that returns test cookies
         struct TestStrategy;
-        impl crate::browser::BrowserStrategy for TestStrategy {
-            fn fetch_cookies(&self, _domains: Vec<String>) -> Result<Vec<Cookie>, crate::browser::BrowserError> {
+        impl rustdl::browser::BrowserStrategy for TestStrategy {
+            fn fetch_cookies(&self, _domains: Vec<String>) -> Result<Vec<Cookie>, rustdl::browser::BrowserError> {
                 Ok(vec![
                     Cookie {
                         domain: "example.com".to_string(),
@@ -0,0 +1,173 @@
+use std::fmt;
+use std::sync::Arc;
+
+use clap::crate_version;
+use log::info;
+use reqwest::Method;
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::{self, HeaderMap, HeaderValue};
+
+#[cfg(feature = "browser-cookies")]
+use crate::cookies::CookieJarWrapper;
+use crate::logging;
+
+/// What a middleware gets to know about the request it's shaping or the
+/// response it's looking at
+#[derive(Debug, Clone, Copy)]
+pub struct DownloadContext<'a> {
+    pub method: &'a Method,
+    /// The URL as it was asked for, before any redirects
+    pub url: &'a str,
+}
+
+/// Shapes every request before it's sent: adds headers, starts a trace, and so on
+pub trait RequestMiddleware: Send + Sync {
+    fn request(&self, request: RequestBuilder, context: &DownloadContext) -> RequestBuilder;
+}
+
+impl<F> RequestMiddleware for F
+where
+    F: Fn(RequestBuilder, &DownloadContext) -> RequestBuilder + Send + Sync,
+{
+    fn request(&self, request: RequestBuilder, context: &DownloadContext) -> RequestBuilder {
+        self(request, context)
+    }
+}
+
+/// Looks at every response as soon as its headers arrive, and can turn it
+/// down, failing the download with the reason given
+pub trait ResponseInterceptor: Send + Sync {
+    fn response(&self, response: &Response, context: &DownloadContext) -> Result<(), String>;
+}
+
+impl<F> ResponseInterceptor for F
+where
+    F: Fn(&Response, &DownloadContext) -> Result<(), String> + Send + Sync,
+{
+    fn response(&self, response: &Response, context: &DownloadContext) -> Result<(), String> {
+        self(response, context)
+    }
+}
+
+/// The middleware every request goes through and the interceptors every
+/// response does, in the order they were added
+#[derive(Clone, Default)]
+pub struct Middleware {
+    requests: Vec<Arc<dyn RequestMiddleware>>,
+    responses: Vec<Arc<dyn ResponseInterceptor>>,
+}
+
+impl fmt::Debug for Middleware {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Middleware").field("requests", &self.requests.len()).field("responses", &self.responses.len()).finish()
+    }
+}
+
+impl Middleware {
+    /// What every request gets: our User-Agent, and responses logged with `-vv`
+    pub fn standard() -> Self {
+        Middleware::default().with_request(UserAgent::default()).with_response(LogResponses)
+    }
+
+    /// Add a middleware to the end of the request chain
+    pub fn with_request(mut self, middleware: impl RequestMiddleware + 'static) -> Self {
+        self.requests.push(Arc::new(middleware));
+        self
+    }
+
+    /// Add an interceptor to the end of the response chain
+    pub fn with_response(mut self, interceptor: impl ResponseInterceptor + 'static) -> Self {
+        self.responses.push(Arc::new(interceptor));
+        self
+    }
+
+    /// A request for `url`, shaped by each middleware in turn
+    pub fn request(&self, client: &Client, method: Method, url: &str) -> RequestBuilder {
+        let request = client.request(method.clone(), url);
+        let context = DownloadContext { method: &method, url };
+        self.requests.iter().fold(request, |request, middleware| middleware.request(request, &context))
+    }
+
+    /// A GET request for `url`
+    pub fn get(&self, client: &Client, url: &str) -> RequestBuilder {
+        self.request(client, Method::GET, url)
+    }
+
+    /// Show `response` to each interceptor in turn, stopping at the first that turns it down
+    pub fn intercept(&self, response: &Response, context: &DownloadContext) -> Result<(), String> {
+        self.responses.iter().try_for_each(|interceptor| interceptor.response(response, context))
+    }
+}
+
+/// Says who we are, and that we'll take whatever the server has
+#[derive(Debug, Clone)]
+pub struct UserAgent(HeaderMap);
+
+impl Default for UserAgent {
+    fn default() -> Self {
+        let user_agent = format!("rust-downloader/{} (https://github.com/danudey/rust-downloader)", crate_version!());
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_static("*/*"));
+        headers.insert(header::USER_AGENT, HeaderValue::from_str(&user_agent).expect("the user agent is a valid header value"));
+        UserAgent(headers)
+    }
+}
+
+impl RequestMiddleware for UserAgent {
+    fn request(&self, request: RequestBuilder, _context: &DownloadContext) -> RequestBuilder {
+        // Replacing rather than adding, so whatever set them before us doesn't get a second value
+        request.headers(self.0.clone())
+    }
+}
+
+/// Sends the cookies a browser has for the URL. The client still asks the
+/// same jar after a redirect, since the new host's cookies can't be known
+/// until then.
+#[cfg(feature = "browser-cookies")]
+pub struct BrowserCookies(pub Arc<CookieJarWrapper>);
+
+#[cfg(feature = "browser-cookies")]
+impl RequestMiddleware for BrowserCookies {
+    fn request(&self, request: RequestBuilder, context: &DownloadContext) -> RequestBuilder {
+        use reqwest::cookie::CookieStore;
+
+        match url::Url::parse(context.url).ok().and_then(|url| self.0.cookies(&url)) {
+            Some(cookies) => request.header(header::COOKIE, cookies),
+            None => request,
+        }
+    }
+}
+
+/// Logs each response's status, where it came from with `-v` if that isn't
+/// where we asked, and its headers with `-vvv`
+#[derive(Debug, Clone, Copy)]
+pub struct LogResponses;
+
+impl ResponseInterceptor for LogResponses {
+    fn response(&self, response: &Response, context: &DownloadContext) -> Result<(), String> {
+        if url::Url::parse(context.url).ok().as_ref() != Some(response.url()) {
+            info!("{} {} ended up at {}", context.method, context.url, response.url());
+        }
+        logging::response(response);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_middleware_runs_in_order() {
+        let client = Client::new();
+        let middleware = Middleware::default()
+            .with_request(UserAgent::default())
+            .with_request(|request: RequestBuilder, context: &DownloadContext| request.header("x-trace", format!("{} {}", context.method, context.url)))
+            .with_request(|request: RequestBuilder, _: &DownloadContext| request.header("x-trace", "second"));
+        let request = middleware.request(&client, Method::HEAD, "http://example.com/a").build().unwrap();
+        assert_eq!(request.headers().get_all("x-trace").iter().collect::<Vec<_>>(), ["HEAD http://example.com/a", "second"]);
+        assert_eq!(request.headers()[header::ACCEPT], "*/*");
+        let standard = Middleware::standard().get(&client, "http://example.com/a").build().unwrap();
+        assert!(standard.headers()[header::USER_AGENT].to_str().unwrap().starts_with("rust-downloader/"));
+    }
+}
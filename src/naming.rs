@@ -0,0 +1,298 @@
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use log::debug;
+use reqwest::header::HeaderMap;
+use url::Url;
+
+use crate::disposition;
+use crate::partial::CONTROL_SUFFIX;
+use crate::template::{self, OutputTemplate};
+
+/// Names Windows reserves for devices, whatever extension they're given
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2",
+    "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Extensions that belong with the one before them, so `a.tar.gz` becomes `a.1.tar.gz`
+const COMPOUND_EXTENSIONS: &[&str] = &["tar"];
+
+/// How the path a download is saved to is chosen, one step at a time: the
+/// name at the end of the URL, the server's suggestion (which wins), making
+/// that safe, placing the name in the output directory, and keeping clear of
+/// other downloads. Embedders can implement this to change any of the steps
+/// and leave the others to the defaults.
+pub trait NamePolicy: Send + Sync {
+    /// The name the URL suggests, before the server's had its say
+    fn url_name(&self, url: &Url) -> Option<PathBuf> {
+        url.path_segments()?.next_back().filter(|name| !name.is_empty()).map(PathBuf::from)
+    }
+
+    /// The name the response suggests (from `Content-Disposition`), made safe
+    fn server_name(&self, headers: &HeaderMap) -> Option<PathBuf> {
+        self.sanitize(&disposition::suggested_filename(headers)?)
+    }
+
+    /// Make a name that came from the server safe to save, or `None` if
+    /// there's nothing usable left of it
+    fn sanitize(&self, name: &str) -> Option<PathBuf>;
+
+    /// Where to save `filename`, from `url` (the `index`th URL of the run),
+    /// or `None` if there's nowhere sensible
+    fn place(&self, url: &Url, filename: &Path, index: usize) -> Option<PathBuf>;
+
+    /// Reserve `path` for a download, so no other download in the run writes there
+    fn claim(&self, path: PathBuf) -> PathBuf;
+}
+
+/// The naming the command line asks for: `--trust-server-names`,
+/// `--output-dir`, `--output-template` and `--auto-rename`
+#[derive(Debug, Default)]
+pub struct StandardNames {
+    trust_server_names: bool,
+    output_dir: PathBuf,
+    template: Option<OutputTemplate>,
+    names: OutputNames,
+    /// Save URLs ending in `/` as `index.html`, as a mirror of a site needs
+    index_pages: bool,
+}
+
+impl StandardNames {
+    pub fn new(trust_server_names: bool, output_dir: PathBuf, template: Option<OutputTemplate>, auto_rename: bool) -> Self {
+        StandardNames { trust_server_names, output_dir, template, names: OutputNames::new(auto_rename), index_pages: false }
+    }
+
+    /// Name directory URLs `index.html` instead of leaving them to the server (`--recursive`)
+    pub fn index_pages(mut self) -> Self {
+        self.index_pages = true;
+        self
+    }
+}
+
+impl NamePolicy for StandardNames {
+    fn url_name(&self, url: &Url) -> Option<PathBuf> {
+        match url.path_segments()?.next_back()? {
+            "" if self.index_pages => Some(PathBuf::from("index.html")),
+            "" => None,
+            name => Some(PathBuf::from(name)),
+        }
+    }
+
+    fn server_name(&self, headers: &HeaderMap) -> Option<PathBuf> {
+        disposition::filename(headers, self.trust_server_names)
+    }
+
+    fn sanitize(&self, name: &str) -> Option<PathBuf> {
+        sanitize(name, self.trust_server_names)
+    }
+
+    fn place(&self, url: &Url, filename: &Path, index: usize) -> Option<PathBuf> {
+        template::output_path(self.template.as_ref(), &self.output_dir, url, filename, index)
+    }
+
+    fn claim(&self, path: PathBuf) -> PathBuf {
+        self.names.claim(path)
+    }
+}
+
+/// Hands out the paths downloads are saved to, so that two downloads in the
+/// same run never write to the same file. With auto-rename, a name that's
+/// already taken (on disk or by another download) gets a number added, the
+/// way browsers do it: `file.ext`, `file.1.ext`, `file.2.ext`, ...
+#[derive(Debug, Default)]
+pub struct OutputNames {
+    auto_rename: bool,
+    claimed: Mutex<HashSet<PathBuf>>,
+}
+
+impl OutputNames {
+    pub fn new(auto_rename: bool) -> Self {
+        Self { auto_rename, claimed: Mutex::new(HashSet::new()) }
+    }
+
+    /// Reserve a path to save a download to, renaming it if it's taken and
+    /// we're allowed to
+    pub fn claim(&self, path: PathBuf) -> PathBuf {
+        let mut claimed = self.claimed.lock().unwrap();
+        if !self.auto_rename {
+            claimed.insert(path.clone());
+            return path;
+        }
+        let chosen = (0..)
+            .map(|n| numbered(&path, n))
+            .find(|candidate| !claimed.contains(candidate) && !is_taken(candidate))
+            .unwrap();
+        if chosen != path {
+            debug!("{} is taken, saving as {}", path.display(), chosen.display());
+        }
+        claimed.insert(chosen.clone());
+        chosen
+    }
+}
+
+/// Make a file name suggested by a server safe to save in the output
+/// directory, or `None` if there's nothing usable left of it.
+///
+/// Control characters and `.`/`..` components always go, so the name can't
+/// climb out of the output directory. By default only the last path
+/// component is kept, leading dots are dropped (no surprise hidden files) and
+/// Windows device names get an underscore in front. With
+/// `trust_server_names`, the name may include subdirectories, and is
+/// otherwise kept as it is.
+pub fn sanitize(name: &str, trust_server_names: bool) -> Option<PathBuf> {
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    let mut components = name.split(['/', '\\']).map(str::trim).filter(|c| !c.is_empty() && *c != "." && *c != "..");
+
+    let sanitized = if trust_server_names {
+        // Drive letters (C:) would make the path absolute on Windows
+        components.filter(|component| !component.ends_with(':')).collect::<PathBuf>()
+    } else {
+        let name = components.next_back()?.trim_start_matches('.').trim_end_matches(['.', ' ']);
+        let stem = name.split('.').next().unwrap_or_default().trim_end();
+        if RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem)) {
+            PathBuf::from(format!("_{}", name))
+        } else {
+            PathBuf::from(name)
+        }
+    };
+    if sanitized.as_os_str().is_empty() {
+        return None;
+    }
+    if sanitized.as_os_str() != name.as_str() {
+        debug!("Saving {:?} as {}", name, sanitized.display());
+    }
+    Some(sanitized)
+}
+
+/// Whether a file, or a partial download of one, is already there
+fn is_taken(path: &Path) -> bool {
+    let mut control = path.as_os_str().to_owned();
+    control.push(CONTROL_SUFFIX);
+    path.exists() || Path::new(&control).exists()
+}
+
+/// The `n`th alternative name for `path`; 0 is the path itself
+pub fn numbered(path: &Path, n: usize) -> PathBuf {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()).filter(|_| n > 0) else {
+        return path.to_path_buf();
+    };
+    // A leading dot starts a hidden file's name, not an extension
+    let split = name
+        .char_indices()
+        .skip(1)
+        .filter(|&(_, c)| c == '.')
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+    let extension_start = match split.as_slice() {
+        [.., before, last] if COMPOUND_EXTENSIONS.contains(&&name[before + 1..*last]) => Some(*before),
+        [.., last] => Some(*last),
+        [] => None,
+    };
+    let renamed: OsString = match extension_start {
+        Some(index) => format!("{}.{}{}", &name[..index], n, &name[index..]).into(),
+        None => format!("{}.{}", name, n).into(),
+    };
+    path.with_file_name(renamed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_numbered() {
+        assert_eq!(numbered(Path::new("dir/file.txt"), 0), Path::new("dir/file.txt"));
+        assert_eq!(numbered(Path::new("dir/file.txt"), 1), Path::new("dir/file.1.txt"));
+        assert_eq!(numbered(Path::new("release-1.2.zip"), 2), Path::new("release-1.2.2.zip"));
+        assert_eq!(numbered(Path::new("source.tar.gz"), 3), Path::new("source.3.tar.gz"));
+        assert_eq!(numbered(Path::new("README"), 1), Path::new("README.1"));
+        assert_eq!(numbered(Path::new(".bashrc"), 1), Path::new(".bashrc.1"));
+    }
+
+    #[test]
+    fn test_sanitize() {
+        let sanitize = |name| sanitize(name, false).map(|path| path.to_string_lossy().into_owned());
+        assert_eq!(sanitize("report.pdf").as_deref(), Some("report.pdf"));
+        assert_eq!(sanitize("../../etc/passwd").as_deref(), Some("passwd"));
+        assert_eq!(sanitize("C:\\Windows\\System32\\evil.dll").as_deref(), Some("evil.dll"));
+        assert_eq!(sanitize("/absolute/path.txt").as_deref(), Some("path.txt"));
+        assert_eq!(sanitize("bad\u{1b}[31mname\n.txt").as_deref(), Some("bad[31mname.txt"));
+        assert_eq!(sanitize(".bashrc").as_deref(), Some("bashrc"));
+        assert_eq!(sanitize("...hidden.txt..").as_deref(), Some("hidden.txt"));
+        assert_eq!(sanitize("con").as_deref(), Some("_con"));
+        assert_eq!(sanitize("NUL.tar.gz").as_deref(), Some("_NUL.tar.gz"));
+        assert_eq!(sanitize("console.log").as_deref(), Some("console.log"));
+        assert_eq!(sanitize(".."), None);
+        assert_eq!(sanitize(" / . /"), None);
+        assert_eq!(sanitize("..."), None);
+    }
+
+    #[test]
+    fn test_sanitize_trusting_server_names() {
+        let sanitize = |name| sanitize(name, true);
+        assert_eq!(sanitize("docs/2024/report.pdf"), Some(PathBuf::from("docs/2024/report.pdf")));
+        assert_eq!(sanitize(".config"), Some(PathBuf::from(".config")));
+        assert_eq!(sanitize("NUL"), Some(PathBuf::from("NUL")));
+        // Still no escaping the output directory
+        assert_eq!(sanitize("../../etc/passwd"), Some(PathBuf::from("etc/passwd")));
+        assert_eq!(sanitize("/etc/passwd"), Some(PathBuf::from("etc/passwd")));
+        assert_eq!(sanitize("C:\\Windows\\evil.dll"), Some(PathBuf::from("Windows/evil.dll")));
+        assert_eq!(sanitize("a\u{0}b"), Some(PathBuf::from("ab")));
+        assert_eq!(sanitize("../.."), None);
+    }
+
+    #[test]
+    fn test_name_policy_steps_can_be_replaced() {
+        /// Everything goes in `incoming/`, named after the host
+        struct ByHost(StandardNames);
+
+        impl NamePolicy for ByHost {
+            fn sanitize(&self, name: &str) -> Option<PathBuf> {
+                self.0.sanitize(name)
+            }
+
+            fn place(&self, url: &Url, filename: &Path, _index: usize) -> Option<PathBuf> {
+                Some(Path::new("incoming").join(url.host_str()?).join(filename))
+            }
+
+            fn claim(&self, path: PathBuf) -> PathBuf {
+                self.0.claim(path)
+            }
+        }
+
+        let url = Url::parse("https://example.com/files/report.pdf?download=1").unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(reqwest::header::CONTENT_DISPOSITION, "attachment; filename=\"../Q3 report.pdf\"".parse().unwrap());
+
+        let standard = StandardNames::new(false, PathBuf::from("out"), None, false);
+        assert_eq!(standard.url_name(&url), Some(PathBuf::from("report.pdf")));
+        assert_eq!(standard.server_name(&headers), Some(PathBuf::from("Q3 report.pdf")));
+        assert_eq!(standard.place(&url, Path::new("report.pdf"), 0), Some(PathBuf::from("out/report.pdf")));
+
+        let by_host = ByHost(StandardNames::default());
+        assert_eq!(by_host.server_name(&headers), Some(PathBuf::from("Q3 report.pdf")));
+        assert_eq!(by_host.place(&url, Path::new("report.pdf"), 0), Some(PathBuf::from("incoming/example.com/report.pdf")));
+        assert_eq!(by_host.url_name(&Url::parse("https://example.com/").unwrap()), None);
+        let mirror = StandardNames::default().index_pages();
+        assert_eq!(mirror.url_name(&Url::parse("https://example.com/docs/").unwrap()), Some(PathBuf::from("index.html")));
+    }
+
+    #[test]
+    fn test_claim_renames_collisions_within_a_run() {
+        let dir = std::env::temp_dir().join(format!("rustdl-naming-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("file.txt"), b"already here").unwrap();
+
+        let names = OutputNames::new(true);
+        assert_eq!(names.claim(dir.join("file.txt")), dir.join("file.1.txt"));
+        assert_eq!(names.claim(dir.join("file.txt")), dir.join("file.2.txt"));
+        assert_eq!(names.claim(dir.join("other.txt")), dir.join("other.txt"));
+
+        let names = OutputNames::new(false);
+        assert_eq!(names.claim(dir.join("file.txt")), dir.join("file.txt"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,302 @@
+use std::ffi::c_void;
+use std::ptr;
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use log::{debug, info, warn};
+use reqwest::StatusCode;
+use reqwest::blocking::{Client, Request, Response};
+use reqwest::header::{self, HeaderMap, HeaderValue};
+
+use crate::timeouts::{self, RequestError};
+
+/// GSSAPI libraries to try, in order
+#[cfg(target_os = "macos")]
+const GSSAPI_LIBRARIES: &[&str] = &["/System/Library/Frameworks/GSS.framework/GSS"];
+#[cfg(all(unix, not(target_os = "macos")))]
+const GSSAPI_LIBRARIES: &[&str] = &["libgssapi_krb5.so.2", "libgssapi.so.3", "libgssapi_krb5.so"];
+#[cfg(not(unix))]
+const GSSAPI_LIBRARIES: &[&str] = &[];
+
+/// GSS_C_NT_HOSTBASED_SERVICE, 1.2.840.113554.1.2.1.4
+const HOSTBASED_SERVICE_OID: &[u8] = b"\x2a\x86\x48\x86\xf7\x12\x01\x02\x01\x04";
+/// SPNEGO, 1.3.6.1.5.5.2
+const SPNEGO_OID: &[u8] = b"\x2b\x06\x01\x05\x05\x02";
+
+const GSS_C_MUTUAL_FLAG: u32 = 2;
+const GSS_C_GSS_CODE: i32 = 1;
+const GSS_C_MECH_CODE: i32 = 2;
+
+/// GSS_ERROR(): any calling or routine error bits set
+fn gss_error(major: u32) -> bool {
+    major & 0xffff_0000 != 0
+}
+
+// Apple's GSS framework packs these structs to 2 bytes
+#[cfg_attr(target_os = "macos", repr(C, packed(2)))]
+#[cfg_attr(not(target_os = "macos"), repr(C))]
+struct GssOidDesc {
+    length: u32,
+    elements: *const c_void,
+}
+
+#[cfg_attr(target_os = "macos", repr(C, packed(2)))]
+#[cfg_attr(not(target_os = "macos"), repr(C))]
+struct GssBufferDesc {
+    length: usize,
+    value: *mut c_void,
+}
+
+impl GssBufferDesc {
+    fn empty() -> Self {
+        Self { length: 0, value: ptr::null_mut() }
+    }
+
+    /// Copy the buffer's contents out; it still has to be released
+    unsafe fn to_vec(&self) -> Vec<u8> {
+        let (length, value) = (self.length, self.value);
+        if value.is_null() {
+            return Vec::new();
+        }
+        unsafe { std::slice::from_raw_parts(value as *const u8, length) }.to_vec()
+    }
+}
+
+type GssName = *mut c_void;
+type GssContext = *mut c_void;
+
+type ImportNameFn = unsafe extern "C" fn(*mut u32, *const GssBufferDesc, *const GssOidDesc, *mut GssName) -> u32;
+type InitSecContextFn = unsafe extern "C" fn(
+    *mut u32,
+    *const c_void,
+    *mut GssContext,
+    GssName,
+    *const GssOidDesc,
+    u32,
+    u32,
+    *const c_void,
+    *const GssBufferDesc,
+    *mut *const GssOidDesc,
+    *mut GssBufferDesc,
+    *mut u32,
+    *mut u32,
+) -> u32;
+type ReleaseBufferFn = unsafe extern "C" fn(*mut u32, *mut GssBufferDesc) -> u32;
+type ReleaseNameFn = unsafe extern "C" fn(*mut u32, *mut GssName) -> u32;
+type DeleteSecContextFn = unsafe extern "C" fn(*mut u32, *mut GssContext, *mut GssBufferDesc) -> u32;
+type DisplayStatusFn = unsafe extern "C" fn(*mut u32, u32, i32, *const GssOidDesc, *mut u32, *mut GssBufferDesc) -> u32;
+
+/// Errors from Negotiate authentication
+#[derive(Debug, thiserror::Error)]
+pub enum NegotiateError {
+    #[error("Negotiate authentication isn't supported on this platform")]
+    Unsupported,
+
+    #[error("could not load the system GSSAPI library (is Kerberos installed?): {0}")]
+    LibraryUnavailable(String),
+
+    #[error("Kerberos error: {message}")]
+    Gss { major: u32, minor: u32, message: String },
+}
+
+/// Answers `401 Negotiate` challenges with a Kerberos ticket from the system
+/// credential cache (what `kinit` fills in), using the platform's GSSAPI library.
+///
+/// The server's mutual-authentication token isn't verified; like the rest of
+/// the download, we rely on TLS to know who we're talking to.
+pub struct Negotiator {
+    library: libloading::Library,
+}
+
+impl Negotiator {
+    /// Load the system GSSAPI library
+    pub fn new() -> Result<Self, NegotiateError> {
+        if GSSAPI_LIBRARIES.is_empty() {
+            return Err(NegotiateError::Unsupported);
+        }
+        let mut errors = Vec::new();
+        for name in GSSAPI_LIBRARIES {
+            // SAFETY: these are the system GSSAPI libraries, whose initialisers are safe to run
+            match unsafe { libloading::Library::new(name) } {
+                Ok(library) => {
+                    debug!("Loaded GSSAPI from {}", name);
+                    return Ok(Self { library });
+                }
+                Err(e) => errors.push(format!("{}: {}", name, e)),
+            }
+        }
+        Err(NegotiateError::LibraryUnavailable(errors.join("; ")))
+    }
+
+    /// Create the initial SPNEGO token for `HTTP@host`
+    pub fn token(&self, host: &str) -> Result<Vec<u8>, NegotiateError> {
+        let symbol_err = |e: libloading::Error| NegotiateError::LibraryUnavailable(e.to_string());
+        // SAFETY: the signatures match the GSSAPI C bindings (RFC 2744)
+        let (import_name, init_sec_context, release_buffer, release_name, delete_sec_context) = unsafe {
+            (
+                self.library.get::<ImportNameFn>(b"gss_import_name\0").map_err(symbol_err)?,
+                self.library.get::<InitSecContextFn>(b"gss_init_sec_context\0").map_err(symbol_err)?,
+                self.library.get::<ReleaseBufferFn>(b"gss_release_buffer\0").map_err(symbol_err)?,
+                self.library.get::<ReleaseNameFn>(b"gss_release_name\0").map_err(symbol_err)?,
+                self.library.get::<DeleteSecContextFn>(b"gss_delete_sec_context\0").map_err(symbol_err)?,
+            )
+        };
+
+        let hostbased = GssOidDesc { length: HOSTBASED_SERVICE_OID.len() as u32, elements: HOSTBASED_SERVICE_OID.as_ptr().cast() };
+        let spnego = GssOidDesc { length: SPNEGO_OID.len() as u32, elements: SPNEGO_OID.as_ptr().cast() };
+        let service = format!("HTTP@{}", host);
+        let service_buffer = GssBufferDesc { length: service.len(), value: service.as_ptr() as *mut c_void };
+
+        let mut minor = 0;
+        let mut ignored = 0;
+        let mut name: GssName = ptr::null_mut();
+        // SAFETY: all pointers refer to live locals, and everything GSSAPI
+        // allocates is released before we return
+        unsafe {
+            let major = import_name(&mut minor, &service_buffer, &hostbased, &mut name);
+            if gss_error(major) {
+                return Err(self.status_error(major, minor));
+            }
+
+            let mut context: GssContext = ptr::null_mut();
+            let mut output = GssBufferDesc::empty();
+            let major = init_sec_context(
+                &mut minor,
+                ptr::null(),
+                &mut context,
+                name,
+                &spnego,
+                GSS_C_MUTUAL_FLAG,
+                0,
+                ptr::null(),
+                ptr::null(),
+                ptr::null_mut(),
+                &mut output,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            );
+            let result = if gss_error(major) { Err(self.status_error(major, minor)) } else { Ok(output.to_vec()) };
+
+            release_buffer(&mut ignored, &mut output);
+            release_name(&mut ignored, &mut name);
+            if !context.is_null() {
+                delete_sec_context(&mut ignored, &mut context, ptr::null_mut());
+            }
+            result
+        }
+    }
+
+    /// Turn GSSAPI status codes into an error with a readable message
+    fn status_error(&self, major: u32, minor: u32) -> NegotiateError {
+        let mut messages = self.display_status(major, GSS_C_GSS_CODE);
+        if minor != 0 {
+            messages.extend(self.display_status(minor, GSS_C_MECH_CODE));
+        }
+        let message = if messages.is_empty() { format!("major status {:#x}, minor status {}", major, minor) } else { messages.join(": ") };
+        NegotiateError::Gss { major, minor, message }
+    }
+
+    fn display_status(&self, status: u32, status_type: i32) -> Vec<String> {
+        // SAFETY: signature matches the GSSAPI C bindings
+        let Ok(display_status) = (unsafe { self.library.get::<DisplayStatusFn>(b"gss_display_status\0") }) else {
+            return Vec::new();
+        };
+        let Ok(release_buffer) = (unsafe { self.library.get::<ReleaseBufferFn>(b"gss_release_buffer\0") }) else {
+            return Vec::new();
+        };
+
+        let mut messages = Vec::new();
+        let mut message_context = 0;
+        loop {
+            let mut minor = 0;
+            let mut buffer = GssBufferDesc::empty();
+            // SAFETY: pointers refer to live locals and the buffer is released below
+            let major = unsafe {
+                display_status(&mut minor, status, status_type, ptr::null(), &mut message_context, &mut buffer)
+            };
+            if gss_error(major) {
+                break;
+            }
+            // SAFETY: GSSAPI filled the buffer in and we release it right after copying
+            let text = unsafe { buffer.to_vec() };
+            unsafe { release_buffer(&mut minor, &mut buffer) };
+            messages.push(String::from_utf8_lossy(&text).trim().to_string());
+            if message_context == 0 {
+                break;
+            }
+        }
+        messages.retain(|message| !message.is_empty());
+        messages
+    }
+
+    /// Send a request, retrying once with a Negotiate token if the server challenges us for one
+    pub fn execute(&self, client: &Client, request: Request, header_timeout: Option<Duration>) -> Result<Response, RequestError> {
+        let retry = request.try_clone();
+        let response = timeouts::execute(client, request, header_timeout)?;
+        if response.status() != StatusCode::UNAUTHORIZED || !offers_negotiate(response.headers()) {
+            return Ok(response);
+        }
+        let Some(mut retry) = retry else {
+            return Ok(response);
+        };
+        let Some(host) = retry.url().host_str().map(str::to_string) else {
+            return Ok(response);
+        };
+
+        match self.token(&host) {
+            Ok(token) => {
+                info!("Answering Negotiate challenge from {}", host);
+                let value = format!("Negotiate {}", BASE64.encode(token));
+                retry.headers_mut().insert(header::AUTHORIZATION, HeaderValue::from_str(&value).expect("base64 is a valid header value"));
+                timeouts::execute(client, retry, header_timeout)
+            }
+            Err(e) => {
+                warn!("Could not answer Negotiate challenge from {}: {}", host, e);
+                Ok(response)
+            }
+        }
+    }
+}
+
+/// Check whether a 401 response offers Negotiate authentication
+pub fn offers_negotiate(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::WWW_AUTHENTICATE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|challenge| {
+            let scheme = challenge.split_whitespace().next().unwrap_or_default();
+            scheme.eq_ignore_ascii_case("negotiate")
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(header::WWW_AUTHENTICATE, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_offers_negotiate() {
+        assert!(offers_negotiate(&headers(&["Negotiate"])));
+        assert!(offers_negotiate(&headers(&["Basic realm=\"x\"", "negotiate"])));
+        assert!(offers_negotiate(&headers(&["Basic realm=\"x\", Negotiate"])));
+        assert!(!offers_negotiate(&headers(&["Basic realm=\"Negotiate\""])));
+        assert!(!offers_negotiate(&headers(&[])));
+    }
+
+    #[test]
+    fn test_gss_error() {
+        assert!(!gss_error(0));
+        assert!(!gss_error(1)); // GSS_S_CONTINUE_NEEDED
+        assert!(gss_error(7 << 16)); // GSS_S_NO_CRED
+    }
+}
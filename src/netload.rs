@@ -0,0 +1,202 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use serde::Deserialize;
+
+use crate::throttle::{ByteRate, RateLimiter};
+
+/// How often the network's load is measured
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Samples spent downloading flat out at the start, to find out how fast the network can go
+const CALIBRATION_SAMPLES: u32 = 3;
+
+/// Never throttle below this, so downloads keep moving however busy the network is
+const MIN_RATE: u64 = 16 * 1024;
+
+/// The share of the network's capacity downloads may use, written like `70%`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct NetworkShare(u8);
+
+impl NetworkShare {
+    fn of(&self, rate: u64) -> u64 {
+        rate / 100 * u64::from(self.0) + rate % 100 * u64::from(self.0) / 100
+    }
+}
+
+impl FromStr for NetworkShare {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.trim()
+            .trim_end_matches('%')
+            .trim()
+            .parse::<u8>()
+            .ok()
+            .filter(|percent| (1..=100).contains(percent))
+            .map(NetworkShare)
+            .ok_or_else(|| format!("invalid share '{}', expected a percentage from 1% to 100%", s))
+    }
+}
+
+impl TryFrom<String> for NetworkShare {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for NetworkShare {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}%", self.0)
+    }
+}
+
+/// Works out how fast downloads may go from how busy the network is. The
+/// network's capacity is the fastest it's been seen receiving; downloads
+/// get their share of that, less whatever everything else is using.
+#[derive(Debug)]
+struct LoadEstimator {
+    share: NetworkShare,
+    ceiling: Option<ByteRate>,
+    capacity: u64,
+    samples: u32,
+}
+
+impl LoadEstimator {
+    fn new(share: NetworkShare, ceiling: Option<ByteRate>) -> Self {
+        Self { share, ceiling, capacity: 0, samples: 0 }
+    }
+
+    /// The rate to allow, given the whole system's receive rate and ours
+    fn allowance(&mut self, total: u64, own: u64) -> u64 {
+        self.capacity = self.capacity.max(total).max(own);
+        self.samples += 1;
+        let ceiling = self.ceiling.map_or(u64::MAX, |ceiling| ceiling.0);
+        if self.samples <= CALIBRATION_SAMPLES {
+            return ceiling;
+        }
+        let others = total.saturating_sub(own);
+        self.share.of(self.capacity).saturating_sub(others).clamp(MIN_RATE, ceiling.max(MIN_RATE))
+    }
+}
+
+/// Keep adjusting `limiter` so downloads use no more than `share` of the
+/// network, until the limiter is dropped. `ceiling` is the most they may
+/// use however quiet it is (`--limit-rate`).
+pub fn spawn(limiter: &Arc<RateLimiter>, share: NetworkShare, ceiling: Option<ByteRate>) {
+    let Some(mut received) = received_bytes() else {
+        warn!("Can't measure network load on this system; not throttling to {} of it", share);
+        return;
+    };
+    debug!("Throttling downloads to {} of the network", share);
+    let limiter: Weak<RateLimiter> = Arc::downgrade(limiter);
+    let mut transferred = 0;
+    let mut estimator = LoadEstimator::new(share, ceiling);
+    let mut sampled = Instant::now();
+    thread::spawn(move || {
+        loop {
+            thread::sleep(SAMPLE_INTERVAL);
+            let Some(limiter) = limiter.upgrade() else { return };
+            let Some(now_received) = received_bytes() else { return };
+            let elapsed = sampled.elapsed().as_secs_f64().max(0.001);
+            sampled = Instant::now();
+            let now_transferred = limiter.transferred();
+            let total = (now_received.saturating_sub(received) as f64 / elapsed) as u64;
+            let own = (now_transferred.saturating_sub(transferred) as f64 / elapsed) as u64;
+            (received, transferred) = (now_received, now_transferred);
+
+            let allowance = estimator.allowance(total, own);
+            debug!("Network receiving {}/s ({}/s ours); allowing {}/s", total, own, allowance);
+            limiter.set_rate(allowance);
+        }
+    });
+}
+
+/// Bytes received so far by the system's network interfaces
+#[cfg(target_os = "linux")]
+fn received_bytes() -> Option<u64> {
+    let contents = std::fs::read_to_string("/proc/net/dev").ok()?;
+    let interfaces = interface_counters(&contents);
+    // Virtual interfaces (loopback, bridges, VPNs) would count traffic twice or not at all
+    let physical: Vec<_> = interfaces
+        .iter()
+        .filter(|(name, _)| {
+            std::fs::canonicalize(format!("/sys/class/net/{}", name)).is_ok_and(|path| !path.to_string_lossy().contains("/virtual/"))
+        })
+        .collect();
+    match physical.is_empty() {
+        true => Some(interfaces.iter().filter(|(name, _)| *name != "lo").map(|(_, bytes)| bytes).sum()),
+        false => Some(physical.iter().map(|(_, bytes)| bytes).sum()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn received_bytes() -> Option<u64> {
+    None
+}
+
+/// Each interface and the bytes it's received, from `/proc/net/dev`
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn interface_counters(contents: &str) -> Vec<(&str, u64)> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let (name, counters) = line.split_once(':')?;
+            Some((name.trim(), counters.split_whitespace().next()?.parse().ok()?))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_network_share() {
+        assert_eq!("70%".parse::<NetworkShare>().unwrap(), NetworkShare(70));
+        assert_eq!(" 50 ".parse::<NetworkShare>().unwrap(), NetworkShare(50));
+        assert_eq!(NetworkShare(70).of(1000), 700);
+        for invalid in ["0%", "101", "half", ""] {
+            assert!(invalid.parse::<NetworkShare>().is_err(), "{} should be invalid", invalid);
+        }
+    }
+
+    #[test]
+    fn test_allowance_leaves_room_for_other_traffic() {
+        let mut estimator = LoadEstimator::new(NetworkShare(70), None);
+        // Flat out at first, learning that the network does 1,000,000 bytes a second
+        for _ in 0..CALIBRATION_SAMPLES {
+            assert_eq!(estimator.allowance(1_000_000, 1_000_000), u64::MAX);
+        }
+        // Nothing else going on: 70% of it
+        assert_eq!(estimator.allowance(700_000, 700_000), 700_000);
+        // A video call taking 200,000 bytes a second
+        assert_eq!(estimator.allowance(900_000, 700_000), 500_000);
+        // The network's busy with other things, but downloads don't stop altogether
+        assert_eq!(estimator.allowance(1_000_000, 0), MIN_RATE);
+
+        let mut estimator = LoadEstimator::new(NetworkShare(70), Some(ByteRate(100_000)));
+        assert_eq!(estimator.allowance(100_000, 100_000), 100_000);
+        for _ in 0..CALIBRATION_SAMPLES {
+            estimator.allowance(1_000_000, 100_000);
+        }
+        assert_eq!(estimator.allowance(100_000, 100_000), 100_000);
+    }
+
+    #[test]
+    fn test_interface_counters() {
+        let contents = "Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo: 111028944   12317    0    0    0     0          0         0 111028944   12317    0    0    0     0       0          0
+  eth0:  220035     607    0    0    0     0          0         0    50597     616    0    0    0     0       0          0
+";
+        assert_eq!(interface_counters(contents), vec![("lo", 111028944), ("eth0", 220035)]);
+    }
+}
@@ -0,0 +1,426 @@
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use hmac::{Hmac, Mac};
+use log::{debug, info, warn};
+use md4::{Digest, Md4};
+use md5::Md5;
+use reqwest::StatusCode;
+use reqwest::blocking::{Client, Request, Response};
+use reqwest::header::{self, HeaderMap, HeaderValue};
+
+use crate::timeouts::{self, RequestError};
+
+const SIGNATURE: &[u8; 8] = b"NTLMSSP\0";
+
+const NEGOTIATE_UNICODE: u32 = 0x0000_0001;
+const NEGOTIATE_OEM: u32 = 0x0000_0002;
+const REQUEST_TARGET: u32 = 0x0000_0004;
+const NEGOTIATE_NTLM: u32 = 0x0000_0200;
+const NEGOTIATE_ALWAYS_SIGN: u32 = 0x0000_8000;
+const NEGOTIATE_EXTENDED_SESSIONSECURITY: u32 = 0x0008_0000;
+const NEGOTIATE_128: u32 = 0x2000_0000;
+const NEGOTIATE_56: u32 = 0x8000_0000;
+
+/// Flags we ask for: NTLMv2 with Unicode strings, no signing or sealing
+const NEGOTIATE_FLAGS: u32 = NEGOTIATE_UNICODE
+    | NEGOTIATE_OEM
+    | REQUEST_TARGET
+    | NEGOTIATE_NTLM
+    | NEGOTIATE_ALWAYS_SIGN
+    | NEGOTIATE_EXTENDED_SESSIONSECURITY
+    | NEGOTIATE_128
+    | NEGOTIATE_56;
+
+/// AV pair IDs in the challenge's target info
+const AV_EOL: u16 = 0;
+const AV_TIMESTAMP: u16 = 7;
+
+/// Seconds between 1601-01-01 (the FILETIME epoch) and 1970-01-01
+const FILETIME_UNIX_OFFSET: u64 = 11_644_473_600;
+
+type HmacMd5 = Hmac<Md5>;
+
+/// Errors from NTLM authentication
+#[derive(Debug, thiserror::Error)]
+pub enum NtlmError {
+    #[error("invalid NTLM user '{0}', expected DOMAIN\\user:password")]
+    InvalidUser(String),
+
+    #[error("could not read password: {0}")]
+    Prompt(#[source] io::Error),
+
+    #[error("invalid NTLM challenge: {0}")]
+    InvalidChallenge(&'static str),
+}
+
+/// An NTLM account, given as `DOMAIN\user:password`
+#[derive(Clone, PartialEq, Eq)]
+pub struct NtlmCredentials {
+    pub domain: String,
+    pub username: String,
+    pub password: String,
+}
+
+impl NtlmCredentials {
+    /// Parse `DOMAIN\user:password` (the domain is optional); if there's no
+    /// password, ask `prompt` for one
+    pub fn parse(spec: &str, prompt: impl FnOnce(&str) -> io::Result<String>) -> Result<Self, NtlmError> {
+        let (account, password) = match spec.split_once(':') {
+            Some((account, password)) => (account, Some(password.to_string())),
+            None => (spec, None),
+        };
+        let (domain, username) = account.split_once('\\').unwrap_or(("", account));
+        if username.is_empty() {
+            return Err(NtlmError::InvalidUser(account.to_string()));
+        }
+        let password = match password {
+            Some(password) => password,
+            None => prompt(username).map_err(NtlmError::Prompt)?,
+        };
+        Ok(Self { domain: domain.to_string(), username: username.to_string(), password })
+    }
+}
+
+impl FromStr for NtlmCredentials {
+    type Err = NtlmError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s, |_| Err(io::Error::new(io::ErrorKind::InvalidInput, "no password given")))
+    }
+}
+
+impl fmt::Debug for NtlmCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NtlmCredentials")
+            .field("domain", &self.domain)
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+/// The parts of the server's CHALLENGE message we need
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Challenge {
+    server_challenge: [u8; 8],
+    target_info: Vec<u8>,
+}
+
+impl Challenge {
+    fn parse(message: &[u8]) -> Result<Self, NtlmError> {
+        if message.len() < 32 || &message[..8] != SIGNATURE {
+            return Err(NtlmError::InvalidChallenge("not an NTLM message"));
+        }
+        if read_u32(message, 8) != 2 {
+            return Err(NtlmError::InvalidChallenge("not a CHALLENGE message"));
+        }
+        let mut server_challenge = [0; 8];
+        server_challenge.copy_from_slice(&message[24..32]);
+        // Target info is optional in very old servers' challenges
+        let target_info = if message.len() >= 48 {
+            let length = read_u16(message, 40) as usize;
+            let offset = read_u32(message, 44) as usize;
+            message
+                .get(offset..offset + length)
+                .ok_or(NtlmError::InvalidChallenge("target info runs past the end of the message"))?
+                .to_vec()
+        } else {
+            Vec::new()
+        };
+        Ok(Self { server_challenge, target_info })
+    }
+
+    /// The server's MsvAvTimestamp, if it sent one
+    fn timestamp(&self) -> Option<[u8; 8]> {
+        let mut rest = self.target_info.as_slice();
+        while rest.len() >= 4 {
+            let id = read_u16(rest, 0);
+            let length = read_u16(rest, 2) as usize;
+            let value = rest.get(4..4 + length)?;
+            match id {
+                AV_EOL => break,
+                AV_TIMESTAMP if length == 8 => return value.try_into().ok(),
+                _ => rest = &rest[4 + length..],
+            }
+        }
+        None
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn utf16le(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+fn hmac_md5(key: &[u8], parts: &[&[u8]]) -> [u8; 16] {
+    let mut mac = HmacMd5::new_from_slice(key).expect("HMAC takes keys of any length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// The NTLMv2 response key: HMAC-MD5 over the user and domain, keyed by the NT hash
+fn ntlmv2_hash(credentials: &NtlmCredentials) -> [u8; 16] {
+    let nt_hash = Md4::digest(utf16le(&credentials.password));
+    let identity = utf16le(&format!("{}{}", credentials.username.to_uppercase(), credentials.domain));
+    hmac_md5(&nt_hash, &[&identity])
+}
+
+/// The current time as a Windows FILETIME (100ns ticks since 1601)
+fn filetime_now() -> [u8; 8] {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let ticks = (since_epoch.as_secs() + FILETIME_UNIX_OFFSET) * 10_000_000 + u64::from(since_epoch.subsec_nanos() / 100);
+    ticks.to_le_bytes()
+}
+
+/// The first message of the handshake
+fn negotiate_message() -> Vec<u8> {
+    let mut message = Vec::with_capacity(32);
+    message.extend_from_slice(SIGNATURE);
+    message.extend_from_slice(&1u32.to_le_bytes());
+    message.extend_from_slice(&NEGOTIATE_FLAGS.to_le_bytes());
+    // Empty domain and workstation fields
+    message.extend_from_slice(&[0; 16]);
+    message
+}
+
+/// The LMv2 and NTLMv2 responses to a challenge
+fn responses(credentials: &NtlmCredentials, challenge: &Challenge, client_challenge: [u8; 8], timestamp: [u8; 8]) -> (Vec<u8>, Vec<u8>) {
+    let key = ntlmv2_hash(credentials);
+
+    let mut blob = vec![1, 1, 0, 0, 0, 0, 0, 0];
+    blob.extend_from_slice(&timestamp);
+    blob.extend_from_slice(&client_challenge);
+    blob.extend_from_slice(&[0; 4]);
+    blob.extend_from_slice(&challenge.target_info);
+    blob.extend_from_slice(&[0; 4]);
+
+    let mut nt_response = hmac_md5(&key, &[&challenge.server_challenge, &blob]).to_vec();
+    nt_response.extend_from_slice(&blob);
+
+    // Servers that send a timestamp expect an empty LMv2 response
+    let lm_response = if challenge.timestamp().is_some() {
+        vec![0; 24]
+    } else {
+        let mut lm_response = hmac_md5(&key, &[&challenge.server_challenge, &client_challenge]).to_vec();
+        lm_response.extend_from_slice(&client_challenge);
+        lm_response
+    };
+    (lm_response, nt_response)
+}
+
+/// The final message of the handshake, answering the server's challenge
+fn authenticate_message(credentials: &NtlmCredentials, challenge: &Challenge, client_challenge: [u8; 8]) -> Vec<u8> {
+    let timestamp = challenge.timestamp().unwrap_or_else(filetime_now);
+    let (lm_response, nt_response) = responses(credentials, challenge, client_challenge, timestamp);
+    let fields = [
+        lm_response,
+        nt_response,
+        utf16le(&credentials.domain),
+        utf16le(&credentials.username),
+        Vec::new(), // workstation
+        Vec::new(), // encrypted session key
+    ];
+
+    const HEADER_LENGTH: usize = 64;
+    let mut message = Vec::with_capacity(HEADER_LENGTH + fields.iter().map(Vec::len).sum::<usize>());
+    message.extend_from_slice(SIGNATURE);
+    message.extend_from_slice(&3u32.to_le_bytes());
+    let mut offset = HEADER_LENGTH;
+    for field in &fields {
+        message.extend_from_slice(&(field.len() as u16).to_le_bytes());
+        message.extend_from_slice(&(field.len() as u16).to_le_bytes());
+        message.extend_from_slice(&(offset as u32).to_le_bytes());
+        offset += field.len();
+    }
+    message.extend_from_slice(&NEGOTIATE_FLAGS.to_le_bytes());
+    for field in &fields {
+        message.extend_from_slice(field);
+    }
+    message
+}
+
+/// The parameters of any `NTLM` challenges in a 401 response
+fn ntlm_challenges(headers: &HeaderMap) -> impl Iterator<Item = &str> {
+    headers
+        .get_all(header::WWW_AUTHENTICATE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|challenge| {
+            let challenge = challenge.trim();
+            let (scheme, parameter) = challenge.split_once(' ').unwrap_or((challenge, ""));
+            scheme.eq_ignore_ascii_case("ntlm").then(|| parameter.trim())
+        })
+}
+
+/// Check whether a 401 response offers NTLM authentication
+pub fn offers_ntlm(headers: &HeaderMap) -> bool {
+    ntlm_challenges(headers).next().is_some()
+}
+
+/// Answers `401 NTLM` challenges. NTLM authenticates the connection rather
+/// than the request, so the whole handshake has to happen over one kept-alive
+/// HTTP/1.1 connection; each 401 body is drained so the connection goes back
+/// to the pool for the next step.
+pub struct NtlmAuthenticator {
+    credentials: NtlmCredentials,
+}
+
+impl NtlmAuthenticator {
+    pub fn new(credentials: NtlmCredentials) -> Self {
+        Self { credentials }
+    }
+
+    /// Send a request, going through the NTLM handshake if the server asks for it
+    pub fn execute(&self, client: &Client, request: Request, header_timeout: Option<Duration>) -> Result<Response, RequestError> {
+        let (Some(mut negotiate), Some(mut authenticate)) = (request.try_clone(), request.try_clone()) else {
+            return timeouts::execute(client, request, header_timeout);
+        };
+        let response = timeouts::execute(client, request, header_timeout)?;
+        if response.status() != StatusCode::UNAUTHORIZED || !offers_ntlm(response.headers()) {
+            return Ok(response);
+        }
+        let host = response.url().host_str().unwrap_or_default().to_string();
+        info!("Answering NTLM challenge from {} as {}\\{}", host, self.credentials.domain, self.credentials.username);
+        drain(response);
+
+        set_authorization(&mut negotiate, &negotiate_message());
+        let response = timeouts::execute(client, negotiate, header_timeout)?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+        let challenge = ntlm_challenges(response.headers())
+            .find(|parameter| !parameter.is_empty())
+            .ok_or(NtlmError::InvalidChallenge("server didn't send a challenge"))
+            .and_then(|parameter| BASE64.decode(parameter).map_err(|_| NtlmError::InvalidChallenge("challenge isn't valid base64")))
+            .and_then(|message| Challenge::parse(&message));
+        let challenge = match challenge {
+            Ok(challenge) => challenge,
+            Err(e) => {
+                warn!("Could not answer NTLM challenge from {}: {}", host, e);
+                return Ok(response);
+            }
+        };
+        debug!("Received NTLM challenge from {}", host);
+        drain(response);
+
+        let mut client_challenge = [0; 8];
+        getrandom::fill(&mut client_challenge).expect("system random number generator failed");
+        set_authorization(&mut authenticate, &authenticate_message(&self.credentials, &challenge, client_challenge));
+        timeouts::execute(client, authenticate, header_timeout)
+    }
+}
+
+fn set_authorization(request: &mut Request, message: &[u8]) {
+    let value = format!("NTLM {}", BASE64.encode(message));
+    request.headers_mut().insert(header::AUTHORIZATION, HeaderValue::from_str(&value).expect("base64 is a valid header value"));
+}
+
+/// Read the rest of a response so its connection can be reused
+fn drain(mut response: Response) {
+    let _ = response.copy_to(&mut io::sink());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test values from [MS-NLMP] section 4.2.4
+    fn credentials() -> NtlmCredentials {
+        NtlmCredentials { domain: "Domain".into(), username: "User".into(), password: "Password".into() }
+    }
+
+    fn challenge() -> Challenge {
+        let mut target_info = vec![0x02, 0x00, 0x0c, 0x00];
+        target_info.extend(utf16le("Domain"));
+        target_info.extend([0x01, 0x00, 0x0c, 0x00]);
+        target_info.extend(utf16le("Server"));
+        target_info.extend([0x00, 0x00, 0x00, 0x00]);
+        Challenge { server_challenge: [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef], target_info }
+    }
+
+    #[test]
+    fn test_parse_credentials() {
+        let parsed: NtlmCredentials = "CORP\\alice:pa:ss".parse().unwrap();
+        assert_eq!(parsed, NtlmCredentials { domain: "CORP".into(), username: "alice".into(), password: "pa:ss".into() });
+
+        let parsed: NtlmCredentials = "bob:secret".parse().unwrap();
+        assert_eq!((parsed.domain.as_str(), parsed.username.as_str()), ("", "bob"));
+
+        let prompted = NtlmCredentials::parse("CORP\\carol", |user| Ok(format!("{}-pw", user))).unwrap();
+        assert_eq!(prompted.password, "carol-pw");
+
+        assert!("CORP\\:x".parse::<NtlmCredentials>().is_err());
+        assert!("CORP\\dave".parse::<NtlmCredentials>().is_err());
+        assert!(!format!("{:?}", credentials()).contains("Password"));
+    }
+
+    #[test]
+    fn test_ntlmv2_hash() {
+        assert_eq!(hex::encode(ntlmv2_hash(&credentials())), "0c868a403bfd7a93a3001ef22ef02e3f");
+    }
+
+    #[test]
+    fn test_ntlmv2_responses() {
+        let (lm_response, nt_response) = responses(&credentials(), &challenge(), [0xaa; 8], [0; 8]);
+        assert_eq!(hex::encode(lm_response), "86c35097ac9cec102554764a57cccc19aaaaaaaaaaaaaaaa");
+        assert_eq!(hex::encode(&nt_response[..16]), "68cd0ab851e51c96aabc927bebef6a1c");
+    }
+
+    #[test]
+    fn test_challenge_roundtrip() {
+        let target_info = challenge().target_info;
+        let mut message = SIGNATURE.to_vec();
+        message.extend(2u32.to_le_bytes());
+        message.extend([0; 8]); // target name
+        message.extend(NEGOTIATE_FLAGS.to_le_bytes());
+        message.extend(challenge().server_challenge);
+        message.extend([0; 8]);
+        message.extend((target_info.len() as u16).to_le_bytes());
+        message.extend((target_info.len() as u16).to_le_bytes());
+        message.extend(48u32.to_le_bytes());
+        message.extend(&target_info);
+
+        assert_eq!(Challenge::parse(&message).unwrap(), challenge());
+        assert!(Challenge::parse(&negotiate_message()).is_err());
+        assert!(Challenge::parse(&message[..40]).is_ok());
+        assert!(Challenge::parse(&message[..60]).is_err());
+        assert_eq!(challenge().timestamp(), None);
+    }
+
+    #[test]
+    fn test_authenticate_message_layout() {
+        let message = authenticate_message(&credentials(), &challenge(), [0xaa; 8]);
+        assert_eq!(&message[..8], SIGNATURE);
+        assert_eq!(read_u32(&message, 8), 3);
+        // The user name field points at "User" in UTF-16
+        let length = read_u16(&message, 36) as usize;
+        let offset = read_u32(&message, 40) as usize;
+        assert_eq!(&message[offset..offset + length], utf16le("User").as_slice());
+    }
+
+    #[test]
+    fn test_offers_ntlm() {
+        let mut headers = HeaderMap::new();
+        headers.append(header::WWW_AUTHENTICATE, HeaderValue::from_static("Negotiate"));
+        assert!(!offers_ntlm(&headers));
+        headers.append(header::WWW_AUTHENTICATE, HeaderValue::from_static("NTLM"));
+        assert!(offers_ntlm(&headers));
+        headers.insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("NTLM TlRMTVNTUAACAAAA"));
+        assert_eq!(ntlm_challenges(&headers).next(), Some("TlRMTVNTUAACAAAA"));
+    }
+}
@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{debug, info};
+use reqwest::blocking::Client;
+use reqwest::header;
+use serde::{Deserialize, Serialize};
+
+use crate::credentials::{CredentialError, CredentialStore};
+
+/// Grant type for polling the token endpoint (RFC 8628 section 3.4)
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// Treat tokens this close to expiring as already expired
+const EXPIRY_LEEWAY_SECS: u64 = 60;
+
+/// Polling interval when the server doesn't suggest one
+const DEFAULT_POLL_INTERVAL_SECS: u64 = 5;
+
+/// OAuth settings from the `[oauth]` section of the config file, keyed by provider name
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct OAuthSettings {
+    pub providers: HashMap<String, ProviderSettings>,
+}
+
+/// Settings for a single provider; anything left out falls back to the built-in defaults
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ProviderSettings {
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub scopes: Option<Vec<String>>,
+    pub device_authorization_url: Option<String>,
+    pub token_url: Option<String>,
+    /// Hosts the token is sent to
+    pub hosts: Option<Vec<String>>,
+}
+
+/// Everything needed to run the device flow against one provider
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OAuthProvider {
+    pub name: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub scopes: Vec<String>,
+    pub device_authorization_url: String,
+    pub token_url: String,
+    pub hosts: Vec<String>,
+}
+
+/// Endpoints and defaults for the providers we know about
+struct BuiltinProvider {
+    name: &'static str,
+    device_authorization_url: &'static str,
+    token_url: &'static str,
+    scopes: &'static [&'static str],
+    hosts: &'static [&'static str],
+}
+
+const BUILTIN_PROVIDERS: &[BuiltinProvider] = &[
+    BuiltinProvider {
+        name: "github",
+        device_authorization_url: "https://github.com/login/device/code",
+        token_url: "https://github.com/login/oauth/access_token",
+        scopes: &["repo"],
+        hosts: &["github.com", "api.github.com", "raw.githubusercontent.com"],
+    },
+    BuiltinProvider {
+        name: "google",
+        device_authorization_url: "https://oauth2.googleapis.com/device/code",
+        token_url: "https://oauth2.googleapis.com/token",
+        scopes: &["https://www.googleapis.com/auth/drive.readonly"],
+        hosts: &["www.googleapis.com", "storage.googleapis.com"],
+    },
+];
+
+impl OAuthProvider {
+    /// Look up a provider by name, combining the built-in defaults with the user's settings
+    pub fn resolve(name: &str, settings: &OAuthSettings) -> Result<Self, OAuthError> {
+        let name = name.to_lowercase();
+        let configured = settings.providers.get(&name).cloned().unwrap_or_default();
+        let builtin = BUILTIN_PROVIDERS.iter().find(|provider| provider.name == name);
+        if builtin.is_none() && !settings.providers.contains_key(&name) {
+            return Err(OAuthError::UnknownProvider { provider: name });
+        }
+
+        let missing = |option: &'static str| OAuthError::MissingSetting { provider: name.clone(), option };
+        let to_strings = |values: &[&str]| values.iter().map(|value| value.to_string()).collect::<Vec<_>>();
+        Ok(Self {
+            client_id: configured.client_id.ok_or_else(|| missing("client_id"))?,
+            client_secret: configured.client_secret,
+            scopes: configured.scopes.or_else(|| builtin.map(|b| to_strings(b.scopes))).unwrap_or_default(),
+            device_authorization_url: configured
+                .device_authorization_url
+                .or_else(|| builtin.map(|b| b.device_authorization_url.to_string()))
+                .ok_or_else(|| missing("device_authorization_url"))?,
+            token_url: configured
+                .token_url
+                .or_else(|| builtin.map(|b| b.token_url.to_string()))
+                .ok_or_else(|| missing("token_url"))?,
+            hosts: configured.hosts.or_else(|| builtin.map(|b| to_strings(b.hosts))).ok_or_else(|| missing("hosts"))?,
+            name,
+        })
+    }
+
+    /// Name of the provider's tokens in the credential store
+    pub fn secret_key(name: &str) -> String {
+        format!("oauth:{}", name)
+    }
+}
+
+/// Tokens for one provider, as cached in the system keyring
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenSet {
+    pub access_token: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    /// When the access token expires, in seconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+}
+
+impl TokenSet {
+    /// Whether the access token has expired (or is about to)
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| now() + EXPIRY_LEEWAY_SECS >= expires_at)
+    }
+}
+
+// Keep tokens out of debug logs
+impl std::fmt::Debug for TokenSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenSet")
+            .field("refreshable", &self.refresh_token.is_some())
+            .field("expires_at", &self.expires_at)
+            .finish()
+    }
+}
+
+/// What the user has to do to approve the login
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct DeviceAuthorization {
+    pub device_code: String,
+    pub user_code: String,
+    /// Google calls this `verification_url`
+    #[serde(alias = "verification_url")]
+    pub verification_uri: String,
+    pub expires_in: u64,
+    #[serde(default)]
+    pub interval: Option<u64>,
+}
+
+/// Response from the token endpoint, which reports errors in the body
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: Option<String>,
+    refresh_token: Option<String>,
+    expires_in: Option<u64>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/// Errors from logging in or refreshing tokens
+#[derive(Debug, thiserror::Error)]
+pub enum OAuthError {
+    #[error("Unknown OAuth provider '{provider}'. Built-in providers: {}; others can be added under [oauth.<name>] in config.toml",
+            BUILTIN_PROVIDERS.iter().map(|p| p.name).collect::<Vec<_>>().join(", "))]
+    UnknownProvider { provider: String },
+
+    #[error("{provider} login needs `{option}`; set it under [oauth.{provider}] in config.toml")]
+    MissingSetting { provider: String, option: &'static str },
+
+    #[error("request to {provider} failed: {source}")]
+    Http { provider: String, source: reqwest::Error },
+
+    #[error("{provider} refused the login: {message}")]
+    Denied { provider: String, message: String },
+
+    #[error("the {provider} login code expired before it was approved; run `download auth login {provider}` again")]
+    Expired { provider: String },
+
+    #[error("{provider} access token expired and can't be refreshed; run `download auth login {provider}` again")]
+    NotRefreshable { provider: String },
+
+    #[error(transparent)]
+    Credentials(#[from] CredentialError),
+}
+
+/// Runs the OAuth 2.0 device authorization grant (RFC 8628) against a provider
+pub struct DeviceFlow<'a> {
+    client: &'a Client,
+    provider: &'a OAuthProvider,
+}
+
+impl<'a> DeviceFlow<'a> {
+    pub fn new(client: &'a Client, provider: &'a OAuthProvider) -> Self {
+        Self { client, provider }
+    }
+
+    fn http_err(&self, source: reqwest::Error) -> OAuthError {
+        OAuthError::Http { provider: self.provider.name.clone(), source }
+    }
+
+    /// Ask the provider for a device code and the code the user has to enter
+    pub fn start(&self) -> Result<DeviceAuthorization, OAuthError> {
+        let scope = self.provider.scopes.join(" ");
+        let mut form = vec![("client_id", self.provider.client_id.as_str())];
+        if !scope.is_empty() {
+            form.push(("scope", &scope));
+        }
+        debug!("Requesting a device code from {}", self.provider.device_authorization_url);
+        self.client
+            .post(&self.provider.device_authorization_url)
+            .header(header::ACCEPT, "application/json")
+            .form(&form)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.json())
+            .map_err(|e| self.http_err(e))
+    }
+
+    /// Poll the token endpoint until the user approves or denies the login, or the code expires
+    pub fn wait_for_tokens(&self, authorization: &DeviceAuthorization) -> Result<TokenSet, OAuthError> {
+        let deadline = now() + authorization.expires_in;
+        let mut interval = authorization.interval.unwrap_or(DEFAULT_POLL_INTERVAL_SECS);
+        loop {
+            thread::sleep(Duration::from_secs(interval));
+            if now() >= deadline {
+                return Err(OAuthError::Expired { provider: self.provider.name.clone() });
+            }
+
+            let response = self.request_token(&[
+                ("grant_type", DEVICE_CODE_GRANT),
+                ("device_code", &authorization.device_code),
+            ])?;
+            match response.error.as_deref() {
+                Some("authorization_pending") => debug!("Waiting for the user to approve the login"),
+                // The server wants us to back off (RFC 8628 section 3.5)
+                Some("slow_down") => interval += 5,
+                Some("expired_token") => return Err(OAuthError::Expired { provider: self.provider.name.clone() }),
+                _ => return self.token_set(response, None),
+            }
+        }
+    }
+
+    /// Exchange a refresh token for a new access token
+    pub fn refresh(&self, tokens: &TokenSet) -> Result<TokenSet, OAuthError> {
+        let refresh_token = tokens
+            .refresh_token
+            .as_deref()
+            .ok_or_else(|| OAuthError::NotRefreshable { provider: self.provider.name.clone() })?;
+        info!("Refreshing {} access token", self.provider.name);
+        let response = self.request_token(&[("grant_type", "refresh_token"), ("refresh_token", refresh_token)])?;
+        // Providers may or may not rotate refresh tokens; keep the old one if they don't
+        self.token_set(response, tokens.refresh_token.clone())
+    }
+
+    fn request_token(&self, params: &[(&str, &str)]) -> Result<TokenResponse, OAuthError> {
+        let mut form = vec![("client_id", self.provider.client_id.as_str())];
+        if let Some(secret) = &self.provider.client_secret {
+            form.push(("client_secret", secret));
+        }
+        form.extend_from_slice(params);
+        // Errors come back as JSON with a 4xx status, so don't bail out on the status code
+        self.client
+            .post(&self.provider.token_url)
+            .header(header::ACCEPT, "application/json")
+            .form(&form)
+            .send()
+            .and_then(|response| response.json())
+            .map_err(|e| self.http_err(e))
+    }
+
+    fn token_set(&self, response: TokenResponse, previous_refresh: Option<String>) -> Result<TokenSet, OAuthError> {
+        match response {
+            TokenResponse { access_token: Some(access_token), refresh_token, expires_in, .. } => Ok(TokenSet {
+                access_token,
+                refresh_token: refresh_token.or(previous_refresh),
+                expires_at: expires_in.map(|seconds| now() + seconds),
+            }),
+            TokenResponse { error, error_description, .. } => Err(OAuthError::Denied {
+                provider: self.provider.name.clone(),
+                message: error_description.or(error).unwrap_or_else(|| "no access token in response".to_string()),
+            }),
+        }
+    }
+}
+
+/// Run the whole device flow, telling the user what to do via `prompt`, and
+/// store the resulting tokens for the provider's hosts
+pub fn login(
+    client: &Client,
+    provider: &OAuthProvider,
+    store: &mut CredentialStore,
+    prompt: impl FnOnce(&DeviceAuthorization),
+) -> Result<(), OAuthError> {
+    let flow = DeviceFlow::new(client, provider);
+    let authorization = flow.start()?;
+    prompt(&authorization);
+    let tokens = flow.wait_for_tokens(&authorization)?;
+    store.add_oauth(&provider.name, &provider.hosts, &tokens)?;
+    Ok(())
+}
+
+/// Get a usable access token for a provider, refreshing and re-storing it if it has expired
+pub fn access_token(
+    client: &Client,
+    settings: &OAuthSettings,
+    store: &CredentialStore,
+    provider: &str,
+    tokens: TokenSet,
+) -> Result<String, OAuthError> {
+    if !tokens.is_expired() {
+        return Ok(tokens.access_token);
+    }
+    let provider = OAuthProvider::resolve(provider, settings)?;
+    let tokens = DeviceFlow::new(client, &provider).refresh(&tokens)?;
+    store.update_oauth(&provider.name, &tokens)?;
+    Ok(tokens.access_token)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(toml: &str) -> OAuthSettings {
+        crate::settings::Settings::from_toml(toml).unwrap().oauth
+    }
+
+    #[test]
+    fn test_resolve_builtin_provider() {
+        let settings = settings("[oauth.github]\nclient_id = \"Iv1.abc\"\n");
+        let provider = OAuthProvider::resolve("GitHub", &settings).unwrap();
+        assert_eq!(provider.name, "github");
+        assert_eq!(provider.client_id, "Iv1.abc");
+        assert_eq!(provider.token_url, "https://github.com/login/oauth/access_token");
+        assert!(provider.hosts.contains(&"api.github.com".to_string()));
+    }
+
+    #[test]
+    fn test_resolve_requires_client_id() {
+        let result = OAuthProvider::resolve("google", &OAuthSettings::default());
+        assert!(matches!(result, Err(OAuthError::MissingSetting { option: "client_id", .. })));
+    }
+
+    #[test]
+    fn test_resolve_custom_provider() {
+        let settings = settings(r#"
+            [oauth.gitlab]
+            client_id = "abc"
+            device_authorization_url = "https://gitlab.example.com/oauth/authorize_device"
+            token_url = "https://gitlab.example.com/oauth/token"
+            hosts = ["gitlab.example.com"]
+        "#);
+        let provider = OAuthProvider::resolve("gitlab", &settings).unwrap();
+        assert_eq!(provider.hosts, vec!["gitlab.example.com"]);
+        assert!(provider.scopes.is_empty());
+
+        assert!(matches!(
+            OAuthProvider::resolve("bitbucket", &settings),
+            Err(OAuthError::UnknownProvider { .. })
+        ));
+    }
+
+    #[test]
+    fn test_device_authorization_accepts_google_field_name() {
+        let authorization: DeviceAuthorization = serde_json::from_str(
+            r#"{"device_code":"d","user_code":"ABCD-EFGH","verification_url":"https://www.google.com/device","expires_in":1800}"#,
+        ).unwrap();
+        assert_eq!(authorization.verification_uri, "https://www.google.com/device");
+        assert_eq!(authorization.interval, None);
+    }
+
+    #[test]
+    fn test_token_expiry() {
+        let tokens = |expires_at| TokenSet { access_token: "t".into(), refresh_token: None, expires_at };
+        assert!(!tokens(None).is_expired());
+        assert!(!tokens(Some(now() + 3600)).is_expired());
+        assert!(tokens(Some(now() + 10)).is_expired());
+    }
+}
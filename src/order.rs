@@ -0,0 +1,183 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::debug;
+use reqwest::Method;
+use reqwest::blocking::Client;
+
+use crate::middleware::Middleware;
+use crate::politeness::Politeness;
+
+/// How many HEAD requests to have in flight while finding out file sizes
+const PROBE_WORKERS: usize = 8;
+
+/// The order URLs in a batch are downloaded in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DownloadOrder {
+    /// In the order they were given
+    #[default]
+    Fifo,
+    /// Smallest files first, for quick wins and to surface problems early
+    SmallestFirst,
+    /// Largest files first, so the long downloads start right away
+    LargestFirst,
+    /// Shuffled, to spread load across hosts
+    Random,
+}
+
+impl DownloadOrder {
+    /// Get all supported orders
+    pub fn all() -> Vec<DownloadOrder> {
+        vec![
+            DownloadOrder::Fifo,
+            DownloadOrder::SmallestFirst,
+            DownloadOrder::LargestFirst,
+            DownloadOrder::Random,
+        ]
+    }
+
+    /// Get the string representation of the order
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DownloadOrder::Fifo => "fifo",
+            DownloadOrder::SmallestFirst => "smallest-first",
+            DownloadOrder::LargestFirst => "largest-first",
+            DownloadOrder::Random => "random",
+        }
+    }
+
+    /// Whether we need to know file sizes before we can order the batch
+    pub fn needs_sizes(&self) -> bool {
+        matches!(self, DownloadOrder::SmallestFirst | DownloadOrder::LargestFirst)
+    }
+}
+
+impl fmt::Display for DownloadOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for DownloadOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        DownloadOrder::all()
+            .into_iter()
+            .find(|order| order.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                format!(
+                    "Unknown order '{}'. Available orders: {}",
+                    s,
+                    DownloadOrder::all().iter().map(|o| o.as_str()).collect::<Vec<_>>().join(", ")
+                )
+            })
+    }
+}
+
+/// Reorder `urls` according to `order`. `sizes` gives each URL's size where
+/// it's known; URLs of unknown size go last, in their original order.
+pub fn arrange(mut urls: Vec<String>, order: DownloadOrder, sizes: &HashMap<String, u64>, seed: u64) -> Vec<String> {
+    match order {
+        DownloadOrder::Fifo => {}
+        // sort_by_key is stable, so equal and unknown sizes keep their order
+        DownloadOrder::SmallestFirst => urls.sort_by_key(|url| sizes.get(url).copied().unwrap_or(u64::MAX)),
+        // None sorts below any size, so reversing still puts unknown sizes last
+        DownloadOrder::LargestFirst => urls.sort_by_key(|url| Reverse(sizes.get(url).copied())),
+        DownloadOrder::Random => shuffle(&mut urls, seed),
+    }
+    urls
+}
+
+/// A seed for random ordering that differs from run to run
+pub fn random_seed() -> u64 {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or_default();
+    (nanos as u64) ^ u64::from(std::process::id()).rotate_left(32)
+}
+
+/// Fisher-Yates shuffle driven by xorshift64*; plenty for spreading load
+fn shuffle<T>(items: &mut [T], seed: u64) {
+    let mut state = seed | 1;
+    let mut next = || {
+        state ^= state >> 12;
+        state ^= state << 25;
+        state ^= state >> 27;
+        state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    };
+    for i in (1..items.len()).rev() {
+        let j = (next() % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
+
+/// Find out how big each URL is with HEAD requests; URLs whose size can't be
+/// determined are left out. They're asked no faster than `politeness` allows.
+pub fn probe_sizes(client: &Client, middleware: &Middleware, politeness: &Arc<Politeness>, urls: &[String]) -> HashMap<String, u64> {
+    let queue = Mutex::new(urls.iter().collect::<VecDeque<_>>());
+    let sizes = Mutex::new(HashMap::new());
+    thread::scope(|scope| {
+        for _ in 0..PROBE_WORKERS.min(urls.len()) {
+            scope.spawn(|| {
+                loop {
+                    let Some((url, _permit)) = politeness.next(&queue, |url| url) else { break };
+                    let size = middleware
+                        .request(client, Method::HEAD, url)
+                        .send()
+                        .ok()
+                        .filter(|response| response.status().is_success())
+                        .and_then(|response| response.content_length())
+                        .filter(|length| *length > 0);
+                    debug!("Size of {}: {:?}", url, size);
+                    if let Some(size) = size {
+                        sizes.lock().unwrap().insert(url.clone(), size);
+                    }
+                }
+            });
+        }
+    });
+    sizes.into_inner().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn urls() -> Vec<String> {
+        vec!["a".into(), "b".into(), "c".into(), "d".into()]
+    }
+
+    fn sizes() -> HashMap<String, u64> {
+        HashMap::from([("a".into(), 30), ("b".into(), 10), ("d".into(), 20)])
+    }
+
+    #[test]
+    fn test_order_from_str() {
+        assert_eq!("smallest-first".parse::<DownloadOrder>().unwrap(), DownloadOrder::SmallestFirst);
+        assert_eq!("FIFO".parse::<DownloadOrder>().unwrap(), DownloadOrder::Fifo);
+        assert!("biggest".parse::<DownloadOrder>().is_err());
+    }
+
+    #[test]
+    fn test_arrange_by_size_puts_unknown_last() {
+        assert_eq!(arrange(urls(), DownloadOrder::SmallestFirst, &sizes(), 0), vec!["b", "d", "a", "c"]);
+        assert_eq!(arrange(urls(), DownloadOrder::LargestFirst, &sizes(), 0), vec!["a", "d", "b", "c"]);
+        assert_eq!(arrange(urls(), DownloadOrder::Fifo, &sizes(), 0), urls());
+    }
+
+    #[test]
+    fn test_random_is_a_permutation_and_seeded() {
+        let many: Vec<String> = (0..100).map(|i| i.to_string()).collect();
+        let shuffled = arrange(many.clone(), DownloadOrder::Random, &HashMap::new(), 42);
+        assert_ne!(shuffled, many);
+        assert_eq!(shuffled, arrange(many.clone(), DownloadOrder::Random, &HashMap::new(), 42));
+
+        let mut sorted = shuffled.clone();
+        sorted.sort_by_key(|s| s.parse::<u32>().unwrap());
+        assert_eq!(sorted, many);
+    }
+}
@@ -0,0 +1,745 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::LazyLock;
+use std::time::{Duration, SystemTime};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use log::{debug, warn};
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use reqwest::{Method, StatusCode};
+use reqwest::blocking::Client;
+use reqwest::header::{self, HeaderMap};
+use url::Url;
+
+use crate::middleware::{DownloadContext, Middleware};
+use crate::naming::{self, NamePolicy};
+use crate::timeouts::{self, RequestError};
+use crate::timestamping;
+use crate::warc::{Exchange, RequestHead, WarcError, WarcWriter};
+
+/// Elements whose `src`/`href`/`srcset`/`poster` point at something the page needs
+static RESOURCE_TAG: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?is)<(img|script|link|source|video|audio|embed|input|track)\b([^>]*)>").unwrap());
+static ATTRIBUTE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?is)\b(src|href|srcset|poster|rel|integrity)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+))"#).unwrap()
+});
+static STYLE_BLOCK: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?is)<style\b[^>]*>(.*?)</style>").unwrap());
+static STYLE_ATTRIBUTE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?is)\sstyle\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap());
+/// Elements that link to other pages
+static LINK_TAG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?is)<(a|area|frame|iframe)\b([^>]*)>").unwrap());
+static BASE_TAG: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?is)<base\b[^>]*?\bhref\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+))[^>]*>"#).unwrap());
+static CSS_URL: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?i)url\(\s*(?:"([^"]*)"|'([^']*)'|([^)'"\s]*))\s*\)|@import\s+(?:"([^"]*)"|'([^']*)')"#).unwrap()
+});
+
+/// How a saved page is laid out on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PageFormat {
+    /// `page.html` with its resources in `page_files/`
+    #[default]
+    Directory,
+    /// Everything in a single `page.mhtml` archive
+    Mhtml,
+}
+
+impl PageFormat {
+    /// Get all supported formats
+    pub fn all() -> Vec<PageFormat> {
+        vec![PageFormat::Directory, PageFormat::Mhtml]
+    }
+
+    /// Get the string representation of the format
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PageFormat::Directory => "directory",
+            PageFormat::Mhtml => "mhtml",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            PageFormat::Directory => "html",
+            PageFormat::Mhtml => "mhtml",
+        }
+    }
+}
+
+impl fmt::Display for PageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for PageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        PageFormat::all()
+            .into_iter()
+            .find(|format| format.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                format!(
+                    "Unknown page format '{}'. Available formats: {}",
+                    s,
+                    PageFormat::all().iter().map(|f| f.as_str()).collect::<Vec<_>>().join(", ")
+                )
+            })
+    }
+}
+
+/// Errors from saving a page
+#[derive(Debug, thiserror::Error)]
+pub enum PageError {
+    #[error("{url}: {source}")]
+    InvalidUrl { url: String, source: url::ParseError },
+
+    #[error(transparent)]
+    Request(#[from] RequestError),
+
+    #[error("{url}: server returned {status}")]
+    Status { url: Url, status: StatusCode },
+
+    #[error("{url}: {reason}")]
+    Rejected { url: Url, reason: String },
+
+    #[error("{url}: {source}")]
+    Body { url: Url, source: reqwest::Error },
+
+    #[error("could not write {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error(transparent)]
+    Warc(#[from] WarcError),
+}
+
+/// What `PageSaver::save` did with a URL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SavedPage {
+    pub path: PathBuf,
+    /// Stylesheets, images and scripts saved along with it
+    pub resources: usize,
+    /// Resources we couldn't fetch, which are left pointing at the web
+    pub missing: usize,
+}
+
+impl fmt::Display for SavedPage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "saved with {} resources", self.resources)?;
+        if self.missing > 0 {
+            write!(f, ", {} missing", self.missing)?;
+        }
+        Ok(())
+    }
+}
+
+/// A response we've read into memory
+struct Fetched {
+    /// Where we ended up after redirects, which relative links are resolved against
+    url: Url,
+    content_type: String,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+impl Fetched {
+    fn is(&self, mime: &str) -> bool {
+        self.content_type.split(';').next().is_some_and(|t| t.trim().eq_ignore_ascii_case(mime))
+    }
+}
+
+/// Saves web pages together with the stylesheets, images and scripts they
+/// need, so they can be viewed offline
+pub struct PageSaver<'a> {
+    pub client: &'a Client,
+    pub middleware: &'a Middleware,
+    pub header_timeout: Option<Duration>,
+    pub format: PageFormat,
+    /// Archive the page and everything fetched for it here as well
+    pub warc: Option<&'a WarcWriter>,
+    /// Date the saved files, and MHTML archives, with this instead of now (`--deterministic`)
+    pub deterministic: Option<SystemTime>,
+}
+
+impl PageSaver<'_> {
+    /// Save the page at `url` (the `index`th URL of the run) where `names`
+    /// places it. Anything that isn't HTML is saved as it is.
+    pub fn save(&self, url: &str, index: usize, names: &dyn NamePolicy) -> Result<SavedPage, PageError> {
+        let url = Url::parse(url).map_err(|source| PageError::InvalidUrl { url: url.to_string(), source })?;
+        let page = self.fetch(&url)?;
+        let filename = names
+            .server_name(&page.headers)
+            .or_else(|| names.url_name(&page.url))
+            .map(|path| path.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "index".to_string());
+        let place = |filename: &str| {
+            names
+                .place(&page.url, Path::new(filename), index)
+                .map(|path| names.claim(path))
+                .ok_or_else(|| PageError::Rejected { url: page.url.clone(), reason: format!("nowhere to save {}", filename) })
+        };
+
+        if !page.is("text/html") && !page.is("application/xhtml+xml") {
+            debug!("{} is {}, not a page; saving it as it is", page.url, page.content_type);
+            let path = place(&filename)?;
+            write(&path, &page.body, self.deterministic)?;
+            return Ok(SavedPage { path, resources: 0, missing: 0 });
+        }
+
+        let path = place(&with_extension(&filename, self.format))?;
+        let html = String::from_utf8_lossy(&page.body);
+        match self.format {
+            PageFormat::Directory => {
+                let stem = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+                let files_name = format!("{}_files", stem);
+                let mut collector = Collector::new(self);
+                let html = rewrite_html(&html, &page.url, true, &mut |url| {
+                    collector.localize(url).map(|name| format!("{}/{}", files_name, name))
+                });
+                if !collector.saved.is_empty() {
+                    let files_dir = path.with_file_name(&files_name);
+                    fs::create_dir_all(&files_dir).map_err(|source| PageError::Io { path: files_dir.clone(), source })?;
+                    for resource in &collector.saved {
+                        write(&files_dir.join(&resource.name), &resource.body, self.deterministic)?;
+                    }
+                }
+                write(&path, html.as_bytes(), self.deterministic)?;
+                Ok(SavedPage { path, resources: collector.saved.len(), missing: collector.missing })
+            }
+            PageFormat::Mhtml => {
+                let mut collector = Collector::new(self);
+                // Links are left alone: MHTML readers match them up with each part's Content-Location
+                rewrite_html(&html, &page.url, false, &mut |url| {
+                    collector.localize(url);
+                    None
+                });
+                let mut archive = Vec::new();
+                write_mhtml(&mut archive, &page, &collector.saved, self.deterministic).map_err(|source| PageError::Io { path: path.clone(), source })?;
+                write(&path, &archive, self.deterministic)?;
+                Ok(SavedPage { path, resources: collector.saved.len(), missing: collector.missing })
+            }
+        }
+    }
+
+    fn fetch(&self, url: &Url) -> Result<Fetched, PageError> {
+        let request = self.middleware.get(self.client, url.as_str()).build().map_err(RequestError::from)?;
+        let request_head = self.warc.map(|_| RequestHead::new(&request));
+        let response = timeouts::execute(self.client, request, self.header_timeout)?;
+        self.middleware
+            .intercept(&response, &DownloadContext { method: &Method::GET, url: url.as_str() })
+            .map_err(|reason| PageError::Rejected { url: url.clone(), reason })?;
+        if !response.status().is_success() {
+            return Err(PageError::Status { url: url.clone(), status: response.status() });
+        }
+        let exchange = request_head.map(|head| Exchange::new(head, &response));
+        let url = response.url().clone();
+        let headers = response.headers().clone();
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("application/octet-stream")
+            .to_string();
+        let body = response.bytes().map_err(|source| PageError::Body { url: url.clone(), source })?.to_vec();
+        if let (Some(warc), Some(exchange)) = (self.warc, &exchange) {
+            warc.write(exchange, &mut body.as_slice(), body.len() as u64)?;
+        }
+        Ok(Fetched { url, content_type, headers, body })
+    }
+}
+
+/// A resource saved along with a page
+struct Resource {
+    url: Url,
+    /// File name in the page's `_files` directory
+    name: String,
+    content_type: String,
+    body: Vec<u8>,
+}
+
+/// Fetches the resources a page refers to, each only once
+struct Collector<'a> {
+    saver: &'a PageSaver<'a>,
+    /// Local names for the URLs we've seen; `None` if they couldn't be fetched
+    names: HashMap<Url, Option<String>>,
+    used: HashSet<String>,
+    saved: Vec<Resource>,
+    missing: usize,
+}
+
+impl<'a> Collector<'a> {
+    fn new(saver: &'a PageSaver<'a>) -> Self {
+        Collector { saver, names: HashMap::new(), used: HashSet::new(), saved: Vec::new(), missing: 0 }
+    }
+
+    /// Fetch a resource (and whatever it refers to, for stylesheets) and return its local name
+    fn localize(&mut self, url: &Url) -> Option<String> {
+        if let Some(name) = self.names.get(url) {
+            return name.clone();
+        }
+        // Claim a name before fetching, so stylesheets that import each other don't loop
+        let name = self.unique_name(url);
+        self.names.insert(url.clone(), Some(name.clone()));
+        match self.saver.fetch(url) {
+            Ok(fetched) => {
+                let body = if fetched.is("text/css") {
+                    let css = String::from_utf8_lossy(&fetched.body).into_owned();
+                    let rewritten = rewrite_css(&css, &fetched.url, &mut |url| self.localize(url));
+                    match self.saver.format {
+                        PageFormat::Directory => rewritten.into_bytes(),
+                        PageFormat::Mhtml => fetched.body,
+                    }
+                } else {
+                    fetched.body
+                };
+                debug!("Saved {} as {}", url, name);
+                self.saved.push(Resource { url: url.clone(), name: name.clone(), content_type: fetched.content_type, body });
+                Some(name)
+            }
+            Err(e) => {
+                warn!("Could not save {}: {}", url, e);
+                self.names.insert(url.clone(), None);
+                self.missing += 1;
+                None
+            }
+        }
+    }
+
+    fn unique_name(&mut self, url: &Url) -> String {
+        let name = local_name(url);
+        let name = (0..)
+            .map(|n| naming::numbered(Path::new(&name), n).to_string_lossy().into_owned())
+            .find(|candidate| !self.used.contains(candidate))
+            .unwrap();
+        self.used.insert(name.clone());
+        name
+    }
+}
+
+/// A safe file name for a resource: its last path segment, with anything
+/// that would need escaping in a link replaced
+fn local_name(url: &Url) -> String {
+    let segment = url.path_segments().and_then(|mut s| s.next_back()).unwrap_or_default();
+    let name: String = segment
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "._-".contains(c) { c } else { '_' })
+        .collect();
+    match name.trim_start_matches('.') {
+        "" => "resource".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Give a page's file name the extension for `format`, unless it already has a suitable one
+fn with_extension(filename: &str, format: PageFormat) -> String {
+    let path = Path::new(filename);
+    let current = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_ascii_lowercase();
+    let is_html = ["html", "htm", "xhtml"].contains(&current.as_str());
+    match format {
+        PageFormat::Directory if is_html => filename.to_string(),
+        PageFormat::Mhtml if current == "mhtml" => filename.to_string(),
+        // page.html becomes page.mhtml rather than page.html.mhtml
+        PageFormat::Mhtml if is_html => path.with_extension("mhtml").to_string_lossy().into_owned(),
+        format => format!("{}.{}", filename, format.extension()),
+    }
+}
+
+/// Resolve a link found in a page, if it's something we could fetch
+fn resolve(base: &Url, link: &str) -> Option<Url> {
+    let link = link.trim().replace("&amp;", "&");
+    if link.is_empty() || link.starts_with('#') {
+        return None;
+    }
+    base.join(&link).ok().filter(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+/// The text and span of whichever alternative of a quoted-or-not capture matched
+fn value_of<'t>(captures: &regex::Captures<'t>, groups: &[usize]) -> Option<regex::Match<'t>> {
+    groups.iter().find_map(|&group| captures.get(group))
+}
+
+/// What relative links in `html` are relative to: its `<base href>`, or where it came from
+fn base_of(html: &str, page_url: &Url) -> Url {
+    BASE_TAG
+        .captures(html)
+        .and_then(|captures| value_of(&captures, &[1, 2, 3]).and_then(|href| page_url.join(href.as_str()).ok()))
+        .unwrap_or_else(|| page_url.clone())
+}
+
+/// Where each link to another page is in `html`, and what it points to
+fn page_links(html: &str, base: &Url) -> Vec<(Range<usize>, Url)> {
+    let mut links = Vec::new();
+    for tag in LINK_TAG.captures_iter(html) {
+        let attributes = tag.get(2).unwrap();
+        for captures in ATTRIBUTE.captures_iter(attributes.as_str()) {
+            if !matches!(captures[1].to_ascii_lowercase().as_str(), "href" | "src") {
+                continue;
+            }
+            if let Some(value) = value_of(&captures, &[2, 3, 4])
+                && let Some(url) = resolve(base, value.as_str())
+            {
+                links.push((attributes.start() + value.start()..attributes.start() + value.end(), url));
+            }
+        }
+    }
+    links
+}
+
+/// The links in an HTML page to other pages or files, in the order they appear
+pub fn links(html: &str, page_url: &Url) -> Vec<Url> {
+    page_links(html, &base_of(html, page_url)).into_iter().map(|(_, url)| url).collect()
+}
+
+/// The stylesheets, images, scripts and so on an HTML page needs to be shown
+pub fn requisites(html: &str, page_url: &Url) -> Vec<Url> {
+    let mut requisites = Vec::new();
+    rewrite_html(html, page_url, false, &mut |url| {
+        requisites.push(url.clone());
+        None
+    });
+    requisites
+}
+
+/// The fonts, images and other stylesheets a stylesheet needs
+pub fn stylesheet_requisites(css: &str, base: &Url) -> Vec<Url> {
+    let mut requisites = Vec::new();
+    rewrite_css(css, base, &mut |url| {
+        requisites.push(url.clone());
+        None
+    });
+    requisites
+}
+
+/// Point the links in a page we've saved at the copies `local` has of
+/// what they link to, making the rest absolute (`--convert-links`)
+pub fn convert_links(html: &str, page_url: &Url, local: &mut dyn FnMut(&Url) -> Option<String>) -> String {
+    let edits = page_links(html, &base_of(html, page_url))
+        .into_iter()
+        .map(|(span, url)| (span, local(&url).unwrap_or_else(|| url.to_string().replace('&', "&amp;"))))
+        .collect();
+    rewrite_html(&apply(html, edits), page_url, true, local)
+}
+
+/// The same for a stylesheet we've saved
+pub fn convert_stylesheet_links(css: &str, base: &Url, local: &mut dyn FnMut(&Url) -> Option<String>) -> String {
+    rewrite_css(css, base, local)
+}
+
+/// Find the resources an HTML page refers to, handing each to `localize`,
+/// and return the page with links replaced by whatever it returns. Links
+/// it returns `None` for are made absolute (when `rewrite` is set), so they
+/// still work from a saved copy.
+fn rewrite_html(html: &str, page_url: &Url, rewrite: bool, localize: &mut dyn FnMut(&Url) -> Option<String>) -> String {
+    let mut edits: Vec<(Range<usize>, String)> = Vec::new();
+
+    // Links are relative to <base href>, which mustn't survive into a local copy
+    let mut base = page_url.clone();
+    if let Some(captures) = BASE_TAG.captures(html) {
+        if let Some(href) = value_of(&captures, &[1, 2, 3]).and_then(|href| page_url.join(href.as_str()).ok()) {
+            base = href;
+        }
+        edits.push((captures.get(0).unwrap().range(), String::new()));
+    }
+    let mut replacement = |link: &str| -> Option<String> {
+        let url = resolve(&base, link)?;
+        Some(localize(&url).unwrap_or_else(|| url.to_string().replace('&', "&amp;")))
+    };
+
+    for tag in RESOURCE_TAG.captures_iter(html) {
+        let (name, attributes) = (tag.get(1).unwrap(), tag.get(2).unwrap());
+        let attributes_start = attributes.start();
+        let found: Vec<_> = ATTRIBUTE.captures_iter(attributes.as_str()).collect();
+        let attribute = |wanted: &str| {
+            found.iter().find(|c| c[1].eq_ignore_ascii_case(wanted)).and_then(|c| value_of(c, &[2, 3, 4]))
+        };
+        // Only stylesheets and icons among <link>s, not every related page
+        if name.as_str().eq_ignore_ascii_case("link") {
+            let rel = attribute("rel").map(|rel| rel.as_str().to_ascii_lowercase()).unwrap_or_default();
+            if !rel.split_ascii_whitespace().any(|rel| rel == "stylesheet" || rel == "icon") {
+                continue;
+            }
+        }
+        for captures in &found {
+            let Some(value) = value_of(captures, &[2, 3, 4]) else { continue };
+            let span = attributes_start + value.start()..attributes_start + value.end();
+            let new = match captures[1].to_ascii_lowercase().as_str() {
+                "src" | "href" | "poster" => replacement(value.as_str()),
+                "srcset" => Some(
+                    value
+                        .as_str()
+                        .split(',')
+                        .map(|candidate| {
+                            let candidate = candidate.trim();
+                            let (link, descriptor) = candidate.split_once(char::is_whitespace).unwrap_or((candidate, ""));
+                            let link = replacement(link).unwrap_or_else(|| link.to_string());
+                            if descriptor.is_empty() { link } else { format!("{} {}", link, descriptor.trim()) }
+                        })
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                ),
+                // Our copies of stylesheets won't match the page's subresource integrity hashes
+                "integrity" => {
+                    let whole = captures.get(0).unwrap();
+                    edits.push((attributes_start + whole.start()..attributes_start + whole.end(), String::new()));
+                    None
+                }
+                _ => None,
+            };
+            if let Some(new) = new {
+                edits.push((span, new));
+            }
+        }
+    }
+
+    for attribute in STYLE_ATTRIBUTE.captures_iter(html) {
+        let css = value_of(&attribute, &[1, 2]).unwrap();
+        edits.push((css.range(), rewrite_css(css.as_str(), &base, &mut |url| Some(replacement(url.as_str()).unwrap()))));
+    }
+    // The text of a <style> element isn't HTML-escaped, unlike attribute values
+    for block in STYLE_BLOCK.captures_iter(html) {
+        let css = block.get(1).unwrap();
+        edits.push((css.range(), rewrite_css(css.as_str(), &base, localize)));
+    }
+
+    if !rewrite {
+        return html.to_string();
+    }
+    apply(html, edits)
+}
+
+/// Find the `url()`s and `@import`s in a stylesheet, handing each to
+/// `localize`, and return the stylesheet with them replaced by whatever it
+/// returns (or made absolute, if it returns `None`)
+fn rewrite_css(css: &str, base: &Url, localize: &mut dyn FnMut(&Url) -> Option<String>) -> String {
+    let mut edits = Vec::new();
+    for captures in CSS_URL.captures_iter(css) {
+        let Some(link) = value_of(&captures, &[1, 2, 3, 4, 5]) else { continue };
+        let Some(url) = resolve(base, link.as_str()) else { continue };
+        let new = localize(&url).unwrap_or_else(|| url.to_string());
+        edits.push((link.range(), new));
+    }
+    apply(css, edits)
+}
+
+/// Apply non-overlapping replacements to `text`; any that overlap an earlier one are dropped
+fn apply(text: &str, mut edits: Vec<(Range<usize>, String)>) -> String {
+    edits.sort_by_key(|(range, _)| range.start);
+    let mut result = String::with_capacity(text.len());
+    let mut position = 0;
+    for (range, new) in edits {
+        if range.start < position {
+            continue;
+        }
+        result.push_str(&text[position..range.start]);
+        result.push_str(&new);
+        position = range.end;
+    }
+    result.push_str(&text[position..]);
+    result
+}
+
+/// Write a page and its resources as a `multipart/related` MHTML archive,
+/// dated `date` if it's given, and otherwise now
+fn write_mhtml(out: &mut impl Write, page: &Fetched, resources: &[Resource], date: Option<SystemTime>) -> io::Result<()> {
+    let unique = match date {
+        // Made from the page, so saving it again gives the same archive
+        Some(_) => hex::encode(&Sha256::digest(&page.body)[..8]),
+        None => format!("{:016x}", getrandom::u64().unwrap_or_default()),
+    };
+    let boundary = format!("----rustdl-boundary-{}", unique);
+    write!(out, "From: <Saved by rustdl>\r\n")?;
+    write!(out, "Snapshot-Content-Location: {}\r\n", page.url)?;
+    write!(out, "Date: {}\r\n", httpdate::fmt_http_date(date.unwrap_or_else(SystemTime::now)))?;
+    write!(out, "MIME-Version: 1.0\r\n")?;
+    write!(out, "Content-Type: multipart/related;\r\n\ttype=\"text/html\";\r\n\tboundary=\"{}\"\r\n\r\n", boundary)?;
+    let parts = std::iter::once((&page.url, page.content_type.as_str(), &page.body))
+        .chain(resources.iter().map(|r| (&r.url, r.content_type.as_str(), &r.body)));
+    for (url, content_type, body) in parts {
+        write!(out, "--{}\r\n", boundary)?;
+        write!(out, "Content-Type: {}\r\n", content_type)?;
+        write!(out, "Content-Transfer-Encoding: base64\r\n")?;
+        write!(out, "Content-Location: {}\r\n\r\n", url)?;
+        let encoded = STANDARD.encode(body);
+        for line in encoded.as_bytes().chunks(76) {
+            out.write_all(line)?;
+            out.write_all(b"\r\n")?;
+        }
+    }
+    write!(out, "--{}--\r\n", boundary)
+}
+
+fn write(path: &Path, contents: &[u8], modified: Option<SystemTime>) -> Result<(), PageError> {
+    // Trusted server names can put the page in a subdirectory
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).map_err(|source| PageError::Io { path: parent.to_path_buf(), source })?;
+    }
+    fs::write(path, contents).map_err(|source| PageError::Io { path: path.to_path_buf(), source })?;
+    match modified {
+        Some(modified) => timestamping::set_time(path, modified).map_err(|source| PageError::Io { path: path.to_path_buf(), source }),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Localize everything under example.com, as if the other hosts were down
+    fn localize(seen: &mut Vec<String>) -> impl FnMut(&Url) -> Option<String> + '_ {
+        |url| {
+            seen.push(url.to_string());
+            (url.host_str() == Some("example.com")).then(|| format!("files/{}", local_name(url)))
+        }
+    }
+
+    #[test]
+    fn test_page_format_from_str() {
+        assert_eq!("MHTML".parse::<PageFormat>().unwrap(), PageFormat::Mhtml);
+        assert_eq!("directory".parse::<PageFormat>().unwrap(), PageFormat::Directory);
+        assert!("zip".parse::<PageFormat>().unwrap_err().contains("directory, mhtml"));
+    }
+
+    #[test]
+    fn test_rewrite_html_localizes_resources() {
+        let page = Url::parse("https://example.com/blog/post.html").unwrap();
+        let html = r#"<html><head>
+<link rel="stylesheet" href="../css/site.css" integrity="sha384-abc" crossorigin="anonymous">
+<link rel="alternate" href="/feed.xml">
+<script src='app.js?v=1&amp;x=2'></script>
+<style>body { background: url("bg.png") }</style>
+</head><body>
+<img src=photo.jpg srcset="photo-2x.jpg 2x, https://cdn.example.net/photo-3x.jpg 3x" style="border-image: url(border.png)">
+<a href="other.html">not a resource</a>
+<img src="data:image/png;base64,AAAA">
+</body></html>"#;
+
+        let mut seen = Vec::new();
+        let rewritten = rewrite_html(html, &page, true, &mut localize(&mut seen));
+
+        assert!(rewritten.contains(r#"<link rel="stylesheet" href="files/site.css"  crossorigin="anonymous">"#), "{}", rewritten);
+        assert!(rewritten.contains(r#"<link rel="alternate" href="/feed.xml">"#));
+        assert!(rewritten.contains("<script src='files/app.js'>"));
+        assert!(rewritten.contains(r#"url("files/bg.png")"#));
+        assert!(rewritten.contains(r#"srcset="files/photo-2x.jpg 2x, https://cdn.example.net/photo-3x.jpg 3x""#));
+        assert!(rewritten.contains("<img src=files/photo.jpg"));
+        assert!(rewritten.contains("url(files/border.png)"));
+        assert!(rewritten.contains(r#"<a href="other.html">"#));
+        assert!(rewritten.contains("data:image/png"));
+        assert!(seen.contains(&"https://example.com/blog/app.js?v=1&x=2".to_string()));
+        assert!(!seen.iter().any(|url| url.contains("feed.xml") || url.contains("other.html")));
+    }
+
+    #[test]
+    fn test_rewrite_html_drops_base_tag() {
+        let page = Url::parse("https://example.com/a/page.html").unwrap();
+        let html = r#"<head><base href="https://example.com/static/"></head><img src="logo.png">"#;
+        let mut seen = Vec::new();
+        let rewritten = rewrite_html(html, &page, true, &mut localize(&mut seen));
+        assert_eq!(rewritten, r#"<head></head><img src="files/logo.png">"#);
+        assert_eq!(seen, vec!["https://example.com/static/logo.png"]);
+
+        // Without rewriting, we only look
+        let mut seen = Vec::new();
+        assert_eq!(rewrite_html(html, &page, false, &mut localize(&mut seen)), html);
+        assert_eq!(seen.len(), 1);
+    }
+
+    #[test]
+    fn test_rewrite_html_styles() {
+        let page = Url::parse("https://example.com/index.html").unwrap();
+        let html = r#"<style>body { background: url(/bg.png?a=1&b=2) }</style><p style="background: url(/bg.png?a=1&b=2)">"#;
+        let rewritten = rewrite_html(html, &page, true, &mut |_| None);
+        assert_eq!(
+            rewritten,
+            r#"<style>body { background: url(https://example.com/bg.png?a=1&b=2) }</style><p style="background: url(https://example.com/bg.png?a=1&amp;b=2)">"#
+        );
+    }
+
+    #[test]
+    fn test_links() {
+        let page = Url::parse("https://example.com/docs/index.html").unwrap();
+        let html = r#"<a href="guide.html">Guide</a> <a href='#top'>Top</a> <a href="mailto:a@example.com">Mail</a>
+            <img src="/logo.png"> <iframe src="https://other.example/embed"></iframe>"#;
+        let links: Vec<String> = links(html, &page).into_iter().map(String::from).collect();
+        assert_eq!(links, ["https://example.com/docs/guide.html", "https://other.example/embed"]);
+        let requisites: Vec<String> = requisites(html, &page).into_iter().map(String::from).collect();
+        assert_eq!(requisites, ["https://example.com/logo.png"]);
+        let css = "@font-face { src: url(fonts/a.woff2) } body { background: url('/bg.png') }";
+        let requisites: Vec<String> = stylesheet_requisites(css, &page).into_iter().map(String::from).collect();
+        assert_eq!(requisites, ["https://example.com/docs/fonts/a.woff2", "https://example.com/bg.png"]);
+    }
+
+    #[test]
+    fn test_convert_links() {
+        let page = Url::parse("https://example.com/docs/index.html").unwrap();
+        let html = r#"<base href="/docs/"><a href="guide.html">Guide</a> <a href="/about">About</a> <img src="img/logo.png">"#;
+        let converted = convert_links(html, &page, &mut |url| match url.path() {
+            "/docs/guide.html" => Some("guide.html".to_string()),
+            "/docs/img/logo.png" => Some("img/logo.png".to_string()),
+            _ => None,
+        });
+        assert_eq!(converted, r#"<a href="guide.html">Guide</a> <a href="https://example.com/about">About</a> <img src="img/logo.png">"#);
+    }
+
+    #[test]
+    fn test_rewrite_css() {
+        let base = Url::parse("https://example.com/css/site.css").unwrap();
+        let css = r#"@import "fonts.css"; .a { background: url( '../img/a.png' ) } .b { background: url(data:image/gif;base64,R0) }"#;
+        let mut seen = Vec::new();
+        let rewritten = rewrite_css(css, &base, &mut localize(&mut seen));
+        assert_eq!(rewritten, r#"@import "files/fonts.css"; .a { background: url( 'files/a.png' ) } .b { background: url(data:image/gif;base64,R0) }"#);
+        assert_eq!(seen, vec!["https://example.com/css/fonts.css", "https://example.com/img/a.png"]);
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(local_name(&Url::parse("https://example.com/img/a%20b.png?x=1").unwrap()), "a_20b.png");
+        assert_eq!(local_name(&Url::parse("https://example.com/").unwrap()), "resource");
+        assert_eq!(local_name(&Url::parse("https://example.com/.htaccess").unwrap()), "htaccess");
+        assert_eq!(with_extension("post", PageFormat::Directory), "post.html");
+        assert_eq!(with_extension("post.HTM", PageFormat::Directory), "post.HTM");
+        assert_eq!(with_extension("post.php", PageFormat::Directory), "post.php.html");
+        assert_eq!(with_extension("post.html", PageFormat::Mhtml), "post.mhtml");
+        assert_eq!(with_extension("post.php", PageFormat::Mhtml), "post.php.mhtml");
+    }
+
+    #[test]
+    fn test_write_mhtml() {
+        let page = Fetched {
+            url: Url::parse("https://example.com/").unwrap(),
+            content_type: "text/html; charset=utf-8".to_string(),
+            headers: HeaderMap::new(),
+            body: b"<img src=a.png>".to_vec(),
+        };
+        let resources = [Resource {
+            url: Url::parse("https://example.com/a.png").unwrap(),
+            name: "a.png".to_string(),
+            content_type: "image/png".to_string(),
+            body: vec![0x89, b'P', b'N', b'G'],
+        }];
+        let mut archive = Vec::new();
+        write_mhtml(&mut archive, &page, &resources, None).unwrap();
+        let archive = String::from_utf8(archive).unwrap();
+
+        assert!(archive.contains("Content-Type: multipart/related;\r\n\ttype=\"text/html\";"));
+        assert!(archive.contains("Content-Location: https://example.com/\r\n\r\nPGltZyBzcmM9YS5wbmc+\r\n"));
+        assert!(archive.contains("Content-Type: image/png\r\nContent-Transfer-Encoding: base64\r\nContent-Location: https://example.com/a.png\r\n\r\niVBORw==\r\n"));
+        assert!(archive.trim_end().ends_with("--"));
+
+        let dated = |date| {
+            let mut archive = Vec::new();
+            write_mhtml(&mut archive, &page, &resources, Some(date)).unwrap();
+            archive
+        };
+        let epoch = SystemTime::UNIX_EPOCH;
+        assert_eq!(dated(epoch), dated(epoch));
+        assert!(String::from_utf8(dated(epoch)).unwrap().contains("Date: Thu, 01 Jan 1970 00:00:00 GMT\r\n"));
+    }
+}
@@ -0,0 +1,632 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use indicatif::ProgressBar;
+use log::{debug, info, warn};
+use reqwest::StatusCode;
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::{self, HeaderMap};
+use serde::{Deserialize, Serialize};
+
+use crate::cancel::{self, Cancellation, Cancelled};
+use crate::checksum::ChecksumError;
+use crate::copy;
+use crate::preallocate;
+use crate::range::ByteRange;
+use crate::throttle::{self, RateLimiter};
+#[cfg(feature = "state-db")]
+use crate::state_db::StateDb;
+use crate::timeouts::{self, RequestError};
+
+/// Suffix for files that are still being downloaded
+pub const PART_SUFFIX: &str = ".part";
+/// Suffix for the control file that sits next to a `.part` file
+pub const CONTROL_SUFFIX: &str = ".part.json";
+
+/// How much to write between updates of the control file
+const CHECKPOINT_INTERVAL: u64 = 8 * 1024 * 1024;
+
+/// Everything needed to finish a download, independent of where it was started
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartMetadata {
+    pub url: String,
+    /// Name to give the file once it's complete, next to the `.part` file
+    pub filename: String,
+    /// Bytes known to be safely in the `.part` file
+    pub offset: u64,
+    pub total_length: Option<u64>,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// The part of the remote file being downloaded, if it's not all of it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range: Option<ByteRange>,
+}
+
+/// Errors from partial downloads
+#[derive(Debug, thiserror::Error)]
+pub enum PartError {
+    #[error("could not access {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("control file {path} is corrupt: {source}")]
+    Corrupt { path: PathBuf, source: serde_json::Error },
+
+    #[error("not enough space for {path} ({})", indicatif::HumanBytes(*needed))]
+    NoSpace { path: PathBuf, needed: u64 },
+
+    #[error("{0} is not a partial download")]
+    NotPartial(PathBuf),
+
+    #[error(transparent)]
+    Request(#[from] RequestError),
+
+    #[error("{url}: server returned {status}")]
+    Status { url: String, status: StatusCode },
+
+    #[error("{url}: server resumed at the wrong offset ({content_range})")]
+    WrongRange { url: String, content_range: String },
+
+    #[error("{url}: server sent the whole file instead of a range (it changed, or it doesn't support ranges)")]
+    RangeIgnored { url: String },
+
+    #[error("{url}: {source}")]
+    Checksum { url: String, source: ChecksumError },
+
+    #[error(transparent)]
+    Cancelled(#[from] Cancelled),
+
+    #[error("{0} download(s) could not be resumed")]
+    Failed(usize),
+}
+
+/// A download in progress: `<name>.part` holds the data and `<name>.part.json`
+/// records where it came from and how much of it is there
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartFile {
+    control_path: PathBuf,
+    pub metadata: PartMetadata,
+}
+
+impl PartFile {
+    /// Start a new partial download for `output`, recording what we know from the response
+    pub fn create(
+        output: &Path,
+        url: &str,
+        headers: &HeaderMap,
+        total_length: Option<u64>,
+        range: Option<&ByteRange>,
+    ) -> Result<PartWriter, PartError> {
+        let filename = output.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        let part = PartFile {
+            control_path: with_suffix(output, CONTROL_SUFFIX),
+            metadata: PartMetadata {
+                url: url.to_string(),
+                filename,
+                offset: 0,
+                total_length,
+                etag: header_string(headers, header::ETAG),
+                last_modified: header_string(headers, header::LAST_MODIFIED),
+                range: range.cloned(),
+            },
+        };
+        let data_path = part.data_path();
+        let file = File::create(&data_path).map_err(|source| PartError::Io { path: data_path.clone(), source })?;
+        reserve_space(&file, &data_path, total_length)?;
+        part.save()?;
+        Ok(PartWriter::new(file, part))
+    }
+
+    /// Record the first `offset` bytes of `output`'s `.part` file, written
+    /// some other way, as a partial download of `url` to be finished with
+    /// `download resume`
+    pub fn adopt(output: &Path, url: &str, offset: u64, total_length: u64) -> Result<Self, PartError> {
+        let part = PartFile {
+            control_path: with_suffix(output, CONTROL_SUFFIX),
+            metadata: PartMetadata {
+                url: url.to_string(),
+                filename: output.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default(),
+                offset,
+                total_length: Some(total_length),
+                etag: None,
+                last_modified: None,
+                range: None,
+            },
+        };
+        part.save()?;
+        Ok(part)
+    }
+
+    /// Open a partial download from either its `.part` file or its control file
+    pub fn open(path: &Path) -> Result<Self, PartError> {
+        let name = path.to_string_lossy();
+        let control_path = if name.ends_with(CONTROL_SUFFIX) {
+            path.to_path_buf()
+        } else if name.ends_with(PART_SUFFIX) {
+            PathBuf::from(format!("{}.json", name))
+        } else {
+            return Err(PartError::NotPartial(path.to_path_buf()));
+        };
+        let contents = fs::read_to_string(&control_path).map_err(|source| PartError::Io { path: control_path.clone(), source })?;
+        let metadata = serde_json::from_str(&contents).map_err(|source| PartError::Corrupt { path: control_path.clone(), source })?;
+        let mut part = Self { control_path, metadata };
+
+        // Only trust bytes that actually made it into the file
+        let data_path = part.data_path();
+        let length = fs::metadata(&data_path).map_err(|source| PartError::Io { path: data_path.clone(), source })?.len();
+        if length < part.metadata.offset {
+            warn!("{} is shorter than recorded, resuming from {} bytes", data_path.display(), length);
+            part.metadata.offset = length;
+        }
+        Ok(part)
+    }
+
+    /// Find the control files of partial downloads in a directory
+    pub fn find(directory: &Path) -> Result<Vec<PathBuf>, PartError> {
+        let entries = fs::read_dir(directory).map_err(|source| PartError::Io { path: directory.to_path_buf(), source })?;
+        let mut found: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.to_string_lossy().ends_with(CONTROL_SUFFIX))
+            .collect();
+        found.sort();
+        Ok(found)
+    }
+
+    /// Where the downloaded data is
+    pub fn data_path(&self) -> PathBuf {
+        let name = self.control_path.to_string_lossy();
+        PathBuf::from(name.strip_suffix(".json").unwrap_or(&name))
+    }
+
+    /// Where the finished file will go: wherever the `.part` file is now
+    pub fn output_path(&self) -> PathBuf {
+        self.control_path.with_file_name(&self.metadata.filename)
+    }
+
+    /// Whether we know enough to ask the server for just the rest of the file.
+    /// Without a length the response was probably compressed on the fly, so
+    /// our offset doesn't correspond to a byte range on the server.
+    pub fn can_resume(&self) -> bool {
+        self.metadata.offset > 0 && self.metadata.total_length.is_some()
+    }
+
+    /// Headers asking for the rest of the file (or of its range), as long as
+    /// it hasn't changed
+    pub fn range_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let range = match &self.metadata.range {
+            Some(range) => range.header_value(self.metadata.offset),
+            None => format!("bytes={}-", self.metadata.offset),
+        };
+        headers.insert(header::RANGE, range.parse().unwrap());
+        // Byte offsets only mean something for the unencoded file
+        headers.insert(header::ACCEPT_ENCODING, header::HeaderValue::from_static("identity"));
+        let validator = self.metadata.etag.as_ref().filter(|etag| !etag.starts_with("W/")).or(self.metadata.last_modified.as_ref());
+        if let Some(value) = validator.and_then(|value| value.parse().ok()) {
+            headers.insert(header::IF_RANGE, value);
+        }
+        headers
+    }
+
+    /// Reopen the data file to carry on from the recorded offset, dropping
+    /// anything written after the last checkpoint
+    pub fn reopen(self) -> Result<PartWriter, PartError> {
+        let data_path = self.data_path();
+        let io_err = |source| PartError::Io { path: data_path.clone(), source };
+        let mut file = OpenOptions::new().write(true).open(&data_path).map_err(io_err)?;
+        file.set_len(self.metadata.offset).map_err(io_err)?;
+        file.seek(SeekFrom::End(0)).map_err(io_err)?;
+        reserve_space(&file, &data_path, self.metadata.total_length)?;
+        Ok(PartWriter::new(file, self))
+    }
+
+    /// Throw away what we have and start again from the beginning
+    pub fn restart(mut self, headers: &HeaderMap, total_length: Option<u64>) -> Result<PartWriter, PartError> {
+        let data_path = self.data_path();
+        let file = File::create(&data_path).map_err(|source| PartError::Io { path: data_path.clone(), source })?;
+        reserve_space(&file, &data_path, total_length)?;
+        self.metadata.offset = 0;
+        self.metadata.total_length = total_length;
+        self.metadata.etag = header_string(headers, header::ETAG);
+        self.metadata.last_modified = header_string(headers, header::LAST_MODIFIED);
+        self.save()?;
+        Ok(PartWriter::new(file, self))
+    }
+
+    /// Move the finished file into place and remove the control file
+    pub fn complete(self) -> Result<PathBuf, PartError> {
+        let (data_path, output) = (self.data_path(), self.output_path());
+        fs::rename(&data_path, &output).map_err(|source| PartError::Io { path: data_path, source })?;
+        fs::remove_file(&self.control_path).map_err(|source| PartError::Io { path: self.control_path.clone(), source })?;
+        Ok(output)
+    }
+
+    /// Remove both the data and the control file
+    pub fn discard(self) {
+        let _ = fs::remove_file(self.data_path());
+        let _ = fs::remove_file(&self.control_path);
+    }
+
+    /// Write the control file; via a temporary file so it's never half-written
+    fn save(&self) -> Result<(), PartError> {
+        let io_err = |source| PartError::Io { path: self.control_path.clone(), source };
+        let contents = serde_json::to_string_pretty(&self.metadata).expect("part metadata is always serializable");
+        let temporary = with_suffix(&self.control_path, ".tmp");
+        fs::write(&temporary, contents).map_err(io_err)?;
+        fs::rename(&temporary, &self.control_path).map_err(io_err)
+    }
+}
+
+/// Set aside room for the whole file, if we know how big it'll be
+fn reserve_space(file: &File, path: &Path, total_length: Option<u64>) -> Result<(), PartError> {
+    let Some(needed) = total_length else { return Ok(()) };
+    match preallocate::preallocate(file, needed) {
+        Ok(reserved) => {
+            if reserved {
+                debug!("Reserved {} bytes for {}", needed, path.display());
+            }
+            Ok(())
+        }
+        Err(e) if preallocate::is_out_of_space(&e) => Err(PartError::NoSpace { path: path.to_path_buf(), needed }),
+        Err(source) => Err(PartError::Io { path: path.to_path_buf(), source }),
+    }
+}
+
+fn header_string(headers: &HeaderMap, name: header::HeaderName) -> Option<String> {
+    headers.get(name).and_then(|value| value.to_str().ok()).map(str::to_string)
+}
+
+/// `path` with `suffix` on the end of its name
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Writes a `.part` file, updating its control file every few megabytes. If
+/// it's dropped without being finished, the final offset is recorded so the
+/// download can be picked up later with `download resume` (unless it was
+/// cancelled, and the cancellation's policy says to delete it).
+pub struct PartWriter {
+    /// The data file, or with the `io-uring` feature, a ring that writes to it
+    file: Box<dyn Write + Send>,
+    part: Option<PartFile>,
+    offset: u64,
+    checkpointed: u64,
+    cancellation: Option<Cancellation>,
+    /// Where each checkpoint's offset is recorded too
+    #[cfg(feature = "state-db")]
+    state: Option<Arc<StateDb>>,
+}
+
+impl PartWriter {
+    fn new(file: File, part: PartFile) -> Self {
+        let offset = part.metadata.offset;
+        Self { file: data_writer(file), part: Some(part), offset, checkpointed: offset, cancellation: None, #[cfg(feature = "state-db")] state: None }
+    }
+
+    /// Stop writing once `cancellation` is triggered, and clean up as it says
+    pub fn cancellable(mut self, cancellation: &Cancellation) -> Self {
+        self.cancellation = Some(cancellation.clone());
+        self
+    }
+
+    /// Record how far the download has got in `state` at each checkpoint
+    #[cfg(feature = "state-db")]
+    pub fn recorded(mut self, state: Option<&Arc<StateDb>>) -> Self {
+        self.state = state.cloned();
+        self
+    }
+
+    /// How many bytes of the file we have
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Finish writing and hand back the partial download, ready to be completed or discarded
+    pub fn finish(mut self) -> Result<PartFile, PartError> {
+        let mut part = self.part.take().expect("part is only taken once");
+        self.file.flush().map_err(|source| PartError::Io { path: part.data_path(), source })?;
+        part.metadata.offset = self.offset;
+        Ok(part)
+    }
+
+    fn checkpoint(&mut self) -> io::Result<()> {
+        if let Some(part) = &mut self.part {
+            // The offset's only recorded once everything before it is in the file
+            self.file.flush()?;
+            part.metadata.offset = self.offset;
+            part.save().map_err(io::Error::other)?;
+            self.checkpointed = self.offset;
+            #[cfg(feature = "state-db")]
+            if let Some(state) = &self.state
+                && let Err(e) = state.progress(&part.metadata.url, self.offset)
+            {
+                debug!("Could not record {} at {} bytes: {}", part.metadata.url, self.offset, e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+fn data_writer(file: File) -> Box<dyn Write + Send> {
+    crate::uring::writer(file)
+}
+
+#[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+fn data_writer(file: File) -> Box<dyn Write + Send> {
+    Box::new(file)
+}
+
+impl Write for PartWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(cancellation) = &self.cancellation {
+            cancellation.check()?;
+        }
+        let written = self.file.write(buf)?;
+        self.offset += written as u64;
+        if self.offset - self.checkpointed >= CHECKPOINT_INTERVAL {
+            self.checkpoint()?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for PartWriter {
+    fn drop(&mut self) {
+        // Everything that stops a download early ends up here, so this is where cancelled downloads are tidied up
+        let cancelled = self.cancellation.as_ref().filter(|cancellation| cancellation.reason().is_some());
+        if let (Some(cancellation), Some(part)) = (cancelled, &self.part)
+            && !cancellation.keep(&part.data_path())
+        {
+            let part = self.part.take().expect("part was just checked");
+            info!("Deleted partial download {}", part.data_path().display());
+            part.discard();
+            return;
+        }
+        if self.part.is_some() && self.offset != self.checkpointed {
+            match self.checkpoint() {
+                Ok(()) => info!("Saved partial download at {} bytes; finish it with `download resume`", self.offset),
+                Err(e) => warn!("Could not record partial download progress: {}", e),
+            }
+        }
+    }
+}
+
+/// Fetch the rest of a partial download. `request` should carry any headers
+/// and credentials the original request had. Returns the finished part, still
+/// under its `.part` name so the caller can verify it before completing it.
+#[allow(clippy::too_many_arguments)]
+pub fn resume(
+    client: &Client,
+    part: PartFile,
+    request: RequestBuilder,
+    header_timeout: Option<Duration>,
+    limiter: Option<Arc<RateLimiter>>,
+    pb: &ProgressBar,
+    cancellation: &Cancellation,
+    #[cfg(feature = "state-db")] state: Option<&Arc<StateDb>>,
+) -> Result<PartFile, PartError> {
+    let url = part.metadata.url.clone();
+    let resuming = part.can_resume();
+    // A range of a file has to be asked for even if we have none of it yet
+    let ranged = part.metadata.range.is_some();
+    let request = if resuming || ranged { request.headers(part.range_headers()) } else { request };
+    let request = request.build().map_err(RequestError::from)?;
+    let response = timeouts::execute(client, request, header_timeout)?;
+    let status = response.status();
+
+    let writer = match status {
+        StatusCode::PARTIAL_CONTENT if resuming || ranged => {
+            let content_range = response.headers().get(header::CONTENT_RANGE).and_then(|value| value.to_str().ok()).unwrap_or_default().to_string();
+            let expected = part.metadata.range.as_ref().map_or(0, |range| range.start) + part.metadata.offset;
+            if range_start(&content_range) != Some(expected) {
+                return Err(PartError::WrongRange { url, content_range });
+            }
+            debug!("Resuming {} at {} bytes", url, part.metadata.offset);
+            part.reopen()?
+        }
+        StatusCode::RANGE_NOT_SATISFIABLE if part.metadata.total_length == Some(part.metadata.offset) => {
+            debug!("{} was already complete", url);
+            return Ok(part);
+        }
+        status if status.is_success() && ranged => return Err(PartError::RangeIgnored { url }),
+        status if status.is_success() => {
+            if resuming {
+                info!("{} can't be resumed (it changed, or the server doesn't support ranges); starting over", url);
+            }
+            let total_length = response.content_length();
+            part.restart(response.headers(), total_length)?
+        }
+        status => return Err(PartError::Status { url, status }),
+    };
+    let mut writer = writer.cancellable(cancellation);
+    #[cfg(feature = "state-db")]
+    {
+        writer = writer.recorded(state);
+    }
+
+    let total = writer.offset() + response.content_length().unwrap_or(0);
+    pb.set_length(total);
+    pb.set_position(writer.offset());
+    copy_response(response, &mut writer, pb, limiter, cancellation)?;
+    writer.finish()
+}
+
+fn copy_response(response: Response, writer: &mut PartWriter, pb: &ProgressBar, limiter: Option<Arc<RateLimiter>>, cancellation: &Cancellation) -> Result<(), PartError> {
+    let path = writer.part.as_ref().map(PartFile::data_path).unwrap_or_default();
+    copy::buffered(&mut pb.wrap_read(throttle::throttled(response, cancellation, limiter, None)), writer).map(|_| ()).map_err(|source| match cancel::reason(&source) {
+        Some(reason) => Cancelled(reason).into(),
+        None => PartError::Io { path, source },
+    })
+}
+
+/// The first byte of a `Content-Range: bytes <start>-<end>/<total>` header
+pub fn range_start(content_range: &str) -> Option<u64> {
+    content_range.strip_prefix("bytes ")?.split('-').next()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rustdl-partial-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ETAG, "\"abc\"".parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_interrupted_download_records_offset() {
+        let dir = temp_dir("interrupted");
+        let mut writer = PartFile::create(&dir.join("file.bin"), "https://example.com/file.bin", &headers(), Some(100), None).unwrap();
+        writer.write_all(b"hello").unwrap();
+        drop(writer);
+
+        let part = PartFile::open(&dir.join("file.bin.part")).unwrap();
+        assert_eq!(part.metadata.offset, 5);
+        assert_eq!(part.metadata.url, "https://example.com/file.bin");
+        assert_eq!(part.metadata.etag.as_deref(), Some("\"abc\""));
+        assert!(part.can_resume());
+        assert_eq!(part.range_headers().get(header::RANGE).unwrap(), "bytes=5-");
+        assert_eq!(part.range_headers().get(header::IF_RANGE).unwrap(), "\"abc\"");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_ranged_download_resumes_within_its_range() {
+        let dir = temp_dir("ranged");
+        let range: ByteRange = "100-200".parse().unwrap();
+        let mut writer = PartFile::create(&dir.join("file.bin.part-100-200"), "https://example.com/file.bin", &headers(), Some(100), Some(&range)).unwrap();
+        writer.write_all(b"hello").unwrap();
+        drop(writer);
+
+        let part = PartFile::open(&dir.join("file.bin.part-100-200.part")).unwrap();
+        assert_eq!(part.metadata.range, Some(range));
+        assert_eq!(part.range_headers().get(header::RANGE).unwrap(), "bytes=105-199");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_moved_part_completes_in_its_new_directory() {
+        let (old, new) = (temp_dir("old"), temp_dir("new"));
+        let mut writer = PartFile::create(&old.join("file.bin"), "https://example.com/file.bin", &headers(), Some(10), None).unwrap();
+        writer.write_all(b"hello").unwrap();
+        drop(writer);
+        for name in ["file.bin.part", "file.bin.part.json"] {
+            fs::rename(old.join(name), new.join(name)).unwrap();
+        }
+
+        let mut writer = PartFile::open(&new.join("file.bin.part.json")).unwrap().reopen().unwrap();
+        writer.write_all(b"world").unwrap();
+        let output = writer.finish().unwrap().complete().unwrap();
+
+        assert_eq!(output, new.join("file.bin"));
+        assert_eq!(fs::read(&output).unwrap(), b"helloworld");
+        assert_eq!(PartFile::find(&new).unwrap(), Vec::<PathBuf>::new());
+        fs::remove_dir_all(&old).unwrap();
+        fs::remove_dir_all(&new).unwrap();
+    }
+
+    #[test]
+    fn test_reopen_drops_unrecorded_bytes() {
+        let dir = temp_dir("truncate");
+        let writer = PartFile::create(&dir.join("file.bin"), "https://example.com/file.bin", &headers(), Some(10), None).unwrap();
+        let mut part = writer.finish().unwrap();
+        part.metadata.offset = 3;
+        fs::write(part.data_path(), b"abcdef").unwrap();
+
+        let writer = part.reopen().unwrap();
+        assert_eq!(writer.offset(), 3);
+        assert_eq!(fs::read(dir.join("file.bin.part")).unwrap(), b"abc");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_resume_appends_partial_content() {
+        use std::io::Read;
+        use std::net::TcpListener;
+
+        // Serves the last five bytes, but only if asked for them
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            if let Ok((mut stream, _)) = listener.accept() {
+                let mut buffer = [0u8; 4096];
+                let read = stream.read(&mut buffer).unwrap();
+                let request = String::from_utf8_lossy(&buffer[..read]).to_lowercase();
+                let response = if request.contains("range: bytes=5-") && request.contains("if-range: \"abc\"") {
+                    "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 5-9/10\r\nContent-Length: 5\r\n\r\nworld"
+                } else {
+                    "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n"
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        let dir = temp_dir("resume");
+        let url = format!("http://{}/file.bin", address);
+        let mut writer = PartFile::create(&dir.join("file.bin"), &url, &headers(), Some(10), None).unwrap();
+        writer.write_all(b"hello").unwrap();
+        drop(writer);
+
+        let client = Client::new();
+        let part = PartFile::open(&dir.join("file.bin.part")).unwrap();
+        #[cfg(feature = "state-db")]
+        let part = resume(&client, part, client.get(&url), None, None, &ProgressBar::hidden(), &Cancellation::default(), None).unwrap();
+        #[cfg(not(feature = "state-db"))]
+        let part = resume(&client, part, client.get(&url), None, None, &ProgressBar::hidden(), &Cancellation::default()).unwrap();
+        assert_eq!(part.metadata.offset, 10);
+        assert_eq!(fs::read(part.complete().unwrap()).unwrap(), b"helloworld");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cancelled_download_follows_policy() {
+        use crate::cancel::{CancelPolicy, CancelReason};
+
+        let dir = temp_dir("cancelled");
+        for (policy, kept) in [(CancelPolicy::KeepPart, true), (CancelPolicy::Delete, false)] {
+            let cancellation = Cancellation::new(policy);
+            let mut writer = PartFile::create(&dir.join("file.bin"), "https://example.com/file.bin", &headers(), Some(100), None)
+                .unwrap()
+                .cancellable(&cancellation);
+            writer.write_all(b"hello").unwrap();
+            cancellation.cancel(CancelReason::Interrupted);
+            assert!(writer.write_all(b"world").is_err());
+            drop(writer);
+
+            assert_eq!(dir.join("file.bin.part").exists(), kept);
+            assert_eq!(dir.join("file.bin.part.json").exists(), kept);
+            if kept {
+                assert_eq!(PartFile::open(&dir.join("file.bin.part")).unwrap().metadata.offset, 5);
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_open_rejects_other_files() {
+        assert!(matches!(PartFile::open(Path::new("file.bin")), Err(PartError::NotPartial(_))));
+    }
+
+    #[test]
+    fn test_range_start() {
+        assert_eq!(range_start("bytes 100-199/200"), Some(100));
+        assert_eq!(range_start("bytes */200"), None);
+        assert_eq!(range_start(""), None);
+    }
+}
@@ -0,0 +1,199 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use log::{debug, warn};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::crypto::CryptoProvider;
+use rustls::{ClientConfig, DigitallySignedStruct, SignatureScheme};
+use sha2::{Digest, Sha256};
+
+/// Prefix curl uses for SPKI SHA-256 pins
+const SHA256_PREFIX: &str = "sha256//";
+
+/// A set of acceptable SHA-256 hashes of the server's SubjectPublicKeyInfo,
+/// written like curl's `--pinnedpubkey`: `sha256//<base64>[;sha256//<base64>...]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PinSet {
+    pins: Vec<[u8; 32]>,
+}
+
+impl PinSet {
+    /// Check whether a DER-encoded SPKI matches one of our pins
+    pub fn matches_spki(&self, spki: &[u8]) -> bool {
+        let hash: [u8; 32] = Sha256::digest(spki).into();
+        self.pins.contains(&hash)
+    }
+}
+
+impl FromStr for PinSet {
+    type Err = PinningError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pins = s
+            .split(';')
+            .map(str::trim)
+            .filter(|pin| !pin.is_empty())
+            .map(|pin| {
+                let encoded = pin
+                    .strip_prefix(SHA256_PREFIX)
+                    .ok_or_else(|| PinningError::InvalidPin { pin: pin.to_string() })?;
+                BASE64
+                    .decode(encoded)
+                    .ok()
+                    .and_then(|hash| <[u8; 32]>::try_from(hash).ok())
+                    .ok_or_else(|| PinningError::InvalidPin { pin: pin.to_string() })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if pins.is_empty() {
+            return Err(PinningError::InvalidPin { pin: s.to_string() });
+        }
+        Ok(Self { pins })
+    }
+}
+
+impl fmt::Display for PinSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pins = self.pins.iter().map(|pin| format!("{}{}", SHA256_PREFIX, BASE64.encode(pin))).collect::<Vec<_>>();
+        write!(f, "{}", pins.join(";"))
+    }
+}
+
+/// Errors related to public key pinning
+#[derive(Debug, thiserror::Error)]
+pub enum PinningError {
+    #[error("'{pin}' is not a valid public key pin; expected sha256//<base64 of a SHA-256 hash>")]
+    InvalidPin { pin: String },
+
+    #[error("could not set up TLS for public key pinning: {0}")]
+    Tls(#[from] rustls::Error),
+}
+
+/// Certificate verifier that performs normal verification and then checks the
+/// end-entity certificate's public key against the pin set
+#[derive(Debug)]
+struct PinnedKeyVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+    pins: PinSet,
+}
+
+impl ServerCertVerifier for PinnedKeyVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+
+        let certificate = webpki::EndEntityCert::try_from(end_entity)
+            .map_err(|e| rustls::Error::General(format!("could not parse server certificate: {}", e)))?;
+        let spki = certificate.subject_public_key_info();
+        if self.pins.matches_spki(spki.as_ref()) {
+            debug!("Server public key for {:?} matches pinned key", server_name);
+            Ok(verified)
+        } else {
+            let actual = BASE64.encode(Sha256::digest(spki.as_ref()));
+            warn!("Server public key for {:?} does not match any pinned key (got {}{})", server_name, SHA256_PREFIX, actual);
+            Err(rustls::Error::General(format!(
+                "server public key {}{} does not match the pinned public key",
+                SHA256_PREFIX, actual
+            )))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Build a rustls configuration that uses the platform verifier plus our pins
+pub fn pinned_tls_config(pins: &PinSet) -> Result<ClientConfig, PinningError> {
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    let inner = Arc::new(rustls_platform_verifier::Verifier::new(Arc::clone(&provider))?);
+    Ok(tls_config(provider, Arc::new(PinnedKeyVerifier { inner, pins: pins.clone() }))?)
+}
+
+/// Build the rustls configuration reqwest would: the platform verifier, and nothing pinned
+pub fn platform_tls_config() -> Result<ClientConfig, rustls::Error> {
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    let verifier = Arc::new(rustls_platform_verifier::Verifier::new(Arc::clone(&provider))?);
+    tls_config(provider, verifier)
+}
+
+fn tls_config(provider: Arc<CryptoProvider>, verifier: Arc<dyn ServerCertVerifier>) -> Result<ClientConfig, rustls::Error> {
+    let mut config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth();
+    // reqwest leaves ALPN alone for preconfigured TLS, so offer HTTP/2 ourselves
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // SHA-256 of the bytes "spki" is b+7MjBbFVR2f6z61934tp3O/aL2e+cUpJ86yyG5WiSs=
+    const SPKI_PIN: &str = "sha256//b+7MjBbFVR2f6z61934tp3O/aL2e+cUpJ86yyG5WiSs=";
+
+    #[test]
+    fn test_pin_set_parses_multiple_pins() {
+        let pins: PinSet = format!("{}; sha256//{}", SPKI_PIN, BASE64.encode([0u8; 32])).parse().unwrap();
+        assert_eq!(pins.pins.len(), 2);
+    }
+
+    #[test]
+    fn test_pin_set_rejects_invalid_pins() {
+        assert!("".parse::<PinSet>().is_err());
+        assert!("sha1//AAAA".parse::<PinSet>().is_err());
+        assert!("sha256//not-base64!".parse::<PinSet>().is_err());
+        assert!("sha256//AAAA".parse::<PinSet>().is_err());
+    }
+
+    #[test]
+    fn test_pin_set_matches_spki() {
+        let pins: PinSet = SPKI_PIN.parse().unwrap();
+        assert!(pins.matches_spki(b"spki"));
+        assert!(!pins.matches_spki(b"other"));
+    }
+
+    #[test]
+    fn test_pin_set_display_round_trips() {
+        let pins: PinSet = SPKI_PIN.parse().unwrap();
+        assert_eq!(pins.to_string(), SPKI_PIN);
+    }
+
+    #[test]
+    fn test_pinned_tls_config_builds() {
+        let pins: PinSet = SPKI_PIN.parse().unwrap();
+        let config = pinned_tls_config(&pins).unwrap();
+        assert_eq!(config.alpn_protocols, vec![b"h2".to_vec(), b"http/1.1".to_vec()]);
+    }
+}
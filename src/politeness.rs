@@ -0,0 +1,192 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use url::Url;
+
+/// How hard we're willing to hit any one host
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HostLimits {
+    /// Most downloads to have going from a host at once
+    pub connections: Option<usize>,
+    /// Least time between starting requests to a host
+    pub delay: Option<Duration>,
+}
+
+#[derive(Debug, Default)]
+struct Host {
+    active: usize,
+    /// When the next request may start
+    next: Option<Instant>,
+}
+
+/// Hands out URLs only as fast as their hosts' limits allow, skipping ahead to
+/// other hosts' URLs rather than waiting, so a batch spread over many hosts
+/// keeps them all busy
+#[derive(Debug, Default)]
+pub struct Politeness {
+    limits: HostLimits,
+    /// Wait for the first URL's host instead of skipping ahead
+    in_order: bool,
+    /// Most downloads to have going at once, across every host
+    total: Option<usize>,
+    hosts: Mutex<HashMap<String, Host>>,
+    /// Signalled whenever a download finishes and frees up its host
+    freed: Condvar,
+}
+
+/// A download's place at its host, given up when this is dropped
+#[derive(Debug)]
+pub struct HostPermit {
+    politeness: Arc<Politeness>,
+    /// None when there are no limits to keep track of
+    host: Option<String>,
+}
+
+impl Drop for HostPermit {
+    fn drop(&mut self) {
+        let Some(host) = &self.host else { return };
+        if let Some(host) = self.politeness.hosts.lock().unwrap().get_mut(host) {
+            host.active -= 1;
+        }
+        self.politeness.freed.notify_all();
+    }
+}
+
+impl Politeness {
+    pub fn new(limits: HostLimits) -> Self {
+        Politeness { limits, ..Default::default() }
+    }
+
+    /// Hand out URLs strictly in the order they're queued, so what each one
+    /// is named can't depend on which host was free first (`--deterministic`)
+    pub fn in_order(mut self) -> Self {
+        self.in_order = true;
+        self
+    }
+
+    /// Have no more than `total` downloads going at once, whatever their hosts
+    pub fn at_most(mut self, total: usize) -> Self {
+        self.total = Some(total);
+        self
+    }
+
+    /// Take the first item in `queue` whose host can be asked for something
+    /// now, waiting until there is one; None once the queue is empty
+    pub fn next<T>(self: &Arc<Self>, queue: &Mutex<VecDeque<T>>, url: impl Fn(&T) -> &str) -> Option<(T, HostPermit)> {
+        if self.limits == HostLimits::default() && self.total.is_none() {
+            let item = queue.lock().unwrap().pop_front()?;
+            return Some((item, HostPermit { politeness: Arc::clone(self), host: None }));
+        }
+        let mut hosts = self.hosts.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let mut items = queue.lock().unwrap();
+            if items.is_empty() {
+                return None;
+            }
+            if self.total.is_some_and(|total| hosts.values().map(|host| host.active).sum::<usize>() >= total) {
+                drop(items);
+                hosts = self.freed.wait(hosts).unwrap();
+                continue;
+            }
+            // The soonest a host that's only waiting out its delay can go again
+            let mut wake: Option<Instant> = None;
+            let candidates = if self.in_order { 1 } else { items.len() };
+            let found = items.iter().take(candidates).enumerate().find_map(|(index, item)| {
+                let host = host_of(url(item));
+                match self.ready(hosts.get(&host), now) {
+                    Ok(()) => Some((index, host)),
+                    Err(at) => {
+                        wake = wake.into_iter().chain(at).min();
+                        None
+                    }
+                }
+            });
+            if let Some((index, host)) = found {
+                let item = items.remove(index).expect("the index is in the queue");
+                let state = hosts.entry(host.clone()).or_default();
+                state.active += 1;
+                state.next = self.limits.delay.map(|delay| now + delay);
+                return Some((item, HostPermit { politeness: Arc::clone(self), host: Some(host) }));
+            }
+            drop(items);
+            hosts = match wake {
+                Some(at) => self.freed.wait_timeout(hosts, at - now).unwrap().0,
+                None => self.freed.wait(hosts).unwrap(),
+            };
+        }
+    }
+
+    /// Whether a request to `host` can start now; if not, when it can, or
+    /// None if that's whenever one of its downloads finishes
+    fn ready(&self, host: Option<&Host>, now: Instant) -> Result<(), Option<Instant>> {
+        let Some(host) = host else { return Ok(()) };
+        if self.limits.connections.is_some_and(|limit| host.active >= limit) {
+            return Err(None);
+        }
+        match host.next.filter(|next| *next > now) {
+            Some(next) => Err(Some(next)),
+            None => Ok(()),
+        }
+    }
+}
+
+fn host_of(url: &str) -> String {
+    Url::parse(url).ok().and_then(|url| url.host_str().map(str::to_string)).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_next_skips_busy_hosts() {
+        let queue = Mutex::new(VecDeque::from(["http://a.example/1", "http://a.example/2", "http://b.example/1"]));
+        let politeness = Arc::new(Politeness::new(HostLimits { connections: Some(1), delay: None }));
+        let (first, permit) = politeness.next(&queue, |url| url).unwrap();
+        assert_eq!(first, "http://a.example/1");
+        // a.example is busy, so b.example goes ahead of it
+        let (second, _) = politeness.next(&queue, |url| url).unwrap();
+        assert_eq!(second, "http://b.example/1");
+        drop(permit);
+        assert_eq!(politeness.next(&queue, |url| url).unwrap().0, "http://a.example/2");
+        assert!(politeness.next(&queue, |url| url).is_none());
+
+        let queue = Mutex::new(VecDeque::from(["http://a.example/1", "http://a.example/2", "http://b.example/1"]));
+        let politeness = Arc::new(Politeness::new(HostLimits { connections: Some(1), delay: None }).in_order());
+        let (_, permit) = politeness.next(&queue, |url| url).unwrap();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            drop(permit);
+        });
+        // b.example has to wait its turn
+        assert_eq!(politeness.next(&queue, |url| url).unwrap().0, "http://a.example/2");
+    }
+
+    #[test]
+    fn test_next_keeps_to_the_total() {
+        let queue = Mutex::new(VecDeque::from(["http://a.example/1", "http://b.example/1"]));
+        let politeness = Arc::new(Politeness::new(HostLimits::default()).at_most(1));
+        let (_, permit) = politeness.next(&queue, |url| url).unwrap();
+        let started = Instant::now();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            drop(permit);
+        });
+        // b.example is free, but one download's all we're allowed
+        assert_eq!(politeness.next(&queue, |url| url).unwrap().0, "http://b.example/1");
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_next_spaces_requests_to_a_host() {
+        let queue = Mutex::new(VecDeque::from(["http://a.example/1", "http://a.example/2"]));
+        let politeness = Arc::new(Politeness::new(HostLimits { connections: None, delay: Some(Duration::from_millis(200)) }));
+        let started = Instant::now();
+        politeness.next(&queue, |url| url).unwrap();
+        politeness.next(&queue, |url| url).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+}
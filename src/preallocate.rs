@@ -0,0 +1,120 @@
+use std::fs::File;
+use std::io;
+
+use log::debug;
+
+/// Reserve disk space for `length` bytes of `file` without changing its size,
+/// so a download that won't fit fails straight away and large files aren't
+/// scattered across the disk. Returns whether the space was reserved;
+/// filesystems that can't do it aren't an error, but running out of space is.
+pub fn preallocate(file: &File, length: u64) -> io::Result<bool> {
+    if length == 0 {
+        return Ok(false);
+    }
+    match reserve(file, length) {
+        Ok(()) => Ok(true),
+        Err(e) if is_out_of_space(&e) => {
+            // Some filesystems keep what they did manage to reserve, until it's truncated away
+            if let Ok(metadata) = file.metadata() {
+                let _ = file.set_len(metadata.len());
+            }
+            Err(e)
+        }
+        Err(e) => {
+            debug!("Not preallocating {} bytes: {}", length, e);
+            Ok(false)
+        }
+    }
+}
+
+/// Whether an error means there wasn't room for the file
+pub fn is_out_of_space(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn reserve(file: &File, length: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let length = libc::off_t::try_from(length).map_err(io::Error::other)?;
+    // SAFETY: the descriptor is valid for as long as `file` is borrowed
+    if unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, length) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn reserve(file: &File, length: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let length = libc::off_t::try_from(length).map_err(io::Error::other)?;
+    let mut store = libc::fstore_t {
+        fst_flags: libc::F_ALLOCATECONTIG,
+        fst_posmode: libc::F_PEOFPOSMODE,
+        fst_offset: 0,
+        fst_length: length,
+        fst_bytesalloc: 0,
+    };
+    // SAFETY: the descriptor is valid for as long as `file` is borrowed, and
+    // `store` outlives both calls
+    unsafe {
+        // Contiguous space if there is any, otherwise wherever it fits
+        if libc::fcntl(file.as_raw_fd(), libc::F_PREALLOCATE, &store) == -1 {
+            store.fst_flags = libc::F_ALLOCATEALL;
+            if libc::fcntl(file.as_raw_fd(), libc::F_PREALLOCATE, &store) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn reserve(file: &File, length: u64) -> io::Result<()> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Storage::FileSystem::{FILE_ALLOCATION_INFO, FileAllocationInfo, SetFileInformationByHandle};
+
+    let info = FILE_ALLOCATION_INFO { AllocationSize: i64::try_from(length).map_err(io::Error::other)? };
+    // SAFETY: the handle is valid for as long as `file` is borrowed, and the
+    // buffer is a FILE_ALLOCATION_INFO of the size we say it is
+    let ok = unsafe {
+        SetFileInformationByHandle(
+            file.as_raw_handle(),
+            FileAllocationInfo,
+            &info as *const FILE_ALLOCATION_INFO as *const _,
+            size_of::<FILE_ALLOCATION_INFO>() as u32,
+        )
+    };
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios", windows)))]
+fn reserve(_file: &File, _length: u64) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preallocate_keeps_the_file_size() {
+        let path = std::env::temp_dir().join(format!("rustdl-preallocate-{}", std::process::id()));
+        let file = File::create(&path).unwrap();
+        assert!(!preallocate(&file, 0).unwrap());
+        // Whether it actually reserves anything depends on the filesystem
+        preallocate(&file, 1024 * 1024).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_out_of_space_errors() {
+        assert!(is_out_of_space(&io::Error::from(io::ErrorKind::StorageFull)));
+        assert!(!is_out_of_space(&io::Error::from(io::ErrorKind::Unsupported)));
+    }
+}
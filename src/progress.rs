@@ -0,0 +1,569 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use indicatif::{HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use serde::Serialize;
+
+use crate::speed::{self, SpeedEstimator};
+
+/// How often JSON progress reports how far each download has got
+const JSON_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How download progress is shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProgressMode {
+    /// Animated progress bars
+    #[default]
+    Bars,
+    /// One line per file once it's done, for logs that aren't a terminal
+    Plain,
+    /// Nothing but errors
+    Quiet,
+    /// One JSON object per event on stdout, for wrappers and GUIs
+    Json,
+    /// Nothing drawn; every event goes to a listener registered with
+    /// [`listen`], for programs embedding the library to show their own way
+    Events(ListenerId),
+}
+
+/// A listener registered with [`listen`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListenerId(u64);
+
+/// Where a download has got to
+#[derive(Debug, Clone, PartialEq)]
+pub enum DownloadState {
+    Queued,
+    /// Its response has arrived, and it's being saved
+    Started,
+    Downloading,
+    Finished,
+    /// It wasn't downloaded, for this reason (the file exists, say)
+    Skipped(String),
+    Failed(String),
+}
+
+/// How a download's going, as a listener hears about it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgressEvent {
+    pub url: String,
+    /// What it's being saved as, once it has a name
+    pub file: String,
+    pub bytes: u64,
+    pub total: Option<u64>,
+    /// Bytes per second
+    pub speed: f64,
+    pub state: DownloadState,
+}
+
+type Callback = Arc<dyn Fn(&ProgressEvent) + Send + Sync>;
+
+static LISTENERS: LazyLock<Mutex<HashMap<u64, Callback>>> = LazyLock::new(Mutex::default);
+static NEXT_LISTENER: AtomicU64 = AtomicU64::new(0);
+
+/// Have downloads with `ProgressMode::Events(subscription.id())` report to
+/// `callback`, until the subscription's dropped. It's called on whichever
+/// thread the download's on, so it should be quick.
+pub fn listen(callback: impl Fn(&ProgressEvent) + Send + Sync + 'static) -> Subscription {
+    let id = NEXT_LISTENER.fetch_add(1, Ordering::SeqCst);
+    LISTENERS.lock().unwrap().insert(id, Arc::new(callback));
+    Subscription(ListenerId(id))
+}
+
+/// A listener that's registered until this is dropped
+#[derive(Debug)]
+pub struct Subscription(ListenerId);
+
+impl Subscription {
+    pub fn id(&self) -> ListenerId {
+        self.0
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        LISTENERS.lock().unwrap().remove(&(self.0).0);
+    }
+}
+
+/// Tell a listener about `event`, if it's still listening
+fn deliver(id: ListenerId, event: &Event) {
+    let Some(callback) = LISTENERS.lock().unwrap().get(&id.0).cloned() else { return };
+    let progress = |url: Option<&str>, file: &str, bytes, total, speed, state| ProgressEvent {
+        url: url.unwrap_or_default().to_string(),
+        file: file.to_string(),
+        bytes,
+        total,
+        speed,
+        state,
+    };
+    let progress = match *event {
+        Event::Queued { url } => progress(Some(url), "", 0, None, 0.0, DownloadState::Queued),
+        Event::Started { url, file, total } => progress(url, file, 0, total, 0.0, DownloadState::Started),
+        Event::Progress { url, file, bytes, total, speed } => progress(url, file, bytes, total, speed, DownloadState::Downloading),
+        Event::Finished { url, file, bytes, seconds, message } => {
+            let speed = if seconds > 0.0 { bytes as f64 / seconds } else { 0.0 };
+            let state = if message.is_empty() { DownloadState::Finished } else { DownloadState::Skipped(message.to_string()) };
+            progress(url, file, bytes, Some(bytes), speed, state)
+        }
+        Event::Failed { url, file, error } => progress(url, file, 0, None, 0.0, DownloadState::Failed(error.to_string())),
+    };
+    callback(&progress);
+}
+
+/// Something that happened to a download, as reported by JSON progress
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum Event<'a> {
+    /// A URL is waiting to be downloaded
+    Queued { url: &'a str },
+    /// A download's response has arrived and it's being saved to `file`
+    Started {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        url: Option<&'a str>,
+        file: &'a str,
+        total: Option<u64>,
+    },
+    /// How far a download has got, with its current speed in bytes per second
+    Progress {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        url: Option<&'a str>,
+        file: &'a str,
+        bytes: u64,
+        total: Option<u64>,
+        speed: f64,
+    },
+    /// A download is done, or was skipped (with the reason in `message`)
+    Finished {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        url: Option<&'a str>,
+        file: &'a str,
+        bytes: u64,
+        seconds: f64,
+        #[serde(skip_serializing_if = "str::is_empty")]
+        message: &'a str,
+    },
+    /// A download failed; `file` is missing if it failed before it had a name
+    Failed {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        url: Option<&'a str>,
+        #[serde(skip_serializing_if = "str::is_empty")]
+        file: &'a str,
+        error: &'a str,
+    },
+}
+
+impl ProgressMode {
+    /// Plain progress in containers and CI jobs, bars everywhere else
+    pub fn for_environment(container: bool) -> Self {
+        if container { ProgressMode::Plain } else { ProgressMode::Bars }
+    }
+
+    pub fn all() -> Vec<ProgressMode> {
+        vec![ProgressMode::Bars, ProgressMode::Plain, ProgressMode::Quiet, ProgressMode::Json]
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProgressMode::Bars => "bars",
+            ProgressMode::Plain => "plain",
+            ProgressMode::Quiet => "quiet",
+            ProgressMode::Json => "json",
+            ProgressMode::Events(_) => "events",
+        }
+    }
+
+    /// A container for the progress bars of a batch of downloads
+    pub fn multi_progress(self) -> MultiProgress {
+        match self {
+            ProgressMode::Bars => MultiProgress::new(),
+            ProgressMode::Plain | ProgressMode::Quiet | ProgressMode::Json | ProgressMode::Events(_) => {
+                MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+            }
+        }
+    }
+
+    /// A single progress bar, for a download that isn't part of a `MultiProgress`
+    pub fn bar(self, length: u64) -> ProgressBar {
+        match self {
+            ProgressMode::Bars => ProgressBar::new(length),
+            ProgressMode::Plain | ProgressMode::Quiet | ProgressMode::Json | ProgressMode::Events(_) => {
+                ProgressBar::with_draw_target(Some(length), ProgressDrawTarget::hidden())
+            }
+        }
+    }
+
+    /// Report an event, if we're reporting them (JSON progress, or a listener)
+    pub fn event(self, event: Event) {
+        match self {
+            ProgressMode::Json => {}
+            ProgressMode::Events(id) => return deliver(id, &event),
+            ProgressMode::Bars | ProgressMode::Plain | ProgressMode::Quiet => return,
+        }
+        let line = serde_json::to_string(&event).expect("progress events are always serializable");
+        // Nobody listening isn't a reason to stop downloading
+        let mut stdout = io::stdout().lock();
+        let _ = writeln!(stdout, "{}", line).and_then(|()| stdout.flush());
+    }
+
+    /// A URL is waiting its turn
+    pub fn queued(self, url: &str) {
+        self.event(Event::Queued { url });
+    }
+
+    /// A download has a name and (maybe) a length, and its data is on the way;
+    /// with events, its progress is reported until it's finished
+    pub fn started(self, pb: &ProgressBar, url: Option<&str>) {
+        if !matches!(self, ProgressMode::Json | ProgressMode::Events(_)) {
+            return;
+        }
+        self.event(Event::Started { url, file: &pb.prefix(), total: pb.length().filter(|&length| length > 0) });
+        // Only as long as someone else has the bar: one that's dropped without
+        // being finished is done with too
+        let bar = pb.downgrade();
+        let url = url.map(str::to_string);
+        let mut speed = SpeedEstimator::default();
+        speed.record(pb.position(), Instant::now());
+        thread::spawn(move || {
+            loop {
+                thread::sleep(JSON_INTERVAL);
+                let Some(pb) = bar.upgrade().filter(|pb| !pb.is_finished()) else { break };
+                speed.record(pb.position(), Instant::now());
+                let total = pb.length().filter(|&length| length > 0);
+                self.event(Event::Progress { url: url.as_deref(), file: &pb.prefix(), bytes: pb.position(), total, speed: speed.bytes_per_sec() });
+            }
+        });
+    }
+
+    /// Finish a download's progress bar, of `url` if it's a single URL's; in
+    /// plain mode that's when we print its line
+    pub fn finish(self, pb: &ProgressBar, url: Option<&str>, style: ProgressStyle, message: impl Into<Cow<'static, str>>) {
+        let message = message.into();
+        match self {
+            ProgressMode::Plain => {
+                let prefix = pb.prefix();
+                match (prefix.is_empty(), message.is_empty()) {
+                    (_, true) => eprintln!("{}: {} in {}", prefix, HumanBytes(pb.position()), HumanDuration(pb.elapsed())),
+                    (true, false) => eprintln!("{}", message),
+                    (false, false) => eprintln!("{}: {}", prefix, message),
+                }
+            }
+            ProgressMode::Json | ProgressMode::Events(_) => self.event(Event::Finished {
+                url,
+                file: &pb.prefix(),
+                bytes: pb.position(),
+                seconds: pb.elapsed().as_secs_f64(),
+                message: &message,
+            }),
+            ProgressMode::Bars | ProgressMode::Quiet => {}
+        }
+        // Only now is the size of a download that had none known
+        if pb.length().is_none() {
+            pb.set_length(pb.position());
+        }
+        pb.set_style(style);
+        pb.finish_with_message(message);
+    }
+
+    /// Finish a download's progress bar with an error, which is shown however quiet we're being
+    pub fn fail(self, pb: &ProgressBar, url: Option<&str>, style: ProgressStyle, message: impl Into<Cow<'static, str>>) {
+        match self {
+            ProgressMode::Bars | ProgressMode::Plain => self.finish(pb, url, style, message),
+            ProgressMode::Json | ProgressMode::Events(_) => {
+                let message = message.into();
+                self.event(Event::Failed { url, file: &pb.prefix(), error: &message });
+                pb.set_style(style);
+                pb.finish_with_message(message);
+            }
+            ProgressMode::Quiet => {
+                let message = message.into();
+                match pb.prefix().is_empty() {
+                    true => eprintln!("{}", message),
+                    false => eprintln!("{}: {}", pb.prefix(), message),
+                }
+                pb.set_style(style);
+                pb.finish_with_message(message);
+            }
+        }
+    }
+
+    /// Print a line about how a download is going, unless we're being quiet
+    pub fn status(self, pb: &ProgressBar, line: impl AsRef<str>) {
+        match self {
+            ProgressMode::Bars => pb.println(line),
+            ProgressMode::Plain => eprintln!("{}", line.as_ref()),
+            ProgressMode::Quiet | ProgressMode::Json | ProgressMode::Events(_) => {}
+        }
+    }
+
+    /// Print an error above the progress bars, or on stderr without them
+    pub fn error(self, pb: &ProgressBar, line: impl AsRef<str>) {
+        match self {
+            ProgressMode::Bars => pb.println(line),
+            ProgressMode::Plain | ProgressMode::Quiet | ProgressMode::Json | ProgressMode::Events(_) => eprintln!("{}", line.as_ref()),
+        }
+    }
+
+    /// Print a line above the progress bars (hidden bars don't print anything);
+    /// JSON progress keeps stdout for its events
+    pub fn println(self, pb: &ProgressBar, line: impl AsRef<str>) {
+        match self {
+            ProgressMode::Bars => pb.println(line),
+            ProgressMode::Plain | ProgressMode::Quiet => println!("{}", line.as_ref()),
+            ProgressMode::Json | ProgressMode::Events(_) => eprintln!("{}", line.as_ref()),
+        }
+    }
+
+    /// Like `println`, but never on stdout, for when the download itself is going there
+    pub fn eprintln(self, pb: &ProgressBar, line: impl AsRef<str>) {
+        match self {
+            ProgressMode::Bars => pb.println(line),
+            ProgressMode::Plain | ProgressMode::Quiet | ProgressMode::Json | ProgressMode::Events(_) => eprintln!("{}", line.as_ref()),
+        }
+    }
+}
+
+impl fmt::Display for ProgressMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for ProgressMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ProgressMode::all()
+            .into_iter()
+            .find(|mode| mode.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                format!(
+                    "Unknown progress mode '{}'. Available modes: {}",
+                    s,
+                    ProgressMode::all().iter().map(|m| m.as_str()).collect::<Vec<_>>().join(", ")
+                )
+            })
+    }
+}
+
+/// The placeholders indicatif understands in a progress bar template
+pub const TEMPLATE_KEYS: &[&str] = &[
+    "bar", "wide_bar", "spinner", "prefix", "msg", "wide_msg", "pos", "human_pos", "len", "human_len", "percent",
+    "percent_precise", "bytes", "total_bytes", "binary_bytes", "binary_total_bytes", "decimal_bytes", "decimal_total_bytes",
+    "bytes_per_sec", "binary_bytes_per_sec", "decimal_bytes_per_sec", "per_sec", "elapsed", "elapsed_precise", "eta",
+    "eta_precise", "duration", "duration_precise",
+];
+
+/// The bar shown while a file downloads, unless `--progress-template` says otherwise
+const DOWNLOADING_TEMPLATE: &str =
+    "{prefix:.blue} {wide_bar:.blue/white} {percent}% • {bytes:.green}/{total_bytes:.green} • {binary_bytes_per_sec:>11.red} • eta {eta:>5.cyan}  ";
+
+/// The bar shown while a file of unknown size downloads, with no total to head for
+const STREAMING_TEMPLATE: &str = "{prefix:.blue} {spinner:.blue} {bytes:.green} • {binary_bytes_per_sec:>11.red} • elapsed {elapsed:>4.cyan}  ";
+
+/// The bar shown while downloads are paused, without the speed and ETA that no longer mean anything
+const PAUSED_TEMPLATE: &str =
+    "{prefix:.blue} {wide_bar:.yellow/white} {percent}% • {bytes:.green}/{total_bytes:.green} • {msg:>11.yellow}  ";
+
+/// The bar left behind once a file's done
+const FINISHED_TEMPLATE: &str =
+    "{prefix:.blue} {wide_bar:.blue/white} {percent}% • {total_bytes:.green} • {binary_bytes_per_sec:>11.red} • elapsed {elapsed:>4.cyan}  ";
+
+/// The bar's filled part, its tip and its empty part
+const PROGRESS_CHARS: &str = "━╸━";
+
+/// Errors from a progress bar template or characters that won't work
+#[derive(Debug, thiserror::Error)]
+pub enum BarStyleError {
+    #[error("unknown placeholder {{{key}}} in progress template; available placeholders are {}", TEMPLATE_KEYS.join(", "))]
+    UnknownKey { key: String },
+
+    #[error("invalid progress template: {0}")]
+    Template(#[from] indicatif::style::TemplateError),
+
+    #[error("progress characters need at least two characters (filled and empty), got '{0}'")]
+    Chars(String),
+}
+
+/// How download progress bars look: the built-in style, or a template and
+/// characters from `--progress-template`, `--progress-chars` or the config file
+#[derive(Debug, Clone)]
+pub struct BarStyle {
+    /// A custom template, used both while downloading and once done
+    template: Option<String>,
+    chars: String,
+}
+
+impl BarStyle {
+    /// Check a custom template and characters up front, so a typo is an
+    /// error at startup rather than a blank space in every bar
+    pub fn new(template: Option<String>, chars: Option<String>) -> Result<Self, BarStyleError> {
+        if let Some(template) = &template {
+            if let Some(key) = template_keys(template).into_iter().find(|key| !TEMPLATE_KEYS.contains(&key.as_str())) {
+                return Err(BarStyleError::UnknownKey { key });
+            }
+            ProgressStyle::with_template(template)?;
+        }
+        let chars = chars.unwrap_or_else(|| PROGRESS_CHARS.to_string());
+        if chars.chars().count() < 2 {
+            return Err(BarStyleError::Chars(chars));
+        }
+        Ok(BarStyle { template, chars })
+    }
+
+    /// The style of a bar that's still going, with smoothed speed and ETA
+    pub fn downloading(&self) -> ProgressStyle {
+        speed::smoothed(self.style(DOWNLOADING_TEMPLATE))
+    }
+
+    /// The style of a bar whose length isn't known, like a response that's
+    /// decompressed as it arrives
+    pub fn streaming(&self) -> ProgressStyle {
+        speed::smoothed(self.style(STREAMING_TEMPLATE))
+    }
+
+    /// The style of a bar that's paused; a custom template can show that with `{msg}`
+    pub fn paused(&self) -> ProgressStyle {
+        self.style(PAUSED_TEMPLATE)
+    }
+
+    /// The style of a bar that's done
+    pub fn finished(&self) -> ProgressStyle {
+        self.style(FINISHED_TEMPLATE)
+    }
+
+    fn style(&self, default: &str) -> ProgressStyle {
+        // Both templates were checked in new()
+        ProgressStyle::with_template(self.template.as_deref().unwrap_or(default)).unwrap().progress_chars(&self.chars)
+    }
+}
+
+impl Default for BarStyle {
+    fn default() -> Self {
+        BarStyle { template: None, chars: PROGRESS_CHARS.to_string() }
+    }
+}
+
+/// The placeholder names in a template, skipping `{{` escapes and any `:style`
+fn template_keys(template: &str) -> Vec<String> {
+    template
+        .replace("{{", "")
+        .split('{')
+        .skip(1)
+        .filter_map(|part| part.split_once('}'))
+        .map(|(key, _)| key.split(':').next().unwrap_or_default().trim().to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_progress_hides_bars() {
+        assert_eq!(ProgressMode::for_environment(false), ProgressMode::Bars);
+        assert_eq!(ProgressMode::for_environment(true), ProgressMode::Plain);
+
+        let pb = ProgressMode::Plain.bar(10).with_prefix("file.txt");
+        assert!(pb.is_hidden());
+        assert_eq!(pb.length(), Some(10));
+        ProgressMode::Plain.finish(&pb, None, ProgressStyle::default_bar(), "done");
+        assert!(pb.is_finished());
+        assert_eq!(pb.message(), "done");
+    }
+
+    #[test]
+    fn test_quiet_progress_still_fails() {
+        let pb = ProgressMode::Quiet.bar(10).with_prefix("file.txt");
+        assert!(pb.is_hidden());
+        ProgressMode::Quiet.fail(&pb, None, ProgressStyle::default_bar(), "server returned 404 Not Found");
+        assert!(pb.is_finished());
+        assert_eq!(pb.message(), "server returned 404 Not Found");
+    }
+
+    #[test]
+    fn test_bar_style_checks_template() {
+        assert!(BarStyle::new(Some("{prefix} {bar:40.cyan/blue} {bytes}/{total_bytes} {{literal}}".to_string()), Some("#>-".to_string())).is_ok());
+        let error = BarStyle::new(Some("{prefix} {speed}".to_string()), None).unwrap_err();
+        assert!(matches!(&error, BarStyleError::UnknownKey { key } if key == "speed"));
+        assert!(error.to_string().contains("available placeholders are bar, wide_bar"));
+        assert!(matches!(BarStyle::new(None, Some("#".to_string())), Err(BarStyleError::Chars(_))));
+        BarStyle::default().downloading();
+        BarStyle::default().finished();
+    }
+
+    #[test]
+    fn test_progress_mode_from_str() {
+        assert_eq!("json".parse::<ProgressMode>().unwrap(), ProgressMode::Json);
+        assert_eq!("Plain".parse::<ProgressMode>().unwrap(), ProgressMode::Plain);
+        assert!("fancy".parse::<ProgressMode>().unwrap_err().contains("Available modes: bars, plain, quiet, json"));
+    }
+
+    #[test]
+    fn test_listener() {
+        let heard = Arc::new(Mutex::new(Vec::new()));
+        let subscription = listen({
+            let heard = Arc::clone(&heard);
+            move |event: &ProgressEvent| heard.lock().unwrap().push(event.clone())
+        });
+        let progress = ProgressMode::Events(subscription.id());
+        progress.event(Event::Started { url: Some("http://example.com/a"), file: "a", total: Some(10) });
+        progress.event(Event::Progress { url: Some("http://example.com/a"), file: "a", bytes: 4, total: Some(10), speed: 8.0 });
+        progress.event(Event::Finished { url: Some("http://example.com/a"), file: "a", bytes: 10, seconds: 0.5, message: "" });
+        progress.event(Event::Finished { url: Some("http://example.com/b"), file: "b", bytes: 0, seconds: 0.0, message: "exists, skipped" });
+        let heard: Vec<(String, u64, f64, DownloadState)> =
+            heard.lock().unwrap().iter().map(|event| (event.url.clone(), event.bytes, event.speed, event.state.clone())).collect();
+        assert_eq!(
+            heard,
+            [
+                ("http://example.com/a".to_string(), 0, 0.0, DownloadState::Started),
+                ("http://example.com/a".to_string(), 4, 8.0, DownloadState::Downloading),
+                ("http://example.com/a".to_string(), 10, 20.0, DownloadState::Finished),
+                ("http://example.com/b".to_string(), 0, 0.0, DownloadState::Skipped("exists, skipped".to_string())),
+            ]
+        );
+        let id = subscription.id();
+        drop(subscription);
+        assert!(!LISTENERS.lock().unwrap().contains_key(&id.0));
+    }
+
+    #[test]
+    fn test_dropped_bar_stops_reporting() {
+        let heard = Arc::new(Mutex::new(Vec::new()));
+        let subscription = listen({
+            let heard = Arc::clone(&heard);
+            move |event: &ProgressEvent| heard.lock().unwrap().push(event.state.clone())
+        });
+        let progress = ProgressMode::Events(subscription.id());
+        let pb = progress.bar(10).with_prefix("a");
+        progress.started(&pb, Some("http://example.com/a"));
+        drop(pb);
+        thread::sleep(JSON_INTERVAL * 3);
+        assert_eq!(*heard.lock().unwrap(), [DownloadState::Started]);
+    }
+
+    #[test]
+    fn test_json_events() {
+        let event = |event| serde_json::to_string(&event).unwrap();
+        assert_eq!(event(Event::Queued { url: "http://example.com/a" }), r#"{"event":"queued","url":"http://example.com/a"}"#);
+        assert_eq!(
+            event(Event::Started { url: None, file: "a", total: Some(10) }),
+            r#"{"event":"started","file":"a","total":10}"#
+        );
+        assert_eq!(
+            event(Event::Finished { url: None, file: "a", bytes: 10, seconds: 0.5, message: "" }),
+            r#"{"event":"finished","file":"a","bytes":10,"seconds":0.5}"#
+        );
+        assert_eq!(
+            event(Event::Failed { url: None, file: "", error: "server returned 404 Not Found" }),
+            r#"{"event":"failed","error":"server returned 404 Not Found"}"#
+        );
+    }
+}
@@ -0,0 +1,131 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// A slice of a remote file, written like `100M-200M` (binary units, end
+/// exclusive) or `1G-` for everything from there on
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ByteRange {
+    pub start: u64,
+    /// First byte after the range; `None` for the end of the file
+    pub end: Option<u64>,
+    /// The range as it was given, for naming files after it
+    text: String,
+}
+
+impl ByteRange {
+    /// How many bytes the range covers, if it has an end
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> Option<u64> {
+        self.end.map(|end| end - self.start)
+    }
+
+    /// The `Range` header asking for this range, skipping the first `offset`
+    /// bytes of it (which we already have)
+    pub fn header_value(&self, offset: u64) -> String {
+        match self.end {
+            Some(end) => format!("bytes={}-{}", self.start + offset, end - 1),
+            None => format!("bytes={}-", self.start + offset),
+        }
+    }
+
+    /// Where to save this range of `path`: `file.iso` becomes `file.iso.part-100M-200M`
+    pub fn output_path(&self, path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_os_string();
+        name.push(format!(".part-{}", self.text));
+        PathBuf::from(name)
+    }
+}
+
+impl FromStr for ByteRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid range '{}', expected START-END or START- with sizes like 100M", s);
+        let (start, end) = s.trim().split_once('-').ok_or_else(invalid)?;
+        let start = parse_size(start).ok_or_else(invalid)?;
+        let end = match end.trim() {
+            "" => None,
+            end => Some(parse_size(end).ok_or_else(invalid)?),
+        };
+        if end.is_some_and(|end| end <= start) {
+            return Err(format!("invalid range '{}': the end must come after the start", s));
+        }
+        Ok(ByteRange { start, end, text: s.trim().to_string() })
+    }
+}
+
+impl TryFrom<String> for ByteRange {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<ByteRange> for String {
+    fn from(range: ByteRange) -> Self {
+        range.text
+    }
+}
+
+impl fmt::Display for ByteRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+/// A size in bytes, optionally with a binary unit (`512`, `100k`, `1.5G`)
+fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (number, multiplier) = match s.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('k') => (&s[..s.len() - 1], 1u64 << 10),
+        Some('m') => (&s[..s.len() - 1], 1 << 20),
+        Some('g') => (&s[..s.len() - 1], 1 << 30),
+        Some('t') => (&s[..s.len() - 1], 1 << 40),
+        _ => (s, 1),
+    };
+    if let Ok(number) = number.parse::<u64>() {
+        return number.checked_mul(multiplier);
+    }
+    number
+        .parse::<f64>()
+        .ok()
+        .map(|number| number * multiplier as f64)
+        .filter(|size| size.is_finite() && *size >= 0.0 && *size < u64::MAX as f64)
+        .map(|size| size as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ranges() {
+        let range: ByteRange = "100M-200M".parse().unwrap();
+        assert_eq!((range.start, range.end), (100 << 20, Some(200 << 20)));
+        assert_eq!(range.len(), Some(100 << 20));
+        assert_eq!(range.header_value(0), format!("bytes={}-{}", 100 << 20, (200 << 20) - 1));
+        assert_eq!(range.header_value(10), format!("bytes={}-{}", (100 << 20) + 10, (200 << 20) - 1));
+
+        let range: ByteRange = "1.5k-".parse().unwrap();
+        assert_eq!((range.start, range.end, range.len()), (1536, None, None));
+        assert_eq!(range.header_value(0), "bytes=1536-");
+        assert_eq!("0-512".parse::<ByteRange>().unwrap().header_value(0), "bytes=0-511");
+
+        for invalid in ["", "100M", "-100M", "200M-100M", "5-5", "abc-def", "1x-2x"] {
+            assert!(invalid.parse::<ByteRange>().is_err(), "{} should be invalid", invalid);
+        }
+    }
+
+    #[test]
+    fn test_output_path_and_serialization() {
+        let range: ByteRange = "100M-200M".parse().unwrap();
+        assert_eq!(range.output_path(Path::new("dir/disk.img")), Path::new("dir/disk.img.part-100M-200M"));
+        assert_eq!(serde_json::to_string(&range).unwrap(), "\"100M-200M\"");
+        assert_eq!(serde_json::from_str::<ByteRange>("\"100M-200M\"").unwrap(), range);
+    }
+}
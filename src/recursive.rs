@@ -0,0 +1,345 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use percent_encoding::{AsciiSet, CONTROLS, utf8_percent_encode};
+use reqwest::blocking::Client;
+use reqwest::header;
+use url::Url;
+
+use crate::cancel::Cancellation;
+use crate::credentials::CredentialStore;
+use crate::middleware::{DownloadContext, Middleware};
+use crate::oauth::OAuthSettings;
+use crate::page;
+use crate::report::{Entry, Status};
+use crate::timeouts;
+
+/// How deep to follow links when `--level` isn't given, as wget does
+pub const DEFAULT_LEVEL: usize = 5;
+
+/// Where `--recursive` saves files unless `--output-template` says otherwise,
+/// laid out as they are on the site
+pub const MIRROR_TEMPLATE: &str = "{host}/{path}/{filename}";
+
+/// The most of a page we'll read looking for links
+const MAX_PAGE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Extensions of URLs that are worth fetching to look for links in
+const PAGE_EXTENSIONS: &[&str] = &["html", "htm", "xhtml", "shtml", "php", "asp", "aspx", "jsp", "cgi"];
+
+/// What has to be escaped in a path to be used as a link
+const LINK_PATH: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'#').add(b'%').add(b'?').add(b'<').add(b'>');
+
+/// Which links `--recursive` follows and which of what it finds are downloaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recursion {
+    /// How many links away from the URLs given to go; None for no limit
+    pub level: Option<usize>,
+    /// Only download files with these extensions (`--accept`)
+    pub accept: Vec<String>,
+    /// Never download files with these extensions (`--reject`)
+    pub reject: Vec<String>,
+    /// Also download everything the pages need to be shown, wherever it is (`--page-requisites`)
+    pub requisites: bool,
+}
+
+impl Recursion {
+    /// Whether what `url` points at should be downloaded, going by its
+    /// extension (or its whole name)
+    pub fn wants(&self, url: &Url) -> bool {
+        let name = url.path_segments().and_then(|mut segments| segments.next_back()).unwrap_or_default().to_ascii_lowercase();
+        let ends_with = |suffix: &String| {
+            let suffix = suffix.trim_start_matches('.').to_ascii_lowercase();
+            name == suffix || name.ends_with(&format!(".{}", suffix))
+        };
+        (self.accept.is_empty() || self.accept.iter().any(ends_with)) && !self.reject.iter().any(ends_with)
+    }
+
+    /// Whether a link `depth` links away from where we started is worth following further
+    fn follows(&self, depth: usize) -> bool {
+        self.level.is_none_or(|level| depth < level)
+    }
+}
+
+/// Why a URL is to be downloaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Found {
+    /// It's linked to, or one of the URLs given
+    Link,
+    /// A page or stylesheet needs it to be shown
+    Requisite,
+}
+
+/// Follows links from the URLs given, without leaving their hosts, and
+/// works out everything there is to download (`--recursive`, and
+/// `--page-requisites`). Only pages and stylesheets are fetched here; the
+/// files they link to, and the pages themselves, are left for the download
+/// that follows.
+pub struct Crawler<'a> {
+    pub client: &'a Client,
+    pub middleware: &'a Middleware,
+    pub credentials: Option<&'a CredentialStore>,
+    pub oauth: &'a OAuthSettings,
+    pub header_timeout: Option<Duration>,
+    pub recursion: &'a Recursion,
+    pub cancellation: &'a Cancellation,
+}
+
+impl Crawler<'_> {
+    /// Every URL found from `urls`, them included, that `--accept` and
+    /// `--reject` let through, in the order they were found
+    pub fn crawl(&self, urls: Vec<String>) -> Vec<String> {
+        let mut queue: VecDeque<(Url, usize, Found)> = VecDeque::new();
+        let mut seen = HashSet::new();
+        let mut hosts = HashSet::new();
+        let mut found = Vec::new();
+        for url in urls {
+            match Url::parse(&url) {
+                Ok(parsed_url) => {
+                    hosts.extend(parsed_url.host_str().map(str::to_string));
+                    seen.insert(parsed_url.to_string());
+                    queue.push_back((parsed_url, 0, Found::Link));
+                }
+                // The download will say what's wrong with it
+                Err(_) => found.push(url),
+            }
+        }
+
+        while let Some((url, depth, why)) = queue.pop_front() {
+            if self.cancellation.reason().is_some() {
+                break;
+            }
+            // Pages at the last level are still fetched for what they need to be shown
+            let follows = why == Found::Link && self.recursion.follows(depth);
+            if (follows || self.recursion.requisites) && looks_like_document(&url) {
+                match self.links(&url, depth == 0) {
+                    Ok(Links { pages, requisites, redirected_host }) => {
+                        // A site that moved to www. is still the same site
+                        hosts.extend(redirected_host);
+                        let pages = pages.into_iter().filter(|_| follows).map(|link| (link, Found::Link));
+                        let requisites = requisites.into_iter().filter_map(|link| match self.recursion.requisites {
+                            true => Some((link, Found::Requisite)),
+                            false => follows.then_some((link, Found::Link)),
+                        });
+                        for (mut link, why) in pages.chain(requisites) {
+                            link.set_fragment(None);
+                            // Pages can use images and fonts from anywhere, but links only go to the same site
+                            let allowed = why == Found::Requisite || link.host_str().is_some_and(|host| hosts.contains(host));
+                            if allowed && seen.insert(link.to_string()) {
+                                queue.push_back((link, depth + 1, why));
+                            }
+                        }
+                    }
+                    Err(e) => warn!("Not following links from {}: {}", url, e),
+                }
+            }
+            if self.recursion.wants(&url) {
+                found.push(url.to_string());
+            } else {
+                debug!("Not downloading {}, rejected by --accept/--reject", url);
+            }
+        }
+        info!("Found {} URLs to download", found.len());
+        found
+    }
+
+    /// Fetch `url` and find its links, if it's HTML or CSS; with where it
+    /// redirected to, if it's `first` and it did
+    fn links(&self, url: &Url, first: bool) -> Result<Links, String> {
+        debug!("Looking for links in {}", url);
+        let mut request = self.middleware.get(self.client, url.as_str());
+        if let Some(store) = self.credentials {
+            request = store.authorize(request, url, self.client, self.oauth);
+        }
+        let request = request.build().map_err(|e| e.to_string())?;
+        let response = timeouts::execute(self.client, request, self.header_timeout).map_err(|e| e.to_string())?;
+        self.middleware.intercept(&response, &DownloadContext { method: &reqwest::Method::GET, url: url.as_str() })?;
+        if !response.status().is_success() {
+            return Err(format!("server returned {}", response.status()));
+        }
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_ascii_lowercase)
+            .unwrap_or_default();
+        let (is_html, is_css) = (content_type.contains("html"), content_type.contains("text/css"));
+        if !is_html && !is_css {
+            return Ok(Links::default());
+        }
+        let final_url = response.url().clone();
+        let redirected_host = final_url.host_str().filter(|host| first && Some(*host) != url.host_str()).map(str::to_string);
+        let mut body = Vec::new();
+        response.take(MAX_PAGE_SIZE).read_to_end(&mut body).map_err(|e| e.to_string())?;
+        let body = String::from_utf8_lossy(&body);
+        Ok(match is_html {
+            true => Links { pages: page::links(&body, &final_url), requisites: page::requisites(&body, &final_url), redirected_host },
+            false => Links { pages: Vec::new(), requisites: page::stylesheet_requisites(&body, &final_url), redirected_host },
+        })
+    }
+}
+
+/// What a page or stylesheet links to
+#[derive(Default)]
+struct Links {
+    pages: Vec<Url>,
+    requisites: Vec<Url>,
+    redirected_host: Option<String>,
+}
+
+/// Point the links in the pages and stylesheets that were downloaded at the
+/// copies of what they link to, so they can be browsed offline
+/// (`--convert-links`); links to anything not downloaded are made absolute
+pub fn convert_links(entries: &[Entry]) {
+    let saved: Vec<(&Entry, PathBuf)> = entries
+        .iter()
+        .filter(|entry| matches!(entry.status, Status::Downloaded | Status::Skipped))
+        .filter_map(|entry| Some((entry, std::path::absolute(entry.file.as_ref()?).ok()?)))
+        .collect();
+    let mut local: HashMap<String, &Path> = HashMap::new();
+    for (entry, file) in &saved {
+        local.insert(entry.url.clone(), file);
+        if let Some(final_url) = &entry.final_url {
+            local.insert(final_url.clone(), file);
+        }
+    }
+
+    for (entry, file) in &saved {
+        let extension = file.extension().and_then(|extension| extension.to_str()).unwrap_or_default().to_ascii_lowercase();
+        let is_css = extension == "css";
+        if !is_css && !matches!(extension.as_str(), "html" | "htm" | "xhtml") {
+            continue;
+        }
+        let Ok(base) = Url::parse(entry.final_url.as_ref().unwrap_or(&entry.url)) else { continue };
+        let contents = match fs::read(file) {
+            Ok(contents) => String::from_utf8_lossy(&contents).into_owned(),
+            Err(e) => {
+                warn!("Could not convert links in {}: {}", file.display(), e);
+                continue;
+            }
+        };
+        let mut localize = |url: &Url| {
+            let mut without_fragment = url.clone();
+            without_fragment.set_fragment(None);
+            let target = local.get(without_fragment.as_str())?;
+            let mut link = relative_link(file, target);
+            if let Some(fragment) = url.fragment() {
+                link = format!("{}#{}", link, fragment);
+            }
+            Some(link)
+        };
+        let converted = match is_css {
+            true => page::convert_stylesheet_links(&contents, &base, &mut localize),
+            false => page::convert_links(&contents, &base, &mut localize),
+        };
+        if converted != contents {
+            debug!("Converted links in {}", file.display());
+            if let Err(e) = fs::write(file, converted) {
+                warn!("Could not convert links in {}: {}", file.display(), e);
+            }
+        }
+    }
+}
+
+/// A link from the file `from` to the file `to`, both absolute
+fn relative_link(from: &Path, to: &Path) -> String {
+    let from: Vec<Component> = from.parent().map(|parent| parent.components().collect()).unwrap_or_default();
+    let to: Vec<Component> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let mut parts: Vec<String> = vec!["..".to_string(); from.len() - common];
+    parts.extend(to[common..].iter().map(|part| utf8_percent_encode(&part.as_os_str().to_string_lossy(), LINK_PATH).to_string()));
+    parts.join("/")
+}
+
+/// Whether `url` might be a page or stylesheet with links in it, rather than a file
+fn looks_like_document(url: &Url) -> bool {
+    let name = url.path_segments().and_then(|mut segments| segments.next_back()).unwrap_or_default();
+    match name.rsplit_once('.') {
+        Some((_, extension)) => {
+            let extension = extension.to_ascii_lowercase();
+            extension == "css" || PAGE_EXTENSIONS.contains(&extension.as_str())
+        }
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_wants() {
+        let recursion = Recursion { level: None, accept: vec!["iso".to_string(), ".ZIP".to_string()], reject: vec!["old.iso".to_string()], requisites: false };
+        let wants = |url: &str| recursion.wants(&Url::parse(url).unwrap());
+        assert!(wants("https://example.com/os.iso"));
+        assert!(wants("https://example.com/src.zip?download=1"));
+        assert!(!wants("https://example.com/os.old.iso"));
+        assert!(!wants("https://example.com/old.iso"));
+        assert!(!wants("https://example.com/"));
+        assert!(Recursion { level: None, accept: Vec::new(), reject: Vec::new(), requisites: false }.wants(&Url::parse("https://example.com/").unwrap()));
+    }
+
+    #[test]
+    fn test_crawl() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = [0u8; 1024];
+                let read = std::io::Read::read(&mut stream, &mut request).unwrap();
+                let path = String::from_utf8_lossy(&request[..read]).split(' ').nth(1).unwrap_or_default().to_string();
+                let (content_type, body) = match path.as_str() {
+                    "/" => ("text/html", r#"<a href="docs/">Docs</a> <a href="a.iso">A</a> <a href="https://elsewhere.example/b.iso">B</a>"#),
+                    "/docs/" => ("text/html", r#"<a href="../">Up</a> <a href="deep/">Deeper</a> <a href="c.iso#top">C</a>"#),
+                    "/page.html" => ("text/html", r#"<a href="a.iso">A</a> <link rel="stylesheet" href="style.css"> <img src="https://cdn.example/logo.png">"#),
+                    "/style.css" => ("text/css", "@font-face { src: url(font.woff2) }"),
+                    _ => ("text/html", "<a href=\"never.iso\">Too deep</a>"),
+                };
+                let reply = format!("HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", content_type, body.len(), body);
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+        });
+
+        let client = Client::builder().pool_max_idle_per_host(0).build().unwrap();
+        let recursion = Recursion { level: Some(2), accept: Vec::new(), reject: Vec::new(), requisites: false };
+        let crawler = Crawler {
+            client: &client,
+            middleware: &Middleware::default(),
+            credentials: None,
+            oauth: &OAuthSettings::default(),
+            header_timeout: None,
+            recursion: &recursion,
+            cancellation: &Cancellation::default(),
+        };
+        let root = format!("http://{}/", address);
+        let found = crawler.crawl(vec![root.clone()]);
+        let expected: Vec<String> = ["", "docs/", "a.iso", "docs/deep/", "docs/c.iso"].iter().map(|path| format!("{}{}", root, path)).collect();
+        assert_eq!(found, expected);
+
+        let recursion = Recursion { level: Some(2), accept: vec!["iso".to_string()], reject: Vec::new(), requisites: false };
+        let found = Crawler { recursion: &recursion, ..crawler }.crawl(vec![root.clone()]);
+        assert_eq!(found, [format!("{}a.iso", root), format!("{}docs/c.iso", root)]);
+
+        // --page-requisites on its own: the page, and what it needs from anywhere, but not what it links to
+        let recursion = Recursion { level: Some(0), accept: Vec::new(), reject: Vec::new(), requisites: true };
+        let found = Crawler { recursion: &recursion, ..crawler }.crawl(vec![format!("{}page.html", root)]);
+        let expected = [format!("{}page.html", root), format!("{}style.css", root), "https://cdn.example/logo.png".to_string(), format!("{}font.woff2", root)];
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn test_relative_link() {
+        let link = |from: &str, to: &str| relative_link(Path::new(from), Path::new(to));
+        assert_eq!(link("/m/example.com/index.html", "/m/example.com/docs/a b.html"), "docs/a%20b.html");
+        assert_eq!(link("/m/example.com/docs/index.html", "/m/example.com/style.css"), "../style.css");
+        assert_eq!(link("/m/example.com/index.html", "/m/cdn.example/logo.png"), "../cdn.example/logo.png");
+    }
+}
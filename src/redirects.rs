@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use log::info;
+use reqwest::redirect::{Action, Attempt, Policy};
+use serde::Serialize;
+
+/// How many redirects to follow before giving up, as reqwest does by default
+const MAX_REDIRECTS: usize = 10;
+
+/// One hop of a redirect chain: what the server answered, and where it sent us
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Redirect {
+    pub status: u16,
+    /// The `Location` it gave, made absolute
+    pub location: String,
+}
+
+/// Redirects a client has followed so far, by the URL first asked for, until
+/// the report picks them up. The redirect policy runs inside the HTTP client,
+/// away from the download that asked, so this is the only way back.
+#[derive(Debug, Clone, Default)]
+pub struct Redirects(Arc<Mutex<HashMap<String, Vec<Redirect>>>>);
+
+impl Redirects {
+    /// Follow up to 10 redirects, remembering each one here and logging it with `-v`
+    pub fn policy(&self) -> Policy {
+        let chains = self.clone();
+        Policy::custom(move |attempt| chains.follow(attempt))
+    }
+
+    fn follow(&self, attempt: Attempt) -> Action {
+        let previous = attempt.previous();
+        if previous.len() > MAX_REDIRECTS {
+            return attempt.error("too many redirects");
+        }
+        let (Some(first), Some(from)) = (previous.first(), previous.last()) else {
+            return attempt.follow();
+        };
+        info!("{} redirected ({}) to {}", from, attempt.status(), attempt.url());
+        let hop = Redirect { status: attempt.status().as_u16(), location: attempt.url().to_string() };
+        let mut chains = self.0.lock().unwrap();
+        let chain = chains.entry(first.to_string()).or_default();
+        // A first hop means the request was sent again, so the old chain is stale
+        if previous.len() == 1 {
+            chain.clear();
+        }
+        chain.push(hop);
+        drop(chains);
+        attempt.follow()
+    }
+
+    /// The redirects followed for `url`, forgetting them
+    pub fn take(&self, url: &str) -> Vec<Redirect> {
+        let key = url::Url::parse(url).map_or_else(|_| url.to_string(), String::from);
+        self.0.lock().unwrap().remove(&key).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_redirect_chain() {
+        // /short sends us to /long, which sends us to /file
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let replies = [
+                "HTTP/1.1 301 Moved Permanently\r\nLocation: /long\r\nContent-Length: 0\r\n\r\n".to_string(),
+                format!("HTTP/1.1 302 Found\r\nLocation: http://{}/file\r\nContent-Length: 0\r\n\r\n", address),
+                "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok".to_string(),
+            ];
+            for reply in replies {
+                let (mut stream, _) = listener.accept().unwrap();
+                let _ = stream.read(&mut [0u8; 1024]);
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+        });
+
+        let redirects = Redirects::default();
+        let client = reqwest::blocking::Client::builder().redirect(redirects.policy()).pool_max_idle_per_host(0).build().unwrap();
+        let url = format!("http://{}/short", address);
+        let response = client.get(&url).send().unwrap();
+        assert_eq!(response.url().path(), "/file");
+        assert_eq!(
+            redirects.take(&url),
+            [
+                Redirect { status: 301, location: format!("http://{}/long", address) },
+                Redirect { status: 302, location: format!("http://{}/file", address) },
+            ]
+        );
+        assert!(redirects.take(&url).is_empty());
+        // Another client's redirects are its own
+        assert!(Redirects::default().take(&url).is_empty());
+    }
+}
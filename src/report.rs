@@ -0,0 +1,641 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::cancel::{CancelReason, Cancellation};
+use crate::checksum::Checksum;
+use crate::clock::TimeZone;
+use crate::exec_hook::{Download, ExecHook};
+use crate::exit_code::{ExitCode, Failure};
+#[cfg(feature = "state-db")]
+use crate::partial::{self, PartFile, PART_SUFFIX};
+use crate::redirects::{Redirect, Redirects};
+use crate::retry_history::RetryHistory;
+use crate::spider::Checked;
+use crate::session::{Session, SessionError};
+#[cfg(feature = "state-db")]
+use crate::state_db::{StateDb, StateError};
+use crate::timing::{Timing, WriteOut};
+use crate::webhook::Webhook;
+
+/// What became of a URL
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Downloaded,
+    /// Not downloaded, because there was no need (see `reason`)
+    Skipped,
+    Failed,
+    /// Found to be there, but not downloaded (`--spider`)
+    Checked,
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Downloaded => "downloaded",
+            Status::Skipped => "skipped",
+            Status::Failed => "failed",
+            Status::Checked => "checked",
+        }
+    }
+}
+
+/// A request sent again, and why
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Retry {
+    /// 1 for the first retry, 2 for the second...
+    pub attempt: u32,
+    /// What the server said, e.g. `429 Too Many Requests`
+    pub reason: String,
+    /// Seconds waited before retrying
+    pub backoff: f64,
+}
+
+/// One URL's line in the report
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Entry {
+    pub url: String,
+    pub status: Status,
+    /// Where it was saved (or would have been)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+    pub bytes: u64,
+    pub seconds: f64,
+    /// Average speed, in bytes per second
+    pub speed: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_status: Option<u16>,
+    /// Digests computed along the way, by algorithm
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub checksums: BTreeMap<String, String>,
+    /// Where the time went before the response arrived, when we know
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timing: Option<Timing>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub retries: Vec<Retry>,
+    /// Every redirect followed on the way, in order
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub redirects: Vec<Redirect>,
+    /// Where the redirects ended up
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub final_url: Option<String>,
+    /// What the server says the file is, for checked URLs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// Why a skipped URL was skipped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// What kind of failure it was, for the exit code
+    #[serde(skip)]
+    pub failure: Option<ExitCode>,
+}
+
+impl Entry {
+    pub fn new(url: &str, status: Status, file: Option<&Path>) -> Self {
+        Entry {
+            url: url.to_string(),
+            status,
+            file: file.map(Path::to_path_buf),
+            bytes: 0,
+            seconds: 0.0,
+            speed: 0.0,
+            http_status: None,
+            checksums: BTreeMap::new(),
+            timing: None,
+            retries: Vec::new(),
+            redirects: Vec::new(),
+            final_url: None,
+            content_type: None,
+            reason: None,
+            error: None,
+            failure: None,
+        }
+    }
+}
+
+/// The whole run, as written by `--report-json`
+#[derive(Debug, Serialize)]
+struct Summary<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    started: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finished: Option<String>,
+    downloaded: usize,
+    skipped: usize,
+    failed: usize,
+    /// URLs only checked, with `--spider`
+    #[serde(skip_serializing_if = "is_zero")]
+    checked: usize,
+    /// What was downloaded, not counting what checked URLs would come to
+    bytes: u64,
+    downloads: &'a [Entry],
+}
+
+fn is_zero(count: &usize) -> bool {
+    *count == 0
+}
+
+/// The columns of `--report-csv`, in order
+const CSV_COLUMNS: &[&str] = &["url", "file", "status", "bytes", "seconds", "sha256"];
+
+/// Collects what happened to every URL in a run, for a report at the end
+#[derive(Debug)]
+pub struct Report {
+    /// Where to write the JSON summary; `-` for stdout
+    json: Option<PathBuf>,
+    /// A CSV file to add a row to for each URL
+    csv: Option<PathBuf>,
+    /// A line to print for each URL as it's done (`--write-out`)
+    write_out: Option<WriteOut>,
+    /// What the summary's times are shown in
+    timezone: TimeZone,
+    started: SystemTime,
+    /// Timings of URLs that have had a response, until they're recorded
+    timings: Mutex<HashMap<String, Timing>>,
+    /// Retries of URLs that aren't done yet
+    retries: Mutex<HashMap<String, Vec<Retry>>>,
+    /// Where the client records the redirects it follows, until they're recorded
+    redirects: Redirects,
+    entries: Mutex<Vec<Entry>>,
+    /// The report a batch's entries are passed on to, to be saved along with the rest
+    parent: Option<Arc<Report>>,
+    /// What to cancel at the first failure, with `--fail-fast`
+    fail_fast: Option<Cancellation>,
+    /// Where retries are remembered between runs
+    history: Option<RetryHistory>,
+    /// Leave out anything that changes from run to run, and sort by URL
+    deterministic: bool,
+    /// A command to run for each URL as it's done (`--exec-after`)
+    exec_after: Option<ExecHook>,
+    /// Where to POST each URL's entry, and the summary (`--webhook`)
+    webhook: Option<Webhook>,
+    /// Where every download is remembered between runs
+    #[cfg(feature = "state-db")]
+    state: Option<Arc<StateDb>>,
+    /// Where to say what's left if the run is interrupted, and the URLs it was started with
+    session: Option<(PathBuf, Vec<String>)>,
+}
+
+impl Report {
+    pub fn new(json: Option<PathBuf>, csv: Option<PathBuf>, write_out: Option<WriteOut>, timezone: TimeZone) -> Self {
+        Report {
+            json,
+            csv,
+            write_out,
+            timezone,
+            started: SystemTime::now(),
+            timings: Mutex::default(),
+            retries: Mutex::default(),
+            redirects: Redirects::default(),
+            entries: Mutex::new(Vec::new()),
+            parent: None,
+            fail_fast: None,
+            history: None,
+            deterministic: false,
+            exec_after: None,
+            webhook: None,
+            #[cfg(feature = "state-db")]
+            state: None,
+            session: None,
+        }
+    }
+
+    /// A report for one batch of `report`'s URLs, so the exit code only goes
+    /// by how they fared; each entry is passed on to `report` as well, which
+    /// does the printing, hooks and saving
+    pub fn batch(report: &Arc<Report>) -> Self {
+        Report { fail_fast: report.fail_fast.clone(), parent: Some(Arc::clone(report)), ..Report::new(None, None, None, report.timezone) }
+    }
+
+    /// Cancel everything else as soon as a URL fails
+    pub fn fail_fast(mut self, cancellation: &Cancellation) -> Self {
+        self.fail_fast = Some(cancellation.clone());
+        self
+    }
+
+    /// Remember retries in `history` too, for `download stats`
+    pub fn history(mut self, history: RetryHistory) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    /// Make the saved report the same every time the same URLs give the
+    /// same results: sorted by URL, with no times or speeds
+    pub fn deterministic(mut self) -> Self {
+        self.deterministic = true;
+        self
+    }
+
+    /// Run `hook` for each URL as soon as it's done, whatever became of it
+    pub fn exec_after(mut self, hook: ExecHook) -> Self {
+        self.exec_after = Some(hook);
+        self
+    }
+
+    /// Send each URL's entry to `webhook` as soon as it's done, and the
+    /// summary when the report's saved, which waits for them to be sent
+    pub fn webhook(mut self, webhook: Webhook) -> Self {
+        self.webhook = Some(webhook);
+        self
+    }
+
+    /// Write what's left to `path` with `save_session`, for `--continue-session`
+    pub fn session(mut self, path: PathBuf, urls: Vec<String>) -> Self {
+        self.session = Some((path, urls));
+        self
+    }
+
+    /// Remember each URL's download in `state`, as it's done
+    #[cfg(feature = "state-db")]
+    pub fn state(mut self, state: Arc<StateDb>) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    /// Where the client downloading this report's URLs should record the
+    /// redirects it follows, for their entries
+    pub fn redirects(&self) -> &Redirects {
+        &self.redirects
+    }
+
+    /// Note that `url` had to be sent again, for its entry once it's done
+    pub fn retried(&self, url: &str, retry: Retry) {
+        if let Some(history) = &self.parent.as_deref().unwrap_or(self).history
+            && let Err(e) = history.append(url, &retry)
+        {
+            warn!("Could not record retry of {}: {}", url, e);
+        }
+        self.retries.lock().unwrap().entry(url.to_string()).or_default().push(retry);
+    }
+
+    /// Note how long `url` took to get a response, for its entry once it's done
+    pub fn timed(&self, url: &str, timing: Timing) {
+        self.timings.lock().unwrap().insert(url.to_string(), timing);
+    }
+
+    pub fn downloaded(&self, url: &str, file: &Path, bytes: u64, elapsed: Duration, http_status: Option<u16>, checksums: &[Checksum]) {
+        let seconds = elapsed.as_secs_f64();
+        self.record(Entry {
+            bytes,
+            seconds,
+            speed: if seconds > 0.0 { bytes as f64 / seconds } else { 0.0 },
+            http_status,
+            checksums: checksums.iter().map(|checksum| (checksum.algorithm.to_string(), checksum.digest.clone())).collect(),
+            ..Entry::new(url, Status::Downloaded, Some(file))
+        });
+    }
+
+    /// Note that `url` is there to be downloaded, without having downloaded it
+    pub fn checked(&self, checked: &Checked) {
+        self.record(Entry {
+            bytes: checked.size.unwrap_or_default(),
+            http_status: Some(checked.status.as_u16()),
+            content_type: checked.content_type.clone(),
+            ..Entry::new(&checked.url, Status::Checked, checked.file.as_deref())
+        });
+    }
+
+    pub fn skipped(&self, url: &str, file: Option<&Path>, reason: &str) {
+        self.record(Entry { reason: Some(reason.to_string()), ..Entry::new(url, Status::Skipped, file) });
+    }
+
+    /// Note that `url` failed; failures that aren't of any particular kind
+    /// take theirs from the HTTP status, if there was one
+    pub fn failed(&self, url: &str, file: Option<&Path>, http_status: Option<u16>, error: impl Into<Failure>) {
+        let Failure { code, message } = error.into();
+        let code = match code {
+            ExitCode::Error => http_status.and_then(ExitCode::for_http).unwrap_or(code),
+            code => code,
+        };
+        self.record(Entry { http_status, error: Some(message), failure: Some(code), ..Entry::new(url, Status::Failed, file) });
+        if let Some(cancellation) = self.fail_fast.as_ref().filter(|cancellation| cancellation.reason().is_none()) {
+            warn!("{} failed, stopping the other downloads", url);
+            cancellation.cancel(CancelReason::Failed);
+        }
+    }
+
+    /// What the run should exit with, from how the batch's URLs fared; with
+    /// `--fail-fast`, the failure that stopped the others is the one that counts
+    pub fn exit_code(&self, strict: bool) -> ExitCode {
+        let entries = self.entries.lock().unwrap();
+        let mut failures: Vec<ExitCode> = entries.iter().filter_map(|entry| entry.failure).collect();
+        // Skipping a URL isn't getting it, so it can't make a failed batch a partial one
+        let succeeded = entries.iter().filter(|entry| matches!(entry.status, Status::Downloaded | Status::Checked)).count();
+        if self.fail_fast.is_some() {
+            failures.truncate(1);
+        }
+        ExitCode::for_batch(&failures, succeeded, strict)
+    }
+
+    /// The URLs that couldn't be connected to, if that's why every URL failed
+    pub fn unreachable(&self) -> Option<Vec<String>> {
+        let entries = self.entries.lock().unwrap();
+        if entries.is_empty() || entries.iter().any(|entry| entry.failure.is_none()) {
+            return None;
+        }
+        // With --fail-fast, the rest were only stopped because of the first
+        let failures = &entries[..if self.fail_fast.is_some() { 1 } else { entries.len() }];
+        failures.iter().all(|entry| entry.failure == Some(ExitCode::Network)).then(|| failures.iter().map(|entry| entry.url.clone()).collect())
+    }
+
+    fn record(&self, mut entry: Entry) {
+        debug!("{} {:?}", entry.url, entry.status);
+        entry.timing = self.timings.lock().unwrap().remove(&entry.url);
+        entry.retries = self.retries.lock().unwrap().remove(&entry.url).unwrap_or_default();
+        entry.redirects = self.redirects.take(&entry.url);
+        entry.final_url = entry.redirects.last().map(|redirect| redirect.location.clone());
+        match &self.parent {
+            Some(parent) => {
+                self.entries.lock().unwrap().push(entry.clone());
+                parent.publish(entry);
+            }
+            None => self.publish(entry),
+        }
+    }
+
+    /// Print, hook and remember a finished entry
+    fn publish(&self, entry: Entry) {
+        if let Some(write_out) = &self.write_out {
+            let mut stdout = io::stdout().lock();
+            let _ = stdout.write_all(write_out.render(&entry).as_bytes()).and_then(|()| stdout.flush());
+        }
+        // The file's done with by now, so a failing command can't change how it went
+        if let Some(hook) = &self.exec_after
+            && let Err(e) = hook.run(&Download::from(&entry))
+        {
+            error!("{}: {}", entry.url, e);
+        }
+        if let Some(webhook) = &self.webhook {
+            webhook.entry(&entry);
+        }
+        #[cfg(feature = "state-db")]
+        if let Some(state) = &self.state
+            && let Err(e) = remember(state, &entry)
+        {
+            warn!("Could not record {}: {}", entry.url, e);
+        }
+        self.entries.lock().unwrap().push(entry);
+    }
+
+    /// What's been recorded so far
+    pub fn entries(&self) -> Vec<Entry> {
+        self.entries.lock().unwrap().clone()
+    }
+
+    /// Write the report wherever we were asked to, once everything's done;
+    /// a report that can't be written is an error, but not a reason to fail the downloads
+    pub fn save(&self) {
+        if let Some(parent) = &self.parent {
+            return parent.save();
+        }
+        if let Some(path) = &self.json
+            && let Err(e) = self.write_json(path)
+        {
+            error!("Could not write report to {}: {}", path.display(), e);
+        }
+        if let Some(path) = &self.csv
+            && let Err(e) = self.write_csv(path)
+        {
+            error!("Could not write report to {}: {}", path.display(), e);
+        }
+        if let Some(webhook) = &self.webhook {
+            webhook.summary(&self.summary(&self.saved_entries()));
+            webhook.finish();
+        }
+    }
+
+    /// Write the URLs that didn't finish to the session file, if there are
+    /// any, returning where it was written
+    pub fn save_session(&self) -> Result<Option<&Path>, SessionError> {
+        if let Some(parent) = &self.parent {
+            return parent.save_session();
+        }
+        let Some((path, urls)) = &self.session else { return Ok(None) };
+        let session = Session::from_entries(urls.clone(), &self.entries());
+        if session.unfinished.is_empty() {
+            return Ok(None);
+        }
+        session.save(path)?;
+        Ok(Some(path))
+    }
+
+    /// The entries as they're saved
+    fn saved_entries(&self) -> Vec<Entry> {
+        let mut entries = self.entries.lock().unwrap().clone();
+        if self.deterministic {
+            entries.sort_by(|a, b| a.url.cmp(&b.url));
+            for entry in &mut entries {
+                entry.seconds = 0.0;
+                entry.speed = 0.0;
+                entry.timing = None;
+            }
+        }
+        entries
+    }
+
+    /// The whole run, from its entries
+    fn summary<'a>(&self, entries: &'a [Entry]) -> Summary<'a> {
+        let count = |status| entries.iter().filter(|entry| entry.status == status).count();
+        Summary {
+            started: (!self.deterministic).then(|| self.timezone.format(self.started)),
+            finished: (!self.deterministic).then(|| self.timezone.format(SystemTime::now())),
+            downloaded: count(Status::Downloaded),
+            skipped: count(Status::Skipped),
+            failed: count(Status::Failed),
+            checked: count(Status::Checked),
+            bytes: entries.iter().filter(|entry| entry.status != Status::Checked).map(|entry| entry.bytes).sum(),
+            downloads: entries,
+        }
+    }
+
+    fn write_json(&self, path: &Path) -> io::Result<()> {
+        let entries = self.saved_entries();
+        let summary = self.summary(&entries);
+        let mut out: Box<dyn Write> = match path == Path::new("-") {
+            true => Box::new(io::stdout().lock()),
+            false => Box::new(BufWriter::new(File::create(path)?)),
+        };
+        serde_json::to_writer_pretty(&mut out, &summary)?;
+        writeln!(out)?;
+        out.flush()
+    }
+
+    /// Add this run's rows to the CSV file at `path`, so a script run again
+    /// and again builds up a history; the header's only written to a new file
+    fn write_csv(&self, path: &Path) -> io::Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let new = file.metadata()?.len() == 0;
+        let mut out = BufWriter::new(file);
+        if new {
+            writeln!(out, "{}", CSV_COLUMNS.join(","))?;
+        }
+        for entry in self.saved_entries() {
+            let file = entry.file.as_deref().map(|file| file.to_string_lossy()).unwrap_or_default();
+            let sha256 = entry.checksums.get("sha256").map(String::as_str).unwrap_or_default();
+            let row = [csv_field(&entry.url), csv_field(&file), entry.status.as_str().to_string(), entry.bytes.to_string(), format!("{:.3}", entry.seconds), sha256.to_string()];
+            writeln!(out, "{}", row.join(","))?;
+        }
+        out.flush()
+    }
+}
+
+/// Quote a CSV field if it needs it
+fn csv_field(value: &str) -> String {
+    match value.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_string(),
+    }
+}
+
+/// Note in `state` what became of a download; a failed one's offset is
+/// whatever its `.part` file has, to resume from
+#[cfg(feature = "state-db")]
+fn remember(state: &StateDb, entry: &Entry) -> Result<(), StateError> {
+    match (entry.status, &entry.file) {
+        (Status::Downloaded, Some(file)) => state.finished(&entry.url, file),
+        (Status::Failed, file) => {
+            let part = file.as_deref().and_then(|file| PartFile::open(&partial::with_suffix(file, PART_SUFFIX)).ok());
+            state.failed(&entry.url, file.as_deref(), part.map_or(0, |part| part.metadata.offset), entry.error.as_deref().unwrap_or_default())
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_report() {
+        let path = std::env::temp_dir().join(format!("rustdl-report-{}.json", std::process::id()));
+        let report = Report::new(Some(path.clone()), None, None, TimeZone::Utc);
+        let md5: Checksum = "md5:5d41402abc4b2a76b9719d911017c592".parse().unwrap();
+        report.timed("http://example.com/a", Timing { dns: 0.5, connect: 0.25, tls: 0.0, ttfb: 1.0 });
+        report.retried("http://example.com/a", Retry { attempt: 1, reason: "429 Too Many Requests".to_string(), backoff: 2.0 });
+        report.downloaded("http://example.com/a", Path::new("a"), 1000, Duration::from_secs(2), Some(200), &[md5]);
+        report.skipped("http://example.com/b", Some(Path::new("b")), "not modified");
+        report.failed("http://example.com/c", None, Some(404), "server returned 404 Not Found");
+        report.save();
+        assert_eq!(report.exit_code(false), ExitCode::Partial);
+        assert_eq!(report.exit_code(true), ExitCode::ClientError);
+
+        let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!((json["downloaded"].as_u64(), json["skipped"].as_u64(), json["failed"].as_u64()), (Some(1), Some(1), Some(1)));
+        assert_eq!(json["bytes"], 1000);
+        assert!(json["started"].as_str().unwrap().ends_with('Z'));
+        let downloads = json["downloads"].as_array().unwrap();
+        assert_eq!(downloads[0]["speed"], 500.0);
+        assert_eq!(downloads[0]["file"], "a");
+        assert_eq!(downloads[0]["checksums"]["md5"], "5d41402abc4b2a76b9719d911017c592");
+        assert!(downloads[1].get("checksums").is_none());
+        assert_eq!(downloads[0]["timing"]["connect"], 0.25);
+        assert!(downloads[1].get("timing").is_none());
+        assert_eq!(downloads[0]["retries"][0]["reason"], "429 Too Many Requests");
+        assert!(downloads[1].get("retries").is_none());
+        assert_eq!(downloads[1]["reason"], "not modified");
+        assert_eq!(downloads[2]["status"], "failed");
+        assert_eq!(downloads[2]["http_status"], 404);
+        assert!(downloads[2].get("file").is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_deterministic_report() {
+        let path = std::env::temp_dir().join(format!("rustdl-report-deterministic-{}.json", std::process::id()));
+        let save = |order: [&str; 2], seconds: f64| {
+            let report = Report::new(Some(path.clone()), None, None, TimeZone::Utc).deterministic();
+            for name in order {
+                let url = format!("http://example.com/{}", name);
+                report.timed(&url, Timing { dns: seconds, connect: seconds, tls: seconds, ttfb: seconds });
+                report.downloaded(&url, Path::new(name), 5, Duration::from_secs_f64(seconds), Some(200), &[]);
+            }
+            report.save();
+            std::fs::read_to_string(&path).unwrap()
+        };
+        // Finishing in another order, at another speed, makes no difference
+        let first = save(["a", "b"], 1.0);
+        assert_eq!(first, save(["b", "a"], 3.0));
+        let json: serde_json::Value = serde_json::from_str(&first).unwrap();
+        assert!(json.get("started").is_none());
+        assert_eq!(json["downloads"][0]["url"], "http://example.com/a");
+        assert_eq!(json["downloads"][0]["seconds"], 0.0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_fail_fast_cancels_the_rest() {
+        let cancellation = Cancellation::default();
+        let report = Report::new(None, None, None, TimeZone::Utc).fail_fast(&cancellation);
+        report.downloaded("http://example.com/a", Path::new("a"), 5, Duration::from_secs(1), Some(200), &[]);
+        assert_eq!(cancellation.reason(), None);
+        report.failed("http://example.com/b", None, Some(503), "server returned 503 Service Unavailable");
+        assert_eq!(cancellation.reason(), Some(CancelReason::Failed));
+        // Whatever was stopped because of it doesn't change why we exit
+        report.failed("http://example.com/c", None, None, Failure::new(ExitCode::Network, "cancelled"));
+        assert_eq!(report.exit_code(false), ExitCode::Partial);
+        assert_eq!(report.exit_code(true), ExitCode::ServerError);
+        assert_eq!(report.unreachable(), None);
+    }
+
+    #[test]
+    fn test_batches_are_separate() {
+        let report = Arc::new(Report::new(None, None, None, TimeZone::Utc));
+        let (first, second) = (Report::batch(&report), Report::batch(&report));
+        first.failed("http://example.com/a", None, Some(404), "server returned 404 Not Found");
+        second.downloaded("http://example.com/b", Path::new("b"), 5, Duration::from_secs(1), Some(200), &[]);
+        assert_eq!(first.exit_code(false), ExitCode::ClientError);
+        assert_eq!(second.exit_code(false), ExitCode::Success);
+        assert_eq!(report.entries().len(), 2);
+    }
+
+    #[test]
+    fn test_unreachable_only_when_nothing_connected() {
+        let report = Report::new(None, None, None, TimeZone::Utc);
+        assert_eq!(report.unreachable(), None);
+        report.failed("http://example.com/a", None, None, Failure::new(ExitCode::Network, "connection refused"));
+        report.failed("http://example.com/b", None, None, Failure::new(ExitCode::Network, "connection refused"));
+        assert_eq!(report.unreachable(), Some(vec!["http://example.com/a".to_string(), "http://example.com/b".to_string()]));
+        report.failed("http://example.com/c", None, Some(404), "server returned 404 Not Found");
+        assert_eq!(report.unreachable(), None);
+    }
+
+    #[test]
+    fn test_csv_report_appends() {
+        let path = std::env::temp_dir().join(format!("rustdl-report-{}.csv", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let sha256: Checksum = "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824".parse().unwrap();
+        for _ in 0..2 {
+            let report = Report::new(None, Some(path.clone()), None, TimeZone::Local);
+            report.downloaded("http://example.com/a,b", Path::new("a,b"), 5, Duration::from_millis(1500), Some(200), std::slice::from_ref(&sha256));
+            report.failed("http://example.com/c", None, Some(404), "server returned 404 Not Found");
+            report.save();
+        }
+
+        let csv = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], "url,file,status,bytes,seconds,sha256");
+        assert_eq!(lines[1], "\"http://example.com/a,b\",\"a,b\",downloaded,5,1.500,2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824");
+        assert_eq!(lines[2], "http://example.com/c,,failed,0,0.000,");
+        assert_eq!(lines[3..], lines[1..3]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_skipped_isnt_success() {
+        let report = Report::new(None, None, None, TimeZone::Utc);
+        report.skipped("http://example.com/a", Some(Path::new("a")), "already exists");
+        assert_eq!(report.exit_code(false), ExitCode::Success);
+        report.failed("http://example.com/b", None, Some(404), "server returned 404 Not Found");
+        assert_eq!(report.exit_code(false), ExitCode::ClientError);
+    }
+}
@@ -0,0 +1,451 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::Instant;
+
+use futures::channel::oneshot;
+use log::{debug, info};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use tower_layer::Layer;
+use tower_service::Service;
+use tracing::trace_span;
+use url::Url;
+
+use crate::timing;
+
+/// Most host names looked up at once; getaddrinfo blocks, so any more wait
+/// for a lookup thread to be free
+const LOOKUP_THREADS: usize = 8;
+
+/// `--resolve HOST:PORT:ADDR[,ADDR...]`: look HOST up as these addresses
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolveOverride {
+    pub host: String,
+    pub port: u16,
+    pub addrs: Vec<IpAddr>,
+}
+
+impl FromStr for ResolveOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid --resolve '{}', expected HOST:PORT:ADDR[,ADDR...]", s);
+        let mut parts = s.splitn(3, ':');
+        let (Some(host), Some(port), Some(addrs)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(invalid());
+        };
+        let port = port.parse().map_err(|_| invalid())?;
+        let addrs = addrs
+            .split(',')
+            .map(|addr| addr.trim().trim_start_matches('[').trim_end_matches(']').parse())
+            .collect::<Result<Vec<IpAddr>, _>>()
+            .map_err(|e| format!("{}: {}", invalid(), e))?;
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(ResolveOverride { host: host.to_ascii_lowercase(), port, addrs })
+    }
+}
+
+impl fmt::Display for ResolveOverride {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let addrs: Vec<String> = self.addrs.iter().map(|addr| bracketed(&addr.to_string())).collect();
+        write!(f, "{}:{}:{}", self.host, self.port, addrs.join(","))
+    }
+}
+
+/// `--connect-to HOST:PORT:TARGET:TARGET_PORT`: connect to TARGET whenever
+/// HOST is asked for, still calling it HOST in the request and TLS handshake
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectTo {
+    pub host: String,
+    pub port: u16,
+    pub target: String,
+    /// Left empty, the URL's own port
+    pub target_port: Option<u16>,
+}
+
+impl FromStr for ConnectTo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid --connect-to '{}', expected HOST:PORT:TARGET:TARGET_PORT", s);
+        let mut parts = s.splitn(3, ':');
+        let (Some(host), Some(port), Some(target)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(invalid());
+        };
+        let (target, target_port) = target.rsplit_once(':').ok_or_else(invalid)?;
+        let target = target.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() || target.is_empty() {
+            return Err(invalid());
+        }
+        Ok(ConnectTo {
+            host: host.to_ascii_lowercase(),
+            port: port.parse().map_err(|_| invalid())?,
+            target: target.to_string(),
+            target_port: match target_port {
+                "" => None,
+                port => Some(port.parse().map_err(|_| invalid())?),
+            },
+        })
+    }
+}
+
+impl fmt::Display for ConnectTo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let port = self.target_port.map(|port| port.to_string()).unwrap_or_default();
+        write!(f, "{}:{}:{}:{}", self.host, self.port, bracketed(&self.target), port)
+    }
+}
+
+/// IPv6 addresses in brackets, as they have to be next to a port
+fn bracketed(host: &str) -> String {
+    match host.contains(':') {
+        true => format!("[{}]", host),
+        false => host.to_string(),
+    }
+}
+
+/// `-4` or `-6`: the only kind of address to connect to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressFamily {
+    V4,
+    V6,
+}
+
+impl AddressFamily {
+    pub fn matches(self, addr: &IpAddr) -> bool {
+        match self {
+            AddressFamily::V4 => addr.is_ipv4(),
+            AddressFamily::V6 => addr.is_ipv6(),
+        }
+    }
+
+    /// The flag that picks this family, for ssh
+    pub fn flag(self) -> &'static str {
+        match self {
+            AddressFamily::V4 => "-4",
+            AddressFamily::V6 => "-6",
+        }
+    }
+}
+
+impl fmt::Display for AddressFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressFamily::V4 => write!(f, "IPv4"),
+            AddressFamily::V6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+/// A host with addresses, just none that `-4` or `-6` allows
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("{host} has no {family} addresses")]
+pub struct NoAddresses {
+    pub host: String,
+    pub family: AddressFamily,
+}
+
+impl NoAddresses {
+    /// The one somewhere in `error`'s causes, if there is one
+    pub fn find<'a>(error: &'a (dyn Error + 'static)) -> Option<&'a NoAddresses> {
+        let mut source = Some(error);
+        while let Some(error) = source {
+            // io::Error hides what it wraps from source(), so it has to be unwrapped by hand
+            let inner = error.downcast_ref::<io::Error>().and_then(io::Error::get_ref).map(|inner| inner as &(dyn Error + 'static));
+            if let Some(found) = inner.unwrap_or(error).downcast_ref::<NoAddresses>() {
+                return Some(found);
+            }
+            source = error.source();
+        }
+        None
+    }
+}
+
+thread_local! {
+    /// The port of the connection being made on this thread, while it's being made
+    static PORT: Cell<Option<u16>> = const { Cell::new(None) };
+}
+
+/// Lets the `Resolver` know which port a connection is for, as the HTTP
+/// client only gives it the host name, and `--resolve` and `--connect-to`
+/// entries are for a host on one port
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectPort;
+
+impl<S> Layer<S> for ConnectPort {
+    type Service = WithPort<S>;
+
+    fn layer(&self, inner: S) -> WithPort<S> {
+        WithPort(inner)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WithPort<S>(S);
+
+impl<S, R> Service<R> for WithPort<S>
+where
+    S: Service<R>,
+    S::Future: Send + 'static,
+    R: fmt::Debug,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        // reqwest doesn't let the URI be seen any other way than how it's printed
+        let printed = format!("{:?}", request);
+        let uri = printed.split_once('(').and_then(|(_, rest)| rest.strip_suffix(')')).unwrap_or(&printed).trim_matches('"');
+        let port = Url::parse(uri).ok().and_then(|url| url.port_or_known_default());
+        let mut connecting = Box::pin(self.0.call(request));
+        Box::pin(futures::future::poll_fn(move |cx| {
+            // The host's looked up as part of connecting, on this thread
+            let outer = PORT.replace(port);
+            let poll = connecting.as_mut().poll(cx);
+            PORT.set(outer);
+            poll
+        }))
+    }
+}
+
+/// Looks host names up with the system resolver, like reqwest does by
+/// default, but in a `resolve` span so `-vvv` shows how long it took, and
+/// with the answers `--resolve` and `--connect-to` give for the hosts and
+/// ports they name (which needs the client to have the `ConnectPort` layer)
+#[derive(Debug, Clone, Default)]
+pub struct Resolver {
+    pinned: Arc<HashMap<(String, u16), ResolveOverride>>,
+    redirected: Arc<HashMap<(String, u16), ConnectTo>>,
+    family: Option<AddressFamily>,
+    lookups: Arc<Lookups>,
+}
+
+type Lookup = Box<dyn FnOnce() + Send>;
+
+/// The threads a resolver looks host names up on, started with its first
+/// lookup and kept until it's gone
+#[derive(Debug, Default)]
+struct Lookups(OnceLock<Sender<Lookup>>);
+
+impl Lookups {
+    fn run(&self, lookup: Lookup) {
+        let sender = self.0.get_or_init(|| {
+            let (sender, receiver) = mpsc::channel::<Lookup>();
+            let receiver = Arc::new(Mutex::new(receiver));
+            for _ in 0..LOOKUP_THREADS {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || {
+                    loop {
+                        // Not holding the lock while looking up, so the others can
+                        let next = receiver.lock().unwrap().recv();
+                        match next {
+                            Ok(lookup) => lookup(),
+                            Err(_) => break,
+                        }
+                    }
+                });
+            }
+            sender
+        });
+        // The threads only stop once the sender's gone, along with us
+        let _ = sender.send(lookup);
+    }
+}
+
+impl Resolver {
+    pub fn new(resolve: &[ResolveOverride], connect_to: &[ConnectTo]) -> Self {
+        Resolver {
+            pinned: Arc::new(resolve.iter().map(|pinned| ((pinned.host.clone(), pinned.port), pinned.clone())).collect()),
+            redirected: Arc::new(connect_to.iter().map(|redirect| ((redirect.host.clone(), redirect.port), redirect.clone())).collect()),
+            family: None,
+            lookups: Arc::default(),
+        }
+    }
+
+    /// Only hand out addresses of this family, failing hosts that have none
+    pub fn only(mut self, family: Option<AddressFamily>) -> Self {
+        self.family = family;
+        self
+    }
+
+    /// Where a connection to `host` on `port` goes, after `--resolve`,
+    /// `--connect-to` and `-4`/`-6`, for checking the way there
+    pub fn addresses(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let mut addrs = self.addrs(host, Some(port))?;
+        for addr in addrs.iter_mut().filter(|addr| addr.port() == 0) {
+            addr.set_port(port);
+        }
+        Ok(addrs)
+    }
+
+    /// `host`'s addresses for a connection to `port`, from `--resolve` if
+    /// it's there; a port of 0 means the URL's
+    fn lookup(&self, host: &str, port: Option<u16>) -> io::Result<Vec<SocketAddr>> {
+        let pinned = port.and_then(|port| self.pinned.get(&(host.to_ascii_lowercase(), port)));
+        let mut addrs: Vec<SocketAddr> = match pinned {
+            Some(pinned) => {
+                info!("Resolving {} to {:?} (--resolve {})", host, pinned.addrs, pinned);
+                pinned.addrs.iter().map(|addr| SocketAddr::new(*addr, 0)).collect()
+            }
+            None => {
+                let addrs = (host, 0).to_socket_addrs()?.collect();
+                debug!("Resolved {} to {:?}", host, addrs);
+                addrs
+            }
+        };
+        if let Some(family) = self.family {
+            addrs.retain(|addr| family.matches(&addr.ip()));
+            if addrs.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, NoAddresses { host: host.to_string(), family }));
+            }
+        }
+        Ok(addrs)
+    }
+
+    fn addrs(&self, host: &str, port: Option<u16>) -> io::Result<Vec<SocketAddr>> {
+        let Some(redirect) = port.and_then(|port| self.redirected.get(&(host.to_ascii_lowercase(), port))) else {
+            return self.lookup(host, port);
+        };
+        info!("Connecting to {} for {} (--connect-to {})", redirect.target, host, redirect);
+        let mut addrs = self.lookup(&redirect.target, redirect.target_port.or(port))?;
+        for addr in &mut addrs {
+            addr.set_port(redirect.target_port.unwrap_or(0));
+        }
+        Ok(addrs)
+    }
+}
+
+impl Resolve for Resolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        let port = PORT.get();
+        let span = trace_span!("resolve", host = %host);
+        let (sender, receiver) = oneshot::channel();
+        let resolver = self.clone();
+        let started = Instant::now();
+        // getaddrinfo blocks, so it's done on one of our threads
+        self.lookups.run(Box::new(move || {
+            let _span = span.entered();
+            let _ = sender.send(resolver.addrs(&host, port));
+        }));
+        Box::pin(async move {
+            let addrs = receiver.await??;
+            timing::looked_up(started.elapsed());
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(resolver: &Resolver, host: &str) -> Vec<SocketAddr> {
+        resolve_on(resolver, host, 443).unwrap()
+    }
+
+    /// Look `host` up as the client would for a connection to `port`
+    fn resolve_on(resolver: &Resolver, host: &str, port: u16) -> Result<Vec<SocketAddr>, Box<dyn Error + Send + Sync>> {
+        PORT.set(Some(port));
+        let resolving = resolver.resolve(Name::from_str(host).unwrap());
+        PORT.set(None);
+        Ok(futures::executor::block_on(resolving)?.collect())
+    }
+
+    #[test]
+    fn test_resolves_localhost() {
+        let addrs = resolve(&Resolver::default(), "localhost");
+        assert!(addrs.iter().all(|addr| addr.ip().is_loopback()));
+        assert!(!addrs.is_empty());
+    }
+
+    #[test]
+    fn test_more_lookups_than_threads() {
+        let resolver = Resolver::default();
+        let lookups: Vec<_> = (0..LOOKUP_THREADS * 4).map(|_| resolver.resolve(Name::from_str("localhost").unwrap())).collect();
+        for addrs in futures::executor::block_on(futures::future::join_all(lookups)) {
+            assert!(addrs.unwrap().next().is_some());
+        }
+    }
+
+    #[test]
+    fn test_overrides() {
+        let pinned: ResolveOverride = "Staging.example.com:443:10.0.0.5,[::1]".parse().unwrap();
+        assert_eq!(pinned.host, "staging.example.com");
+        assert_eq!(pinned.addrs, ["10.0.0.5".parse::<IpAddr>().unwrap(), "::1".parse().unwrap()]);
+        assert!("example.com:443".parse::<ResolveOverride>().is_err());
+        assert!("example.com:https:10.0.0.5".parse::<ResolveOverride>().is_err());
+
+        let moved: ConnectTo = "example.com:443:staging.example.com:8443".parse().unwrap();
+        assert_eq!((moved.target.as_str(), moved.target_port), ("staging.example.com", Some(8443)));
+        assert_eq!("example.com:443:[::1]:".parse::<ConnectTo>().unwrap().to_string(), "example.com:443:[::1]:");
+        assert_eq!(pinned.to_string(), "staging.example.com:443:10.0.0.5,[::1]");
+        assert!("example.com:443:staging".parse::<ConnectTo>().is_err());
+
+        let resolver = Resolver::new(&[pinned, "staging.example.com:8443:10.0.0.6".parse().unwrap()], &[moved, "www.example.com:80:localhost:".parse().unwrap()]);
+        assert_eq!(resolve(&resolver, "staging.example.com")[0], "10.0.0.5:0".parse().unwrap());
+        // Where --connect-to sends us is looked up with --resolve too, for the port it sends us to
+        assert_eq!(resolve(&resolver, "example.com")[0], "10.0.0.6:8443".parse().unwrap());
+        assert!(resolve_on(&resolver, "www.example.com", 80).unwrap().iter().all(|addr| addr.ip().is_loopback() && addr.port() == 0));
+        // Entries are only for the port they name
+        let resolver = Resolver::new(&["localhost:443:10.0.0.7".parse().unwrap()], &[]);
+        assert_eq!(resolve(&resolver, "localhost"), ["10.0.0.7:0".parse().unwrap()]);
+        assert!(resolve_on(&resolver, "localhost", 80).unwrap().iter().all(|addr| addr.ip().is_loopback()));
+    }
+
+    /// A connector that just notes which port the resolver would be told
+    struct Connector;
+
+    #[derive(Debug)]
+    struct Unnameable(#[allow(dead_code)] &'static str);
+
+    impl Service<Unnameable> for Connector {
+        type Response = Option<u16>;
+        type Error = io::Error;
+        type Future = futures::future::Lazy<fn(&mut Context<'_>) -> io::Result<Option<u16>>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _request: Unnameable) -> Self::Future {
+            futures::future::lazy(|_| Ok(PORT.get()))
+        }
+    }
+
+    #[test]
+    fn test_connect_port() {
+        let mut connector = ConnectPort.layer(Connector);
+        assert_eq!(futures::executor::block_on(connector.call(Unnameable("http://example.com:8080/"))).unwrap(), Some(8080));
+        assert_eq!(futures::executor::block_on(connector.call(Unnameable("https://example.com/"))).unwrap(), Some(443));
+        assert_eq!(PORT.get(), None);
+    }
+
+    #[test]
+    fn test_only_one_family() {
+        let pinned: ResolveOverride = "dual.example.com:443:10.0.0.5,::1".parse().unwrap();
+        let v6 = Resolver::new(&[pinned], &[]).only(Some(AddressFamily::V6));
+        assert_eq!(resolve(&v6, "dual.example.com"), ["[::1]:0".parse().unwrap()]);
+        let v4 = Resolver::new(&["v6.example.com:443:::1".parse().unwrap()], &[]).only(Some(AddressFamily::V4));
+        let error = resolve_on(&v4, "v6.example.com", 443).err().unwrap();
+        assert_eq!(error.to_string(), "v6.example.com has no IPv4 addresses");
+        assert_eq!(NoAddresses::find(&*error).unwrap().family, AddressFamily::V4);
+    }
+}
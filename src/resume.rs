@@ -0,0 +1,234 @@
+//! Finishing downloads from the `.part` files they left behind
+//! (`download resume`), and carrying on with an interrupted session
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use log::{debug, error, info, warn};
+use url::Url;
+
+use crate::cancel::Cancellation;
+use crate::checksum;
+use crate::checksum_db::ChecksumDatabase;
+use crate::credentials::CredentialStore;
+use crate::downloader::{build_client, ClientOptions};
+use crate::middleware::Middleware;
+use crate::oauth::OAuthSettings;
+use crate::partial::{self, PartError, PartFile};
+use crate::progress::{BarStyle, ProgressMode};
+use crate::session::{Session, Unfinished};
+#[cfg(feature = "state-db")]
+use crate::state_db::StateDb;
+use crate::throttle::{ByteRate, RateLimiter};
+use crate::timeouts;
+use crate::timestamping;
+
+/// The .part files to resume: those in `paths`, searching any that are
+/// directories, or in the current directory if there are none; with `all`,
+/// every one `state` says didn't finish
+pub fn find(paths: Vec<PathBuf>, #[cfg(feature = "state-db")] all: bool, #[cfg(feature = "state-db")] state: Option<&StateDb>) -> Result<Vec<PathBuf>, PartError> {
+    #[cfg(feature = "state-db")]
+    if all {
+        return search(unfinished_parts(state));
+    }
+    search(if paths.is_empty() { vec![PathBuf::from(".")] } else { paths })
+}
+
+/// The .part files among `paths`, and in any of them that are directories
+fn search(paths: Vec<PathBuf>) -> Result<Vec<PathBuf>, PartError> {
+    let mut parts = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            parts.extend(PartFile::find(&path)?);
+        } else {
+            parts.push(path);
+        }
+    }
+    Ok(parts)
+}
+
+/// The .part files of the downloads the state database says didn't finish,
+/// where they're still there to be resumed
+#[cfg(feature = "state-db")]
+fn unfinished_parts(state: Option<&StateDb>) -> Vec<PathBuf> {
+    let unfinished = match state.map(StateDb::unfinished) {
+        Some(Ok(unfinished)) => unfinished,
+        Some(Err(e)) => {
+            warn!("{}", e);
+            Vec::new()
+        }
+        None => Vec::new(),
+    };
+    unfinished
+        .into_iter()
+        .filter_map(|download| download.path)
+        .map(|path| partial::with_suffix(&path, partial::PART_SUFFIX))
+        .filter(|part| part.exists())
+        .collect()
+}
+
+/// Finishes partial downloads
+pub struct Resumer<'a> {
+    /// From the same flags a download's would be, so a part is finished
+    /// through the same proxy, resolver and cookies it was started with
+    pub client: &'a ClientOptions<'a>,
+    /// What each request goes through, as a download's would
+    pub middleware: &'a Middleware,
+    pub limit_rate: Option<ByteRate>,
+    pub oauth: &'a OAuthSettings,
+    /// Where what's finished, and what's left of what isn't, is recorded
+    #[cfg(feature = "state-db")]
+    pub state: Option<&'a Arc<StateDb>>,
+    pub progress: ProgressMode,
+    pub bar_style: &'a BarStyle,
+    pub cancellation: &'a Cancellation,
+}
+
+impl Resumer<'_> {
+    /// Finish each of `parts` in turn, until cancelled
+    pub fn resume(&self, parts: Vec<PathBuf>) -> Result<(), PartError> {
+        let (progress, bar_style, cancellation) = (self.progress, self.bar_style, self.cancellation);
+        let header_timeout = self.client.header_timeout;
+        let client = build_client(self.client).map_err(timeouts::RequestError::from)?;
+        let limiter = self.limit_rate.map(|rate| Arc::new(RateLimiter::new(rate)));
+        let credentials = CredentialStore::open_saved();
+        let checksum_db = ChecksumDatabase::open_default()
+            .inspect_err(|e| warn!("Not verifying against known checksums: {}", e))
+            .ok();
+
+        cancellation.on_suspend(progress != ProgressMode::Json);
+        let mut failed = 0;
+        for path in parts {
+            if cancellation.reason().is_some() {
+                break;
+            }
+            let result = PartFile::open(&path).and_then(|part| {
+                let pb = progress.bar(0).with_style(bar_style.downloading()).with_prefix(part.metadata.filename.clone());
+                progress.started(&pb, Some(&part.metadata.url));
+                cancellation.track(&pb, bar_style.paused());
+                let mut request = self.middleware.get(&client, &part.metadata.url);
+                if let (Some(store), Ok(url)) = (&credentials, Url::parse(&part.metadata.url)) {
+                    request = store.authorize(request, &url, &client, self.oauth);
+                }
+                let url = part.metadata.url.clone();
+                #[cfg(feature = "state-db")]
+                if let Some(state) = self.state
+                    && let Err(e) = state.started(&url, &part.output_path(), part.metadata.etag.as_deref())
+                {
+                    warn!("Could not record {}: {}", url, e);
+                }
+                #[cfg(feature = "state-db")]
+                let part = partial::resume(&client, part, request, header_timeout, limiter.clone(), &pb, cancellation, self.state)?;
+                #[cfg(not(feature = "state-db"))]
+                let part = partial::resume(&client, part, request, header_timeout, limiter.clone(), &pb, cancellation)?;
+                progress.finish(&pb, Some(&url), bar_style.finished(), "");
+
+                let known_checksums = checksum_db.as_ref().filter(|_| part.metadata.range.is_none());
+                if let Some(expected) = known_checksums.and_then(|db| db.lookup(&url)) {
+                    let actual = checksum::file_checksum(&part.data_path(), expected.algorithm)
+                        .map_err(|source| PartError::Io { path: part.data_path(), source })?;
+                    if let Err(e) = checksum::verify(expected, &actual) {
+                        part.discard();
+                        return Err(PartError::Checksum { url, source: e });
+                    }
+                }
+                let last_modified = part.metadata.last_modified.clone();
+                let output = part.complete()?;
+                if let Some(last_modified) = last_modified
+                    && let Err(e) = timestamping::set_modified(&output, &last_modified)
+                {
+                    warn!("Could not set modification time of {}: {}", output.display(), e);
+                }
+                Ok((url, output))
+            });
+            match result {
+                #[cfg_attr(not(feature = "state-db"), allow(unused_variables))]
+                Ok((url, output)) => {
+                    info!("Finished {}", output.display());
+                    #[cfg(feature = "state-db")]
+                    if let Some(state) = self.state
+                        && let Err(e) = state.finished(&url, &output)
+                    {
+                        warn!("Could not record {}: {}", url, e);
+                    }
+                }
+                Err(e) => {
+                    error!("{}: {}", path.display(), e);
+                    // Whatever's left of it can be resumed again
+                    #[cfg(feature = "state-db")]
+                    if let Some(state) = self.state
+                        && let Ok(part) = PartFile::open(&path)
+                        && let Err(e) = state.failed(&part.metadata.url, Some(&part.output_path()), part.metadata.offset, &e.to_string())
+                    {
+                        warn!("Could not record {}: {}", part.metadata.url, e);
+                    }
+                    failed += 1;
+                }
+            }
+        }
+        if failed > 0 {
+            return Err(PartError::Failed(failed));
+        }
+        Ok(())
+    }
+
+    /// Resume an interrupted session's .part files, returning the URLs still
+    /// to be downloaded from the start: the ones it never got to, any that
+    /// can't be resumed after all, and `extra`. If this is cancelled too,
+    /// what's still left of the session is returned instead, to be saved
+    pub fn continue_session(&self, session: Session, extra: Vec<String>) -> Result<Vec<String>, Session> {
+        let urls = session.urls.clone();
+        let (parts, mut fresh) = session.split();
+        if !parts.is_empty()
+            && let Err(e) = self.resume(parts.iter().filter_map(|unfinished| unfinished.part.clone()).collect())
+        {
+            debug!("Not every part was resumed: {}", e);
+        }
+        let left: Vec<Unfinished> = parts.into_iter().filter(|unfinished| unfinished.part.as_ref().is_some_and(|part| part.exists())).collect();
+        if self.cancellation.reason().is_some() {
+            let unfinished = left.into_iter().chain(fresh.into_iter().chain(extra).map(|url| Unfinished { url, part: None, offset: 0 })).collect();
+            return Err(Session { urls, unfinished });
+        }
+        // Whatever couldn't be resumed starts over
+        fresh.extend(left.into_iter().map(|unfinished| unfinished.url));
+        fresh.extend(extra.into_iter().filter(|url| !fresh.contains(url)).collect::<Vec<_>>());
+        Ok(fresh)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use crate::cancel::CancelPolicy;
+
+    #[test]
+    fn test_continue_session_starts_over_what_cant_be_resumed() {
+        let dir = std::env::temp_dir().join(format!("rustdl-resume-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let broken = dir.join("a.iso.part");
+        fs::write(&broken, b"not a part file").unwrap();
+        let session = Session {
+            urls: vec!["https://example.com/a.iso".to_string(), "https://example.com/b.iso".to_string()],
+            unfinished: vec![
+                Unfinished { url: "https://example.com/a.iso".to_string(), part: Some(broken), offset: 15 },
+                Unfinished { url: "https://example.com/b.iso".to_string(), part: None, offset: 0 },
+            ],
+        };
+        let resumer = Resumer {
+            client: &ClientOptions::default(),
+            middleware: &Middleware::standard(),
+            limit_rate: None,
+            oauth: &OAuthSettings::default(),
+            #[cfg(feature = "state-db")]
+            state: None,
+            progress: ProgressMode::Quiet,
+            bar_style: &BarStyle::default(),
+            cancellation: &Cancellation::new(CancelPolicy::KeepPart),
+        };
+        let urls = resumer.continue_session(session, vec!["https://example.com/c.iso".to_string()]).unwrap();
+        assert_eq!(urls, ["https://example.com/b.iso", "https://example.com/a.iso", "https://example.com/c.iso"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
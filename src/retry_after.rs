@@ -0,0 +1,355 @@
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{debug, warn};
+use reqwest::StatusCode;
+use reqwest::blocking::{Client, Request, Response};
+use reqwest::header::{self, HeaderValue};
+
+use crate::cancel::Cancellation;
+use crate::partial;
+use crate::report::{Report, Retry};
+use crate::timeouts::{self, RequestError};
+
+/// How long we'll wait for a rate-limited server unless told otherwise
+pub const DEFAULT_MAX_WAIT: Duration = Duration::from_secs(300);
+
+/// How many times to come back before taking the server's answer as final
+const MAX_RETRIES: u32 = 5;
+
+/// How long to wait before retrying a request that timed out, doubling each time
+const TIMEOUT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How often the countdown ticks, and how quickly Ctrl-C interrupts the wait
+const TICK: Duration = Duration::from_secs(1);
+
+/// How long a rate-limited (429) or overloaded (503) response asks us to wait
+/// before trying again, given as seconds or an HTTP date
+pub fn delay(response: &Response) -> Option<Duration> {
+    if !matches!(response.status(), StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE) {
+        return None;
+    }
+    parse(response.headers().get(header::RETRY_AFTER)?.to_str().ok()?, SystemTime::now())
+}
+
+fn parse(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        // A date that's already gone means we can go again straight away
+        Err(_) => Some(httpdate::parse_http_date(value).ok()?.duration_since(now).unwrap_or_default()),
+    }
+}
+
+/// Waits out `Retry-After` and sends the request again, as long as the server
+/// doesn't ask for longer than `max_wait`; requests that time out are sent
+/// again too, backing off a little more each time
+#[derive(Debug, Clone, Copy)]
+pub struct RetryAfter {
+    max_wait: Duration,
+}
+
+impl Default for RetryAfter {
+    fn default() -> Self {
+        RetryAfter { max_wait: DEFAULT_MAX_WAIT }
+    }
+}
+
+impl RetryAfter {
+    pub fn new(max_wait: Duration) -> Self {
+        RetryAfter { max_wait }
+    }
+
+    /// Send `request` with `send`, coming back when a rate-limited server says
+    /// to or when it timed out; `countdown` is told why, and how long is left
+    /// every second while we wait, and each retry goes in the report. The
+    /// last response or error is returned whatever it is, for the caller to
+    /// fail on.
+    pub fn send(
+        &self,
+        mut request: Request,
+        cancellation: &Cancellation,
+        report: &Report,
+        mut countdown: impl FnMut(&str, Duration),
+        mut send: impl FnMut(Request) -> Result<Response, RequestError>,
+    ) -> Result<Response, RequestError> {
+        let url = request.url().clone();
+        let mut retries = 0;
+        loop {
+            // Bodies that can't be sent twice can't be retried
+            let again = request.try_clone();
+            let result = send(request);
+            let wait = match &result {
+                Ok(response) => delay(response),
+                Err(e) if e.is_timeout() => Some((TIMEOUT_BACKOFF * 2u32.pow(retries)).min(self.max_wait)),
+                Err(_) => None,
+            };
+            let Some(wait) = wait else {
+                return result;
+            };
+            let Some(next) = again.filter(|_| retries < MAX_RETRIES) else {
+                debug!("{} is still failing after {} retries", url, retries);
+                return result;
+            };
+            if self.max_wait.is_zero() {
+                return result;
+            }
+            if wait > self.max_wait {
+                warn!("{} asked us to come back in {}, longer than --retry-after-max allows", url, humantime::format_duration(wait));
+                return result;
+            }
+            let reason = match &result {
+                Ok(response) => response.status().to_string(),
+                Err(_) => "timed out".to_string(),
+            };
+            match result.is_ok() {
+                true => warn!("{} returned {}, retrying in {}", url, reason, humantime::format_duration(wait)),
+                false => warn!("{} timed out, retrying in {}", url, humantime::format_duration(wait)),
+            }
+            let until = Instant::now() + wait;
+            while let Some(left) = until.checked_duration_since(Instant::now()).filter(|left| !left.is_zero()) {
+                if cancellation.reason().is_some() {
+                    return result;
+                }
+                countdown(&reason, left);
+                thread::sleep(left.min(TICK));
+            }
+            retries += 1;
+            report.retried(url.as_str(), Retry { attempt: retries, reason, backoff: wait.as_secs_f64() });
+            request = next;
+        }
+    }
+
+    /// `response`'s body, asked for again from where it stopped whenever the
+    /// server stops sending for longer than the read timeout, backing off as
+    /// for a request that timed out; `request` is the one it answered. Bodies
+    /// that can't be asked for in parts, like those decompressed as they
+    /// arrive, are left as they are.
+    pub fn resuming(
+        &self,
+        response: Response,
+        request: Request,
+        client: &Client,
+        header_timeout: Option<Duration>,
+        cancellation: &Cancellation,
+        report: Arc<Report>,
+    ) -> Box<dyn Read + Send> {
+        let content_range = response.headers().get(header::CONTENT_RANGE).and_then(|value| value.to_str().ok()).unwrap_or_default();
+        let (position, end) = match response.status() {
+            StatusCode::OK => (Some(0), None),
+            StatusCode::PARTIAL_CONTENT => (partial::range_start(content_range), range_end(content_range)),
+            _ => (None, None),
+        };
+        let ranges = response.headers().get(header::ACCEPT_RANGES).is_none_or(|value| value != "none");
+        let (Some(position), true, Some(_)) = (position, ranges, response.content_length()) else {
+            return Box::new(response);
+        };
+        let etag = response.headers().get(header::ETAG).filter(|etag| !etag.as_bytes().starts_with(b"W/"));
+        let validator = etag.or(response.headers().get(header::LAST_MODIFIED)).cloned();
+        Box::new(ResumingBody {
+            body: response,
+            request,
+            client: client.clone(),
+            header_timeout,
+            position,
+            end,
+            validator,
+            retries: 0,
+            max_wait: self.max_wait,
+            cancellation: cancellation.clone(),
+            report,
+        })
+    }
+}
+
+/// The last byte a `Content-Range` says is in the response
+fn range_end(content_range: &str) -> Option<u64> {
+    content_range.strip_prefix("bytes ")?.split_once('-')?.1.split('/').next()?.trim().parse().ok()
+}
+
+/// See `RetryAfter::resuming`
+struct ResumingBody {
+    body: Response,
+    request: Request,
+    client: Client,
+    header_timeout: Option<Duration>,
+    /// Where the next byte we read is in the remote file, and the last byte
+    /// we want, if it isn't the end of it
+    position: u64,
+    end: Option<u64>,
+    /// For asking for the rest only if it's still the same file
+    validator: Option<HeaderValue>,
+    retries: u32,
+    max_wait: Duration,
+    cancellation: Cancellation,
+    report: Arc<Report>,
+}
+
+impl ResumingBody {
+    /// Wait, then ask for the rest of the body; if that can't be done, the
+    /// read that timed out fails after all
+    fn resume(&mut self) -> Result<(), String> {
+        let url = self.request.url().clone();
+        let wait = (TIMEOUT_BACKOFF * 2u32.pow(self.retries)).min(self.max_wait);
+        warn!("{} stopped sending, asking for the rest in {}", url, humantime::format_duration(wait));
+        let until = Instant::now() + wait;
+        while let Some(left) = until.checked_duration_since(Instant::now()).filter(|left| !left.is_zero()) {
+            if self.cancellation.reason().is_some() {
+                return Err("cancelled".to_string());
+            }
+            thread::sleep(left.min(TICK));
+        }
+        let mut request = self.request.try_clone().ok_or("the request can't be sent again")?;
+        let range = match self.end {
+            Some(end) => format!("bytes={}-{}", self.position, end),
+            None => format!("bytes={}-", self.position),
+        };
+        let headers = request.headers_mut();
+        headers.insert(header::RANGE, range.parse().map_err(|_| "bad range")?);
+        headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+        if let Some(validator) = &self.validator {
+            headers.insert(header::IF_RANGE, validator.clone());
+        }
+        self.retries += 1;
+        self.report.retried(url.as_str(), Retry { attempt: self.retries, reason: "stopped sending".to_string(), backoff: wait.as_secs_f64() });
+        let response = timeouts::execute(&self.client, request, self.header_timeout).map_err(|e| e.to_string())?;
+        let content_range = response.headers().get(header::CONTENT_RANGE).and_then(|value| value.to_str().ok()).unwrap_or_default();
+        if response.status() != StatusCode::PARTIAL_CONTENT || partial::range_start(content_range) != Some(self.position) {
+            return Err(format!("server returned {} ({}) instead of the rest", response.status(), content_range));
+        }
+        self.body = response;
+        Ok(())
+    }
+}
+
+impl Read for ResumingBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.body.read(buf) {
+                Ok(read) => {
+                    self.position += read as u64;
+                    return Ok(read);
+                }
+                Err(e) if timed_out(&e) && self.retries < MAX_RETRIES && !self.max_wait.is_zero() => {
+                    if let Err(reason) = self.resume() {
+                        debug!("Could not ask {} for the rest: {}", self.request.url(), reason);
+                        return Err(e);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Whether reading a body failed because the server stopped sending
+fn timed_out(error: &io::Error) -> bool {
+    error.kind() == io::ErrorKind::TimedOut || error.get_ref().and_then(|inner| inner.downcast_ref::<reqwest::Error>()).is_some_and(reqwest::Error::is_timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use reqwest::blocking::Client;
+    use crate::clock::TimeZone;
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(parse("120", now), Some(Duration::from_secs(120)));
+        assert_eq!(parse("Sun, 06 Nov 1994 08:49:47 GMT", now), Some(Duration::from_secs(10)));
+        assert_eq!(parse("Sun, 06 Nov 1994 08:49:27 GMT", now), Some(Duration::ZERO));
+        assert_eq!(parse("soon", now), None);
+    }
+
+    #[test]
+    fn test_retries_after_waiting() {
+        // Rate limits the first request, lets the second through, then rate limits for an hour
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let replies = [
+                "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 1\r\nContent-Length: 0\r\n\r\n",
+                "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok",
+                "HTTP/1.1 503 Service Unavailable\r\nRetry-After: 3600\r\nContent-Length: 0\r\n\r\n",
+            ];
+            for reply in replies {
+                let (mut stream, _) = listener.accept().unwrap();
+                let _ = stream.read(&mut [0u8; 1024]);
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+        });
+
+        let client = Client::builder().pool_max_idle_per_host(0).build().unwrap();
+        let request = client.get(format!("http://{}/", address)).build().unwrap();
+        let report = Report::new(None, None, None, TimeZone::Utc);
+        let mut ticks = 0;
+        let started = Instant::now();
+        let response = RetryAfter::default().send(request, &Cancellation::default(), &report, |_, _| ticks += 1, |request| Ok(client.execute(request)?)).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(ticks, 1);
+
+        // Asked to wait longer than we're willing to, we don't
+        let request = client.get(format!("http://{}/", address)).build().unwrap();
+        let response = RetryAfter::new(Duration::from_secs(60)).send(request, &Cancellation::default(), &report, |_, _| panic!("waited"), |request| Ok(client.execute(request)?)).unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn test_retries_after_timing_out() {
+        // Never answers the first request, then answers the second
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut silent, _) = listener.accept().unwrap();
+            let _ = silent.read(&mut [0u8; 1024]);
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read(&mut [0u8; 1024]);
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").unwrap();
+            thread::sleep(Duration::from_secs(1));
+        });
+
+        let client = Client::builder().pool_max_idle_per_host(0).timeout(Duration::from_millis(200)).build().unwrap();
+        let request = client.get(format!("http://{}/", address)).build().unwrap();
+        let report = Report::new(None, None, None, TimeZone::Utc);
+        let mut reasons = Vec::new();
+        let response = RetryAfter::default()
+            .send(request, &Cancellation::default(), &report, |reason, _| reasons.push(reason.to_string()), |request| Ok(client.execute(request)?))
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(reasons, ["timed out"]);
+    }
+
+    #[test]
+    fn test_stalled_body_is_resumed() {
+        // Sends half the body and goes quiet, then sends the rest when asked for it
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stalled, _) = listener.accept().unwrap();
+            let _ = stalled.read(&mut [0u8; 1024]);
+            stalled.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 8\r\nETag: \"v1\"\r\n\r\nabcd").unwrap();
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 1024];
+            let read = stream.read(&mut request).unwrap();
+            stream.write_all(b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 4-7/8\r\nContent-Length: 4\r\n\r\nefgh").unwrap();
+            drop(stalled);
+            String::from_utf8_lossy(&request[..read]).to_ascii_lowercase()
+        });
+
+        let client = Client::builder().pool_max_idle_per_host(0).timeout(Duration::from_millis(200)).build().unwrap();
+        let request = client.get(format!("http://{}/", address)).build().unwrap();
+        let response = client.execute(request.try_clone().unwrap()).unwrap();
+        let report = Arc::new(Report::new(None, None, None, TimeZone::Utc));
+        let mut body = String::new();
+        RetryAfter::default().resuming(response, request, &client, None, &Cancellation::default(), report).read_to_string(&mut body).unwrap();
+        assert_eq!(body, "abcdefgh");
+        let resumed = server.join().unwrap();
+        assert!(resumed.contains("range: bytes=4-\r\n") && resumed.contains("if-range: \"v1\"\r\n"), "{}", resumed);
+    }
+}
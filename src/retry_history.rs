@@ -0,0 +1,172 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::report::Retry;
+
+/// Name of the history file inside the XDG data directory
+const HISTORY_FILE: &str = "retries.jsonl";
+
+/// Errors from reading the retry history
+#[derive(Debug, thiserror::Error)]
+pub enum HistoryError {
+    #[error("could not read retry history {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("could not determine where to store the retry history")]
+    NoDataDir,
+}
+
+/// One retry, as remembered between runs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    /// When it happened, in seconds since the Unix epoch
+    pub at: u64,
+    pub host: String,
+    pub url: String,
+    #[serde(flatten)]
+    pub retry: Retry,
+}
+
+/// How much trouble a host has given us
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostStats {
+    pub host: String,
+    pub retries: usize,
+    /// How many different URLs needed retrying
+    pub urls: usize,
+    /// Seconds spent waiting to retry
+    pub waited: f64,
+    pub last: SystemTime,
+}
+
+/// Every retry of every run, one JSON object per line, so `download stats`
+/// can say which hosts keep needing them
+#[derive(Debug, Clone)]
+pub struct RetryHistory {
+    path: PathBuf,
+}
+
+impl RetryHistory {
+    /// Default location of the history
+    pub fn default_path() -> Result<PathBuf, HistoryError> {
+        xdg::BaseDirectories::with_prefix("rustdl")
+            .get_data_home()
+            .map(|dir| dir.join(HISTORY_FILE))
+            .ok_or(HistoryError::NoDataDir)
+    }
+
+    pub fn new(path: PathBuf) -> Self {
+        RetryHistory { path }
+    }
+
+    /// Remember that `url` was retried. Only its scheme, host and path are
+    /// kept, as the query string of a signed URL is as good as a password.
+    pub fn append(&self, url: &str, retry: &Retry) -> io::Result<()> {
+        let parsed = Url::parse(url).ok();
+        let record = Record {
+            at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            host: parsed.as_ref().and_then(|url| url.host_str().map(str::to_string)).unwrap_or_default(),
+            url: parsed.map_or_else(String::new, |mut url| {
+                url.set_query(None);
+                url.set_fragment(None);
+                let _ = url.set_username("");
+                let _ = url.set_password(None);
+                url.into()
+            }),
+            retry: retry.clone(),
+        };
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        // One write per line, so runs appending at the same time don't interleave
+        file.write_all(format!("{}\n", serde_json::to_string(&record)?).as_bytes())
+    }
+
+    /// Every retry remembered; a missing file is an empty history, and lines
+    /// that can't be read (say, from a run that was killed mid-write) are skipped
+    pub fn load(&self) -> Result<Vec<Record>, HistoryError> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(source) => return Err(HistoryError::Io { path: self.path.clone(), source }),
+        };
+        Ok(contents
+            .lines()
+            .enumerate()
+            .filter_map(|(index, line)| match serde_json::from_str(line) {
+                Ok(record) => Some(record),
+                Err(e) => {
+                    debug!("Skipping line {} of {}: {}", index + 1, self.path.display(), e);
+                    None
+                }
+            })
+            .collect())
+    }
+}
+
+/// Hosts that needed retrying since `since`, the most retried first
+pub fn flaky(records: &[Record], since: Option<SystemTime>) -> Vec<HostStats> {
+    let since = since.map_or(0, |since| since.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs());
+    let mut hosts: HashMap<&str, (HostStats, HashSet<&str>)> = HashMap::new();
+    for record in records.iter().filter(|record| record.at >= since) {
+        let (stats, urls) = hosts.entry(&record.host).or_insert_with(|| {
+            let stats = HostStats { host: record.host.clone(), retries: 0, urls: 0, waited: 0.0, last: UNIX_EPOCH };
+            (stats, HashSet::new())
+        });
+        stats.retries += 1;
+        stats.waited += record.retry.backoff;
+        stats.last = stats.last.max(UNIX_EPOCH.checked_add(Duration::from_secs(record.at)).unwrap_or(UNIX_EPOCH));
+        urls.insert(&record.url);
+    }
+    let mut hosts: Vec<HostStats> = hosts.into_values().map(|(stats, urls)| HostStats { urls: urls.len(), ..stats }).collect();
+    hosts.sort_by(|a, b| b.retries.cmp(&a.retries).then_with(|| a.host.cmp(&b.host)));
+    hosts
+}
+
+/// How many retries there were for each reason since `since`
+pub fn reasons(records: &[Record], since: Option<SystemTime>) -> BTreeMap<&str, usize> {
+    let since = since.map_or(0, |since| since.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs());
+    let mut reasons = BTreeMap::new();
+    for record in records.iter().filter(|record| record.at >= since) {
+        *reasons.entry(record.retry.reason.as_str()).or_default() += 1;
+    }
+    reasons
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_and_flaky_hosts() {
+        let path = std::env::temp_dir().join(format!("rustdl-retries-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let history = RetryHistory::new(path.clone());
+        assert!(history.load().unwrap().is_empty());
+
+        let limited = |attempt| Retry { attempt, reason: "429 Too Many Requests".to_string(), backoff: 2.0 };
+        history.append("https://mirror.example.com/a.iso", &limited(1)).unwrap();
+        history.append("https://mirror.example.com/a.iso", &limited(2)).unwrap();
+        history.append("https://mirror.example.com/b.iso?X-Amz-Signature=secret", &limited(1)).unwrap();
+        history.append("https://other.example.org/c.iso", &Retry { attempt: 1, reason: "503 Service Unavailable".to_string(), backoff: 30.0 }).unwrap();
+        fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"at\": 1, \"ho").unwrap();
+
+        let records = history.load().unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[2].url, "https://mirror.example.com/b.iso");
+        let hosts = flaky(&records, None);
+        assert_eq!(hosts.iter().map(|host| (host.host.as_str(), host.retries, host.urls)).collect::<Vec<_>>(), [("mirror.example.com", 3, 2), ("other.example.org", 1, 1)]);
+        assert_eq!(hosts[0].waited, 6.0);
+        assert!(flaky(&records, Some(SystemTime::now() + Duration::from_secs(60))).is_empty());
+        assert_eq!(reasons(&records, None).into_iter().collect::<Vec<_>>(), [("429 Too Many Requests", 3), ("503 Service Unavailable", 1)]);
+        fs::remove_file(&path).unwrap();
+    }
+}
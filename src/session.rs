@@ -0,0 +1,140 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::partial::{self, PartFile, PART_SUFFIX};
+use crate::report::{Entry, Status};
+
+/// Name of the session file, in the directory the downloads are saved in
+const SESSION_FILE: &str = ".download-session.json";
+
+/// Errors from reading or writing a session file
+#[derive(Debug, thiserror::Error)]
+pub enum SessionError {
+    #[error("could not access {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("session file {path} is corrupt: {source}")]
+    Corrupt { path: PathBuf, source: serde_json::Error },
+}
+
+/// A URL an interrupted run didn't get to finish
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Unfinished {
+    pub url: String,
+    /// Its `.part` file, if it got far enough to have one worth resuming
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub part: Option<PathBuf>,
+    /// Bytes already in the `.part` file
+    #[serde(default)]
+    pub offset: u64,
+}
+
+/// Where an interrupted run left off, so the whole batch can be picked up
+/// again instead of started over
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Session {
+    /// The URLs the run was started with, to recognise it when it's run again
+    pub urls: Vec<String>,
+    pub unfinished: Vec<Unfinished>,
+}
+
+impl Session {
+    /// What's left of a run started with `urls`, going by its report
+    pub fn from_entries(urls: Vec<String>, entries: &[Entry]) -> Self {
+        let unfinished = entries
+            .iter()
+            .filter(|entry| entry.status == Status::Failed)
+            .map(|entry| {
+                let part = entry
+                    .file
+                    .as_deref()
+                    .map(|file| partial::with_suffix(file, PART_SUFFIX))
+                    // So it can be found from wherever the session's continued
+                    .map(|part| std::path::absolute(&part).unwrap_or(part))
+                    .and_then(|part| PartFile::open(&part).ok().map(|opened| (part, opened.metadata.offset)));
+                Unfinished { url: entry.url.clone(), offset: part.as_ref().map_or(0, |(_, offset)| *offset), part: part.map(|(part, _)| part) }
+            })
+            .collect();
+        Session { urls, unfinished }
+    }
+
+    /// The session file for downloads saved in `dir` (or the current directory)
+    pub fn default_path(dir: Option<&Path>) -> PathBuf {
+        dir.unwrap_or(Path::new(".")).join(SESSION_FILE)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, SessionError> {
+        let contents = fs::read_to_string(path).map_err(|source| SessionError::Io { path: path.to_path_buf(), source })?;
+        serde_json::from_str(&contents).map_err(|source| SessionError::Corrupt { path: path.to_path_buf(), source })
+    }
+
+    /// The session at `path`, if it's one that was started with `urls`
+    pub fn detect(path: &Path, urls: &[String]) -> Option<Self> {
+        if !path.exists() {
+            return None;
+        }
+        match Session::load(path) {
+            Ok(session) if session.urls == urls => Some(session),
+            Ok(_) => {
+                debug!("{} is from a run with other URLs, not continuing it", path.display());
+                None
+            }
+            Err(e) => {
+                debug!("Not continuing a session: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Write the session via a temporary file, so it's never half-written
+    pub fn save(&self, path: &Path) -> Result<(), SessionError> {
+        let io_err = |source| SessionError::Io { path: path.to_path_buf(), source };
+        let contents = serde_json::to_string_pretty(self).expect("sessions are always serializable");
+        let temporary = partial::with_suffix(path, ".tmp");
+        fs::write(&temporary, contents).map_err(io_err)?;
+        fs::rename(&temporary, path).map_err(io_err)
+    }
+
+    /// The `.part` files that are still there to resume, and the URLs that
+    /// have to be downloaded from the start
+    pub fn split(self) -> (Vec<Unfinished>, Vec<String>) {
+        let (parts, fresh): (Vec<_>, Vec<_>) = self.unfinished.into_iter().partition(|unfinished| unfinished.part.as_ref().is_some_and(|part| part.exists()));
+        (parts, fresh.into_iter().map(|unfinished| unfinished.url).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session() {
+        let dir = std::env::temp_dir().join(format!("rustdl-session-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("b.iso");
+        let part = PartFile::create(&output, "https://example.com/b.iso", &Default::default(), Some(100), None).unwrap();
+        drop(part);
+
+        let urls = vec!["https://example.com/{a,b,c}.iso".to_string()];
+        let entries = [
+            Entry::new("https://example.com/a.iso", Status::Downloaded, Some(&dir.join("a.iso"))),
+            Entry::new("https://example.com/b.iso", Status::Failed, Some(&output)),
+            Entry::new("https://example.com/c.iso", Status::Failed, None),
+        ];
+        let session = Session::from_entries(urls.clone(), &entries);
+        let path = Session::default_path(Some(&dir));
+        session.save(&path).unwrap();
+        assert_eq!(Session::load(&path).unwrap(), session);
+        assert!(Session::detect(&path, &urls).is_some());
+        assert!(Session::detect(&path, &["https://example.com/a.iso".to_string()]).is_none());
+
+        let (parts, fresh) = session.split();
+        assert_eq!(parts, vec![Unfinished { url: "https://example.com/b.iso".to_string(), part: Some(dir.join("b.iso.part")), offset: 0 }]);
+        assert_eq!(fresh, vec!["https://example.com/c.iso"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,175 @@
+use std::path::PathBuf;
+
+use config::{Config, ConfigError, Environment, File, FileFormat, Map};
+use log::{debug, info};
+use serde::Deserialize;
+
+use crate::netload::NetworkShare;
+use crate::notify::NotifySettings;
+use crate::oauth::OAuthSettings;
+use crate::throttle::ByteRate;
+
+/// Name of the directory (under the XDG config home) holding our configuration
+const CONFIG_PREFIX: &str = "rustdl";
+
+/// Name of the configuration file inside the config directory
+const CONFIG_FILE: &str = "config.toml";
+
+/// Prefix for environment variables that override the configuration file
+const ENV_PREFIX: &str = "DOWNLOAD";
+
+/// Settings loaded from the user's configuration file and `DOWNLOAD_*`
+/// environment variables. The option defaults are overridden by the
+/// matching command-line flags.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Browser to take cookies from (`--browser`)
+    pub browser: Option<String>,
+    /// Directory to save downloads in (`--output-dir`)
+    pub output_dir: Option<PathBuf>,
+    /// Parallel requests in small-file mode (`--jobs`)
+    pub jobs: Option<usize>,
+    /// Proxy for every request (`--proxy`)
+    pub proxy: Option<String>,
+    /// Combined download rate limit (`--limit-rate`)
+    pub limit_rate: Option<ByteRate>,
+    /// Share of the network downloads may use (`--auto-throttle`)
+    pub auto_throttle: Option<NetworkShare>,
+    /// Progress bar template (`--progress-template`)
+    pub progress_template: Option<String>,
+    /// Progress bar characters (`--progress-chars`)
+    pub progress_chars: Option<String>,
+    /// Notifications sent when a batch completes or fails
+    pub notify: NotifySettings,
+    /// OAuth providers for `download auth login`
+    pub oauth: OAuthSettings,
+    /// Whether to remember downloads in the state database (`--no-state` turns it off)
+    pub state: Option<bool>,
+}
+
+impl Settings {
+    /// Load settings from `$XDG_CONFIG_HOME/rustdl/config.toml` if it exists,
+    /// with `DOWNLOAD_*` environment variables taking precedence over it
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::build(Self::config_path(), None)
+    }
+
+    /// Load settings from `DOWNLOAD_*` environment variables alone, ignoring any configuration file
+    pub fn from_environment() -> Result<Self, ConfigError> {
+        if let Some(path) = Self::config_path() {
+            info!("Ignoring {}, taking configuration from the environment only", path.display());
+        }
+        Self::build(None, None)
+    }
+
+    /// Layer the environment (or `environment`, in place of the real one) over a configuration file
+    fn build(path: Option<PathBuf>, environment: Option<Map<String, String>>) -> Result<Self, ConfigError> {
+        let mut builder = Config::builder();
+        match path {
+            Some(path) => {
+                debug!("Loading configuration from {}", path.display());
+                builder = builder.add_source(File::from(path).format(FileFormat::Toml));
+            }
+            None => debug!("No configuration file found, using defaults"),
+        }
+        // Nested settings use a double underscore, e.g. DOWNLOAD_NOTIFY__ON_FAILURE
+        let environment = Environment::with_prefix(ENV_PREFIX)
+            .prefix_separator("_")
+            .separator("__")
+            .source(environment);
+        builder.add_source(environment).build()?.try_deserialize()
+    }
+
+    /// Parse settings from a TOML string
+    #[cfg(test)]
+    pub fn from_toml(contents: &str) -> Result<Self, ConfigError> {
+        Config::builder()
+            .add_source(File::from_str(contents, FileFormat::Toml))
+            .build()?
+            .try_deserialize()
+    }
+
+    /// Find the configuration file, if the user has one
+    pub fn config_path() -> Option<PathBuf> {
+        xdg::BaseDirectories::with_prefix(CONFIG_PREFIX).find_config_file(CONFIG_FILE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::SinkConfig;
+
+    #[test]
+    fn test_settings_empty_file_uses_defaults() {
+        let settings = Settings::from_toml("").unwrap();
+        assert!(settings.notify.sinks.is_empty());
+        assert!(settings.notify.on_complete);
+        assert!(settings.notify.on_failure);
+    }
+
+    #[test]
+    fn test_settings_parses_notification_sinks() {
+        let settings = Settings::from_toml(r#"
+            [notify]
+            on_complete = false
+
+            [[notify.sinks]]
+            type = "slack"
+            webhook_url = "https://hooks.slack.com/services/T000/B000/XXXX"
+
+            [[notify.sinks]]
+            type = "matrix"
+            homeserver = "https://matrix.example.com"
+            room_id = "!room:example.com"
+            access_token = "secret"
+        "#).unwrap();
+
+        assert!(!settings.notify.on_complete);
+        assert!(settings.notify.on_failure);
+        assert_eq!(settings.notify.sinks.len(), 2);
+        assert!(matches!(settings.notify.sinks[0], SinkConfig::Slack { .. }));
+        assert!(matches!(settings.notify.sinks[1], SinkConfig::Matrix { .. }));
+    }
+
+    #[test]
+    fn test_environment_overrides_config_file() {
+        let path = std::env::temp_dir().join(format!("rustdl-settings-{}.toml", std::process::id()));
+        std::fs::write(&path, "browser = \"firefox\"\njobs = 4\nlimit_rate = 1000\n\n[notify]\non_failure = true\n").unwrap();
+        let environment = Map::from([
+            ("DOWNLOAD_BROWSER".to_string(), "chrome".to_string()),
+            ("DOWNLOAD_OUTPUT_DIR".to_string(), "/downloads".to_string()),
+            ("DOWNLOAD_PROXY".to_string(), "http://proxy:3128".to_string()),
+            ("DOWNLOAD_NOTIFY__ON_FAILURE".to_string(), "false".to_string()),
+            ("UNRELATED".to_string(), "ignored".to_string()),
+        ]);
+
+        let settings = Settings::build(Some(path.clone()), Some(environment)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(settings.browser.as_deref(), Some("chrome"));
+        assert_eq!(settings.output_dir, Some(PathBuf::from("/downloads")));
+        assert_eq!(settings.proxy.as_deref(), Some("http://proxy:3128"));
+        assert_eq!(settings.jobs, Some(4));
+        assert_eq!(settings.limit_rate, Some(ByteRate(1000)));
+        assert!(!settings.notify.on_failure);
+    }
+
+    #[test]
+    fn test_environment_values_are_validated() {
+        let environment = Map::from([("DOWNLOAD_LIMIT_RATE".to_string(), "fast".to_string())]);
+        assert!(Settings::build(None, Some(environment)).is_err());
+        let environment = Map::from([("DOWNLOAD_JOBS".to_string(), "many".to_string())]);
+        assert!(Settings::build(None, Some(environment)).is_err());
+    }
+
+    #[test]
+    fn test_settings_rejects_unknown_sink_type() {
+        let result = Settings::from_toml(r#"
+            [[notify.sinks]]
+            type = "carrier-pigeon"
+        "#);
+        assert!(result.is_err());
+    }
+}
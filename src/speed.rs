@@ -0,0 +1,150 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use indicatif::style::ProgressTracker;
+use indicatif::{BinaryBytes, DecimalBytes, FormattedDuration, HumanBytes, HumanDuration, ProgressState, ProgressStyle};
+
+/// How quickly the estimate follows changes in speed: a sample this old
+/// counts for about a third as much as a new one
+const SMOOTHING: Duration = Duration::from_secs(3);
+
+/// Samples closer together than this are too noisy to count
+const MIN_SAMPLE: Duration = Duration::from_millis(250);
+
+/// Download speed, smoothed with an exponentially weighted moving average so
+/// a bursty connection doesn't make it (and the ETA) jump around
+#[derive(Debug, Clone, Default)]
+pub struct SpeedEstimator {
+    /// The position and time of the last sample
+    last: Option<(u64, Instant)>,
+    /// Bytes per second, once there's been a sample
+    rate: Option<f64>,
+}
+
+impl SpeedEstimator {
+    /// Note that the download has got to `position` by `now`
+    pub fn record(&mut self, position: u64, now: Instant) {
+        let Some((last_position, last_time)) = self.last else {
+            self.last = Some((position, now));
+            return;
+        };
+        let elapsed = now.saturating_duration_since(last_time);
+        if elapsed < MIN_SAMPLE {
+            return;
+        }
+        if position < last_position {
+            // Started over
+            *self = SpeedEstimator { last: Some((position, now)), rate: None };
+            return;
+        }
+        let sample = (position - last_position) as f64 / elapsed.as_secs_f64();
+        // Weighting by time means it smooths the same however often it's sampled
+        let weight = 1.0 - (-elapsed.as_secs_f64() / SMOOTHING.as_secs_f64()).exp();
+        self.rate = Some(match self.rate {
+            Some(rate) => rate + weight * (sample - rate),
+            None => sample,
+        });
+        self.last = Some((position, now));
+    }
+
+    /// The smoothed speed, in bytes per second
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.rate.unwrap_or_default()
+    }
+
+    /// How long the `remaining` bytes should take at this speed; zero if we can't tell yet
+    pub fn eta(&self, remaining: u64) -> Duration {
+        match self.rate {
+            Some(rate) if rate > 0.0 => Duration::try_from_secs_f64(remaining as f64 / rate).unwrap_or(Duration::MAX),
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+/// A template key that shows a smoothed figure in place of indicatif's own
+#[derive(Debug, Clone, Copy)]
+enum Shown {
+    BytesPerSec,
+    BinaryBytesPerSec,
+    DecimalBytesPerSec,
+    Eta,
+    EtaPrecise,
+}
+
+/// Keeps a bar's `SpeedEstimator` up to date, and writes one of its keys
+#[derive(Debug, Clone)]
+struct SmoothedKey {
+    shown: Shown,
+    estimator: SpeedEstimator,
+}
+
+impl ProgressTracker for SmoothedKey {
+    fn clone_box(&self) -> Box<dyn ProgressTracker> {
+        Box::new(self.clone())
+    }
+
+    fn tick(&mut self, state: &ProgressState, now: Instant) {
+        self.estimator.record(state.pos(), now);
+    }
+
+    fn reset(&mut self, _: &ProgressState, _: Instant) {
+        self.estimator = SpeedEstimator::default();
+    }
+
+    fn write(&self, state: &ProgressState, w: &mut dyn fmt::Write) {
+        let rate = self.estimator.bytes_per_sec() as u64;
+        let eta = || self.estimator.eta(state.len().unwrap_or_default().saturating_sub(state.pos()));
+        let _ = match self.shown {
+            Shown::BytesPerSec => write!(w, "{}/s", HumanBytes(rate)),
+            Shown::BinaryBytesPerSec => write!(w, "{}/s", BinaryBytes(rate)),
+            Shown::DecimalBytesPerSec => write!(w, "{}/s", DecimalBytes(rate)),
+            Shown::Eta => write!(w, "{:#}", HumanDuration(eta())),
+            Shown::EtaPrecise => write!(w, "{}", FormattedDuration(eta())),
+        };
+    }
+}
+
+/// `style`, with its speed and ETA keys showing smoothed figures. Every bar
+/// given (a clone of) the style gets an estimator of its own.
+pub fn smoothed(style: ProgressStyle) -> ProgressStyle {
+    let key = |shown| SmoothedKey { shown, estimator: SpeedEstimator::default() };
+    style
+        .with_key("bytes_per_sec", key(Shown::BytesPerSec))
+        .with_key("binary_bytes_per_sec", key(Shown::BinaryBytesPerSec))
+        .with_key("decimal_bytes_per_sec", key(Shown::DecimalBytesPerSec))
+        .with_key("eta", key(Shown::Eta))
+        .with_key("eta_precise", key(Shown::EtaPrecise))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_speed_is_smoothed() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let mut estimator = SpeedEstimator::default();
+        estimator.record(0, at(0));
+        assert_eq!(estimator.bytes_per_sec(), 0.0);
+        assert_eq!(estimator.eta(1000), Duration::ZERO);
+
+        // A steady 1000 bytes a second
+        for second in 1..=10 {
+            estimator.record(second * 1000, at(second * 1000));
+        }
+        assert_eq!(estimator.bytes_per_sec(), 1000.0);
+        assert_eq!(estimator.eta(5000), Duration::from_secs(5));
+
+        // A burst moves the estimate, but nowhere near all the way
+        estimator.record(20_000, at(11_000));
+        assert!(estimator.bytes_per_sec() > 1000.0 && estimator.bytes_per_sec() < 5000.0);
+
+        // Samples too close together are ignored, and going backwards starts over
+        let rate = estimator.bytes_per_sec();
+        estimator.record(30_000, at(11_010));
+        assert_eq!(estimator.bytes_per_sec(), rate);
+        estimator.record(0, at(12_000));
+        assert_eq!(estimator.bytes_per_sec(), 0.0);
+    }
+}
@@ -0,0 +1,374 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use indicatif::{HumanBytes, ProgressStyle};
+use log::debug;
+use reqwest::blocking::{Client, Response};
+use reqwest::header::HeaderMap;
+use reqwest::{Method, StatusCode, Version, header};
+use serde::Serialize;
+use url::Url;
+
+use crate::auth_hook::AuthHook;
+use crate::cancel::{Cancellation, Cancelled};
+use crate::credentials::CredentialStore;
+use crate::exit_code::{ExitCode, Failure};
+use crate::logging;
+use crate::middleware::{DownloadContext, Middleware};
+use crate::naming::NamePolicy;
+use crate::negotiate::Negotiator;
+use crate::oauth::OAuthSettings;
+use crate::politeness::Politeness;
+use crate::progress::ProgressMode;
+use crate::report::Report;
+use crate::retry_after::RetryAfter;
+use crate::timeouts;
+
+/// What a URL would give us, found without downloading it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checked {
+    pub url: String,
+    pub status: StatusCode,
+    /// How it was asked: HEAD, or GET when the server wouldn't answer HEAD
+    pub method: Method,
+    pub size: Option<u64>,
+    pub content_type: Option<String>,
+    /// Where it would be saved
+    pub file: Option<PathBuf>,
+    /// Where the redirects ended up
+    pub final_url: String,
+    pub version: Version,
+    pub headers: HeaderMap,
+}
+
+impl Checked {
+    /// One line for the URL, like `https://example.com/a.iso: 200 OK, 1.2 GiB, application/x-iso9660-image, would be saved as a.iso`
+    pub fn line(&self) -> String {
+        let mut line = format!("{}: {}", self.url, self.status);
+        line.push_str(&match self.size {
+            Some(size) => format!(", {}", HumanBytes(size)),
+            None => ", size unknown".to_string(),
+        });
+        if let Some(content_type) = &self.content_type {
+            line.push_str(&format!(", {}", content_type));
+        }
+        if let Some(file) = &self.file {
+            line.push_str(&format!(", would be saved as {}", file.display()));
+        }
+        line
+    }
+
+    /// The status line and headers, as a server sends them, and a blank line after
+    pub fn head(&self) -> String {
+        let mut head = format!("{:?} {}\n", self.version, self.status);
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\n", name, String::from_utf8_lossy(value.as_bytes())));
+        }
+        head.push('\n');
+        head
+    }
+
+    /// The status line and headers as a line of JSON, each header with every value it was given
+    pub fn head_json(&self) -> String {
+        let mut headers: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for (name, value) in &self.headers {
+            headers.entry(name.as_str()).or_default().push(String::from_utf8_lossy(value.as_bytes()).into_owned());
+        }
+        let head = Head {
+            url: &self.url,
+            final_url: &self.final_url,
+            version: format!("{:?}", self.version),
+            status: self.status.as_u16(),
+            reason: self.status.canonical_reason(),
+            headers,
+        };
+        serde_json::to_string(&head).expect("headers serialize as JSON")
+    }
+}
+
+/// One URL's response headers, for `--headers=json`
+#[derive(Serialize)]
+struct Head<'a> {
+    url: &'a str,
+    final_url: &'a str,
+    version: String,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'static str>,
+    headers: BTreeMap<&'a str, Vec<String>>,
+}
+
+/// How `--headers` prints them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HeaderFormat {
+    /// The way the server sent them
+    #[default]
+    Text,
+    /// A JSON object a line
+    Json,
+}
+
+impl HeaderFormat {
+    /// Get all supported formats
+    pub fn all() -> Vec<HeaderFormat> {
+        vec![HeaderFormat::Text, HeaderFormat::Json]
+    }
+
+    /// Get the string representation of the format
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HeaderFormat::Text => "text",
+            HeaderFormat::Json => "json",
+        }
+    }
+}
+
+impl fmt::Display for HeaderFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for HeaderFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        HeaderFormat::all()
+            .into_iter()
+            .find(|format| format.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                format!(
+                    "Unknown header format '{}'. Available formats: {}",
+                    s,
+                    HeaderFormat::all().iter().map(|f| f.as_str()).collect::<Vec<_>>().join(", ")
+                )
+            })
+    }
+}
+
+/// Checks URLs the way they'd be downloaded, cookies, credentials and all,
+/// but only asks for their headers (`--spider`): a HEAD request, or a GET
+/// whose body is never read when the server won't answer HEAD
+pub struct Spider<'a> {
+    pub client: &'a Client,
+    pub middleware: &'a Middleware,
+    pub credentials: Option<&'a CredentialStore>,
+    pub auth_hook: Option<&'a AuthHook>,
+    pub oauth: &'a OAuthSettings,
+    pub negotiator: Option<&'a Negotiator>,
+    pub header_timeout: Option<Duration>,
+    pub retry_after: RetryAfter,
+    /// Works out where each URL would be saved
+    pub naming: &'a dyn NamePolicy,
+    pub politeness: &'a Arc<Politeness>,
+    pub report: &'a Report,
+    pub progress: ProgressMode,
+    pub jobs: usize,
+    pub cancellation: &'a Cancellation,
+    /// Print each response's headers on stdout instead of a line about it (`--headers`)
+    pub headers: Option<HeaderFormat>,
+}
+
+impl Spider<'_> {
+    /// Check every URL, printing a line or its headers for each, and return how many failed
+    pub fn run(&self, urls: Vec<String>) -> usize {
+        let style = ProgressStyle::with_template("{prefix:.blue} {wide_bar:.blue/white} {pos}/{len} URLs • elapsed {elapsed:>4.cyan}  ")
+            .unwrap()
+            .progress_chars("━╸━");
+        let pb = self.progress.bar(urls.len() as u64).with_style(style).with_prefix("checking");
+        let jobs = self.jobs.clamp(1, urls.len().max(1));
+        let queue = Mutex::new(urls.into_iter().enumerate().collect::<VecDeque<_>>());
+        let failed = AtomicUsize::new(0);
+        // Which header block is which, when there's more than one
+        let label = self.headers == Some(HeaderFormat::Text) && queue.lock().unwrap().len() > 1;
+        thread::scope(|scope| {
+            for _ in 0..jobs {
+                scope.spawn(|| {
+                    while let Some(((index, url), _permit)) = self.politeness.next(&queue, |(_, url)| url) {
+                        let result = match self.cancellation.reason() {
+                            Some(reason) => Err(Failure::from(Cancelled(reason).to_string())),
+                            None => self.check(&url, index),
+                        };
+                        if let (Ok(checked), Some(format)) = (&result, self.headers) {
+                            let head = match format {
+                                HeaderFormat::Text if label => format!("==> {} <==\n{}", url, checked.head()),
+                                HeaderFormat::Text => checked.head(),
+                                HeaderFormat::Json => format!("{}\n", checked.head_json()),
+                            };
+                            // Headers are the output, so they go to stdout whatever the progress bars are doing
+                            pb.suspend(|| print!("{}", head));
+                        }
+                        match result {
+                            Ok(checked) if checked.status.is_client_error() || checked.status.is_server_error() => {
+                                let error = format!("{}: server returned {}", url, checked.status);
+                                failed.fetch_add(1, Ordering::Relaxed);
+                                self.progress.error(&pb, format!("[error] {}", error));
+                                self.report.failed(&url, None, Some(checked.status.as_u16()), error);
+                            }
+                            Ok(checked) => {
+                                if self.headers.is_none() {
+                                    self.progress.println(&pb, checked.line());
+                                }
+                                self.report.checked(&checked);
+                            }
+                            Err(failure) => {
+                                failed.fetch_add(1, Ordering::Relaxed);
+                                self.progress.error(&pb, format!("[error] {}", failure));
+                                self.report.failed(&url, None, None, failure);
+                            }
+                        }
+                        pb.inc(1);
+                    }
+                });
+            }
+        });
+        let failed = failed.into_inner();
+        self.progress.finish(&pb, None, pb.style(), format!("{} URLs checked, {} failed", pb.position(), failed));
+        failed
+    }
+
+    /// Ask for `url`'s headers (it's the `index`th URL), and work out what
+    /// we'd save; the server saying no is for the caller to fail on
+    pub fn check(&self, url: &str, index: usize) -> Result<Checked, Failure> {
+        let parsed_url = Url::parse(url).map_err(|e| format!("{}: {}", url, e))?;
+        let mut method = Method::HEAD;
+        let mut response = self.send(&parsed_url, &method)?;
+        // Some servers don't do HEAD, and presigned URLs are only signed for GET
+        if matches!(response.status(), StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED | StatusCode::FORBIDDEN) {
+            debug!("{} answered HEAD with {}, trying GET", url, response.status());
+            method = Method::GET;
+            response = self.send(&parsed_url, &method)?;
+        }
+        self.middleware.intercept(&response, &DownloadContext { method: &method, url }).map_err(|e| format!("{}: {}", url, e))?;
+        let final_url = response.url().clone();
+        let file = self
+            .naming
+            .server_name(response.headers())
+            .or_else(|| self.naming.url_name(&final_url))
+            .and_then(|name| self.naming.place(&final_url, &name, index));
+        Ok(Checked {
+            url: url.to_string(),
+            status: response.status(),
+            size: size(&response),
+            content_type: response.headers().get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).map(str::to_string),
+            method,
+            file,
+            final_url: final_url.to_string(),
+            version: response.version(),
+            headers: response.headers().clone(),
+        })
+    }
+
+    fn send(&self, url: &Url, method: &Method) -> Result<Response, Failure> {
+        let mut request = self.middleware.request(self.client, method.clone(), url.as_str());
+        if let Some(store) = self.credentials {
+            request = store.authorize(request, url, self.client, self.oauth);
+        }
+        if let Some(hook) = self.auth_hook {
+            request = request.headers(hook.headers(method.as_str(), url.as_str()).map_err(|e| format!("{}: {}", url, e))?);
+        }
+        let request = request.build().map_err(|e| e.to_string())?;
+        logging::request(&request);
+        self.retry_after
+            .send(request, self.cancellation, self.report, |_, _| {}, |request| match self.negotiator {
+                Some(negotiator) => negotiator.execute(self.client, request, self.header_timeout),
+                None => timeouts::execute(self.client, request, self.header_timeout),
+            })
+            .map_err(|e| Failure::new(ExitCode::Network, e.to_string()))
+    }
+}
+
+/// The size of the file, from `Content-Length` (the body of a GET we don't
+/// read is still the whole file)
+fn size(response: &Response) -> Option<u64> {
+    response.headers().get(header::CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::TimeZone;
+    use crate::naming::StandardNames;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_check_falls_back_to_get() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let replies = [
+                "HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\n\r\n",
+                "HTTP/1.1 200 OK\r\nContent-Type: application/x-iso9660-image\r\nContent-Length: 1234\r\n\r\n",
+            ];
+            let mut methods = Vec::new();
+            for reply in replies {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0u8; 1024];
+                let read = stream.read(&mut request).unwrap();
+                methods.push(String::from_utf8_lossy(&request[..read]).split(' ').next().unwrap_or_default().to_string());
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+            methods
+        });
+
+        let client = Client::builder().pool_max_idle_per_host(0).build().unwrap();
+        let spider = Spider {
+            client: &client,
+            middleware: &Middleware::default(),
+            credentials: None,
+            auth_hook: None,
+            oauth: &OAuthSettings::default(),
+            negotiator: None,
+            header_timeout: None,
+            retry_after: RetryAfter::default(),
+            naming: &StandardNames::default(),
+            politeness: &Arc::default(),
+            report: &Report::new(None, None, None, TimeZone::Utc),
+            progress: ProgressMode::Bars,
+            jobs: 1,
+            cancellation: &Cancellation::default(),
+            headers: None,
+        };
+        let url = format!("http://{}/os.iso", address);
+        let checked = spider.check(&url, 0).unwrap();
+        assert_eq!(server.join().unwrap(), ["HEAD", "GET"]);
+        assert_eq!(checked.method, Method::GET);
+        assert_eq!(checked.status, StatusCode::OK);
+        assert_eq!(checked.size, Some(1234));
+        assert_eq!(checked.content_type.as_deref(), Some("application/x-iso9660-image"));
+        assert_eq!(checked.file, Some(PathBuf::from("os.iso")));
+        assert_eq!(checked.line(), format!("{}: 200 OK, 1.21 KiB, application/x-iso9660-image, would be saved as os.iso", url));
+    }
+
+    #[test]
+    fn test_head() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, "text/html".parse().unwrap());
+        headers.append(header::SET_COOKIE, "a=1".parse().unwrap());
+        headers.append(header::SET_COOKIE, "b=2".parse().unwrap());
+        let checked = Checked {
+            url: "https://example.com/a.iso".to_string(),
+            status: StatusCode::OK,
+            method: Method::HEAD,
+            size: None,
+            content_type: Some("text/html".to_string()),
+            file: None,
+            final_url: "https://example.com/login".to_string(),
+            version: Version::HTTP_11,
+            headers,
+        };
+        assert_eq!(checked.head(), "HTTP/1.1 200 OK\ncontent-type: text/html\nset-cookie: a=1\nset-cookie: b=2\n\n");
+        assert_eq!(
+            checked.head_json(),
+            r#"{"url":"https://example.com/a.iso","final_url":"https://example.com/login","version":"HTTP/1.1","status":200,"reason":"OK","headers":{"content-type":["text/html"],"set-cookie":["a=1","b=2"]}}"#
+        );
+    }
+}
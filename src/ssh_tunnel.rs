@@ -0,0 +1,137 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+
+use crate::resolver::AddressFamily;
+
+/// How long to wait for ssh to log in and start forwarding; long enough to
+/// type a password or touch a security key
+const READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often to check whether the tunnel is up yet
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Errors from setting up an SSH tunnel
+#[derive(Debug, thiserror::Error)]
+pub enum TunnelError {
+    #[error("could not run ssh: {source} (is it installed?)")]
+    Spawn { source: io::Error },
+
+    #[error("could not find a free local port for the tunnel: {0}")]
+    Port(io::Error),
+
+    #[error("ssh to {destination} failed ({status})")]
+    Exited { destination: String, status: ExitStatus },
+
+    #[error("ssh to {destination} wasn't forwarding after {}", humantime::format_duration(*timeout))]
+    Timeout { destination: String, timeout: Duration },
+}
+
+/// The ssh process behind the tunnel, so it can be stopped however we exit
+static SSH: Mutex<Option<Child>> = Mutex::new(None);
+
+/// A SOCKS proxy through a jump host, run by `ssh -D`: requests sent through
+/// it are made from the jump host, and host names are looked up there too
+#[derive(Debug)]
+pub struct SshTunnel {
+    port: u16,
+}
+
+impl SshTunnel {
+    /// Log in to `destination` (anything ssh accepts, like `user@bastion` or
+    /// `ssh://user@bastion:2222`) and wait until the tunnel is ready; with a
+    /// `family`, ssh only reaches the jump host over that
+    pub fn open(destination: &str, family: Option<AddressFamily>) -> Result<Self, TunnelError> {
+        // ssh doesn't say which port it picked, so pick one for it
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).and_then(|listener| listener.local_addr()).map_err(TunnelError::Port)?.port();
+        info!("Opening SSH tunnel through {}", destination);
+        let mut child = command(destination, port, family).spawn().map_err(|source| TunnelError::Spawn { source })?;
+        let started = Instant::now();
+        let address = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        loop {
+            if let Ok(Some(status)) = child.try_wait() {
+                return Err(TunnelError::Exited { destination: destination.to_string(), status });
+            }
+            if TcpStream::connect_timeout(&address, POLL_INTERVAL).is_ok() {
+                break;
+            }
+            if started.elapsed() > READY_TIMEOUT {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(TunnelError::Timeout { destination: destination.to_string(), timeout: READY_TIMEOUT });
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        debug!("SSH tunnel through {} is listening on port {}", destination, port);
+        *SSH.lock().unwrap() = Some(child);
+        #[cfg(unix)]
+        {
+            // process::exit doesn't run destructors, but it does run these
+            extern "C" fn close_at_exit() {
+                if let Ok(mut ssh) = SSH.try_lock() {
+                    close(&mut ssh);
+                }
+            }
+            unsafe { libc::atexit(close_at_exit) };
+        }
+        Ok(SshTunnel { port })
+    }
+
+    /// The proxy to send requests through; `socks5h` so names that only
+    /// resolve inside the private network are looked up on the far side
+    pub fn proxy(&self) -> String {
+        format!("socks5h://127.0.0.1:{}", self.port)
+    }
+}
+
+impl Drop for SshTunnel {
+    fn drop(&mut self) {
+        close(&mut SSH.lock().unwrap());
+    }
+}
+
+/// Stop ssh, if it's still running
+fn close(ssh: &mut Option<Child>) {
+    if let Some(mut child) = ssh.take() {
+        debug!("Closing SSH tunnel");
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+/// `ssh -D`, forwarding nothing but the SOCKS port, and giving up rather than
+/// carrying on without it
+fn command(destination: &str, port: u16, family: Option<AddressFamily>) -> Command {
+    let mut command = Command::new("ssh");
+    command.args(family.map(AddressFamily::flag));
+    command
+        .args(["-N", "-D", &format!("127.0.0.1:{}", port)])
+        .args(["-o", "ExitOnForwardFailure=yes", "-o", "ServerAliveInterval=30"])
+        .arg(destination)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null());
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ssh_command() {
+        let command = command("alice@bastion.example.com", 1080, None);
+        assert_eq!(command.get_program(), "ssh");
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            ["-N", "-D", "127.0.0.1:1080", "-o", "ExitOnForwardFailure=yes", "-o", "ServerAliveInterval=30", "alice@bastion.example.com"]
+        );
+        assert_eq!(SshTunnel { port: 1080 }.proxy(), "socks5h://127.0.0.1:1080");
+        let command = super::command("alice@bastion.example.com", 1080, Some(AddressFamily::V6));
+        assert_eq!(command.get_args().next().unwrap(), "-6");
+    }
+}
@@ -0,0 +1,303 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::debug;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+/// Name of the database inside the XDG data directory
+const DATABASE_FILE: &str = "state.sqlite3";
+
+/// How long to wait for another download that's writing to the database
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long downloads are remembered for after they were last touched
+const RETENTION: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS downloads (
+        id INTEGER PRIMARY KEY,
+        url TEXT NOT NULL,
+        path TEXT,
+        offset INTEGER NOT NULL DEFAULT 0,
+        etag TEXT,
+        status TEXT NOT NULL,
+        error TEXT,
+        started INTEGER NOT NULL,
+        updated INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS downloads_url ON downloads (url);
+";
+
+const COLUMNS: &str = "url, path, offset, etag, status, error, updated";
+
+/// Errors from the download state database
+#[derive(Debug, thiserror::Error)]
+pub enum StateError {
+    #[error("could not determine where to keep the download state")]
+    NoDataDir,
+
+    #[error("could not create {path}: {source}")]
+    Io { path: PathBuf, source: std::io::Error },
+
+    #[error("download state: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+/// Where a download got to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadStatus {
+    /// Started; if nothing's downloading it now, it was killed partway
+    Downloading,
+    Done,
+    Failed,
+}
+
+impl DownloadStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DownloadStatus::Downloading => "downloading",
+            DownloadStatus::Done => "done",
+            DownloadStatus::Failed => "failed",
+        }
+    }
+
+    fn parse(s: &str) -> Self {
+        match s {
+            "done" => DownloadStatus::Done,
+            "downloading" => DownloadStatus::Downloading,
+            _ => DownloadStatus::Failed,
+        }
+    }
+}
+
+/// One download, as remembered between runs
+#[derive(Debug, Clone, PartialEq)]
+pub struct Download {
+    /// Without any credentials, and with its query string hashed
+    pub url: String,
+    /// Where it was saved, or was being saved; missing if it failed before it had a name
+    pub path: Option<PathBuf>,
+    /// Bytes safely on disk: the whole file once it's done, or where a resume would start
+    pub offset: u64,
+    pub etag: Option<String>,
+    pub status: DownloadStatus,
+    pub error: Option<String>,
+    pub updated: SystemTime,
+}
+
+impl Download {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Download {
+            url: row.get(0)?,
+            path: row.get::<_, Option<String>>(1)?.map(PathBuf::from),
+            offset: row.get(2)?,
+            etag: row.get(3)?,
+            status: DownloadStatus::parse(&row.get::<_, String>(4)?),
+            error: row.get(5)?,
+            updated: UNIX_EPOCH + Duration::from_secs(row.get(6)?),
+        })
+    }
+}
+
+/// Every download of the last 90 days, in SQLite, so an interrupted one can
+/// be found again, a finished one needn't be fetched twice, and `download
+/// history` can list them. Query strings often carry signatures and tokens,
+/// so only a hash of them is kept, which still tells URLs apart.
+#[derive(Debug)]
+pub struct StateDb {
+    connection: Mutex<Connection>,
+}
+
+impl StateDb {
+    /// Default location of the database
+    pub fn default_path() -> Result<PathBuf, StateError> {
+        xdg::BaseDirectories::with_prefix("rustdl")
+            .get_data_home()
+            .map(|dir| dir.join(DATABASE_FILE))
+            .ok_or(StateError::NoDataDir)
+    }
+
+    /// Open the database at the default location
+    pub fn open_default() -> Result<Self, StateError> {
+        Self::open(&Self::default_path()?)
+    }
+
+    /// Open the database at `path`, creating it if need be
+    pub fn open(path: &Path) -> Result<Self, StateError> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|source| StateError::Io { path: parent.to_path_buf(), source })?;
+        }
+        let connection = Connection::open(path)?;
+        connection.busy_timeout(BUSY_TIMEOUT)?;
+        // Other downloads can read while one writes
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.execute_batch(SCHEMA)?;
+        let forgotten = connection.execute("DELETE FROM downloads WHERE updated < ?1", [now().saturating_sub(RETENTION.as_secs())])?;
+        debug!("Keeping download state in {} (forgot {} old downloads)", path.display(), forgotten);
+        Ok(StateDb { connection: Mutex::new(connection) })
+    }
+
+    /// Note that `url` has started downloading to `path`
+    pub fn started(&self, url: &str, path: &Path, etag: Option<&str>) -> Result<(), StateError> {
+        let now = now();
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO downloads (url, path, etag, status, started, updated) VALUES (?1, ?2, ?3, 'downloading', ?4, ?4)",
+            params![redact(url), absolute(path).to_string_lossy(), etag, now],
+        )?;
+        Ok(())
+    }
+
+    /// Note that `offset` bytes of `url` are safely on disk, so a download
+    /// that's killed can be resumed from there
+    pub fn progress(&self, url: &str, offset: u64) -> Result<(), StateError> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE downloads SET offset = ?2, updated = ?3 WHERE id = (SELECT max(id) FROM downloads WHERE url = ?1 AND status = 'downloading')",
+            params![redact(url), offset, now()],
+        )?;
+        Ok(())
+    }
+
+    /// Note that `url` was saved, whole, as `path`. What's recorded is the
+    /// size it ended up on disk, which is what it's checked against later,
+    /// whatever was decoded or decompressed on the way
+    pub fn finished(&self, url: &str, path: &Path) -> Result<(), StateError> {
+        let size = fs::metadata(path).map_or(0, |metadata| metadata.len());
+        self.settle(url, Some(path), size, DownloadStatus::Done, None)
+    }
+
+    /// Note that `url` failed, leaving `offset` bytes that could be resumed
+    pub fn failed(&self, url: &str, path: Option<&Path>, offset: u64, error: &str) -> Result<(), StateError> {
+        self.settle(url, path, offset, DownloadStatus::Failed, Some(error))
+    }
+
+    /// Finish the latest download of `url` that was under way, or record one
+    /// that never got that far
+    fn settle(&self, url: &str, path: Option<&Path>, offset: u64, status: DownloadStatus, error: Option<&str>) -> Result<(), StateError> {
+        let (url, now, path) = (redact(url), now(), path.map(|path| absolute(path).to_string_lossy().into_owned()));
+        let connection = self.connection.lock().unwrap();
+        let updated = connection.execute(
+            "UPDATE downloads SET path = coalesce(?2, path), offset = ?3, status = ?4, error = ?5, updated = ?6
+             WHERE id = (SELECT max(id) FROM downloads WHERE url = ?1 AND status = 'downloading')",
+            params![url, path, offset, status.as_str(), error, now],
+        )?;
+        if updated == 0 {
+            connection.execute(
+                "INSERT INTO downloads (url, path, offset, status, error, started, updated) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)",
+                params![url, path, offset, status.as_str(), error, now],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The latest `limit` downloads, most recent first
+    pub fn history(&self, limit: usize) -> Result<Vec<Download>, StateError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(&format!("SELECT {} FROM downloads ORDER BY id DESC LIMIT ?1", COLUMNS))?;
+        let downloads = statement.query_map([limit as i64], Download::from_row)?.collect::<Result<_, _>>()?;
+        Ok(downloads)
+    }
+
+    /// Files whose latest download didn't finish, oldest first
+    pub fn unfinished(&self) -> Result<Vec<Download>, StateError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(&format!(
+            "SELECT {} FROM downloads WHERE id IN (SELECT max(id) FROM downloads WHERE path IS NOT NULL GROUP BY path) AND status != 'done' ORDER BY id",
+            COLUMNS
+        ))?;
+        let downloads = statement.query_map([], Download::from_row)?.collect::<Result<_, _>>()?;
+        Ok(downloads)
+    }
+
+    /// Where `url` was last saved, if the file's still there and the size it
+    /// was (or for an archive that was extracted, the directory's still there)
+    pub fn downloaded(&self, url: &str) -> Result<Option<PathBuf>, StateError> {
+        let connection = self.connection.lock().unwrap();
+        let download = connection
+            .query_row(
+                &format!("SELECT {} FROM downloads WHERE url = ?1 AND status = 'done' ORDER BY id DESC LIMIT 1", COLUMNS),
+                [redact(url)],
+                Download::from_row,
+            )
+            .optional()?;
+        let unchanged = |path: &PathBuf, offset| fs::metadata(path).is_ok_and(|metadata| metadata.is_dir() || metadata.len() == offset);
+        Ok(download.and_then(|download| download.path.filter(|path| unchanged(path, download.offset))))
+    }
+}
+
+/// `url` as it's kept: without a user name or password, and with a hash of
+/// its query string in place of the query string itself
+fn redact(url: &str) -> String {
+    let Ok(mut url) = Url::parse(url) else { return url.to_string() };
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+    url.set_fragment(None);
+    if let Some(query) = url.query().map(|query| hex::encode(&Sha256::digest(query)[..8])) {
+        url.set_query(Some(&format!("sha256={}", query)));
+    }
+    url.into()
+}
+
+/// Paths are kept whole, so they mean the same thing wherever `download` runs next
+fn absolute(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_db() {
+        let dir = std::env::temp_dir().join(format!("rustdl-state-{}", std::process::id()));
+        let db = StateDb::open(&dir.join(DATABASE_FILE)).unwrap();
+        let (a, b) = (dir.join("a.iso"), dir.join("b.iso"));
+        fs::write(&a, "0123456789").unwrap();
+        db.started("https://example.com/a.iso", &a, Some("\"v1\"")).unwrap();
+        db.started("https://example.com/b.iso", &b, None).unwrap();
+        db.finished("https://example.com/a.iso", &a).unwrap();
+        db.failed("https://example.com/b.iso", None, 4096, "connection reset").unwrap();
+        db.failed("https://example.com/c.iso", None, 0, "404 Not Found").unwrap();
+
+        assert_eq!(db.downloaded("https://example.com/a.iso").unwrap(), Some(a.clone()));
+        assert_eq!(db.downloaded("https://example.com/b.iso").unwrap(), None);
+        let unfinished = db.unfinished().unwrap();
+        assert_eq!(unfinished.len(), 1);
+        assert_eq!((unfinished[0].path.as_deref(), unfinished[0].offset, unfinished[0].error.as_deref()), (Some(b.as_path()), 4096, Some("connection reset")));
+        let history = db.history(2).unwrap();
+        assert_eq!((history[0].url.as_str(), history[0].status), ("https://example.com/c.iso", DownloadStatus::Failed));
+        assert_eq!((history[1].etag.as_deref(), history[1].status), (None, DownloadStatus::Failed));
+
+        // A file that's changed since isn't the one we downloaded
+        fs::write(&a, "012345678").unwrap();
+        assert_eq!(db.downloaded("https://example.com/a.iso").unwrap(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_state_db_keeps_no_secrets() {
+        let dir = std::env::temp_dir().join(format!("rustdl-state-secrets-{}", std::process::id()));
+        let db = StateDb::open(&dir.join(DATABASE_FILE)).unwrap();
+        let (signed, other) = ("https://user:pw@example.com/a.iso?X-Amz-Signature=abc", "https://example.com/a.iso?X-Amz-Signature=def");
+        let a = dir.join("a.iso");
+        db.started(signed, &a, None).unwrap();
+        db.progress(signed, 4096).unwrap();
+        assert_eq!(db.unfinished().unwrap()[0].offset, 4096);
+
+        // Decompressed, say, so it's a different size to what was downloaded
+        fs::write(&a, "0123456789").unwrap();
+        db.finished(signed, &a).unwrap();
+        assert_eq!(db.downloaded(signed).unwrap(), Some(a.clone()));
+        assert_eq!(db.downloaded(other).unwrap(), None);
+        let url = &db.history(1).unwrap()[0].url;
+        assert!(url.starts_with("https://example.com/a.iso?sha256=") && !url.contains("abc"), "{}", url);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
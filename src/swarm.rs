@@ -0,0 +1,582 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use indicatif::{HumanBytes, ProgressBar};
+use log::{debug, info, warn};
+use reqwest::StatusCode;
+use reqwest::blocking::Client;
+use reqwest::header::{self, HeaderMap};
+
+use crate::cancel::{Cancellation, Cancelled};
+use crate::checksum::{self, Checksum, ChecksumError, ChecksumPlan, HashAlgorithm};
+use crate::middleware::Middleware;
+use crate::partial::{self, PartFile};
+use crate::preallocate;
+use crate::throttle::{self, MinimumSpeed, RateLimiter};
+use crate::timeouts;
+
+/// Segments per source, so faster mirrors can take on more of the file
+const SEGMENTS_PER_SOURCE: u64 = 4;
+
+/// The smallest segment worth a request of its own
+const MIN_SEGMENT: u64 = 1024 * 1024;
+
+/// How long an idle source waits before checking for segments other sources gave up on
+const IDLE_WAIT: Duration = Duration::from_millis(100);
+
+/// How many segments a source can send that don't match their digests before it's given up on
+const MAX_CORRUPT: usize = 2;
+
+/// Errors from downloading a file from several sources at once
+#[derive(Debug, thiserror::Error)]
+pub enum SwarmError {
+    #[error("could not write {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("none of the sources can be used: {0}")]
+    NoSources(String),
+
+    #[error("every source failed before the download was complete (last error: {0})")]
+    AllFailed(String),
+
+    #[error("{path}: {source}")]
+    Checksum { path: PathBuf, source: ChecksumError },
+
+    #[error(transparent)]
+    Cancelled(#[from] Cancelled),
+}
+
+/// A byte range of the file still to be fetched, end exclusive
+type Segment = (u64, u64);
+
+/// A range of the file that's been written, and the source it came from
+struct Piece {
+    segment: Segment,
+    source: String,
+}
+
+/// What the sources share: the segments nobody is fetching yet
+struct Work {
+    queue: Mutex<VecDeque<Segment>>,
+    /// Segments not yet written, including those being fetched right now
+    remaining: AtomicUsize,
+    pieces: Mutex<Vec<Piece>>,
+    last_error: Mutex<String>,
+}
+
+/// Why a segment didn't get written
+enum SegmentError {
+    /// The source failed, after sending however much was written
+    Failed(String),
+    /// It was all sent, but doesn't match the digest the server gave for it
+    Corrupt(String),
+}
+
+impl From<String> for SegmentError {
+    fn from(message: String) -> Self {
+        SegmentError::Failed(message)
+    }
+}
+
+/// Download the same file from every one of `sources` at once, each fetching
+/// different parts of it, into `path`. Sources that disagree about the file's
+/// size are left out, and when a source fails its unfinished parts go to the
+/// others. The file is checked against `checksums` before it's given its
+/// name; returns its size and the digests computed.
+#[allow(clippy::too_many_arguments)]
+pub fn download(
+    client: &Client,
+    middleware: &Middleware,
+    sources: &[String],
+    path: &Path,
+    header_timeout: Option<Duration>,
+    pb: &ProgressBar,
+    limiter: Option<Arc<RateLimiter>>,
+    min_speed: Option<MinimumSpeed>,
+    cancellation: &Cancellation,
+    checksums: &ChecksumPlan,
+) -> Result<(u64, Vec<Checksum>), SwarmError> {
+    let (length, sources) = usable_sources(client, middleware, sources, header_timeout)?;
+    debug!("Downloading {} from {} sources", HumanBytes(length), sources.len());
+    pb.set_length(length);
+
+    let partial = partial::with_suffix(path, partial::PART_SUFFIX);
+    let io_err = |source| SwarmError::Io { path: partial.clone(), source };
+    let file = File::create(&partial).map_err(io_err)?;
+    if let Err(e) = preallocate::preallocate(&file, length) {
+        drop(file);
+        let _ = fs::remove_file(&partial);
+        return Err(io_err(e));
+    }
+    file.set_len(length).map_err(io_err)?;
+    drop(file);
+
+    let fetch = Fetch { client, middleware, partial: &partial, header_timeout, pb, limiter, min_speed, cancellation, written: Mutex::default() };
+    let result = fetch.all(&sources, segments(length, sources.len())).and_then(|pieces| fetch.verify(path, &sources, pieces, checksums));
+    match result {
+        Ok(actual) => {
+            fs::rename(&partial, path).map_err(|source| SwarmError::Io { path: path.to_path_buf(), source })?;
+            Ok((length, actual))
+        }
+        Err(SwarmError::Cancelled(cancelled)) => {
+            // Only what's unbroken from the start can be resumed, from a single source
+            match cancellation.keep(&partial) {
+                true => match PartFile::adopt(path, &sources[0], fetch.resumable(), length) {
+                    Ok(part) => info!("Saved partial download at {} bytes; finish it with `download resume`", part.metadata.offset),
+                    Err(e) => warn!("Could not record partial download progress: {}", e),
+                },
+                false => {
+                    let _ = fs::remove_file(&partial);
+                }
+            }
+            Err(cancelled.into())
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            Err(e)
+        }
+    }
+}
+
+/// Ask each source how big the file is, keeping those that agree with the
+/// first source to answer
+fn usable_sources(client: &Client, middleware: &Middleware, sources: &[String], header_timeout: Option<Duration>) -> Result<(u64, Vec<String>), SwarmError> {
+    let probes: Vec<Result<u64, String>> = thread::scope(|scope| {
+        let handles: Vec<_> = sources
+            .iter()
+            .map(|source| scope.spawn(move || probe(client, middleware, source, header_timeout)))
+            .collect();
+        handles.into_iter().map(|handle| handle.join().unwrap_or_else(|_| Err("probe panicked".to_string()))).collect()
+    });
+
+    let mut length = None;
+    let mut usable = Vec::new();
+    let mut errors = Vec::new();
+    for (source, probe) in sources.iter().zip(probes) {
+        match (probe, length) {
+            (Ok(size), None) => {
+                length = Some(size);
+                usable.push(source.clone());
+            }
+            (Ok(size), Some(expected)) if size == expected => usable.push(source.clone()),
+            (Ok(size), Some(expected)) => {
+                warn!("Not using {}: it has a {} file, not {}", source, size, expected);
+                errors.push(format!("{}: size {} instead of {}", source, size, expected));
+            }
+            (Err(e), _) => {
+                warn!("Not using {}: {}", source, e);
+                errors.push(format!("{}: {}", source, e));
+            }
+        }
+    }
+    match length {
+        Some(length) => Ok((length, usable)),
+        None => Err(SwarmError::NoSources(errors.join("; "))),
+    }
+}
+
+/// The size of the file at `url`, if the server will send us parts of it
+fn probe(client: &Client, middleware: &Middleware, url: &str, header_timeout: Option<Duration>) -> Result<u64, String> {
+    let request = middleware
+        .get(client, url)
+        .header(header::RANGE, "bytes=0-0")
+        .header(header::ACCEPT_ENCODING, "identity")
+        .build()
+        .map_err(|e| e.to_string())?;
+    let response = timeouts::execute(client, request, header_timeout).map_err(|e| e.to_string())?;
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        return Err(format!("server returned {} instead of part of the file (it doesn't support ranges)", response.status()));
+    }
+    response
+        .headers()
+        .get(header::CONTENT_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|range| range.rsplit_once('/'))
+        .and_then(|(_, total)| total.parse().ok())
+        .filter(|length| *length > 0)
+        .ok_or_else(|| "server didn't say how big the file is".to_string())
+}
+
+/// Split a file of `length` bytes into segments for `sources` sources to share
+fn segments(length: u64, sources: usize) -> Vec<Segment> {
+    let size = (length / (sources as u64 * SEGMENTS_PER_SOURCE).max(1)).max(MIN_SEGMENT);
+    (0..length).step_by(size as usize).map(|start| (start, (start + size).min(length))).collect()
+}
+
+/// The digest a server gave for the bytes it sent: `Content-Digest` (RFC
+/// 9530) or the older `Content-MD5`, both of which are for just the range in
+/// a 206 response
+fn range_digest(headers: &HeaderMap) -> Option<Checksum> {
+    let content_digest = headers
+        .get("content-digest")
+        .and_then(|value| value.to_str().ok())
+        .into_iter()
+        .flat_map(|value| value.split(','))
+        .filter_map(|member| {
+            let (name, value) = member.trim().split_once('=')?;
+            Some((name, value.strip_prefix(':')?.strip_suffix(':')?))
+        });
+    let content_md5 = headers.get("content-md5").and_then(|value| value.to_str().ok()).map(|value| ("md5", value));
+    content_digest.chain(content_md5).find_map(|(name, value)| {
+        let algorithm = match name.to_ascii_lowercase().as_str() {
+            "sha-512" => HashAlgorithm::Sha512,
+            "sha-256" => HashAlgorithm::Sha256,
+            "md5" => HashAlgorithm::Md5,
+            _ => return None,
+        };
+        Checksum::new(algorithm, &hex::encode(BASE64.decode(value.trim()).ok()?)).ok()
+    })
+}
+
+/// What every source's fetches have in common
+struct Fetch<'a> {
+    client: &'a Client,
+    middleware: &'a Middleware,
+    /// The file being written, all `length` bytes of it there from the start
+    partial: &'a Path,
+    header_timeout: Option<Duration>,
+    pb: &'a ProgressBar,
+    limiter: Option<Arc<RateLimiter>>,
+    min_speed: Option<MinimumSpeed>,
+    cancellation: &'a Cancellation,
+    /// What's been written to the file so far, and can be trusted
+    written: Mutex<Vec<Segment>>,
+}
+
+impl Fetch<'_> {
+    /// How much of the file from the start has been written without a gap
+    fn resumable(&self) -> u64 {
+        let mut written = self.written.lock().unwrap().clone();
+        written.sort_unstable();
+        let mut end = 0;
+        for (start, finish) in written {
+            if start > end {
+                break;
+            }
+            end = end.max(finish);
+        }
+        end
+    }
+
+    /// Fetch `segments` from `sources` between them, returning who sent what
+    fn all(&self, sources: &[String], segments: Vec<Segment>) -> Result<Vec<Piece>, SwarmError> {
+        let work = Work {
+            remaining: AtomicUsize::new(segments.len()),
+            queue: Mutex::new(segments.into()),
+            pieces: Mutex::new(Vec::new()),
+            last_error: Mutex::new(String::new()),
+        };
+        thread::scope(|scope| {
+            for source in sources {
+                let work = &work;
+                scope.spawn(move || {
+                    if let Err(e) = self.segments(source, work)
+                        && self.cancellation.reason().is_none()
+                    {
+                        warn!("Giving up on {}: {}", source, e);
+                        *work.last_error.lock().unwrap() = format!("{}: {}", source, e);
+                    }
+                });
+            }
+        });
+
+        if let Some(reason) = self.cancellation.reason() {
+            return Err(Cancelled(reason).into());
+        }
+        // Once every source is done, either everything was written or nobody was left to write it
+        if work.remaining.load(Ordering::SeqCst) > 0 {
+            return Err(SwarmError::AllFailed(work.last_error.into_inner().unwrap()));
+        }
+        Ok(work.pieces.into_inner().unwrap())
+    }
+
+    /// Check the whole file against `checksums`. If it's wrong, one of the
+    /// sources sent something it shouldn't have, so each source's pieces in
+    /// turn are fetched again from the others, until it comes out right or
+    /// there's nobody left to suspect.
+    fn verify(&self, path: &Path, sources: &[String], pieces: Vec<Piece>, checksums: &ChecksumPlan) -> Result<Vec<Checksum>, SwarmError> {
+        if checksums.is_empty() {
+            return Ok(Vec::new());
+        }
+        let hash = || checksum::file_checksums(self.partial, &checksums.algorithms).map_err(|source| SwarmError::Io { path: self.partial.to_path_buf(), source });
+        let mut actual = hash()?;
+        let mut pieces = pieces;
+        let mut suspects = sources.iter();
+        while let Err(e) = checksums.verify(&actual) {
+            let suspected = |suspect: &&String| pieces.iter().any(|piece| &piece.source == *suspect) && sources.iter().any(|source| source != *suspect);
+            let Some(suspect) = suspects.find(suspected) else {
+                return Err(SwarmError::Checksum { path: path.to_path_buf(), source: e });
+            };
+            let others: Vec<String> = sources.iter().filter(|source| *source != suspect).cloned().collect();
+            let (suspected, kept): (Vec<Piece>, Vec<Piece>) = pieces.into_iter().partition(|piece| &piece.source == suspect);
+            let segments: Vec<Segment> = suspected.iter().map(|piece| piece.segment).collect();
+            self.written.lock().unwrap().retain(|segment| !segments.contains(segment));
+            let bytes = segments.iter().map(|(start, end)| end - start).sum();
+            warn!("{} doesn't match its checksum; fetching the {} that came from {} again from the other sources", path.display(), HumanBytes(bytes), suspect);
+            self.pb.dec(bytes);
+            // What they send might be the problem instead, so it can be suspected in turn
+            pieces = kept;
+            pieces.extend(self.all(&others, segments)?);
+            actual = hash()?;
+        }
+        Ok(actual)
+    }
+
+    /// Keep fetching segments from `source` until there are none left, or it fails
+    fn segments(&self, source: &str, work: &Work) -> Result<(), String> {
+        let mut file = OpenOptions::new().write(true).open(self.partial).map_err(|e| e.to_string())?;
+        let mut corrupt = 0;
+        while work.remaining.load(Ordering::SeqCst) > 0 {
+            self.cancellation.check().map_err(|e| e.to_string())?;
+            let Some((start, end)) = work.queue.lock().unwrap().pop_front() else {
+                // Everything's being fetched, but another source might give up on its part
+                thread::sleep(IDLE_WAIT);
+                continue;
+            };
+            let mut written = 0;
+            match self.segment(source, &mut file, (start, end), &mut written) {
+                Ok(()) => {
+                    self.written.lock().unwrap().push((start, end));
+                    work.pieces.lock().unwrap().push(Piece { segment: (start, end), source: source.to_string() });
+                    work.remaining.fetch_sub(1, Ordering::SeqCst);
+                }
+                Err(SegmentError::Corrupt(e)) => {
+                    // None of it can be trusted, so the whole segment goes back to be fetched again
+                    warn!("{}: {}", source, e);
+                    self.pb.dec(written);
+                    work.queue.lock().unwrap().push_back((start, end));
+                    corrupt += 1;
+                    if corrupt == MAX_CORRUPT {
+                        return Err(format!("{} segments didn't match their digests", corrupt));
+                    }
+                }
+                Err(SegmentError::Failed(e)) => {
+                    // Whatever we didn't get is someone else's job now
+                    if written > 0 {
+                        self.written.lock().unwrap().push((start, start + written));
+                        work.pieces.lock().unwrap().push(Piece { segment: (start, start + written), source: source.to_string() });
+                    }
+                    work.queue.lock().unwrap().push_back((start + written, end));
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fetch one segment from `source` into `file`, checking it against the
+    /// digest the server gives for it, if there is one
+    fn segment(&self, source: &str, file: &mut File, (start, end): Segment, written: &mut u64) -> Result<(), SegmentError> {
+        let request = self
+            .middleware
+            .get(self.client, source)
+            .header(header::RANGE, format!("bytes={}-{}", start, end - 1))
+            .header(header::ACCEPT_ENCODING, "identity")
+            .build()
+            .map_err(|e| e.to_string())?;
+        let response = timeouts::execute(self.client, request, self.header_timeout).map_err(|e| e.to_string())?;
+        let content_range = response.headers().get(header::CONTENT_RANGE).and_then(|value| value.to_str().ok()).unwrap_or_default();
+        if response.status() != StatusCode::PARTIAL_CONTENT || partial::range_start(content_range) != Some(start) {
+            return Err(format!("server returned {} ({}) instead of bytes {}-{}", response.status(), content_range, start, end - 1).into());
+        }
+        let expected = range_digest(response.headers());
+        let mut hasher = expected.as_ref().map(|expected| expected.algorithm.hasher());
+
+        let mut body = throttle::throttled(response, self.cancellation, self.limiter.clone(), self.min_speed);
+        file.seek(SeekFrom::Start(start)).map_err(|e| e.to_string())?;
+        let mut buffer = vec![0; 64 * 1024];
+        while start + *written < end {
+            let wanted = buffer.len().min((end - start - *written) as usize);
+            let read = body.read(&mut buffer[..wanted]).map_err(|e| e.to_string())?;
+            if read == 0 {
+                return Err(format!("connection closed after {} of {} bytes", *written, end - start).into());
+            }
+            file.write_all(&buffer[..read]).map_err(|e| e.to_string())?;
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&buffer[..read]);
+            }
+            *written += read as u64;
+            self.pb.inc(read as u64);
+        }
+
+        if let (Some(expected), Some(hasher)) = (expected, hasher) {
+            let actual = hex::encode(hasher.finalize());
+            if actual != expected.digest {
+                return Err(SegmentError::Corrupt(format!("bytes {}-{} came out as {}:{}, not {}", start, end - 1, expected.algorithm, actual, expected)));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_segments_cover_the_file() {
+        let length = 100 * 1024 * 1024 + 7;
+        let split = segments(length, 3);
+        assert_eq!(split.len(), 13);
+        assert_eq!(split.first().unwrap().0, 0);
+        assert_eq!(split.last().unwrap().1, length);
+        assert!(split.windows(2).all(|pair| pair[0].1 == pair[1].0));
+
+        // Small files aren't split more finely than is worth it
+        assert_eq!(segments(1000, 4), vec![(0, 1000)]);
+        assert_eq!(segments(3 * MIN_SEGMENT, 8).len(), 3);
+    }
+
+    #[test]
+    fn test_range_digest() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(range_digest(&headers), None);
+        // md5("hello")
+        headers.insert("content-md5", "XUFAKrxLKna5cZ2REBfFkg==".parse().unwrap());
+        assert_eq!(range_digest(&headers).unwrap().to_string(), "md5:5d41402abc4b2a76b9719d911017c592");
+        // Content-Digest wins, and algorithms we don't know are passed over
+        headers.insert("content-digest", "unixsum=:MTIz:, sha-256=:LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=:".parse().unwrap());
+        assert_eq!(range_digest(&headers).unwrap().to_string(), "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824");
+    }
+
+    fn md5(data: &[u8]) -> Vec<u8> {
+        let mut hasher = HashAlgorithm::Md5.hasher();
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    /// A source for `body` that answers range requests, counting them. One
+    /// that corrupts what it sends flips every byte, and says what the digest
+    /// of the right bytes is if `digest` is set. Each request's range is shown
+    /// to `before` first, which can hold it up, or turn it away by closing the
+    /// connection.
+    fn source(body: Arc<Vec<u8>>, corrupt: bool, digest: bool, before: impl Fn(usize, usize) -> bool + Send + Sync + 'static) -> (String, Arc<AtomicUsize>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/file.bin", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counted = requests.clone();
+        let before = Arc::new(before);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let (mut stream, body, requests, before) = (stream.unwrap(), body.clone(), counted.clone(), before.clone());
+                thread::spawn(move || {
+                    let mut request = [0u8; 4096];
+                    let read = stream.read(&mut request).unwrap();
+                    let request = String::from_utf8_lossy(&request[..read]).to_ascii_lowercase();
+                    let range = request.lines().find_map(|line| line.strip_prefix("range: bytes=")).unwrap();
+                    let (start, end) = range.split_once('-').unwrap();
+                    let (start, end): (usize, usize) = (start.parse().unwrap(), end.parse().unwrap());
+                    requests.fetch_add(1, Ordering::SeqCst);
+                    if !before(start, end) {
+                        return;
+                    }
+                    let right = &body[start..=end];
+                    let sent: Vec<u8> = match corrupt {
+                        true => right.iter().map(|byte| !byte).collect(),
+                        false => right.to_vec(),
+                    };
+                    let mut head = format!("HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\nConnection: close\r\n", start, end, body.len(), sent.len());
+                    if digest {
+                        head += &format!("Content-MD5: {}\r\n", BASE64.encode(md5(right)));
+                    }
+                    head += "\r\n";
+                    let _ = stream.write_all(head.as_bytes()).and_then(|()| stream.write_all(&sent));
+                });
+            }
+        });
+        (url, requests)
+    }
+
+    /// Holds a source's segments (not its probe) back until `opened` hears
+    /// something, and lets them all through from then on
+    fn held_until(opened: mpsc::Receiver<()>) -> impl Fn(usize, usize) -> bool + Send + Sync {
+        let opened = Mutex::new(Some(opened));
+        move |start, end| {
+            if (start, end) != (0, 0)
+                && let Some(opened) = opened.lock().unwrap().take()
+            {
+                let _ = opened.recv();
+            }
+            true
+        }
+    }
+
+    /// Says when a source is first asked for a segment
+    fn signals(opened: mpsc::Sender<()>) -> impl Fn(usize, usize) -> bool + Send + Sync {
+        let opened = Mutex::new(opened);
+        move |start, end| {
+            if (start, end) != (0, 0) {
+                let _ = opened.lock().unwrap().send(());
+            }
+            true
+        }
+    }
+
+    fn fetch(name: &str, sources: &[String], checksums: &ChecksumPlan) -> Result<Vec<u8>, SwarmError> {
+        let dir = std::env::temp_dir().join(format!("rustdl-swarm-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.bin");
+        let cancellation = Cancellation::new(crate::cancel::CancelPolicy::Delete);
+        let result = download(&Client::new(), &Middleware::standard(), sources, &path, None, &ProgressBar::hidden(), None, None, &cancellation, checksums);
+        let body = result.map(|_| fs::read(&path).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+        body
+    }
+
+    #[test]
+    fn test_corrupt_segment_is_fetched_again() {
+        let body: Arc<Vec<u8>> = Arc::new((0..2 * MIN_SEGMENT).map(|i| (i % 251) as u8).collect());
+        // The good source waits for the bad one to be asked for a segment, so it gets one of its own
+        let (opened, held) = mpsc::channel();
+        let (good, _) = source(body.clone(), false, true, held_until(held));
+        let (bad, bad_requests) = source(body.clone(), true, true, signals(opened));
+        let fetched = fetch("segment", &[good, bad], &ChecksumPlan::default()).unwrap();
+        assert!(bad_requests.load(Ordering::SeqCst) > 1, "the bad source never sent a segment");
+        assert!(fetched == *body, "the corrupt segment was kept");
+    }
+
+    #[test]
+    fn test_suspect_is_fetched_again() {
+        let body: Arc<Vec<u8>> = Arc::new((0..2 * MIN_SEGMENT).map(|i| (i % 251) as u8).collect());
+        let (opened, held) = mpsc::channel();
+        let (good, _) = source(body.clone(), false, false, held_until(held));
+        let (bad, bad_requests) = source(body.clone(), true, false, signals(opened));
+        // Nothing says which segment is wrong, only that the whole file is
+        let checksums = ChecksumPlan { algorithms: vec![HashAlgorithm::Md5], expected: vec![Checksum::new(HashAlgorithm::Md5, &hex::encode(md5(&body))).unwrap()] };
+        let fetched = fetch("suspect", &[good, bad], &checksums).unwrap();
+        assert!(bad_requests.load(Ordering::SeqCst) > 1, "the bad source never sent a segment");
+        assert!(fetched == *body, "the corrupt source's segments were kept");
+    }
+
+    #[test]
+    fn test_cancelled_download_can_be_resumed() {
+        let body: Arc<Vec<u8>> = Arc::new(vec![7; 2 * MIN_SEGMENT as usize]);
+        let dir = std::env::temp_dir().join(format!("rustdl-swarm-cancel-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.bin");
+        let cancellation = Cancellation::new(crate::cancel::CancelPolicy::KeepPart);
+        // One source fetches its segments in turn, and is cancelled once it's sent the first
+        let cancel = cancellation.clone();
+        let (url, _) = source(body, false, false, move |start, _| match start as u64 == MIN_SEGMENT {
+            true => {
+                cancel.cancel(crate::cancel::CancelReason::Requested);
+                false
+            }
+            false => true,
+        });
+        let result = download(&Client::new(), &Middleware::standard(), std::slice::from_ref(&url), &path, None, &ProgressBar::hidden(), None, None, &cancellation, &ChecksumPlan::default());
+        assert!(matches!(result, Err(SwarmError::Cancelled(_))));
+        let part = PartFile::open(&partial::with_suffix(&path, partial::PART_SUFFIX)).unwrap();
+        assert_eq!((part.metadata.url.as_str(), part.metadata.offset, part.output_path()), (url.as_str(), MIN_SEGMENT, path));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
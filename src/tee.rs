@@ -0,0 +1,39 @@
+use std::io::{self, Read, Write};
+
+/// A reader that copies everything read through it to a writer as well,
+/// like `tee`
+pub struct TeeReader<R, W> {
+    inner: R,
+    copy: W,
+}
+
+impl<R: Read, W: Write> TeeReader<R, W> {
+    pub fn new(inner: R, copy: W) -> Self {
+        Self { inner, copy }
+    }
+}
+
+impl<R: Read, W: Write> Read for TeeReader<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.copy.write_all(&buf[..read])?;
+        if read == 0 {
+            self.copy.flush()?;
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tee_reader_copies_everything() {
+        let mut copy = Vec::new();
+        let mut saved = Vec::new();
+        io::copy(&mut TeeReader::new(&b"hello world"[..], &mut copy), &mut saved).unwrap();
+        assert_eq!(saved, b"hello world");
+        assert_eq!(copy, b"hello world");
+    }
+}
@@ -0,0 +1,247 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use percent_encoding::percent_decode_str;
+use url::Url;
+
+use crate::glob;
+use crate::naming;
+
+/// Something an output template can refer to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TemplateVariable {
+    /// The URL's host name
+    Host,
+    /// The directories in the URL's path, without the file name
+    Path,
+    /// The name the file would otherwise have been saved as
+    Filename,
+    /// That name's extension, without the dot
+    Ext,
+    /// Today's date, as YYYY-MM-DD (UTC)
+    Date,
+    /// Where the URL comes in the download order, from 1
+    Index,
+    /// What the Nth `[...]` or `{...}` in the URL given matched, from 1
+    Glob(usize),
+}
+
+impl TemplateVariable {
+    pub fn all() -> Vec<TemplateVariable> {
+        vec![
+            TemplateVariable::Host,
+            TemplateVariable::Path,
+            TemplateVariable::Filename,
+            TemplateVariable::Ext,
+            TemplateVariable::Date,
+            TemplateVariable::Index,
+        ]
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TemplateVariable::Host => "host",
+            TemplateVariable::Path => "path",
+            TemplateVariable::Filename => "filename",
+            TemplateVariable::Ext => "ext",
+            TemplateVariable::Date => "date",
+            TemplateVariable::Index => "index",
+            TemplateVariable::Glob(_) => "N",
+        }
+    }
+}
+
+impl fmt::Display for TemplateVariable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateVariable::Glob(n) => write!(f, "{{{}}}", n),
+            variable => write!(f, "{{{}}}", variable.as_str()),
+        }
+    }
+}
+
+impl FromStr for TemplateVariable {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(n) = s.parse::<usize>()
+            && n > 0
+        {
+            return Ok(TemplateVariable::Glob(n));
+        }
+        TemplateVariable::all()
+            .into_iter()
+            .find(|variable| variable.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                format!(
+                    "Unknown template variable '{{{}}}'. Available variables: {}, {{N}}",
+                    s,
+                    TemplateVariable::all().iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", ")
+                )
+            })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Variable(TemplateVariable),
+}
+
+/// Where to save downloads relative to the output directory, like
+/// `{host}/{filename}`; `{{` and `}}` stand for literal braces
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputTemplate {
+    segments: Vec<Segment>,
+    /// What `{date}` gives, if not today
+    date: Option<SystemTime>,
+}
+
+impl OutputTemplate {
+    /// Give `{date}` as the day of `date` rather than today
+    pub fn dated(mut self, date: SystemTime) -> Self {
+        self.date = Some(date);
+        self
+    }
+
+    /// The path for `filename`, downloaded from `url` as the `index`th URL
+    /// (from 0), or `None` if the template comes out empty. Directories are
+    /// allowed, but the result can't climb out of the output directory.
+    pub fn render(&self, url: &Url, filename: &Path, index: usize) -> Option<PathBuf> {
+        let mut rendered = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => rendered.push_str(text),
+                Segment::Variable(variable) => rendered.push_str(&value(*variable, url, filename, index, self.date)),
+            }
+        }
+        naming::sanitize(&rendered, true)
+    }
+}
+
+impl FromStr for OutputTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut rest = s;
+        while let Some(index) = rest.find(['{', '}']) {
+            literal.push_str(&rest[..index]);
+            let (brace, after) = rest[index..].split_at(1);
+            if let Some(after) = after.strip_prefix(brace) {
+                literal.push_str(brace);
+                rest = after;
+                continue;
+            }
+            if brace == "}" {
+                return Err(format!("invalid output template '{}': unmatched '}}' (use '}}}}' for a literal brace)", s));
+            }
+            let (name, after) = after
+                .split_once('}')
+                .ok_or_else(|| format!("invalid output template '{}': unclosed '{{' (use '{{{{' for a literal brace)", s))?;
+            if !literal.is_empty() {
+                segments.push(Segment::Literal(std::mem::take(&mut literal)));
+            }
+            segments.push(Segment::Variable(name.trim().parse()?));
+            rest = after;
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        if !segments.iter().any(|segment| matches!(segment, Segment::Variable(_))) {
+            return Err(format!(
+                "invalid output template '{}': it has no variables, so every download would get the same name",
+                s
+            ));
+        }
+        Ok(OutputTemplate { segments, date: None })
+    }
+}
+
+/// Where to save `filename` from `url` in `output_dir`, through the template
+/// if there is one
+pub fn output_path(
+    template: Option<&OutputTemplate>,
+    output_dir: &Path,
+    url: &Url,
+    filename: &Path,
+    index: usize,
+) -> Option<PathBuf> {
+    match template {
+        Some(template) => template.render(url, filename, index).map(|path| output_dir.join(path)),
+        None => Some(output_dir.join(filename)),
+    }
+}
+
+fn value(variable: TemplateVariable, url: &Url, filename: &Path, index: usize, date: Option<SystemTime>) -> String {
+    match variable {
+        TemplateVariable::Host => url.host_str().unwrap_or_default().to_string(),
+        TemplateVariable::Path => {
+            let mut segments: Vec<&str> = url.path_segments().map(Iterator::collect).unwrap_or_default();
+            segments.pop();
+            segments
+                .iter()
+                .map(|segment| percent_decode_str(segment).decode_utf8_lossy())
+                .collect::<Vec<_>>()
+                .join("/")
+        }
+        TemplateVariable::Filename => filename.to_string_lossy().into_owned(),
+        TemplateVariable::Ext => filename.extension().unwrap_or_default().to_string_lossy().into_owned(),
+        TemplateVariable::Date => humantime::format_rfc3339_seconds(date.unwrap_or_else(SystemTime::now)).to_string()[..10].to_string(),
+        TemplateVariable::Index => (index + 1).to_string(),
+        TemplateVariable::Glob(n) => glob::matched(url.as_str(), n).unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str, url: &str, filename: &str) -> Option<PathBuf> {
+        let template: OutputTemplate = template.parse().unwrap();
+        template.render(&Url::parse(url).unwrap(), Path::new(filename), 2)
+    }
+
+    #[test]
+    fn test_render() {
+        let url = "https://example.com:8443/releases/v1%2E0/tool.tar.gz?x=1";
+        assert_eq!(render("{host}/{filename}", url, "tool.tar.gz"), Some(PathBuf::from("example.com/tool.tar.gz")));
+        assert_eq!(render("{host}/{path}/{filename}", url, "tool.tar.gz"), Some(PathBuf::from("example.com/releases/v1.0/tool.tar.gz")));
+        assert_eq!(render("{ext}/{index}-{filename}", url, "tool.tar.gz"), Some(PathBuf::from("gz/3-tool.tar.gz")));
+        assert_eq!(render("{{{index}}}.bin", url, "tool.tar.gz"), Some(PathBuf::from("{3}.bin")));
+        // Empty values don't leave empty directories behind
+        assert_eq!(render("{host}/{path}/{filename}", "https://example.com/file", "file"), Some(PathBuf::from("example.com/file")));
+        assert_eq!(render("{ext}", url, "README"), None);
+
+        let date = render("{date}", url, "file").unwrap().to_string_lossy().into_owned();
+        assert_eq!(date.len(), 10);
+        assert_eq!(date.matches('-').count(), 2);
+        let template: OutputTemplate = "{date}-{filename}".parse().unwrap();
+        let dated = template.dated(SystemTime::UNIX_EPOCH).render(&Url::parse(url).unwrap(), Path::new("file"), 0);
+        assert_eq!(dated, Some(PathBuf::from("1970-01-01-file")));
+
+        glob::expand_all(&["https://template.example/{cats,dogs}/[1-2].jpg".to_string()]).unwrap();
+        assert_eq!(render("{1}-{2}.{ext}", "https://template.example/dogs/2.jpg", "2.jpg"), Some(PathBuf::from("dogs-2.jpg")));
+        assert_eq!(render("{1}{filename}", url, "tool.tar.gz"), Some(PathBuf::from("tool.tar.gz")));
+    }
+
+    #[test]
+    fn test_render_stays_in_the_output_directory() {
+        assert_eq!(render("{path}/{filename}", "https://example.com/a/..%2F..%2Fb/f", "f"), Some(PathBuf::from("a/b/f")));
+        assert_eq!(render("/{host}/../{filename}", "https://example.com/f", "f"), Some(PathBuf::from("example.com/f")));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!("{host".parse::<OutputTemplate>().unwrap_err().contains("unclosed"));
+        assert!("host}".parse::<OutputTemplate>().unwrap_err().contains("unmatched"));
+        assert!("{hostname}".parse::<OutputTemplate>().unwrap_err().contains("Available variables: {host}, {path}"));
+        assert!("fixed.bin".parse::<OutputTemplate>().unwrap_err().contains("no variables"));
+        assert!("{ HOST }/{filename}".parse::<OutputTemplate>().is_ok());
+        assert!("{0}.jpg".parse::<OutputTemplate>().unwrap_err().contains("{N}"));
+    }
+}
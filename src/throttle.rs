@@ -0,0 +1,353 @@
+use std::fmt;
+use std::io::{self, Read};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::cancel::{self, Cancellation};
+
+/// How often (at most) a throttled reader pauses, so bursts stay short
+const SLICES_PER_SECOND: u64 = 10;
+
+/// How long a transfer can stay under `--speed-limit` if `--speed-time` isn't given, as in curl
+pub const DEFAULT_SPEED_TIME: Duration = Duration::from_secs(30);
+
+/// A transfer rate in bytes per second, written like `500k` or `2M` (binary units)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ByteRate(pub u64);
+
+impl FromStr for ByteRate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (number, multiplier) = match s.chars().last().map(|c| c.to_ascii_lowercase()) {
+            Some('k') => (&s[..s.len() - 1], 1024.0),
+            Some('m') => (&s[..s.len() - 1], 1024.0 * 1024.0),
+            Some('g') => (&s[..s.len() - 1], 1024.0 * 1024.0 * 1024.0),
+            _ => (s, 1.0),
+        };
+        let rate = number
+            .parse::<f64>()
+            .ok()
+            .map(|number| number * multiplier)
+            .filter(|rate| rate.is_finite() && *rate >= 1.0)
+            .ok_or_else(|| format!("invalid rate '{}', expected bytes per second like 500k or 2M", s))?;
+        Ok(ByteRate(rate as u64))
+    }
+}
+
+impl TryFrom<String> for ByteRate {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for ByteRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/s", indicatif::BinaryBytes(self.0))
+    }
+}
+
+/// Caps the combined rate of every reader sharing it
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: AtomicU64,
+    /// When the bytes handed out so far will have been "paid for"
+    next: Mutex<Instant>,
+    /// Everything that's passed through so far
+    transferred: AtomicU64,
+}
+
+impl RateLimiter {
+    pub fn new(rate: ByteRate) -> Self {
+        Self { rate: AtomicU64::new(rate.0.max(1)), next: Mutex::new(Instant::now()), transferred: AtomicU64::new(0) }
+    }
+
+    /// A limiter that doesn't limit anything until it's given a rate
+    pub fn unlimited() -> Self {
+        Self::new(ByteRate(u64::MAX))
+    }
+
+    /// Change the rate, for readers already sharing the limiter too
+    pub fn set_rate(&self, rate: u64) {
+        self.rate.store(rate.max(1), Ordering::Relaxed);
+    }
+
+    /// How many bytes the limiter's readers have read between them
+    pub fn transferred(&self) -> u64 {
+        self.transferred.load(Ordering::Relaxed)
+    }
+
+    fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    /// The most a reader should take in one go
+    fn slice(&self) -> usize {
+        usize::try_from((self.rate() / SLICES_PER_SECOND).max(1)).unwrap_or(usize::MAX)
+    }
+
+    /// Account for `bytes` just transferred, sleeping until they fit within the rate
+    fn consume(&self, bytes: usize) {
+        self.transferred.fetch_add(bytes as u64, Ordering::Relaxed);
+        let cost = Duration::from_secs_f64(bytes as f64 / self.rate() as f64);
+        let until = {
+            let mut next = self.next.lock().unwrap();
+            // Time spent idle isn't saved up for a burst later
+            *next = (*next).max(Instant::now()) + cost;
+            *next
+        };
+        let now = Instant::now();
+        if until > now {
+            thread::sleep(until - now);
+        }
+    }
+}
+
+/// A reader that doesn't go faster than its rate limiter allows
+pub struct ThrottledReader<R> {
+    inner: R,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<R: Read> ThrottledReader<R> {
+    pub fn new(inner: R, limiter: Arc<RateLimiter>) -> Self {
+        Self { inner, limiter }
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let length = buf.len().min(self.limiter.slice());
+        let read = self.inner.read(&mut buf[..length])?;
+        self.limiter.consume(read);
+        Ok(read)
+    }
+}
+
+/// `--speed-limit` and `--speed-time`: give up on a transfer that stays slower
+/// than `rate` for `time`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MinimumSpeed {
+    pub rate: ByteRate,
+    pub time: Duration,
+}
+
+impl MinimumSpeed {
+    /// Like curl, either flag on its own is enough: the rate defaults to 1
+    /// byte per second and the time to 30 seconds
+    pub fn new(rate: Option<ByteRate>, time: Option<Duration>) -> Option<Self> {
+        if rate.is_none() && time.is_none() {
+            return None;
+        }
+        Some(MinimumSpeed { rate: rate.unwrap_or(ByteRate(1)), time: time.unwrap_or(DEFAULT_SPEED_TIME) })
+    }
+}
+
+/// Why a transfer was given up on by a [`MinimumSpeedReader`]
+#[derive(Debug, thiserror::Error)]
+#[error("slower than {} for {} (--speed-limit)", .0.rate, humantime::format_duration(.0.time))]
+pub struct TooSlow(pub MinimumSpeed);
+
+/// How much of the body is read at a time, and how many reads can be waiting
+/// for us, by a [`MinimumSpeedReader`]
+const READ_AHEAD_CHUNK: usize = 64 * 1024;
+const READ_AHEAD_CHUNKS: usize = 4;
+
+/// A reader that fails with [`TooSlow`] once the server's been sending slower
+/// than the minimum speed for long enough, including when it's stopped
+/// sending altogether. The body's read a little ahead on a thread of its
+/// own, so a read that never returns is noticed in time, and only the time
+/// spent waiting on the server counts: a rate limit holding us back, or a
+/// pause, isn't the server being slow.
+pub struct MinimumSpeedReader {
+    minimum: MinimumSpeed,
+    /// Whether we're paused
+    cancellation: Cancellation,
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    position: usize,
+    finished: bool,
+    /// When the current stretch started, how long in it we've waited on the
+    /// server, and how much it's sent
+    since: Instant,
+    waited: Duration,
+    read: u64,
+}
+
+impl MinimumSpeedReader {
+    pub fn new<R: Read + Send + 'static>(mut inner: R, minimum: MinimumSpeed, cancellation: Cancellation) -> Self {
+        let (sender, chunks) = mpsc::sync_channel(READ_AHEAD_CHUNKS);
+        // Once we've given up, it stops at its next read; the client's read
+        // timeout makes sure there is one
+        thread::spawn(move || {
+            loop {
+                let mut chunk = vec![0; READ_AHEAD_CHUNK];
+                let result = match inner.read(&mut chunk) {
+                    Ok(read) => {
+                        chunk.truncate(read);
+                        Ok(chunk)
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e),
+                };
+                let last = !matches!(&result, Ok(chunk) if !chunk.is_empty());
+                if sender.send(result).is_err() || last {
+                    break;
+                }
+            }
+        });
+        Self { minimum, cancellation, chunks, chunk: Vec::new(), position: 0, finished: false, since: Instant::now(), waited: Duration::ZERO, read: 0 }
+    }
+
+    /// Wait for the next chunk, giving up if the server's too slow in sending it
+    fn next(&mut self) -> io::Result<()> {
+        loop {
+            let started = Instant::now();
+            let received = self.chunks.recv_timeout(self.minimum.time.saturating_sub(self.waited));
+            self.waited += started.elapsed();
+            if let Ok(Ok(chunk)) = &received {
+                self.read += chunk.len() as u64;
+            }
+            // Time spent paused isn't the server being slow, so start again after it
+            if self.cancellation.is_paused() || cancel::resumed_since(self.since) {
+                self.restart();
+            } else if self.waited >= self.minimum.time {
+                if (self.read as f64 / self.waited.as_secs_f64()) < self.minimum.rate.0 as f64 {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, TooSlow(self.minimum)));
+                }
+                self.restart();
+            }
+            match received {
+                Ok(Ok(chunk)) => {
+                    self.finished = chunk.is_empty();
+                    (self.chunk, self.position) = (chunk, 0);
+                    return Ok(());
+                }
+                Ok(Err(e)) => return Err(e),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.finished = true;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn restart(&mut self) {
+        (self.since, self.waited, self.read) = (Instant::now(), Duration::ZERO, 0);
+    }
+}
+
+impl Read for MinimumSpeedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.next()?;
+        }
+        let read = buf.len().min(self.chunk.len() - self.position);
+        buf[..read].copy_from_slice(&self.chunk[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+/// Make a response body stop when `cancellation` says to, and apply the
+/// minimum speed and the rate limit if there are any; the speed's measured
+/// before the rate limit slows it down
+pub fn throttled<R: Read + Send + 'static>(reader: R, cancellation: &Cancellation, limiter: Option<Arc<RateLimiter>>, min_speed: Option<MinimumSpeed>) -> Box<dyn Read + Send> {
+    let reader: Box<dyn Read + Send> = match min_speed {
+        Some(minimum) => Box::new(MinimumSpeedReader::new(cancellation.reader(reader), minimum, cancellation.clone())),
+        None => Box::new(cancellation.reader(reader)),
+    };
+    match limiter {
+        Some(limiter) => Box::new(ThrottledReader::new(reader, limiter)),
+        None => reader,
+    }
+}
+
+/// Why an I/O error happened, if it was because the transfer was too slow
+pub fn too_slow(error: &io::Error) -> Option<&TooSlow> {
+    error.get_ref().and_then(|inner| inner.downcast_ref::<TooSlow>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_byte_rate_from_str() {
+        assert_eq!("500".parse::<ByteRate>().unwrap(), ByteRate(500));
+        assert_eq!("500k".parse::<ByteRate>().unwrap(), ByteRate(512_000));
+        assert_eq!("1.5M".parse::<ByteRate>().unwrap(), ByteRate(1_572_864));
+        assert_eq!("2g".parse::<ByteRate>().unwrap(), ByteRate(2 * 1024 * 1024 * 1024));
+        assert!("fast".parse::<ByteRate>().is_err());
+        assert!("0".parse::<ByteRate>().is_err());
+        assert!("-5k".parse::<ByteRate>().is_err());
+    }
+
+    #[test]
+    fn test_throttled_readers_share_the_rate() {
+        let limiter = Arc::new(RateLimiter::new(ByteRate(10_000)));
+        let start = Instant::now();
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let limiter = Arc::clone(&limiter);
+                thread::spawn(move || {
+                    let mut reader = ThrottledReader::new(&[0u8; 2_000][..], limiter);
+                    io::copy(&mut reader, &mut io::sink()).unwrap()
+                })
+            })
+            .collect();
+        let total: u64 = handles.into_iter().map(|handle| handle.join().unwrap()).sum();
+
+        // 4,000 bytes at 10,000 bytes a second between them
+        assert_eq!(total, 4_000);
+        assert!(start.elapsed() >= Duration::from_millis(350), "took {:?}", start.elapsed());
+    }
+
+    #[test]
+    fn test_minimum_speed() {
+        assert_eq!(MinimumSpeed::new(None, None), None);
+        assert_eq!(MinimumSpeed::new(Some(ByteRate(1024)), None), Some(MinimumSpeed { rate: ByteRate(1024), time: DEFAULT_SPEED_TIME }));
+        assert_eq!(MinimumSpeed::new(None, Some(Duration::from_secs(5))).unwrap().rate, ByteRate(1));
+
+        // 3,000 bytes at 5,000 bytes a second is too slow for 10,000, but fast enough for 100
+        let slow = |rate| {
+            let server = ThrottledReader::new(&[0u8; 3_000][..], Arc::new(RateLimiter::new(ByteRate(5_000))));
+            let minimum = MinimumSpeed { rate: ByteRate(rate), time: Duration::from_millis(200) };
+            io::copy(&mut MinimumSpeedReader::new(server, minimum, Cancellation::default()), &mut io::sink())
+        };
+        let error = slow(10_000).unwrap_err();
+        assert_eq!(too_slow(&error).unwrap().0.rate, ByteRate(10_000));
+        assert_eq!(slow(100).unwrap(), 3_000);
+
+        // Our own rate limit doesn't count against the server
+        let minimum = MinimumSpeed { rate: ByteRate(10_000), time: Duration::from_millis(200) };
+        let mut limited = throttled(&[0u8; 3_000][..], &Cancellation::default(), Some(Arc::new(RateLimiter::new(ByteRate(5_000)))), Some(minimum));
+        assert_eq!(io::copy(&mut limited, &mut io::sink()).unwrap(), 3_000);
+
+        // A server that stops sending is given up on in time, not whenever its read returns
+        struct Stalled;
+        impl Read for Stalled {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                thread::sleep(Duration::from_secs(5));
+                Ok(0)
+            }
+        }
+        let started = Instant::now();
+        let error = io::copy(&mut MinimumSpeedReader::new(Stalled, minimum, Cancellation::default()), &mut io::sink()).unwrap_err();
+        assert!(too_slow(&error).is_some() && started.elapsed() < Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,148 @@
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use log::debug;
+use reqwest::blocking::{Client, Request, Response};
+use tracing::trace_span;
+use url::Url;
+
+use crate::resolver::NoAddresses;
+
+/// How long a server gets to send the next bit of a response, and the
+/// response headers, unless `--read-timeout` says otherwise
+pub const DEFAULT_IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a server gets to accept the connection unless `--connect-timeout` says otherwise
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Parse a duration given on the command line: plain seconds (`2.5`) or a
+/// humantime duration (`90s`, `2m`, `1h 30m`)
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    if let Ok(seconds) = s.parse::<f64>() {
+        return Duration::try_from_secs_f64(seconds).map_err(|e| format!("invalid duration '{}': {}", s, e));
+    }
+    humantime::parse_duration(s).map_err(|e| format!("invalid duration '{}': {}", s, e))
+}
+
+/// Why `header` can't be used with `read` (None for the default), if it can't:
+/// the read timeout already covers waiting for the headers, so a header
+/// timeout can only be shorter, and waiting forever would leave the thread
+/// `execute` sends from waiting forever too
+pub fn check_header_timeout(header: Duration, read: Option<Duration>) -> Result<(), String> {
+    match read.unwrap_or(DEFAULT_IO_TIMEOUT) {
+        read if read.is_zero() => Err("--header-timeout needs a --read-timeout".to_string()),
+        read if header > read => Err(format!(
+            "--header-timeout can't be longer than --read-timeout ({}), which covers waiting for the headers too",
+            humantime::format_duration(read)
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Errors from sending a request
+#[derive(Debug, thiserror::Error)]
+pub enum RequestError {
+    #[error(transparent)]
+    Http(reqwest::Error),
+
+    /// Said outright, since reqwest's own message for it is just "error sending request"
+    #[error("{url}: {source}")]
+    NoAddresses { url: String, source: NoAddresses },
+
+    #[error("{url}: no response headers within {}", humantime::format_duration(*timeout))]
+    HeaderTimeout { url: Url, timeout: Duration },
+
+    /// `--connect-timeout` or `--read-timeout` ran out
+    #[error("{url}: timed out {}", if *connecting { "connecting" } else { "waiting for a response" })]
+    TimedOut { url: String, connecting: bool },
+}
+
+impl RequestError {
+    /// Whether the server was given up on for taking too long, to connect to
+    /// or to answer, which is worth trying again
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, RequestError::HeaderTimeout { .. } | RequestError::TimedOut { .. })
+    }
+}
+
+impl From<reqwest::Error> for RequestError {
+    fn from(error: reqwest::Error) -> Self {
+        let url = error.url().map(Url::to_string).unwrap_or_default();
+        match NoAddresses::find(&error) {
+            Some(found) => RequestError::NoAddresses { url, source: found.clone() },
+            None if error.is_timeout() => RequestError::TimedOut { url, connecting: error.is_connect() },
+            None => RequestError::Http(error),
+        }
+    }
+}
+
+/// Send a request, giving up if the response headers haven't arrived within
+/// `header_timeout`. Reading the body afterwards isn't affected by it.
+///
+/// The request is sent from a thread of its own, which carries on after we
+/// give up until the client's own timeout ends it; that's why a header
+/// timeout can't be longer than the read timeout, and isn't allowed without one.
+pub fn execute(client: &Client, request: Request, header_timeout: Option<Duration>) -> Result<Response, RequestError> {
+    let _span = trace_span!("request", method = %request.method(), url = %request.url()).entered();
+    let Some(timeout) = header_timeout else {
+        return Ok(client.execute(request)?);
+    };
+
+    let url = request.url().clone();
+    let (sender, receiver) = mpsc::channel();
+    let client = client.clone();
+    thread::spawn(move || {
+        // Nobody's listening any more if we already gave up waiting
+        let _ = sender.send(client.execute(request));
+    });
+
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => Ok(result?),
+        Err(_) => {
+            debug!("Gave up waiting for response headers from {} after {:?}", url, timeout);
+            Err(RequestError::HeaderTimeout { url, timeout })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("10").unwrap(), Duration::from_secs(10));
+        assert_eq!(parse_duration("0.5").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("2m").unwrap(), Duration::from_secs(120));
+        assert_eq!(parse_duration("1h 30m").unwrap(), Duration::from_secs(5400));
+        assert!(parse_duration("-1").is_err());
+        assert!(parse_duration("soon").is_err());
+
+        assert!(check_header_timeout(Duration::from_secs(10), None).is_ok());
+        assert!(check_header_timeout(Duration::from_secs(90), None).is_err());
+        assert!(check_header_timeout(Duration::from_secs(90), Some(Duration::from_secs(120))).is_ok());
+        assert!(check_header_timeout(Duration::from_secs(10), Some(Duration::ZERO)).is_err());
+    }
+
+    #[test]
+    fn test_header_timeout_on_silent_server() {
+        // Accepts the connection and reads the request, but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            if let Ok((mut stream, _)) = listener.accept() {
+                let mut buffer = [0u8; 1024];
+                let _ = stream.read(&mut buffer);
+                thread::sleep(Duration::from_secs(5));
+            }
+        });
+
+        let client = Client::new();
+        let request = client.get(format!("http://{}/", address)).build().unwrap();
+        let result = execute(&client, request, Some(Duration::from_millis(200)));
+        assert!(matches!(result, Err(RequestError::HeaderTimeout { .. })));
+    }
+}
@@ -0,0 +1,53 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::debug;
+use reqwest::header::HeaderValue;
+
+/// `If-Modified-Since` for a file we already have, based on its mtime
+pub fn if_modified_since(path: &Path) -> Option<HeaderValue> {
+    let modified = fs::metadata(path).and_then(|metadata| metadata.modified()).ok()?;
+    let value = httpdate::fmt_http_date(modified);
+    debug!("{} was last modified {}", path.display(), value);
+    HeaderValue::from_str(&value).ok()
+}
+
+/// Set a downloaded file's mtime from the server's `Last-Modified` date, so
+/// the next timestamping run can tell whether it changed
+pub fn set_modified(path: &Path, last_modified: &str) -> io::Result<()> {
+    let modified = httpdate::parse_http_date(last_modified).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    set_time(path, modified)
+}
+
+/// Set a file's mtime
+pub fn set_time(path: &Path, time: SystemTime) -> io::Result<()> {
+    File::options().write(true).open(path)?.set_modified(time)
+}
+
+/// The time reproducible builds stamp everything with: `$SOURCE_DATE_EPOCH`
+/// if it's set, or else the Unix epoch itself
+pub fn source_date_epoch() -> SystemTime {
+    let seconds = std::env::var("SOURCE_DATE_EPOCH").ok().and_then(|value| value.trim().parse().ok()).unwrap_or(0);
+    UNIX_EPOCH + Duration::from_secs(seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamp_roundtrip() {
+        let path = std::env::temp_dir().join(format!("rustdl-timestamping-{}", std::process::id()));
+        fs::write(&path, b"hello").unwrap();
+
+        set_modified(&path, "Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), UNIX_EPOCH + Duration::from_secs(784111777));
+        assert_eq!(if_modified_since(&path).unwrap(), "Sun, 06 Nov 1994 08:49:37 GMT");
+
+        assert!(set_modified(&path, "yesterday").is_err());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(if_modified_since(&path), None);
+    }
+}
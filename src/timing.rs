@@ -0,0 +1,375 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use rustls::client::{ClientSessionMemoryCache, ClientSessionStore, Resumption, Tls12ClientSessionValue, Tls13ClientSessionValue};
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, NamedGroup};
+use serde::Serialize;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::report::Entry;
+
+/// How many recent connections to remember
+const MAX_PHASES: usize = 256;
+
+/// TLS sessions to keep for resuming, as many as rustls keeps by default
+const MAX_SESSIONS: usize = 256;
+
+/// A step in setting up a request that happens out of sight, inside the HTTP client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Dns,
+    /// Resolving, connecting and any TLS handshake, all together
+    Connect,
+    /// Just the TLS handshake
+    Tls,
+}
+
+/// The connections one client has made, phase by phase: what, when it
+/// started, and how long it took. Each request takes its own from here.
+#[derive(Debug, Clone, Default)]
+pub struct Phases(Arc<Mutex<VecDeque<(Phase, Instant, Duration)>>>);
+
+impl Phases {
+    /// Note that a lookup, connection or handshake starting at `started` took `took`
+    pub fn record(&self, phase: Phase, started: Instant, took: Duration) {
+        let mut phases = self.0.lock().unwrap();
+        if phases.len() == MAX_PHASES {
+            phases.pop_front();
+        }
+        phases.push_back((phase, started, took));
+    }
+
+    /// The time spent in each phase by whatever started since `since`, which
+    /// is taken so that no later request counts it too
+    fn take(&self, since: Instant) -> impl Fn(Phase) -> Duration {
+        let mut phases = self.0.lock().unwrap();
+        let (taken, kept): (Vec<_>, Vec<_>) = phases.drain(..).partition(|(_, started, _)| *started >= since);
+        *phases = kept.into();
+        move |phase| taken.iter().filter(|(p, _, _)| *p == phase).map(|(_, _, took)| *took).sum()
+    }
+}
+
+/// What's happened so far in making a connection
+#[derive(Debug, Default)]
+struct Connecting {
+    dns: Duration,
+    handshake: Option<Instant>,
+}
+
+thread_local! {
+    /// The connection being made on this thread, while it's being made
+    static CONNECTING: RefCell<Option<Connecting>> = const { RefCell::new(None) };
+}
+
+/// Note that the connection being made on this thread spent `took` looking its host up
+pub fn looked_up(took: Duration) {
+    CONNECTING.with_borrow_mut(|connecting| {
+        if let Some(connecting) = connecting {
+            connecting.dns += took;
+        }
+    });
+}
+
+/// `config`, with its handshakes timed
+pub fn timed_tls(mut config: ClientConfig) -> ClientConfig {
+    config.resumption = Resumption::store(Arc::new(TimedSessions(ClientSessionMemoryCache::new(MAX_SESSIONS))));
+    config
+}
+
+/// The usual TLS session cache, which also notes when a handshake starts:
+/// rustls asks it which key exchange the server prefers first thing
+#[derive(Debug)]
+struct TimedSessions(ClientSessionMemoryCache);
+
+impl ClientSessionStore for TimedSessions {
+    fn set_kx_hint(&self, server_name: ServerName<'static>, group: NamedGroup) {
+        self.0.set_kx_hint(server_name, group);
+    }
+
+    fn kx_hint(&self, server_name: &ServerName<'_>) -> Option<NamedGroup> {
+        CONNECTING.with_borrow_mut(|connecting| {
+            if let Some(connecting) = connecting {
+                connecting.handshake.get_or_insert_with(Instant::now);
+            }
+        });
+        self.0.kx_hint(server_name)
+    }
+
+    fn set_tls12_session(&self, server_name: ServerName<'static>, value: Tls12ClientSessionValue) {
+        self.0.set_tls12_session(server_name, value);
+    }
+
+    fn tls12_session(&self, server_name: &ServerName<'_>) -> Option<Tls12ClientSessionValue> {
+        self.0.tls12_session(server_name)
+    }
+
+    fn remove_tls12_session(&self, server_name: &ServerName<'static>) {
+        self.0.remove_tls12_session(server_name);
+    }
+
+    fn insert_tls13_ticket(&self, server_name: ServerName<'static>, value: Tls13ClientSessionValue) {
+        self.0.insert_tls13_ticket(server_name, value);
+    }
+
+    fn take_tls13_ticket(&self, server_name: &ServerName<'static>) -> Option<Tls13ClientSessionValue> {
+        self.0.take_tls13_ticket(server_name)
+    }
+}
+
+/// Where a URL's time went, as far as we know; connections reused from an
+/// earlier request take no time to look up or connect
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Timing {
+    /// Looking the host name up
+    pub dns: f64,
+    /// Connecting to the server
+    pub connect: f64,
+    /// The TLS handshake
+    pub tls: f64,
+    /// From sending the request to having the response headers
+    pub ttfb: f64,
+}
+
+/// Times a request from when it's sent. Requests in the main download loop
+/// are sent one at a time, so whatever connecting their client does in
+/// between is theirs.
+#[derive(Debug, Clone, Copy)]
+pub struct Stopwatch {
+    started: Instant,
+}
+
+impl Stopwatch {
+    pub fn start() -> Self {
+        Stopwatch { started: Instant::now() }
+    }
+
+    /// The response headers are in: work out how long everything before them
+    /// took, from what the client that sent it noted in `phases`
+    pub fn headers(&self, phases: &Phases) -> Timing {
+        let spent = phases.take(self.started);
+        let (dns, tls) = (spent(Phase::Dns), spent(Phase::Tls));
+        let connect = spent(Phase::Connect).saturating_sub(dns).saturating_sub(tls);
+        Timing { dns: dns.as_secs_f64(), connect: connect.as_secs_f64(), tls: tls.as_secs_f64(), ttfb: self.started.elapsed().as_secs_f64() }
+    }
+
+    /// How long it's been since the request was sent
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+/// A connector layer for the HTTP client that records in its `Phases` how
+/// long each new connection took to set up, and how much of that was looking
+/// the host up and the TLS handshake (which needs TLS set up by `timed_tls`)
+#[derive(Debug, Clone, Default)]
+pub struct TimedConnect(pub Phases);
+
+impl<S> Layer<S> for TimedConnect {
+    type Service = Timed<S>;
+
+    fn layer(&self, inner: S) -> Timed<S> {
+        Timed(inner, self.0.clone())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Timed<S>(S, Phases);
+
+impl<S, R> Service<R> for Timed<S>
+where
+    S: Service<R>,
+    S::Future: Send + 'static,
+    S::Response: Send,
+    S::Error: Send,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        let started = Instant::now();
+        let phases = self.1.clone();
+        let mut connecting = Box::pin(self.0.call(request));
+        let mut made = Some(Connecting::default());
+        Box::pin(futures::future::poll_fn(move |cx| {
+            // The lookup and handshake happen as part of connecting, on this thread
+            let outer = CONNECTING.replace(made.take());
+            let poll = connecting.as_mut().poll(cx);
+            made = CONNECTING.replace(outer);
+            if let Poll::Ready(Ok(_)) = &poll {
+                let made = made.take().unwrap_or_default();
+                phases.record(Phase::Dns, started, made.dns);
+                if let Some(handshake) = made.handshake {
+                    phases.record(Phase::Tls, started, handshake.elapsed());
+                }
+                phases.record(Phase::Connect, started, started.elapsed());
+            }
+            poll
+        }))
+    }
+}
+
+/// The variables `--write-out` knows
+pub const WRITE_OUT_VARIABLES: &[&str] =
+    &["url", "url_effective", "num_redirects", "file", "status", "http_code", "size", "speed", "dns", "connect", "tls", "ttfb", "total"];
+
+/// A piece of a `--write-out` template
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Variable(&'static str),
+}
+
+/// A line (or several) to print for each URL, like curl's `-w`: `%{name}`
+/// is replaced with one of `WRITE_OUT_VARIABLES`, and `\n` and `\t` work
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteOut {
+    parts: Vec<Part>,
+}
+
+impl WriteOut {
+    /// Fill the template in for one URL's entry in the report
+    pub fn render(&self, entry: &Entry) -> String {
+        self.render_with(entry, str::to_string)
+    }
+
+    /// Fill in a JSON template, escaping what goes in so it stays valid
+    /// inside a string
+    pub fn render_json(&self, entry: &Entry) -> String {
+        self.render_with(entry, |value| {
+            let quoted = serde_json::Value::from(value).to_string();
+            quoted[1..quoted.len() - 1].to_string()
+        })
+    }
+
+    fn render_with(&self, entry: &Entry, escape: impl Fn(&str) -> String) -> String {
+        let timing = entry.timing.unwrap_or_default();
+        let mut out = String::new();
+        for part in &self.parts {
+            let value = match part {
+                Part::Text(text) => {
+                    out.push_str(text);
+                    continue;
+                }
+                Part::Variable("url") => entry.url.clone(),
+                Part::Variable("url_effective") => entry.final_url.as_ref().unwrap_or(&entry.url).clone(),
+                Part::Variable("num_redirects") => entry.redirects.len().to_string(),
+                Part::Variable("file") => entry.file.as_deref().map(|file| file.display().to_string()).unwrap_or_default(),
+                Part::Variable("status") => entry.status.as_str().to_string(),
+                Part::Variable("http_code") => entry.http_status.unwrap_or_default().to_string(),
+                Part::Variable("size") => entry.bytes.to_string(),
+                Part::Variable("speed") => format!("{:.0}", entry.speed),
+                Part::Variable("dns") => format!("{:.6}", timing.dns),
+                Part::Variable("connect") => format!("{:.6}", timing.connect),
+                Part::Variable("tls") => format!("{:.6}", timing.tls),
+                Part::Variable("ttfb") => format!("{:.6}", timing.ttfb),
+                Part::Variable(_) => format!("{:.6}", entry.seconds),
+            };
+            out.push_str(&escape(&value));
+        }
+        out
+    }
+}
+
+impl FromStr for WriteOut {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut rest = s;
+        while let Some(c) = rest.chars().next() {
+            rest = &rest[c.len_utf8()..];
+            match (c, rest.chars().next()) {
+                ('\\', Some(escaped @ ('n' | 't' | '\\'))) => {
+                    text.push(match escaped {
+                        'n' => '\n',
+                        't' => '\t',
+                        _ => '\\',
+                    });
+                    rest = &rest[1..];
+                }
+                ('%', Some('%')) => {
+                    text.push('%');
+                    rest = &rest[1..];
+                }
+                ('%', Some('{')) => {
+                    let (name, after) = rest[1..].split_once('}').ok_or_else(|| format!("unclosed %{{ in write-out template '{}'", s))?;
+                    let variable = WRITE_OUT_VARIABLES.iter().find(|variable| **variable == name).ok_or_else(|| {
+                        format!("unknown write-out variable '{}'; available variables are {}", name, WRITE_OUT_VARIABLES.join(", "))
+                    })?;
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(Part::Variable(variable));
+                    rest = after;
+                }
+                (c, _) => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(WriteOut { parts })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redirects::Redirect;
+    use crate::report::Status;
+
+    #[test]
+    fn test_stopwatch_takes_phases_since_it_started() {
+        let phases = Phases::default();
+        let stopwatch = Stopwatch::start();
+        let earlier = stopwatch.started - Duration::from_secs(1);
+        phases.record(Phase::Dns, earlier, Duration::from_secs(60));
+        phases.record(Phase::Dns, stopwatch.started, Duration::from_millis(2));
+        phases.record(Phase::Tls, stopwatch.started, Duration::from_millis(3));
+        phases.record(Phase::Connect, stopwatch.started, Duration::from_millis(10));
+        let timing = stopwatch.headers(&phases);
+        assert_eq!((timing.dns, timing.connect, timing.tls), (0.002, 0.005, 0.003));
+        assert!(timing.ttfb <= stopwatch.elapsed().as_secs_f64());
+        // They were this request's, so the next one doesn't count them again
+        assert_eq!(stopwatch.headers(&phases).dns, 0.0);
+    }
+
+    #[test]
+    fn test_write_out() {
+        let write_out: WriteOut = r"%{url}\t%{http_code} %{size} bytes, 100%% in %{total}s (dns %{dns})\n".parse().unwrap();
+        let entry = Entry {
+            http_status: Some(200),
+            bytes: 1000,
+            seconds: 0.5,
+            timing: Some(Timing { dns: 0.25, connect: 0.0, tls: 0.0, ttfb: 0.3 }),
+            ..Entry::new("https://example.com/a", Status::Downloaded, None)
+        };
+        assert_eq!(write_out.render(&entry), "https://example.com/a\t200 1000 bytes, 100% in 0.500000s (dns 0.250000)\n");
+
+        let redirected = Entry {
+            redirects: vec![Redirect { status: 301, location: "https://example.com/b".to_string() }],
+            final_url: Some("https://example.com/b".to_string()),
+            ..entry.clone()
+        };
+        assert_eq!("%{num_redirects} %{url_effective}".parse::<WriteOut>().unwrap().render(&redirected), "1 https://example.com/b");
+        assert_eq!("%{url_effective}".parse::<WriteOut>().unwrap().render(&entry), "https://example.com/a");
+
+        assert!("%{nope}".parse::<WriteOut>().unwrap_err().contains("available variables are url, url_effective"));
+        assert!("%{url".parse::<WriteOut>().is_err());
+        assert_eq!("50% off".parse::<WriteOut>().unwrap().render(&entry), "50% off");
+    }
+}
@@ -0,0 +1,266 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::{Arc, OnceLock};
+use std::time::SystemTime;
+
+use reqwest::blocking::Client;
+use reqwest::{header, StatusCode};
+use url::Url;
+
+/// Errors from fetching through a transport
+#[derive(Debug, thiserror::Error)]
+pub enum TransportError {
+    #[error("{url}: {message}")]
+    Fetch { url: String, message: String },
+
+    #[error("{url}: {source}")]
+    Io { url: String, source: io::Error },
+}
+
+impl TransportError {
+    pub fn fetch(url: &Url, message: impl Into<String>) -> Self {
+        TransportError::Fetch { url: url.to_string(), message: message.into() }
+    }
+
+    pub fn io(url: &Url, source: io::Error) -> Self {
+        TransportError::Io { url: url.to_string(), source }
+    }
+}
+
+/// What's known about a remote file before it's downloaded
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    /// Its length in bytes, if the other end says
+    pub size: Option<u64>,
+    pub modified: Option<SystemTime>,
+}
+
+/// Knows how to fetch files for one or more URL schemes
+pub trait Transport: Send + Sync {
+    /// What it's called, for logs and errors
+    fn name(&self) -> &str;
+
+    /// What's at `url`, without fetching it
+    fn metadata(&self, url: &Url) -> Result<Metadata, TransportError>;
+
+    /// The file at `url`, from byte `start` up to (not including) `end`, or
+    /// to the end of the file if there isn't one
+    fn open(&self, url: &Url, start: u64, end: Option<u64>) -> Result<Box<dyn Read + Send>, TransportError>;
+}
+
+/// Which transport fetches URLs of each scheme. HTTP and HTTPS URLs are
+/// downloaded by the downloader itself, with everything the command line can
+/// do over HTTP, unless another transport's registered for them; any other
+/// scheme with a transport is read through it and saved like any download,
+/// checksums, range and all.
+#[derive(Clone)]
+pub struct Transports {
+    schemes: HashMap<String, Arc<dyn Transport>>,
+    /// Schemes still left to the downloader's own HTTP client
+    builtin_http: HashSet<String>,
+}
+
+impl Transports {
+    /// No transports at all; URLs are all left to the downloader's own HTTP client
+    pub fn empty() -> Self {
+        Transports { schemes: HashMap::new(), builtin_http: HashSet::new() }
+    }
+
+    /// HTTP and HTTPS. `file://` isn't among them, as reading local files
+    /// has to be asked for, with `.with("file", LocalFile)`
+    pub fn builtin() -> Self {
+        let http = Arc::new(Http::default());
+        let mut transports = Transports::empty();
+        for scheme in ["http", "https"] {
+            transports.schemes.insert(scheme.to_string(), Arc::clone(&http) as Arc<dyn Transport>);
+            transports.builtin_http.insert(scheme.to_string());
+        }
+        transports
+    }
+
+    /// Fetch `scheme` URLs with `transport`, instead of whatever did before
+    pub fn with(mut self, scheme: &str, transport: impl Transport + 'static) -> Self {
+        let scheme = scheme.to_ascii_lowercase();
+        self.builtin_http.remove(&scheme);
+        self.schemes.insert(scheme, Arc::new(transport));
+        self
+    }
+
+    /// The transport for `scheme`, if there is one
+    pub fn get(&self, scheme: &str) -> Option<&Arc<dyn Transport>> {
+        self.schemes.get(&scheme.to_ascii_lowercase())
+    }
+
+    /// The transport to read `url` through, unless it's one the downloader
+    /// fetches itself
+    pub fn for_url(&self, url: &Url) -> Option<&Arc<dyn Transport>> {
+        self.get(url.scheme()).filter(|_| !self.builtin_http.contains(url.scheme()))
+    }
+
+    /// The schemes there are transports for, sorted
+    pub fn schemes(&self) -> Vec<&str> {
+        let mut schemes: Vec<&str> = self.schemes.keys().map(String::as_str).collect();
+        schemes.sort_unstable();
+        schemes
+    }
+}
+
+impl Default for Transports {
+    fn default() -> Self {
+        Transports::builtin()
+    }
+}
+
+impl std::fmt::Debug for Transports {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map().entries(self.schemes().into_iter().map(|scheme| (scheme, self.schemes[scheme].name()))).finish()
+    }
+}
+
+/// Plain HTTP(S) with a client of its own, for using HTTP through the same
+/// interface as other schemes
+#[derive(Debug, Default)]
+pub struct Http {
+    /// Made when it's first needed, as making one starts a thread
+    client: OnceLock<Client>,
+}
+
+impl Http {
+    pub fn new(client: Client) -> Self {
+        Http { client: OnceLock::from(client) }
+    }
+
+    fn client(&self) -> &Client {
+        self.client.get_or_init(Client::new)
+    }
+
+    fn check(url: &Url, status: StatusCode) -> Result<(), TransportError> {
+        match status.is_success() {
+            true => Ok(()),
+            false => Err(TransportError::fetch(url, format!("server returned {}", status))),
+        }
+    }
+}
+
+impl Transport for Http {
+    fn name(&self) -> &str {
+        "http"
+    }
+
+    fn metadata(&self, url: &Url) -> Result<Metadata, TransportError> {
+        let response = self.client().head(url.as_str()).send().map_err(|e| TransportError::fetch(url, e.to_string()))?;
+        Http::check(url, response.status())?;
+        let header = |name| response.headers().get(name).and_then(|value| value.to_str().ok());
+        Ok(Metadata {
+            size: header(header::CONTENT_LENGTH).and_then(|length| length.parse().ok()),
+            modified: header(header::LAST_MODIFIED).and_then(|date| httpdate::parse_http_date(date).ok()),
+        })
+    }
+
+    fn open(&self, url: &Url, start: u64, end: Option<u64>) -> Result<Box<dyn Read + Send>, TransportError> {
+        // An empty range needs nothing from the server, and has no last byte to ask for
+        if end.is_some_and(|end| end <= start) {
+            return Ok(Box::new(io::empty()));
+        }
+        let mut request = self.client().get(url.as_str());
+        let ranged = start > 0 || end.is_some();
+        if ranged {
+            let range = match end {
+                Some(end) => format!("bytes={}-{}", start, end - 1),
+                None => format!("bytes={}-", start),
+            };
+            request = request.header(header::RANGE, range).header(header::ACCEPT_ENCODING, "identity");
+        }
+        let response = request.send().map_err(|e| TransportError::fetch(url, e.to_string()))?;
+        Http::check(url, response.status())?;
+        if ranged && response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(TransportError::fetch(url, "server sent the whole file instead of a range (it doesn't support ranges)"));
+        }
+        Ok(Box::new(response))
+    }
+}
+
+/// `file://` URLs, read straight from the filesystem
+#[derive(Debug, Default)]
+pub struct LocalFile;
+
+impl LocalFile {
+    fn path(url: &Url) -> Result<std::path::PathBuf, TransportError> {
+        url.to_file_path().map_err(|()| TransportError::fetch(url, "not a local path"))
+    }
+}
+
+impl Transport for LocalFile {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    fn metadata(&self, url: &Url) -> Result<Metadata, TransportError> {
+        let metadata = fs::metadata(LocalFile::path(url)?).map_err(|e| TransportError::io(url, e))?;
+        Ok(Metadata { size: Some(metadata.len()), modified: metadata.modified().ok() })
+    }
+
+    fn open(&self, url: &Url, start: u64, end: Option<u64>) -> Result<Box<dyn Read + Send>, TransportError> {
+        let mut file = File::open(LocalFile::path(url)?).map_err(|e| TransportError::io(url, e))?;
+        file.seek(SeekFrom::Start(start)).map_err(|e| TransportError::io(url, e))?;
+        Ok(match end {
+            Some(end) => Box::new(file.take(end.saturating_sub(start))),
+            None => Box::new(file),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serves every URL as the same few bytes
+    struct Fixed;
+
+    impl Transport for Fixed {
+        fn name(&self) -> &str {
+            "fixed"
+        }
+
+        fn metadata(&self, _url: &Url) -> Result<Metadata, TransportError> {
+            Ok(Metadata { size: Some(10), modified: None })
+        }
+
+        fn open(&self, _url: &Url, start: u64, end: Option<u64>) -> Result<Box<dyn Read + Send>, TransportError> {
+            Ok(Box::new(&b"0123456789"[start as usize..end.unwrap_or(10) as usize]))
+        }
+    }
+
+    #[test]
+    fn test_transports() {
+        assert!(Transports::builtin().for_url(&Url::parse("file:///etc/passwd").unwrap()).is_none());
+        let transports = Transports::builtin().with("artifactory", Fixed).with("file", LocalFile);
+        assert_eq!(transports.schemes(), ["artifactory", "file", "http", "https"]);
+        // HTTP is left to the downloader, unless someone replaces it
+        assert!(transports.for_url(&Url::parse("https://example.com/a.iso").unwrap()).is_none());
+        assert_eq!(transports.get("HTTPS").unwrap().name(), "http");
+        assert_eq!(transports.clone().with("https", Fixed).for_url(&Url::parse("https://example.com/a.iso").unwrap()).unwrap().name(), "fixed");
+
+        let url = Url::parse("artifactory://repo/libs/a.jar").unwrap();
+        let transport = transports.for_url(&url).unwrap();
+        let mut body = String::new();
+        transport.open(&url, 2, Some(5)).unwrap().read_to_string(&mut body).unwrap();
+        assert_eq!(body, "234");
+        // Nothing to ask the server for
+        let mut body = String::new();
+        Http::default().open(&Url::parse("http://127.0.0.1:9/a.iso").unwrap(), 0, Some(0)).unwrap().read_to_string(&mut body).unwrap();
+        assert_eq!(body, "");
+
+        let path = std::env::temp_dir().join(format!("rustdl-transport-{}", std::process::id()));
+        fs::write(&path, "hello, world").unwrap();
+        let url = Url::from_file_path(&path).unwrap();
+        let transport = transports.for_url(&url).unwrap();
+        assert_eq!(transport.metadata(&url).unwrap().size, Some(12));
+        let mut body = String::new();
+        transport.open(&url, 7, None).unwrap().read_to_string(&mut body).unwrap();
+        assert_eq!(body, "world");
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(transport.metadata(&url), Err(TransportError::Io { .. })));
+    }
+}
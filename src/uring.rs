@@ -0,0 +1,209 @@
+use std::fs::File;
+use std::io::{self, ErrorKind, Seek, Write};
+use std::mem;
+use std::os::fd::AsRawFd;
+use std::sync::Once;
+
+use io_uring::{IoUring, opcode, types};
+use log::debug;
+
+/// Writes that can be in the kernel's hands at once
+const QUEUE_DEPTH: u32 = 4;
+
+/// Size of each write, the size `copy::buffered` writes in
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+/// A write the kernel hasn't finished yet, and the buffer it's writing from
+struct InFlight {
+    buffer: Vec<u8>,
+    /// Where in the file the buffer goes
+    offset: u64,
+    /// How much of it has been written so far
+    done: usize,
+}
+
+/// Writes a file through io_uring: what's written is gathered into buffers,
+/// and each full buffer is handed to the kernel to write while the download
+/// carries on reading, with up to `QUEUE_DEPTH` of them on the way at once.
+/// Like a `BufWriter`, errors can turn up a write or two late; `flush` waits
+/// for everything to be written, and reports any that went wrong.
+pub struct RingFile {
+    file: File,
+    ring: IoUring,
+    /// Where the next buffer goes in the file
+    offset: u64,
+    filling: Vec<u8>,
+    /// By slot, which is the `user_data` of its submission
+    in_flight: Vec<Option<InFlight>>,
+    spare: Vec<Vec<u8>>,
+}
+
+/// A writer for `file` that goes through io_uring, or `file` itself on a
+/// kernel without it (or where it's been turned off, as it often is in containers)
+pub fn writer(file: File) -> Box<dyn Write + Send> {
+    static UNAVAILABLE: Once = Once::new();
+    match (&file).stream_position().and_then(|offset| Ok((offset, IoUring::new(QUEUE_DEPTH)?))) {
+        Ok((offset, ring)) => Box::new(RingFile::new(file, ring, offset)),
+        Err(e) => {
+            UNAVAILABLE.call_once(|| debug!("Not writing files through io_uring: {}", e));
+            Box::new(file)
+        }
+    }
+}
+
+impl RingFile {
+    /// Write `file` from `offset` on, through `ring`
+    pub fn new(file: File, ring: IoUring, offset: u64) -> Self {
+        let in_flight = (0..QUEUE_DEPTH).map(|_| None).collect();
+        RingFile { file, ring, offset, filling: Vec::with_capacity(BUFFER_SIZE), in_flight, spare: Vec::new() }
+    }
+
+    /// Hand the buffer being filled to the kernel, once there's a slot for it
+    fn send(&mut self) -> io::Result<()> {
+        let slot = loop {
+            match self.in_flight.iter().position(Option::is_none) {
+                Some(slot) => break slot,
+                None => self.reap()?,
+            }
+        };
+        let buffer = mem::replace(&mut self.filling, self.spare.pop().unwrap_or_else(|| Vec::with_capacity(BUFFER_SIZE)));
+        let length = buffer.len() as u64;
+        self.in_flight[slot] = Some(InFlight { buffer, offset: self.offset, done: 0 });
+        self.offset += length;
+        self.submit(slot)
+    }
+
+    /// Ask the kernel to write what's left of the buffer in `slot`. If it
+    /// can't even be queued the slot's freed, as there's nothing to wait for;
+    /// once queued it stays busy until it completes, even if telling the
+    /// kernel about it failed, as the next wait tells it again.
+    fn submit(&mut self, slot: usize) -> io::Result<()> {
+        let write = self.in_flight[slot].as_ref().expect("only busy slots are submitted");
+        let rest = &write.buffer[write.done..];
+        let entry = opcode::Write::new(types::Fd(self.file.as_raw_fd()), rest.as_ptr(), rest.len() as u32)
+            .offset(write.offset + write.done as u64)
+            .build()
+            .user_data(slot as u64);
+        // SAFETY: the buffer stays in its slot until the write's completion has
+        // been reaped, and dropping waits for every write to complete
+        if let Err(e) = unsafe { self.ring.submission().push(&entry) } {
+            self.free(slot);
+            return Err(io::Error::other(e));
+        }
+        self.ring.submit()?;
+        Ok(())
+    }
+
+    /// Wait for at least one write to complete, and deal with those that have
+    fn reap(&mut self) -> io::Result<()> {
+        match self.ring.submit_and_wait(1) {
+            Err(e) if e.kind() == ErrorKind::Interrupted => Ok(()),
+            Err(e) => Err(e),
+            Ok(_) => self.complete(),
+        }
+    }
+
+    /// Deal with every write that's completed: short ones are sent again for
+    /// the rest, and finished ones free their slots
+    fn complete(&mut self) -> io::Result<()> {
+        let completions: Vec<(usize, i32)> = self.ring.completion().map(|entry| (entry.user_data() as usize, entry.result())).collect();
+        let mut failure = None;
+        for (slot, result) in completions {
+            let write = self.in_flight[slot].as_mut().expect("completions are for busy slots");
+            match result {
+                ..0 => failure = Some(io::Error::from_raw_os_error(-result)),
+                0 => failure = Some(io::Error::from(ErrorKind::WriteZero)),
+                written => {
+                    write.done += written as usize;
+                    if write.done < write.buffer.len() {
+                        // The rest of the completions still have to be dealt with
+                        if let Err(e) = self.submit(slot) {
+                            failure = Some(e);
+                        }
+                        continue;
+                    }
+                }
+            }
+            self.free(slot);
+        }
+        failure.map_or(Ok(()), Err)
+    }
+
+    /// Put the buffer in `slot` back for reuse
+    fn free(&mut self, slot: usize) {
+        let mut buffer = self.in_flight[slot].take().expect("only busy slots are freed").buffer;
+        buffer.clear();
+        self.spare.push(buffer);
+    }
+}
+
+impl Write for RingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let taken = buf.len().min(BUFFER_SIZE - self.filling.len());
+        self.filling.extend_from_slice(&buf[..taken]);
+        if self.filling.len() == BUFFER_SIZE {
+            self.send()?;
+        }
+        Ok(taken)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.filling.is_empty() {
+            self.send()?;
+        }
+        while self.in_flight.iter().any(Option::is_some) {
+            self.reap()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RingFile {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            debug!("Could not finish writing through io_uring: {}", e);
+        }
+        // The kernel may still be writing from the buffers, so they can't be freed under it
+        while self.in_flight.iter().any(Option::is_some) {
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => {
+                    let _ = self.complete();
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => {
+                    // Nothing can be known about them now, so they're never freed
+                    self.in_flight.iter_mut().filter_map(Option::take).for_each(|write| mem::forget(write.buffer));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_ring_file() {
+        let Ok(ring) = IoUring::new(QUEUE_DEPTH) else {
+            eprintln!("io_uring isn't available here, skipping");
+            return;
+        };
+        let path = std::env::temp_dir().join(format!("rustdl-uring-{}", std::process::id()));
+        let data: Vec<u8> = (0..7 * BUFFER_SIZE + 12345).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, b"keep").unwrap();
+
+        let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        let mut writer = RingFile::new(file, ring, 4);
+        for chunk in data.chunks(100_000) {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+        let written = fs::read(&path).unwrap();
+        assert_eq!((&written[..4], written.len()), (&b"keep"[..], data.len() + 4));
+        assert!(written[4..] == data);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,266 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use indicatif::ProgressBar;
+use log::debug;
+use reqwest::Method;
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{self, HeaderMap};
+use url::Url;
+
+use crate::checksum::{self, Checksum, HashAlgorithm};
+use crate::checksum_db::ChecksumDatabase;
+use crate::credentials::CredentialStore;
+use crate::middleware::Middleware;
+use crate::naming::NamePolicy;
+use crate::oauth::OAuthSettings;
+use crate::progress::{Event, ProgressMode};
+use crate::timeouts;
+
+/// Errors from checking downloaded files against the server they came from
+#[derive(Debug, thiserror::Error)]
+pub enum VerifyRemoteError {
+    #[error("could not read URL list {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("{} of {total} files failed verification ({stale} stale, {missing} missing, {corrupted} corrupted, {unchecked} couldn't be checked)",
+            stale + missing + corrupted + unchecked)]
+    Failed { stale: usize, missing: usize, corrupted: usize, unchecked: usize, total: usize },
+}
+
+/// What's wrong with a downloaded file
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum Problem {
+    #[error("missing")]
+    Missing,
+
+    /// The server has a different file now
+    #[error("stale: {0}")]
+    Stale(String),
+
+    /// The file isn't what the server has, though it looks like it should be
+    #[error("corrupted: {0}")]
+    Corrupted(String),
+
+    /// The file couldn't be checked at all
+    #[error("{0}")]
+    Unchecked(String),
+}
+
+/// Checks files already downloaded against what the server says they should
+/// be, without downloading them again (`download verify`): their size and
+/// date, an ETag that might be an MD5 of the file, and any checksum in the database
+pub struct RemoteVerifier<'a> {
+    pub client: &'a Client,
+    pub middleware: &'a Middleware,
+    pub credentials: Option<&'a CredentialStore>,
+    pub oauth: &'a OAuthSettings,
+    pub checksum_db: Option<&'a ChecksumDatabase>,
+    pub header_timeout: Option<Duration>,
+    /// Where each URL would have been saved
+    pub naming: &'a dyn NamePolicy,
+}
+
+impl RemoteVerifier<'_> {
+    /// Check the file for every URL, `jobs` at a time, reporting problems as
+    /// they're found; `pb` counts the files
+    pub fn verify(&self, urls: &[String], jobs: usize, progress: ProgressMode, pb: &ProgressBar) -> Result<(), VerifyRemoteError> {
+        pb.set_length(urls.len() as u64);
+        progress.started(pb, None);
+        let next = AtomicUsize::new(0);
+        let problems = Mutex::new(Vec::new());
+        thread::scope(|scope| {
+            for _ in 0..jobs.clamp(1, urls.len().max(1)) {
+                scope.spawn(|| {
+                    loop {
+                        let index = next.fetch_add(1, Ordering::SeqCst);
+                        let Some(url) = urls.get(index) else { break };
+                        let (path, result) = self.check(url, index);
+                        let file = path.as_ref().map_or_else(|| url.clone(), |path| path.display().to_string());
+                        match result {
+                            Ok(()) => progress.status(pb, format!("{}: ok", file)),
+                            Err(problem) => {
+                                let error = problem.to_string();
+                                progress.event(Event::Failed { url: Some(url), file: &file, error: &error });
+                                progress.error(pb, format!("{}: {}", file, error));
+                                problems.lock().unwrap().push(problem);
+                            }
+                        }
+                        pb.inc(1);
+                    }
+                });
+            }
+        });
+
+        let problems = problems.into_inner().unwrap();
+        if problems.is_empty() {
+            return Ok(());
+        }
+        let count = |kind: fn(&Problem) -> bool| problems.iter().filter(|problem| kind(problem)).count();
+        Err(VerifyRemoteError::Failed {
+            stale: count(|problem| matches!(problem, Problem::Stale(_))),
+            missing: count(|problem| matches!(problem, Problem::Missing)),
+            corrupted: count(|problem| matches!(problem, Problem::Corrupted(_))),
+            unchecked: count(|problem| matches!(problem, Problem::Unchecked(_))),
+            total: urls.len(),
+        })
+    }
+
+    /// Check the file `url` (the `index`th URL) was saved as, returning where that is
+    pub fn check(&self, url: &str, index: usize) -> (Option<PathBuf>, Result<(), Problem>) {
+        let parsed_url = match Url::parse(url) {
+            Ok(parsed_url) => parsed_url,
+            Err(e) => return (None, Err(Problem::Unchecked(e.to_string()))),
+        };
+        let Some(path) = self.naming.url_name(&parsed_url).and_then(|name| self.naming.place(&parsed_url, &name, index)) else {
+            return (None, Err(Problem::Unchecked("no filename in the URL to look for".to_string())));
+        };
+        let result = self.check_file(&parsed_url, &path);
+        (Some(path), result)
+    }
+
+    fn check_file(&self, url: &Url, path: &Path) -> Result<(), Problem> {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(Problem::Missing),
+            Err(e) => return Err(Problem::Unchecked(e.to_string())),
+        };
+        let response = self.head(url)?;
+        if !response.status().is_success() {
+            return Err(Problem::Stale(format!("server returned {}", response.status())));
+        }
+        let headers = response.headers();
+
+        if let Some(size) = header_str(headers, header::CONTENT_LENGTH).and_then(|value| value.parse::<u64>().ok())
+            && size != metadata.len()
+        {
+            return Err(Problem::Stale(format!("{} bytes, but the server's copy is {}", metadata.len(), size)));
+        }
+        // Downloads are dated with Last-Modified, so a later date means it's changed since
+        if let Some(last_modified) = header_str(headers, header::LAST_MODIFIED).and_then(|value| httpdate::parse_http_date(value).ok())
+            && let Ok(modified) = metadata.modified()
+            && last_modified > modified + Duration::from_secs(1)
+        {
+            return Err(Problem::Stale(format!("changed on the server {}", httpdate::fmt_http_date(last_modified))));
+        }
+
+        let known = self.checksum_db.and_then(|db| db.lookup(url.as_str())).cloned();
+        let etag = etag_md5(headers);
+        if known.is_none() && etag.is_none() {
+            debug!("{}: nothing to check its contents against", path.display());
+            return Ok(());
+        }
+        let algorithms: Vec<HashAlgorithm> = known.iter().chain(&etag).map(|checksum| checksum.algorithm).collect();
+        let actual = checksum::file_checksums(path, &algorithms).map_err(|e| Problem::Unchecked(e.to_string()))?;
+        let mut actual = actual.iter();
+        if let Some(expected) = &known {
+            let actual = actual.next().expect("a checksum for each algorithm");
+            checksum::verify(expected, actual).map_err(|e| Problem::Corrupted(e.to_string()))?;
+        }
+        // Plenty of ETags that look like an MD5 aren't one, so not matching
+        // only says the server's copy isn't this one, not that this one's damaged
+        if let Some(expected) = &etag
+            && checksum::verify(expected, actual.next().expect("a checksum for each algorithm")).is_err()
+        {
+            return Err(Problem::Stale("changed on the server: its ETag isn't the file's MD5".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Ask for `url`'s headers, as the download would have
+    fn head(&self, url: &Url) -> Result<Response, Problem> {
+        let mut request = self.middleware.request(self.client, Method::HEAD, url.as_str());
+        if let Some(store) = self.credentials {
+            request = store.authorize(request, url, self.client, self.oauth);
+        }
+        let request = request.build().map_err(|e| Problem::Unchecked(e.to_string()))?;
+        timeouts::execute(self.client, request, self.header_timeout).map_err(|e| Problem::Unchecked(e.to_string()))
+    }
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers.get(name)?.to_str().ok()
+}
+
+/// The MD5 an ETag is, if it's one: S3 and servers like it tag files that
+/// weren't uploaded in parts with the hex MD5 of their contents
+fn etag_md5(headers: &HeaderMap) -> Option<Checksum> {
+    let etag = header_str(headers, header::ETAG)?.trim_matches('"');
+    Checksum::new(HashAlgorithm::Md5, etag).ok()
+}
+
+/// The URLs to check: each argument is a URL, or a file listing them one a
+/// line (blank lines and `#` comments are skipped)
+pub fn read_urls(arguments: &[String]) -> Result<Vec<String>, VerifyRemoteError> {
+    let mut urls = Vec::new();
+    for argument in arguments {
+        if Url::parse(argument).is_ok() {
+            urls.push(argument.clone());
+            continue;
+        }
+        let path = PathBuf::from(argument);
+        let contents = fs::read_to_string(&path).map_err(|source| VerifyRemoteError::Io { path, source })?;
+        urls.extend(contents.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')).map(str::to_string));
+    }
+    Ok(urls)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::naming::StandardNames;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_check() {
+        let dir = std::env::temp_dir().join(format!("rustdl-verify-remote-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("good"), "hello").unwrap();
+        fs::write(dir.join("short"), "hell").unwrap();
+        fs::write(dir.join("bad"), "jello").unwrap();
+
+        // Every file is "hello" on the server, with its MD5 as the ETag
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let _ = stream.read(&mut [0u8; 1024]);
+                let reply = "HTTP/1.1 200 OK\r\nETag: \"5d41402abc4b2a76b9719d911017c592\"\r\nContent-Length: 5\r\n\r\n";
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+        });
+
+        let client = Client::builder().pool_max_idle_per_host(0).build().unwrap();
+        let naming = StandardNames::new(false, dir.clone(), None, false);
+        let verifier = RemoteVerifier {
+            client: &client,
+            middleware: &Middleware::default(),
+            credentials: None,
+            oauth: &OAuthSettings::default(),
+            checksum_db: None,
+            header_timeout: None,
+            naming: &naming,
+        };
+        let check = |name: &str| verifier.check(&format!("http://{}/{}", address, name), 0).1;
+        assert_eq!(check("good"), Ok(()));
+        assert_eq!(check("missing"), Err(Problem::Missing));
+        assert!(matches!(check("short"), Err(Problem::Stale(_))));
+        assert!(matches!(check("bad"), Err(Problem::Stale(_))));
+
+        let urls = verifier.check("http://example.com/", 0);
+        assert!(matches!(urls, (None, Err(Problem::Unchecked(_)))));
+
+        let list = dir.join("urls.txt");
+        fs::write(&list, "# mirror\nhttp://example.com/a\n\nhttp://example.com/b\n").unwrap();
+        let urls = read_urls(&["http://example.com/c".to_string(), list.display().to_string()]).unwrap();
+        assert_eq!(urls, ["http://example.com/c", "http://example.com/a", "http://example.com/b"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
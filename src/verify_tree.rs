@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use indicatif::ProgressBar;
+use log::debug;
+use serde::Deserialize;
+
+use crate::checksum::{Checksum, ChecksumPlan, ChecksumSpec, HashingWriter};
+use crate::progress::{Event, ProgressMode};
+
+/// Errors from verifying a tree of files against a manifest
+#[derive(Debug, thiserror::Error)]
+pub enum VerifyTreeError {
+    #[error("could not read manifest {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("invalid manifest {path}: {source}")]
+    Json { path: PathBuf, source: serde_json::Error },
+
+    #[error("invalid checksum for {file} in the manifest: {message}")]
+    BadChecksum { file: String, message: String },
+
+    #[error("{failed} of {total} files failed verification")]
+    Failed { failed: usize, total: usize },
+}
+
+/// One or several checksums for a file, as written in a manifest
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Digests {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// The files a tree should contain, relative to its root, and their checksums.
+/// On disk it's a JSON object like
+/// `{"data/part-0001.parquet": "sha256:<hex>", "README": ["md5:<hex>", "sha256:<hex>"]}`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub files: BTreeMap<PathBuf, Vec<Checksum>>,
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Result<Self, VerifyTreeError> {
+        let contents = fs::read_to_string(path).map_err(|source| VerifyTreeError::Io { path: path.to_path_buf(), source })?;
+        let digests: BTreeMap<String, Digests> =
+            serde_json::from_str(&contents).map_err(|source| VerifyTreeError::Json { path: path.to_path_buf(), source })?;
+        let mut files = BTreeMap::new();
+        for (file, digests) in digests {
+            let digests = match digests {
+                Digests::One(digest) => vec![digest],
+                Digests::Many(digests) => digests,
+            };
+            let checksums = digests
+                .iter()
+                .map(|digest| digest.parse::<Checksum>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| VerifyTreeError::BadChecksum { file: file.clone(), message: e.to_string() })?;
+            if checksums.is_empty() {
+                return Err(VerifyTreeError::BadChecksum { file, message: "no checksums".to_string() });
+            }
+            files.insert(PathBuf::from(file), checksums);
+        }
+        Ok(Manifest { files })
+    }
+}
+
+/// Check every file in `manifest` under `root`, hashing `jobs` of them at a
+/// time. Failures are reported as they're found, and `pb` counts the bytes
+/// hashed across all of them (it's started here, once the total's known).
+/// Returns how many bytes were verified.
+pub fn verify(root: &Path, manifest: &Manifest, jobs: usize, progress: ProgressMode, pb: &ProgressBar) -> Result<u64, VerifyTreeError> {
+    let files: Vec<(PathBuf, &[Checksum])> = manifest.files.iter().map(|(file, checksums)| (root.join(file), checksums.as_slice())).collect();
+    pb.set_length(files.iter().filter_map(|(path, _)| fs::metadata(path).ok()).map(|metadata| metadata.len()).sum());
+    debug!("Verifying {} files under {} with {} jobs", files.len(), root.display(), jobs);
+    progress.started(pb, None);
+
+    let next = AtomicUsize::new(0);
+    let failed = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, files.len().max(1)) {
+            scope.spawn(|| {
+                while let Some((path, expected)) = files.get(next.fetch_add(1, Ordering::SeqCst)) {
+                    if let Err(e) = verify_file(path, expected, pb) {
+                        failed.fetch_add(1, Ordering::SeqCst);
+                        let file = path.display().to_string();
+                        progress.event(Event::Failed { url: None, file: &file, error: &e });
+                        progress.error(pb, format!("{}: {}", file, e));
+                    }
+                }
+            });
+        }
+    });
+
+    match failed.into_inner() {
+        0 => Ok(pb.position()),
+        failed => Err(VerifyTreeError::Failed { failed, total: files.len() }),
+    }
+}
+
+/// Hash one file with every algorithm it has a checksum for, in one pass
+fn verify_file(path: &Path, expected: &[Checksum], pb: &ProgressBar) -> Result<(), String> {
+    let specs: Vec<ChecksumSpec> = expected.iter().cloned().map(ChecksumSpec::Verify).collect();
+    let plan = ChecksumPlan::new(&specs, None);
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let mut hasher = HashingWriter::with_algorithms(io::sink(), &plan.algorithms);
+    io::copy(&mut pb.wrap_read(file), &mut hasher).map_err(|e| e.to_string())?;
+    let (_, actual) = hasher.finish_all();
+    plan.verify(&actual).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::HashAlgorithm;
+
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    fn test_verify_tree() {
+        let dir = std::env::temp_dir().join(format!("rustdl-verify-tree-{}", std::process::id()));
+        fs::create_dir_all(dir.join("data")).unwrap();
+        fs::write(dir.join("data/a"), "hello").unwrap();
+        fs::write(dir.join("b"), "hello").unwrap();
+        let manifest_path = dir.join("manifest.json");
+        fs::write(
+            &manifest_path,
+            format!(r#"{{"data/a": "sha256:{}", "b": ["md5:5d41402abc4b2a76b9719d911017c592", "sha256:{}"]}}"#, HELLO_SHA256, HELLO_SHA256),
+        )
+        .unwrap();
+
+        let manifest = Manifest::load(&manifest_path).unwrap();
+        assert_eq!(manifest.files[Path::new("b")].len(), 2);
+        assert_eq!(manifest.files[Path::new("data/a")][0].algorithm, HashAlgorithm::Sha256);
+        let pb = ProgressMode::Quiet.bar(0);
+        assert_eq!(verify(&dir, &manifest, 4, ProgressMode::Quiet, &pb).unwrap(), 10);
+
+        // A corrupted file and a missing one both count as failures
+        fs::write(dir.join("b"), "jello").unwrap();
+        fs::remove_file(dir.join("data/a")).unwrap();
+        let pb = ProgressMode::Quiet.bar(0);
+        assert!(matches!(verify(&dir, &manifest, 2, ProgressMode::Quiet, &pb), Err(VerifyTreeError::Failed { failed: 2, total: 2 })));
+
+        fs::write(&manifest_path, r#"{"b": "sha256:nope"}"#).unwrap();
+        assert!(matches!(Manifest::load(&manifest_path), Err(VerifyTreeError::BadChecksum { .. })));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
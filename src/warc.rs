@@ -0,0 +1,298 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use log::debug;
+use reqwest::blocking::{Request, Response};
+use reqwest::header::{self, HeaderMap};
+use reqwest::{Method, StatusCode, Version};
+use url::Url;
+
+/// Request headers that would put secrets in the archive
+const REDACTED_HEADERS: &[header::HeaderName] = &[header::AUTHORIZATION, header::PROXY_AUTHORIZATION, header::COOKIE];
+
+/// Errors from writing a WARC file
+#[derive(Debug, thiserror::Error)]
+pub enum WarcError {
+    #[error("could not open WARC file {path}: {source}")]
+    Open { path: PathBuf, source: io::Error },
+
+    #[error("could not write to WARC file {path}: {source}")]
+    Write { path: PathBuf, source: io::Error },
+
+    #[error("could not archive {url}: body was {actual} bytes, expected {expected}")]
+    Truncated { url: Url, expected: u64, actual: u64 },
+}
+
+/// The parts of a request that go in its `request` record
+#[derive(Debug, Clone)]
+pub struct RequestHead {
+    method: Method,
+    version: Version,
+    headers: HeaderMap,
+}
+
+impl RequestHead {
+    /// Capture a request before it's sent. Credentials are left out: an
+    /// archive is meant to be shared.
+    pub fn new(request: &Request) -> Self {
+        let mut headers = request.headers().clone();
+        for name in REDACTED_HEADERS {
+            headers.remove(name);
+        }
+        RequestHead { method: request.method().clone(), version: request.version(), headers }
+    }
+}
+
+/// A request and the response it got, everything but the body
+#[derive(Debug, Clone)]
+pub struct Exchange {
+    request: RequestHead,
+    /// Where the response came from, after any redirects
+    url: Url,
+    status: StatusCode,
+    version: Version,
+    headers: HeaderMap,
+    ip_address: Option<String>,
+    date: SystemTime,
+}
+
+impl Exchange {
+    pub fn new(request: RequestHead, response: &Response) -> Self {
+        Exchange {
+            request,
+            url: response.url().clone(),
+            status: response.status(),
+            version: response.version(),
+            headers: response.headers().clone(),
+            ip_address: response.remote_addr().map(|addr| addr.ip().to_string()),
+            date: SystemTime::now(),
+        }
+    }
+
+    fn request_block(&self) -> Vec<u8> {
+        let mut target = self.url.path().to_string();
+        if let Some(query) = self.url.query() {
+            target = format!("{}?{}", target, query);
+        }
+        let mut block = format!("{} {} {:?}\r\n", self.request.method, target, self.request.version).into_bytes();
+        if !self.request.headers.contains_key(header::HOST)
+            && let Some(host) = self.url.host_str()
+        {
+            match self.url.port() {
+                Some(port) => block.extend(format!("Host: {}:{}\r\n", host, port).bytes()),
+                None => block.extend(format!("Host: {}\r\n", host).bytes()),
+            }
+        }
+        write_headers(&mut block, &self.request.headers);
+        block
+    }
+
+    /// The response's status line and headers. The body we archive has
+    /// already been de-chunked (and decompressed, which drops the
+    /// `Content-Encoding` header), so the headers describe it as it is.
+    fn response_head(&self) -> Vec<u8> {
+        let reason = self.status.canonical_reason().unwrap_or_default();
+        let mut head = format!("{:?} {} {}\r\n", self.version, self.status.as_u16(), reason).into_bytes();
+        let mut headers = self.headers.clone();
+        headers.remove(header::TRANSFER_ENCODING);
+        write_headers(&mut head, &headers);
+        head
+    }
+}
+
+/// Appends WARC 1.1 records to a file, for archiving everything a run fetches
+#[derive(Debug)]
+pub struct WarcWriter {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl WarcWriter {
+    /// Open (or create) a WARC file for appending, and note this run in a
+    /// `warcinfo` record
+    pub fn open(path: &Path) -> Result<Self, WarcError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|source| WarcError::Open { path: path.to_path_buf(), source })?;
+        let writer = WarcWriter { path: path.to_path_buf(), file: Mutex::new(file) };
+        let info = format!(
+            "software: rustdl/{}\r\nformat: WARC File Format 1.1\r\nconformsTo: http://iipc.github.io/warc-specifications/specifications/warc-format/warc-1.1/\r\n",
+            env!("CARGO_PKG_VERSION")
+        );
+        let filename = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let headers = [
+            ("WARC-Type", "warcinfo".to_string()),
+            ("WARC-Record-ID", record_id()),
+            ("WARC-Date", warc_date(SystemTime::now())),
+            ("WARC-Filename", filename),
+            ("Content-Type", "application/warc-fields".to_string()),
+        ];
+        let mut file = writer.file.lock().unwrap();
+        write_record(&mut *file, &headers, info.as_bytes(), &mut io::empty(), 0)
+            .map_err(|source| WarcError::Write { path: path.to_path_buf(), source })?;
+        drop(file);
+        Ok(writer)
+    }
+
+    /// Archive an exchange as a `request` and a `response` record. `body` must
+    /// produce exactly `length` bytes.
+    pub fn write(&self, exchange: &Exchange, body: &mut dyn Read, length: u64) -> Result<(), WarcError> {
+        let response_id = record_id();
+        let date = warc_date(exchange.date);
+        let mut response_headers = vec![
+            ("WARC-Type", "response".to_string()),
+            ("WARC-Record-ID", response_id.clone()),
+            ("WARC-Date", date.clone()),
+            ("WARC-Target-URI", exchange.url.to_string()),
+        ];
+        if let Some(ip_address) = &exchange.ip_address {
+            response_headers.push(("WARC-IP-Address", ip_address.clone()));
+        }
+        response_headers.push(("Content-Type", "application/http;msgtype=response".to_string()));
+        let request_headers = [
+            ("WARC-Type", "request".to_string()),
+            ("WARC-Record-ID", record_id()),
+            ("WARC-Date", date),
+            ("WARC-Target-URI", exchange.url.to_string()),
+            ("WARC-Concurrent-To", response_id),
+            ("Content-Type", "application/http;msgtype=request".to_string()),
+        ];
+
+        // Both records go in together, so concurrent downloads don't interleave
+        let mut file = self.file.lock().unwrap();
+        let io_error = |source| WarcError::Write { path: self.path.clone(), source };
+        let written = write_record(&mut *file, &response_headers, &exchange.response_head(), body, length).map_err(io_error)?;
+        if written != length {
+            return Err(WarcError::Truncated { url: exchange.url.clone(), expected: length, actual: written });
+        }
+        write_record(&mut *file, &request_headers, &exchange.request_block(), &mut io::empty(), 0).map_err(io_error)?;
+        debug!("Archived {} ({} bytes) in {}", exchange.url, length, self.path.display());
+        Ok(())
+    }
+}
+
+/// Write one record whose block is `head` followed by `length` bytes of
+/// `body`, returning how many body bytes there actually were
+fn write_record(
+    out: &mut impl Write,
+    headers: &[(&str, String)],
+    head: &[u8],
+    body: &mut dyn Read,
+    length: u64,
+) -> io::Result<u64> {
+    let mut record = b"WARC/1.1\r\n".to_vec();
+    for (name, value) in headers {
+        record.extend(format!("{}: {}\r\n", name, value).bytes());
+    }
+    record.extend(format!("Content-Length: {}\r\n\r\n", head.len() as u64 + length).bytes());
+    record.extend(head);
+    out.write_all(&record)?;
+    let written = io::copy(&mut body.take(length), out)?;
+    out.write_all(b"\r\n\r\n")?;
+    out.flush()?;
+    Ok(written)
+}
+
+fn write_headers(block: &mut Vec<u8>, headers: &HeaderMap) {
+    for (name, value) in headers {
+        block.extend(name.as_str().bytes());
+        block.extend(b": ");
+        block.extend(value.as_bytes());
+        block.extend(b"\r\n");
+    }
+    block.extend(b"\r\n");
+}
+
+/// A fresh `<urn:uuid:...>` (version 4) record ID
+fn record_id() -> String {
+    let high = getrandom::u64().expect("no random numbers available");
+    let low = getrandom::u64().expect("no random numbers available");
+    let high = (high & !0xf000) | 0x4000;
+    let low = (low & !(0b11 << 62)) | (0b10 << 62);
+    format!(
+        "<urn:uuid:{:08x}-{:04x}-{:04x}-{:04x}-{:012x}>",
+        high >> 32,
+        (high >> 16) & 0xffff,
+        high & 0xffff,
+        low >> 48,
+        low & 0xffff_ffff_ffff
+    )
+}
+
+fn warc_date(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_id_is_a_v4_uuid() {
+        let id = record_id();
+        let uuid = id.strip_prefix("<urn:uuid:").and_then(|id| id.strip_suffix('>')).unwrap();
+        let groups: Vec<&str> = uuid.split('-').collect();
+        assert_eq!(groups.iter().map(|group| group.len()).collect::<Vec<_>>(), vec![8, 4, 4, 4, 12]);
+        assert!(groups[2].starts_with('4'));
+        assert!(matches!(groups[3].chars().next(), Some('8' | '9' | 'a' | 'b')));
+        assert_ne!(record_id(), id);
+    }
+
+    #[test]
+    fn test_write_exchange() {
+        let path = std::env::temp_dir().join(format!("rustdl-warc-{}.warc", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let writer = WarcWriter::open(&path).unwrap();
+
+        let request = reqwest::blocking::Client::new()
+            .get("http://example.com:8080/file.txt?v=1")
+            .header(header::USER_AGENT, "rustdl")
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .build()
+            .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, "text/plain".parse().unwrap());
+        headers.insert(header::TRANSFER_ENCODING, "chunked".parse().unwrap());
+        let exchange = Exchange {
+            request: RequestHead::new(&request),
+            url: request.url().clone(),
+            status: StatusCode::OK,
+            version: Version::HTTP_11,
+            headers,
+            ip_address: Some("127.0.0.1".to_string()),
+            date: SystemTime::now(),
+        };
+        let body = b"hello";
+        writer.write(&exchange, &mut body.as_slice(), body.len() as u64).unwrap();
+        assert!(matches!(writer.write(&exchange, &mut &b"hel"[..], 5), Err(WarcError::Truncated { actual: 3, .. })));
+
+        let contents = String::from_utf8(std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let records: Vec<&str> = contents.split("WARC/1.1\r\n").skip(1).collect();
+        assert_eq!(records.len(), 4);
+        assert!(records[0].contains("WARC-Type: warcinfo\r\n"));
+        assert!(records[0].contains("software: rustdl/"));
+
+        let response = records[1];
+        assert!(response.contains("WARC-Type: response\r\n"));
+        assert!(response.contains("WARC-Target-URI: http://example.com:8080/file.txt?v=1\r\n"));
+        assert!(response.contains("WARC-IP-Address: 127.0.0.1\r\n"));
+        assert!(response.contains("Content-Type: application/http;msgtype=response\r\n"));
+        let (warc_headers, block) = response.split_once("\r\n\r\n").unwrap();
+        let length: usize = warc_headers.lines().find_map(|line| line.strip_prefix("Content-Length: ")).unwrap().parse().unwrap();
+        assert_eq!(block.len(), length + 4);
+        assert_eq!(&block[..length], "HTTP/1.1 200 OK\r\ncontent-type: text/plain\r\n\r\nhello");
+
+        let request = records[2];
+        assert!(request.contains("WARC-Type: request\r\n"));
+        let response_id = response.lines().find_map(|line| line.strip_prefix("WARC-Record-ID: ")).unwrap();
+        assert!(request.contains(&format!("WARC-Concurrent-To: {}\r\n", response_id)));
+        assert!(request.contains("GET /file.txt?v=1 HTTP/1.1\r\nHost: example.com:8080\r\nuser-agent: rustdl\r\n\r\n"));
+        assert!(!request.contains("secret"));
+    }
+}
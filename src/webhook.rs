@@ -0,0 +1,239 @@
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use log::{debug, info, warn};
+use reqwest::blocking::Client;
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+use serde_json::json;
+use sha2::Sha256;
+use url::Url;
+
+use crate::report::Entry;
+use crate::timing::WriteOut;
+
+/// How many times to try delivering each payload
+const ATTEMPTS: u32 = 3;
+
+/// How long to wait before the first retry; it doubles after that
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How long to wait, once the run's over, for payloads that haven't been sent yet
+const DEADLINE: Duration = Duration::from_secs(30);
+
+/// Carries the payload's HMAC-SHA256 as `sha256=<hex>`, as GitHub's webhooks do
+pub const SIGNATURE_HEADER: &str = "X-Download-Signature-256";
+
+/// Errors from delivering a webhook
+#[derive(Debug, thiserror::Error)]
+pub enum WebhookError {
+    #[error("{0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error("server returned {0}")]
+    Status(reqwest::StatusCode),
+}
+
+/// Where to POST a JSON payload as each URL finishes, and a summary at the end;
+/// payloads are sent in the background, so a slow receiver doesn't hold up the downloads
+pub struct Webhook {
+    endpoint: Endpoint,
+    /// The per-URL payload, with `--write-out`'s variables, instead of the report entry
+    template: Option<WriteOut>,
+    /// Sends the payloads, once there's one to send
+    worker: Mutex<Option<Worker>>,
+}
+
+/// Where the payloads go, and how
+#[derive(Clone)]
+struct Endpoint {
+    url: Url,
+    /// Signs each payload, so the receiver knows it came from us
+    secret: Option<String>,
+    client: Client,
+}
+
+/// The thread sending the payloads, in the order they were made
+struct Worker {
+    payloads: Sender<(String, String)>,
+    /// Disconnected once the thread's sent everything it was given
+    done: Receiver<()>,
+}
+
+impl Webhook {
+    pub fn new(url: Url) -> Self {
+        Webhook {
+            endpoint: Endpoint { url, secret: None, client: Client::builder().timeout(Duration::from_secs(30)).build().unwrap_or_default() },
+            template: None,
+            worker: Mutex::new(None),
+        }
+    }
+
+    /// Send payloads with `client`, so they go through the same proxy and
+    /// resolver as the downloads
+    pub fn client(mut self, client: Client) -> Self {
+        self.endpoint.client = client;
+        self
+    }
+
+    /// Sign payloads with `secret`
+    pub fn secret(mut self, secret: String) -> Self {
+        self.endpoint.secret = Some(secret);
+        self
+    }
+
+    /// Send `template`, filled in, for each URL
+    pub fn template(mut self, template: WriteOut) -> Self {
+        self.template = Some(template);
+        self
+    }
+
+    /// Tell the webhook what became of a URL
+    pub fn entry(&self, entry: &Entry) {
+        let payload = match &self.template {
+            Some(template) => template.render_json(entry),
+            None => json!({ "event": entry.status.as_str(), "download": entry }).to_string(),
+        };
+        self.send(&entry.url, payload);
+    }
+
+    /// Tell the webhook the run's over, with the same summary as `--report-json`
+    pub fn summary(&self, summary: &impl Serialize) {
+        self.send("the summary", json!({ "event": "finished", "summary": summary }).to_string());
+    }
+
+    /// Wait for the payloads that haven't been sent yet, for up to `DEADLINE`
+    pub fn finish(&self) {
+        let Some(Worker { payloads, done }) = self.worker.lock().unwrap().take() else { return };
+        drop(payloads);
+        if let Err(RecvTimeoutError::Timeout) = done.recv_timeout(DEADLINE) {
+            warn!("Gave up waiting for webhooks to be sent after {:?}", DEADLINE);
+        }
+    }
+
+    /// Hand `payload` to the worker, starting it if it isn't running
+    fn send(&self, about: &str, payload: String) {
+        let mut worker = self.worker.lock().unwrap();
+        let worker = worker.get_or_insert_with(|| {
+            let (payloads, queue) = mpsc::channel::<(String, String)>();
+            let (finished, done) = mpsc::channel();
+            let endpoint = self.endpoint.clone();
+            thread::spawn(move || {
+                let _finished = finished;
+                for (about, payload) in queue {
+                    endpoint.deliver(&about, &payload);
+                }
+            });
+            Worker { payloads, done }
+        });
+        let _ = worker.payloads.send((about.to_string(), payload));
+    }
+}
+
+impl Endpoint {
+    /// POST `payload`, trying again if it doesn't get through; a webhook
+    /// that can't be reached is worth a warning, not a failed download
+    fn deliver(&self, about: &str, payload: &str) {
+        let mut backoff = FIRST_BACKOFF;
+        for attempt in 1..=ATTEMPTS {
+            match self.post(payload) {
+                Ok(()) => {
+                    info!("Sent webhook for {}", about);
+                    return;
+                }
+                Err(e) if attempt < ATTEMPTS => {
+                    debug!("Webhook for {} failed ({}), retrying in {:?}", about, e, backoff);
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                Err(e) => warn!("Could not send webhook for {}: {}", about, e),
+            }
+        }
+    }
+
+    fn post(&self, payload: &str) -> Result<(), WebhookError> {
+        let mut request = self.client.post(self.url.clone()).header(CONTENT_TYPE, "application/json").body(payload.to_string());
+        if let Some(secret) = &self.secret {
+            request = request.header(SIGNATURE_HEADER, signature(secret, payload));
+        }
+        let response = request.send()?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(WebhookError::Status(status)),
+        }
+    }
+}
+
+impl std::fmt::Debug for Webhook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Webhook").field("url", &self.endpoint.url.as_str()).field("signed", &self.endpoint.secret.is_some()).finish()
+    }
+}
+
+/// `sha256=` and the hex HMAC-SHA256 of `payload`, keyed by `secret`
+fn signature(secret: &str, payload: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(payload.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::Status;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    /// The whole of one request, headers and body
+    fn read_request(stream: &mut impl Read) -> String {
+        let mut request = Vec::new();
+        let mut buffer = [0u8; 4096];
+        loop {
+            let length = stream.read(&mut buffer).unwrap();
+            request.extend_from_slice(&buffer[..length]);
+            let text = String::from_utf8_lossy(&request).into_owned();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let content_length = head.lines().find_map(|line| line.to_lowercase().strip_prefix("content-length:").map(|n| n.trim().parse::<usize>().unwrap()));
+                if length == 0 || body.len() >= content_length.unwrap_or_default() {
+                    return text;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_signature() {
+        // From GitHub's documentation on validating webhook deliveries
+        assert_eq!(signature("It's a Secret to Everybody", "Hello, World!"), "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17");
+    }
+
+    #[test]
+    fn test_entry_is_retried_and_signed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, requests) = mpsc::channel();
+        thread::spawn(move || {
+            for reply in ["HTTP/1.1 503 Service Unavailable", "HTTP/1.1 204 No Content"] {
+                let (mut stream, _) = listener.accept().unwrap();
+                sender.send(read_request(&mut stream)).unwrap();
+                stream.write_all(format!("{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", reply).as_bytes()).unwrap();
+            }
+        });
+
+        let webhook = Webhook::new(format!("http://{}/hook", address).parse().unwrap())
+            .secret("s3cret".to_string())
+            .template(r#"{"text": "%{status}: %{url}"}"#.parse().unwrap());
+        webhook.entry(&Entry::new("https://example.com/\"quoted\"", Status::Failed, None));
+        // Both attempts are made before finish returns
+        webhook.finish();
+        let request = requests.try_recv().unwrap();
+        assert_eq!(request, requests.try_recv().unwrap());
+        let body = r#"{"text": "failed: https://example.com/\"quoted\""}"#;
+        assert!(request.ends_with(body));
+        assert!(request.to_lowercase().contains(&format!("x-download-signature-256: {}", signature("s3cret", body))));
+    }
+}
@@ -1,3 +1,6 @@
+// These exercise choosing a browser to take cookies from
+#![cfg(feature = "browser-cookies")]
+
 use std::process::Command;
 use std::env;
 use std::path::PathBuf;